@@ -0,0 +1,155 @@
+//! Load test spawning a dummy origin and the proxy in-process, then hammering the proxy with concurrent clients.
+//!
+//! Reports throughput, latency percentiles and memory, so changes to hot paths such as the cache locking can be
+//! compared before and after. Run with `cargo bench --features test-util --bench load`, tuned with:
+//!
+//! - `LOAD_CONCURRENCY`: concurrent clients, 64 by default
+//! - `LOAD_REQUESTS`: total requests, 100000 by default
+//! - `LOAD_BODY_SIZE`: size of the origin responses in bytes, 1024 by default
+//! - `LOAD_CACHE`: whether the proxy cache is enabled, `true` by default
+//! - `LOAD_PATHS`: number of distinct paths requested, 100 by default
+
+mod support;
+
+use std::{
+    env,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use fortifynet_proxy::{ProxyConfig, TestProxy};
+use hyper::{body::to_bytes, Client, Uri};
+
+/// Reads a setting from the environment, falling back to `default`.
+fn setting<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Returns the resident memory of the process in KiB, on Linux.
+fn resident_memory_kib() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Returns the latency at `percentile` of sorted latencies.
+fn percentile(sorted: &[Duration], percentile: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() as f64 * percentile / 100.0).ceil() as usize).clamp(1, sorted.len());
+    sorted[index - 1]
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // Listing the benchmarks, as `cargo bench -- --list` does, must not run the load test
+    if env::args().any(|arg| arg == "--list") {
+        return Ok(());
+    }
+    let concurrency: usize = setting("LOAD_CONCURRENCY", 64);
+    let requests: usize = setting("LOAD_REQUESTS", 100_000);
+    let body_size: usize = setting("LOAD_BODY_SIZE", 1024);
+    let cache_enabled: bool = setting("LOAD_CACHE", true);
+    let paths: usize = setting("LOAD_PATHS", 100).max(1);
+
+    let origin = support::start_origin(body_size);
+    let proxy = TestProxy::start(ProxyConfig {
+        cache_enabled,
+        target_address: Some(format!("http://{}", origin)),
+        ..Default::default()
+    })
+    .await?;
+    let urls: Arc<Vec<Uri>> = Arc::new(
+        (0..paths)
+            .map(|i| proxy.url(&format!("/load/{}", i)).parse())
+            .collect::<Result<_, _>>()?,
+    );
+    let memory_before = resident_memory_kib();
+
+    println!(
+        "{} requests, {} clients, {} paths, {} byte bodies, cache {}",
+        requests,
+        concurrency,
+        paths,
+        body_size,
+        if cache_enabled { "on" } else { "off" }
+    );
+    let next = Arc::new(AtomicUsize::new(0));
+    let errors = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let workers: Vec<_> = (0..concurrency)
+        .map(|_| {
+            let (next, errors, urls) = (next.clone(), errors.clone(), urls.clone());
+            tokio::spawn(async move {
+                let client = Client::new();
+                let mut latencies = Vec::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= requests {
+                        return latencies;
+                    }
+                    let sent = Instant::now();
+                    let succeeded = match client.get(urls[i % urls.len()].clone()).await {
+                        Ok(response) => {
+                            response.status().is_success()
+                                && to_bytes(response.into_body()).await.is_ok()
+                        }
+                        Err(_) => false,
+                    };
+                    if !succeeded {
+                        errors.fetch_add(1, Ordering::Relaxed);
+                    }
+                    latencies.push(sent.elapsed());
+                }
+            })
+        })
+        .collect();
+    let mut latencies = Vec::with_capacity(requests);
+    for worker in workers {
+        latencies.extend(worker.await?);
+    }
+    let elapsed = started.elapsed();
+    latencies.sort_unstable();
+
+    println!(
+        "throughput: {:.0} requests/s over {:.2?}",
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        elapsed
+    );
+    println!(
+        "latency: p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+        percentile(&latencies, 50.0),
+        percentile(&latencies, 90.0),
+        percentile(&latencies, 99.0),
+        latencies.last().copied().unwrap_or_default()
+    );
+    println!("errors: {}", errors.load(Ordering::Relaxed));
+    if let (Some(before), Some(after)) = (memory_before, resident_memory_kib()) {
+        println!(
+            "resident memory: {} KiB, {:+} KiB during the run",
+            after,
+            after as i64 - before as i64
+        );
+    }
+    let metrics = proxy.metrics();
+    println!(
+        "proxy: {} requests, {} cache hits, {} cache misses",
+        metrics.total_requests, metrics.cache_hits, metrics.cache_misses
+    );
+    proxy.shutdown().await;
+    Ok(())
+}
@@ -0,0 +1,96 @@
+//! Criterion benchmarks of the request path and of the hot helpers.
+//!
+//! Run with `cargo bench --features test-util --bench proxy`.
+
+mod support;
+
+use std::collections::HashMap;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use fortifynet_proxy::{
+    CacheAdmission, CacheAdmissionConfig, CachedBody, ProxyConfig, TestProxy, UserAgentAction,
+    UserAgentCategory, UserAgentMatch, UserAgentRule, UserAgentRules,
+};
+use hyper::{body::to_bytes, Client};
+use tokio::runtime::Runtime;
+
+/// Benchmarks requests through the proxy, served from the origin and from the cache.
+fn bench_requests(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("request");
+    for (name, cache_enabled) in [("uncached", false), ("cached", true)] {
+        let (proxy, client) = runtime.block_on(async {
+            let origin = support::start_origin(1024);
+            let proxy = TestProxy::start(ProxyConfig {
+                cache_enabled,
+                target_address: Some(format!("http://{}", origin)),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+            (proxy, Client::new())
+        });
+        let url: hyper::Uri = proxy.url("/bench").parse().unwrap();
+        group.bench_function(name, |b| {
+            b.to_async(&runtime).iter(|| async {
+                let response = client.get(url.clone()).await.unwrap();
+                black_box(to_bytes(response.into_body()).await.unwrap());
+            })
+        });
+        runtime.block_on(proxy.shutdown());
+    }
+    group.finish();
+}
+
+/// Benchmarks cache admission with evictions in a full cache.
+fn bench_cache_admission(c: &mut Criterion) {
+    let admission = CacheAdmission::new(CacheAdmissionConfig {
+        max_total_size: Some(1024 * 1024),
+        frequency_filter: true,
+        ..Default::default()
+    });
+    let full: HashMap<String, CachedBody> = (0..1024)
+        .map(|i| (format!("/entry/{}", i), CachedBody::from(vec![0; 1024])))
+        .collect();
+    c.bench_function("cache_admission/make_room", |b| {
+        b.iter_batched(
+            || full.clone(),
+            |mut cache| {
+                admission.record_access("/new");
+                black_box(admission.make_room(&mut cache, "/new", 4096, None)).ok();
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+/// Benchmarks the evaluation of User-Agent rules.
+fn bench_user_agent_rules(c: &mut Criterion) {
+    let rules = UserAgentRules::new(&[
+        UserAgentRule {
+            matcher: UserAgentMatch::Regex("(?i)badbot/\\d+".to_string()),
+            action: UserAgentAction::Block,
+        },
+        UserAgentRule {
+            matcher: UserAgentMatch::Category(UserAgentCategory::Mobile),
+            action: UserAgentAction::Route("http://127.0.0.1:9000".to_string()),
+        },
+        UserAgentRule {
+            matcher: UserAgentMatch::Category(UserAgentCategory::Bot),
+            action: UserAgentAction::BypassCache,
+        },
+    ])
+    .unwrap();
+    let user_agent = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0 Safari/537.36";
+    c.bench_function("user_agent_rules/evaluate", |b| {
+        b.iter(|| black_box(rules.evaluate(Some(black_box(user_agent)))))
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_requests,
+    bench_cache_admission,
+    bench_user_agent_rules
+);
+criterion_main!(benches);
@@ -0,0 +1,26 @@
+//! Dummy origin server shared by the benchmarks.
+
+use std::{convert::Infallible, net::SocketAddr};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Response, Server,
+};
+
+/// Starts an origin answering every request with `body_size` bytes, returning its address.
+pub fn start_origin(body_size: usize) -> SocketAddr {
+    let body = vec![b'x'; body_size];
+    let make_service = make_service_fn(move |_| {
+        let body = body.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_| {
+                let body = body.clone();
+                async move { Ok::<_, Infallible>(Response::new(Body::from(body))) }
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
@@ -0,0 +1,513 @@
+//! Access log shipping to remote collectors: RFC 5424 syslog over UDP, TCP or TLS, and ndjson batches POSTed over
+//! HTTP for ingestion by Elasticsearch, Loki and similar.
+//!
+//! Entries are queued in a bounded buffer per sink. While a collector is down its shipper retries with backoff and
+//! the buffer fills up; further entries are then dropped and counted instead of slowing down requests.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use hyper::{
+    client::{Client, HttpConnector},
+    header::{HeaderName, HeaderValue, CONTENT_TYPE},
+    Body, Method, Request,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{debug, warn};
+use serde::Serialize;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName},
+    TlsConnector,
+};
+
+use crate::secret;
+
+/// Longest delay between two delivery attempts to a collector that is down.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A request served by the proxy.
+#[derive(Clone, Debug, Serialize)]
+pub struct AccessLogEntry {
+    /// When the request was received, in RFC 3339 format.
+    pub timestamp: String,
+    /// Address of the client.
+    pub client: String,
+    /// Request method.
+    pub method: String,
+    /// Requested URL.
+    pub url: String,
+    /// `Host` header of the request.
+    pub host: Option<String>,
+    /// `User-Agent` header of the request.
+    pub user_agent: Option<String>,
+    /// JA3 hash of the TLS ClientHello of the client, if the proxy terminates its TLS.
+    pub ja3: Option<String>,
+    /// JA4 fingerprint of the TLS ClientHello of the client, if the proxy terminates its TLS.
+    pub ja4: Option<String>,
+    /// Akamai HTTP/2 fingerprint of the client, for HTTP/2 requests.
+    pub http2_fingerprint: Option<String>,
+    /// Why the HTTP/2 client is flagged as anomalous, empty for common clients.
+    pub http2_anomalies: Vec<String>,
+    /// Status of the response.
+    pub status: u16,
+    /// Time taken to produce the response headers, in milliseconds.
+    pub duration_ms: u64,
+}
+
+/// Transport of a syslog sink.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyslogTransport {
+    /// One datagram per entry (RFC 5426).
+    Udp,
+    /// Octet-counted frames over TCP (RFC 6587).
+    Tcp,
+    /// Octet-counted frames over TLS (RFC 5425), verified against the web PKI roots.
+    Tls,
+}
+
+/// A syslog collector receiving access log entries.
+#[derive(Clone, Debug)]
+pub struct SyslogConfig {
+    /// `host:port` of the collector.
+    pub address: String,
+    /// Transport to the collector.
+    pub transport: SyslogTransport,
+    /// Syslog facility; 16 to 23 are `local0` to `local7`.
+    pub facility: u8,
+    /// `HOSTNAME` field of the messages. The nil value `-` is sent when `None`.
+    pub hostname: Option<String>,
+    /// `APP-NAME` field of the messages.
+    pub app_name: String,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:514".to_string(),
+            transport: SyslogTransport::Udp,
+            facility: 16,
+            hostname: None,
+            app_name: "fortifynet_proxy".to_string(),
+        }
+    }
+}
+
+/// Body format of an HTTP sink.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HttpLogFormat {
+    /// One JSON entry per line.
+    Ndjson,
+    /// One JSON entry per line, each preceded by an `index` action, for the Elasticsearch `_bulk` API.
+    ElasticsearchBulk,
+}
+
+/// An HTTP collector receiving batches of access log entries.
+#[derive(Clone)]
+pub struct HttpLogConfig {
+    /// URL the batches are POSTed to (`http` or `https`).
+    pub url: String,
+    /// Body format of the batches.
+    pub format: HttpLogFormat,
+    /// Extra headers sent with every batch, such as `Authorization`.
+    pub headers: Vec<(String, String)>,
+    /// Largest number of entries per batch.
+    pub batch_size: usize,
+    /// How long entries wait for a batch to fill up before it is sent anyway.
+    pub flush_interval: Duration,
+}
+
+impl Default for HttpLogConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            format: HttpLogFormat::Ndjson,
+            headers: Vec::new(),
+            batch_size: 500,
+            flush_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+impl fmt::Debug for HttpLogConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Header values such as `Authorization` often carry credentials
+        let headers: Vec<_> = self
+            .headers
+            .iter()
+            .map(|(name, value)| (name, secret::redact(value)))
+            .collect();
+        f.debug_struct("HttpLogConfig")
+            .field("url", &self.url)
+            .field("format", &self.format)
+            .field("headers", &headers)
+            .field("batch_size", &self.batch_size)
+            .field("flush_interval", &self.flush_interval)
+            .finish()
+    }
+}
+
+/// Access log shipping settings.
+#[derive(Clone, Debug)]
+pub struct AccessLogConfig {
+    /// Syslog collectors.
+    pub syslog: Vec<SyslogConfig>,
+    /// HTTP collectors.
+    pub http: Vec<HttpLogConfig>,
+    /// Number of entries buffered per sink while its collector is slow or down.
+    pub buffer_size: usize,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            syslog: Vec::new(),
+            http: Vec::new(),
+            buffer_size: 10_000,
+        }
+    }
+}
+
+/// A sink with the receiving end of its buffer, until its shipper is started.
+enum Shipper {
+    Syslog(SyslogConfig, mpsc::Receiver<Arc<AccessLogEntry>>),
+    Http(HttpLogConfig, mpsc::Receiver<Arc<AccessLogEntry>>),
+}
+
+/// Queues access log entries for the configured sinks.
+pub struct AccessLog {
+    senders: Vec<mpsc::Sender<Arc<AccessLogEntry>>>,
+    shippers: Mutex<Vec<Shipper>>,
+    dropped: AtomicU64,
+}
+
+impl AccessLog {
+    /// Creates the buffers of the configured sinks, failing on invalid HTTP sink settings.
+    pub fn new(config: AccessLogConfig) -> Result<Self> {
+        let mut senders = Vec::new();
+        let mut shippers = Vec::new();
+        for syslog in config.syslog {
+            let (sender, receiver) = mpsc::channel(config.buffer_size.max(1));
+            senders.push(sender);
+            shippers.push(Shipper::Syslog(syslog, receiver));
+        }
+        for http in config.http {
+            http.url
+                .parse::<hyper::Uri>()
+                .context(format!("Invalid access log URL: {}", http.url))?;
+            for (name, value) in &http.headers {
+                HeaderName::from_bytes(name.as_bytes())
+                    .context(format!("Invalid access log header name: {}", name))?;
+                HeaderValue::from_str(value)
+                    .context(format!("Invalid value of access log header {}", name))?;
+            }
+            let (sender, receiver) = mpsc::channel(config.buffer_size.max(1));
+            senders.push(sender);
+            shippers.push(Shipper::Http(http, receiver));
+        }
+        Ok(AccessLog {
+            senders,
+            shippers: Mutex::new(shippers),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Starts shipping the buffered entries to the collectors in the background.
+    pub fn start(&self) {
+        for shipper in self.shippers.lock().unwrap().drain(..) {
+            match shipper {
+                Shipper::Syslog(config, receiver) => {
+                    tokio::spawn(ship_syslog(config, receiver));
+                }
+                Shipper::Http(config, receiver) => {
+                    tokio::spawn(ship_http(config, receiver));
+                }
+            }
+        }
+    }
+
+    /// Queues `entry` for every sink, dropping it for sinks whose buffer is full.
+    pub fn log(&self, entry: AccessLogEntry) {
+        let entry = Arc::new(entry);
+        for sender in &self.senders {
+            if sender.try_send(entry.clone()).is_err() {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    warn!(
+                        "Dropped {} access log entries: a collector is not keeping up",
+                        dropped
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the number of entries dropped because a sink's buffer was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// A connection to a syslog collector.
+enum SyslogConnection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl SyslogConnection {
+    async fn open(config: &SyslogConfig) -> Result<Self> {
+        Ok(match config.transport {
+            SyslogTransport::Udp => {
+                let addr = tokio::net::lookup_host(&config.address)
+                    .await?
+                    .next()
+                    .context(format!("Failed to resolve {}", config.address))?;
+                let local = if addr.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(addr).await?;
+                SyslogConnection::Udp(socket)
+            }
+            SyslogTransport::Tcp => {
+                SyslogConnection::Tcp(TcpStream::connect(&config.address).await?)
+            }
+            SyslogTransport::Tls => {
+                let host = config
+                    .address
+                    .rsplit_once(':')
+                    .map_or(config.address.as_str(), |(host, _)| host)
+                    .trim_start_matches('[')
+                    .trim_end_matches(']');
+                let server_name = ServerName::try_from(host)
+                    .context(format!("Invalid syslog server name: {}", host))?;
+                let stream = TcpStream::connect(&config.address).await?;
+                let stream = tls_connector().connect(server_name, stream).await?;
+                SyslogConnection::Tls(Box::new(stream))
+            }
+        })
+    }
+
+    async fn send(&mut self, message: &str) -> Result<()> {
+        match self {
+            SyslogConnection::Udp(socket) => {
+                socket.send(message.as_bytes()).await?;
+            }
+            SyslogConnection::Tcp(stream) => {
+                stream.write_all(octet_counted(message).as_bytes()).await?;
+            }
+            SyslogConnection::Tls(stream) => {
+                stream.write_all(octet_counted(message).as_bytes()).await?;
+                stream.flush().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Frames a syslog message with its length.
+fn octet_counted(message: &str) -> String {
+    format!("{} {}", message.len(), message)
+}
+
+/// Returns a TLS connector trusting the web PKI roots.
+fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+/// Formats `entry` as an RFC 5424 message with a JSON payload.
+fn syslog_message(config: &SyslogConfig, entry: &AccessLogEntry) -> Result<String> {
+    // Severity: warning for server errors, informational otherwise
+    let severity = if entry.status >= 500 { 4 } else { 6 };
+    Ok(format!(
+        "<{}>1 {} {} {} {} access - {}",
+        u16::from(config.facility) * 8 + severity,
+        entry.timestamp,
+        config.hostname.as_deref().unwrap_or("-"),
+        config.app_name,
+        std::process::id(),
+        serde_json::to_string(entry)?
+    ))
+}
+
+/// Sends the entries to a syslog collector, reconnecting with backoff while it is down.
+async fn ship_syslog(config: SyslogConfig, mut receiver: mpsc::Receiver<Arc<AccessLogEntry>>) {
+    let mut connection: Option<SyslogConnection> = None;
+    while let Some(entry) = receiver.recv().await {
+        let message = match syslog_message(&config, &entry) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!("Failed to format access log entry: {}", err);
+                continue;
+            }
+        };
+        let mut backoff = Duration::from_millis(500);
+        loop {
+            let result = match &mut connection {
+                Some(connection) => connection.send(&message).await,
+                None => match SyslogConnection::open(&config).await {
+                    Ok(opened) => connection.insert(opened).send(&message).await,
+                    Err(err) => Err(err),
+                },
+            };
+            match result {
+                Ok(()) => break,
+                Err(err) => {
+                    warn!(
+                        "Failed to ship access log to syslog at {}: {}",
+                        config.address, err
+                    );
+                    connection = None;
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+/// Sends the entries to an HTTP collector in batches, retrying a batch with backoff while the collector is down.
+async fn ship_http(config: HttpLogConfig, mut receiver: mpsc::Receiver<Arc<AccessLogEntry>>) {
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client = Client::builder().build(connector);
+    let batch_size = config.batch_size.max(1);
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut closed = false;
+    while !closed {
+        // Collect a batch until it is full or the flush interval elapsed
+        let deadline = tokio::time::Instant::now() + config.flush_interval;
+        while batch.len() < batch_size {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(entry)) => batch.push(entry),
+                Ok(None) => {
+                    closed = true;
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+        if batch.is_empty() {
+            continue;
+        }
+
+        let body = match ndjson(config.format, &batch) {
+            Ok(body) => body,
+            Err(err) => {
+                warn!("Failed to format access log batch: {}", err);
+                batch.clear();
+                continue;
+            }
+        };
+        // New entries wait in the buffer while the batch is retried
+        let mut backoff = Duration::from_millis(500);
+        while let Err(err) = post_batch(&client, &config, body.clone()).await {
+            warn!("Failed to ship access log to {}: {}", config.url, err);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+        debug!(
+            "Shipped {} access log entries to {}",
+            batch.len(),
+            config.url
+        );
+        batch.clear();
+    }
+}
+
+/// Serializes a batch as newline-delimited JSON.
+fn ndjson(format: HttpLogFormat, batch: &[Arc<AccessLogEntry>]) -> Result<String> {
+    let mut body = String::new();
+    for entry in batch {
+        if format == HttpLogFormat::ElasticsearchBulk {
+            body.push_str("{\"index\":{}}\n");
+        }
+        body.push_str(&serde_json::to_string(entry.as_ref())?);
+        body.push('\n');
+    }
+    Ok(body)
+}
+
+/// POSTs a batch, failing on transport errors and non-success statuses.
+async fn post_batch(
+    client: &Client<HttpsConnector<HttpConnector>, Body>,
+    config: &HttpLogConfig,
+    body: String,
+) -> Result<()> {
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(&config.url)
+        .header(CONTENT_TYPE, "application/x-ndjson");
+    for (name, value) in &config.headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    let response = client.request(request.body(Body::from(body))?).await?;
+    if !response.status().is_success() {
+        anyhow::bail!("Collector responded with {}", response.status());
+    }
+    Ok(())
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp with millisecond precision.
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_of_day) = (seconds / 86_400, seconds % 86_400);
+
+    // Convert days since the epoch to a civil date
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
@@ -0,0 +1,549 @@
+//! Content adaptation through an external service (ICAP or a plain HTTP callout), e.g. for virus scanning or DLP.
+//!
+//! The service sees each request before it is forwarded and each response before it is returned, and decides
+//! whether the message is allowed unchanged, modified or blocked.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use hyper::{
+    body::Bytes,
+    client::HttpConnector,
+    header::{HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
+    http::{request, response},
+    Body, Client, HeaderMap, Method, Request, Response, StatusCode, Uri,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{debug, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use url::Url;
+
+use crate::body::{read_limited, LimitedBody};
+
+/// Room left for the ICAP headers and chunk sizes around an answer body of `max_body_size` bytes.
+const MAX_ICAP_OVERHEAD: usize = 64 * 1024;
+
+/// External adaptation service.
+#[derive(Clone, Debug)]
+pub enum AdaptationService {
+    /// An ICAP (RFC 3507) service such as `icap://scanner:1344/avscan`, used with `REQMOD` and `RESPMOD`.
+    Icap {
+        /// URL of the ICAP service.
+        url: String,
+    },
+    /// An HTTP endpoint the message body is POSTed to.
+    ///
+    /// The callout carries `X-Adaptation-Mode` (`request` or `response`), `X-Adaptation-Method`,
+    /// `X-Adaptation-Url` and, for responses, `X-Adaptation-Status`. The service answers `204 No Content` to
+    /// allow the message, `200 OK` with a replacement body to modify it, or a `4xx` status whose response is sent
+    /// to the client instead. Any other answer counts as a failure of the service.
+    Http {
+        /// URL of the HTTP endpoint.
+        url: String,
+    },
+}
+
+/// Content adaptation settings.
+#[derive(Clone, Debug)]
+pub struct AdaptationConfig {
+    /// The adaptation service.
+    pub service: AdaptationService,
+    /// Whether requests are adapted before they are forwarded.
+    pub requests: bool,
+    /// Whether responses are adapted before they are returned.
+    pub responses: bool,
+    /// How long the service may take to return its verdict.
+    pub timeout: Duration,
+    /// Whether messages pass unchanged when the service fails or times out; otherwise they are rejected with
+    /// `503 Service Unavailable`.
+    pub fail_open: bool,
+    /// Largest body sent to or accepted from the service; larger messages count as failures of the service, passing
+    /// unchanged or rejected as `fail_open` says.
+    pub max_body_size: usize,
+}
+
+impl Default for AdaptationConfig {
+    fn default() -> Self {
+        Self {
+            service: AdaptationService::Icap {
+                url: "icap://127.0.0.1:1344/avscan".to_string(),
+            },
+            requests: true,
+            responses: true,
+            timeout: Duration::from_secs(10),
+            fail_open: false,
+            max_body_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// Outcome of adapting a request.
+pub enum AdaptedRequest {
+    /// Forward the request with this body.
+    Forward(Body),
+    /// Answer the client with this response instead of forwarding the request.
+    Respond(Response<Body>),
+}
+
+/// A verdict of the adaptation service.
+enum Verdict {
+    /// The message is allowed unchanged.
+    Allow,
+    /// The message is replaced; for requests, `head` holds the modified request line and headers.
+    Modify { head: Option<Head>, body: Bytes },
+    /// The message is blocked and the client receives this response.
+    Block(Response<Body>),
+}
+
+/// Start line and headers of an encapsulated HTTP message.
+struct Head {
+    method: Option<Method>,
+    uri: Option<Uri>,
+    status: Option<StatusCode>,
+    headers: HeaderMap,
+}
+
+/// Client of the adaptation service.
+pub struct Adapter {
+    config: AdaptationConfig,
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+}
+
+impl Adapter {
+    /// Creates an adapter for the configured service.
+    pub fn new(config: AdaptationConfig) -> Self {
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Adapter {
+            config,
+            client: Client::builder().build(connector),
+        }
+    }
+
+    /// Whether requests are adapted.
+    pub fn adapts_requests(&self) -> bool {
+        self.config.requests
+    }
+
+    /// Whether responses are adapted.
+    pub fn adapts_responses(&self) -> bool {
+        self.config.responses
+    }
+
+    /// Sends a request to the service, applying modifications to `parts` and returning the body to forward.
+    pub async fn adapt_request(
+        &self,
+        parts: &mut request::Parts,
+        body: Body,
+    ) -> Result<AdaptedRequest> {
+        let body = match read_limited(body, self.config.max_body_size).await? {
+            LimitedBody::Complete(body) => body,
+            LimitedBody::Oversized(body) => {
+                debug!("Request body is too large for adaptation");
+                let too_large = Err(anyhow!(
+                    "the body is larger than {} bytes",
+                    self.config.max_body_size
+                ));
+                return Ok(match self.apply_policy(too_large, &parts.uri) {
+                    Verdict::Block(response) => AdaptedRequest::Respond(response),
+                    _ => AdaptedRequest::Forward(body),
+                });
+            }
+        };
+        let verdict = match &self.config.service {
+            AdaptationService::Icap { url } => {
+                let message = icap_request(parts, None, &body);
+                self.icap("REQMOD", url, message).await
+            }
+            AdaptationService::Http { url } => self.callout(url, parts, None, &body).await,
+        };
+        match self.apply_policy(verdict, &parts.uri) {
+            Verdict::Allow => Ok(AdaptedRequest::Forward(Body::from(body))),
+            Verdict::Modify { head, body } => {
+                if let Some(head) = head {
+                    if let Some(method) = head.method {
+                        parts.method = method;
+                    }
+                    if let Some(uri) = head.uri {
+                        parts.uri = uri;
+                    }
+                    parts.headers = head.headers;
+                }
+                parts.headers.remove(TRANSFER_ENCODING);
+                parts
+                    .headers
+                    .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+                Ok(AdaptedRequest::Forward(Body::from(body)))
+            }
+            Verdict::Block(response) => Ok(AdaptedRequest::Respond(response)),
+        }
+    }
+
+    /// Sends a response to the service and returns the response to send to the client.
+    pub async fn adapt_response(
+        &self,
+        request: &request::Parts,
+        response: Response<Body>,
+    ) -> Result<Response<Body>> {
+        let (mut parts, body) = response.into_parts();
+        let body = match read_limited(body, self.config.max_body_size).await? {
+            LimitedBody::Complete(body) => body,
+            LimitedBody::Oversized(body) => {
+                debug!("Response body is too large for adaptation");
+                let too_large = Err(anyhow!(
+                    "the body is larger than {} bytes",
+                    self.config.max_body_size
+                ));
+                return Ok(match self.apply_policy(too_large, &request.uri) {
+                    Verdict::Block(response) => response,
+                    _ => Response::from_parts(parts, body),
+                });
+            }
+        };
+        let verdict = match &self.config.service {
+            AdaptationService::Icap { url } => {
+                let message = icap_request(request, Some(&parts), &body);
+                self.icap("RESPMOD", url, message).await
+            }
+            AdaptationService::Http { url } => {
+                self.callout(url, request, Some(&parts), &body).await
+            }
+        };
+        match self.apply_policy(verdict, &request.uri) {
+            Verdict::Allow => Ok(Response::from_parts(parts, Body::from(body))),
+            Verdict::Modify { head, body } => {
+                if let Some(head) = head {
+                    if let Some(status) = head.status {
+                        parts.status = status;
+                    }
+                    parts.headers = head.headers;
+                }
+                parts.headers.remove(TRANSFER_ENCODING);
+                parts
+                    .headers
+                    .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+                Ok(Response::from_parts(parts, Body::from(body)))
+            }
+            Verdict::Block(response) => Ok(response),
+        }
+    }
+
+    /// Turns a failure of the service into a verdict according to the fail-open/closed policy.
+    fn apply_policy(&self, verdict: Result<Verdict>, uri: &Uri) -> Verdict {
+        match verdict {
+            Ok(verdict) => {
+                if let Verdict::Block(response) = &verdict {
+                    warn!("Adaptation service blocked {} ({})", uri, response.status());
+                }
+                verdict
+            }
+            Err(err) if self.config.fail_open => {
+                warn!(
+                    "Adaptation of {} failed, passing it unchanged: {}",
+                    uri, err
+                );
+                Verdict::Allow
+            }
+            Err(err) => {
+                warn!("Adaptation of {} failed, rejecting it: {}", uri, err);
+                let mut response =
+                    Response::new(Body::from("Content adaptation service unavailable"));
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                Verdict::Block(response)
+            }
+        }
+    }
+
+    /// Sends an ICAP request and interprets the answer.
+    async fn icap(
+        &self,
+        method: &str,
+        service: &str,
+        encapsulated: Encapsulated,
+    ) -> Result<Verdict> {
+        let url = Url::parse(service).context(format!("Invalid ICAP URL: {}", service))?;
+        let host = url.host_str().context("Missing host in ICAP URL")?;
+        let addr = format!("{}:{}", host, url.port().unwrap_or(1344));
+        let mut message = format!(
+            "{} {} ICAP/1.0\r\nHost: {}\r\nAllow: 204\r\nConnection: close\r\nEncapsulated: {}\r\n\r\n",
+            method, service, host, encapsulated.header
+        )
+        .into_bytes();
+        message.extend_from_slice(&encapsulated.data);
+
+        let exchange = async {
+            let mut stream = TcpStream::connect(&addr)
+                .await
+                .context(format!("Failed to connect to ICAP service {}", addr))?;
+            stream.write_all(&message).await?;
+            let limit = self.config.max_body_size + MAX_ICAP_OVERHEAD;
+            let mut answer = Vec::new();
+            stream
+                .take(limit as u64 + 1)
+                .read_to_end(&mut answer)
+                .await?;
+            if answer.len() > limit {
+                bail!("The ICAP answer is larger than {} bytes", limit);
+            }
+            Ok::<_, anyhow::Error>(answer)
+        };
+        let answer = tokio::time::timeout(self.config.timeout, exchange)
+            .await
+            .context("ICAP service timed out")??;
+        parse_icap_answer(&answer, method == "REQMOD")
+    }
+
+    /// POSTs the message body to the HTTP adaptation endpoint and interprets the answer.
+    async fn callout(
+        &self,
+        url: &str,
+        request: &request::Parts,
+        response: Option<&response::Parts>,
+        body: &Bytes,
+    ) -> Result<Verdict> {
+        let headers = response.map_or(&request.headers, |response| &response.headers);
+        let mut builder = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header(
+                "x-adaptation-mode",
+                if response.is_some() {
+                    "response"
+                } else {
+                    "request"
+                },
+            )
+            .header("x-adaptation-method", request.method.as_str())
+            .header("x-adaptation-url", request.uri.to_string());
+        if let Some(response) = response {
+            builder = builder.header("x-adaptation-status", response.status.as_u16());
+        }
+        if let Some(content_type) = headers.get(CONTENT_TYPE) {
+            builder = builder.header(CONTENT_TYPE, content_type);
+        }
+        let callout = builder.body(Body::from(body.clone()))?;
+        let answer = tokio::time::timeout(self.config.timeout, async {
+            let answer = self.client.request(callout).await?;
+            let (parts, body) = answer.into_parts();
+            let body = match read_limited(body, self.config.max_body_size).await? {
+                LimitedBody::Complete(body) => body,
+                LimitedBody::Oversized(_) => bail!(
+                    "The answer of the adaptation service is larger than {} bytes",
+                    self.config.max_body_size
+                ),
+            };
+            Ok::<_, anyhow::Error>((parts, body))
+        })
+        .await
+        .context("Adaptation service timed out")??;
+
+        let (parts, answer_body) = answer;
+        match parts.status {
+            StatusCode::NO_CONTENT => Ok(Verdict::Allow),
+            StatusCode::OK => {
+                let mut headers = headers.clone();
+                if let Some(content_type) = parts.headers.get(CONTENT_TYPE) {
+                    headers.insert(CONTENT_TYPE, content_type.clone());
+                }
+                Ok(Verdict::Modify {
+                    head: Some(Head {
+                        method: None,
+                        uri: None,
+                        status: None,
+                        headers,
+                    }),
+                    body: answer_body,
+                })
+            }
+            status if status.is_client_error() => {
+                let mut block = Response::new(Body::from(answer_body));
+                *block.status_mut() = status;
+                if let Some(content_type) = parts.headers.get(CONTENT_TYPE) {
+                    block
+                        .headers_mut()
+                        .insert(CONTENT_TYPE, content_type.clone());
+                }
+                Ok(Verdict::Block(block))
+            }
+            status => anyhow::bail!("Adaptation service responded with {}", status),
+        }
+    }
+}
+
+/// The `Encapsulated` header value and the encapsulated sections of an ICAP request.
+struct Encapsulated {
+    header: String,
+    data: Vec<u8>,
+}
+
+/// Encapsulates the request headers, the response headers when given, and the body in chunked encoding.
+fn icap_request(
+    request: &request::Parts,
+    response: Option<&response::Parts>,
+    body: &[u8],
+) -> Encapsulated {
+    let mut data = format!("{} {} HTTP/1.1\r\n", request.method, request.uri).into_bytes();
+    write_headers(&mut data, &request.headers);
+    let mut header = "req-hdr=0".to_string();
+    if let Some(response) = response {
+        header.push_str(&format!(", res-hdr={}", data.len()));
+        let reason = response.status.canonical_reason().unwrap_or_default();
+        data.extend(format!("HTTP/1.1 {} {}\r\n", response.status.as_u16(), reason).into_bytes());
+        write_headers(&mut data, &response.headers);
+    }
+    let body_name = if response.is_some() {
+        "res-body"
+    } else {
+        "req-body"
+    };
+    if body.is_empty() {
+        header.push_str(&format!(", null-body={}", data.len()));
+    } else {
+        header.push_str(&format!(", {}={}", body_name, data.len()));
+        data.extend(format!("{:x}\r\n", body.len()).into_bytes());
+        data.extend_from_slice(body);
+        data.extend_from_slice(b"\r\n0\r\n\r\n");
+    }
+    Encapsulated { header, data }
+}
+
+/// Appends HTTP headers and the terminating empty line.
+fn write_headers(data: &mut Vec<u8>, headers: &HeaderMap) {
+    for (name, value) in headers {
+        data.extend_from_slice(name.as_str().as_bytes());
+        data.extend_from_slice(b": ");
+        data.extend_from_slice(value.as_bytes());
+        data.extend_from_slice(b"\r\n");
+    }
+    data.extend_from_slice(b"\r\n");
+}
+
+/// Interprets an ICAP response: `204` allows the message, `200` carries the modified message or a block response.
+fn parse_icap_answer(answer: &[u8], is_request: bool) -> Result<Verdict> {
+    let header_end = find(answer, b"\r\n\r\n").context("Truncated ICAP response")? + 4;
+    let head = String::from_utf8_lossy(&answer[..header_end]);
+    let mut lines = head.lines();
+    let status: u16 = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .context("Malformed ICAP status line")?;
+    match status {
+        204 => return Ok(Verdict::Allow),
+        200 => {}
+        status => anyhow::bail!("ICAP service responded with {}", status),
+    }
+    let encapsulated = lines
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("encapsulated")
+                .then(|| value.trim().to_string())
+        })
+        .context("ICAP response without Encapsulated header")?;
+    let mut sections: Vec<(String, usize)> = encapsulated
+        .split(',')
+        .filter_map(|entry| {
+            let (name, offset) = entry.trim().split_once('=')?;
+            Some((name.to_string(), offset.parse().ok()?))
+        })
+        .collect();
+    sections.sort_by_key(|(_, offset)| *offset);
+    let data = &answer[header_end..];
+    let section = |name: &str| {
+        let index = sections.iter().position(|(section, _)| section == name)?;
+        let start = sections[index].1;
+        let end = sections
+            .get(index + 1)
+            .map_or(data.len(), |(_, offset)| *offset);
+        data.get(start..end)
+    };
+    let body = match section("req-body").or_else(|| section("res-body")) {
+        Some(chunked) => decode_chunked(chunked)?,
+        None => Bytes::new(),
+    };
+
+    match (section("res-hdr"), section("req-hdr")) {
+        // A response in a REQMOD answer is sent to the client instead of forwarding the request
+        (Some(res_hdr), _) if is_request => {
+            let head = parse_head(res_hdr, false)?;
+            let mut response = Response::new(Body::from(body));
+            *response.status_mut() = head.status.unwrap_or(StatusCode::FORBIDDEN);
+            *response.headers_mut() = head.headers;
+            Ok(Verdict::Block(response))
+        }
+        (Some(res_hdr), _) => Ok(Verdict::Modify {
+            head: Some(parse_head(res_hdr, false)?),
+            body,
+        }),
+        (None, Some(req_hdr)) if is_request => Ok(Verdict::Modify {
+            head: Some(parse_head(req_hdr, true)?),
+            body,
+        }),
+        (None, _) => Ok(Verdict::Modify { head: None, body }),
+    }
+}
+
+/// Parses the start line and headers of an encapsulated request or response.
+fn parse_head(data: &[u8], is_request: bool) -> Result<Head> {
+    let mut headers = [httparse::EMPTY_HEADER; 128];
+    let (method, uri, status, parsed) = if is_request {
+        let mut request = httparse::Request::new(&mut headers);
+        request
+            .parse(data)
+            .context("Malformed encapsulated request")?;
+        let method = request.method.map(str::parse::<Method>).transpose()?;
+        let uri = request.path.map(str::parse::<Uri>).transpose()?;
+        (method, uri, None, request.headers.to_vec())
+    } else {
+        let mut response = httparse::Response::new(&mut headers);
+        response
+            .parse(data)
+            .context("Malformed encapsulated response")?;
+        let status = response.code.map(StatusCode::from_u16).transpose()?;
+        (None, None, status, response.headers.to_vec())
+    };
+    let mut map = HeaderMap::new();
+    for header in parsed.iter().filter(|header| !header.name.is_empty()) {
+        map.append(
+            HeaderName::from_bytes(header.name.as_bytes())?,
+            HeaderValue::from_bytes(header.value)?,
+        );
+    }
+    Ok(Head {
+        method,
+        uri,
+        status,
+        headers: map,
+    })
+}
+
+/// Decodes a chunked body, ignoring chunk extensions and trailers.
+fn decode_chunked(mut data: &[u8]) -> Result<Bytes> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = find(data, b"\r\n").context("Truncated chunked body")?;
+        let size = String::from_utf8_lossy(&data[..line_end]);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).context("Malformed chunk size")?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Ok(Bytes::from(body));
+        }
+        body.extend_from_slice(data.get(..size).context("Truncated chunk")?);
+        data = data.get(size + 2..).context("Truncated chunk")?;
+    }
+}
+
+/// Returns the position of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -0,0 +1,110 @@
+//! Adaptive limits on the requests in flight to an upstream, found from the latency it answers with rather than set
+//! by hand.
+//!
+//! The limit follows the gradient between the long-term and the current latency of the upstream: while requests are
+//! answered as fast as usual, the limit grows by about its square root, and once queuing inside the upstream makes
+//! them slower, it shrinks in proportion. Failed requests cut it multiplicatively. The limit stays between a minimum
+//! and the connection limit of the upstream.
+
+use std::time::Duration;
+
+/// Adaptive concurrency settings of an upstream.
+#[derive(Clone, Debug)]
+pub struct AdaptiveConcurrencyConfig {
+    /// Lowest limit. Defaults to 1.
+    pub min_limit: usize,
+    /// Limit before any request was answered. Defaults to 20.
+    pub initial_limit: usize,
+    /// Ratio of the current to the long-term latency tolerated before the limit shrinks. Defaults to 1.5.
+    pub tolerance: f64,
+    /// Weight of a new limit against the previous one, between 0 and 1. Defaults to 0.2.
+    pub smoothing: f64,
+    /// Factor the limit is multiplied by when a request fails. Defaults to 0.9.
+    pub backoff: f64,
+}
+
+impl Default for AdaptiveConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            min_limit: 1,
+            initial_limit: 20,
+            tolerance: 1.5,
+            smoothing: 0.2,
+            backoff: 0.9,
+        }
+    }
+}
+
+/// Weight of a sample in the long-term latency, averaged over about 600 requests.
+const LONG_TERM_SMOOTHING: f64 = 1.0 / 600.0;
+/// Weight of a sample in the current latency.
+const SHORT_TERM_SMOOTHING: f64 = 0.5;
+
+/// Limit of an upstream and the latencies it is derived from.
+#[derive(Debug)]
+pub(crate) struct AdaptiveLimit {
+    config: AdaptiveConcurrencyConfig,
+    max_limit: usize,
+    limit: f64,
+    long_rtt: Option<f64>,
+    short_rtt: Option<f64>,
+}
+
+impl AdaptiveLimit {
+    /// Starts at the initial limit of `config`, never going above `max_limit`.
+    pub(crate) fn new(config: AdaptiveConcurrencyConfig, max_limit: usize) -> Self {
+        let min_limit = config.min_limit.clamp(1, max_limit);
+        let limit = config.initial_limit.clamp(min_limit, max_limit) as f64;
+        AdaptiveLimit {
+            config,
+            max_limit,
+            limit,
+            long_rtt: None,
+            short_rtt: None,
+        }
+    }
+
+    /// Returns the current limit.
+    pub(crate) fn limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    /// Updates the limit with a request answered in `rtt`, or failed, while `in_flight` requests were sent, returning
+    /// the new limit.
+    pub(crate) fn record(&mut self, rtt: Duration, failed: bool, in_flight: usize) -> usize {
+        let min_limit = self.config.min_limit.clamp(1, self.max_limit) as f64;
+        if failed {
+            self.limit = (self.limit * self.config.backoff).max(min_limit);
+            return self.limit();
+        }
+        let rtt = rtt.as_secs_f64().max(1e-6);
+        let short = smooth(self.short_rtt, rtt, SHORT_TERM_SMOOTHING);
+        let mut long = smooth(self.long_rtt, rtt, LONG_TERM_SMOOTHING);
+        // After a slow period, the long-term latency recovers faster than it degraded
+        if long > short * 2.0 {
+            long *= 0.95;
+        }
+        self.short_rtt = Some(short);
+        self.long_rtt = Some(long);
+        let gradient = (self.config.tolerance * long / short).clamp(0.5, 1.0);
+        // An upstream handling far fewer requests than the limit says nothing about a higher one
+        let headroom = if (in_flight as f64) < self.limit / 2.0 {
+            0.0
+        } else {
+            self.limit.sqrt()
+        };
+        let target = self.limit * gradient + headroom;
+        let smoothing = self.config.smoothing.clamp(0.0, 1.0);
+        self.limit = (self.limit * (1.0 - smoothing) + target * smoothing)
+            .clamp(min_limit, self.max_limit as f64);
+        self.limit()
+    }
+}
+
+/// Returns the moving average `previous` updated with `sample` of weight `weight`.
+fn smooth(previous: Option<f64>, sample: f64, weight: f64) -> f64 {
+    match previous {
+        Some(previous) => previous * (1.0 - weight) + sample * weight,
+        None => sample,
+    }
+}
@@ -0,0 +1,301 @@
+//! Cache admission policy: size limits, content-type filters and a TinyLFU-style frequency filter.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+};
+
+use log::debug;
+use rand::Rng;
+
+use crate::chunks::CachedBody;
+
+/// Number of counters per row of the frequency sketch.
+const SKETCH_WIDTH: usize = 4096;
+/// Number of rows of the frequency sketch.
+const SKETCH_DEPTH: usize = 4;
+/// Number of entries sampled when looking for an eviction victim.
+const EVICTION_SAMPLES: usize = 5;
+
+/// Cache admission settings.
+#[derive(Clone, Debug, Default)]
+pub struct CacheAdmissionConfig {
+    /// Responses smaller than this many bytes are not cached.
+    pub min_size: usize,
+    /// Responses larger than this many bytes are not cached. Unlimited when `None`.
+    pub max_size: Option<usize>,
+    /// Total size of the cached bodies above which entries are evicted. Unlimited when `None`.
+    pub max_total_size: Option<usize>,
+    /// Content types that may be cached, such as `text/html` or `image/*`. All are allowed when empty.
+    pub allowed_content_types: Vec<String>,
+    /// Content types that are never cached, even if allowed.
+    pub denied_content_types: Vec<String>,
+    /// Whether a response may only evict entries requested less often than itself.
+    pub frequency_filter: bool,
+}
+
+/// Why a response was not admitted to the cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdmissionRejection {
+    /// The body is smaller than `min_size` or larger than `max_size`.
+    Size,
+    /// The content type is not allowed.
+    ContentType,
+    /// The response is requested less often than the entries it would evict.
+    Frequency,
+    /// The proxy is over its memory budget.
+    Memory,
+}
+
+/// A count-min sketch of 8-bit counters estimating how often keys are requested.
+///
+/// Counters are halved once enough increments were recorded, so old popularity fades.
+struct FrequencySketch {
+    counters: Vec<u8>,
+    increments: usize,
+}
+
+impl FrequencySketch {
+    fn new() -> Self {
+        FrequencySketch {
+            counters: vec![0; SKETCH_WIDTH * SKETCH_DEPTH],
+            increments: 0,
+        }
+    }
+
+    fn slots(key: &str) -> [usize; SKETCH_DEPTH] {
+        let mut slots = [0; SKETCH_DEPTH];
+        for (row, slot) in slots.iter_mut().enumerate() {
+            let mut hasher = DefaultHasher::new();
+            row.hash(&mut hasher);
+            key.hash(&mut hasher);
+            *slot = row * SKETCH_WIDTH + (hasher.finish() as usize % SKETCH_WIDTH);
+        }
+        slots
+    }
+
+    fn increment(&mut self, key: &str) {
+        for slot in Self::slots(key) {
+            self.counters[slot] = self.counters[slot].saturating_add(1);
+        }
+        self.increments += 1;
+        if self.increments >= SKETCH_WIDTH * 10 {
+            self.counters.iter_mut().for_each(|counter| *counter /= 2);
+            self.increments /= 2;
+        }
+    }
+
+    fn estimate(&self, key: &str) -> u8 {
+        Self::slots(key)
+            .iter()
+            .map(|slot| self.counters[*slot])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// Decides which responses enter the cache and which entries they evict.
+pub struct CacheAdmission {
+    config: CacheAdmissionConfig,
+    sketch: Mutex<FrequencySketch>,
+}
+
+impl CacheAdmission {
+    /// Creates an admission policy for the given settings.
+    pub fn new(config: CacheAdmissionConfig) -> Self {
+        CacheAdmission {
+            config,
+            sketch: Mutex::new(FrequencySketch::new()),
+        }
+    }
+
+    /// Records a lookup of `key`, feeding the frequency filter.
+    pub fn record_access(&self, key: &str) {
+        if self.config.frequency_filter {
+            self.sketch.lock().unwrap().increment(key);
+        }
+    }
+
+    /// Checks the size and content type of a response before its body is stored.
+    pub fn check(&self, size: usize, content_type: Option<&str>) -> Result<(), AdmissionRejection> {
+        if size < self.config.min_size || self.config.max_size.is_some_and(|max| size > max) {
+            return Err(AdmissionRejection::Size);
+        }
+        let content_type = content_type
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        let matches = |pattern: &String| content_type_matches(pattern, &content_type);
+        if self.config.denied_content_types.iter().any(matches)
+            || (!self.config.allowed_content_types.is_empty()
+                && !self.config.allowed_content_types.iter().any(matches))
+        {
+            return Err(AdmissionRejection::ContentType);
+        }
+        Ok(())
+    }
+
+    /// Makes room for `size` bytes under `key` in `cache`, evicting entries when over `max_total_size` or when
+    /// the namespace of `key` would exceed `namespace_quota` bytes.
+    ///
+    /// Only entries of the same namespace as `key` are evicted, so a tenant cannot push out another tenant's
+    /// content. With the frequency filter enabled, only entries requested less often than `key` are evicted.
+    pub fn make_room(
+        &self,
+        cache: &mut HashMap<String, CachedBody>,
+        key: &str,
+        size: usize,
+        namespace_quota: Option<usize>,
+    ) -> Result<(), AdmissionRejection> {
+        self.make_room_evicting(cache, key, size, namespace_quota, &mut Vec::new())
+    }
+
+    /// Makes room like [`CacheAdmission::make_room`], adding the evicted entries with their sizes to `evicted`.
+    pub(crate) fn make_room_evicting(
+        &self,
+        cache: &mut HashMap<String, CachedBody>,
+        key: &str,
+        size: usize,
+        namespace_quota: Option<usize>,
+        evicted: &mut Vec<(String, usize)>,
+    ) -> Result<(), AdmissionRejection> {
+        let namespace = key_namespace(key);
+        if let Some(quota) = namespace_quota.filter(|_| namespace.is_some()) {
+            let counted = |k: &str| key_namespace(k) == namespace;
+            self.evict(cache, key, size, quota, counted, evicted)?;
+        }
+        match self.config.max_total_size {
+            Some(max_total) => self.evict(cache, key, size, max_total, |_| true, evicted),
+            None => Ok(()),
+        }
+    }
+
+    /// Evicts entries of the namespace of `key` until the entries for which `counted` holds, which include that
+    /// namespace, fit in `limit` bytes along with `size` more.
+    fn evict(
+        &self,
+        cache: &mut HashMap<String, CachedBody>,
+        key: &str,
+        size: usize,
+        limit: usize,
+        counted: impl Fn(&str) -> bool,
+        evicted: &mut Vec<(String, usize)>,
+    ) -> Result<(), AdmissionRejection> {
+        if size > limit {
+            return Err(AdmissionRejection::Size);
+        }
+        let namespace = key_namespace(key);
+        let mut total: usize = cache
+            .iter()
+            .filter(|(k, _)| k.as_str() != key && counted(k))
+            .map(|(_, body)| body.stored_len())
+            .sum();
+        let mut candidates: Vec<String> = cache
+            .keys()
+            .filter(|k| k.as_str() != key && key_namespace(k) == namespace)
+            .cloned()
+            .collect();
+        let sketch = self.sketch.lock().unwrap();
+        let candidate_frequency = sketch.estimate(key);
+        let mut rng = rand::thread_rng();
+        while total + size > limit {
+            // Sample a few entries and pick the least frequently requested one as the victim
+            let victim = (0..EVICTION_SAMPLES)
+                .filter_map(|_| {
+                    let len = candidates.len();
+                    if len == 0 {
+                        return None;
+                    }
+                    Some(rng.gen_range(0..len))
+                })
+                .min_by_key(|index| sketch.estimate(&candidates[*index]));
+            let victim = match victim {
+                Some(index) => candidates.swap_remove(index),
+                None => return Err(AdmissionRejection::Size),
+            };
+            if self.config.frequency_filter && sketch.estimate(&victim) >= candidate_frequency {
+                debug!("Cache admission of {} rejected in favor of {}", key, victim);
+                return Err(AdmissionRejection::Frequency);
+            }
+            if let Some(body) = cache.remove(&victim) {
+                total -= body.stored_len();
+                debug!("Evicted {} from the cache", victim);
+                evicted.push((victim, body.stored_len()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Evicts entries held in memory, the least requested of a few sampled first, until the cached bodies take at
+    /// most `limit` bytes of memory. Returns the entries evicted, with their sizes.
+    pub(crate) fn shrink(
+        &self,
+        cache: &mut HashMap<String, CachedBody>,
+        limit: usize,
+    ) -> Vec<(String, usize)> {
+        let mut total: usize = cache.values().map(CachedBody::memory_len).sum();
+        let mut candidates: Vec<String> = cache
+            .iter()
+            .filter(|(_, body)| body.memory_len() > 0)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let sketch = self.sketch.lock().unwrap();
+        let mut rng = rand::thread_rng();
+        let mut evicted = Vec::new();
+        while total > limit && !candidates.is_empty() {
+            let index = (0..EVICTION_SAMPLES)
+                .map(|_| rng.gen_range(0..candidates.len()))
+                .min_by_key(|index| sketch.estimate(&candidates[*index]))
+                .unwrap_or(0);
+            let victim = candidates.swap_remove(index);
+            if let Some(body) = cache.remove(&victim) {
+                total -= body.memory_len();
+                debug!(
+                    "Evicted {} from the cache to stay within the memory budget",
+                    victim
+                );
+                evicted.push((victim, body.stored_len()));
+            }
+        }
+        evicted
+    }
+}
+
+/// Returns the cache key of `url` in `namespace`, or `url` itself outside of any namespace.
+pub(crate) fn cache_key(namespace: Option<&str>, url: &str) -> String {
+    match namespace {
+        // URLs never contain spaces, so the namespace cannot be confused with part of a URL
+        Some(namespace) => format!("{} {}", namespace, url),
+        None => url.to_string(),
+    }
+}
+
+/// Returns the key URL `url` told apart by the `variations` of its responses, such as the upstream a route picked for
+/// the client. They are added as a fragment, which request targets never carry, so prefix invalidation still finds
+/// every variant.
+pub(crate) fn varied_url(url: String, variations: &[(&str, &str)]) -> String {
+    if variations.is_empty() {
+        return url;
+    }
+    let fragment = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(variations)
+        .finish();
+    format!("{}#{}", url, fragment)
+}
+
+/// Returns the namespace of a cache key, if any.
+pub(crate) fn key_namespace(key: &str) -> Option<&str> {
+    key.split_once(' ').map(|(namespace, _)| namespace)
+}
+
+/// Whether `content_type` matches `pattern`, which may end in `/*` to match a whole type.
+fn content_type_matches(pattern: &str, content_type: &str) -> bool {
+    let pattern = pattern.trim().to_ascii_lowercase();
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => content_type
+            .split_once('/')
+            .is_some_and(|(kind, _)| kind == prefix),
+        None => pattern == content_type,
+    }
+}
@@ -0,0 +1,58 @@
+//! Buffering of request and response bodies up to a size limit, for the features that need a whole body at once,
+//! such as retries, inspection and rewriting, without letting a client or upstream make the proxy buffer any size.
+
+use anyhow::{Context, Result};
+use futures::{stream, StreamExt};
+use hyper::{body::Bytes, Body};
+
+/// A body read up to a limit.
+pub(crate) enum LimitedBody {
+    /// The whole body, no larger than the limit.
+    Complete(Bytes),
+    /// A body larger than the limit, read again from the start.
+    Oversized(Body),
+}
+
+/// Reads `body` until it ends or more than `limit` bytes arrived.
+pub(crate) async fn read_limited(mut body: Body, limit: usize) -> Result<LimitedBody> {
+    let mut buffered = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.context("Failed to read body")?;
+        if buffered.len() + chunk.len() > limit {
+            let head = stream::iter([Ok(Bytes::from(buffered)), Ok(chunk)]);
+            return Ok(LimitedBody::Oversized(Body::wrap_stream(head.chain(body))));
+        }
+        buffered.extend_from_slice(&chunk);
+    }
+    Ok(LimitedBody::Complete(Bytes::from(buffered)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked(chunks: &[&'static str]) -> Body {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes())))
+            .collect();
+        Body::wrap_stream(stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn reads_bodies_up_to_the_limit() {
+        match read_limited(chunked(&["abc", "def"]), 6).await.unwrap() {
+            LimitedBody::Complete(body) => assert_eq!(body, "abcdef"),
+            LimitedBody::Oversized(_) => panic!("Body within the limit read as oversized"),
+        }
+        match read_limited(chunked(&["abc", "def", "gh"]), 4)
+            .await
+            .unwrap()
+        {
+            LimitedBody::Complete(_) => panic!("Body over the limit read as complete"),
+            LimitedBody::Oversized(body) => {
+                assert_eq!(hyper::body::to_bytes(body).await.unwrap(), "abcdefgh");
+            }
+        }
+    }
+}
@@ -0,0 +1,399 @@
+//! Lightweight bot mitigation: heuristics scoring how likely a request comes from an automated client, and a challenge
+//! page that suspect clients must get through before their requests are forwarded.
+//!
+//! The heuristics look for headers every browser sends but scripts often leave out, for combinations of headers no
+//! real browser sends, such as a browser `User-Agent` over HTTP/1.0, and for clients sending requests faster than a
+//! person browses. A client whose score reaches the threshold is a suspect. Suspects are either logged, blocked, or
+//! challenged: the cookie challenge redirects to the same URL while setting a pass cookie, which clients without a
+//! cookie jar never send back, and the JavaScript challenge sets the pass cookie from a script, which clients that do
+//! not run scripts never do. A pass is signed, bound to the client address and expires, so it cannot be shared. A
+//! pass exempts its holder from the heuristics but the request rate, and a holder sending requests too fast is blocked
+//! rather than challenged again.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use hyper::{
+    header::{
+        HeaderValue, ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, CACHE_CONTROL, CONTENT_TYPE,
+        LOCATION, SET_COOKIE,
+    },
+    http::request,
+    Body, Method, Response, StatusCode, Version,
+};
+use log::warn;
+use rand::Rng;
+use ring::hmac;
+use serde::Serialize;
+
+use crate::{
+    canary::{hex, unhex},
+    secret::SecretSource,
+    session::read_cookie,
+    timeseries::unix_now,
+    UserAgentCategory,
+};
+
+/// Number of tracked clients above which the clients without recent requests are forgotten.
+const PRUNE_THRESHOLD: usize = 10_000;
+/// Name of the request rate heuristic.
+const RATE_HEURISTIC: &str = "request_rate";
+/// Score of the request rate heuristic.
+const RATE_SCORE: u32 = 3;
+
+/// Challenge suspect clients must get through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BotChallenge {
+    /// A redirect to the same URL setting the pass cookie.
+    Cookie,
+    /// A page setting the pass cookie from a script and reloading.
+    JavaScript,
+}
+
+/// What happens to the requests of suspect clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BotAction {
+    /// Only logs the request.
+    Log,
+    /// Rejects the request with `403 Forbidden`.
+    Block,
+    /// Serves a challenge to `GET` and `HEAD` requests, and rejects the others with `403 Forbidden`.
+    Challenge(BotChallenge),
+}
+
+/// Bot detection settings.
+#[derive(Clone)]
+pub struct BotDetectionConfig {
+    /// Score at which a client is a suspect. Defaults to 3.
+    pub suspicion_threshold: u32,
+    /// Requests a client may send within `rate_window` before its rate counts as an anomaly. Defaults to 50.
+    pub rate_limit: u32,
+    /// Window the requests of a client are counted over. Defaults to 10 seconds.
+    pub rate_window: Duration,
+    /// What happens to the requests of suspects. Defaults to the JavaScript challenge.
+    pub action: BotAction,
+    /// Name of the pass cookie. Defaults to `fortifynet_bot`.
+    pub cookie_name: String,
+    /// How long a pass is valid. Defaults to an hour.
+    pub pass_lifetime: Duration,
+    /// Secret signing the passes, which must be shared by the proxy instances behind a load balancer and must not be
+    /// empty (optional). A random secret is used when `None`, the default, so passes do not survive a restart.
+    pub secret: Option<SecretSource>,
+}
+
+impl Default for BotDetectionConfig {
+    fn default() -> Self {
+        Self {
+            suspicion_threshold: 3,
+            rate_limit: 50,
+            rate_window: Duration::from_secs(10),
+            action: BotAction::Challenge(BotChallenge::JavaScript),
+            cookie_name: "fortifynet_bot".to_string(),
+            pass_lifetime: Duration::from_secs(3600),
+            secret: None,
+        }
+    }
+}
+
+impl fmt::Debug for BotDetectionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BotDetectionConfig")
+            .field("suspicion_threshold", &self.suspicion_threshold)
+            .field("rate_limit", &self.rate_limit)
+            .field("rate_window", &self.rate_window)
+            .field("action", &self.action)
+            .field("cookie_name", &self.cookie_name)
+            .field("pass_lifetime", &self.pass_lifetime)
+            .field("secret", &self.secret)
+            .finish()
+    }
+}
+
+/// Outcome of the bot detection of a request.
+#[derive(Debug)]
+pub enum BotVerdict {
+    /// Forward the request.
+    Allow,
+    /// Reject the request with `403 Forbidden`, for the listed reasons.
+    Block(Vec<&'static str>),
+    /// Answer the request with this challenge.
+    Challenge(Response<Body>),
+}
+
+/// Counters of the bot detection.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BotStats {
+    /// Number of requests inspected.
+    pub inspected: u64,
+    /// Number of requests carrying a valid pass.
+    pub passed: u64,
+    /// Number of requests from suspects.
+    pub suspects: u64,
+    /// Number of challenges served.
+    pub challenged: u64,
+    /// Number of requests blocked.
+    pub blocked: u64,
+    /// Number of suspect requests each heuristic matched, by heuristic.
+    pub reasons: HashMap<&'static str, u64>,
+}
+
+/// Scores requests and challenges suspect clients.
+pub struct BotDetector {
+    config: BotDetectionConfig,
+    key: hmac::Key,
+    /// Times of the last `rate_limit + 1` requests of every client, and when the idle clients were last forgotten.
+    recent: Mutex<(HashMap<IpAddr, VecDeque<Instant>>, Instant)>,
+    inspected: AtomicU64,
+    passed: AtomicU64,
+    suspects: AtomicU64,
+    challenged: AtomicU64,
+    blocked: AtomicU64,
+    reasons: Mutex<HashMap<&'static str, u64>>,
+}
+
+impl BotDetector {
+    /// Creates a detector with the given settings, failing if its secret cannot be read or is empty.
+    pub fn new(config: BotDetectionConfig) -> Result<Self> {
+        let secret = match &config.secret {
+            Some(secret) => secret.read()?.into_bytes(),
+            None => rand::thread_rng().gen::<[u8; 32]>().to_vec(),
+        };
+        if secret.is_empty() {
+            bail!("The bot detection secret is empty");
+        }
+        Ok(BotDetector {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+            config,
+            recent: Mutex::new((HashMap::new(), Instant::now())),
+            inspected: AtomicU64::new(0),
+            passed: AtomicU64::new(0),
+            suspects: AtomicU64::new(0),
+            challenged: AtomicU64::new(0),
+            blocked: AtomicU64::new(0),
+            reasons: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Scores the request with `parts` from `client_ip`, and decides what happens to it.
+    pub(crate) fn inspect(&self, parts: &request::Parts, client_ip: IpAddr) -> BotVerdict {
+        self.inspected.fetch_add(1, Ordering::Relaxed);
+        let rate_exceeded = self.record_rate(client_ip);
+        let pass = read_cookie(&parts.headers, &self.config.cookie_name);
+        let passed = pass.is_some_and(|pass| self.verify(&pass, client_ip));
+        let (score, reasons) = if passed {
+            self.passed.fetch_add(1, Ordering::Relaxed);
+            if !rate_exceeded {
+                return BotVerdict::Allow;
+            }
+            (RATE_SCORE, vec![RATE_HEURISTIC])
+        } else {
+            score(parts, rate_exceeded)
+        };
+        if score < self.config.suspicion_threshold {
+            return BotVerdict::Allow;
+        }
+        self.suspects.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut counts = self.reasons.lock().unwrap();
+            for reason in &reasons {
+                *counts.entry(*reason).or_insert(0) += 1;
+            }
+        }
+        let challenge = match self.config.action {
+            BotAction::Log => {
+                warn!(
+                    "Suspected bot {} for {} (heuristics: {})",
+                    client_ip,
+                    parts.uri,
+                    reasons.join(", ")
+                );
+                return BotVerdict::Allow;
+            }
+            BotAction::Block => None,
+            BotAction::Challenge(challenge) => Some(challenge),
+        };
+        // Another challenge would only hand a new pass to a client that already holds one
+        let challenge = challenge.filter(|_| !passed);
+        match challenge.filter(|_| matches!(parts.method, Method::GET | Method::HEAD)) {
+            Some(challenge) => {
+                self.challenged.fetch_add(1, Ordering::Relaxed);
+                BotVerdict::Challenge(self.challenge(challenge, parts, client_ip))
+            }
+            None => {
+                self.blocked.fetch_add(1, Ordering::Relaxed);
+                BotVerdict::Block(reasons)
+            }
+        }
+    }
+
+    /// Records a request of `client_ip`, returning whether the client exceeded the rate limit.
+    ///
+    /// Only the last `rate_limit + 1` request times of a client are kept: the limit is exceeded when the oldest of them
+    /// is still within the window.
+    fn record_rate(&self, client_ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let window = self.config.rate_window;
+        let capacity = self.config.rate_limit as usize + 1;
+        let mut guard = self.recent.lock().unwrap();
+        let (recent, pruned) = &mut *guard;
+        // Forget the idle clients at most once per window, rather than on every request while many are tracked
+        if recent.len() > PRUNE_THRESHOLD && now.duration_since(*pruned) >= window {
+            recent.retain(|_, times| times.back().is_some_and(|at| now - *at < window));
+            *pruned = now;
+        }
+        let times = recent
+            .entry(client_ip)
+            .or_insert_with(|| VecDeque::with_capacity(capacity));
+        if times.len() == capacity {
+            times.pop_front();
+        }
+        times.push_back(now);
+        times.len() == capacity && times.front().is_some_and(|at| now - *at < window)
+    }
+
+    /// Returns the challenge answering the request with `parts`.
+    fn challenge(
+        &self,
+        challenge: BotChallenge,
+        parts: &request::Parts,
+        client_ip: IpAddr,
+    ) -> Response<Body> {
+        let pass = self.sign(client_ip);
+        let cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            self.config.cookie_name,
+            pass,
+            self.config.pass_lifetime.as_secs()
+        );
+        let mut response = Response::new(Body::empty());
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        match challenge {
+            BotChallenge::Cookie => {
+                *response.status_mut() = StatusCode::TEMPORARY_REDIRECT;
+                let location = parts
+                    .uri
+                    .path_and_query()
+                    .map_or("/", |path_and_query| path_and_query.as_str());
+                if let Ok(location) = HeaderValue::from_str(location) {
+                    response.headers_mut().insert(LOCATION, location);
+                }
+                if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+                    response.headers_mut().insert(SET_COOKIE, cookie);
+                }
+            }
+            BotChallenge::JavaScript => {
+                // The pass is written backwards, so only a client running the script gets the cookie
+                let reversed: String = pass.chars().rev().collect();
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                *response.body_mut() = Body::from(format!(
+                    "<!DOCTYPE html><html><head><title>Checking your browser</title></head><body>\
+                    <p>Checking your browser before accessing the site. This requires JavaScript and cookies.</p>\
+                    <script>document.cookie = \"{}=\" + \"{}\".split(\"\").reverse().join(\"\") + \
+                    \"; path=/; max-age={}; SameSite=Lax\"; location.reload();</script>\
+                    </body></html>",
+                    self.config.cookie_name,
+                    reversed,
+                    self.config.pass_lifetime.as_secs()
+                ));
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/html; charset=utf-8"),
+                );
+            }
+        }
+        response
+    }
+
+    /// Returns a pass for `client_ip`, as `expiry.signature`.
+    fn sign(&self, client_ip: IpAddr) -> String {
+        let expiry = unix_now() + self.config.pass_lifetime.as_secs();
+        let payload = format!("{}.{}", expiry, client_ip);
+        let tag = hmac::sign(&self.key, payload.as_bytes());
+        format!("{}.{}", expiry, hex(tag.as_ref()))
+    }
+
+    /// Whether `pass` is a valid and unexpired pass for `client_ip`.
+    fn verify(&self, pass: &str, client_ip: IpAddr) -> bool {
+        let (expiry, signature) = match pass.split_once('.') {
+            Some(pass) => pass,
+            None => return false,
+        };
+        let signature = match unhex(signature) {
+            Some(signature) => signature,
+            None => return false,
+        };
+        let payload = format!("{}.{}", expiry, client_ip);
+        hmac::verify(&self.key, payload.as_bytes(), &signature).is_ok()
+            && expiry
+                .parse::<u64>()
+                .is_ok_and(|expiry| expiry > unix_now())
+    }
+
+    /// Returns the counters of the detection.
+    pub fn stats(&self) -> BotStats {
+        BotStats {
+            inspected: self.inspected.load(Ordering::Relaxed),
+            passed: self.passed.load(Ordering::Relaxed),
+            suspects: self.suspects.load(Ordering::Relaxed),
+            challenged: self.challenged.load(Ordering::Relaxed),
+            blocked: self.blocked.load(Ordering::Relaxed),
+            reasons: self.reasons.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Returns the bot score of the request with `parts`, and the heuristics that contributed to it.
+fn score(parts: &request::Parts, rate_exceeded: bool) -> (u32, Vec<&'static str>) {
+    let header = |name| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+    };
+    let user_agent = header(hyper::header::USER_AGENT);
+    let browser = user_agent.is_some_and(|user_agent| user_agent.starts_with("Mozilla/"));
+    let mut heuristics: Vec<(&'static str, u32)> = Vec::new();
+    if UserAgentCategory::Missing.matches(user_agent) {
+        heuristics.push(("missing_user_agent", 3));
+    } else if UserAgentCategory::Bot.matches(user_agent) {
+        heuristics.push(("automation_user_agent", 2));
+    }
+    if header(ACCEPT).is_none() {
+        heuristics.push(("missing_accept", 1));
+    }
+    if browser {
+        if header(ACCEPT_LANGUAGE).is_none() {
+            heuristics.push(("browser_without_accept_language", 2));
+        }
+        if !header(ACCEPT_ENCODING).is_some_and(|encoding| encoding.contains("gzip")) {
+            heuristics.push(("browser_without_compression", 1));
+        }
+        if parts.version == Version::HTTP_10 {
+            heuristics.push(("browser_over_http10", 2));
+        }
+        if user_agent.is_some_and(|user_agent| {
+            user_agent.contains("Firefox/") && user_agent.contains("Chrome/")
+        }) {
+            heuristics.push(("inconsistent_user_agent", 2));
+        }
+    }
+    if rate_exceeded {
+        heuristics.push((RATE_HEURISTIC, RATE_SCORE));
+    }
+    let score = heuristics.iter().map(|(_, score)| score).sum();
+    (
+        score,
+        heuristics.into_iter().map(|(name, _)| name).collect(),
+    )
+}
@@ -0,0 +1,194 @@
+//! Hosts bypassing the proxy, such as intranet domains and private networks, and the PAC file served for WPAD.
+//!
+//! The PAC file sends clients directly to the bypassed hosts and through the proxy to the others. Forward-proxy
+//! requests and tunnels to bypassed hosts that reach the proxy anyway are refused, or sent directly rather than
+//! through the egress transports, such as a SOCKS5 proxy or an upstream HTTP proxy.
+
+use std::net::IpAddr;
+
+use anyhow::Result;
+
+use crate::{policy::Network, tor, tunnel};
+
+/// What the proxy does with forward-proxy requests to bypassed hosts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BypassAction {
+    /// The request is sent directly to the host, rather than through the egress transports.
+    #[default]
+    Direct,
+    /// The request is refused with `403 Forbidden`, so misconfigured clients notice.
+    Refuse,
+}
+
+/// Hosts reached without the proxy, as in the "no proxy" list of clients.
+#[derive(Clone, Debug, Default)]
+pub struct BypassConfig {
+    /// Host names, wildcards such as `*.corp.example`, domains with a leading dot such as `.corp.example` matching
+    /// themselves and their subdomains, and IP addresses or CIDR networks such as `10.0.0.0/8`.
+    pub hosts: Vec<String>,
+    /// Whether host names without a dot, such as `intranet`, are bypassed, as `<local>` is in Windows proxy
+    /// settings. Defaults to false.
+    pub plain_hostnames: bool,
+    /// What the proxy does with forward-proxy requests to the bypassed hosts. Defaults to `Direct`.
+    pub action: BypassAction,
+}
+
+/// Proxy auto-configuration for WPAD: a PAC file served at `/wpad.dat` and `/proxy.pac`.
+#[derive(Clone, Debug, Default)]
+pub struct WpadConfig {
+    /// Address of the proxy in the PAC file, as `host:port`. Defaults to the `Host` header of the request for the
+    /// PAC file, the address clients reached the proxy at.
+    pub proxy: Option<String>,
+    /// Whether clients connect directly when the proxy is unreachable. Defaults to false.
+    pub fallback_direct: bool,
+}
+
+/// Paths the PAC file is served at: the one WPAD clients look up, and the customary one.
+pub(crate) const PAC_PATHS: [&str; 2] = ["/wpad.dat", "/proxy.pac"];
+/// Content type of PAC files.
+pub(crate) const PAC_CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+/// A host pattern of the bypass list.
+enum Pattern {
+    /// A host name, or a wildcard such as `*.corp.example`.
+    Host(String),
+    /// A domain and its subdomains, without the leading dot.
+    Domain(String),
+    Network(Network),
+}
+
+/// The bypass list, matching the hosts of forward-proxy requests.
+pub(crate) struct Bypass {
+    patterns: Vec<Pattern>,
+    plain_hostnames: bool,
+    action: BypassAction,
+}
+
+impl Bypass {
+    /// Parses the bypass list. Fails on an invalid network, or a host name with characters not allowed in hosts.
+    pub(crate) fn new(config: &BypassConfig) -> Result<Self> {
+        let mut patterns = Vec::new();
+        for host in &config.hosts {
+            let host = host.trim().trim_end_matches('.').to_ascii_lowercase();
+            let address = host.trim_start_matches('[').trim_end_matches(']');
+            let pattern = if host.contains('/') || address.parse::<IpAddr>().is_ok() {
+                Pattern::Network(Network::parse(address)?)
+            } else if host.is_empty()
+                || !host
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || b"-._*".contains(&byte))
+            {
+                anyhow::bail!("Invalid bypassed host: {:?}", host);
+            } else if let Some(domain) = host.strip_prefix('.') {
+                Pattern::Domain(domain.to_string())
+            } else {
+                Pattern::Host(host)
+            };
+            patterns.push(pattern);
+        }
+        Ok(Bypass {
+            patterns,
+            plain_hostnames: config.plain_hostnames,
+            action: config.action,
+        })
+    }
+
+    /// What the proxy does with requests to the bypassed hosts.
+    pub(crate) fn action(&self) -> BypassAction {
+        self.action
+    }
+
+    /// Whether `host`, a host name or IP address, bypasses the proxy.
+    pub(crate) fn matches(&self, host: &str) -> bool {
+        // IPv6 literals are matched without their brackets, and names without their trailing dot
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Ok(address) = host.parse::<IpAddr>() {
+            return self.patterns.iter().any(|pattern| match pattern {
+                Pattern::Network(network) => network.contains(address),
+                Pattern::Host(pattern) => pattern == "*",
+                Pattern::Domain(_) => false,
+            });
+        }
+        (self.plain_hostnames && !host.contains('.'))
+            || self.patterns.iter().any(|pattern| match pattern {
+                Pattern::Host(pattern) => pattern == "*" || tunnel::host_matches(pattern, &host),
+                Pattern::Domain(domain) => host
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.')),
+                Pattern::Network(_) => false,
+            })
+    }
+
+    /// Whether requests to `host` are sent directly rather than through the egress transports. Onion services are
+    /// only reached through Tor, bypassed or not.
+    pub(crate) fn sends_direct(&self, host: &str) -> bool {
+        self.action == BypassAction::Direct && self.matches(host) && !tor::is_onion(host)
+    }
+}
+
+/// Returns the PAC file sending clients directly to the hosts of `bypass` and through the proxy at `proxy`, as
+/// `host:port`, to the others.
+pub(crate) fn pac(bypass: Option<&Bypass>, proxy: &str, fallback_direct: bool) -> String {
+    let mut conditions = Vec::new();
+    let patterns = bypass.map_or(&[][..], |bypass| bypass.patterns.as_slice());
+    if bypass.is_some_and(|bypass| bypass.plain_hostnames) {
+        conditions.push("!ipv6 && isPlainHostName(host)".to_string());
+    }
+    for pattern in patterns {
+        let condition = match pattern {
+            Pattern::Host(pattern) if pattern == "*" => "true".to_string(),
+            Pattern::Host(pattern) if pattern.contains('*') => {
+                format!("shExpMatch(host, \"{}\")", pattern)
+            }
+            Pattern::Host(pattern) => format!("host == \"{}\"", pattern),
+            Pattern::Domain(domain) => format!(
+                "host == \"{}\" || dnsDomainIs(host, \".{}\")",
+                domain, domain
+            ),
+            // Only IP literals are checked, as isInNet resolves host names
+            Pattern::Network(network) => match network.address() {
+                IpAddr::V4(address) => {
+                    let mask = u32::MAX
+                        .checked_shl(32 - u32::from(network.prefix_len()))
+                        .unwrap_or(0);
+                    format!(
+                        "ipv4 && isInNet(host, \"{}\", \"{}\")",
+                        address,
+                        std::net::Ipv4Addr::from(mask)
+                    )
+                }
+                IpAddr::V6(address) => format!(
+                    "ipv6 && typeof isInNetEx == \"function\" && isInNetEx(host, \"{}/{}\")",
+                    address,
+                    network.prefix_len()
+                ),
+            },
+        };
+        conditions.push(condition);
+    }
+    let mut pac = String::from("function FindProxyForURL(url, host) {\n");
+    pac.push_str("    host = host.toLowerCase().replace(/^\\[|\\]$/g, \"\");\n");
+    pac.push_str("    var ipv4 = /^\\d+\\.\\d+\\.\\d+\\.\\d+$/.test(host);\n");
+    pac.push_str("    var ipv6 = host.indexOf(\":\") >= 0;\n");
+    for condition in conditions {
+        pac.push_str(&format!("    if ({}) return \"DIRECT\";\n", condition));
+    }
+    let fallback = if fallback_direct { "; DIRECT" } else { "" };
+    pac.push_str(&format!(
+        "    return \"PROXY {}{}\";\n}}\n",
+        proxy, fallback
+    ));
+    pac
+}
+
+/// Returns the address of the proxy in the PAC file: the configured one, or `host`, the `Host` header of the request
+/// for the PAC file. Returns `None` when it is missing or not a valid `host:port`, which must not reach the script.
+pub(crate) fn pac_proxy(config: &WpadConfig, host: Option<&str>) -> Option<String> {
+    let proxy = config.proxy.as_deref().or(host)?;
+    let valid = !proxy.is_empty()
+        && proxy
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-._:[]".contains(&byte));
+    valid.then(|| proxy.to_string())
+}
@@ -0,0 +1,66 @@
+//! Storage backends for cached data.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A key-value store with per-entry expiry.
+///
+/// Implementations must be safe to share between connections; they are called from the request path and must not block for long.
+pub trait CacheBackend: Send + Sync {
+    /// Returns the value stored under `key`, if present and not expired.
+    fn get(&self, key: &str) -> Option<Vec<u8>>;
+    /// Stores `value` under `key` for `ttl`.
+    fn put(&self, key: &str, value: Vec<u8>, ttl: Duration);
+    /// Removes the value stored under `key`.
+    fn remove(&self, key: &str);
+}
+
+/// In-memory cache backend.
+#[derive(Default)]
+pub struct MemoryCache {
+    entries: Mutex<HashMap<String, (Instant, Vec<u8>)>>,
+}
+
+impl MemoryCache {
+    /// Creates an empty in-memory cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes every expired entry.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, (expires, _)| *expires > now);
+    }
+}
+
+impl CacheBackend for MemoryCache {
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((expires, value)) if *expires > Instant::now() => Some(value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put(&self, key: &str, value: Vec<u8>, ttl: Duration) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), (Instant::now() + ttl, value));
+    }
+
+    fn remove(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
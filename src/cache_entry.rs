@@ -0,0 +1,93 @@
+//! Typed keys and entries of the response cache, for applications embedding the proxy that pre-populate or purge it
+//! through `ProxyState::cache_put`, `ProxyState::cache_get` and `ProxyState::cache_invalidate_prefix`.
+
+use anyhow::{bail, Context, Result};
+use hyper::{header::HeaderValue, StatusCode, Uri};
+
+use crate::{admission, chunks::CachedBody};
+
+/// Identifies a cached response: the URL of its request, in the cache namespace of a tenant or in the shared cache.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    namespace: Option<String>,
+    url: String,
+}
+
+impl CacheKey {
+    /// Creates the key of the response to a request for `url` in the shared cache. The URL is the request target as
+    /// the proxy receives it: absolute in forward-proxy mode, such as `http://example.com/a?b=1`, and a path with its
+    /// query in reverse-proxy mode.
+    ///
+    /// Fails if `url` is not a valid request target.
+    pub fn new(url: impl Into<String>) -> Result<Self> {
+        let url = url.into();
+        url.parse::<Uri>()
+            .context(format!("Invalid cache key URL: {}", url))?;
+        Ok(CacheKey {
+            namespace: None,
+            url,
+        })
+    }
+
+    /// Moves the key to the cache namespace of a tenant.
+    ///
+    /// Fails if `namespace` is empty or contains whitespace, which tenant namespaces never do.
+    pub fn in_namespace(mut self, namespace: impl Into<String>) -> Result<Self> {
+        let namespace = namespace.into();
+        if namespace.is_empty() || namespace.contains(char::is_whitespace) {
+            bail!("Invalid cache namespace: {:?}", namespace);
+        }
+        self.namespace = Some(namespace);
+        Ok(self)
+    }
+
+    /// Returns the cache namespace of the key, or `None` in the shared cache.
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /// Returns the URL of the key.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the key an entry is stored under, for the key URL `url`.
+    pub(crate) fn stored(&self, url: &str) -> String {
+        admission::cache_key(self.namespace.as_deref(), url)
+    }
+
+    /// Returns the key of the entry stored under `key`.
+    pub(crate) fn from_stored(key: &str) -> Self {
+        match admission::key_namespace(key) {
+            Some(namespace) => CacheKey {
+                namespace: Some(namespace.to_string()),
+                url: key[namespace.len() + 1..].to_string(),
+            },
+            None => CacheKey {
+                namespace: None,
+                url: key.to_string(),
+            },
+        }
+    }
+
+    /// Whether the entry stored under `key` is in the namespace of this key, with a URL starting with its URL.
+    pub(crate) fn prefixes(&self, key: &str) -> bool {
+        let (namespace, url) = match admission::key_namespace(key) {
+            Some(namespace) => (Some(namespace), &key[namespace.len() + 1..]),
+            None => (None, key),
+        };
+        namespace == self.namespace.as_deref() && url.starts_with(&self.url)
+    }
+}
+
+/// A cached response.
+#[derive(Clone, Debug)]
+pub enum CacheEntry {
+    /// A response served with `200 OK` and this body.
+    Body(CachedBody),
+    /// A permanent redirect, served with this status and `Location` header.
+    Redirect {
+        status: StatusCode,
+        location: HeaderValue,
+    },
+}
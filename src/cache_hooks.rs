@@ -0,0 +1,82 @@
+//! Hooks observing the lifecycle of cached responses, for applications embedding the proxy that tier the cache,
+//! warm an external store or emit their own metrics.
+//!
+//! Hooks are called on the request path, once the cache is unlocked, so they may read the cache through
+//! `ProxyState::cache_get`. They must return quickly and never block: anything slow, such as writing to another store,
+//! belongs in a task they spawn or a channel they feed.
+
+use std::sync::{Arc, Mutex};
+
+use crate::cache_entry::CacheKey;
+
+/// Why an entry left the cache. Response bodies have no time to live, so they stay cached until one of these.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// Evicted, the least requested of a sample, to make room for another entry within the total size limit or the
+    /// namespace quota.
+    Capacity,
+    /// Evicted, the least requested of a sample, to keep the cache within the memory budget.
+    Memory,
+    /// Replaced by a newer response under the same key.
+    Replaced,
+    /// Removed by a flush or an invalidation.
+    Invalidated,
+    /// Dropped after one of its chunks failed its checksum.
+    Corrupt,
+}
+
+/// Callbacks invoked as response bodies enter, are served from, and leave the cache. Sizes are the bytes the entries
+/// take in the cache, less than the size of their bodies once compressed.
+pub trait CacheHook: Send + Sync {
+    /// Called once a response body of `size` bytes is stored under `key`.
+    fn on_insert(&self, _key: &CacheKey, _size: usize) {}
+
+    /// Called once the entry of `size` bytes under `key` left the cache for `reason`.
+    fn on_evict(&self, _key: &CacheKey, _size: usize, _reason: EvictionReason) {}
+
+    /// Called when a request is served the entry of `size` bytes under `key`.
+    fn on_hit(&self, _key: &CacheKey, _size: usize) {}
+}
+
+/// The registered cache hooks.
+#[derive(Default)]
+pub struct CacheHooks {
+    hooks: Mutex<Vec<Arc<dyn CacheHook>>>,
+}
+
+impl CacheHooks {
+    /// Registers `hook`, called for every later cache event.
+    pub fn add_hook(&self, hook: Arc<dyn CacheHook>) {
+        self.hooks.lock().unwrap().push(hook);
+    }
+
+    /// Calls `event` on every hook with the key of the entry stored under `key`, unless none is registered.
+    fn notify(&self, key: &str, event: impl Fn(&dyn CacheHook, &CacheKey)) {
+        // The hooks are called without holding the lock, so they may register more hooks
+        let hooks = self.hooks.lock().unwrap().clone();
+        if hooks.is_empty() {
+            return;
+        }
+        let key = CacheKey::from_stored(key);
+        for hook in hooks {
+            event(hook.as_ref(), &key);
+        }
+    }
+
+    /// Reports the insertion of an entry of `size` bytes under `key`.
+    pub(crate) fn inserted(&self, key: &str, size: usize) {
+        self.notify(key, |hook, key| hook.on_insert(key, size));
+    }
+
+    /// Reports the evictions of `evicted`, entries with their sizes, for `reason`.
+    pub(crate) fn evicted(&self, evicted: &[(String, usize)], reason: EvictionReason) {
+        for (key, size) in evicted {
+            self.notify(key, |hook, key| hook.on_evict(key, *size, reason));
+        }
+    }
+
+    /// Reports a hit on the entry of `size` bytes under `key`.
+    pub(crate) fn hit(&self, key: &str, size: usize) {
+        self.notify(key, |hook, key| hook.on_hit(key, size));
+    }
+}
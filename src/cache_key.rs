@@ -0,0 +1,88 @@
+//! Per-route choice of the query parameters taking part in cache keys, so URLs differing only by irrelevant
+//! parameters share a cache entry.
+
+use hyper::Uri;
+
+use crate::{tenant::strip_port, tunnel};
+
+/// Which query parameters take part in the cache keys of a route.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum QueryKey {
+    /// Every parameter, as sent.
+    #[default]
+    All,
+    /// Only the parameters with these names.
+    Include(Vec<String>),
+    /// Every parameter except those with these names.
+    Exclude(Vec<String>),
+    /// No parameter; the query is left out of the key.
+    Ignore,
+}
+
+/// Cache key settings of a route.
+#[derive(Clone, Debug)]
+pub struct CacheKeyRule {
+    /// Path prefix of the requests the rule applies to; `/` matches every request.
+    pub path_prefix: String,
+    /// Host name, or wildcard such as `*.example.com`, the rule is restricted to. Applies to every host when `None`.
+    pub host: Option<String>,
+    /// Query parameters taking part in the cache keys.
+    pub query: QueryKey,
+}
+
+impl Default for CacheKeyRule {
+    fn default() -> Self {
+        Self {
+            path_prefix: "/".to_string(),
+            host: None,
+            query: QueryKey::All,
+        }
+    }
+}
+
+/// Cache key settings.
+#[derive(Clone, Debug, Default)]
+pub struct CacheKeyConfig {
+    /// Rules evaluated in order; the first rule matching a request applies. Requests matching no rule are keyed by
+    /// their whole URL.
+    pub rules: Vec<CacheKeyRule>,
+}
+
+impl CacheKeyConfig {
+    /// Returns the URL identifying the cached response of a request for `uri` on `host`, with only the query
+    /// parameters chosen by the matching rule.
+    pub fn key_url(&self, host: Option<&str>, uri: &Uri) -> String {
+        let url = uri.to_string();
+        let host = host.map(strip_port);
+        let rule = self.rules.iter().find(|rule| {
+            uri.path().starts_with(&rule.path_prefix)
+                && rule.host.as_deref().is_none_or(|pattern| {
+                    host.is_some_and(|host| tunnel::host_matches(pattern, host))
+                })
+        });
+        let query_key = match rule {
+            Some(rule) if rule.query != QueryKey::All => &rule.query,
+            _ => return url,
+        };
+        let (base, query) = match url.split_once('?') {
+            Some(split) => split,
+            None => return url,
+        };
+        let params: Vec<&str> = query
+            .split('&')
+            .filter(|param| {
+                let name = param.split('=').next().unwrap_or(param);
+                match query_key {
+                    QueryKey::Include(names) => names.iter().any(|kept| kept == name),
+                    QueryKey::Exclude(names) => !names.iter().any(|dropped| dropped == name),
+                    QueryKey::All => true,
+                    QueryKey::Ignore => false,
+                }
+            })
+            .collect();
+        if params.is_empty() {
+            return base.to_string();
+        }
+        format!("{}?{}", base, params.join("&"))
+    }
+}
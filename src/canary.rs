@@ -0,0 +1,187 @@
+//! Canary traffic splitting with sticky assignment through a signed cookie.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use anyhow::{bail, Result};
+use hyper::HeaderMap;
+use log::info;
+use rand::Rng;
+use ring::hmac;
+
+use crate::{secret::SecretSource, session::read_cookie};
+
+/// Canary traffic splitting settings.
+#[derive(Clone)]
+pub struct CanaryConfig {
+    /// Target address requests of the canary bucket are forwarded to.
+    pub target: String,
+    /// Percentage of new clients assigned to the canary bucket, between `0` and `100`.
+    pub percent: u8,
+    /// Name of the cookie carrying the assignment.
+    pub cookie_name: String,
+    /// Secret used to sign the assignment cookie so clients cannot pick their bucket. Required, and must not be empty.
+    pub secret: Option<SecretSource>,
+}
+
+impl Default for CanaryConfig {
+    fn default() -> Self {
+        Self {
+            target: String::new(),
+            percent: 0,
+            cookie_name: "fortifynet_canary".to_string(),
+            secret: None,
+        }
+    }
+}
+
+impl fmt::Debug for CanaryConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CanaryConfig")
+            .field("target", &self.target)
+            .field("percent", &self.percent)
+            .field("cookie_name", &self.cookie_name)
+            .field("secret", &self.secret)
+            .finish()
+    }
+}
+
+/// Bucket a client is assigned to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CanaryBucket {
+    /// Requests go to the configured target address.
+    Stable,
+    /// Requests go to the canary target.
+    Canary,
+}
+
+impl CanaryBucket {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CanaryBucket::Stable => "stable",
+            CanaryBucket::Canary => "canary",
+        }
+    }
+}
+
+/// Result of assigning a request to a bucket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CanaryAssignment {
+    /// Bucket of the request.
+    pub bucket: CanaryBucket,
+    /// `Set-Cookie` value to send back when the client was (re)assigned.
+    pub set_cookie: Option<String>,
+}
+
+/// Splits clients between the stable and canary targets.
+pub struct CanarySplitter {
+    config: CanaryConfig,
+    percent: AtomicU8,
+    key: hmac::Key,
+}
+
+impl CanarySplitter {
+    /// Creates a splitter for the given settings, failing if its secret is missing, cannot be read or is empty.
+    pub fn new(config: CanaryConfig) -> Result<Self> {
+        let secret = match &config.secret {
+            Some(secret) => secret.read()?,
+            None => String::new(),
+        };
+        if secret.is_empty() {
+            bail!("Canary traffic splitting requires a secret to sign the assignment cookie");
+        }
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        Ok(CanarySplitter {
+            percent: AtomicU8::new(config.percent.min(100)),
+            config,
+            key,
+        })
+    }
+
+    /// Returns the canary target address.
+    pub fn target(&self) -> &str {
+        &self.config.target
+    }
+
+    /// Returns the percentage of new clients assigned to the canary bucket.
+    pub fn percent(&self) -> u8 {
+        self.percent.load(Ordering::Relaxed)
+    }
+
+    /// Changes the percentage of new clients assigned to the canary bucket.
+    ///
+    /// Clients already assigned keep their bucket, except that setting `0` moves every client back to stable.
+    pub fn set_percent(&self, percent: u8) {
+        let percent = percent.min(100);
+        self.percent.store(percent, Ordering::Relaxed);
+        info!("Canary traffic set to {}%", percent);
+    }
+
+    /// Assigns a request to a bucket, honoring a valid assignment cookie.
+    pub fn assign(&self, headers: &HeaderMap) -> CanaryAssignment {
+        let percent = self.percent();
+        let existing = read_cookie(headers, &self.config.cookie_name)
+            .and_then(|value| self.verify(&value));
+        match existing {
+            Some(CanaryBucket::Canary) if percent == 0 => {}
+            Some(bucket) => {
+                return CanaryAssignment {
+                    bucket,
+                    set_cookie: None,
+                }
+            }
+            None => {}
+        }
+        let bucket = if rand::thread_rng().gen_range(0..100) < percent {
+            CanaryBucket::Canary
+        } else {
+            CanaryBucket::Stable
+        };
+        CanaryAssignment {
+            bucket,
+            set_cookie: Some(format!(
+                "{}={}; Path=/; HttpOnly",
+                self.config.cookie_name,
+                self.sign(bucket)
+            )),
+        }
+    }
+
+    /// Returns the cookie value `bucket.nonce.signature` for `bucket`.
+    fn sign(&self, bucket: CanaryBucket) -> String {
+        let nonce: u64 = rand::thread_rng().gen();
+        let payload = format!("{}.{:016x}", bucket.as_str(), nonce);
+        let tag = hmac::sign(&self.key, payload.as_bytes());
+        format!("{}.{}", payload, hex(tag.as_ref()))
+    }
+
+    /// Returns the bucket of a cookie value if its signature is valid.
+    fn verify(&self, value: &str) -> Option<CanaryBucket> {
+        let (payload, signature) = value.rsplit_once('.')?;
+        let signature = unhex(signature)?;
+        hmac::verify(&self.key, payload.as_bytes(), &signature).ok()?;
+        match payload.split('.').next()? {
+            "stable" => Some(CanaryBucket::Stable),
+            "canary" => Some(CanaryBucket::Canary),
+            _ => None,
+        }
+    }
+}
+
+/// Encodes bytes as lowercase hexadecimal.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes hexadecimal, in either case.
+pub(crate) fn unhex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
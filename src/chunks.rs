@@ -0,0 +1,434 @@
+//! Chunked storage of large cached bodies, in memory or on disk.
+//!
+//! Bodies above a size threshold are split into fixed-size chunks, each with a CRC-32 checksum verified whenever it
+//! is read, so range requests and streaming from the cache only touch the chunks they need and never require one
+//! contiguous buffer. A body whose chunk fails its checksum is marked corrupt and fetched again by the next request.
+//! With cache compression enabled, every chunk is compressed on its own, so ranges still only decompress the chunks
+//! they need.
+
+use std::{
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{Context, Result};
+use futures::stream;
+use hyper::{body::Bytes, Body};
+use log::{error, warn};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::compression::{self, CacheCompressor, CompressionAlgorithm};
+
+/// Extension of the files holding bodies stored on disk.
+const CHUNK_FILE_EXTENSION: &str = "chunks";
+
+/// Chunked storage settings.
+#[derive(Clone, Debug)]
+pub struct ChunkedStorageConfig {
+    /// Bodies of at least this many bytes are stored in chunks. Defaults to 1 MiB.
+    pub threshold: usize,
+    /// Size of the chunks, in bytes. Defaults to 256 KiB.
+    pub chunk_size: usize,
+    /// Directory the chunks are stored in, emptied of leftover chunk files at startup. The chunks are kept in memory
+    /// when `None`, the default.
+    pub directory: Option<PathBuf>,
+}
+
+impl Default for ChunkedStorageConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 1024 * 1024,
+            chunk_size: 256 * 1024,
+            directory: None,
+        }
+    }
+}
+
+/// A cached response body.
+#[derive(Clone, Debug)]
+pub enum CachedBody {
+    /// A body stored in one buffer.
+    Inline(Bytes),
+    /// A large or compressed body stored in chunks.
+    Chunked(Arc<ChunkedBody>),
+}
+
+impl From<Vec<u8>> for CachedBody {
+    fn from(body: Vec<u8>) -> Self {
+        CachedBody::Inline(Bytes::from(body))
+    }
+}
+
+impl From<Bytes> for CachedBody {
+    fn from(body: Bytes) -> Self {
+        CachedBody::Inline(body)
+    }
+}
+
+impl CachedBody {
+    /// Returns `body` for the cache, compressed as one chunk when it is large enough for `compressor`.
+    pub(crate) fn compress(body: Bytes, compressor: Option<&CacheCompressor>) -> Self {
+        match compressor.filter(|compressor| compressor.applies_to(body.len())) {
+            Some(compressor) => {
+                let (mut chunks, stored) = ChunkedBody::split(&body, body.len(), Some(compressor));
+                keep_in_memory(&mut chunks, stored);
+                CachedBody::Chunked(Arc::new(ChunkedBody {
+                    len: body.len(),
+                    chunk_size: body.len(),
+                    chunks,
+                    file: None,
+                    compression: Some(compressor.algorithm()),
+                    corrupt: AtomicBool::new(false),
+                }))
+            }
+            None => CachedBody::Inline(body),
+        }
+    }
+
+    /// Returns the size of the body, in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            CachedBody::Inline(body) => body.len(),
+            CachedBody::Chunked(body) => body.len,
+        }
+    }
+
+    /// Whether the body is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the space taken by the body in the cache, in bytes, which is less than its size once compressed.
+    pub fn stored_len(&self) -> usize {
+        match self {
+            CachedBody::Inline(body) => body.len(),
+            CachedBody::Chunked(body) => body.chunks.iter().map(|chunk| chunk.stored_len).sum(),
+        }
+    }
+
+    /// Returns the memory taken by the body, in bytes, leaving out the chunks stored on disk.
+    pub fn memory_len(&self) -> usize {
+        match self {
+            CachedBody::Inline(body) => body.len(),
+            CachedBody::Chunked(body) => body
+                .chunks
+                .iter()
+                .filter_map(|chunk| chunk.data.as_ref())
+                .map(Bytes::len)
+                .sum(),
+        }
+    }
+
+    /// Whether a chunk of the body failed its checksum.
+    pub fn is_corrupt(&self) -> bool {
+        match self {
+            CachedBody::Inline(_) => false,
+            CachedBody::Chunked(body) => body.corrupt.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reads the whole body, verifying its chunks. Chunks stored on disk are read synchronously.
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        match self {
+            CachedBody::Inline(body) => Ok(body.to_vec()),
+            CachedBody::Chunked(body) => {
+                let file = match &body.file {
+                    Some(file) => Some(std::fs::read(&file.path)?),
+                    None => None,
+                };
+                let mut output = Vec::with_capacity(body.len);
+                for (index, chunk) in body.chunks.iter().enumerate() {
+                    let data = match &file {
+                        Some(file) => {
+                            let start = chunk.offset as usize;
+                            let data =
+                                file.get(start..start + chunk.stored_len).ok_or_else(|| {
+                                    io::Error::new(
+                                        io::ErrorKind::UnexpectedEof,
+                                        "Truncated chunk file",
+                                    )
+                                })?;
+                            Bytes::copy_from_slice(data)
+                        }
+                        None => chunk.data.clone().unwrap_or_default(),
+                    };
+                    output.extend_from_slice(&body.decode(index, data)?);
+                }
+                Ok(output)
+            }
+        }
+    }
+
+    /// Returns a response body streaming the whole cached body.
+    pub(crate) fn into_body(self) -> Body {
+        match self {
+            CachedBody::Inline(body) => Body::from(body),
+            CachedBody::Chunked(_) if self.is_empty() => Body::empty(),
+            CachedBody::Chunked(body) => {
+                let last = body.len - 1;
+                body.stream(0, last)
+            }
+        }
+    }
+
+    /// Returns a response body streaming the bytes `first..=last` of the cached body.
+    pub(crate) fn range_body(self, first: usize, last: usize) -> Body {
+        match self {
+            CachedBody::Inline(body) => Body::from(body.slice(first..=last)),
+            CachedBody::Chunked(body) => body.stream(first, last),
+        }
+    }
+}
+
+/// A body stored on disk, removed once no entry refers to it.
+#[derive(Debug)]
+struct ChunkFile {
+    path: PathBuf,
+}
+
+impl Drop for ChunkFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove cached chunks {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// A chunk of a body, with its stored bytes when kept in memory.
+#[derive(Debug)]
+struct Chunk {
+    /// Checksum of the stored bytes.
+    checksum: u32,
+    /// Offset of the stored bytes in the chunk file.
+    offset: u64,
+    stored_len: usize,
+    /// Whether the stored bytes are compressed.
+    compressed: bool,
+    data: Option<Bytes>,
+}
+
+/// A large body stored in fixed-size chunks.
+#[derive(Debug)]
+pub struct ChunkedBody {
+    len: usize,
+    chunk_size: usize,
+    chunks: Vec<Chunk>,
+    /// File holding the chunks one after the other, when stored on disk.
+    file: Option<ChunkFile>,
+    /// Algorithm the compressed chunks were compressed with.
+    compression: Option<CompressionAlgorithm>,
+    corrupt: AtomicBool,
+}
+
+impl ChunkedBody {
+    /// Splits `body` into chunks of `chunk_size` bytes, compressed when `compressor` applies to the body, and
+    /// returns them along with their stored bytes.
+    fn split(
+        body: &Bytes,
+        chunk_size: usize,
+        compressor: Option<&CacheCompressor>,
+    ) -> (Vec<Chunk>, Vec<Bytes>) {
+        let compressor = compressor.filter(|compressor| compressor.applies_to(body.len()));
+        let mut chunks = Vec::new();
+        let mut stored = Vec::new();
+        let mut offset = 0;
+        for start in (0..body.len()).step_by(chunk_size) {
+            let chunk = body.slice(start..(start + chunk_size).min(body.len()));
+            let compressed = compressor.and_then(|compressor| compressor.compress(&chunk));
+            let data = compressed.clone().unwrap_or(chunk);
+            chunks.push(Chunk {
+                checksum: crc32fast::hash(&data),
+                offset,
+                stored_len: data.len(),
+                compressed: compressed.is_some(),
+                data: None,
+            });
+            offset += data.len() as u64;
+            stored.push(data);
+        }
+        if let Some(compressor) = compressor {
+            compressor.record(body.len(), offset as usize);
+        }
+        (chunks, stored)
+    }
+
+    /// Returns the content of chunk `index` from its stored bytes `data`, which must match the checksum of the
+    /// chunk. The body is marked corrupt otherwise.
+    fn decode(&self, index: usize, data: Bytes) -> io::Result<Bytes> {
+        let chunk = &self.chunks[index];
+        let decoded = if crc32fast::hash(&data) != chunk.checksum {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Cached chunk failed its checksum",
+            ))
+        } else {
+            match (chunk.compressed, self.compression) {
+                (true, Some(algorithm)) => compression::decompress(algorithm, &data),
+                (true, None) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Cached chunk compressed with an unknown algorithm",
+                )),
+                (false, _) => Ok(data),
+            }
+        };
+        if let Err(err) = &decoded {
+            self.corrupt.store(true, Ordering::Relaxed);
+            error!("Failed to read cached chunk {}: {}", index, err);
+        }
+        decoded
+    }
+
+    /// Reads and decodes chunk `index`, opening the chunk file into `file` on first use.
+    async fn read_chunk(&self, index: usize, file: &mut Option<File>) -> io::Result<Bytes> {
+        let chunk = &self.chunks[index];
+        let data = match (&chunk.data, &self.file) {
+            (Some(data), _) => data.clone(),
+            (None, Some(chunk_file)) => {
+                if file.is_none() {
+                    *file = Some(File::open(&chunk_file.path).await?);
+                }
+                let file = file.as_mut().unwrap();
+                let mut data = vec![0; chunk.stored_len];
+                file.seek(SeekFrom::Start(chunk.offset)).await?;
+                file.read_exact(&mut data).await?;
+                Bytes::from(data)
+            }
+            (None, None) => Bytes::new(),
+        };
+        self.decode(index, data)
+    }
+
+    /// Returns a response body streaming the bytes `first..=last`, one verified chunk at a time. The body fails at
+    /// the first corrupt chunk.
+    fn stream(self: Arc<Self>, first: usize, last: usize) -> Body {
+        let chunk_size = self.chunk_size;
+        let end = last / chunk_size;
+        let chunks = stream::unfold(
+            (self, first / chunk_size, None),
+            move |(body, index, mut file)| async move {
+                if index > end {
+                    return None;
+                }
+                let offset = index * chunk_size;
+                let result = body.read_chunk(index, &mut file).await.map(|chunk| {
+                    // Only the first and last chunks extend beyond the range
+                    let start = first.max(offset) - offset;
+                    let stop = (last + 1).min(offset + chunk.len()) - offset;
+                    chunk.slice(start..stop)
+                });
+                // No chunk is read after a failed one
+                let next = if result.is_ok() {
+                    index + 1
+                } else {
+                    usize::MAX
+                };
+                Some((result, (body, next, file)))
+            },
+        );
+        Body::wrap_stream(chunks)
+    }
+}
+
+/// Stores bodies in chunks once they reach the size threshold.
+pub struct ChunkStore {
+    config: ChunkedStorageConfig,
+    next_file: AtomicU64,
+}
+
+impl ChunkStore {
+    /// Creates the store, creating its directory and removing the chunk files left over from an earlier run.
+    pub fn new(config: ChunkedStorageConfig) -> Result<Self> {
+        if config.chunk_size == 0 {
+            anyhow::bail!("The cache chunk size must not be zero");
+        }
+        if let Some(directory) = &config.directory {
+            std::fs::create_dir_all(directory).context(format!(
+                "Failed to create the cache chunk directory {}",
+                directory.display()
+            ))?;
+            for entry in std::fs::read_dir(directory)?.flatten() {
+                let path = entry.path();
+                if path
+                    .extension()
+                    .is_some_and(|ext| ext == CHUNK_FILE_EXTENSION)
+                {
+                    std::fs::remove_file(&path).ok();
+                }
+            }
+        }
+        Ok(ChunkStore {
+            config,
+            next_file: AtomicU64::new(0),
+        })
+    }
+
+    /// Stores `body`, in chunks if it reaches the threshold and compressed if it is large enough for
+    /// `compressor`. Chunks that cannot be written to disk are kept in memory.
+    pub async fn store(&self, body: Bytes, compressor: Option<&CacheCompressor>) -> CachedBody {
+        if body.len() < self.config.threshold || body.is_empty() {
+            return CachedBody::compress(body, compressor);
+        }
+        let (mut chunks, stored) = ChunkedBody::split(&body, self.config.chunk_size, compressor);
+        let file = match &self.config.directory {
+            Some(directory) => {
+                let id = self.next_file.fetch_add(1, Ordering::Relaxed);
+                let path = directory.join(format!("{:016x}.{}", id, CHUNK_FILE_EXTENSION));
+                match write_chunks(&path, &stored).await {
+                    Ok(()) => Some(ChunkFile { path }),
+                    Err(err) => {
+                        warn!(
+                            "Failed to write cached chunks to {}, keeping them in memory: {}",
+                            path.display(),
+                            err
+                        );
+                        std::fs::remove_file(&path).ok();
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        if file.is_none() {
+            keep_in_memory(&mut chunks, stored);
+        }
+        CachedBody::Chunked(Arc::new(ChunkedBody {
+            len: body.len(),
+            chunk_size: self.config.chunk_size,
+            chunks,
+            file,
+            compression: compressor.map(CacheCompressor::algorithm),
+            corrupt: AtomicBool::new(false),
+        }))
+    }
+}
+
+/// Keeps the stored bytes of the chunks in memory.
+fn keep_in_memory(chunks: &mut [Chunk], stored: Vec<Bytes>) {
+    for (chunk, data) in chunks.iter_mut().zip(stored) {
+        // Uncompressed chunks are copied so each owns its memory, instead of keeping the whole body alive
+        chunk.data = Some(match chunk.compressed {
+            true => data,
+            false => Bytes::copy_from_slice(&data),
+        });
+    }
+}
+
+/// Writes the stored bytes of the chunks one after the other to `path`.
+async fn write_chunks(path: &Path, stored: &[Bytes]) -> io::Result<()> {
+    let mut file = File::create(path).await?;
+    for data in stored {
+        file.write_all(data).await?;
+    }
+    file.flush().await
+}
@@ -0,0 +1,171 @@
+//! Malware scanning of downloaded content with clamd.
+//!
+//! Scanning requires the `clamav` feature; without it, configuring a scanner is an error.
+
+use std::time::Duration;
+
+use anyhow::Result;
+
+/// Address of the clamd daemon.
+#[derive(Clone, Debug)]
+pub enum ClamdAddress {
+    /// A TCP `host:port`, usually port 3310.
+    Tcp(String),
+    /// A Unix socket path such as `/run/clamav/clamd.ctl`.
+    Unix(String),
+}
+
+/// ClamAV scanning settings.
+#[derive(Clone, Debug)]
+pub struct ClamAvConfig {
+    /// Address of clamd.
+    pub address: ClamdAddress,
+    /// Smallest response body scanned, in bytes.
+    pub min_size: usize,
+    /// Largest response body scanned, in bytes; should not exceed clamd's `StreamMaxLength`.
+    pub max_size: usize,
+    /// Whether bodies larger than `max_size` are replaced by `403 Forbidden`; otherwise they pass unscanned. Defaults
+    /// to `true`.
+    pub block_oversized: bool,
+    /// How long a scan may take.
+    pub timeout: Duration,
+    /// HTML page served with `403 Forbidden` in place of infected content; `{signature}` is replaced by the detected signature.
+    pub block_page: String,
+    /// Whether content passes when clamd fails or times out; otherwise it is replaced by `503 Service Unavailable`.
+    pub fail_open: bool,
+}
+
+impl Default for ClamAvConfig {
+    fn default() -> Self {
+        Self {
+            address: ClamdAddress::Tcp("127.0.0.1:3310".to_string()),
+            min_size: 0,
+            max_size: 25 * 1024 * 1024,
+            block_oversized: true,
+            timeout: Duration::from_secs(30),
+            block_page:
+                "<h1>Blocked</h1><p>The requested content contains malware ({signature}).</p>"
+                    .to_string(),
+            fail_open: false,
+        }
+    }
+}
+
+/// Result of scanning content.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScanResult {
+    /// No malware was found.
+    Clean,
+    /// Malware with the given signature was found.
+    Infected(String),
+}
+
+/// Scanner streaming content to clamd.
+pub struct ClamAv {
+    config: ClamAvConfig,
+}
+
+impl ClamAv {
+    /// Creates a scanner for the configured clamd.
+    #[cfg(feature = "clamav")]
+    pub fn new(config: ClamAvConfig) -> Result<Self> {
+        Ok(ClamAv { config })
+    }
+
+    /// Creates a scanner for the configured clamd.
+    #[cfg(not(feature = "clamav"))]
+    pub fn new(_config: ClamAvConfig) -> Result<Self> {
+        anyhow::bail!(
+            "Cannot scan with ClamAV: fortifynet_proxy was built without the `clamav` feature"
+        )
+    }
+
+    /// Returns the scanning settings.
+    pub fn config(&self) -> &ClamAvConfig {
+        &self.config
+    }
+
+    /// Whether a body of `size` bytes is scanned.
+    pub fn should_scan(&self, size: usize) -> bool {
+        size >= self.config.min_size && size <= self.config.max_size
+    }
+
+    /// Returns the block page for content infected with `signature`.
+    pub fn block_page(&self, signature: &str) -> String {
+        let escaped = signature
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+        self.config.block_page.replace("{signature}", &escaped)
+    }
+
+    /// Scans `content` with the clamd `INSTREAM` command.
+    #[cfg(feature = "clamav")]
+    pub async fn scan(&self, content: &[u8]) -> Result<ScanResult> {
+        use anyhow::Context;
+        use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+        /// Size of the chunks streamed to clamd.
+        const CHUNK_SIZE: usize = 64 * 1024;
+
+        async fn instream<S: AsyncRead + AsyncWrite + Unpin>(
+            mut stream: S,
+            content: &[u8],
+        ) -> Result<String> {
+            stream.write_all(b"zINSTREAM\0").await?;
+            for chunk in content.chunks(CHUNK_SIZE) {
+                stream
+                    .write_all(&(chunk.len() as u32).to_be_bytes())
+                    .await?;
+                stream.write_all(chunk).await?;
+            }
+            stream.write_all(&0u32.to_be_bytes()).await?;
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).await?;
+            Ok(String::from_utf8_lossy(&reply)
+                .trim_end_matches(['\0', '\n'])
+                .to_string())
+        }
+
+        let scan = async {
+            match &self.config.address {
+                ClamdAddress::Tcp(addr) => {
+                    let stream = tokio::net::TcpStream::connect(addr)
+                        .await
+                        .context(format!("Failed to connect to clamd at {}", addr))?;
+                    instream(stream, content).await
+                }
+                #[cfg(unix)]
+                ClamdAddress::Unix(path) => {
+                    let stream = tokio::net::UnixStream::connect(path)
+                        .await
+                        .context(format!("Failed to connect to clamd at {}", path))?;
+                    instream(stream, content).await
+                }
+                #[cfg(not(unix))]
+                ClamdAddress::Unix(path) => {
+                    anyhow::bail!("Unix sockets are not supported on this platform: {}", path)
+                }
+            }
+        };
+        let reply = tokio::time::timeout(self.config.timeout, scan)
+            .await
+            .context("clamd timed out")??;
+
+        // Replies look like `stream: OK` or `stream: Eicar-Signature FOUND`
+        let verdict = reply.strip_prefix("stream: ").unwrap_or(&reply);
+        if verdict == "OK" {
+            Ok(ScanResult::Clean)
+        } else if let Some(signature) = verdict.strip_suffix(" FOUND") {
+            Ok(ScanResult::Infected(signature.to_string()))
+        } else {
+            anyhow::bail!("clamd failed to scan: {}", reply)
+        }
+    }
+
+    /// Scans `content` with the clamd `INSTREAM` command.
+    #[cfg(not(feature = "clamav"))]
+    pub async fn scan(&self, _content: &[u8]) -> Result<ScanResult> {
+        Ok(ScanResult::Clean)
+    }
+}
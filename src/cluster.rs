@@ -0,0 +1,389 @@
+//! Cluster mode: proxy instances periodically report their metrics to a designated aggregator instance, whose
+//! dashboard shows fleet-wide totals and the health of every instance.
+//!
+//! Every instance has an identity carried by each of its reports. An instance not heard from for three report
+//! intervals is shown as stale, keeping its last totals, and is forgotten after thirty. Reports carry a shared token,
+//! without which the aggregator rejects them.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use hyper::{
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Client, Request, StatusCode, Uri,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tokio::sync::oneshot;
+use warp::Filter;
+
+use crate::secret::SecretSource;
+
+/// Largest report accepted by the aggregator, in bytes.
+const MAX_REPORT_SIZE: u64 = 64 * 1024;
+/// Number of report intervals without a report after which an instance is stale.
+const STALE_AFTER_INTERVALS: u32 = 3;
+/// Number of report intervals without a report after which an instance is forgotten.
+const FORGET_AFTER_INTERVALS: u32 = 30;
+/// Largest number of instances tracked by the aggregator; reports of further instances are rejected.
+const MAX_INSTANCES: usize = 1000;
+
+/// Cluster mode settings.
+#[derive(Clone)]
+pub struct ClusterConfig {
+    /// Identity of this instance in the reports. Defaults to the host name.
+    pub instance: String,
+    /// URL of the aggregator's report listener, such as `http://10.0.0.1:9090`, to which this instance reports.
+    pub aggregator: Option<String>,
+    /// Address on which this instance accepts the reports of the other instances, making it the aggregator.
+    pub listen: Option<SocketAddr>,
+    /// Interval between reports. Defaults to 10 seconds.
+    pub report_interval: Duration,
+    /// Shared secret the instances send as a bearer token, without which reports are rejected. Cluster mode refuses
+    /// to start without one.
+    pub token: Option<SecretSource>,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        let instance = std::env::var("HOSTNAME")
+            .ok()
+            .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .unwrap_or("fortifynet".to_string());
+        Self {
+            instance,
+            aggregator: None,
+            listen: None,
+            report_interval: Duration::from_secs(10),
+            token: None,
+        }
+    }
+}
+
+impl fmt::Debug for ClusterConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClusterConfig")
+            .field("instance", &self.instance)
+            .field("aggregator", &self.aggregator)
+            .field("listen", &self.listen)
+            .field("report_interval", &self.report_interval)
+            .field("token", &self.token)
+            .finish()
+    }
+}
+
+/// Metrics reported by an instance.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct InstanceReport {
+    /// Identity of the instance.
+    pub instance: String,
+    /// Total number of requests handled by the instance.
+    pub total_requests: u64,
+    /// Total number of cache hits.
+    pub cache_hits: u64,
+    /// Total number of cache misses.
+    pub cache_misses: u64,
+    /// Total number of responses with an error status.
+    pub errors: u64,
+    /// Average response time, in milliseconds.
+    pub average_response_ms: f64,
+    /// Upstreams the instance considers unhealthy.
+    pub unhealthy_upstreams: Vec<String>,
+    /// Upstreams whose SLO the instance sees breached.
+    pub breached_slos: Vec<String>,
+    /// Time since the instance started, in seconds.
+    pub uptime_secs: u64,
+}
+
+/// Last report of an instance, as seen by the aggregator.
+#[derive(Clone, Debug, Serialize)]
+pub struct InstanceStatus {
+    /// The last report.
+    #[serde(flatten)]
+    pub report: InstanceReport,
+    /// Time since the last report, in seconds.
+    pub last_seen_secs: u64,
+    /// Whether the instance stopped reporting.
+    pub stale: bool,
+    /// Whether the instance reports recently, with healthy upstreams and no breached SLO.
+    pub healthy: bool,
+}
+
+/// Totals over the instances of the cluster.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ClusterTotals {
+    /// Number of instances that reported.
+    pub instances: usize,
+    /// Number of healthy instances.
+    pub healthy_instances: usize,
+    /// Total number of requests.
+    pub total_requests: u64,
+    /// Total number of cache hits.
+    pub cache_hits: u64,
+    /// Total number of cache misses.
+    pub cache_misses: u64,
+    /// Ratio of cache hits among the cache lookups.
+    pub cache_hit_rate: f64,
+    /// Total number of responses with an error status.
+    pub errors: u64,
+}
+
+/// Fleet-wide view of the cluster.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ClusterView {
+    /// Totals over the instances.
+    pub totals: ClusterTotals,
+    /// Every instance that reported, sorted by identity.
+    pub instances: Vec<InstanceStatus>,
+}
+
+/// Membership of this instance in a cluster, and the reports received when it is the aggregator.
+pub struct Cluster {
+    config: ClusterConfig,
+    token: String,
+    started: Instant,
+    reports: Mutex<HashMap<String, (InstanceReport, Instant)>>,
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+}
+
+impl Cluster {
+    /// Creates the membership, failing on an empty identity, a missing, unreadable or empty token or an invalid
+    /// aggregator URL.
+    pub fn new(config: ClusterConfig) -> Result<Self> {
+        if config.instance.is_empty() {
+            anyhow::bail!("The cluster instance identity must not be empty");
+        }
+        let token = match &config.token {
+            Some(token) => token.read()?,
+            None => String::new(),
+        };
+        if token.is_empty() {
+            anyhow::bail!("Cluster mode requires a token");
+        }
+        if let Some(aggregator) = &config.aggregator {
+            aggregator
+                .parse::<Uri>()
+                .context(format!("Invalid cluster aggregator URL: {}", aggregator))?;
+        }
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(Cluster {
+            config,
+            token,
+            started: Instant::now(),
+            reports: Mutex::new(HashMap::new()),
+            client: Client::builder().build(connector),
+        })
+    }
+
+    /// Returns the identity of this instance.
+    pub fn instance(&self) -> &str {
+        &self.config.instance
+    }
+
+    /// Returns the time since this instance started.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Records the report of an instance, replacing its previous one, and forgets the instances long silent. Returns
+    /// `false` when the report of a new instance is rejected because as many instances as allowed are tracked.
+    pub fn receive(&self, report: InstanceReport) -> bool {
+        let forget_after = self.config.report_interval * FORGET_AFTER_INTERVALS;
+        let mut reports = self.reports.lock().unwrap();
+        reports.retain(|instance, (_, received)| {
+            let forgotten = received.elapsed() > forget_after;
+            if forgotten {
+                info!("Instance {} left the cluster", instance);
+            }
+            !forgotten
+        });
+        if !reports.contains_key(&report.instance) {
+            if reports.len() >= MAX_INSTANCES {
+                return false;
+            }
+            info!("Instance {} joined the cluster", report.instance);
+        }
+        reports.insert(report.instance.clone(), (report, Instant::now()));
+        true
+    }
+
+    /// Returns the fleet-wide totals and the status of every instance that reported.
+    pub fn view(&self) -> ClusterView {
+        let stale_after = self.config.report_interval * STALE_AFTER_INTERVALS;
+        let mut instances: Vec<InstanceStatus> = self
+            .reports
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(report, received)| {
+                let stale = received.elapsed() > stale_after;
+                InstanceStatus {
+                    healthy: !stale
+                        && report.unhealthy_upstreams.is_empty()
+                        && report.breached_slos.is_empty(),
+                    report: report.clone(),
+                    last_seen_secs: received.elapsed().as_secs(),
+                    stale,
+                }
+            })
+            .collect();
+        instances.sort_by(|a, b| a.report.instance.cmp(&b.report.instance));
+
+        let mut totals = ClusterTotals {
+            instances: instances.len(),
+            ..Default::default()
+        };
+        for status in &instances {
+            totals.healthy_instances += usize::from(status.healthy);
+            totals.total_requests += status.report.total_requests;
+            totals.cache_hits += status.report.cache_hits;
+            totals.cache_misses += status.report.cache_misses;
+            totals.errors += status.report.errors;
+        }
+        let lookups = totals.cache_hits + totals.cache_misses;
+        if lookups > 0 {
+            totals.cache_hit_rate = totals.cache_hits as f64 / lookups as f64;
+        }
+        ClusterView { totals, instances }
+    }
+
+    /// Starts reporting the snapshots taken by `report` every interval, and accepting the reports of the other
+    /// instances when this instance is the aggregator. Both stop once `report` returns `None`.
+    pub(crate) fn start<F>(self: &Arc<Self>, report: F)
+    where
+        F: Fn() -> Option<InstanceReport> + Send + 'static,
+    {
+        let (stop, stopped) = oneshot::channel::<()>();
+        if let Some(listen) = self.config.listen {
+            let cluster = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = cluster.serve(listen, stopped).await {
+                    error!("Cluster report listener failed: {}", err);
+                }
+            });
+        }
+        let cluster = self.clone();
+        tokio::spawn(async move {
+            // Dropped when reporting stops, which shuts the listener down
+            let _stop = stop;
+            let mut interval = tokio::time::interval(cluster.config.report_interval);
+            loop {
+                interval.tick().await;
+                let report = match report() {
+                    Some(report) => report,
+                    None => return,
+                };
+                // The aggregator counts itself in the fleet
+                if cluster.config.listen.is_some() {
+                    cluster.receive(report.clone());
+                }
+                if let Some(aggregator) = &cluster.config.aggregator {
+                    if let Err(err) = cluster.send(aggregator, &report).await {
+                        warn!(
+                            "Failed to report to cluster aggregator {}: {}",
+                            aggregator, err
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    /// Sends a report to the aggregator.
+    async fn send(&self, aggregator: &str, report: &InstanceReport) -> Result<()> {
+        let request = Request::post(format!(
+            "{}/cluster/report",
+            aggregator.trim_end_matches('/')
+        ))
+        .header(CONTENT_TYPE, "application/json")
+        .header(AUTHORIZATION, format!("Bearer {}", self.token))
+        .body(Body::from(serde_json::to_vec(report)?))?;
+        let response =
+            tokio::time::timeout(self.config.report_interval, self.client.request(request))
+                .await
+                .context("Timed out")??;
+        if !response.status().is_success() {
+            anyhow::bail!("Aggregator answered {}", response.status());
+        }
+        debug!("Reported to cluster aggregator {}", aggregator);
+        Ok(())
+    }
+
+    /// Accepts reports on `POST /cluster/report` and serves the fleet view on `GET /cluster`.
+    async fn serve(
+        self: Arc<Self>,
+        listen: SocketAddr,
+        stopped: oneshot::Receiver<()>,
+    ) -> Result<()> {
+        let report_cluster = self.clone();
+        let report_route = warp::post()
+            .and(warp::path!("cluster" / "report"))
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::body::content_length_limit(MAX_REPORT_SIZE))
+            .and(warp::body::json())
+            .map(
+                move |authorization: Option<String>, report: InstanceReport| {
+                    if !report_cluster.authorized(authorization.as_deref()) {
+                        warn!(
+                            "Rejected cluster report without a valid token from {}",
+                            report.instance
+                        );
+                        return StatusCode::UNAUTHORIZED;
+                    }
+                    debug!("Received cluster report from {}", report.instance);
+                    let instance = report.instance.clone();
+                    if !report_cluster.receive(report) {
+                        warn!(
+                            "Rejected cluster report from {}, beyond {} instances",
+                            instance, MAX_INSTANCES
+                        );
+                        return StatusCode::SERVICE_UNAVAILABLE;
+                    }
+                    StatusCode::NO_CONTENT
+                },
+            );
+        let view_cluster = self.clone();
+        let view_route = warp::get()
+            .and(warp::path!("cluster"))
+            .and(warp::header::optional::<String>("authorization"))
+            .map(move |authorization: Option<String>| {
+                if !view_cluster.authorized(authorization.as_deref()) {
+                    return warp::reply::with_status(
+                        warp::reply::json(&"Missing or invalid token"),
+                        StatusCode::UNAUTHORIZED,
+                    );
+                }
+                warp::reply::with_status(warp::reply::json(&view_cluster.view()), StatusCode::OK)
+            });
+        let (address, server) = warp::serve(report_route.or(view_route))
+            .try_bind_with_graceful_shutdown(listen, async {
+                stopped.await.ok();
+            })
+            .context(format!("Failed to bind the cluster listener to {}", listen))?;
+        info!("Accepting cluster reports on {}", address);
+        server.await;
+        Ok(())
+    }
+
+    /// Whether an `Authorization` header carries the configured token.
+    fn authorized(&self, authorization: Option<&str>) -> bool {
+        authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|value| bool::from(value.trim().as_bytes().ct_eq(self.token.as_bytes())))
+    }
+}
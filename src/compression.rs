@@ -0,0 +1,241 @@
+//! Transparent compression of cached bodies at rest, trading CPU for a larger effective cache.
+//!
+//! Compression requires the `compression` feature; without it, enabling it is an error.
+
+use std::{
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::Result;
+use hyper::body::Bytes;
+use log::warn;
+
+/// Algorithm compressing cached bodies.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    /// Zstandard, fast with good ratios.
+    #[default]
+    Zstd,
+    /// Gzip, slower and larger than zstd.
+    Gzip,
+}
+
+/// Cache compression settings.
+#[derive(Clone, Debug)]
+pub struct CacheCompressionConfig {
+    /// Algorithm compressing the bodies. Defaults to zstd.
+    pub algorithm: CompressionAlgorithm,
+    /// Compression level, from 1 to 22 for zstd and from 0 to 9 for gzip; higher levels compress better but more
+    /// slowly. Defaults to the default level of the algorithm when `None`.
+    pub level: Option<i32>,
+    /// Bodies of at least this many bytes are compressed. Defaults to 1 KiB.
+    pub threshold: usize,
+}
+
+impl Default for CacheCompressionConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::Zstd,
+            level: None,
+            threshold: 1024,
+        }
+    }
+}
+
+/// Totals of the bodies compressed into the cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// Number of bodies compressed.
+    pub bodies: u64,
+    /// Size of those bodies before compression, in bytes.
+    pub original_bytes: u64,
+    /// Size of those bodies as stored, in bytes. Parts that do not shrink are stored uncompressed.
+    pub stored_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Returns the ratio of original to stored bytes, or 1 before any body is compressed.
+    pub fn ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            return 1.0;
+        }
+        self.original_bytes as f64 / self.stored_bytes as f64
+    }
+}
+
+/// Compresses cached bodies and keeps track of the space saved.
+pub struct CacheCompressor {
+    config: CacheCompressionConfig,
+    bodies: AtomicU64,
+    original_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+impl CacheCompressor {
+    /// Creates a compressor for the given settings.
+    #[cfg(feature = "compression")]
+    pub fn new(config: CacheCompressionConfig) -> Result<Self> {
+        if let Some(level) = config.level {
+            let valid = match config.algorithm {
+                CompressionAlgorithm::Zstd => zstd::compression_level_range().contains(&level),
+                CompressionAlgorithm::Gzip => (0..=9).contains(&level),
+            };
+            if !valid {
+                anyhow::bail!(
+                    "Invalid {:?} cache compression level {}",
+                    config.algorithm,
+                    level
+                );
+            }
+        }
+        Ok(CacheCompressor {
+            config,
+            bodies: AtomicU64::new(0),
+            original_bytes: AtomicU64::new(0),
+            stored_bytes: AtomicU64::new(0),
+        })
+    }
+
+    /// Creates a compressor for the given settings.
+    #[cfg(not(feature = "compression"))]
+    pub fn new(_config: CacheCompressionConfig) -> Result<Self> {
+        anyhow::bail!(
+            "Cannot compress the cache: fortifynet_proxy was built without the `compression` feature"
+        )
+    }
+
+    /// Returns the totals of the bodies compressed so far.
+    pub fn stats(&self) -> CompressionStats {
+        CompressionStats {
+            bodies: self.bodies.load(Ordering::Relaxed),
+            original_bytes: self.original_bytes.load(Ordering::Relaxed),
+            stored_bytes: self.stored_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the algorithm compressing the bodies.
+    pub(crate) fn algorithm(&self) -> CompressionAlgorithm {
+        self.config.algorithm
+    }
+
+    /// Whether a body of `len` bytes is compressed.
+    pub(crate) fn applies_to(&self, len: usize) -> bool {
+        len >= self.config.threshold && len > 0
+    }
+
+    /// Records a body of `original` bytes stored in `stored` bytes.
+    pub(crate) fn record(&self, original: usize, stored: usize) {
+        self.bodies.fetch_add(1, Ordering::Relaxed);
+        self.original_bytes
+            .fetch_add(original as u64, Ordering::Relaxed);
+        self.stored_bytes
+            .fetch_add(stored as u64, Ordering::Relaxed);
+    }
+
+    /// Compresses `data`, returning `None` when compression does not make it smaller.
+    pub(crate) fn compress(&self, data: &[u8]) -> Option<Bytes> {
+        match encode(self.config.algorithm, self.config.level, data) {
+            Ok(compressed) if compressed.len() < data.len() => Some(Bytes::from(compressed)),
+            Ok(_) => None,
+            Err(err) => {
+                warn!("Failed to compress a cached body: {}", err);
+                None
+            }
+        }
+    }
+}
+
+/// Compresses `data` with `algorithm` at `level`, or at the default level of the algorithm when `None`.
+#[cfg(feature = "compression")]
+pub(crate) fn encode(
+    algorithm: CompressionAlgorithm,
+    level: Option<i32>,
+    data: &[u8],
+) -> io::Result<Vec<u8>> {
+    match algorithm {
+        CompressionAlgorithm::Zstd => {
+            zstd::bulk::compress(data, level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL))
+        }
+        CompressionAlgorithm::Gzip => {
+            use std::io::Write;
+
+            let level = level.map_or(flate2::Compression::default(), |level| {
+                flate2::Compression::new(level as u32)
+            });
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+    }
+}
+
+/// Compresses `data` with `algorithm` at `level`, or at the default level of the algorithm when `None`.
+#[cfg(not(feature = "compression"))]
+pub(crate) fn encode(
+    _algorithm: CompressionAlgorithm,
+    _level: Option<i32>,
+    _data: &[u8],
+) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+/// Decompresses data compressed with `algorithm`.
+#[cfg(feature = "compression")]
+pub(crate) fn decompress(algorithm: CompressionAlgorithm, data: &[u8]) -> io::Result<Bytes> {
+    let decompressed = match algorithm {
+        CompressionAlgorithm::Zstd => zstd::stream::decode_all(data)?,
+        CompressionAlgorithm::Gzip => {
+            use std::io::Read;
+
+            let mut decompressed = Vec::new();
+            flate2::read::GzDecoder::new(data).read_to_end(&mut decompressed)?;
+            decompressed
+        }
+    };
+    Ok(Bytes::from(decompressed))
+}
+
+/// Decompresses data compressed with `algorithm`.
+#[cfg(not(feature = "compression"))]
+pub(crate) fn decompress(_algorithm: CompressionAlgorithm, _data: &[u8]) -> io::Result<Bytes> {
+    Err(unsupported())
+}
+
+/// Decompresses data compressed with `algorithm`, returning `None` once it decompresses to more than `limit` bytes.
+#[cfg(feature = "compression")]
+pub(crate) fn decompress_limited(
+    algorithm: CompressionAlgorithm,
+    data: &[u8],
+    limit: usize,
+) -> io::Result<Option<Bytes>> {
+    use std::io::Read;
+
+    let decoder: Box<dyn Read + '_> = match algorithm {
+        CompressionAlgorithm::Zstd => Box::new(zstd::stream::read::Decoder::new(data)?),
+        CompressionAlgorithm::Gzip => Box::new(flate2::read::GzDecoder::new(data)),
+    };
+    let mut decompressed = Vec::new();
+    decoder
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+    Ok((decompressed.len() <= limit).then(|| Bytes::from(decompressed)))
+}
+
+/// Decompresses data compressed with `algorithm`, returning `None` once it decompresses to more than `limit` bytes.
+#[cfg(not(feature = "compression"))]
+pub(crate) fn decompress_limited(
+    _algorithm: CompressionAlgorithm,
+    _data: &[u8],
+    _limit: usize,
+) -> io::Result<Option<Bytes>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "compression"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "fortifynet_proxy was built without the `compression` feature",
+    )
+}
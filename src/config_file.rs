@@ -0,0 +1,717 @@
+//! Loading of the proxy settings from TOML files, composed from included files and named profiles.
+//!
+//! A file may merge other files with `include = ["upstreams.toml", "policies/*.toml"]`, whose paths and glob patterns
+//! are relative to it, and define profiles such as `[profiles.prod]` overriding its settings. Settings are merged in
+//! this order, later ones taking precedence: the included files in the order they are listed, the including file,
+//! then the selected profile of every file in the same order. Tables are merged key by key, arrays of tables such as
+//! `[[policies]]` are concatenated, and any other value replaces the previous one. The merged settings are validated
+//! as a whole, rejecting unknown keys.
+//!
+//! Files hold the top-level settings of [`ProxyConfig`], the static upstreams as `[[upstreams]]`, the request
+//! policies as `[[policies]]` and the tenants as `[[tenants]]`; other nested settings are configured in code. Instead
+//! of a plaintext `password`, files may name a file holding it with `password_file`, relative to the file naming it,
+//! or an environment variable with `password_env`, for the proxy and for the Basic credentials of tenants alike. The
+//! passphrase of an encrypted private key is given the same way with `private_key_passphrase_file` or
+//! `private_key_passphrase_env`.
+
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use hyper::Method;
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use crate::{
+    discovery::{DiscoveryConfig, DiscoverySource, Endpoint},
+    policy::{CacheOverride, HeaderCondition, PolicyAction, PolicyMatch, PolicyRule},
+    rate_limit::{RateLimitConfig, RateLimitKey},
+    secret::SecretSource,
+    tenant::{BasicAuth, TenantConfig},
+    user_agent::{UserAgentCategory, UserAgentMatch},
+    ProxyConfig,
+};
+
+/// Environment variable selecting the profile when none is given explicitly.
+pub const PROFILE_ENV: &str = "FORTIFYNET_PROFILE";
+
+/// Settings read from configuration files, each overriding the default of the field of the same name.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileSettings {
+    ip_address: Option<String>,
+    port: Option<u16>,
+    authentication: Option<bool>,
+    username: Option<String>,
+    password: Option<String>,
+    password_file: Option<String>,
+    password_env: Option<String>,
+    cache_enabled: Option<bool>,
+    range_caching: Option<bool>,
+    socks5_address: Option<String>,
+    https_enabled: Option<bool>,
+    certificate_path: Option<String>,
+    private_key_path: Option<String>,
+    private_key_passphrase_file: Option<String>,
+    private_key_passphrase_env: Option<String>,
+    keylog_path: Option<String>,
+    target_address: Option<String>,
+    server_timing: Option<bool>,
+    #[serde(default)]
+    upstreams: Vec<FileUpstream>,
+    #[serde(default)]
+    policies: Vec<FilePolicy>,
+    #[serde(default)]
+    tenants: Vec<FileTenant>,
+}
+
+/// A fixed upstream among which requests are spread, as `[[upstreams]]`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileUpstream {
+    address: SocketAddr,
+    #[serde(default = "default_weight")]
+    weight: u16,
+}
+
+fn default_weight() -> u16 {
+    1
+}
+
+/// A request policy rule, as `[[policies]]`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FilePolicy {
+    name: String,
+    #[serde(default)]
+    conditions: FilePolicyMatch,
+    #[serde(default)]
+    actions: Vec<FilePolicyAction>,
+    #[serde(default)]
+    fallthrough: bool,
+}
+
+/// Conditions of a policy rule, as `[policies.conditions]`.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FilePolicyMatch {
+    hosts: Vec<String>,
+    path_prefix: Option<String>,
+    path_regex: Option<String>,
+    methods: Vec<String>,
+    headers: Vec<FileHeaderCondition>,
+    user_agents: Vec<String>,
+    user_agent_categories: Vec<FileUserAgentCategory>,
+    client_networks: Vec<String>,
+    users: Vec<String>,
+    tls_fingerprints: Vec<String>,
+    http2_fingerprints: Vec<String>,
+    http2_anomalous: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileHeaderCondition {
+    name: String,
+    pattern: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum FileUserAgentCategory {
+    Bot,
+    Mobile,
+    Missing,
+}
+
+/// An action of a policy rule, as a table with a single key such as `{ block = 403 }`.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum FilePolicyAction {
+    Route(String),
+    RewritePath { prefix: String, replacement: String },
+    SetHeader { name: String, value: String },
+    RemoveHeader(String),
+    Block(u16),
+    RateLimit(FileRateLimit),
+    Cache(FileCacheOverride),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileRateLimit {
+    requests: u32,
+    period_secs: Option<u64>,
+    burst: Option<u32>,
+    key_header: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum FileCacheOverride {
+    Bypass,
+    Force,
+}
+
+/// A site served by the proxy, as `[[tenants]]`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTenant {
+    name: String,
+    #[serde(default)]
+    hosts: Vec<String>,
+    target_address: Option<String>,
+    certificate_path: Option<String>,
+    private_key_path: Option<String>,
+    private_key_passphrase_file: Option<String>,
+    private_key_passphrase_env: Option<String>,
+    basic_auth: Option<FileBasicAuth>,
+    cache_namespace: Option<String>,
+    cache_quota: Option<usize>,
+    max_requests_per_minute: Option<u32>,
+}
+
+/// Basic credentials of a tenant, as `[tenants.basic_auth]`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileBasicAuth {
+    username: String,
+    password: Option<String>,
+    password_file: Option<String>,
+    password_env: Option<String>,
+}
+
+/// Settings merged from a file and its includes.
+#[derive(Default)]
+struct Merged {
+    settings: Table,
+    profiles: Vec<Table>,
+    profile_found: bool,
+}
+
+impl ProxyConfig {
+    /// Loads the settings of the file at `path` and of the files it includes, applying `profile`, or the profile
+    /// named by the `FORTIFYNET_PROFILE` environment variable when `None`. Settings missing from the files keep
+    /// their default.
+    pub fn from_file(path: impl AsRef<Path>, profile: Option<&str>) -> Result<ProxyConfig> {
+        let profile = match profile {
+            Some(profile) => Some(profile.to_string()),
+            None => std::env::var(PROFILE_ENV)
+                .ok()
+                .filter(|profile| !profile.is_empty()),
+        };
+        let mut merged = Merged::default();
+        load(
+            path.as_ref(),
+            profile.as_deref(),
+            &mut Vec::new(),
+            &mut merged,
+        )?;
+        if let Some(profile) = &profile {
+            if !merged.profile_found {
+                anyhow::bail!("Unknown configuration profile {}", profile);
+            }
+        }
+        let mut settings = merged.settings;
+        for overrides in merged.profiles {
+            merge(&mut settings, overrides);
+        }
+        let settings =
+            FileSettings::deserialize(Value::Table(settings)).context("Invalid configuration")?;
+        settings.validate()?;
+        settings.apply(ProxyConfig::default())
+    }
+}
+
+impl FileSettings {
+    /// Checks settings that only make sense together.
+    fn validate(&self) -> Result<()> {
+        if self.https_enabled == Some(true)
+            && (self.certificate_path.is_none() || self.private_key_path.is_none())
+        {
+            anyhow::bail!(
+                "Invalid configuration: HTTPS requires certificate_path and private_key_path"
+            );
+        }
+        let passwords = [&self.password, &self.password_file, &self.password_env];
+        if self.private_key_passphrase_file.is_some() && self.private_key_passphrase_env.is_some() {
+            anyhow::bail!(
+                "Invalid configuration: only one of private_key_passphrase_file and private_key_passphrase_env may be set"
+            );
+        }
+        if passwords
+            .iter()
+            .filter(|password| password.is_some())
+            .count()
+            > 1
+        {
+            anyhow::bail!(
+                "Invalid configuration: only one of password, password_file and password_env may be set"
+            );
+        }
+        let has_password = self.password_file.is_some()
+            || self.password_env.is_some()
+            || !self.password.as_deref().unwrap_or_default().is_empty();
+        if self.authentication == Some(true) && !has_password {
+            anyhow::bail!("Invalid configuration: authentication requires a password");
+        }
+        for tenant in &self.tenants {
+            if tenant.private_key_passphrase_file.is_some()
+                && tenant.private_key_passphrase_env.is_some()
+            {
+                anyhow::bail!(
+                    "Invalid configuration of tenant {}: only one of private_key_passphrase_file and \
+                     private_key_passphrase_env may be set",
+                    tenant.name
+                );
+            }
+            if let Some(auth) = &tenant.basic_auth {
+                let passwords = [&auth.password, &auth.password_file, &auth.password_env];
+                if passwords
+                    .iter()
+                    .filter(|password| password.is_some())
+                    .count()
+                    != 1
+                {
+                    anyhow::bail!(
+                        "Invalid configuration of tenant {}: basic_auth requires exactly one of password, \
+                         password_file and password_env",
+                        tenant.name
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Overrides the settings of `config` given in the files, reading the secrets they refer to.
+    fn apply(self, mut config: ProxyConfig) -> Result<ProxyConfig> {
+        if let Some(ip_address) = self.ip_address {
+            config.ip_address = ip_address;
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(authentication) = self.authentication {
+            config.authentication = authentication;
+        }
+        if let Some(username) = self.username {
+            config.username = username;
+        }
+        let password = match (self.password, self.password_file, self.password_env) {
+            (Some(password), _, _) => Some(SecretSource::Value(password)),
+            (_, Some(path), _) => Some(SecretSource::File(path)),
+            (_, _, Some(name)) => Some(SecretSource::Env(name)),
+            (None, None, None) => None,
+        };
+        if let Some(password) = password {
+            config.password = password.read()?;
+        }
+        if let Some(cache_enabled) = self.cache_enabled {
+            config.cache_enabled = cache_enabled;
+        }
+        if let Some(range_caching) = self.range_caching {
+            config.range_caching = range_caching;
+        }
+        if let Some(https_enabled) = self.https_enabled {
+            config.https_enabled = https_enabled;
+        }
+        if let Some(server_timing) = self.server_timing {
+            config.server_timing = server_timing;
+        }
+        config.socks5_address = self.socks5_address.or(config.socks5_address);
+        config.certificate_path = self.certificate_path.or(config.certificate_path);
+        config.private_key_path = self.private_key_path.or(config.private_key_path);
+        let passphrase = match (
+            self.private_key_passphrase_file,
+            self.private_key_passphrase_env,
+        ) {
+            (Some(path), _) => Some(SecretSource::File(path)),
+            (_, Some(name)) => Some(SecretSource::Env(name)),
+            (None, None) => None,
+        };
+        config.private_key_passphrase = passphrase.or(config.private_key_passphrase);
+        config.keylog_path = self.keylog_path.or(config.keylog_path);
+        config.target_address = self.target_address.or(config.target_address);
+        if !self.upstreams.is_empty() {
+            let endpoints = self
+                .upstreams
+                .into_iter()
+                .map(|upstream| Endpoint {
+                    address: upstream.address,
+                    weight: upstream.weight,
+                })
+                .collect();
+            config.discovery = Some(DiscoveryConfig::new(DiscoverySource::Static(endpoints)));
+        }
+        for policy in self.policies {
+            config.policies.push(policy.into_rule()?);
+        }
+        config
+            .tenants
+            .extend(self.tenants.into_iter().map(FileTenant::into_config));
+        Ok(config)
+    }
+}
+
+impl FilePolicy {
+    /// Converts the rule, failing on invalid methods.
+    fn into_rule(self) -> Result<PolicyRule> {
+        let conditions = self.conditions;
+        let methods = conditions
+            .methods
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.as_bytes())
+                    .with_context(|| format!("Invalid method {} in policy {}", method, self.name))
+            })
+            .collect::<Result<_>>()?;
+        let actions = self
+            .actions
+            .into_iter()
+            .map(|action| match action {
+                FilePolicyAction::Route(target) => PolicyAction::Route(target),
+                FilePolicyAction::RewritePath {
+                    prefix,
+                    replacement,
+                } => PolicyAction::RewritePath {
+                    prefix,
+                    replacement,
+                },
+                FilePolicyAction::SetHeader { name, value } => {
+                    PolicyAction::SetHeader { name, value }
+                }
+                FilePolicyAction::RemoveHeader(name) => PolicyAction::RemoveHeader(name),
+                FilePolicyAction::Block(status) => PolicyAction::Block(status),
+                FilePolicyAction::RateLimit(limit) => PolicyAction::RateLimit(RateLimitConfig {
+                    requests: limit.requests,
+                    period: limit
+                        .period_secs
+                        .map_or(RateLimitConfig::default().period, Duration::from_secs),
+                    burst: limit.burst,
+                    key: limit
+                        .key_header
+                        .map_or(RateLimitKey::ClientIp, RateLimitKey::Header),
+                    redis: None,
+                }),
+                FilePolicyAction::Cache(FileCacheOverride::Bypass) => {
+                    PolicyAction::Cache(CacheOverride::Bypass)
+                }
+                FilePolicyAction::Cache(FileCacheOverride::Force) => {
+                    PolicyAction::Cache(CacheOverride::Force)
+                }
+            })
+            .collect();
+        Ok(PolicyRule {
+            name: self.name,
+            conditions: PolicyMatch {
+                hosts: conditions.hosts,
+                path_prefix: conditions.path_prefix,
+                path_regex: conditions.path_regex,
+                methods,
+                headers: conditions
+                    .headers
+                    .into_iter()
+                    .map(|header| HeaderCondition {
+                        name: header.name,
+                        pattern: header.pattern,
+                    })
+                    .collect(),
+                user_agents: conditions
+                    .user_agents
+                    .into_iter()
+                    .map(UserAgentMatch::Regex)
+                    .chain(
+                        conditions
+                            .user_agent_categories
+                            .into_iter()
+                            .map(|category| {
+                                UserAgentMatch::Category(match category {
+                                    FileUserAgentCategory::Bot => UserAgentCategory::Bot,
+                                    FileUserAgentCategory::Mobile => UserAgentCategory::Mobile,
+                                    FileUserAgentCategory::Missing => UserAgentCategory::Missing,
+                                })
+                            }),
+                    )
+                    .collect(),
+                client_networks: conditions.client_networks,
+                users: conditions.users,
+                tls_fingerprints: conditions.tls_fingerprints,
+                http2_fingerprints: conditions.http2_fingerprints,
+                http2_anomalous: conditions.http2_anomalous,
+            },
+            actions,
+            fallthrough: self.fallthrough,
+        })
+    }
+}
+
+impl FileTenant {
+    /// Converts the tenant, whose secrets were validated.
+    fn into_config(self) -> TenantConfig {
+        let private_key_passphrase = match (
+            self.private_key_passphrase_file,
+            self.private_key_passphrase_env,
+        ) {
+            (Some(path), _) => Some(SecretSource::File(path)),
+            (_, Some(name)) => Some(SecretSource::Env(name)),
+            (None, None) => None,
+        };
+        let basic_auth = self.basic_auth.and_then(|auth| {
+            let password = match (auth.password, auth.password_file, auth.password_env) {
+                (Some(password), _, _) => SecretSource::Value(password),
+                (_, Some(path), _) => SecretSource::File(path),
+                (_, _, Some(name)) => SecretSource::Env(name),
+                (None, None, None) => return None,
+            };
+            Some(BasicAuth {
+                username: auth.username,
+                password,
+            })
+        });
+        TenantConfig {
+            name: self.name,
+            hosts: self.hosts,
+            target_address: self.target_address,
+            certificate_path: self.certificate_path,
+            private_key_path: self.private_key_path,
+            private_key_passphrase,
+            basic_auth,
+            cache_namespace: self.cache_namespace,
+            cache_quota: self.cache_quota,
+            max_requests_per_minute: self.max_requests_per_minute,
+        }
+    }
+}
+
+/// Merges the file at `path` and its includes into `merged`. `stack` holds the files including it, to detect cycles.
+fn load(
+    path: &Path,
+    profile: Option<&str>,
+    stack: &mut Vec<PathBuf>,
+    merged: &mut Merged,
+) -> Result<()> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("Failed to find configuration file {}", path.display()))?;
+    if stack.contains(&path) {
+        anyhow::bail!("Configuration file {} includes itself", path.display());
+    }
+    let text = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read configuration file {}", path.display()))?;
+    let mut table: Table = text
+        .parse()
+        .with_context(|| format!("Failed to parse configuration file {}", path.display()))?;
+
+    stack.push(path.clone());
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    resolve_secret_files(dir, &mut table);
+    for include in includes(&path, table.remove("include"))? {
+        for included in expand(dir, &include)? {
+            load(&included, profile, stack, merged)?;
+        }
+    }
+    stack.pop();
+
+    let profiles = match table.remove("profiles") {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => anyhow::bail!("Invalid profiles in {}: expected a table", path.display()),
+        None => Table::new(),
+    };
+    for (name, overrides) in profiles {
+        let overrides = match overrides {
+            Value::Table(overrides) => overrides,
+            _ => anyhow::bail!(
+                "Invalid profile {} in {}: expected a table",
+                name,
+                path.display()
+            ),
+        };
+        if overrides.contains_key("include") || overrides.contains_key("profiles") {
+            anyhow::bail!(
+                "Invalid profile {} in {}: profiles cannot include files or define profiles",
+                name,
+                path.display()
+            );
+        }
+        if Some(name.as_str()) == profile {
+            let mut overrides = overrides;
+            resolve_secret_files(dir, &mut overrides);
+            merged.profile_found = true;
+            merged.profiles.push(overrides);
+        }
+    }
+    merge(&mut merged.settings, table);
+    Ok(())
+}
+
+/// Makes the relative secret files of `table`, and of its tenants, relative to `dir`, the directory of the file
+/// naming them.
+fn resolve_secret_files(dir: &Path, table: &mut Table) {
+    for key in ["password_file", "private_key_passphrase_file"] {
+        if let Some(Value::String(path)) = table.get_mut(key) {
+            *path = dir.join(&*path).to_string_lossy().into_owned();
+        }
+    }
+    if let Some(Value::Array(tenants)) = table.get_mut("tenants") {
+        for tenant in tenants.iter_mut().filter_map(Value::as_table_mut) {
+            resolve_secret_files(dir, tenant);
+            if let Some(Value::Table(auth)) = tenant.get_mut("basic_auth") {
+                resolve_secret_files(dir, auth);
+            }
+        }
+    }
+}
+
+/// Returns the paths and patterns of the `include` directive of the file at `path`.
+fn includes(path: &Path, include: Option<Value>) -> Result<Vec<String>> {
+    let include = match include {
+        Some(Value::Array(include)) => include,
+        Some(Value::String(include)) => return Ok(vec![include]),
+        Some(_) => anyhow::bail!(
+            "Invalid include in {}: expected a list of paths",
+            path.display()
+        ),
+        None => return Ok(Vec::new()),
+    };
+    include
+        .into_iter()
+        .map(|include| match include {
+            Value::String(include) => Ok(include),
+            _ => anyhow::bail!(
+                "Invalid include in {}: expected a list of paths",
+                path.display()
+            ),
+        })
+        .collect()
+}
+
+/// Returns the files matching `include`, relative to `dir`, in lexical order. Patterns may match no file, but plain
+/// paths must exist.
+fn expand(dir: &Path, include: &str) -> Result<Vec<PathBuf>> {
+    let pattern = dir.join(include);
+    let pattern = pattern.to_string_lossy();
+    if !include.contains(['*', '?', '[']) {
+        return Ok(vec![PathBuf::from(pattern.as_ref())]);
+    }
+    let mut paths = glob::glob(&pattern)
+        .with_context(|| format!("Invalid include pattern {}", include))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to expand include pattern {}", include))?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Merges `overrides` into `base`, key by key for tables, appending arrays of tables and replacing any other value.
+fn merge(base: &mut Table, overrides: Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overrides)) => merge(base, overrides),
+            (Some(Value::Array(base)), Value::Array(overrides))
+                if is_array_of_tables(base) && is_array_of_tables(&overrides) =>
+            {
+                base.extend(overrides)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Whether `values` is an array of tables, such as `[[policies]]`.
+fn is_array_of_tables(values: &[Value]) -> bool {
+    values.iter().all(Value::is_table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn includes_upstreams_policies_and_tenants() {
+        let dir = std::env::temp_dir().join(format!("fortifynet-config-{}", std::process::id()));
+        fs::create_dir_all(dir.join("policies")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        fs::write(
+            dir.join("proxy.toml"),
+            r#"
+                include = ["upstreams.toml", "policies/*.toml"]
+                port = 8081
+
+                [[tenants]]
+                name = "shop"
+                hosts = ["shop.example.com"]
+                basic_auth = { username = "admin", password_file = "shop.secret" }
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("upstreams.toml"),
+            r#"
+                [[upstreams]]
+                address = "10.0.0.1:8080"
+
+                [[upstreams]]
+                address = "10.0.0.2:8080"
+                weight = 3
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("policies/admin.toml"),
+            r#"
+                [[policies]]
+                name = "admin"
+                conditions = { path_prefix = "/admin", methods = ["POST"] }
+                actions = [{ block = 403 }]
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("policies/static.toml"),
+            r#"
+                [[policies]]
+                name = "static"
+                conditions = { path_prefix = "/static", user_agent_categories = ["bot"] }
+                actions = [{ cache = "force" }, { set_header = { name = "x-static", value = "1" } }]
+            "#,
+        )
+        .unwrap();
+
+        let config = ProxyConfig::from_file(dir.join("proxy.toml"), None).unwrap();
+        assert_eq!(config.port, 8081);
+        match config.discovery.map(|discovery| discovery.source) {
+            Some(DiscoverySource::Static(endpoints)) => {
+                assert_eq!(endpoints.len(), 2);
+                assert_eq!(endpoints[1].weight, 3);
+            }
+            source => panic!("unexpected discovery source {:?}", source),
+        }
+        let names: Vec<_> = config
+            .policies
+            .iter()
+            .map(|rule| rule.name.as_str())
+            .collect();
+        assert_eq!(names, ["admin", "static"]);
+        assert_eq!(config.policies[0].conditions.methods, [Method::POST]);
+        assert!(matches!(
+            config.policies[0].actions[..],
+            [PolicyAction::Block(403)]
+        ));
+        assert!(matches!(
+            config.policies[1].conditions.user_agents[..],
+            [UserAgentMatch::Category(UserAgentCategory::Bot)]
+        ));
+        let auth = config.tenants[0].basic_auth.as_ref().unwrap();
+        assert_eq!(
+            auth.password,
+            SecretSource::File(dir.join("shop.secret").to_string_lossy().into_owned())
+        );
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,349 @@
+//! Live records of the client connections: their peer, protocol, requests, bytes and state, with totals over all the
+//! connections served, for the dashboard and applications embedding the proxy alike.
+//!
+//! A connection stays listed as long as anything serves it, including the tunnel of a `CONNECT` request after the
+//! connection was handed over to it. Bytes are counted as they cross the socket once the connection is admitted, so
+//! they include the TLS overhead of HTTPS connections and the tunneled traffic.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use hyper::Version;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{timeseries::unix_now, trace};
+
+tokio::task_local! {
+    /// The connection being served by the current task.
+    static CURRENT: Arc<Connection>;
+}
+
+/// What a client connection is doing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    /// Accepted, and being checked or authenticated.
+    Accepted,
+    /// In its TLS handshake.
+    Handshaking,
+    /// Waiting for a request.
+    Idle,
+    /// Serving at least one request.
+    Active,
+    /// Relaying the tunnel of a `CONNECT` request.
+    Tunneling,
+}
+
+/// A client connection.
+#[derive(Clone, Debug, Serialize)]
+pub struct ConnectionRecord {
+    /// ID of the connection, the `conn=` field of its log lines.
+    pub id: u64,
+    /// Address of the client.
+    pub peer: SocketAddr,
+    /// HTTP version of the latest request, such as `HTTP/1.1`, or `unknown` before the first one.
+    pub protocol: &'static str,
+    /// Whether the connection is over TLS.
+    pub tls: bool,
+    /// Unix timestamp of the acceptance of the connection.
+    pub started_at: u64,
+    /// Seconds since the connection was accepted.
+    pub open_seconds: f64,
+    /// Number of requests received on the connection.
+    pub requests: u64,
+    /// Number of bytes received from the client.
+    pub bytes_in: u64,
+    /// Number of bytes sent to the client.
+    pub bytes_out: u64,
+    /// What the connection is doing.
+    pub state: ConnectionState,
+}
+
+/// The open client connections, and totals over all the connections served.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ConnectionStats {
+    /// Number of open connections.
+    pub open: usize,
+    /// Number of connections accepted since the start.
+    pub accepted: u64,
+    /// Number of connections closed since the start.
+    pub closed: u64,
+    /// Number of requests received on all connections.
+    pub requests: u64,
+    /// Number of bytes received from clients on all connections.
+    pub bytes_in: u64,
+    /// Number of bytes sent to clients on all connections.
+    pub bytes_out: u64,
+    /// The open connections, the oldest first.
+    pub connections: Vec<ConnectionRecord>,
+}
+
+/// A client connection being served, closed once nothing holds it anymore.
+pub(crate) struct Connection {
+    id: u64,
+    peer: SocketAddr,
+    started: Instant,
+    started_at: u64,
+    tls: AtomicBool,
+    protocol: Mutex<&'static str>,
+    requests: AtomicU64,
+    active_requests: AtomicUsize,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    state: Mutex<ConnectionState>,
+    tracker: Arc<ConnectionTracker>,
+}
+
+impl Connection {
+    /// Returns the address of the client.
+    pub(crate) fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Sets what the connection is doing.
+    pub(crate) fn set_state(&self, state: ConnectionState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    fn record(&self) -> ConnectionRecord {
+        ConnectionRecord {
+            id: self.id,
+            peer: self.peer,
+            protocol: *self.protocol.lock().unwrap(),
+            tls: self.tls.load(Ordering::Relaxed),
+            started_at: self.started_at,
+            open_seconds: self.started.elapsed().as_secs_f64(),
+            requests: self.requests.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            state: *self.state.lock().unwrap(),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let tracker = &self.tracker;
+        tracker.open.lock().unwrap().remove(&self.id);
+        tracker.closed.fetch_add(1, Ordering::Relaxed);
+        tracker
+            .closed_requests
+            .fetch_add(self.requests.load(Ordering::Relaxed), Ordering::Relaxed);
+        tracker
+            .closed_bytes_in
+            .fetch_add(self.bytes_in.load(Ordering::Relaxed), Ordering::Relaxed);
+        tracker
+            .closed_bytes_out
+            .fetch_add(self.bytes_out.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+/// A request being served on a connection, which turns idle once its last request is served.
+pub(crate) struct ActiveRequest(Arc<Connection>);
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        if self.0.active_requests.fetch_sub(1, Ordering::Relaxed) == 1 {
+            let mut state = self.0.state.lock().unwrap();
+            if *state == ConnectionState::Active {
+                *state = ConnectionState::Idle;
+            }
+        }
+    }
+}
+
+/// Tracks the client connections.
+#[derive(Default)]
+pub struct ConnectionTracker {
+    open: Mutex<HashMap<u64, Weak<Connection>>>,
+    accepted: AtomicU64,
+    closed: AtomicU64,
+    closed_requests: AtomicU64,
+    closed_bytes_in: AtomicU64,
+    closed_bytes_out: AtomicU64,
+}
+
+impl ConnectionTracker {
+    /// Runs `future`, serving the connection accepted from `peer`, as the current connection.
+    pub(crate) async fn track<F: Future>(
+        self: Arc<Self>,
+        peer: SocketAddr,
+        future: F,
+    ) -> F::Output {
+        let id = trace::connection_id().unwrap_or_default();
+        let connection = Arc::new(Connection {
+            id,
+            peer,
+            started: Instant::now(),
+            started_at: unix_now(),
+            tls: AtomicBool::new(false),
+            protocol: Mutex::new("unknown"),
+            requests: AtomicU64::new(0),
+            active_requests: AtomicUsize::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            state: Mutex::new(ConnectionState::Accepted),
+            tracker: self.clone(),
+        });
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.open
+            .lock()
+            .unwrap()
+            .insert(id, Arc::downgrade(&connection));
+        CURRENT.scope(connection, future).await
+    }
+
+    /// Returns the open connections and the totals over all connections.
+    pub fn stats(&self) -> ConnectionStats {
+        let open: Vec<Arc<Connection>> = self
+            .open
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let mut connections: Vec<ConnectionRecord> =
+            open.iter().map(|connection| connection.record()).collect();
+        // Dropped outside of the lock, as closing a connection takes it
+        drop(open);
+        connections.sort_by_key(|connection| connection.id);
+        let sum = |closed: &AtomicU64, open: fn(&ConnectionRecord) -> u64| {
+            closed.load(Ordering::Relaxed) + connections.iter().map(open).sum::<u64>()
+        };
+        ConnectionStats {
+            open: connections.len(),
+            accepted: self.accepted.load(Ordering::Relaxed),
+            closed: self.closed.load(Ordering::Relaxed),
+            requests: sum(&self.closed_requests, |connection| connection.requests),
+            bytes_in: sum(&self.closed_bytes_in, |connection| connection.bytes_in),
+            bytes_out: sum(&self.closed_bytes_out, |connection| connection.bytes_out),
+            connections,
+        }
+    }
+}
+
+/// Returns the connection being served by the current task, if any.
+pub(crate) fn current() -> Option<Arc<Connection>> {
+    CURRENT.try_with(Arc::clone).ok()
+}
+
+/// Sets what the current connection is doing.
+pub(crate) fn set_state(state: ConnectionState) {
+    if let Some(connection) = current() {
+        connection.set_state(state);
+    }
+}
+
+/// Marks the current connection as being over TLS.
+pub(crate) fn set_tls() {
+    if let Some(connection) = current() {
+        connection.tls.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Counts a request of `version` on the current connection, which stays active until the returned guard is dropped.
+pub(crate) fn request_started(version: Version) -> Option<ActiveRequest> {
+    let connection = current()?;
+    *connection.protocol.lock().unwrap() = match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_11 => "HTTP/1.1",
+        Version::HTTP_2 => "HTTP/2",
+        Version::HTTP_3 => "HTTP/3",
+        _ => "unknown",
+    };
+    connection.requests.fetch_add(1, Ordering::Relaxed);
+    connection.active_requests.fetch_add(1, Ordering::Relaxed);
+    connection.set_state(ConnectionState::Active);
+    Some(ActiveRequest(connection))
+}
+
+/// A stream counting the bytes crossing it for the connection it serves.
+pub(crate) struct Counted<S> {
+    inner: S,
+    connection: Option<Arc<Connection>>,
+}
+
+impl<S> Counted<S> {
+    /// Wraps `inner`, counting its bytes for the current connection.
+    pub(crate) fn new(inner: S) -> Self {
+        Counted {
+            inner,
+            connection: current(),
+        }
+    }
+
+    fn count(&self, counter: fn(&Connection) -> &AtomicU64, bytes: usize) {
+        if let Some(connection) = &self.connection {
+            counter(connection).fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            self.count(
+                |connection| &connection.bytes_in,
+                buf.filled().len() - before,
+            );
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.count(|connection| &connection.bytes_out, written);
+        }
+        poll
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.count(|connection| &connection.bytes_out, written);
+        }
+        poll
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
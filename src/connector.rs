@@ -0,0 +1,615 @@
+//! Transports to upstreams, chosen per upstream or by destination host: a direct TCP connection, TLS whatever the
+//! scheme, a SOCKS5 or HTTP proxy, a Unix socket, SSH tunnels, or an implementation of [`Connector`] for another
+//! transport.
+//!
+//! Requests to upstreams with a configured transport are sent over a new HTTP/1.1 connection each, with TLS on top
+//! of the transport for `https` upstreams. The others share the pooled clients, which also negotiate HTTP/2.
+
+use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{ClientConfig, ServerName},
+    TlsConnector,
+};
+use tokio_socks::tcp::Socks5Stream;
+use url::Url;
+
+use crate::{
+    bypass::Bypass,
+    failover::{FailoverConfig, FailoverConnector},
+    proxy_auth::ProxyAuth,
+    ssh::{SshConfig, SshConnector},
+    tor::{self, TorConfig, TorConnector},
+    tunnel::{self, UpstreamStream},
+    wireguard::{WireGuardConfig, WireGuardConnector},
+};
+
+/// Longest response to a `CONNECT` request read from an HTTP proxy.
+const MAX_CONNECT_RESPONSE_LEN: usize = 8 * 1024;
+/// Longest body of a 407 response read from an HTTP proxy, before authenticating on the same connection.
+const MAX_CHALLENGE_BODY_LEN: u64 = 64 * 1024;
+/// Most `CONNECT` requests sent to authenticate a tunnel, NTLM taking two.
+const MAX_AUTHENTICATION_ROUNDS: usize = 3;
+
+/// Error of a transport that works but whose destination refused the connection or could not be reached, such as an
+/// HTTP proxy refusing the tunnel.
+#[derive(Debug)]
+pub(crate) struct DestinationError(String);
+
+impl fmt::Display for DestinationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DestinationError {}
+
+/// Returns whether `err`, returned by a transport failing to connect, comes from the destination rather than the
+/// transport, which then works: a [`DestinationError`], or a SOCKS proxy replying the destination cannot be reached.
+pub(crate) fn is_destination_failure(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.is::<DestinationError>()
+            || matches!(
+                cause.downcast_ref::<tokio_socks::Error>(),
+                Some(
+                    tokio_socks::Error::ConnectionNotAllowedByRuleset
+                        | tokio_socks::Error::NetworkUnreachable
+                        | tokio_socks::Error::HostUnreachable
+                        | tokio_socks::Error::ConnectionRefused
+                        | tokio_socks::Error::TtlExpired
+                )
+            )
+    })
+}
+
+/// A transport opening byte streams to upstreams.
+///
+/// Implement it to reach upstreams through a transport the proxy does not support, and configure it with
+/// [`ConnectorConfig::Custom`].
+pub trait Connector: Send + Sync {
+    /// Describes the transport in logs.
+    fn describe(&self) -> String;
+
+    /// Opens a stream to port `port` of `host`, a host name or IP address.
+    fn connect(&self, host: &str, port: u16)
+        -> BoxFuture<'static, Result<Box<dyn UpstreamStream>>>;
+}
+
+impl fmt::Debug for dyn Connector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.describe())
+    }
+}
+
+/// How an upstream is reached.
+#[derive(Clone, Debug, Default)]
+pub enum ConnectorConfig {
+    /// A TCP connection to the upstream, over TLS for `https` upstreams.
+    #[default]
+    Direct,
+    /// A TCP connection to the upstream over TLS, even for `http` upstreams such as a TLS-only port listed as
+    /// `http://host:443`. The server name is the one of the upstream host settings, or its host.
+    Tls,
+    /// Through a SOCKS5 proxy at `address`, as `ip:port`.
+    Socks5 { address: String },
+    /// Through a tunnel opened with a `CONNECT` request to an HTTP proxy at `address`, as `host:port`,
+    /// authenticating with `auth` when given.
+    HttpProxy {
+        address: String,
+        auth: Option<ProxyAuth>,
+    },
+    /// Through the Unix socket at `path`, whatever the host and port of the upstream. Unix only.
+    Unix { path: PathBuf },
+    /// Through SSH tunnels from a jump host. Requires the `ssh` feature.
+    Ssh(SshConfig),
+    /// Through Tor, by the SOCKS port of a local Tor client. The only transport reaching `.onion` hosts.
+    Tor(TorConfig),
+    /// Through a userspace WireGuard tunnel to a peer. Requires the `wireguard` feature.
+    WireGuard(WireGuardConfig),
+    /// Through the first of several transports that is up, failing over to the next ones and back.
+    Failover(FailoverConfig),
+    /// Another transport.
+    Custom(Arc<dyn Connector>),
+}
+
+/// Transport of a single upstream.
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamConnectorConfig {
+    /// Upstream the transport applies to, as `host` or `host:port`.
+    pub upstream: String,
+    /// How the upstream is reached.
+    pub connector: ConnectorConfig,
+}
+
+/// Transport of the destinations matching host patterns, such as `*.onion` through Tor.
+#[derive(Clone, Debug, Default)]
+pub struct EgressRoute {
+    /// Host names, IP addresses, wildcards such as `*.example.com`, or `*` for any host.
+    pub hosts: Vec<String>,
+    /// How the matching destinations are reached. Direct routes use the pooled clients.
+    pub connector: ConnectorConfig,
+}
+
+/// A transport opening TCP connections.
+struct DirectConnector;
+
+impl Connector for DirectConnector {
+    fn describe(&self) -> String {
+        "direct".to_string()
+    }
+
+    fn connect(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> BoxFuture<'static, Result<Box<dyn UpstreamStream>>> {
+        let host = host.to_string();
+        Box::pin(async move {
+            let stream = match TcpStream::connect((host.as_str(), port)).await {
+                Ok(stream) => stream,
+                Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    bail!(DestinationError(format!(
+                        "{}:{} refused the connection",
+                        host, port
+                    )))
+                }
+                Err(err) => {
+                    return Err(err).context(format!("Failed to connect to {}:{}", host, port))
+                }
+            };
+            stream.set_nodelay(true)?;
+            Ok(Box::new(stream) as Box<dyn UpstreamStream>)
+        })
+    }
+}
+
+/// A transport through a SOCKS5 proxy.
+struct Socks5Connector {
+    proxy: SocketAddr,
+}
+
+impl Connector for Socks5Connector {
+    fn describe(&self) -> String {
+        format!("SOCKS5 proxy {}", self.proxy)
+    }
+
+    fn connect(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> BoxFuture<'static, Result<Box<dyn UpstreamStream>>> {
+        let proxy = self.proxy;
+        let host = host.to_string();
+        Box::pin(async move {
+            let stream = Socks5Stream::connect(proxy, (host.as_str(), port))
+                .await
+                .context(format!(
+                    "Failed to connect to {}:{} through SOCKS5",
+                    host, port
+                ))?;
+            Ok(Box::new(stream) as Box<dyn UpstreamStream>)
+        })
+    }
+}
+
+/// A transport through tunnels of an HTTP proxy.
+struct HttpProxyConnector {
+    proxy: String,
+    auth: Option<ProxyAuth>,
+}
+
+/// Response of an HTTP proxy to a `CONNECT` request.
+struct ConnectResponse {
+    code: u16,
+    /// Values of the `Proxy-Authenticate` headers.
+    challenges: Vec<String>,
+    /// Length of the body, if given.
+    content_length: Option<u64>,
+    /// Whether the proxy closes the connection after the response.
+    close: bool,
+}
+
+/// Reads the response of the HTTP proxy at `proxy` to a `CONNECT` request, byte by byte so no byte of the tunnel is
+/// consumed with it.
+async fn read_connect_response(
+    stream: &mut TcpStream,
+    proxy: &str,
+    target: &str,
+) -> Result<ConnectResponse> {
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_CONNECT_RESPONSE_LEN {
+            bail!("Response of HTTP proxy {} to CONNECT is too long", proxy);
+        }
+        let byte = stream.read_u8().await.context(format!(
+            "HTTP proxy {} closed the tunnel to {}",
+            proxy, target
+        ))?;
+        response.push(byte);
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Response::new(&mut headers);
+    parsed.parse(&response).context(format!(
+        "Invalid response of HTTP proxy {} to CONNECT",
+        proxy
+    ))?;
+    let mut connect_response = ConnectResponse {
+        code: parsed.code.unwrap_or_default(),
+        challenges: Vec::new(),
+        content_length: None,
+        close: parsed.version == Some(0),
+    };
+    for header in parsed.headers.iter() {
+        let value = String::from_utf8_lossy(header.value);
+        if header.name.eq_ignore_ascii_case("proxy-authenticate") {
+            connect_response.challenges.push(value.into_owned());
+        } else if header.name.eq_ignore_ascii_case("content-length") {
+            connect_response.content_length = value.trim().parse().ok();
+        } else if header.name.eq_ignore_ascii_case("connection")
+            || header.name.eq_ignore_ascii_case("proxy-connection")
+        {
+            let value = value.trim();
+            if value.eq_ignore_ascii_case("close") {
+                connect_response.close = true;
+            } else if value.eq_ignore_ascii_case("keep-alive") {
+                connect_response.close = false;
+            }
+        }
+    }
+    Ok(connect_response)
+}
+
+/// Returns the error of a failed authentication with the HTTP proxy at `proxy`, with its cause, such as a missing
+/// Kerberos ticket.
+fn authentication_error(proxy: &str, err: anyhow::Error) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Failed to authenticate with HTTP proxy {}: {:#}",
+        proxy,
+        err
+    )
+}
+
+impl Connector for HttpProxyConnector {
+    fn describe(&self) -> String {
+        format!("HTTP proxy {}", self.proxy)
+    }
+
+    fn connect(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> BoxFuture<'static, Result<Box<dyn UpstreamStream>>> {
+        let proxy = self.proxy.clone();
+        let target = match host.parse::<std::net::Ipv6Addr>() {
+            Ok(_) => format!("[{}]:{}", host, port),
+            Err(_) => format!("{}:{}", host, port),
+        };
+        let mut handshake = self.auth.as_ref().map(|auth| auth.start(&proxy));
+        Box::pin(async move {
+            let mut authorization = match &mut handshake {
+                Some(handshake) => Some(
+                    handshake
+                        .first()
+                        .await
+                        .map_err(|err| authentication_error(&proxy, err))?,
+                ),
+                None => None,
+            };
+            let mut stream = TcpStream::connect(&proxy)
+                .await
+                .context(format!("Failed to connect to HTTP proxy {}", proxy))?;
+            stream.set_nodelay(true)?;
+            // Authentication rounds go over the same connection, which NTLM authenticates
+            for _ in 0..MAX_AUTHENTICATION_ROUNDS {
+                let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+                if let Some(authorization) = &authorization {
+                    request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+                }
+                request.push_str("\r\n");
+                stream.write_all(request.as_bytes()).await?;
+                let response = read_connect_response(&mut stream, &proxy, &target).await?;
+                if (200..300).contains(&response.code) {
+                    return Ok(Box::new(stream) as Box<dyn UpstreamStream>);
+                }
+                let next = match &mut handshake {
+                    Some(handshake) if response.code == 407 => handshake
+                        .next(&response.challenges)
+                        .map_err(|err| authentication_error(&proxy, err))?,
+                    _ => None,
+                };
+                let next = match next {
+                    Some(next) => next,
+                    None if response.code == 407 => bail!(
+                        "HTTP proxy {} requires authentication for the tunnel to {}",
+                        proxy,
+                        target
+                    ),
+                    None => bail!(DestinationError(format!(
+                        "HTTP proxy {} refused the tunnel to {} with status {}",
+                        proxy, target, response.code
+                    ))),
+                };
+                match response.content_length {
+                    Some(len) if !response.close && len <= MAX_CHALLENGE_BODY_LEN => {
+                        tokio::io::copy(&mut (&mut stream).take(len), &mut tokio::io::sink())
+                            .await?;
+                    }
+                    _ => bail!(
+                        "HTTP proxy {} does not keep the connection open to authenticate",
+                        proxy
+                    ),
+                }
+                authorization = Some(next);
+            }
+            bail!(
+                "HTTP proxy {} did not complete the authentication of the tunnel to {}",
+                proxy,
+                target
+            )
+        })
+    }
+}
+
+/// A transport through a Unix socket.
+struct UnixConnector {
+    path: PathBuf,
+}
+
+impl Connector for UnixConnector {
+    fn describe(&self) -> String {
+        format!("Unix socket {}", self.path.display())
+    }
+
+    #[cfg(unix)]
+    fn connect(
+        &self,
+        _host: &str,
+        _port: u16,
+    ) -> BoxFuture<'static, Result<Box<dyn UpstreamStream>>> {
+        let path = self.path.clone();
+        Box::pin(async move {
+            let stream = tokio::net::UnixStream::connect(&path)
+                .await
+                .context(format!("Failed to connect to {}", path.display()))?;
+            Ok(Box::new(stream) as Box<dyn UpstreamStream>)
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn connect(
+        &self,
+        _host: &str,
+        _port: u16,
+    ) -> BoxFuture<'static, Result<Box<dyn UpstreamStream>>> {
+        let path = self.path.clone();
+        Box::pin(async move {
+            bail!(
+                "Unix sockets are not supported on this platform: {}",
+                path.display()
+            )
+        })
+    }
+}
+
+/// The transport of an upstream.
+#[derive(Clone)]
+pub(crate) struct Transport {
+    connector: Arc<dyn Connector>,
+    /// Whether TLS is used whatever the scheme of the upstream.
+    tls: bool,
+    /// Whether the transport is Tor, which alone reaches onion services.
+    tor: bool,
+}
+
+/// Creates the connector of `config`, with TLS left to the caller for the `Tls` transport.
+pub(crate) fn build(config: &ConnectorConfig) -> Result<Arc<dyn Connector>> {
+    let connector: Arc<dyn Connector> = match config {
+        ConnectorConfig::Direct | ConnectorConfig::Tls => Arc::new(DirectConnector),
+        ConnectorConfig::Socks5 { address } => Arc::new(Socks5Connector {
+            proxy: SocketAddr::from_str(address)
+                .map_err(|e| anyhow::anyhow!("Failed to parse SOCKS5 address: {}", e))?,
+        }),
+        ConnectorConfig::HttpProxy { address, auth } => {
+            if address.rsplit_once(':').is_none() {
+                bail!("Missing port in HTTP proxy address {}", address);
+            }
+            if let Some(auth) = auth {
+                auth.check()?;
+            }
+            Arc::new(HttpProxyConnector {
+                proxy: address.clone(),
+                auth: auth.clone(),
+            })
+        }
+        ConnectorConfig::Unix { path } => Arc::new(UnixConnector { path: path.clone() }),
+        ConnectorConfig::Ssh(config) => Arc::new(SshConnector::new(config.clone())?),
+        ConnectorConfig::Tor(config) => Arc::new(TorConnector::new(config)?),
+        ConnectorConfig::WireGuard(config) => Arc::new(WireGuardConnector::new(config.clone())?),
+        ConnectorConfig::Failover(config) => Arc::new(FailoverConnector::new(config)?),
+        ConnectorConfig::Custom(connector) => connector.clone(),
+    };
+    Ok(connector)
+}
+
+/// Whether `config` only goes through Tor.
+fn is_tor(config: &ConnectorConfig) -> bool {
+    match config {
+        ConnectorConfig::Tor(_) => true,
+        ConnectorConfig::Failover(failover) => failover.paths.iter().all(is_tor),
+        _ => false,
+    }
+}
+
+impl Transport {
+    fn new(config: &ConnectorConfig) -> Result<Self> {
+        Ok(Transport {
+            connector: build(config)?,
+            tls: matches!(config, ConnectorConfig::Tls),
+            tor: is_tor(config),
+        })
+    }
+
+    /// Describes the transport in logs.
+    pub(crate) fn describe(&self) -> String {
+        self.connector.describe()
+    }
+}
+
+/// The transports of the upstreams.
+pub(crate) struct Connectors {
+    /// Transports of single upstreams, by lowercase upstream.
+    upstreams: Vec<(String, Transport)>,
+    /// Transports of the destinations matching host patterns, in order, the pooled clients when `None`.
+    routes: Vec<(Vec<String>, Option<Transport>)>,
+    /// Transport of the other upstreams, the pooled clients when `None`.
+    default: Option<Transport>,
+    /// Hosts reached directly whatever the egress routes and default transport, if any.
+    bypass: Option<Arc<Bypass>>,
+    tls: TlsConnector,
+}
+
+impl Connectors {
+    /// Creates the transports of `configs` and `routes`, with `default` for the other upstreams, except for the hosts
+    /// `bypass` sends directly, and TLS with `tls`.
+    ///
+    /// Fails if an upstream is configured twice, a route has no host or an address is invalid.
+    pub(crate) fn new(
+        configs: &[UpstreamConnectorConfig],
+        routes: &[EgressRoute],
+        default: Option<&ConnectorConfig>,
+        bypass: Option<Arc<Bypass>>,
+        tls: &ClientConfig,
+    ) -> Result<Self> {
+        let mut upstreams: Vec<(String, Transport)> = Vec::new();
+        for config in configs {
+            let upstream = config.upstream.to_ascii_lowercase();
+            if upstreams.iter().any(|(other, _)| *other == upstream) {
+                bail!("Duplicate connector for upstream {}", config.upstream);
+            }
+            let transport = Transport::new(&config.connector).context(format!(
+                "Invalid connector for upstream {}",
+                config.upstream
+            ))?;
+            upstreams.push((upstream, transport));
+        }
+        let mut egress_routes = Vec::new();
+        for (index, route) in routes.iter().enumerate() {
+            if route.hosts.is_empty() {
+                bail!("Egress route {} has no host", index);
+            }
+            let transport = match &route.connector {
+                ConnectorConfig::Direct => None,
+                connector => Some(
+                    Transport::new(connector)
+                        .context(format!("Invalid connector for egress route {}", index))?,
+                ),
+            };
+            egress_routes.push((route.hosts.clone(), transport));
+        }
+        let default = default.map(Transport::new).transpose()?;
+        // Connections over a transport speak HTTP/1.1
+        let mut tls = tls.clone();
+        tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Connectors {
+            upstreams,
+            routes: egress_routes,
+            default,
+            bypass,
+            tls: TlsConnector::from(Arc::new(tls)),
+        })
+    }
+
+    /// Returns the transport of `upstream`, as returned by `upstream_key`, on host `host`, or `None` for the pooled
+    /// clients. The transport of the upstream itself takes precedence over the egress routes.
+    pub(crate) fn find(&self, upstream: &str, host: &str) -> Option<&Transport> {
+        match self
+            .upstreams
+            .iter()
+            .find(|(other, _)| other.eq_ignore_ascii_case(upstream))
+        {
+            Some((_, transport)) => Some(transport),
+            None => self.route(host),
+        }
+    }
+
+    /// Returns the transport of the destinations on `host`: none for bypassed hosts, the one of the first egress
+    /// route matching it, or the default one.
+    fn route(&self, host: &str) -> Option<&Transport> {
+        if self
+            .bypass
+            .as_ref()
+            .is_some_and(|bypass| bypass.sends_direct(host))
+        {
+            return None;
+        }
+        // IPv6 literals are matched without their brackets
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let route = self.routes.iter().find(|(hosts, _)| {
+            hosts
+                .iter()
+                .any(|pattern| pattern == "*" || tunnel::host_matches(pattern, host))
+        });
+        match route {
+            Some((_, transport)) => transport.as_ref(),
+            None => self.default.as_ref(),
+        }
+    }
+
+    /// Opens the stream of a `CONNECT` tunnel to `target`, as `host:port`, through the transport of the egress route
+    /// matching its host or the default one, or a TCP connection if there is none.
+    pub(crate) async fn open_tunnel(&self, target: &str) -> Result<Box<dyn UpstreamStream>> {
+        let (host, port) = target
+            .rsplit_once(':')
+            .context(format!("Missing port in tunnel target {}", target))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port: u16 = port
+            .parse()
+            .context(format!("Invalid port in tunnel target {}", target))?;
+        let transport = self.route(host);
+        if tor::is_onion(host) && !transport.is_some_and(|transport| transport.tor) {
+            return Err(tor::outside_tor(host));
+        }
+        match transport {
+            Some(transport) => transport.connector.connect(host, port).await,
+            None => DirectConnector.connect(host, port).await,
+        }
+    }
+
+    /// Opens a stream to the upstream of `url` over `transport`, over TLS with `server_name`, or the host of the URL,
+    /// as server name for `https` URLs or a TLS transport.
+    pub(crate) async fn connect(
+        &self,
+        transport: &Transport,
+        url: &Url,
+        server_name: Option<&str>,
+    ) -> Result<Box<dyn UpstreamStream>> {
+        let host = url.host_str().context(format!("Missing host in {}", url))?;
+        // IPv6 hosts are bracketed in URLs
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let port = url
+            .port_or_known_default()
+            .context(format!("Missing port in {}", url))?;
+        if tor::is_onion(host) && !transport.tor {
+            return Err(tor::outside_tor(host));
+        }
+        let stream = transport.connector.connect(host, port).await?;
+        if !transport.tls && url.scheme() != "https" {
+            return Ok(stream);
+        }
+        let server_name = server_name.unwrap_or(host);
+        let name = ServerName::try_from(server_name)
+            .map_err(|_| anyhow::anyhow!("Invalid TLS server name {}", server_name))?;
+        let stream = self
+            .tls
+            .connect(name, stream)
+            .await
+            .context(format!("TLS handshake with {} failed", url))?;
+        Ok(Box::new(stream))
+    }
+}
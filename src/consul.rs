@@ -0,0 +1,269 @@
+//! Upstream discovery from the health of a Consul service, followed with blocking queries so changes are applied as
+//! soon as Consul sees them.
+
+use std::{fmt, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use hyper::{client::HttpConnector, Body, Client, Request, StatusCode};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{debug, info, warn};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use tokio::net::lookup_host;
+
+use crate::{
+    discovery::{Discovery, Endpoint, UpstreamPool},
+    secret::SecretSource,
+};
+
+/// Longest time Consul holds a blocking query before answering unchanged results.
+const WAIT: Duration = Duration::from_secs(300);
+/// Margin over `WAIT` before a blocking query is abandoned, as Consul adds up to `WAIT / 16` of jitter.
+const WAIT_MARGIN: Duration = Duration::from_secs(60);
+/// Header carrying the index of the results, to block on in the next query.
+const INDEX_HEADER: &str = "x-consul-index";
+
+/// Discovery of the upstream pool from the instances of a Consul service.
+#[derive(Clone)]
+pub struct ConsulDiscoveryConfig {
+    /// Name of the service.
+    pub service: String,
+    /// URL of the Consul HTTP API. Defaults to `http://127.0.0.1:8500`, the local agent.
+    pub address: String,
+    /// Datacenter of the service. Defaults to the datacenter of the agent.
+    pub datacenter: Option<String>,
+    /// Only use the instances with this tag.
+    pub tag: Option<String>,
+    /// ACL token sent in `X-Consul-Token`, read before every query.
+    pub token: Option<SecretSource>,
+    /// Whether instances with warning checks still receive requests, weighted with their warning weight. By default
+    /// only instances whose checks all pass are used; instances with critical checks or in maintenance never are.
+    pub include_warning: bool,
+}
+
+impl Default for ConsulDiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            service: String::new(),
+            address: "http://127.0.0.1:8500".to_string(),
+            datacenter: None,
+            tag: None,
+            token: None,
+            include_warning: false,
+        }
+    }
+}
+
+impl fmt::Debug for ConsulDiscoveryConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConsulDiscoveryConfig")
+            .field("service", &self.service)
+            .field("address", &self.address)
+            .field("datacenter", &self.datacenter)
+            .field("tag", &self.tag)
+            .field("token", &self.token)
+            .field("include_warning", &self.include_warning)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceEntry {
+    node: Node,
+    service: Service,
+    #[serde(default)]
+    checks: Vec<Check>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Node {
+    address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Service {
+    /// Address of the instance, empty when it is the address of its node.
+    #[serde(default)]
+    address: String,
+    port: u16,
+    #[serde(default)]
+    weights: Option<Weights>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Weights {
+    passing: u32,
+    warning: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Check {
+    status: String,
+}
+
+/// Watcher of the instances of a Consul service.
+pub(crate) struct ConsulWatcher {
+    config: ConsulDiscoveryConfig,
+    retry_delay: Duration,
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+}
+
+impl ConsulWatcher {
+    /// Prepares the watch of the configured service, querying again after `retry_delay` when a query fails.
+    pub(crate) fn new(mut config: ConsulDiscoveryConfig, retry_delay: Duration) -> Self {
+        config.address = config.address.trim_end_matches('/').to_string();
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        ConsulWatcher {
+            config,
+            retry_delay,
+            client: Client::builder().build(connector),
+        }
+    }
+
+    /// Queries the instances, blocking until they change past `index` or the wait expires. Returns the upstreams of
+    /// the instances used with the index of the results.
+    async fn query(&self, index: u64) -> Result<(Vec<Endpoint>, u64)> {
+        let mut url = format!(
+            "{}/v1/health/service/{}?index={}&wait={}s",
+            self.config.address,
+            utf8_percent_encode(&self.config.service, NON_ALPHANUMERIC),
+            index,
+            WAIT.as_secs()
+        );
+        if !self.config.include_warning {
+            url.push_str("&passing=true");
+        }
+        if let Some(datacenter) = &self.config.datacenter {
+            url.push_str(&format!(
+                "&dc={}",
+                utf8_percent_encode(datacenter, NON_ALPHANUMERIC)
+            ));
+        }
+        if let Some(tag) = &self.config.tag {
+            url.push_str(&format!(
+                "&tag={}",
+                utf8_percent_encode(tag, NON_ALPHANUMERIC)
+            ));
+        }
+        let mut request = Request::get(url);
+        if let Some(token) = &self.config.token {
+            request = request.header("x-consul-token", token.read()?);
+        }
+        let response = tokio::time::timeout(
+            WAIT + WAIT_MARGIN,
+            self.client.request(request.body(Body::empty())?),
+        )
+        .await
+        .context("Consul did not answer the blocking query")??;
+        let status = response.status();
+        let next_index = response
+            .headers()
+            .get(INDEX_HEADER)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .unwrap_or(0);
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        if status != StatusCode::OK {
+            anyhow::bail!(
+                "Consul answered {}: {}",
+                status,
+                String::from_utf8_lossy(&body)
+            );
+        }
+        let entries: Vec<ServiceEntry> =
+            serde_json::from_slice(&body).context("Invalid Consul health entries")?;
+
+        let mut endpoints = Vec::new();
+        for entry in entries {
+            let weight = match self.weight(&entry) {
+                Some(weight) => weight,
+                None => continue,
+            };
+            let host = if entry.service.address.is_empty() {
+                entry.node.address
+            } else {
+                entry.service.address
+            };
+            let resolved = lookup_host((host.as_str(), entry.service.port)).await;
+            match resolved {
+                Ok(addresses) => endpoints.extend(
+                    addresses
+                        .take(1)
+                        .map(|address| Endpoint { address, weight }),
+                ),
+                Err(err) => warn!("Failed to resolve Consul instance {}: {}", host, err),
+            }
+        }
+        Ok((endpoints, next_index))
+    }
+
+    /// Returns the weight of an instance from its health, or `None` if it must not receive requests.
+    fn weight(&self, entry: &ServiceEntry) -> Option<u16> {
+        let status = |status: &str| entry.checks.iter().any(|check| check.status == status);
+        if status("critical") || status("maintenance") {
+            return None;
+        }
+        let weights = entry.service.weights.as_ref();
+        let weight = if status("warning") {
+            if !self.config.include_warning {
+                return None;
+            }
+            weights.map_or(1, |weights| weights.warning)
+        } else {
+            weights.map_or(1, |weights| weights.passing)
+        };
+        Some(weight.min(u32::from(u16::MAX)) as u16)
+    }
+}
+
+impl Discovery for ConsulWatcher {
+    fn describe(&self) -> String {
+        format!(
+            "Consul service {} at {}",
+            self.config.service, self.config.address
+        )
+    }
+
+    fn run(self: Arc<Self>, pool: Arc<UpstreamPool>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let mut index = 0;
+            loop {
+                match self.query(index).await {
+                    Ok((endpoints, next_index)) => {
+                        // An index going backwards means the Consul state was reset, so the watch starts over
+                        index = if next_index < index { 0 } else { next_index };
+                        debug!(
+                            "Consul service {} has {} usable instances at index {}",
+                            self.config.service,
+                            endpoints.len(),
+                            index
+                        );
+                        pool.apply(Ok(endpoints));
+                        // Without an index the query cannot block, so it is polled instead
+                        if index == 0 {
+                            tokio::time::sleep(self.retry_delay).await;
+                        }
+                    }
+                    Err(err) => {
+                        index = 0;
+                        pool.apply(Err(err));
+                        info!(
+                            "Querying Consul service {} again in {:?}",
+                            self.config.service, self.retry_delay
+                        );
+                        tokio::time::sleep(self.retry_delay).await;
+                    }
+                }
+            }
+        })
+    }
+}
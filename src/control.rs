@@ -0,0 +1,379 @@
+//! Control plane: a gRPC service mirroring the admin routes of the dashboard (metrics, cache flush, upstreams,
+//! canary and debug logging settings, sessions), so orchestration tools can manage many proxies programmatically.
+//!
+//! The service is defined in `proto/control.proto`. Serving it requires the `grpc` feature; without it, configuring
+//! the control plane is an error.
+
+use std::{fmt, net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+
+use crate::{secret::SecretSource, ProxyState};
+
+/// Messages and services of `proto/control.proto`, including a client for the control plane.
+#[cfg(feature = "grpc")]
+#[allow(clippy::all, missing_docs)]
+pub mod proto;
+
+/// Control plane settings.
+#[derive(Clone)]
+pub struct ControlPlaneConfig {
+    /// Address of the gRPC listener. Defaults to `127.0.0.1:50051`.
+    pub listen: SocketAddr,
+    /// Token required in the `authorization` metadata of every call as `Bearer <token>`. The control plane refuses to
+    /// start without one.
+    pub token: Option<SecretSource>,
+}
+
+impl Default for ControlPlaneConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from(([127, 0, 0, 1], 50051)),
+            token: None,
+        }
+    }
+}
+
+impl fmt::Debug for ControlPlaneConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ControlPlaneConfig")
+            .field("listen", &self.listen)
+            .field("token", &self.token)
+            .finish()
+    }
+}
+
+/// The gRPC control plane of the proxy.
+pub struct ControlPlane {
+    config: ControlPlaneConfig,
+    #[cfg(feature = "grpc")]
+    token: String,
+}
+
+impl ControlPlane {
+    /// Creates the control plane, failing if its token is missing, cannot be read or is empty.
+    #[cfg(feature = "grpc")]
+    pub fn new(config: ControlPlaneConfig) -> Result<Self> {
+        let token = match &config.token {
+            Some(token) => token.read()?,
+            None => String::new(),
+        };
+        if token.is_empty() {
+            anyhow::bail!("The control plane requires a token");
+        }
+        Ok(ControlPlane { config, token })
+    }
+
+    /// Creates the control plane.
+    #[cfg(not(feature = "grpc"))]
+    pub fn new(_config: ControlPlaneConfig) -> Result<Self> {
+        anyhow::bail!(
+            "Cannot serve the control plane: fortifynet_proxy was built without the `grpc` feature"
+        )
+    }
+
+    /// Returns the control plane settings.
+    pub fn config(&self) -> &ControlPlaneConfig {
+        &self.config
+    }
+
+    /// Serves the control plane of `state` until the listener fails.
+    #[cfg(feature = "grpc")]
+    pub(crate) async fn serve(&self, state: Arc<ProxyState>) -> Result<()> {
+        service::serve(&self.config, self.token.clone(), state).await
+    }
+
+    /// Serves the control plane of `state`.
+    #[cfg(not(feature = "grpc"))]
+    pub(crate) async fn serve(&self, _state: Arc<ProxyState>) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Every gRPC handler returns `tonic::Status`, whose size is out of our hands
+#[cfg(feature = "grpc")]
+#[allow(clippy::result_large_err)]
+mod service {
+    use std::{net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+
+    use anyhow::{Context, Result};
+    use futures::{stream, Stream};
+    use log::info;
+    use subtle::ConstantTimeEq;
+    use tonic::{transport::Server, Request, Response, Status};
+
+    use super::{
+        proto::{
+            control_plane_server::{self, ControlPlaneServer},
+            ConfigUpdate, FlushCacheRequest, FlushCacheResponse, GetStatsRequest,
+            GetUpstreamsRequest, RuntimeConfig, Stats, TerminateSessionRequest,
+            TerminateSessionResponse, Upstream, Upstreams, WatchStatsRequest,
+        },
+        ControlPlaneConfig,
+    };
+    use crate::{discovery::Endpoint, flush_cache, notify::Event, CacheCompressor, ProxyState};
+
+    /// Shortest interval between two updates of a stats stream.
+    const MIN_WATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+    pub(super) async fn serve(
+        config: &ControlPlaneConfig,
+        token: String,
+        state: Arc<ProxyState>,
+    ) -> Result<()> {
+        let service =
+            ControlPlaneServer::with_interceptor(Service { state }, move |request: Request<()>| {
+                let authorized = request
+                    .metadata()
+                    .get("authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .is_some_and(|value| {
+                        bool::from(value.trim().as_bytes().ct_eq(token.as_bytes()))
+                    });
+                if !authorized {
+                    return Err(Status::unauthenticated("Missing or invalid token"));
+                }
+                Ok(request)
+            });
+        info!("Control plane listening on {}", config.listen);
+        Server::builder()
+            .add_service(service)
+            .serve(config.listen)
+            .await
+            .context(format!("Control plane failed on {}", config.listen))
+    }
+
+    struct Service {
+        state: Arc<ProxyState>,
+    }
+
+    impl Service {
+        fn stats(&self) -> Stats {
+            stats(&self.state)
+        }
+
+        fn runtime_config(&self) -> RuntimeConfig {
+            RuntimeConfig {
+                canary_percent: self
+                    .state
+                    .canary
+                    .as_ref()
+                    .map(|canary| u32::from(canary.percent())),
+                debug_enabled: self
+                    .state
+                    .debug_log
+                    .as_ref()
+                    .map(|debug_log| debug_log.enabled()),
+                debug_percent: self
+                    .state
+                    .debug_log
+                    .as_ref()
+                    .map(|debug_log| u32::from(debug_log.percent())),
+            }
+        }
+
+        fn upstreams(&self) -> Result<Upstreams, Status> {
+            let pool = self.state.upstream_pool.as_ref().ok_or_else(|| {
+                Status::failed_precondition("Upstream discovery is not configured")
+            })?;
+            Ok(Upstreams {
+                upstreams: pool
+                    .endpoints()
+                    .into_iter()
+                    .map(|endpoint| Upstream {
+                        address: endpoint.address.to_string(),
+                        weight: u32::from(endpoint.weight),
+                    })
+                    .collect(),
+            })
+        }
+    }
+
+    fn stats(state: &ProxyState) -> Stats {
+        let entries = state.cache.lock().unwrap().len() as u64;
+        let compression = state
+            .cache_compressor
+            .as_ref()
+            .map(CacheCompressor::stats)
+            .unwrap_or_default();
+        let metrics = state.metrics.lock().unwrap();
+        Stats {
+            total_requests: metrics.total_requests,
+            cache_hits: metrics.cache_hits,
+            cache_misses: metrics.cache_misses,
+            cache_rejections: metrics.cache_rejections,
+            cache_entries: entries,
+            errors: metrics.error_counts.values().sum(),
+            rate_limited: metrics.rate_limited,
+            average_response_ms: metrics.get_average_response_time().as_secs_f64() * 1000.0,
+            error_counts: metrics
+                .error_counts
+                .iter()
+                .map(|(status, count)| (u32::from(*status), *count))
+                .collect(),
+            compressed_bodies: compression.bodies,
+            compression_original_bytes: compression.original_bytes,
+            compression_stored_bytes: compression.stored_bytes,
+            compression_ratio: compression.ratio(),
+        }
+    }
+
+    /// Parses a percentage of a request.
+    fn percent(value: u32, name: &str) -> Result<u8, Status> {
+        u8::try_from(value)
+            .ok()
+            .filter(|percent| *percent <= 100)
+            .ok_or_else(|| Status::invalid_argument(format!("{} must be between 0 and 100", name)))
+    }
+
+    #[tonic::async_trait]
+    impl control_plane_server::ControlPlane for Service {
+        type WatchStatsStream = Pin<Box<dyn Stream<Item = Result<Stats, Status>> + Send>>;
+
+        async fn get_stats(
+            &self,
+            _request: Request<GetStatsRequest>,
+        ) -> Result<Response<Stats>, Status> {
+            Ok(Response::new(self.stats()))
+        }
+
+        async fn watch_stats(
+            &self,
+            request: Request<WatchStatsRequest>,
+        ) -> Result<Response<Self::WatchStatsStream>, Status> {
+            let interval = match request.get_ref().interval_ms {
+                0 => Duration::from_secs(1),
+                interval_ms => Duration::from_millis(interval_ms).max(MIN_WATCH_INTERVAL),
+            };
+            let state = self.state.clone();
+            // The stream, and its interval, end when the client cancels the call
+            let updates = stream::unfold(tokio::time::interval(interval), move |mut interval| {
+                let state = state.clone();
+                async move {
+                    interval.tick().await;
+                    Some((Ok(stats(&state)), interval))
+                }
+            });
+            Ok(Response::new(Box::pin(updates)))
+        }
+
+        async fn flush_cache(
+            &self,
+            request: Request<FlushCacheRequest>,
+        ) -> Result<Response<FlushCacheResponse>, Status> {
+            let namespace = &request.get_ref().namespace;
+            let namespace = (!namespace.is_empty()).then_some(namespace.as_str());
+            let flushed = flush_cache(&self.state, namespace);
+            Ok(Response::new(FlushCacheResponse {
+                flushed: flushed as u64,
+            }))
+        }
+
+        async fn get_upstreams(
+            &self,
+            _request: Request<GetUpstreamsRequest>,
+        ) -> Result<Response<Upstreams>, Status> {
+            Ok(Response::new(self.upstreams()?))
+        }
+
+        async fn set_upstreams(
+            &self,
+            request: Request<Upstreams>,
+        ) -> Result<Response<Upstreams>, Status> {
+            let pool = self.state.upstream_pool.as_ref().ok_or_else(|| {
+                Status::failed_precondition("Upstream discovery is not configured")
+            })?;
+            let endpoints = request
+                .into_inner()
+                .upstreams
+                .into_iter()
+                .map(|upstream| {
+                    Ok(Endpoint {
+                        address: upstream.address.parse::<SocketAddr>().map_err(|_| {
+                            Status::invalid_argument(format!(
+                                "Invalid upstream address: {}",
+                                upstream.address
+                            ))
+                        })?,
+                        weight: u16::try_from(upstream.weight).map_err(|_| {
+                            Status::invalid_argument(format!(
+                                "Weight of upstream {} exceeds {}",
+                                upstream.address,
+                                u16::MAX
+                            ))
+                        })?,
+                    })
+                })
+                .collect::<Result<Vec<_>, Status>>()?;
+            info!(
+                "Setting {} upstreams from the control plane",
+                endpoints.len()
+            );
+            pool.update(endpoints);
+            Ok(Response::new(self.upstreams()?))
+        }
+
+        async fn push_config(
+            &self,
+            request: Request<ConfigUpdate>,
+        ) -> Result<Response<RuntimeConfig>, Status> {
+            let update = request.into_inner();
+            // Every setting is validated before any is applied, so a rejected update changes nothing
+            let canary_percent = update
+                .canary_percent
+                .map(|percent| {
+                    let canary = self.state.canary.as_ref().ok_or_else(|| {
+                        Status::failed_precondition("Canary splitting is disabled")
+                    })?;
+                    Ok::<_, Status>((canary, self::percent(percent, "canary_percent")?))
+                })
+                .transpose()?;
+            let debug_percent = update
+                .debug_percent
+                .map(|percent| self::percent(percent, "debug_percent"))
+                .transpose()?;
+            let debug_log = match (update.debug_enabled, debug_percent) {
+                (None, None) => None,
+                _ => Some(self.state.debug_log.as_ref().ok_or_else(|| {
+                    Status::failed_precondition("Debug logging is not configured")
+                })?),
+            };
+
+            if let Some((canary, percent)) = canary_percent {
+                canary.set_percent(percent);
+            }
+            if let Some(debug_log) = debug_log {
+                if let Some(enabled) = update.debug_enabled {
+                    debug_log.set_enabled(enabled);
+                }
+                if let Some(percent) = debug_percent {
+                    debug_log.set_percent(percent);
+                }
+            }
+            info!("Applied a configuration update from the control plane");
+            Ok(Response::new(self.runtime_config()))
+        }
+
+        async fn terminate_session(
+            &self,
+            request: Request<TerminateSessionRequest>,
+        ) -> Result<Response<TerminateSessionResponse>, Status> {
+            let request = request.into_inner();
+            let tracker = self
+                .state
+                .sessions
+                .as_ref()
+                .ok_or_else(|| Status::failed_precondition("Session tracking is disabled"))?;
+            let terminated = tracker.terminate(&request.id);
+            if request.ban {
+                tracker.ban(&request.id);
+                self.state.notifier.notify(Event::BanIssued {
+                    client: format!("session {}", request.id),
+                    reason: "banned from the control plane".to_string(),
+                });
+            }
+            Ok(Response::new(TerminateSessionResponse { terminated }))
+        }
+    }
+}
@@ -0,0 +1,807 @@
+// Messages and services of proto/control.proto, written in the form prost and tonic generate them. Keep them in step
+// with the proto file when changing either.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetStatsRequest {}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct WatchStatsRequest {
+    /// Interval between two updates, in milliseconds. Defaults to a second.
+    #[prost(uint64, tag = "1")]
+    pub interval_ms: u64,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Stats {
+    #[prost(uint64, tag = "1")]
+    pub total_requests: u64,
+    #[prost(uint64, tag = "2")]
+    pub cache_hits: u64,
+    #[prost(uint64, tag = "3")]
+    pub cache_misses: u64,
+    #[prost(uint64, tag = "4")]
+    pub cache_rejections: u64,
+    #[prost(uint64, tag = "5")]
+    pub cache_entries: u64,
+    #[prost(uint64, tag = "6")]
+    pub errors: u64,
+    #[prost(uint64, tag = "7")]
+    pub rate_limited: u64,
+    #[prost(double, tag = "8")]
+    pub average_response_ms: f64,
+    #[prost(map = "uint32, uint64", tag = "9")]
+    pub error_counts: ::std::collections::HashMap<u32, u64>,
+    /// Totals of the bodies compressed into the cache, all zero without cache compression.
+    #[prost(uint64, tag = "10")]
+    pub compressed_bodies: u64,
+    #[prost(uint64, tag = "11")]
+    pub compression_original_bytes: u64,
+    #[prost(uint64, tag = "12")]
+    pub compression_stored_bytes: u64,
+    #[prost(double, tag = "13")]
+    pub compression_ratio: f64,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct FlushCacheRequest {
+    /// Namespace of the entries to remove; every entry is removed when empty.
+    #[prost(string, tag = "1")]
+    pub namespace: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct FlushCacheResponse {
+    #[prost(uint64, tag = "1")]
+    pub flushed: u64,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GetUpstreamsRequest {}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Upstream {
+    /// `ip:port` of the upstream.
+    #[prost(string, tag = "1")]
+    pub address: ::prost::alloc::string::String,
+    /// Relative share of the requests sent to the upstream.
+    #[prost(uint32, tag = "2")]
+    pub weight: u32,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Upstreams {
+    #[prost(message, repeated, tag = "1")]
+    pub upstreams: ::prost::alloc::vec::Vec<Upstream>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConfigUpdate {
+    #[prost(uint32, optional, tag = "1")]
+    pub canary_percent: ::core::option::Option<u32>,
+    #[prost(bool, optional, tag = "2")]
+    pub debug_enabled: ::core::option::Option<bool>,
+    #[prost(uint32, optional, tag = "3")]
+    pub debug_percent: ::core::option::Option<u32>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RuntimeConfig {
+    #[prost(uint32, optional, tag = "1")]
+    pub canary_percent: ::core::option::Option<u32>,
+    #[prost(bool, optional, tag = "2")]
+    pub debug_enabled: ::core::option::Option<bool>,
+    #[prost(uint32, optional, tag = "3")]
+    pub debug_percent: ::core::option::Option<u32>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TerminateSessionRequest {
+    #[prost(string, tag = "1")]
+    pub id: ::prost::alloc::string::String,
+    /// Whether the further requests of the session are rejected.
+    #[prost(bool, tag = "2")]
+    pub ban: bool,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TerminateSessionResponse {
+    /// Whether the session was active.
+    #[prost(bool, tag = "1")]
+    pub terminated: bool,
+}
+/// Client implementations.
+pub mod control_plane_client {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::http::Uri;
+    use tonic::codegen::*;
+    #[derive(Debug, Clone)]
+    pub struct ControlPlaneClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+    impl ControlPlaneClient<tonic::transport::Channel> {
+        /// Attempt to create a new client by connecting to a given endpoint.
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(conn))
+        }
+    }
+    impl<T> ControlPlaneClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::Error: Into<StdError>,
+        T::ResponseBody: Body<Data = Bytes> + Send + 'static,
+        <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+    {
+        pub fn new(inner: T) -> Self {
+            let inner = tonic::client::Grpc::new(inner);
+            Self { inner }
+        }
+        pub fn with_origin(inner: T, origin: Uri) -> Self {
+            let inner = tonic::client::Grpc::with_origin(inner, origin);
+            Self { inner }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> ControlPlaneClient<InterceptedService<T, F>>
+        where
+            F: tonic::service::Interceptor,
+            T::ResponseBody: Default,
+            T: tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+                Response = http::Response<
+                    <T as tonic::client::GrpcService<tonic::body::BoxBody>>::ResponseBody,
+                >,
+            >,
+            <T as tonic::codegen::Service<http::Request<tonic::body::BoxBody>>>::Error:
+                Into<StdError> + Send + Sync,
+        {
+            ControlPlaneClient::new(InterceptedService::new(inner, interceptor))
+        }
+        /// Compress requests with the given encoding.
+        ///
+        /// This requires the server to support it otherwise it might respond with an
+        /// error.
+        #[must_use]
+        pub fn send_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.inner = self.inner.send_compressed(encoding);
+            self
+        }
+        /// Enable decompressing responses.
+        #[must_use]
+        pub fn accept_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.inner = self.inner.accept_compressed(encoding);
+            self
+        }
+        /// Limits the maximum size of a decoded message.
+        ///
+        /// Default: `4MB`
+        #[must_use]
+        pub fn max_decoding_message_size(mut self, limit: usize) -> Self {
+            self.inner = self.inner.max_decoding_message_size(limit);
+            self
+        }
+        /// Limits the maximum size of an encoded message.
+        ///
+        /// Default: `usize::MAX`
+        #[must_use]
+        pub fn max_encoding_message_size(mut self, limit: usize) -> Self {
+            self.inner = self.inner.max_encoding_message_size(limit);
+            self
+        }
+        /// Returns the current metrics.
+        pub async fn get_stats(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetStatsRequest>,
+        ) -> std::result::Result<tonic::Response<super::Stats>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/fortifynet.control.v1.ControlPlane/GetStats",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "fortifynet.control.v1.ControlPlane",
+                "GetStats",
+            ));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Streams the metrics at an interval until the client cancels.
+        pub async fn watch_stats(
+            &mut self,
+            request: impl tonic::IntoRequest<super::WatchStatsRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::Stats>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/fortifynet.control.v1.ControlPlane/WatchStats",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "fortifynet.control.v1.ControlPlane",
+                "WatchStats",
+            ));
+            self.inner.server_streaming(req, path, codec).await
+        }
+        /// Removes the cached responses of a cache namespace, or all of them.
+        pub async fn flush_cache(
+            &mut self,
+            request: impl tonic::IntoRequest<super::FlushCacheRequest>,
+        ) -> std::result::Result<tonic::Response<super::FlushCacheResponse>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/fortifynet.control.v1.ControlPlane/FlushCache",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "fortifynet.control.v1.ControlPlane",
+                "FlushCache",
+            ));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Returns the upstreams of the pool.
+        pub async fn get_upstreams(
+            &mut self,
+            request: impl tonic::IntoRequest<super::GetUpstreamsRequest>,
+        ) -> std::result::Result<tonic::Response<super::Upstreams>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/fortifynet.control.v1.ControlPlane/GetUpstreams",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "fortifynet.control.v1.ControlPlane",
+                "GetUpstreams",
+            ));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Replaces the upstreams of the pool.
+        pub async fn set_upstreams(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Upstreams>,
+        ) -> std::result::Result<tonic::Response<super::Upstreams>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/fortifynet.control.v1.ControlPlane/SetUpstreams",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "fortifynet.control.v1.ControlPlane",
+                "SetUpstreams",
+            ));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Changes the settings adjustable at runtime, returning their values.
+        pub async fn push_config(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ConfigUpdate>,
+        ) -> std::result::Result<tonic::Response<super::RuntimeConfig>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/fortifynet.control.v1.ControlPlane/PushConfig",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "fortifynet.control.v1.ControlPlane",
+                "PushConfig",
+            ));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Ends a session, optionally rejecting its further requests.
+        pub async fn terminate_session(
+            &mut self,
+            request: impl tonic::IntoRequest<super::TerminateSessionRequest>,
+        ) -> std::result::Result<tonic::Response<super::TerminateSessionResponse>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/fortifynet.control.v1.ControlPlane/TerminateSession",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "fortifynet.control.v1.ControlPlane",
+                "TerminateSession",
+            ));
+            self.inner.unary(req, path, codec).await
+        }
+    }
+}
+/// Server implementations.
+pub mod control_plane_server {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    /// Trait containing gRPC methods that should be implemented for use with ControlPlaneServer.
+    #[async_trait]
+    pub trait ControlPlane: Send + Sync + 'static {
+        /// Returns the current metrics.
+        async fn get_stats(
+            &self,
+            request: tonic::Request<super::GetStatsRequest>,
+        ) -> std::result::Result<tonic::Response<super::Stats>, tonic::Status>;
+        /// Server streaming response type for the WatchStats method.
+        type WatchStatsStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::Stats, tonic::Status>,
+            > + Send
+            + 'static;
+        /// Streams the metrics at an interval until the client cancels.
+        async fn watch_stats(
+            &self,
+            request: tonic::Request<super::WatchStatsRequest>,
+        ) -> std::result::Result<tonic::Response<Self::WatchStatsStream>, tonic::Status>;
+        /// Removes the cached responses of a cache namespace, or all of them.
+        async fn flush_cache(
+            &self,
+            request: tonic::Request<super::FlushCacheRequest>,
+        ) -> std::result::Result<tonic::Response<super::FlushCacheResponse>, tonic::Status>;
+        /// Returns the upstreams of the pool.
+        async fn get_upstreams(
+            &self,
+            request: tonic::Request<super::GetUpstreamsRequest>,
+        ) -> std::result::Result<tonic::Response<super::Upstreams>, tonic::Status>;
+        /// Replaces the upstreams of the pool.
+        async fn set_upstreams(
+            &self,
+            request: tonic::Request<super::Upstreams>,
+        ) -> std::result::Result<tonic::Response<super::Upstreams>, tonic::Status>;
+        /// Changes the settings adjustable at runtime, returning their values.
+        async fn push_config(
+            &self,
+            request: tonic::Request<super::ConfigUpdate>,
+        ) -> std::result::Result<tonic::Response<super::RuntimeConfig>, tonic::Status>;
+        /// Ends a session, optionally rejecting its further requests.
+        async fn terminate_session(
+            &self,
+            request: tonic::Request<super::TerminateSessionRequest>,
+        ) -> std::result::Result<tonic::Response<super::TerminateSessionResponse>, tonic::Status>;
+    }
+    #[derive(Debug)]
+    pub struct ControlPlaneServer<T: ControlPlane> {
+        inner: _Inner<T>,
+        accept_compression_encodings: EnabledCompressionEncodings,
+        send_compression_encodings: EnabledCompressionEncodings,
+        max_decoding_message_size: Option<usize>,
+        max_encoding_message_size: Option<usize>,
+    }
+    struct _Inner<T>(Arc<T>);
+    impl<T: ControlPlane> ControlPlaneServer<T> {
+        pub fn new(inner: T) -> Self {
+            Self::from_arc(Arc::new(inner))
+        }
+        pub fn from_arc(inner: Arc<T>) -> Self {
+            let inner = _Inner(inner);
+            Self {
+                inner,
+                accept_compression_encodings: Default::default(),
+                send_compression_encodings: Default::default(),
+                max_decoding_message_size: None,
+                max_encoding_message_size: None,
+            }
+        }
+        pub fn with_interceptor<F>(inner: T, interceptor: F) -> InterceptedService<Self, F>
+        where
+            F: tonic::service::Interceptor,
+        {
+            InterceptedService::new(Self::new(inner), interceptor)
+        }
+        /// Enable decompressing requests with the given encoding.
+        #[must_use]
+        pub fn accept_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.accept_compression_encodings.enable(encoding);
+            self
+        }
+        /// Compress responses with the given encoding, if the client supports it.
+        #[must_use]
+        pub fn send_compressed(mut self, encoding: CompressionEncoding) -> Self {
+            self.send_compression_encodings.enable(encoding);
+            self
+        }
+        /// Limits the maximum size of a decoded message.
+        ///
+        /// Default: `4MB`
+        #[must_use]
+        pub fn max_decoding_message_size(mut self, limit: usize) -> Self {
+            self.max_decoding_message_size = Some(limit);
+            self
+        }
+        /// Limits the maximum size of an encoded message.
+        ///
+        /// Default: `usize::MAX`
+        #[must_use]
+        pub fn max_encoding_message_size(mut self, limit: usize) -> Self {
+            self.max_encoding_message_size = Some(limit);
+            self
+        }
+    }
+    impl<T, B> tonic::codegen::Service<http::Request<B>> for ControlPlaneServer<T>
+    where
+        T: ControlPlane,
+        B: Body + Send + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = std::convert::Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(
+            &mut self,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::result::Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/fortifynet.control.v1.ControlPlane/GetStats" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetStatsSvc<T: ControlPlane>(pub Arc<T>);
+                    impl<T: ControlPlane> tonic::server::UnaryService<super::GetStatsRequest> for GetStatsSvc<T> {
+                        type Response = super::Stats;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GetStatsRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as ControlPlane>::get_stats(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = GetStatsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/fortifynet.control.v1.ControlPlane/WatchStats" => {
+                    #[allow(non_camel_case_types)]
+                    struct WatchStatsSvc<T: ControlPlane>(pub Arc<T>);
+                    impl<T: ControlPlane>
+                        tonic::server::ServerStreamingService<super::WatchStatsRequest>
+                        for WatchStatsSvc<T>
+                    {
+                        type Response = super::Stats;
+                        type ResponseStream = T::WatchStatsStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::WatchStatsRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as ControlPlane>::watch_stats(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = WatchStatsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/fortifynet.control.v1.ControlPlane/FlushCache" => {
+                    #[allow(non_camel_case_types)]
+                    struct FlushCacheSvc<T: ControlPlane>(pub Arc<T>);
+                    impl<T: ControlPlane> tonic::server::UnaryService<super::FlushCacheRequest> for FlushCacheSvc<T> {
+                        type Response = super::FlushCacheResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::FlushCacheRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as ControlPlane>::flush_cache(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = FlushCacheSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/fortifynet.control.v1.ControlPlane/GetUpstreams" => {
+                    #[allow(non_camel_case_types)]
+                    struct GetUpstreamsSvc<T: ControlPlane>(pub Arc<T>);
+                    impl<T: ControlPlane> tonic::server::UnaryService<super::GetUpstreamsRequest>
+                        for GetUpstreamsSvc<T>
+                    {
+                        type Response = super::Upstreams;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::GetUpstreamsRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as ControlPlane>::get_upstreams(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = GetUpstreamsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/fortifynet.control.v1.ControlPlane/SetUpstreams" => {
+                    #[allow(non_camel_case_types)]
+                    struct SetUpstreamsSvc<T: ControlPlane>(pub Arc<T>);
+                    impl<T: ControlPlane> tonic::server::UnaryService<super::Upstreams> for SetUpstreamsSvc<T> {
+                        type Response = super::Upstreams;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Upstreams>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as ControlPlane>::set_upstreams(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SetUpstreamsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/fortifynet.control.v1.ControlPlane/PushConfig" => {
+                    #[allow(non_camel_case_types)]
+                    struct PushConfigSvc<T: ControlPlane>(pub Arc<T>);
+                    impl<T: ControlPlane> tonic::server::UnaryService<super::ConfigUpdate> for PushConfigSvc<T> {
+                        type Response = super::RuntimeConfig;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ConfigUpdate>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as ControlPlane>::push_config(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = PushConfigSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/fortifynet.control.v1.ControlPlane/TerminateSession" => {
+                    #[allow(non_camel_case_types)]
+                    struct TerminateSessionSvc<T: ControlPlane>(pub Arc<T>);
+                    impl<T: ControlPlane>
+                        tonic::server::UnaryService<super::TerminateSessionRequest>
+                        for TerminateSessionSvc<T>
+                    {
+                        type Response = super::TerminateSessionResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::TerminateSessionRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as ControlPlane>::terminate_session(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = TerminateSessionSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => Box::pin(async move {
+                    Ok(http::Response::builder()
+                        .status(200)
+                        .header("grpc-status", "12")
+                        .header("content-type", "application/grpc")
+                        .body(empty_body())
+                        .unwrap())
+                }),
+            }
+        }
+    }
+    impl<T: ControlPlane> Clone for ControlPlaneServer<T> {
+        fn clone(&self) -> Self {
+            let inner = self.inner.clone();
+            Self {
+                inner,
+                accept_compression_encodings: self.accept_compression_encodings,
+                send_compression_encodings: self.send_compression_encodings,
+                max_decoding_message_size: self.max_decoding_message_size,
+                max_encoding_message_size: self.max_encoding_message_size,
+            }
+        }
+    }
+    impl<T: ControlPlane> Clone for _Inner<T> {
+        fn clone(&self) -> Self {
+            Self(Arc::clone(&self.0))
+        }
+    }
+    impl<T: std::fmt::Debug> std::fmt::Debug for _Inner<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.0)
+        }
+    }
+    impl<T: ControlPlane> tonic::server::NamedService for ControlPlaneServer<T> {
+        const NAME: &'static str = "fortifynet.control.v1.ControlPlane";
+    }
+}
@@ -0,0 +1,202 @@
+//! `Set-Cookie` rewriting for the reverse proxy: public domain and path, enforced attributes, and name prefixes
+//! keeping the cookies of several upstreams apart under one domain.
+
+use anyhow::{Context, Result};
+use hyper::{
+    header::{HeaderValue, COOKIE, SET_COOKIE},
+    HeaderMap,
+};
+use url::Url;
+
+/// Value of the `SameSite` cookie attribute.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    /// `SameSite=Strict`
+    Strict,
+    /// `SameSite=Lax`
+    Lax,
+    /// `SameSite=None`, which also forces `Secure`.
+    None,
+}
+
+impl SameSite {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// Cookie rewriting settings for the responses of an upstream.
+#[derive(Clone, Debug, Default)]
+pub struct CookieRewriteConfig {
+    /// Origin of the upstream whose cookies are rewritten, such as `http://10.0.0.5:8080`. Applies to every upstream when `None`.
+    pub upstream: Option<String>,
+    /// Public domain replacing the `Domain` attribute of cookies that carry one.
+    pub domain: Option<String>,
+    /// Public path prefix prepended to the `Path` attribute, such as `/app1` when the upstream is served under `/app1/`.
+    pub path_prefix: Option<String>,
+    /// Whether the `Secure` attribute is added to every cookie.
+    pub secure: bool,
+    /// Whether the `HttpOnly` attribute is added to every cookie.
+    pub http_only: bool,
+    /// `SameSite` attribute set on every cookie, replacing the upstream's.
+    pub same_site: Option<SameSite>,
+    /// Prefix added to cookie names. Only cookies carrying the prefix are sent back to the upstream, without it.
+    pub name_prefix: Option<String>,
+}
+
+/// Applies the cookie rewriting rules of each upstream.
+#[derive(Default)]
+pub struct CookieRewriter {
+    rules: Vec<(Option<String>, CookieRewriteConfig)>,
+}
+
+impl CookieRewriter {
+    /// Creates a rewriter for the given rules; the first rule matching an upstream applies.
+    ///
+    /// Fails if the upstream of a rule is not a valid URL.
+    pub fn new(rules: &[CookieRewriteConfig]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let upstream = match &rule.upstream {
+                    Some(upstream) => Some(
+                        origin(upstream)
+                            .context(format!("Invalid cookie rewrite upstream: {}", upstream))?,
+                    ),
+                    None => None,
+                };
+                Ok((upstream, rule.clone()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(CookieRewriter { rules })
+    }
+
+    /// Returns the rule applying to `upstream`.
+    fn rule_for(&self, upstream: Option<&str>) -> Option<&CookieRewriteConfig> {
+        let upstream = upstream.and_then(origin);
+        self.rules
+            .iter()
+            .find(|(origin, _)| origin.is_none() || *origin == upstream)
+            .map(|(_, rule)| rule)
+    }
+
+    /// Removes the name prefix from the cookies of a request to `upstream`, dropping cookies without it.
+    pub fn rewrite_request(&self, upstream: Option<&str>, headers: &mut HeaderMap) {
+        let prefix = match self
+            .rule_for(upstream)
+            .and_then(|rule| rule.name_prefix.as_deref())
+        {
+            Some(prefix) => prefix,
+            None => return,
+        };
+        let cookies: Vec<String> = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|cookie| cookie.trim().strip_prefix(prefix))
+            .map(str::to_string)
+            .collect();
+        headers.remove(COOKIE);
+        if let Ok(value) = HeaderValue::from_str(&cookies.join("; ")) {
+            if !cookies.is_empty() {
+                headers.insert(COOKIE, value);
+            }
+        }
+    }
+
+    /// Rewrites the `Set-Cookie` headers of a response from `upstream`.
+    pub fn rewrite_response(&self, upstream: Option<&str>, headers: &mut HeaderMap) {
+        let rule = match self.rule_for(upstream) {
+            Some(rule) => rule,
+            None => return,
+        };
+        let cookies: Vec<HeaderValue> = headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| {
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|cookie| {
+                        HeaderValue::from_str(&rewrite_set_cookie(rule, cookie)).ok()
+                    })
+                    .unwrap_or_else(|| value.clone())
+            })
+            .collect();
+        headers.remove(SET_COOKIE);
+        for cookie in cookies {
+            headers.append(SET_COOKIE, cookie);
+        }
+    }
+}
+
+/// Rewrites a single `Set-Cookie` value according to `rule`.
+fn rewrite_set_cookie(rule: &CookieRewriteConfig, cookie: &str) -> String {
+    let mut parts = cookie.split(';').map(str::trim);
+    let name_value = parts.next().unwrap_or_default();
+    let mut attributes = Vec::new();
+    let mut path = None;
+    for attribute in parts.filter(|attribute| !attribute.is_empty()) {
+        let name = attribute
+            .split('=')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        match name.as_str() {
+            "domain" if rule.domain.is_some() => {
+                attributes.push(format!(
+                    "Domain={}",
+                    rule.domain.as_deref().unwrap_or_default()
+                ));
+            }
+            "path" => {
+                path = attribute
+                    .split_once('=')
+                    .map(|(_, value)| value.trim().to_string())
+            }
+            "secure" if rule.secure || rule.same_site == Some(SameSite::None) => {}
+            "httponly" if rule.http_only => {}
+            "samesite" if rule.same_site.is_some() => {}
+            _ => attributes.push(attribute.to_string()),
+        }
+    }
+
+    let mut rewritten = match &rule.name_prefix {
+        Some(prefix) => format!("{}{}", prefix, name_value),
+        None => name_value.to_string(),
+    };
+    match (&rule.path_prefix, path) {
+        (Some(prefix), path) => {
+            let path = path.unwrap_or_else(|| "/".to_string());
+            let prefix = prefix.trim_end_matches('/');
+            rewritten.push_str(&format!("; Path={}{}", prefix, path));
+        }
+        (None, Some(path)) => rewritten.push_str(&format!("; Path={}", path)),
+        (None, None) => {}
+    }
+    for attribute in attributes {
+        rewritten.push_str("; ");
+        rewritten.push_str(&attribute);
+    }
+    if rule.secure || rule.same_site == Some(SameSite::None) {
+        rewritten.push_str("; Secure");
+    }
+    if rule.http_only {
+        rewritten.push_str("; HttpOnly");
+    }
+    if let Some(same_site) = rule.same_site {
+        rewritten.push_str(&format!("; SameSite={}", same_site.as_str()));
+    }
+    rewritten
+}
+
+/// Returns the ASCII serialization of the origin of `url`.
+fn origin(url: &str) -> Option<String> {
+    Some(Url::parse(url).ok()?.origin().ascii_serialization())
+}
@@ -0,0 +1,117 @@
+//! Running the proxy as a Unix daemon: detached from the terminal, with its ID in a pidfile and its logs in a file.
+//!
+//! [`daemonize`] must be called before the Tokio runtime or any other thread is started, as only the calling thread
+//! survives the forks.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+/// Settings of [`daemonize`].
+#[derive(Clone, Debug, Default)]
+pub struct DaemonOptions {
+    /// File the ID of the daemon is written to. Starting is refused while the process it names is running.
+    pub pid_file: Option<PathBuf>,
+    /// File the standard output and error of the daemon, and so its logs, are appended to. Discarded when `None`.
+    pub log_file: Option<PathBuf>,
+}
+
+/// Removes the pidfile when dropped.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Detaches the process from its terminal and session, returning in the daemon only; the original process exits once
+/// the daemon is started. The working directory is kept, so relative paths of the configuration still resolve.
+pub fn daemonize(options: &DaemonOptions) -> Result<Option<PidFile>> {
+    if let Some(path) = &options.pid_file {
+        check_pid_file(path)?;
+    }
+    // Open the files before detaching, so that errors are still reported to the terminal
+    let log = match &options.log_file {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?,
+        None => File::options()
+            .write(true)
+            .open("/dev/null")
+            .context("Failed to open /dev/null")?,
+    };
+    let null = File::open("/dev/null").context("Failed to open /dev/null")?;
+
+    // Fork twice so the daemon is neither a process group leader nor able to reacquire a terminal
+    fork()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to start a new session");
+    }
+    fork()?;
+
+    let pid_file = match &options.pid_file {
+        Some(path) => {
+            fs::write(path, format!("{}\n", std::process::id()))
+                .with_context(|| format!("Failed to write pidfile {}", path.display()))?;
+            Some(PidFile { path: path.clone() })
+        }
+        None => None,
+    };
+    redirect(&null, libc::STDIN_FILENO)?;
+    redirect(&log, libc::STDOUT_FILENO)?;
+    redirect(&log, libc::STDERR_FILENO)?;
+    Ok(pid_file)
+}
+
+/// Forks, exiting in the parent.
+fn fork() -> Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()).context("Failed to fork"),
+        0 => Ok(()),
+        _ => std::process::exit(0),
+    }
+}
+
+/// Makes `fd` refer to `file`.
+fn redirect(file: &File, fd: libc::c_int) -> Result<()> {
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to redirect the standard streams");
+    }
+    Ok(())
+}
+
+/// Fails if the pidfile at `path` names a running process. Stale pidfiles are left to be overwritten.
+fn check_pid_file(path: &Path) -> Result<()> {
+    let pid = match fs::read_to_string(path) {
+        Ok(pid) => pid,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read pidfile {}", path.display()))
+        }
+    };
+    let pid = match pid.trim().parse::<libc::pid_t>() {
+        Ok(pid) if pid > 0 => pid,
+        _ => return Ok(()),
+    };
+    if unsafe { libc::kill(pid, 0) } == 0
+        || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    {
+        anyhow::bail!(
+            "The proxy is already running as process {} according to {}",
+            pid,
+            path.display()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,248 @@
+//! Sampled full-transaction logging: complete headers, and optionally the beginning of the bodies, of a share of the
+//! requests or of the requests matching a filter.
+
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+};
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use hyper::{
+    body::Bytes,
+    header::{HeaderName, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION},
+    Body, HeaderMap, Request, Response,
+};
+use log::{error, info};
+use rand::Rng;
+
+use crate::secret;
+
+/// Headers whose values are redacted from the log.
+const CREDENTIAL_HEADERS: [HeaderName; 3] = [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION];
+
+/// Selects requests that are always logged while debug logging is enabled.
+#[derive(Clone, Debug)]
+pub enum DebugFilter {
+    /// Requests carrying the header, with the given value if any.
+    Header {
+        /// Name of the header.
+        name: String,
+        /// Required value of the header; any value matches when `None`.
+        value: Option<String>,
+    },
+    /// Requests whose path starts with the prefix.
+    PathPrefix(String),
+    /// Requests from the client address.
+    ClientIp(IpAddr),
+}
+
+impl DebugFilter {
+    fn matches<T>(&self, req: &Request<T>, client: IpAddr) -> bool {
+        match self {
+            DebugFilter::Header { name, value } => {
+                req.headers().get_all(name.as_str()).iter().any(|found| {
+                    value
+                        .as_ref()
+                        .is_none_or(|value| found.as_bytes() == value.as_bytes())
+                })
+            }
+            DebugFilter::PathPrefix(prefix) => req.uri().path().starts_with(prefix.as_str()),
+            DebugFilter::ClientIp(ip) => *ip == client,
+        }
+    }
+}
+
+/// Debug logging settings.
+#[derive(Clone, Debug)]
+pub struct DebugLogConfig {
+    /// Whether logging starts enabled; it can be toggled at runtime through the admin API.
+    pub enabled: bool,
+    /// Percentage of the requests logged, between `0` and `100`.
+    pub percent: u8,
+    /// Requests matching any of these filters are logged regardless of the percentage.
+    pub filters: Vec<DebugFilter>,
+    /// Whether the beginning of the request and response bodies is logged too. Logged bodies are held back until
+    /// `max_body_size` bytes arrived or they ended.
+    pub log_bodies: bool,
+    /// Number of body bytes logged.
+    pub max_body_size: usize,
+}
+
+impl Default for DebugLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            percent: 0,
+            filters: Vec::new(),
+            log_bodies: false,
+            max_body_size: 4096,
+        }
+    }
+}
+
+/// Logs the transactions selected by sampling or filters.
+pub struct DebugLogger {
+    config: DebugLogConfig,
+    enabled: AtomicBool,
+    percent: AtomicU8,
+    next_id: AtomicU64,
+}
+
+impl DebugLogger {
+    /// Creates a logger for the given settings.
+    pub fn new(config: DebugLogConfig) -> Self {
+        DebugLogger {
+            enabled: AtomicBool::new(config.enabled),
+            percent: AtomicU8::new(config.percent.min(100)),
+            next_id: AtomicU64::new(1),
+            config,
+        }
+    }
+
+    /// Whether debug logging is enabled.
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Enables or disables debug logging.
+    pub fn set_enabled(&self, enabled: bool) {
+        info!(
+            "Debug logging {}",
+            if enabled { "enabled" } else { "disabled" }
+        );
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns the percentage of the requests logged.
+    pub fn percent(&self) -> u8 {
+        self.percent.load(Ordering::Relaxed)
+    }
+
+    /// Changes the percentage of the requests logged, capped at `100`.
+    pub fn set_percent(&self, percent: u8) {
+        let percent = percent.min(100);
+        info!("Debug logging percentage set to {}%", percent);
+        self.percent.store(percent, Ordering::Relaxed);
+    }
+
+    /// Whether the request from `client` is logged.
+    pub fn selects<T>(&self, req: &Request<T>, client: IpAddr) -> bool {
+        if !self.enabled() {
+            return false;
+        }
+        self.config
+            .filters
+            .iter()
+            .any(|filter| filter.matches(req, client))
+            || rand::thread_rng().gen_range(0..100) < self.percent()
+    }
+
+    /// Logs a selected request, returning the transaction id used to log its response.
+    pub async fn log_request(
+        &self,
+        req: Request<Body>,
+        client: IpAddr,
+    ) -> Result<(u64, Request<Body>)> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (parts, body) = req.into_parts();
+        let (logged, body) = self.capture(body).await?;
+        info!(
+            "Debug transaction {} request from {}: {} {} {:?}\n{}{}",
+            id,
+            client,
+            parts.method,
+            parts.uri,
+            parts.version,
+            format_headers(&parts.headers),
+            logged
+        );
+        Ok((id, Request::from_parts(parts, body)))
+    }
+
+    /// Logs the response of transaction `id`.
+    pub async fn log_response(&self, id: u64, response: Response<Body>) -> Result<Response<Body>> {
+        let (parts, body) = response.into_parts();
+        let (logged, body) = self.capture(body).await?;
+        info!(
+            "Debug transaction {} response: {:?} {}\n{}{}",
+            id,
+            parts.version,
+            parts.status,
+            format_headers(&parts.headers),
+            logged
+        );
+        Ok(Response::from_parts(parts, body))
+    }
+
+    /// Reads the beginning of `body` when bodies are logged, returning it formatted and the body to forward.
+    async fn capture(&self, body: Body) -> Result<(String, Body)> {
+        if !self.config.log_bodies {
+            return Ok((String::new(), body));
+        }
+        let (head, truncated, body) = peek_body(body, self.config.max_body_size).await?;
+        let logged = format!(
+            "\n{}{}",
+            String::from_utf8_lossy(&head),
+            if truncated { "\n[truncated]" } else { "" }
+        );
+        Ok((logged, body))
+    }
+}
+
+/// Reads the first `max_size` bytes of `body`, returning them, whether the body is longer, and the whole body to
+/// forward, whose rest is streamed after the bytes read.
+pub(crate) async fn peek_body(mut body: Body, max_size: usize) -> Result<(Bytes, bool, Body)> {
+    let mut buffered = Vec::new();
+    let mut truncated = false;
+    while let Some(chunk) = body.next().await {
+        buffered.extend_from_slice(&chunk.context("Failed to read body")?);
+        if buffered.len() > max_size {
+            truncated = true;
+            break;
+        }
+    }
+    let buffered = Bytes::from(buffered);
+    let head = buffered.slice(..buffered.len().min(max_size));
+    if !truncated {
+        return Ok((head, false, Body::from(buffered)));
+    }
+
+    // Stream the rest of the body after the buffered part
+    let (mut sender, forwarded) = Body::channel();
+    tokio::spawn(async move {
+        if sender.send_data(buffered).await.is_err() {
+            return;
+        }
+        while let Some(chunk) = body.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    error!("Failed to read body: {}", err);
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+    });
+    Ok((head, true, forwarded))
+}
+
+/// Formats headers one per line, redacting the values of the headers carrying credentials.
+fn format_headers(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            if CREDENTIAL_HEADERS.contains(name) {
+                format!("{}: {}\n", name, secret::redact(&value))
+            } else {
+                format!("{}: {}\n", name, value)
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,112 @@
+//! Content-addressed storage of cached bodies, so a payload served under many URLs, such as the same build artifact
+//! or library behind several CDN paths, is held once.
+//!
+//! Bodies are indexed by the SHA-256 hash of their content, and every cache key points at the hash of its body. A body
+//! admitted under a new key is replaced by the one already stored for its hash, the cache entries then sharing the
+//! same buffer or chunks. Bodies no cache entry points at any more are dropped along with their keys.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use ring::digest;
+use serde::Serialize;
+
+use crate::{canary::hex, chunks::CachedBody};
+
+/// Savings of storing the cached bodies once per content.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct DedupStats {
+    /// Number of cache entries pointing at a stored body.
+    pub entries: usize,
+    /// Number of distinct bodies stored.
+    pub unique_bodies: usize,
+    /// Bytes the cached bodies would take stored once per entry.
+    pub logical_bytes: usize,
+    /// Bytes the distinct bodies take.
+    pub stored_bytes: usize,
+    /// Bytes saved by storing identical bodies once.
+    pub saved_bytes: usize,
+}
+
+/// Cache keys and the hashes of their bodies, and the body stored for every hash.
+#[derive(Default)]
+struct Index {
+    hashes: HashMap<String, String>,
+    bodies: HashMap<String, CachedBody>,
+}
+
+/// Stores the cached bodies once per content.
+#[derive(Default)]
+pub struct ContentStore {
+    index: Mutex<Index>,
+}
+
+impl ContentStore {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the content hash of `body`.
+    pub(crate) fn hash(body: &[u8]) -> String {
+        hex(digest::digest(&digest::SHA256, body).as_ref())
+    }
+
+    /// Returns the body stored with `hash`, if any.
+    pub(crate) fn lookup(&self, hash: &str) -> Option<CachedBody> {
+        self.index.lock().unwrap().bodies.get(hash).cloned()
+    }
+
+    /// Points `key` at `body`, stored with `hash` unless a body with the same hash already is.
+    pub(crate) fn insert(&self, key: &str, hash: String, body: &CachedBody) {
+        let mut index = self.index.lock().unwrap();
+        index
+            .bodies
+            .entry(hash.clone())
+            .or_insert_with(|| body.clone());
+        index.hashes.insert(key.to_string(), hash);
+    }
+
+    /// Forgets the keys no longer `cached`, and the bodies no key points at.
+    pub(crate) fn retain(&self, cached: impl Fn(&str) -> bool) {
+        let mut index = self.index.lock().unwrap();
+        index.hashes.retain(|key, _| cached(key));
+        let Index { hashes, bodies } = &mut *index;
+        let referenced: HashSet<&String> = hashes.values().collect();
+        bodies.retain(|hash, _| referenced.contains(hash));
+    }
+
+    /// Returns the memory the cache entries share, which summing the memory of every entry counts more than once.
+    pub(crate) fn shared_memory(&self) -> usize {
+        let index = self.index.lock().unwrap();
+        let logical: usize = index
+            .hashes
+            .values()
+            .filter_map(|hash| index.bodies.get(hash))
+            .map(CachedBody::memory_len)
+            .sum();
+        let stored: usize = index.bodies.values().map(CachedBody::memory_len).sum();
+        logical.saturating_sub(stored)
+    }
+
+    /// Returns the savings of storing identical bodies once.
+    pub fn stats(&self) -> DedupStats {
+        let index = self.index.lock().unwrap();
+        let logical_bytes = index
+            .hashes
+            .values()
+            .filter_map(|hash| index.bodies.get(hash))
+            .map(CachedBody::stored_len)
+            .sum();
+        let stored_bytes = index.bodies.values().map(CachedBody::stored_len).sum();
+        DedupStats {
+            entries: index.hashes.len(),
+            unique_bodies: index.bodies.len(),
+            logical_bytes,
+            stored_bytes,
+            saved_bytes: logical_bytes.saturating_sub(stored_bytes),
+        }
+    }
+}
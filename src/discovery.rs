@@ -0,0 +1,479 @@
+//! Dynamic discovery of the upstream pool, so the proxy follows autoscaling backends without configuration changes.
+//!
+//! The pool is kept up to date by a [`Discovery`]: DNS, refreshing the A/AAAA or SRV records of a name on an
+//! interval, the Endpoints of a Kubernetes Service, a Consul service, or an implementation of the trait for another
+//! registry such as etcd. A static pool is only changed through the control plane. Requests without a more specific target are spread over the pool, by weight when the source
+//! provides weights.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use log::{debug, info, warn};
+use rand::Rng;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use crate::consul::{ConsulDiscoveryConfig, ConsulWatcher};
+use crate::kubernetes::{EndpointsWatcher, KubernetesDiscoveryConfig};
+
+/// Timeout of a DNS query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// DNS record type of SRV records.
+const TYPE_SRV: u16 = 33;
+/// DNS class of Internet records.
+const CLASS_IN: u16 = 1;
+
+/// A source of upstreams, keeping a pool up to date.
+///
+/// Implement it to discover the upstreams from a registry the proxy does not support, and configure it with
+/// [`DiscoverySource::Custom`].
+pub trait Discovery: Send + Sync {
+    /// Describes the source in logs.
+    fn describe(&self) -> String;
+
+    /// Keeps `pool` up to date with [`UpstreamPool::update`] until the returned future is dropped, recovering from
+    /// failures itself.
+    fn run(self: Arc<Self>, pool: Arc<UpstreamPool>) -> BoxFuture<'static, ()>;
+}
+
+impl fmt::Debug for dyn Discovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.describe())
+    }
+}
+
+/// DNS records describing the upstream pool.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DnsRecords {
+    /// The A and AAAA records of the name, each address being used with `port`.
+    Address {
+        /// Port of the upstreams.
+        port: u16,
+    },
+    /// The SRV records of the name, such as `_http._tcp.backend.example.com`, giving the port and weight of each
+    /// upstream. Only the records with the lowest priority are used.
+    Srv,
+}
+
+/// Discovery of the upstream pool from DNS.
+#[derive(Clone, Debug)]
+pub struct DnsDiscoveryConfig {
+    /// Name to resolve.
+    pub name: String,
+    /// Records to resolve.
+    pub records: DnsRecords,
+    /// `ip:port` of the DNS server for SRV queries. Defaults to the first `nameserver` of `/etc/resolv.conf`.
+    pub nameserver: Option<SocketAddr>,
+}
+
+/// Where the upstream pool is discovered.
+#[derive(Clone, Debug)]
+pub enum DiscoverySource {
+    /// DNS records of a name.
+    Dns(DnsDiscoveryConfig),
+    /// Endpoints of a Kubernetes Service. Requires the `kubernetes` feature.
+    Kubernetes(KubernetesDiscoveryConfig),
+    /// Healthy instances of a Consul service.
+    Consul(ConsulDiscoveryConfig),
+    /// Another source. The interval of the settings is not used.
+    Custom(Arc<dyn Discovery>),
+    /// Fixed upstreams, replaced only through the control plane. The upstreams set through the control plane with
+    /// the other sources last until the source changes them.
+    Static(Vec<Endpoint>),
+}
+
+/// Upstream discovery settings.
+#[derive(Clone, Debug)]
+pub struct DiscoveryConfig {
+    /// Where the upstreams are discovered.
+    pub source: DiscoverySource,
+    /// Scheme of the requests to the upstreams. Defaults to `http`.
+    pub scheme: String,
+    /// Interval between refreshes of the pool, or delay before watching again after a failed watch. Defaults to 30
+    /// seconds.
+    pub interval: Duration,
+}
+
+impl DiscoveryConfig {
+    /// Creates settings discovering the upstreams from `source`, with the default scheme and interval.
+    pub fn new(source: DiscoverySource) -> Self {
+        Self {
+            source,
+            scheme: "http".to_string(),
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A discovered upstream.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct Endpoint {
+    /// Address of the upstream.
+    pub address: SocketAddr,
+    /// Relative share of the requests sent to the upstream.
+    pub weight: u16,
+}
+
+/// The discovered upstreams, among which requests are spread.
+pub struct UpstreamPool {
+    config: DiscoveryConfig,
+    endpoints: RwLock<Vec<Endpoint>>,
+    discovery: Arc<dyn Discovery>,
+}
+
+impl UpstreamPool {
+    /// Creates an empty pool, filled once `start` is called.
+    ///
+    /// Fails for a zero interval, and for Kubernetes discovery outside of a cluster without a configured API server,
+    /// or without the `kubernetes` feature.
+    pub fn new(config: DiscoveryConfig) -> Result<Self> {
+        // The pool would be refreshed, or a failed watch retried, without pause
+        if config.interval.is_zero()
+            && !matches!(
+                config.source,
+                DiscoverySource::Custom(_) | DiscoverySource::Static(_)
+            )
+        {
+            anyhow::bail!("The discovery interval must not be zero");
+        }
+        let discovery: Arc<dyn Discovery> = match &config.source {
+            DiscoverySource::Dns(dns) => Arc::new(DnsDiscovery {
+                config: dns.clone(),
+                interval: config.interval,
+            }),
+            DiscoverySource::Kubernetes(kubernetes) => {
+                Arc::new(EndpointsWatcher::new(kubernetes.clone(), config.interval)?)
+            }
+            DiscoverySource::Consul(consul) => {
+                Arc::new(ConsulWatcher::new(consul.clone(), config.interval))
+            }
+            DiscoverySource::Custom(discovery) => discovery.clone(),
+            DiscoverySource::Static(endpoints) => Arc::new(StaticDiscovery(endpoints.clone())),
+        };
+        Ok(UpstreamPool {
+            config,
+            endpoints: RwLock::new(Vec::new()),
+            discovery,
+        })
+    }
+
+    /// Starts keeping the pool up to date in the background.
+    pub fn start(self: &Arc<Self>) {
+        info!("Discovering upstreams from {}", self.discovery.describe());
+        tokio::spawn(self.discovery.clone().run(self.clone()));
+    }
+
+    /// Returns the current upstreams.
+    pub fn endpoints(&self) -> Vec<Endpoint> {
+        self.endpoints.read().unwrap().clone()
+    }
+
+    /// Picks an upstream at random by weight, returning its target address. Returns `None` while the pool is empty.
+    pub fn pick(&self) -> Option<String> {
+        let endpoints = self.endpoints.read().unwrap();
+        let total: u32 = endpoints.iter().map(weight).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut point = rand::thread_rng().gen_range(0..total);
+        let endpoint = endpoints.iter().find(|endpoint| {
+            if point < weight(endpoint) {
+                return true;
+            }
+            point -= weight(endpoint);
+            false
+        })?;
+        Some(format!("{}://{}", self.config.scheme, endpoint.address))
+    }
+
+    /// Replaces the upstreams, logging the changes. Only the picks of new requests change: the requests in flight
+    /// and the pooled connections to removed upstreams are left to finish.
+    pub fn update(&self, mut endpoints: Vec<Endpoint>) {
+        endpoints.sort();
+        endpoints.dedup();
+        let mut current = self.endpoints.write().unwrap();
+        if *current == endpoints {
+            return;
+        }
+        for endpoint in endpoints
+            .iter()
+            .filter(|endpoint| !current.contains(endpoint))
+        {
+            info!(
+                "Discovered upstream {} (weight {})",
+                endpoint.address, endpoint.weight
+            );
+        }
+        for endpoint in current
+            .iter()
+            .filter(|endpoint| !endpoints.contains(endpoint))
+        {
+            info!("Removed upstream {}", endpoint.address);
+        }
+        *current = endpoints;
+    }
+
+    /// Applies the result of a discovery, keeping the current upstreams when it failed or found none, so a registry
+    /// outage or a transient failure of every health check does not empty the pool.
+    pub(crate) fn apply(&self, discovered: Result<Vec<Endpoint>>) {
+        match discovered {
+            Ok(endpoints) if endpoints.is_empty() => {
+                warn!("Upstream discovery found no upstreams, keeping the current ones")
+            }
+            Ok(endpoints) => self.update(endpoints),
+            Err(err) => warn!(
+                "Upstream discovery failed, keeping the current upstreams: {}",
+                err
+            ),
+        }
+    }
+}
+
+/// Weight of an upstream in picks; upstreams of weight 0 still get a small share.
+fn weight(endpoint: &Endpoint) -> u32 {
+    u32::from(endpoint.weight).max(1)
+}
+
+/// Discovery of fixed upstreams.
+struct StaticDiscovery(Vec<Endpoint>);
+
+impl Discovery for StaticDiscovery {
+    fn describe(&self) -> String {
+        format!("{} static upstreams", self.0.len())
+    }
+
+    fn run(self: Arc<Self>, pool: Arc<UpstreamPool>) -> BoxFuture<'static, ()> {
+        Box::pin(async move { pool.update(self.0.clone()) })
+    }
+}
+
+/// Discovery refreshing DNS records on an interval.
+struct DnsDiscovery {
+    config: DnsDiscoveryConfig,
+    interval: Duration,
+}
+
+impl Discovery for DnsDiscovery {
+    fn describe(&self) -> String {
+        match self.config.records {
+            DnsRecords::Address { port } => format!("DNS name {} port {}", self.config.name, port),
+            DnsRecords::Srv => format!("DNS SRV records of {}", self.config.name),
+        }
+    }
+
+    fn run(self: Arc<Self>, pool: Arc<UpstreamPool>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let mut interval = tokio::time::interval(self.interval);
+            loop {
+                interval.tick().await;
+                pool.apply(resolve(&self.config).await);
+            }
+        })
+    }
+}
+
+/// Resolves the upstreams described by DNS records.
+async fn resolve(config: &DnsDiscoveryConfig) -> Result<Vec<Endpoint>> {
+    match config.records {
+        DnsRecords::Address { port } => {
+            let addresses = lookup_host((config.name.as_str(), port))
+                .await
+                .context(format!("Failed to resolve {}", config.name))?;
+            Ok(addresses
+                .map(|address| Endpoint { address, weight: 1 })
+                .collect())
+        }
+        DnsRecords::Srv => {
+            let nameserver = match config.nameserver {
+                Some(nameserver) => nameserver,
+                None => system_nameserver().await,
+            };
+            let records = query_srv(&config.name, nameserver).await?;
+            let priority = records.iter().map(|record| record.priority).min();
+            let mut endpoints = Vec::new();
+            for record in records
+                .iter()
+                .filter(|record| Some(record.priority) == priority)
+            {
+                match lookup_host((record.target.as_str(), record.port)).await {
+                    Ok(addresses) => endpoints.extend(addresses.map(|address| Endpoint {
+                        address,
+                        weight: record.weight,
+                    })),
+                    Err(err) => warn!("Failed to resolve SRV target {}: {}", record.target, err),
+                }
+            }
+            Ok(endpoints)
+        }
+    }
+}
+
+/// Returns the first name server of `/etc/resolv.conf`, or the local one.
+async fn system_nameserver() -> SocketAddr {
+    let configured = tokio::fs::read_to_string("/etc/resolv.conf")
+        .await
+        .ok()
+        .and_then(|content| {
+            content.lines().find_map(|line| {
+                let mut fields = line.split_whitespace();
+                match fields.next() {
+                    Some("nameserver") => fields.next()?.parse::<IpAddr>().ok(),
+                    _ => None,
+                }
+            })
+        });
+    SocketAddr::new(configured.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)), 53)
+}
+
+/// An SRV record (RFC 2782).
+#[derive(Debug)]
+struct SrvRecord {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+/// Queries the SRV records of `name`, over UDP then over TCP if the answer is truncated.
+async fn query_srv(name: &str, nameserver: SocketAddr) -> Result<Vec<SrvRecord>> {
+    let id: u16 = rand::random();
+    let query = build_query(id, name, TYPE_SRV)?;
+
+    let bind = if nameserver.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(nameserver).await?;
+    socket.send(&query).await?;
+    let mut buffer = vec![0; 4096];
+    let response = loop {
+        let read = timeout(QUERY_TIMEOUT, socket.recv(&mut buffer))
+            .await
+            .context(format!("DNS query to {} timed out", nameserver))??;
+        // Answers to other queries are ignored
+        if read >= 2 && u16::from_be_bytes([buffer[0], buffer[1]]) == id {
+            break &buffer[..read];
+        }
+    };
+    // Truncated answers are queried again over TCP
+    if response.len() >= 3 && response[2] & 0x02 != 0 {
+        debug!("SRV answer for {} truncated, querying over TCP", name);
+        let response = timeout(QUERY_TIMEOUT, query_tcp(&query, nameserver))
+            .await
+            .context(format!("DNS query to {} timed out", nameserver))??;
+        return parse_srv_response(&response, id);
+    }
+    parse_srv_response(response, id)
+}
+
+/// Sends a DNS query over TCP, returning the answer.
+async fn query_tcp(query: &[u8], nameserver: SocketAddr) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(nameserver).await?;
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(query);
+    stream.write_all(&framed).await?;
+    let mut length = [0; 2];
+    stream.read_exact(&mut length).await?;
+    let mut response = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+/// Builds a recursive DNS query for records of `record_type` of `name`.
+fn build_query(id: u16, name: &str, record_type: u16) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("Invalid DNS name: {}", name);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Extracts the SRV records from the answer to query `id`.
+fn parse_srv_response(response: &[u8], id: u16) -> Result<Vec<SrvRecord>> {
+    let header = response.get(..12).context("Truncated DNS answer")?;
+    if u16::from_be_bytes([header[0], header[1]]) != id {
+        anyhow::bail!("DNS answer to another query");
+    }
+    match header[3] & 0x0f {
+        0 => {}
+        3 => return Ok(Vec::new()),
+        rcode => anyhow::bail!("DNS query failed with response code {}", rcode),
+    }
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let answers = u16::from_be_bytes([header[6], header[7]]);
+
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(response, offset)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        offset = read_name(response, offset)?.1;
+        let fixed = response
+            .get(offset..offset + 10)
+            .context("Truncated DNS record")?;
+        let record_type = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let length = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let data_offset = offset + 10;
+        let data = response
+            .get(data_offset..data_offset + length)
+            .context("Truncated DNS record")?;
+        // CNAME records may precede the SRV records
+        if record_type == TYPE_SRV && data.len() > 6 {
+            records.push(SrvRecord {
+                priority: u16::from_be_bytes([data[0], data[1]]),
+                weight: u16::from_be_bytes([data[2], data[3]]),
+                port: u16::from_be_bytes([data[4], data[5]]),
+                target: read_name(response, data_offset + 6)?.0,
+            });
+        }
+        offset = data_offset + length;
+    }
+    Ok(records)
+}
+
+/// Reads a possibly compressed name at `offset`, returning it with the offset following it.
+fn read_name(message: &[u8], mut offset: usize) -> Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds the pointers followed, so a pointer loop cannot hang the parser
+    for _ in 0..128 {
+        let length = *message.get(offset).context("Truncated DNS name")? as usize;
+        if length & 0xc0 == 0xc0 {
+            let low = *message.get(offset + 1).context("Truncated DNS name")? as usize;
+            end.get_or_insert(offset + 2);
+            offset = ((length & 0x3f) << 8) | low;
+        } else if length == 0 {
+            return Ok((labels.join("."), end.unwrap_or(offset + 1)));
+        } else {
+            let label = message
+                .get(offset + 1..offset + 1 + length)
+                .context("Truncated DNS name")?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            offset += 1 + length;
+        }
+    }
+    anyhow::bail!("DNS name with too many labels or pointers")
+}
@@ -0,0 +1,265 @@
+//! Data-loss prevention: rules matching sensitive data such as credit card numbers or API keys in outbound requests.
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use hyper::{
+    body::Bytes,
+    header::{HeaderValue, CONTENT_LENGTH},
+    http::request,
+    Body,
+};
+use log::{error, warn};
+use regex::bytes::Regex;
+
+/// Replacement of redacted data.
+const REDACTED: &[u8] = b"[REDACTED]";
+
+/// What a DLP rule matches.
+#[derive(Clone, Debug)]
+pub enum DlpPattern {
+    /// A regular expression.
+    Regex(String),
+    /// A keyword, matched case-insensitively.
+    Keyword(String),
+    /// Payment card numbers of 13 to 19 digits, optionally separated by spaces or dashes, passing the Luhn check.
+    CreditCard,
+}
+
+/// What happens to requests matching a rule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DlpAction {
+    /// Rejects the request with `403 Forbidden`.
+    Block,
+    /// Replaces the matched data with `[REDACTED]` and forwards the request.
+    Redact,
+    /// Only logs the match.
+    Log,
+}
+
+/// A rule applying an action to requests carrying matching data.
+#[derive(Clone, Debug)]
+pub struct DlpRule {
+    /// Name of the rule, used in logs.
+    pub name: String,
+    /// Data matched by the rule.
+    pub pattern: DlpPattern,
+    /// Action applied to matching requests.
+    pub action: DlpAction,
+}
+
+/// DLP settings.
+#[derive(Clone, Debug)]
+pub struct DlpConfig {
+    /// Rules evaluated against request headers and bodies.
+    pub rules: Vec<DlpRule>,
+    /// Number of body bytes buffered and scanned.
+    pub max_body_size: usize,
+    /// Whether requests with a body larger than `max_body_size` are rejected with `413 Payload Too Large`;
+    /// otherwise only the first `max_body_size` bytes are scanned.
+    pub block_oversized: bool,
+}
+
+impl Default for DlpConfig {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            max_body_size: 1024 * 1024,
+            block_oversized: false,
+        }
+    }
+}
+
+/// Outcome of inspecting a request.
+pub enum DlpVerdict {
+    /// Forward the request with this body.
+    Forward(Body),
+    /// Reject the request, which matched the named rule.
+    Block(String),
+    /// Reject the request, whose body exceeds the buffering cap.
+    TooLarge,
+}
+
+struct CompiledRule {
+    name: String,
+    regex: Regex,
+    luhn: bool,
+    action: DlpAction,
+}
+
+/// DLP rules with their patterns compiled.
+pub struct Dlp {
+    config: DlpConfig,
+    rules: Vec<CompiledRule>,
+}
+
+impl Dlp {
+    /// Compiles the rules, failing on invalid regular expressions.
+    pub fn new(config: DlpConfig) -> Result<Self> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                let (pattern, luhn) = match &rule.pattern {
+                    DlpPattern::Regex(pattern) => (pattern.clone(), false),
+                    DlpPattern::Keyword(keyword) => {
+                        (format!("(?i){}", regex::escape(keyword)), false)
+                    }
+                    DlpPattern::CreditCard => (r"\b\d(?:[ -]?\d){12,18}\b".to_string(), true),
+                };
+                let regex = Regex::new(&pattern).context(format!(
+                    "Invalid DLP regex in rule {}: {}",
+                    rule.name, pattern
+                ))?;
+                Ok(CompiledRule {
+                    name: rule.name.clone(),
+                    regex,
+                    luhn,
+                    action: rule.action,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Dlp { config, rules })
+    }
+
+    /// Evaluates the rules against the headers and body of a request, redacting matches in place.
+    pub async fn inspect(&self, parts: &mut request::Parts, mut body: Body) -> Result<DlpVerdict> {
+        let target = parts.uri.to_string();
+
+        // Scan header values
+        for (name, value) in parts.headers.iter_mut() {
+            let (scanned, blocked) =
+                self.scan(value.as_bytes(), &format!("header {}", name), &target);
+            if let Some(rule) = blocked {
+                return Ok(DlpVerdict::Block(rule));
+            }
+            if let Some(redacted) = scanned {
+                *value = HeaderValue::from_bytes(&redacted)?;
+            }
+        }
+
+        // Buffer the body up to the cap
+        let mut buffered = Vec::new();
+        let mut overflow = None;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.context("Failed to read request body")?;
+            if buffered.len() + chunk.len() > self.config.max_body_size {
+                overflow = Some(chunk);
+                break;
+            }
+            buffered.extend_from_slice(&chunk);
+        }
+        if overflow.is_some() && self.config.block_oversized {
+            return Ok(DlpVerdict::TooLarge);
+        }
+
+        let (scanned, blocked) = self.scan(&buffered, "body", &target);
+        if let Some(rule) = blocked {
+            return Ok(DlpVerdict::Block(rule));
+        }
+        let redacted = scanned.is_some();
+        let head = Bytes::from(scanned.unwrap_or(buffered));
+        let overflow = match overflow {
+            Some(overflow) => overflow,
+            None => {
+                if redacted {
+                    parts
+                        .headers
+                        .insert(CONTENT_LENGTH, HeaderValue::from(head.len()));
+                }
+                return Ok(DlpVerdict::Forward(Body::from(head)));
+            }
+        };
+
+        // Stream the unscanned remainder after the scanned head
+        if redacted {
+            parts.headers.remove(CONTENT_LENGTH);
+        }
+        let (mut sender, forwarded) = Body::channel();
+        tokio::spawn(async move {
+            for chunk in [head, overflow] {
+                if sender.send_data(chunk).await.is_err() {
+                    return;
+                }
+            }
+            while let Some(chunk) = body.next().await {
+                match chunk {
+                    Ok(chunk) => {
+                        if sender.send_data(chunk).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        error!("Failed to read request body: {}", err);
+                        sender.abort();
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(DlpVerdict::Forward(forwarded))
+    }
+
+    /// Evaluates the rules against `data`, returning the redacted data if anything was redacted and the name of
+    /// the first blocking rule that matched.
+    fn scan(&self, data: &[u8], location: &str, target: &str) -> (Option<Vec<u8>>, Option<String>) {
+        let mut redacted: Option<Vec<u8>> = None;
+        for rule in &self.rules {
+            let current = redacted.as_deref().unwrap_or(data);
+            let matches: Vec<_> = rule
+                .regex
+                .find_iter(current)
+                .filter(|found| !rule.luhn || passes_luhn(found.as_bytes()))
+                .map(|found| found.range())
+                .collect();
+            if matches.is_empty() {
+                continue;
+            }
+            warn!(
+                "DLP rule {} matched {} time(s) in the {} of a request to {}",
+                rule.name,
+                matches.len(),
+                location,
+                target
+            );
+            match rule.action {
+                DlpAction::Block => return (None, Some(rule.name.clone())),
+                DlpAction::Log => {}
+                DlpAction::Redact => {
+                    let mut output = Vec::with_capacity(current.len());
+                    let mut last = 0;
+                    for range in matches {
+                        output.extend_from_slice(&current[last..range.start]);
+                        output.extend_from_slice(REDACTED);
+                        last = range.end;
+                    }
+                    output.extend_from_slice(&current[last..]);
+                    redacted = Some(output);
+                }
+            }
+        }
+        (redacted, None)
+    }
+}
+
+/// Whether the digits of `number` pass the Luhn checksum.
+fn passes_luhn(number: &[u8]) -> bool {
+    let digits: Vec<u32> = number
+        .iter()
+        .filter(|byte| byte.is_ascii_digit())
+        .map(|byte| u32::from(byte - b'0'))
+        .collect();
+    if !(13..=19).contains(&digits.len()) {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(index, digit)| match index % 2 {
+            1 if *digit * 2 > 9 => digit * 2 - 9,
+            1 => digit * 2,
+            _ => *digit,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
@@ -0,0 +1,160 @@
+//! Egress policy of forward-proxy traffic, restricting the destinations clients may reach through the proxy by host,
+//! port and scheme.
+
+use std::{
+    collections::HashSet,
+    ops::RangeInclusive,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::Result;
+use hyper::Uri;
+
+use crate::tunnel;
+
+/// Whether an egress rule allows or denies the destinations it matches.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EgressAction {
+    /// The destination may be reached.
+    #[default]
+    Allow,
+    /// The request or tunnel is refused.
+    Deny,
+}
+
+/// A rule matching destinations by host, port and scheme.
+#[derive(Clone, Debug, Default)]
+pub struct EgressRule {
+    /// Name of the rule, reported along with its hit count.
+    pub name: String,
+    /// Whether the matching destinations are allowed or denied.
+    pub action: EgressAction,
+    /// Host names, IP addresses, wildcards such as `*.example.com`, or `*` for any host. Matches every host when
+    /// empty.
+    pub hosts: Vec<String>,
+    /// Port ranges, such as `443..=443` or `8000..=8999`. Matches every port when empty.
+    pub ports: Vec<RangeInclusive<u16>>,
+    /// Schemes, such as `http`, `https` or `ftp`; CONNECT tunnels have the scheme `connect`. Matches every scheme
+    /// when empty.
+    pub schemes: Vec<String>,
+}
+
+impl EgressRule {
+    /// Whether the rule matches the destination.
+    fn matches(&self, scheme: &str, host: &str, port: u16) -> bool {
+        (self.schemes.is_empty()
+            || self
+                .schemes
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(scheme)))
+            && (self.hosts.is_empty()
+                || self
+                    .hosts
+                    .iter()
+                    .any(|pattern| pattern == "*" || tunnel::host_matches(pattern, host)))
+            && (self.ports.is_empty() || self.ports.iter().any(|ports| ports.contains(&port)))
+    }
+}
+
+/// Egress policy settings.
+#[derive(Clone, Debug, Default)]
+pub struct EgressConfig {
+    /// Rules evaluated in order; the first rule matching a destination decides.
+    pub rules: Vec<EgressRule>,
+    /// Action for the destinations matching no rule. Defaults to `Allow`, making the rules a denylist; `Deny` makes
+    /// them an allowlist.
+    pub default_action: EgressAction,
+}
+
+/// Hit counts of the egress rules.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EgressHits {
+    /// Number of destinations matched by every rule, in rule order.
+    pub rules: Vec<(String, u64)>,
+    /// Number of destinations matching no rule.
+    pub unmatched: u64,
+}
+
+/// Egress policy checking the destinations of forward-proxy requests and CONNECT tunnels.
+pub struct Egress {
+    config: EgressConfig,
+    hits: Vec<AtomicU64>,
+    unmatched: AtomicU64,
+}
+
+impl Egress {
+    /// Validates the rules.
+    pub fn new(config: EgressConfig) -> Result<Self> {
+        let mut names = HashSet::new();
+        for (index, rule) in config.rules.iter().enumerate() {
+            if rule.name.is_empty() {
+                anyhow::bail!("Egress rule {} has no name", index);
+            }
+            if !names.insert(rule.name.as_str()) {
+                anyhow::bail!("Duplicate egress rule name: {}", rule.name);
+            }
+            if let Some(ports) = rule.ports.iter().find(|ports| ports.is_empty()) {
+                anyhow::bail!(
+                    "Empty port range {}-{} in egress rule {}",
+                    ports.start(),
+                    ports.end(),
+                    rule.name
+                );
+            }
+        }
+        let hits = config.rules.iter().map(|_| AtomicU64::new(0)).collect();
+        Ok(Egress {
+            config,
+            hits,
+            unmatched: AtomicU64::new(0),
+        })
+    }
+
+    /// Decides whether `host:port` may be reached over `scheme`, counting the hit of the deciding rule.
+    pub fn check(&self, scheme: &str, host: &str, port: u16) -> EgressAction {
+        // IPv6 literals are matched without their brackets
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let matched = self
+            .config
+            .rules
+            .iter()
+            .position(|rule| rule.matches(scheme, host, port));
+        match matched {
+            Some(index) => {
+                self.hits[index].fetch_add(1, Ordering::Relaxed);
+                self.config.rules[index].action
+            }
+            None => {
+                self.unmatched.fetch_add(1, Ordering::Relaxed);
+                self.config.default_action
+            }
+        }
+    }
+
+    /// Decides whether the destination of an absolute-form request URI may be reached, using the default port of
+    /// its scheme when it has none. Returns `None` for URIs without a host, which do not name a destination.
+    pub fn check_uri(&self, uri: &Uri) -> Option<EgressAction> {
+        let host = uri.host()?;
+        let scheme = uri.scheme_str().unwrap_or("http");
+        let port = uri.port_u16().unwrap_or(match scheme {
+            "https" => 443,
+            "ftp" => 21,
+            _ => 80,
+        });
+        Some(self.check(scheme, host, port))
+    }
+
+    /// Returns the hit counts of the rules.
+    pub fn hits(&self) -> EgressHits {
+        EgressHits {
+            rules: self
+                .config
+                .rules
+                .iter()
+                .zip(&self.hits)
+                .map(|(rule, hits)| (rule.name.clone(), hits.load(Ordering::Relaxed)))
+                .collect(),
+            unmatched: self.unmatched.load(Ordering::Relaxed),
+        }
+    }
+}
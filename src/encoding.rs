@@ -0,0 +1,199 @@
+//! Normalization of the content encodings of responses, so the cache holds one representation per URL whatever
+//! the `Accept-Encoding` of the clients.
+//!
+//! Upstreams are always asked for the same encoding, responses are decoded before being cached, and are encoded
+//! again in the encoding each client prefers. Normalization requires the `compression` feature; without it,
+//! enabling it is an error.
+
+use anyhow::{Context, Result};
+use hyper::{
+    body::to_bytes,
+    header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY},
+    Body, HeaderMap, Response,
+};
+
+use crate::{
+    body::{read_limited, LimitedBody},
+    compression::{self, CompressionAlgorithm},
+};
+
+/// Encoding normalization settings.
+#[derive(Clone, Debug)]
+pub struct EncodingNormalizationConfig {
+    /// The only encoding requested from upstreams. Defaults to gzip.
+    pub upstream: CompressionAlgorithm,
+    /// Encodings of the responses to clients, in order of preference among those a client weighs equally.
+    /// Responses are sent unencoded to clients accepting none of them. Defaults to zstd then gzip.
+    pub client_encodings: Vec<CompressionAlgorithm>,
+    /// Responses smaller than this many bytes are sent unencoded. Defaults to 1 KiB.
+    pub min_size: usize,
+    /// Largest response decoded, before and after decoding; larger responses are passed on in the encoding the
+    /// upstream sent. Defaults to 16 MiB.
+    pub max_size: usize,
+}
+
+impl Default for EncodingNormalizationConfig {
+    fn default() -> Self {
+        Self {
+            upstream: CompressionAlgorithm::Gzip,
+            client_encodings: vec![CompressionAlgorithm::Zstd, CompressionAlgorithm::Gzip],
+            min_size: 1024,
+            max_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Returns the content coding naming `algorithm`.
+fn coding(algorithm: CompressionAlgorithm) -> &'static str {
+    match algorithm {
+        CompressionAlgorithm::Zstd => "zstd",
+        CompressionAlgorithm::Gzip => "gzip",
+    }
+}
+
+/// Returns the algorithm of a content coding, if it is supported.
+fn algorithm(coding: &str) -> Option<CompressionAlgorithm> {
+    match coding.trim().to_ascii_lowercase().as_str() {
+        "zstd" => Some(CompressionAlgorithm::Zstd),
+        "gzip" | "x-gzip" => Some(CompressionAlgorithm::Gzip),
+        _ => None,
+    }
+}
+
+/// Normalizes the encodings of requests and responses.
+pub struct EncodingNormalizer {
+    config: EncodingNormalizationConfig,
+}
+
+impl EncodingNormalizer {
+    /// Creates a normalizer for the given settings.
+    #[cfg(feature = "compression")]
+    pub fn new(config: EncodingNormalizationConfig) -> Result<Self> {
+        Ok(EncodingNormalizer { config })
+    }
+
+    /// Creates a normalizer for the given settings.
+    #[cfg(not(feature = "compression"))]
+    pub fn new(_config: EncodingNormalizationConfig) -> Result<Self> {
+        anyhow::bail!(
+            "Cannot normalize encodings: fortifynet_proxy was built without the `compression` feature"
+        )
+    }
+
+    /// Returns the encoding the client sending `headers` prefers among the configured ones, or `None` when it
+    /// accepts none of them.
+    pub fn negotiate(&self, headers: &HeaderMap) -> Option<CompressionAlgorithm> {
+        let mut weights = Vec::new();
+        for value in headers.get_all(ACCEPT_ENCODING) {
+            let value = match value.to_str() {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            for item in value.split(',') {
+                let mut params = item.split(';');
+                let coding = params
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_ascii_lowercase();
+                let weight = params
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                weights.push((coding, weight));
+            }
+        }
+        let weight_of = |algorithm: CompressionAlgorithm| {
+            let name = coding(algorithm);
+            weights
+                .iter()
+                .find(|(coding, _)| coding == name || (name == "gzip" && coding == "x-gzip"))
+                .or_else(|| weights.iter().find(|(coding, _)| coding == "*"))
+                .map_or(0.0, |(_, weight)| *weight)
+        };
+        let mut preferred: Option<(CompressionAlgorithm, f32)> = None;
+        for &algorithm in &self.config.client_encodings {
+            let weight = weight_of(algorithm);
+            if weight > 0.0 && preferred.is_none_or(|(_, best)| weight > best) {
+                preferred = Some((algorithm, weight));
+            }
+        }
+        preferred.map(|(algorithm, _)| algorithm)
+    }
+
+    /// Replaces the `Accept-Encoding` of a request to an upstream with the configured encoding.
+    pub(crate) fn normalize_request(&self, headers: &mut HeaderMap) {
+        headers.insert(
+            ACCEPT_ENCODING,
+            HeaderValue::from_static(coding(self.config.upstream)),
+        );
+    }
+
+    /// Decodes a response of an upstream, leaving responses without a body, of unsupported encodings or beyond
+    /// `max_size` untouched.
+    pub(crate) async fn decode(&self, response: Response<Body>) -> Result<Response<Body>> {
+        let encoding = response
+            .headers()
+            .get(CONTENT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .map(algorithm);
+        let algorithm = match encoding {
+            Some(Some(algorithm)) => algorithm,
+            _ => return Ok(response),
+        };
+        let (mut parts, body) = response.into_parts();
+        let body = match read_limited(body, self.config.max_size).await? {
+            LimitedBody::Complete(body) => body,
+            LimitedBody::Oversized(body) => return Ok(Response::from_parts(parts, body)),
+        };
+        // Responses to HEAD requests, 204 and 304 responses have no body to decode
+        if body.is_empty() {
+            return Ok(Response::from_parts(parts, Body::empty()));
+        }
+        let max_size = self.config.max_size;
+        let decoded = {
+            let body = body.clone();
+            tokio::task::spawn_blocking(move || {
+                compression::decompress_limited(algorithm, &body, max_size)
+            })
+            .await?
+            .with_context(|| format!("Failed to decode a {} response", coding(algorithm)))?
+        };
+        let decoded = match decoded {
+            Some(decoded) => decoded,
+            None => return Ok(Response::from_parts(parts, Body::from(body))),
+        };
+        parts.headers.remove(CONTENT_ENCODING);
+        parts.headers.remove(CONTENT_LENGTH);
+        Ok(Response::from_parts(parts, Body::from(decoded)))
+    }
+
+    /// Encodes an unencoded response in `encoding`, the one preferred by the client, unless it is too small.
+    pub(crate) async fn encode(
+        &self,
+        response: Response<Body>,
+        encoding: Option<CompressionAlgorithm>,
+    ) -> Result<Response<Body>> {
+        let (mut parts, body) = response.into_parts();
+        parts
+            .headers
+            .append(VARY, HeaderValue::from_static("Accept-Encoding"));
+        let algorithm = match encoding {
+            Some(algorithm) if !parts.headers.contains_key(CONTENT_ENCODING) => algorithm,
+            _ => return Ok(Response::from_parts(parts, body)),
+        };
+        let body = to_bytes(body)
+            .await
+            .context("Failed to read response body")?;
+        if body.len() < self.config.min_size {
+            return Ok(Response::from_parts(parts, Body::from(body)));
+        }
+        let encoded = compression::encode(algorithm, None, &body)?;
+        parts.headers.insert(
+            CONTENT_ENCODING,
+            HeaderValue::from_static(coding(algorithm)),
+        );
+        parts.headers.remove(CONTENT_LENGTH);
+        Ok(Response::from_parts(parts, Body::from(encoded)))
+    }
+}
@@ -0,0 +1,307 @@
+//! Rolling error budgets of the upstreams and routes with an error-rate objective, and alerts on the rate they burn.
+//!
+//! An objective allowing an error rate of 0.1% over the budget period has a budget of one failed request in a thousand.
+//! The burn rate is the error rate over a window divided by that allowance, so a burn rate of 1 exhausts the budget
+//! exactly at the end of the period. As in multiwindow burn-rate alerting, a burn is only alerted when both a long
+//! window and a short one exceed the threshold: the long window ignores brief spikes, and the short one ends the alert
+//! soon after the errors stop. Fast burns are notified to the webhooks; slow burns are only shown on the dashboard.
+
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::{
+    timeseries::{unix_now, Bucket, TimeSeries},
+    SloConfig,
+};
+
+/// Resolution of the series the burn rates are computed over.
+const BURN_RESOLUTION: Duration = Duration::from_secs(60);
+/// Number of buckets the budget period is divided into.
+const PERIOD_BUCKETS: usize = 720;
+
+/// A burn-rate threshold, evaluated over a long and a short window.
+#[derive(Clone, Debug)]
+pub struct BurnRateAlert {
+    /// Window whose burn rate must exceed the threshold.
+    pub long_window: Duration,
+    /// Shorter window whose burn rate must also exceed the threshold, for the alert to end soon after the errors.
+    pub short_window: Duration,
+    /// Burn rate beyond which the budget is burning.
+    pub burn_rate: f64,
+}
+
+/// An error-rate objective of the requests to the paths starting with a prefix.
+#[derive(Clone, Debug)]
+pub struct RouteObjective {
+    /// Prefix of the paths of the route, such as `/api`.
+    pub route: String,
+    /// Maximum allowed ratio of failed requests, between `0.0` and `1.0`. Defaults to 0.001.
+    pub max_error_rate: f64,
+}
+
+impl Default for RouteObjective {
+    fn default() -> Self {
+        Self {
+            route: String::new(),
+            max_error_rate: 0.001,
+        }
+    }
+}
+
+/// Error budget settings.
+///
+/// The budgets of the upstreams derive from the error-rate objectives of their SLOs.
+#[derive(Clone, Debug)]
+pub struct ErrorBudgetConfig {
+    /// Period the budgets are computed over. Defaults to 30 days.
+    pub period: Duration,
+    /// Objectives of routes. Defaults to none.
+    pub routes: Vec<RouteObjective>,
+    /// Threshold of a fast burn, notified to the webhooks. Defaults to a burn rate of 14.4 over 1 hour and 5 minutes,
+    /// which spends 2% of a 30-day budget in an hour.
+    pub fast_burn: BurnRateAlert,
+    /// Threshold of a slow burn. Defaults to a burn rate of 6 over 6 hours and 30 minutes, which spends 5% of a 30-day
+    /// budget in 6 hours.
+    pub slow_burn: BurnRateAlert,
+    /// Webhook notified of fast burns, in addition to the webhooks subscribed to error budget events (optional).
+    pub webhook_url: Option<String>,
+}
+
+impl Default for ErrorBudgetConfig {
+    fn default() -> Self {
+        Self {
+            period: Duration::from_secs(30 * 86_400),
+            routes: Vec::new(),
+            fast_burn: BurnRateAlert {
+                long_window: Duration::from_secs(3600),
+                short_window: Duration::from_secs(300),
+                burn_rate: 14.4,
+            },
+            slow_burn: BurnRateAlert {
+                long_window: Duration::from_secs(6 * 3600),
+                short_window: Duration::from_secs(1800),
+                burn_rate: 6.0,
+            },
+            webhook_url: None,
+        }
+    }
+}
+
+/// Current error budget of an objective.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ErrorBudgetStatus {
+    /// What the objective applies to, such as `upstream api:8080` or `route /api`.
+    pub objective: String,
+    /// Maximum allowed ratio of failed requests.
+    pub max_error_rate: f64,
+    /// Number of requests over the budget period.
+    pub requests: u64,
+    /// Number of failed requests over the budget period.
+    pub errors: u64,
+    /// Ratio of the budget left over the period, negative once exhausted.
+    pub budget_remaining: f64,
+    /// Burn rate over the long window of the fast burn threshold.
+    pub fast_burn_rate: f64,
+    /// Burn rate over the long window of the slow burn threshold.
+    pub slow_burn_rate: f64,
+    /// Whether the budget is burning beyond the fast burn threshold.
+    pub fast_burn: bool,
+    /// Whether the budget is burning beyond the slow burn threshold.
+    pub slow_burn: bool,
+    /// Unix timestamp of the last change of the fast burn state.
+    pub since: u64,
+}
+
+/// Requests to an objective, at the resolution of the burn rates and of the budget period.
+struct Series {
+    recent: TimeSeries,
+    period: TimeSeries,
+}
+
+struct Objective {
+    name: String,
+    route: Option<String>,
+    upstream: Option<String>,
+    max_error_rate: f64,
+}
+
+/// Computes the error budgets and burn rates of the objectives.
+pub struct ErrorBudgets {
+    config: ErrorBudgetConfig,
+    objectives: Vec<Objective>,
+    /// Longest window of the burn rate thresholds, covered by the recent series.
+    recent_window: Duration,
+    series: Mutex<Vec<Series>>,
+    statuses: Mutex<HashMap<String, ErrorBudgetStatus>>,
+}
+
+impl ErrorBudgets {
+    /// Creates the budgets of the routes of `config` and of the upstreams of `slos`.
+    pub fn new(config: ErrorBudgetConfig, slos: &[SloConfig]) -> Self {
+        let upstreams = slos.iter().map(|slo| Objective {
+            name: format!("upstream {}", slo.upstream),
+            route: None,
+            upstream: Some(slo.upstream.clone()),
+            max_error_rate: slo.max_error_rate,
+        });
+        let routes = config.routes.iter().map(|objective| Objective {
+            name: format!("route {}", objective.route),
+            route: Some(objective.route.clone()),
+            upstream: None,
+            max_error_rate: objective.max_error_rate,
+        });
+        let objectives: Vec<Objective> = upstreams.chain(routes).collect();
+        let recent_window = config
+            .fast_burn
+            .long_window
+            .max(config.slow_burn.long_window);
+        let recent_buckets = recent_window.as_secs() / BURN_RESOLUTION.as_secs() + 1;
+        let period_resolution = (config.period / PERIOD_BUCKETS as u32).max(BURN_RESOLUTION);
+        let series = objectives
+            .iter()
+            .map(|_| Series {
+                recent: TimeSeries::new(BURN_RESOLUTION, recent_buckets as usize),
+                period: TimeSeries::new(period_resolution, PERIOD_BUCKETS + 1),
+            })
+            .collect();
+        ErrorBudgets {
+            config,
+            objectives,
+            recent_window,
+            series: Mutex::new(series),
+            statuses: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether any objective applies to routes.
+    pub(crate) fn has_routes(&self) -> bool {
+        self.objectives
+            .iter()
+            .any(|objective| objective.route.is_some())
+    }
+
+    /// Records the outcome of a request forwarded to `upstream`.
+    pub(crate) fn record_upstream(&self, upstream: &str, success: bool) {
+        self.record(
+            |objective| objective.upstream.as_deref() == Some(upstream),
+            success,
+        );
+    }
+
+    /// Records the outcome of a request to `path`, counted under every route it belongs to.
+    pub(crate) fn record_route(&self, path: &str, success: bool) {
+        self.record(
+            |objective| {
+                objective
+                    .route
+                    .as_deref()
+                    .is_some_and(|route| path.starts_with(route))
+            },
+            success,
+        );
+    }
+
+    fn record(&self, applies: impl Fn(&Objective) -> bool, success: bool) {
+        let now = unix_now();
+        let mut series = self.series.lock().unwrap();
+        for (objective, series) in self.objectives.iter().zip(series.iter_mut()) {
+            if !applies(objective) {
+                continue;
+            }
+            for series in [&mut series.recent, &mut series.period] {
+                series.record_request(now, Duration::ZERO);
+                if !success {
+                    series.record_error(now);
+                }
+            }
+        }
+    }
+
+    /// Returns the latest status of every objective.
+    pub fn statuses(&self) -> Vec<ErrorBudgetStatus> {
+        let statuses = self.statuses.lock().unwrap();
+        self.objectives
+            .iter()
+            .filter_map(|objective| statuses.get(&objective.name).cloned())
+            .collect()
+    }
+
+    /// Evaluates the budget of every objective, returning those whose fast burn started or ended.
+    pub fn evaluate(&self) -> Vec<ErrorBudgetStatus> {
+        let now = unix_now();
+        let mut changed = Vec::new();
+        let series = self.series.lock().unwrap();
+        let mut statuses = self.statuses.lock().unwrap();
+        for (objective, series) in self.objectives.iter().zip(series.iter()) {
+            let burn_rate = |window: Duration| {
+                let series = if window <= self.recent_window {
+                    &series.recent
+                } else {
+                    &series.period
+                };
+                let (requests, errors) = totals(&series.window(now, window));
+                burn_rate(requests, errors, objective.max_error_rate)
+            };
+            let burning = |alert: &BurnRateAlert| {
+                burn_rate(alert.long_window) > alert.burn_rate
+                    && burn_rate(alert.short_window) > alert.burn_rate
+            };
+            let (requests, errors) = totals(&series.period.window(now, self.config.period));
+            let previous = statuses.get(&objective.name).cloned().unwrap_or_default();
+            let mut status = ErrorBudgetStatus {
+                objective: objective.name.clone(),
+                max_error_rate: objective.max_error_rate,
+                requests,
+                errors,
+                budget_remaining: 1.0 - burn_rate(self.config.period),
+                fast_burn_rate: burn_rate(self.config.fast_burn.long_window),
+                slow_burn_rate: burn_rate(self.config.slow_burn.long_window),
+                fast_burn: burning(&self.config.fast_burn),
+                slow_burn: burning(&self.config.slow_burn),
+                since: previous.since,
+            };
+            if status.fast_burn != previous.fast_burn || previous.since == 0 {
+                status.since = now;
+            }
+            if status.fast_burn != previous.fast_burn {
+                if status.fast_burn {
+                    warn!(
+                        "Error budget of {} burning fast: burn rate {:.1}, {:.1}% of the budget left",
+                        objective.name,
+                        status.fast_burn_rate,
+                        status.budget_remaining * 100.0
+                    );
+                } else {
+                    info!("Error budget of {} no longer burning fast", objective.name);
+                }
+                changed.push(status.clone());
+            }
+            statuses.insert(objective.name.clone(), status);
+        }
+        changed
+    }
+
+    /// Returns the webhook notified of fast burns, if any.
+    pub(crate) fn webhook_url(&self) -> Option<&str> {
+        self.config.webhook_url.as_deref()
+    }
+}
+
+/// Returns the number of requests and errors of `buckets`.
+fn totals(buckets: &[Bucket]) -> (u64, u64) {
+    buckets.iter().fold((0, 0), |(requests, errors), bucket| {
+        (requests + bucket.requests, errors + bucket.errors)
+    })
+}
+
+/// Returns the rate `errors` out of `requests` burn a budget allowing `max_error_rate` at.
+fn burn_rate(requests: u64, errors: u64, max_error_rate: f64) -> f64 {
+    if requests == 0 {
+        return 0.0;
+    }
+    let error_rate = errors as f64 / requests as f64;
+    // An objective allowing no error has no budget, which the first error exhausts
+    error_rate / max_error_rate.max(f64::EPSILON)
+}
@@ -0,0 +1,142 @@
+//! Generation of strong ETags for responses the origin serves without validators, so clients can make conditional
+//! requests that the proxy answers with `304 Not Modified`.
+//!
+//! The tag is a hash of the body as sent to the client, suffixed with its content coding when the proxy encodes it,
+//! as a strong validator must differ between representations. Tags of cached responses are kept with their cache key,
+//! so cache hits are tagged without hashing the body again.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use hyper::{
+    header::{
+        CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LOCATION, DATE, ETAG, EXPIRES, IF_NONE_MATCH,
+        LAST_MODIFIED, VARY,
+    },
+    Body, HeaderMap, Response, StatusCode,
+};
+use ring::digest;
+
+use crate::canary::hex;
+
+/// ETag generation settings.
+#[derive(Clone, Debug)]
+pub struct EtagConfig {
+    /// Largest body tagged, in bytes; larger ones are streamed to the client untagged. Defaults to 8 MiB.
+    pub max_body_size: usize,
+}
+
+impl Default for EtagConfig {
+    fn default() -> Self {
+        Self {
+            max_body_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// Computes the ETags of responses and remembers those of cached responses.
+pub struct EtagGenerator {
+    config: EtagConfig,
+    tags: Mutex<HashMap<String, String>>,
+}
+
+impl EtagGenerator {
+    /// Creates a generator with the given settings.
+    pub fn new(config: EtagConfig) -> Self {
+        EtagGenerator {
+            config,
+            tags: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a response with `status` and `headers` gets a generated ETag: a full response without any validator.
+    pub(crate) fn applies(&self, status: StatusCode, headers: &HeaderMap) -> bool {
+        status == StatusCode::OK
+            && !headers.contains_key(ETAG)
+            && !headers.contains_key(LAST_MODIFIED)
+    }
+
+    /// Whether a body of `len` bytes is small enough to be tagged.
+    pub(crate) fn accepts(&self, len: u64) -> bool {
+        len <= self.config.max_body_size as u64
+    }
+
+    /// Returns the tag of `body`, or `None` if it is too large.
+    pub(crate) fn tag(&self, body: &[u8]) -> Option<String> {
+        if !self.accepts(body.len() as u64) {
+            return None;
+        }
+        Some(hex(&digest::digest(&digest::SHA256, body).as_ref()[..16]))
+    }
+
+    /// Remembers the tag of the response cached under `key`, forgetting the previous one when it has none.
+    pub(crate) fn remember(&self, key: &str, tag: Option<String>) {
+        let mut tags = self.tags.lock().unwrap();
+        match tag {
+            Some(tag) => tags.insert(key.to_string(), tag),
+            None => tags.remove(key),
+        };
+    }
+
+    /// Returns the tag of the response cached under `key`.
+    pub(crate) fn cached(&self, key: &str) -> Option<String> {
+        self.tags.lock().unwrap().get(key).cloned()
+    }
+
+    /// Forgets the tags of the responses no longer `cached`.
+    pub(crate) fn retain(&self, cached: impl Fn(&str) -> bool) {
+        self.tags.lock().unwrap().retain(|key, _| cached(key));
+    }
+}
+
+/// Sets the ETag of `response` from `tag`, and answers with `304 Not Modified` when `if_none_match`, the header of
+/// the request, matches it.
+pub(crate) fn respond(
+    if_none_match: Option<&str>,
+    tag: &str,
+    mut response: Response<Body>,
+) -> Response<Body> {
+    let etag = match response
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|coding| coding.to_str().ok())
+    {
+        Some(coding) => format!("\"{}-{}\"", tag, coding),
+        None => format!("\"{}\"", tag),
+    };
+    let value = match etag.parse() {
+        Ok(value) => value,
+        Err(_) => return response,
+    };
+    response.headers_mut().insert(ETAG, value);
+    if !if_none_match.is_some_and(|condition| matches(condition, &etag)) {
+        return response;
+    }
+    // A 304 carries the headers a 200 would have that describe caching, but no representation
+    let mut not_modified = Response::new(Body::empty());
+    *not_modified.status_mut() = StatusCode::NOT_MODIFIED;
+    for name in [ETAG, CACHE_CONTROL, CONTENT_LOCATION, DATE, EXPIRES, VARY] {
+        for value in response.headers().get_all(&name) {
+            not_modified
+                .headers_mut()
+                .append(name.clone(), value.clone());
+        }
+    }
+    not_modified
+}
+
+/// Whether an `If-None-Match` condition lists `etag`, compared weakly as the condition requires.
+fn matches(condition: &str, etag: &str) -> bool {
+    condition.trim() == "*"
+        || condition
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == etag)
+}
+
+/// Returns the `If-None-Match` header of a request.
+pub(crate) fn if_none_match(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
@@ -0,0 +1,79 @@
+//! Deterministic assignment of requests to A/B experiment variants.
+
+use std::net::IpAddr;
+
+use hyper::HeaderMap;
+use ring::digest;
+use serde::Serialize;
+
+use crate::session::read_cookie;
+
+/// A variant of an experiment.
+#[derive(Clone, Debug)]
+pub struct ExperimentVariant {
+    /// Name of the variant, sent to the upstream as the header value.
+    pub name: String,
+    /// Relative share of the traffic assigned to the variant.
+    pub weight: u32,
+}
+
+/// What identifies a client when assigning it to a variant.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExperimentKey {
+    /// The IP address of the client.
+    ClientIp,
+    /// The value of the given cookie, falling back to the IP address when the cookie is missing.
+    Cookie(String),
+}
+
+/// An A/B experiment.
+#[derive(Clone, Debug)]
+pub struct ExperimentConfig {
+    /// Name of the experiment; requests carry the assigned variant in `X-Experiment-<name>`.
+    pub name: String,
+    /// Variants of the experiment.
+    pub variants: Vec<ExperimentVariant>,
+    /// What identifies a client.
+    pub key: ExperimentKey,
+}
+
+/// Traffic of an experiment variant.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct VariantStats {
+    /// Number of requests assigned to the variant.
+    pub requests: u64,
+    /// Number of those requests that ended with a non-success status.
+    pub errors: u64,
+}
+
+impl ExperimentConfig {
+    /// Returns the name of the header carrying the variant.
+    pub fn header_name(&self) -> String {
+        format!("x-experiment-{}", self.name.to_ascii_lowercase())
+    }
+
+    /// Returns the variant of a client, the same for every request of the client.
+    pub fn assign(&self, headers: &HeaderMap, ip: IpAddr) -> Option<&ExperimentVariant> {
+        let total: u64 = self.variants.iter().map(|v| u64::from(v.weight)).sum();
+        if total == 0 {
+            return None;
+        }
+        let key = match &self.key {
+            ExperimentKey::Cookie(name) => {
+                read_cookie(headers, name).unwrap_or_else(|| ip.to_string())
+            }
+            ExperimentKey::ClientIp => ip.to_string(),
+        };
+        let hash = digest::digest(&digest::SHA256, format!("{}:{}", self.name, key).as_bytes());
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&hash.as_ref()[..8]);
+        let mut point = u64::from_be_bytes(bytes) % total;
+        self.variants.iter().find(|variant| {
+            if point < u64::from(variant.weight) {
+                return true;
+            }
+            point -= u64::from(variant.weight);
+            false
+        })
+    }
+}
@@ -0,0 +1,283 @@
+//! Failover between egress paths, such as a SOCKS5 proxy backed by direct connections: connections go through the
+//! first path of the list that is up, and a path failing to connect is marked down until it connects again, so
+//! traffic fails back to it as soon as it recovers. Only failures of the path itself count: a destination refusing
+//! the connection, or that a proxy reports unreachable, does not mark the path down.
+//!
+//! Down paths are probed in the background with connections to a probe target, or retried by connections once the
+//! probe interval elapsed when there is none. Every connection logs the path it went through.
+
+use std::{
+    sync::{Arc, Mutex, Once, Weak},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use log::{info, warn};
+
+use crate::{
+    connector::{self, Connector, ConnectorConfig},
+    trace,
+    tunnel::UpstreamStream,
+};
+
+/// Egress paths tried in order of preference.
+#[derive(Clone, Debug)]
+pub struct FailoverConfig {
+    /// Paths in order of preference, the first being the primary one. TLS is configured on the upstreams rather than
+    /// on a path.
+    pub paths: Vec<ConnectorConfig>,
+    /// Consecutive failures to connect after which a path is marked down. Defaults to 3.
+    pub failure_threshold: u32,
+    /// Time allowed to connect through a path before trying the next one. Defaults to 5 seconds.
+    pub connect_timeout: Duration,
+    /// Destination, as `host:port`, connected to through the down paths to detect their recovery (optional).
+    /// Without it, down paths are retried by the first connection once the probe interval elapsed.
+    pub probe_target: Option<String>,
+    /// Interval of the probes of the down paths. Defaults to 10 seconds.
+    pub probe_interval: Duration,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        Self {
+            paths: Vec::new(),
+            failure_threshold: 3,
+            connect_timeout: Duration::from_secs(5),
+            probe_target: None,
+            probe_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Health of an egress path.
+#[derive(Default)]
+struct PathHealth {
+    failures: u32,
+    /// When the path was marked down, or last retried while down.
+    down_since: Option<Instant>,
+}
+
+/// An egress path.
+struct Path {
+    connector: Arc<dyn Connector>,
+    description: String,
+    health: Mutex<PathHealth>,
+}
+
+impl Path {
+    /// Whether connections may go through the path, retrying it after `retry_after` while down if set.
+    fn usable(&self, retry_after: Option<Duration>) -> bool {
+        let mut health = self.health.lock().unwrap();
+        match (health.down_since, retry_after) {
+            (None, _) => true,
+            (Some(down_since), Some(retry_after)) if down_since.elapsed() >= retry_after => {
+                // A single connection retries the path
+                health.down_since = Some(Instant::now());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn is_down(&self) -> bool {
+        self.health.lock().unwrap().down_since.is_some()
+    }
+
+    fn succeeded(&self) {
+        let mut health = self.health.lock().unwrap();
+        if health.down_since.take().is_some() {
+            info!("Egress path {} is back up", self.description);
+        }
+        health.failures = 0;
+    }
+
+    fn failed(&self, threshold: u32) {
+        let mut health = self.health.lock().unwrap();
+        health.failures += 1;
+        if health.failures >= threshold && health.down_since.is_none() {
+            warn!(
+                "Egress path {} is down after {} consecutive failures",
+                self.description, health.failures
+            );
+            health.down_since = Some(Instant::now());
+        }
+    }
+
+    /// Connects to port `port` of `host` through the path, within `timeout`.
+    async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        timeout: Duration,
+    ) -> Result<Box<dyn UpstreamStream>> {
+        match tokio::time::timeout(timeout, self.connector.connect(host, port)).await {
+            Ok(result) => result,
+            Err(_) => anyhow::bail!(
+                "Timed out connecting to {}:{} through {}",
+                host,
+                port,
+                self.description
+            ),
+        }
+    }
+}
+
+/// A transport failing over between egress paths.
+pub(crate) struct FailoverConnector {
+    paths: Arc<Vec<Path>>,
+    failure_threshold: u32,
+    connect_timeout: Duration,
+    probe_target: Option<(String, u16)>,
+    probe_interval: Duration,
+    probing: Once,
+}
+
+impl FailoverConnector {
+    /// Creates the paths of `config`. Fails if there is none, one is invalid, or the probe target has no port.
+    pub(crate) fn new(config: &FailoverConfig) -> Result<Self> {
+        if config.paths.is_empty() {
+            anyhow::bail!("Failover needs at least one egress path");
+        }
+        let mut paths = Vec::new();
+        for (index, path) in config.paths.iter().enumerate() {
+            if matches!(path, ConnectorConfig::Tls) {
+                anyhow::bail!(
+                    "Egress path {} uses TLS, which is configured on upstreams",
+                    index
+                );
+            }
+            let connector =
+                connector::build(path).context(format!("Invalid egress path {}", index))?;
+            paths.push(Path {
+                description: connector.describe(),
+                connector,
+                health: Mutex::new(PathHealth::default()),
+            });
+        }
+        let probe_target = match &config.probe_target {
+            Some(target) => {
+                let (host, port) = target
+                    .rsplit_once(':')
+                    .context(format!("Missing port in probe target {}", target))?;
+                let port = port
+                    .parse()
+                    .context(format!("Invalid port in probe target {}", target))?;
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                Some((host.to_string(), port))
+            }
+            None => None,
+        };
+        Ok(FailoverConnector {
+            paths: Arc::new(paths),
+            failure_threshold: config.failure_threshold.max(1),
+            connect_timeout: config.connect_timeout,
+            probe_target,
+            probe_interval: config.probe_interval,
+            probing: Once::new(),
+        })
+    }
+
+    /// Starts probing the down paths, on the first connection so a runtime is running.
+    fn start_probing(&self) {
+        if let Some((host, port)) = &self.probe_target {
+            self.probing.call_once(|| {
+                tokio::spawn(probe(
+                    Arc::downgrade(&self.paths),
+                    host.clone(),
+                    *port,
+                    self.probe_interval,
+                    self.connect_timeout,
+                ));
+            });
+        }
+    }
+}
+
+impl Connector for FailoverConnector {
+    fn describe(&self) -> String {
+        let paths: Vec<&str> = self
+            .paths
+            .iter()
+            .map(|path| path.description.as_str())
+            .collect();
+        format!("failover between {}", paths.join(", "))
+    }
+
+    fn connect(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> BoxFuture<'static, Result<Box<dyn UpstreamStream>>> {
+        self.start_probing();
+        let paths = self.paths.clone();
+        let host = host.to_string();
+        let threshold = self.failure_threshold;
+        let timeout = self.connect_timeout;
+        let retry_after = match self.probe_target {
+            Some(_) => None,
+            None => Some(self.probe_interval),
+        };
+        Box::pin(async move {
+            // The paths up in order of preference, then the down ones as a last resort
+            let (up, down): (Vec<&Path>, Vec<&Path>) =
+                paths.iter().partition(|path| path.usable(retry_after));
+            let mut last_error = None;
+            for path in up.into_iter().chain(down) {
+                match path.connect(&host, port, timeout).await {
+                    Ok(stream) => {
+                        path.succeeded();
+                        info!(
+                            "Connected to {}:{} through egress path {}",
+                            host, port, path.description
+                        );
+                        trace::event("egress_path", Some(path.description.clone()));
+                        return Ok(stream);
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Egress path {} failed to connect to {}:{}: {:#}",
+                            path.description, host, port, err
+                        );
+                        // An unreachable destination says nothing of the health of the path
+                        if !connector::is_destination_failure(&err) {
+                            path.failed(threshold);
+                        }
+                        last_error = Some(err);
+                    }
+                }
+            }
+            match last_error {
+                Some(err) => Err(err.context(format!(
+                    "Every egress path failed to connect to {}:{}",
+                    host, port
+                ))),
+                None => anyhow::bail!("No egress path to {}:{}", host, port),
+            }
+        })
+    }
+}
+
+/// Connects to `host:port` through the down paths every `interval`, marking those that connect back up, until the
+/// paths are dropped.
+async fn probe(
+    paths: Weak<Vec<Path>>,
+    host: String,
+    port: u16,
+    interval: Duration,
+    timeout: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let paths = match paths.upgrade() {
+            Some(paths) => paths,
+            None => return,
+        };
+        for path in paths.iter().filter(|path| path.is_down()) {
+            if path.connect(&host, port, timeout).await.is_ok() {
+                path.succeeded();
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Fault injection for staging environments: random latency, dropped connections and synthetic server errors per
+//! route, to test how clients cope with a misbehaving upstream.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use hyper::StatusCode;
+use rand::Rng;
+
+use crate::tenant::strip_port;
+
+/// Faults injected into the requests of a route.
+#[derive(Clone, Debug)]
+pub struct FaultRule {
+    /// Path prefix of the requests the rule applies to; `/` matches every request.
+    pub path_prefix: String,
+    /// Host the rule is restricted to, compared with the `Host` header without its port. Applies to every host
+    /// when `None`.
+    pub host: Option<String>,
+    /// Latency added to delayed requests.
+    pub latency: Duration,
+    /// Probability, between `0.0` and `1.0`, that a request is delayed by `latency`.
+    pub latency_probability: f64,
+    /// Probability that the connection of a request is dropped without a response.
+    pub abort_probability: f64,
+    /// Probability that a request is answered with `error_status` instead of being forwarded.
+    pub error_probability: f64,
+    /// Status of the synthetic error responses, between `500` and `599`.
+    pub error_status: u16,
+}
+
+impl Default for FaultRule {
+    fn default() -> Self {
+        Self {
+            path_prefix: "/".to_string(),
+            host: None,
+            latency: Duration::from_secs(1),
+            latency_probability: 0.0,
+            abort_probability: 0.0,
+            error_probability: 0.0,
+            error_status: 503,
+        }
+    }
+}
+
+/// Fault injection settings.
+#[derive(Clone, Debug, Default)]
+pub struct FaultInjectionConfig {
+    /// Rules evaluated in order; the first rule matching a request applies.
+    pub rules: Vec<FaultRule>,
+}
+
+/// Fault injected into a request after its delay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The request is handled normally.
+    None,
+    /// The connection is dropped without a response.
+    Abort,
+    /// The request is answered with this status.
+    Error(StatusCode),
+}
+
+/// Faults drawn for a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FaultPlan {
+    /// Latency added before the request is handled.
+    pub delay: Option<Duration>,
+    /// Fault injected after the delay.
+    pub fault: Fault,
+}
+
+/// Draws the faults injected into requests.
+pub struct FaultInjector {
+    rules: Vec<FaultRule>,
+}
+
+impl FaultInjector {
+    /// Creates an injector, failing on probabilities outside `0.0..=1.0` or non-5xx error statuses.
+    pub fn new(config: FaultInjectionConfig) -> Result<Self> {
+        for rule in &config.rules {
+            for probability in [
+                rule.latency_probability,
+                rule.abort_probability,
+                rule.error_probability,
+            ] {
+                if !(0.0..=1.0).contains(&probability) {
+                    anyhow::bail!(
+                        "Invalid fault probability for {}: {}",
+                        rule.path_prefix,
+                        probability
+                    );
+                }
+            }
+            if !(500..600).contains(&rule.error_status) {
+                anyhow::bail!(
+                    "Invalid fault status for {}: {} is not a server error",
+                    rule.path_prefix,
+                    rule.error_status
+                );
+            }
+        }
+        Ok(FaultInjector {
+            rules: config.rules,
+        })
+    }
+
+    /// Draws the faults of a request for `path` on `host`.
+    pub fn plan(&self, host: Option<&str>, path: &str) -> FaultPlan {
+        let host = host.map(strip_port);
+        let rule = self.rules.iter().find(|rule| {
+            path.starts_with(&rule.path_prefix)
+                && rule.host.as_deref().is_none_or(|expected| {
+                    host.is_some_and(|host| host.eq_ignore_ascii_case(expected))
+                })
+        });
+        let rule = match rule {
+            Some(rule) => rule,
+            None => {
+                return FaultPlan {
+                    delay: None,
+                    fault: Fault::None,
+                }
+            }
+        };
+        let mut rng = rand::thread_rng();
+        let delay = rng
+            .gen_bool(rule.latency_probability)
+            .then_some(rule.latency);
+        let fault = if rng.gen_bool(rule.abort_probability) {
+            Fault::Abort
+        } else if rng.gen_bool(rule.error_probability) {
+            StatusCode::from_u16(rule.error_status).map_or(Fault::None, Fault::Error)
+        } else {
+            Fault::None
+        };
+        FaultPlan { delay, fault }
+    }
+}
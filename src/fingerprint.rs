@@ -0,0 +1,323 @@
+//! TLS client fingerprints, JA3 and JA4, computed from the ClientHello of the connections whose TLS the proxy
+//! terminates, and of the tunnels and passthrough connections whose ClientHello it sniffs.
+//!
+//! Fingerprints identify the TLS stack of clients rather than the clients themselves: connections with a blocked
+//! fingerprint are closed before their handshake, policies match fingerprints, access logs record them and the
+//! dashboard lists the most frequent ones. JA4 sorts the cipher suites and extensions, so unlike JA3 it is stable
+//! across the extension order randomization of recent browsers; connections are counted by their JA4 fingerprint.
+
+use std::collections::HashMap;
+
+use md5::{Digest, Md5};
+use ring::digest;
+
+use crate::{
+    canary::hex,
+    tls_hello::{is_grease, ClientHello},
+};
+
+/// Extension type of `server_name`, left out of the sorted extensions of JA4.
+const EXT_SERVER_NAME: u16 = 0x0000;
+/// Extension type of `application_layer_protocol_negotiation`, left out of the sorted extensions of JA4.
+const EXT_ALPN: u16 = 0x0010;
+/// Extension type of `supported_versions`.
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+/// Distinct fingerprints counted by the metrics; connections with further ones are counted together.
+const MAX_TRACKED_FINGERPRINTS: usize = 1000;
+/// Hash part of JA4 for an empty list.
+const EMPTY_HASH: &str = "000000000000";
+
+/// The JA3 and JA4 fingerprints of a ClientHello.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsFingerprint {
+    /// JA3 hash: the MD5 hash of the version, cipher suites, extensions, groups and point formats, in order.
+    pub ja3: String,
+    /// JA4 fingerprint, such as `t13d1516h2_8daaf6152771_e5627efa2ab1`.
+    pub ja4: String,
+}
+
+impl TlsFingerprint {
+    /// Computes the fingerprints of `hello`, received over TCP.
+    pub fn of(hello: &ClientHello) -> Self {
+        let ja3 = hex(&Md5::digest(ja3_string(hello).as_bytes()));
+        TlsFingerprint {
+            ja3,
+            ja4: ja4(hello),
+        }
+    }
+
+    /// Whether `fingerprint`, a JA3 hash or a JA4 fingerprint, is one of these, ignoring case.
+    pub fn matches(&self, fingerprint: &str) -> bool {
+        let fingerprint = fingerprint.trim();
+        fingerprint.eq_ignore_ascii_case(&self.ja3) || fingerprint.eq_ignore_ascii_case(&self.ja4)
+    }
+}
+
+/// Returns the JA3 string of `hello`, whose MD5 hash is the JA3 fingerprint: the decimal version, cipher suites,
+/// extensions, groups and point formats, without GREASE values.
+pub fn ja3_string(hello: &ClientHello) -> String {
+    fn join<T: ToString>(values: impl Iterator<Item = T>) -> String {
+        values
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+    let values = |list: &[u16]| join(list.iter().filter(|value| !is_grease(**value)));
+    format!(
+        "{},{},{},{},{}",
+        hello.version,
+        values(&hello.cipher_suites),
+        values(&hello.extensions),
+        values(&hello.supported_groups),
+        join(hello.ec_point_formats.iter())
+    )
+}
+
+/// Returns the JA4 fingerprint of `hello`, received over TCP.
+fn ja4(hello: &ClientHello) -> String {
+    let ciphers: Vec<u16> = hello
+        .cipher_suites
+        .iter()
+        .copied()
+        .filter(|cipher| !is_grease(*cipher))
+        .collect();
+    let extensions: Vec<u16> = hello
+        .extensions
+        .iter()
+        .copied()
+        .filter(|extension| !is_grease(*extension))
+        .collect();
+    // The highest version of the supported_versions extension, or the version of the message without one
+    let version = if hello.extensions.contains(&EXT_SUPPORTED_VERSIONS) {
+        hello
+            .supported_versions
+            .iter()
+            .copied()
+            .filter(|version| !is_grease(*version))
+            .max()
+            .unwrap_or(hello.version)
+    } else {
+        hello.version
+    };
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        _ => "00",
+    };
+    let destination = if hello.server_name.is_some() {
+        'd'
+    } else {
+        'i'
+    };
+    let prefix = format!(
+        "t{}{}{:02}{:02}{}",
+        version,
+        destination,
+        ciphers.len().min(99),
+        extensions.len().min(99),
+        alpn(hello.alpn.first().map(String::as_str))
+    );
+
+    let mut sorted_ciphers = ciphers;
+    sorted_ciphers.sort_unstable();
+    let mut sorted_extensions: Vec<u16> = extensions
+        .into_iter()
+        .filter(|extension| *extension != EXT_SERVER_NAME && *extension != EXT_ALPN)
+        .collect();
+    sorted_extensions.sort_unstable();
+    let mut extensions = hex_list(&sorted_extensions);
+    if !hello.signature_algorithms.is_empty() {
+        extensions.push('_');
+        extensions.push_str(&hex_list(&hello.signature_algorithms));
+    }
+    let cipher_hash = if sorted_ciphers.is_empty() {
+        EMPTY_HASH.to_string()
+    } else {
+        truncated_sha256(&hex_list(&sorted_ciphers))
+    };
+    let extension_hash = if extensions.is_empty() {
+        EMPTY_HASH.to_string()
+    } else {
+        truncated_sha256(&extensions)
+    };
+    format!("{}_{}_{}", prefix, cipher_hash, extension_hash)
+}
+
+/// Returns the ALPN part of JA4: the first and last characters of the first protocol when they are alphanumeric,
+/// those of its hexadecimal form otherwise, and `00` without ALPN.
+fn alpn(protocol: Option<&str>) -> String {
+    let protocol = match protocol {
+        Some(protocol) if !protocol.is_empty() => protocol,
+        _ => return "00".to_string(),
+    };
+    let first = protocol.chars().next().unwrap_or('0');
+    let last = protocol.chars().last().unwrap_or('0');
+    if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+        format!("{}{}", first, last)
+    } else {
+        let hex = hex(protocol.as_bytes());
+        format!("{}{}", &hex[..1], &hex[hex.len() - 1..])
+    }
+}
+
+/// Returns `values` as comma-separated, 4-digit lowercase hexadecimal numbers.
+fn hex_list(values: &[u16]) -> String {
+    values
+        .iter()
+        .map(|value| format!("{:04x}", value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Returns the first 12 hexadecimal digits of the SHA-256 hash of `value`.
+fn truncated_sha256(value: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, value.as_bytes());
+    hex(&hash.as_ref()[..6])
+}
+
+/// Connections per TLS fingerprint.
+#[derive(Clone, Debug, Default)]
+pub struct FingerprintStats {
+    /// A hashmap of connection counts, with the keys representing the JA4 fingerprints of the first 1000 distinct
+    /// ones.
+    pub connections: HashMap<String, u64>,
+    /// Connections with a fingerprint beyond the first 1000 distinct ones.
+    pub untracked: u64,
+    /// Connections closed for their blocked fingerprint.
+    pub blocked: u64,
+}
+
+impl FingerprintStats {
+    /// Records a connection with `fingerprint`, closed if `blocked`.
+    pub(crate) fn record(&mut self, fingerprint: &TlsFingerprint, blocked: bool) {
+        if blocked {
+            self.blocked += 1;
+        }
+        let tracked = self.connections.len() < MAX_TRACKED_FINGERPRINTS
+            || self.connections.contains_key(&fingerprint.ja4);
+        if tracked {
+            *self.connections.entry(fingerprint.ja4.clone()).or_insert(0) += 1;
+        } else {
+            self.untracked += 1;
+        }
+    }
+
+    /// Returns the `count` most frequent JA4 fingerprints with their connection counts, most frequent first.
+    pub fn top(&self, count: usize) -> Vec<(&str, u64)> {
+        let mut fingerprints: Vec<_> = self
+            .connections
+            .iter()
+            .map(|(fingerprint, connections)| (fingerprint.as_str(), *connections))
+            .collect();
+        fingerprints.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        fingerprints.truncate(count);
+        fingerprints
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The ClientHello of the JA3 example: `769,47-53-5-10-49161-49162-49171-49172-50-56-19-4,0-10-11,23-24-25,0`.
+    fn ja3_example() -> ClientHello {
+        ClientHello {
+            version: 769,
+            cipher_suites: vec![
+                0x0a0a, 47, 53, 5, 10, 49161, 49162, 49171, 49172, 50, 56, 19, 4,
+            ],
+            extensions: vec![0x1a1a, 0, 10, 11],
+            supported_groups: vec![0x2a2a, 23, 24, 25],
+            ec_point_formats: vec![0],
+            server_name: Some("example.com".to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// The ClientHello of the JA4 example, a Chrome connection: `t13d1516h2_8daaf6152771_e5627efa2ab1`.
+    fn ja4_example() -> ClientHello {
+        ClientHello {
+            version: 0x0303,
+            cipher_suites: vec![
+                0x3a3a, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8,
+                0xc013, 0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+            ],
+            extensions: vec![
+                0x4a4a, 0x0000, 0x0017, 0xff01, 0x000a, 0x000b, 0x0023, 0x0010, 0x0005, 0x000d,
+                0x0012, 0x0033, 0x002d, 0x002b, 0x001b, 0x4469, 0x0015,
+            ],
+            server_name: Some("example.com".to_string()),
+            alpn: vec!["h2".to_string(), "http/1.1".to_string()],
+            signature_algorithms: vec![
+                0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+            ],
+            supported_versions: vec![0x5a5a, 0x0304, 0x0303],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn computes_the_published_ja3() {
+        let hello = ja3_example();
+        assert_eq!(
+            ja3_string(&hello),
+            "769,47-53-5-10-49161-49162-49171-49172-50-56-19-4,0-10-11,23-24-25,0"
+        );
+        let fingerprint = TlsFingerprint::of(&hello);
+        assert_eq!(fingerprint.ja3, "ada70206e40642a3e4461f35503241d5");
+        assert!(fingerprint.matches(" ADA70206E40642A3E4461F35503241D5 "));
+    }
+
+    #[test]
+    fn computes_the_published_ja4() {
+        let mut hello = ja4_example();
+        let ja4 = "t13d1516h2_8daaf6152771_e5627efa2ab1";
+        assert_eq!(TlsFingerprint::of(&hello).ja4, ja4);
+        // Unlike JA3, JA4 does not depend on the extension order
+        let ja3 = TlsFingerprint::of(&hello).ja3;
+        hello.extensions.reverse();
+        assert_eq!(TlsFingerprint::of(&hello).ja4, ja4);
+        assert_ne!(TlsFingerprint::of(&hello).ja3, ja3);
+        assert!(TlsFingerprint::of(&hello).matches(ja4));
+    }
+
+    #[test]
+    fn describes_clients_without_extensions() {
+        let hello = ClientHello {
+            version: 0x0303,
+            ..Default::default()
+        };
+        assert_eq!(
+            TlsFingerprint::of(&hello).ja4,
+            "t12i000000_000000000000_000000000000"
+        );
+        assert_eq!(alpn(Some("h2")), "h2");
+        assert_eq!(alpn(Some("\u{1}x\u{2}")), "02");
+        assert_eq!(alpn(Some("")), "00");
+    }
+
+    #[test]
+    fn counts_fingerprints_beyond_the_limit_as_untracked() {
+        let mut stats = FingerprintStats::default();
+        for i in 0..MAX_TRACKED_FINGERPRINTS + 10 {
+            let fingerprint = TlsFingerprint {
+                ja3: String::new(),
+                ja4: format!("t13d{}", i),
+            };
+            stats.record(&fingerprint, i == 0);
+        }
+        let first = TlsFingerprint {
+            ja3: String::new(),
+            ja4: "t13d0".to_string(),
+        };
+        stats.record(&first, false);
+        assert_eq!(stats.connections.len(), MAX_TRACKED_FINGERPRINTS);
+        assert_eq!(stats.untracked, 10);
+        assert_eq!(stats.blocked, 1);
+        assert_eq!(stats.top(1), vec![("t13d0", 2)]);
+    }
+}
@@ -0,0 +1,399 @@
+//! FTP gateway fetching `ftp://` URLs for HTTP clients: files are streamed and directories rendered as HTML listings.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use hyper::{
+    header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, LOCATION},
+    Body, Response, StatusCode,
+};
+use log::{debug, error};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use url::Url;
+
+use crate::{
+    html::escape_html,
+    tunnel::{self, UpstreamStream},
+};
+
+/// Characters escaped in links of directory listings.
+const HREF: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// FTP gateway settings.
+#[derive(Clone, Debug)]
+pub struct FtpConfig {
+    /// How long connecting and each exchange with the FTP server may take.
+    pub timeout: Duration,
+    /// Password sent for anonymous logins, conventionally an email address.
+    pub anonymous_password: String,
+}
+
+impl Default for FtpConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            anonymous_password: "anonymous@".to_string(),
+        }
+    }
+}
+
+/// A reply of the FTP server.
+struct Reply {
+    code: u16,
+    text: String,
+}
+
+/// The control connection to an FTP server.
+struct Control {
+    stream: BufReader<Box<dyn UpstreamStream>>,
+    timeout: Duration,
+}
+
+impl Control {
+    /// Reads a reply, joining the lines of multi-line replies.
+    async fn reply(&mut self) -> Result<Reply> {
+        let mut text = String::new();
+        let mut line = String::new();
+        let code = loop {
+            line.clear();
+            let read = tokio::time::timeout(self.timeout, self.stream.read_line(&mut line))
+                .await
+                .context("FTP server timed out")??;
+            if read == 0 {
+                anyhow::bail!("FTP server closed the control connection");
+            }
+            text.push_str(&line);
+            // The last line of a reply starts with the code followed by a space
+            if line.len() >= 4 && line.as_bytes()[3] == b' ' {
+                if let Ok(code) = line[..3].parse::<u16>() {
+                    break code;
+                }
+            }
+        };
+        Ok(Reply {
+            code,
+            text: text.trim_end().to_string(),
+        })
+    }
+
+    /// Sends a command and reads its reply.
+    async fn command(&mut self, command: &str) -> Result<Reply> {
+        let shown = if command.starts_with("PASS ") {
+            "PASS ****"
+        } else {
+            command
+        };
+        debug!("FTP command: {}", shown);
+        self.stream
+            .get_mut()
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+        self.reply().await
+    }
+
+    /// Sends a command whose reply must have one of the `expected` codes.
+    async fn expect(&mut self, command: &str, expected: &[u16]) -> Result<Reply> {
+        let reply = self.command(command).await?;
+        if !expected.contains(&reply.code) {
+            anyhow::bail!(
+                "FTP server refused {}: {}",
+                command.split(' ').next().unwrap_or_default(),
+                reply.text
+            );
+        }
+        Ok(reply)
+    }
+
+    /// Reads a reply that must have one of the `expected` codes.
+    async fn expect_reply(&mut self, expected: &[u16]) -> Result<Reply> {
+        let reply = self.reply().await?;
+        if !expected.contains(&reply.code) {
+            anyhow::bail!("FTP transfer failed: {}", reply.text);
+        }
+        Ok(reply)
+    }
+
+    /// Enters passive mode and opens the data connection.
+    async fn data_connection(
+        &mut self,
+        host: &str,
+        socks5: Option<&str>,
+    ) -> Result<Box<dyn UpstreamStream>> {
+        let reply = self.expect("PASV", &[227]).await?;
+        let port = passive_port(&reply.text).context("Malformed PASV reply")?;
+        // Connecting to the control host rather than the advertised address avoids bounce attacks and broken NAT setups
+        let addr = format!("{}:{}", host, port);
+        tokio::time::timeout(self.timeout, tunnel::connect(&addr, socks5))
+            .await
+            .context("FTP data connection timed out")?
+    }
+}
+
+/// Fetches an `ftp://` URL, returning the file or an HTML listing of the directory.
+///
+/// Missing files are returned as `404 Not Found` and other failures as `502 Bad Gateway` responses.
+pub(crate) async fn fetch(
+    config: &FtpConfig,
+    url: &Url,
+    socks5: Option<&str>,
+) -> Result<Response<Body>> {
+    match fetch_inner(config, url, socks5).await {
+        Ok(response) => Ok(response),
+        Err(err) => {
+            error!("FTP request for {} failed: {}", url, err);
+            Ok(Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .header(CONTENT_TYPE, "text/plain")
+                .body(Body::from(format!(
+                    "FTP request for {} failed: {}",
+                    url, err
+                )))?)
+        }
+    }
+}
+
+/// Returns a `404 Not Found` response for a missing file or directory.
+fn not_found(url: &Url) -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Body::from(format!("{} not found", url)))?)
+}
+
+async fn fetch_inner(
+    config: &FtpConfig,
+    url: &Url,
+    socks5: Option<&str>,
+) -> Result<Response<Body>> {
+    let host = url.host_str().context("Missing host in FTP URL")?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let host = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    };
+    let port = url.port().unwrap_or(21);
+    let stream = tokio::time::timeout(
+        config.timeout,
+        tunnel::connect(&format!("{}:{}", host, port), socks5),
+    )
+    .await
+    .context("FTP connection timed out")??;
+    let mut control = Control {
+        stream: BufReader::new(stream),
+        timeout: config.timeout,
+    };
+    let greeting = control.reply().await?;
+    if greeting.code != 220 {
+        anyhow::bail!("FTP server refused the connection: {}", greeting.text);
+    }
+
+    // Log in, anonymously unless the URL carries credentials
+    let user = match url.username() {
+        "" => "anonymous".to_string(),
+        user => percent_decode_str(user).decode_utf8_lossy().into_owned(),
+    };
+    if user.contains(['\r', '\n']) {
+        anyhow::bail!("Line breaks are not allowed in FTP user names");
+    }
+    let password = match url.password() {
+        Some(password) => percent_decode_str(password)
+            .decode_utf8_lossy()
+            .into_owned(),
+        None => config.anonymous_password.clone(),
+    };
+    if password.contains(['\r', '\n']) {
+        anyhow::bail!("Line breaks are not allowed in FTP passwords");
+    }
+    let reply = control
+        .expect(&format!("USER {}", user), &[230, 331])
+        .await?;
+    if reply.code == 331 {
+        control
+            .expect(&format!("PASS {}", password), &[230, 202])
+            .await?;
+    }
+    control.expect("TYPE I", &[200]).await?;
+
+    let path = percent_decode_str(url.path())
+        .decode_utf8_lossy()
+        .into_owned();
+    if path.contains(['\r', '\n']) {
+        anyhow::bail!("Line breaks are not allowed in FTP paths");
+    }
+    if path.ends_with('/') {
+        return list_directory(control, url, &host, &path, socks5).await;
+    }
+
+    let size = match control.command(&format!("SIZE {}", path)).await? {
+        reply if reply.code == 213 => reply
+            .text
+            .get(4..)
+            .and_then(|size| size.trim().parse::<u64>().ok()),
+        _ => None,
+    };
+    let data = control.data_connection(&host, socks5).await?;
+    let reply = control.command(&format!("RETR {}", path)).await?;
+    if reply.code == 550 {
+        // Not a file; redirect directories to their listing
+        if control.command(&format!("CWD {}", path)).await?.code == 250 {
+            let _ = control.command("QUIT").await;
+            let mut location = url.clone();
+            location.set_path(&format!("{}/", url.path()));
+            return Ok(Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(LOCATION, location.as_str())
+                .body(Body::empty())?);
+        }
+        let _ = control.command("QUIT").await;
+        return not_found(url);
+    }
+    if reply.code != 125 && reply.code != 150 {
+        anyhow::bail!("FTP server refused RETR: {}", reply.text);
+    }
+
+    // Stream the file while the transfer is running
+    let (mut sender, body) = Body::channel();
+    let url = url.clone();
+    tokio::spawn(async move {
+        let mut data = data;
+        let mut buffer = vec![0; 16 * 1024];
+        loop {
+            match data.read(&mut buffer).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    if sender.send_data(buffer[..n].to_vec().into()).await.is_err() {
+                        debug!("Client went away during FTP transfer of {}", url);
+                        sender.abort();
+                        return;
+                    }
+                }
+                Err(err) => {
+                    error!("FTP transfer of {} failed: {}", url, err);
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+        drop(data);
+        match control.reply().await {
+            Ok(reply) if reply.code == 226 || reply.code == 250 => {
+                let _ = control.command("QUIT").await;
+            }
+            Ok(reply) => {
+                error!("FTP transfer of {} failed: {}", url, reply.text);
+                sender.abort();
+            }
+            Err(err) => {
+                error!("FTP transfer of {} failed: {}", url, err);
+                sender.abort();
+            }
+        }
+    });
+
+    let mut response = Response::new(body);
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    if let Some(size) = size {
+        response
+            .headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(size));
+    }
+    Ok(response)
+}
+
+/// Lists the directory at `path` and renders it as HTML.
+async fn list_directory(
+    mut control: Control,
+    url: &Url,
+    host: &str,
+    path: &str,
+    socks5: Option<&str>,
+) -> Result<Response<Body>> {
+    if path != "/" {
+        let reply = control.command(&format!("CWD {}", path)).await?;
+        match reply.code {
+            250 => {}
+            550 => return not_found(url),
+            _ => anyhow::bail!("FTP server refused CWD: {}", reply.text),
+        }
+    }
+    let mut data = control.data_connection(host, socks5).await?;
+    control.expect("LIST", &[125, 150]).await?;
+    let mut listing = Vec::new();
+    tokio::time::timeout(control.timeout, data.read_to_end(&mut listing))
+        .await
+        .context("FTP listing timed out")??;
+    drop(data);
+    control.expect_reply(&[226, 250]).await?;
+    let _ = control.command("QUIT").await;
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><title>Index of {0}</title></head><body>\n<h1>Index of {0}</h1>\n<ul>\n",
+        escape_html(&format!("{}{}", url.host_str().unwrap_or_default(), path))
+    );
+    if path != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for line in String::from_utf8_lossy(&listing).lines() {
+        if let Some((name, is_dir)) = parse_list_line(line) {
+            let suffix = if is_dir { "/" } else { "" };
+            html.push_str(&format!(
+                "<li><a href=\"{}{suffix}\">{}{suffix}</a></li>\n",
+                utf8_percent_encode(&name, HREF),
+                escape_html(&name),
+            ));
+        }
+    }
+    html.push_str("</ul>\n</body></html>\n");
+
+    let mut response = Response::new(Body::from(html));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    Ok(response)
+}
+
+/// Returns the port of a `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)` reply.
+fn passive_port(text: &str) -> Option<u16> {
+    let start = text.find('(')?;
+    let end = text[start..].find(')')? + start;
+    let numbers: Vec<u16> = text[start + 1..end]
+        .split(',')
+        .map(|n| n.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    match numbers.as_slice() {
+        [_, _, _, _, high, low] if *high < 256 && *low < 256 => Some(high * 256 + low),
+        _ => None,
+    }
+}
+
+/// Parses a line of a Unix or Windows style `LIST` output into the entry name and whether it is a directory.
+fn parse_list_line(line: &str) -> Option<(String, bool)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (name, is_dir) = if line.starts_with(['d', '-', 'l']) && fields.len() >= 9 {
+        let name = fields[8..].join(" ");
+        // Symbolic links are listed as `name -> target`
+        let name = match name.split_once(" -> ") {
+            Some((name, _)) => name.to_string(),
+            None => name,
+        };
+        (name, line.starts_with('d'))
+    } else if fields.len() >= 4 && fields[0].contains('-') {
+        (fields[3..].join(" "), fields[2] == "<DIR>")
+    } else {
+        return None;
+    };
+    match name.as_str() {
+        "." | ".." | "" => None,
+        _ => Some((name, is_dir)),
+    }
+}
@@ -0,0 +1,88 @@
+//! GeoIP tagging of clients with country-based access control and routing.
+//!
+//! Lookups require the `geoip` feature; without it, configuring a database is an error.
+
+use std::{collections::HashMap, net::IpAddr};
+
+use anyhow::Result;
+
+/// GeoIP settings.
+#[derive(Clone, Debug, Default)]
+pub struct GeoIpConfig {
+    /// Path to a MaxMind GeoLite2/GeoIP2 Country or City database (`.mmdb`).
+    pub database_path: String,
+    /// ISO country codes allowed to connect. All countries are allowed when empty.
+    pub allowed_countries: Vec<String>,
+    /// ISO country codes refused even if present in `allowed_countries`.
+    pub blocked_countries: Vec<String>,
+    /// Whether clients whose country cannot be determined are refused when `allowed_countries` is set.
+    pub block_unknown: bool,
+    /// Target address used instead of `target_address` for clients of the given ISO country code.
+    pub routes: HashMap<String, String>,
+}
+
+impl GeoIpConfig {
+    /// Whether a client from `country` may connect.
+    pub fn is_allowed(&self, country: Option<&str>) -> bool {
+        match country {
+            Some(code) => {
+                if self.blocked_countries.iter().any(|c| c.eq_ignore_ascii_case(code)) {
+                    return false;
+                }
+                self.allowed_countries.is_empty()
+                    || self.allowed_countries.iter().any(|c| c.eq_ignore_ascii_case(code))
+            }
+            None => self.allowed_countries.is_empty() || !self.block_unknown,
+        }
+    }
+
+    /// Returns the target address configured for clients of `country`, if any.
+    pub fn route_for(&self, country: Option<&str>) -> Option<&String> {
+        let code = country?;
+        self.routes
+            .iter()
+            .find(|(c, _)| c.eq_ignore_ascii_case(code))
+            .map(|(_, target)| target)
+    }
+}
+
+/// Country lookups against a MaxMind database.
+pub struct GeoIp {
+    #[cfg(feature = "geoip")]
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    /// Opens the database at `path`.
+    #[cfg(feature = "geoip")]
+    pub fn open(path: &str) -> Result<Self> {
+        let reader = maxminddb::Reader::open_readfile(path)
+            .map_err(|err| anyhow::anyhow!("Failed to open GeoIP database {}: {}", path, err))?;
+        Ok(GeoIp { reader })
+    }
+
+    /// Opens the database at `path`.
+    #[cfg(not(feature = "geoip"))]
+    pub fn open(path: &str) -> Result<Self> {
+        anyhow::bail!(
+            "Cannot open GeoIP database {}: fortifynet_proxy was built without the `geoip` feature",
+            path
+        )
+    }
+
+    /// Returns the ISO country code of `ip`, if known.
+    #[cfg(feature = "geoip")]
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: maxminddb::geoip2::Country = self.reader.lookup(ip).ok()?;
+        record
+            .country
+            .and_then(|country| country.iso_code)
+            .map(str::to_string)
+    }
+
+    /// Returns the ISO country code of `ip`, if known.
+    #[cfg(not(feature = "geoip"))]
+    pub fn country(&self, _ip: IpAddr) -> Option<String> {
+        None
+    }
+}
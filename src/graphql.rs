@@ -0,0 +1,1337 @@
+//! GraphQL-aware proxying of the configured GraphQL endpoints: the operations of their requests are parsed to be
+//! counted per operation name and held to depth and complexity limits, and persisted queries sent with `GET` can be
+//! cached.
+//!
+//! Requests are read as GraphQL over HTTP has them: `GET` with `query`, `operationName`, `variables` and `extensions`
+//! query parameters, `POST` with a JSON body holding the same members, or a batch of them in an array, or `POST` with
+//! an `application/graphql` body holding the document. The depth of an operation is its deepest nesting of fields, and
+//! its complexity the number of fields it selects, where the fields below a field taking a list size argument, such
+//! as `first: 50`, count that many times. Rejected requests are answered with a GraphQL `errors` response.
+//!
+//! Automatic persisted queries send the SHA-256 hash of their document instead of the document once the server knows
+//! it. The documents are remembered by hash when a request carries both, so that a later request with only the hash is
+//! measured with its own variables and held to the limits, and can be cached when it is a query sent with `GET` without
+//! credentials. Other `GET` requests to the endpoints bypass the cache, since their responses depend on the document
+//! rather than the resource.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use futures::StreamExt;
+use hyper::{
+    body::Bytes,
+    header::{AUTHORIZATION, CONTENT_TYPE, COOKIE},
+    http::request,
+    Body, Method, StatusCode,
+};
+use ring::digest;
+use serde::Serialize;
+use serde_json::{json, Value};
+use url::form_urlencoded;
+
+use crate::{canary::hex, policy::CacheOverride};
+
+/// Deepest nesting of selection sets, values and fragments parsed, against documents exhausting the stack.
+const MAX_NESTING: usize = 256;
+/// Name under which the operations beyond the tracked ones are counted.
+const OTHER_OPERATIONS: &str = "other";
+
+/// GraphQL endpoint settings.
+#[derive(Clone, Debug)]
+pub struct GraphQlConfig {
+    /// Paths of the GraphQL endpoints. Defaults to `/graphql`.
+    pub paths: Vec<String>,
+    /// Deepest nesting of fields allowed in an operation (optional). Defaults to 10.
+    pub max_depth: Option<usize>,
+    /// Highest complexity allowed for an operation (optional). Defaults to 1000.
+    pub max_complexity: Option<u64>,
+    /// Arguments giving the size of the list a field returns, which multiplies the complexity of the fields below it.
+    /// Defaults to `first`, `last` and `limit`.
+    pub list_size_arguments: Vec<String>,
+    /// Largest number of operations in a batch. Defaults to 10.
+    pub max_batch_size: usize,
+    /// Whether the responses to persisted queries sent with `GET` without credentials are cached, as long as
+    /// `cache_enabled` is set. Defaults to `false`.
+    pub cache_persisted_queries: bool,
+    /// Number of persisted query hashes remembered. Defaults to 10000.
+    pub max_persisted_queries: usize,
+    /// Largest request body parsed; larger ones are rejected with `413 Payload Too Large`. Defaults to 1 MiB.
+    pub max_body_size: usize,
+    /// Number of operation names counted apart, beyond which operations are counted together under `other`. Defaults
+    /// to 1000.
+    pub max_operations: usize,
+}
+
+impl Default for GraphQlConfig {
+    fn default() -> Self {
+        Self {
+            paths: vec!["/graphql".to_string()],
+            max_depth: Some(10),
+            max_complexity: Some(1000),
+            list_size_arguments: ["first", "last", "limit"].map(String::from).to_vec(),
+            max_batch_size: 10,
+            cache_persisted_queries: false,
+            max_persisted_queries: 10_000,
+            max_body_size: 1024 * 1024,
+            max_operations: 1000,
+        }
+    }
+}
+
+/// Requests for a GraphQL operation name.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GraphQlOperationStats {
+    /// Name of the operation, `anonymous` for unnamed operations.
+    pub name: String,
+    /// Type of the operation: `query`, `mutation`, `subscription`, or `unknown` for persisted queries never seen with
+    /// their document.
+    pub operation_type: &'static str,
+    /// Number of forwarded requests for the operation.
+    pub requests: u64,
+    /// Number of those answered with a server error, or failing without a response.
+    pub errors: u64,
+    /// Number of requests for the operation rejected for exceeding a limit.
+    pub rejected: u64,
+    /// Average duration of the forwarded requests, in milliseconds.
+    pub average_ms: f64,
+    /// Deepest nesting of fields seen for the operation.
+    pub max_depth: usize,
+    /// Highest complexity seen for the operation.
+    pub max_complexity: u64,
+    #[serde(skip)]
+    total_ms: f64,
+}
+
+/// Counters of the GraphQL endpoints.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GraphQlStats {
+    /// Number of requests rejected, whether or not their operation could be told.
+    pub rejected: u64,
+    /// Number of persisted query hashes remembered.
+    pub persisted_queries: usize,
+    /// Requests per operation name, the most requested first.
+    pub operations: Vec<GraphQlOperationStats>,
+}
+
+/// Outcome of checking a request to a GraphQL endpoint.
+#[derive(Debug)]
+pub enum GraphQlVerdict {
+    /// Forward the request with this body, caching its response as `cache` says if set.
+    Forward {
+        body: Body,
+        cache: Option<CacheOverride>,
+    },
+    /// Reject the request with `status` and a GraphQL error with this message and code.
+    Rejected {
+        status: StatusCode,
+        message: String,
+        code: &'static str,
+    },
+    /// Reject the request with `413 Payload Too Large`.
+    TooLarge,
+}
+
+/// Names of the operations of a request to a GraphQL endpoint, filled in once the request is checked so that its
+/// outcome can be counted per operation.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GraphQlOperations(Arc<Mutex<Vec<String>>>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OperationType {
+    Query,
+    Mutation,
+    Subscription,
+}
+
+impl OperationType {
+    fn as_str(self) -> &'static str {
+        match self {
+            OperationType::Query => "query",
+            OperationType::Mutation => "mutation",
+            OperationType::Subscription => "subscription",
+        }
+    }
+}
+
+/// What is known of the operation of a request.
+#[derive(Clone, Debug)]
+struct Analysis {
+    name: Option<String>,
+    /// `None` for persisted queries never seen with their document.
+    operation_type: Option<OperationType>,
+    depth: usize,
+    complexity: u64,
+    /// Whether the request refers to its document by a known persisted query hash.
+    persisted: bool,
+}
+
+/// One GraphQL request, out of a batch or alone.
+struct GraphQlRequest {
+    query: Option<String>,
+    operation_name: Option<String>,
+    variables: Value,
+    hash: Option<String>,
+}
+
+/// A request rejected with `status` and a GraphQL error.
+struct Rejection {
+    status: StatusCode,
+    message: String,
+    code: &'static str,
+    /// Operation name the rejection is counted under, if it could be told.
+    operation: Option<String>,
+}
+
+impl Rejection {
+    fn bad_request(message: impl Into<String>, code: &'static str) -> Self {
+        Rejection {
+            status: StatusCode::BAD_REQUEST,
+            message: message.into(),
+            code,
+            operation: None,
+        }
+    }
+}
+
+/// Parses and limits the requests to the GraphQL endpoints.
+pub struct GraphQl {
+    config: GraphQlConfig,
+    /// Documents of the persisted queries by hash.
+    persisted: Mutex<HashMap<String, String>>,
+    operations: Mutex<HashMap<String, GraphQlOperationStats>>,
+    rejected: Mutex<u64>,
+}
+
+impl GraphQl {
+    /// Creates the GraphQL endpoints with the given settings.
+    pub fn new(config: GraphQlConfig) -> Result<Self> {
+        if config.paths.is_empty() {
+            bail!("GraphQL mode needs at least one endpoint path");
+        }
+        Ok(GraphQl {
+            config,
+            persisted: Mutex::new(HashMap::new()),
+            operations: Mutex::new(HashMap::new()),
+            rejected: Mutex::new(0),
+        })
+    }
+
+    /// Whether `path` is a GraphQL endpoint.
+    pub(crate) fn is_endpoint(&self, path: &str) -> bool {
+        self.config.paths.iter().any(|endpoint| endpoint == path)
+    }
+
+    /// Checks the request with `parts` to a GraphQL endpoint, reading its body if it is sent with `POST`. The names
+    /// of its operations are stored in its [`GraphQlOperations`] extension, if any.
+    pub async fn check(&self, parts: &request::Parts, mut body: Body) -> Result<GraphQlVerdict> {
+        if !self.is_endpoint(parts.uri.path()) {
+            return Ok(GraphQlVerdict::Forward { body, cache: None });
+        }
+        let (requests, body) = if parts.method == Method::GET {
+            let params: HashMap<String, String> =
+                form_urlencoded::parse(parts.uri.query().unwrap_or("").as_bytes())
+                    .into_owned()
+                    .collect();
+            // Without a document nor a hash, the request is for something else, such as an IDE page
+            if !params.contains_key("query") && !params.contains_key("extensions") {
+                return Ok(GraphQlVerdict::Forward {
+                    body,
+                    cache: Some(CacheOverride::Bypass),
+                });
+            }
+            (get_request(&params).map(|request| vec![request]), body)
+        } else if parts.method == Method::POST {
+            let mut buffered = Vec::new();
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk.context("Failed to read request body")?;
+                if buffered.len() + chunk.len() > self.config.max_body_size {
+                    *self.rejected.lock().unwrap() += 1;
+                    return Ok(GraphQlVerdict::TooLarge);
+                }
+                buffered.extend_from_slice(&chunk);
+            }
+            let content_type = parts
+                .headers
+                .get(CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .unwrap_or("application/json");
+            let requests = post_requests(content_type, &buffered, self.config.max_batch_size);
+            (requests, Body::from(Bytes::from(buffered)))
+        } else {
+            return Ok(GraphQlVerdict::Forward { body, cache: None });
+        };
+
+        let analyses = requests.and_then(|requests| {
+            requests
+                .iter()
+                .map(|request| self.analyze(request, &parts.method))
+                .collect::<std::result::Result<Vec<_>, _>>()
+        });
+        let analyses = match analyses {
+            Ok(analyses) => analyses,
+            Err(rejection) => {
+                *self.rejected.lock().unwrap() += 1;
+                if let Some(name) = &rejection.operation {
+                    self.update(name, None, |stats| stats.rejected += 1);
+                }
+                return Ok(GraphQlVerdict::Rejected {
+                    status: rejection.status,
+                    message: rejection.message,
+                    code: rejection.code,
+                });
+            }
+        };
+
+        let names: Vec<String> = analyses
+            .iter()
+            .map(|analysis| {
+                let name = analysis.name.as_deref().unwrap_or("anonymous");
+                self.update(name, analysis.operation_type, |stats| {
+                    stats.max_depth = stats.max_depth.max(analysis.depth);
+                    stats.max_complexity = stats.max_complexity.max(analysis.complexity);
+                })
+            })
+            .collect();
+        if let Some(operations) = parts.extensions.get::<GraphQlOperations>() {
+            *operations.0.lock().unwrap() = names;
+        }
+        let cache = (parts.method == Method::GET).then(|| {
+            let persisted_query = analyses.iter().all(|analysis| {
+                analysis.persisted && analysis.operation_type == Some(OperationType::Query)
+            });
+            // The cache key does not tell clients apart, so the answers to credentialed requests are never shared
+            let credentialed = parts.headers.contains_key(AUTHORIZATION)
+                || parts.headers.contains_key(COOKIE);
+            if persisted_query && !credentialed && self.config.cache_persisted_queries {
+                CacheOverride::Force
+            } else {
+                CacheOverride::Bypass
+            }
+        });
+        Ok(GraphQlVerdict::Forward { body, cache })
+    }
+
+    /// Analyzes one request sent with `method`, holding its operation to the limits.
+    fn analyze(
+        &self,
+        request: &GraphQlRequest,
+        method: &Method,
+    ) -> std::result::Result<Analysis, Rejection> {
+        let analysis = match (&request.query, &request.hash) {
+            (Some(query), hash) => {
+                let mut analysis = self.measure(query, request).map_err(|err| {
+                    Rejection::bad_request(err.to_string(), "GRAPHQL_PARSE_FAILED")
+                })?;
+                if let Some(hash) = hash {
+                    let computed = hex(digest::digest(&digest::SHA256, query.as_bytes()).as_ref());
+                    if !computed.eq_ignore_ascii_case(hash) {
+                        return Err(Rejection::bad_request(
+                            "The persisted query hash does not match the query",
+                            "PERSISTED_QUERY_HASH_MISMATCH",
+                        ));
+                    }
+                    analysis.persisted = true;
+                    let mut persisted = self.persisted.lock().unwrap();
+                    if persisted.len() < self.config.max_persisted_queries
+                        || persisted.contains_key(hash)
+                    {
+                        persisted.insert(hash.to_ascii_lowercase(), query.clone());
+                    }
+                }
+                analysis
+            }
+            (None, Some(hash)) => {
+                let known = self
+                    .persisted
+                    .lock()
+                    .unwrap()
+                    .get(&hash.to_ascii_lowercase())
+                    .cloned();
+                match known {
+                    // The document is measured again, since the list sizes can come from the variables of the request
+                    Some(query) => {
+                        let mut analysis = self.measure(&query, request).map_err(|err| {
+                            Rejection::bad_request(err.to_string(), "GRAPHQL_PARSE_FAILED")
+                        })?;
+                        analysis.persisted = true;
+                        analysis
+                    }
+                    // Unknown hashes are left to the server, which answers that it does not know the query
+                    None => Analysis {
+                        name: request.operation_name.clone(),
+                        operation_type: None,
+                        depth: 0,
+                        complexity: 0,
+                        persisted: false,
+                    },
+                }
+            }
+            (None, None) => {
+                return Err(Rejection::bad_request(
+                    "The request has no query",
+                    "BAD_REQUEST",
+                ))
+            }
+        };
+
+        let name = analysis.name.as_deref().unwrap_or("anonymous");
+        let reject = |status, message: String, code| Rejection {
+            status,
+            message,
+            code,
+            operation: Some(name.to_string()),
+        };
+        if *method == Method::GET && analysis.operation_type == Some(OperationType::Mutation) {
+            return Err(reject(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "Mutations cannot be sent with GET".to_string(),
+                "METHOD_NOT_ALLOWED",
+            ));
+        }
+        if let Some(max_depth) = self.config.max_depth.filter(|max| analysis.depth > *max) {
+            return Err(reject(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Operation {} has a depth of {}, beyond the limit of {}",
+                    name, analysis.depth, max_depth
+                ),
+                "QUERY_TOO_DEEP",
+            ));
+        }
+        if let Some(max_complexity) = self
+            .config
+            .max_complexity
+            .filter(|max| analysis.complexity > *max)
+        {
+            return Err(reject(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Operation {} has a complexity of {}, beyond the limit of {}",
+                    name, analysis.complexity, max_complexity
+                ),
+                "QUERY_TOO_COMPLEX",
+            ));
+        }
+        Ok(analysis)
+    }
+
+    /// Parses the document `query` of `request` and measures the operation it selects.
+    fn measure(&self, query: &str, request: &GraphQlRequest) -> Result<Analysis> {
+        let document = Parser::new(query)?.document()?;
+        let operation = match &request.operation_name {
+            Some(name) => document
+                .operations
+                .iter()
+                .find(|operation| operation.name == Some(name.as_str()))
+                .context(format!("Unknown operation {}", name))?,
+            None if document.operations.len() == 1 => &document.operations[0],
+            None => bail!("operationName is required for documents with several operations"),
+        };
+        let mut measure = Measure {
+            document: &document,
+            list_size_arguments: &self.config.list_size_arguments,
+            variables: &request.variables,
+            fragments: HashMap::new(),
+            visiting: Vec::new(),
+        };
+        let (depth, complexity) = measure.selections(&operation.selections)?;
+        Ok(Analysis {
+            name: operation.name.map(str::to_string),
+            operation_type: Some(operation.operation_type),
+            depth,
+            complexity,
+            persisted: false,
+        })
+    }
+
+    /// Applies `update` to the counters of the operation `name`, returning the name it is counted under.
+    fn update(
+        &self,
+        name: &str,
+        operation_type: Option<OperationType>,
+        update: impl FnOnce(&mut GraphQlOperationStats),
+    ) -> String {
+        let mut operations = self.operations.lock().unwrap();
+        let name = if operations.contains_key(name) || operations.len() < self.config.max_operations
+        {
+            name
+        } else {
+            OTHER_OPERATIONS
+        };
+        let stats = operations
+            .entry(name.to_string())
+            .or_insert_with(|| GraphQlOperationStats {
+                name: name.to_string(),
+                operation_type: "unknown",
+                ..Default::default()
+            });
+        if let Some(operation_type) = operation_type.filter(|_| name != OTHER_OPERATIONS) {
+            stats.operation_type = operation_type.as_str();
+        }
+        update(stats);
+        name.to_string()
+    }
+
+    /// Counts the forwarded request whose operations are `operations` that took `duration`.
+    pub(crate) fn record(&self, operations: &GraphQlOperations, duration: Duration, success: bool) {
+        for name in operations.0.lock().unwrap().iter() {
+            self.update(name, None, |stats| {
+                stats.requests += 1;
+                if !success {
+                    stats.errors += 1;
+                }
+                stats.total_ms += duration.as_secs_f64() * 1000.0;
+                stats.average_ms = stats.total_ms / stats.requests as f64;
+            });
+        }
+    }
+
+    /// Returns the counters of the GraphQL endpoints.
+    pub fn stats(&self) -> GraphQlStats {
+        let mut operations: Vec<GraphQlOperationStats> =
+            self.operations.lock().unwrap().values().cloned().collect();
+        operations.sort_by(|a, b| b.requests.cmp(&a.requests).then(a.name.cmp(&b.name)));
+        GraphQlStats {
+            rejected: *self.rejected.lock().unwrap(),
+            persisted_queries: self.persisted.lock().unwrap().len(),
+            operations,
+        }
+    }
+}
+
+/// Returns the body of a GraphQL response holding one error with `message` and `code`.
+pub(crate) fn errors_body(message: &str, code: &str) -> String {
+    json!({ "errors": [{ "message": message, "extensions": { "code": code } }] }).to_string()
+}
+
+/// Reads the request sent with `GET` from its query parameters.
+fn get_request(params: &HashMap<String, String>) -> std::result::Result<GraphQlRequest, Rejection> {
+    let parse = |name: &str| match params.get(name) {
+        Some(text) => serde_json::from_str::<Value>(text).map_err(|err| {
+            Rejection::bad_request(
+                format!("The {} parameter is not valid JSON: {}", name, err),
+                "BAD_REQUEST",
+            )
+        }),
+        None => Ok(Value::Null),
+    };
+    Ok(GraphQlRequest {
+        query: params.get("query").cloned(),
+        operation_name: operation_name(params.get("operationName").map(String::as_str))?,
+        variables: parse("variables")?,
+        hash: persisted_hash(&parse("extensions")?),
+    })
+}
+
+/// Reads the requests sent with `POST` in a body of `content_type`.
+fn post_requests(
+    content_type: &str,
+    body: &[u8],
+    max_batch_size: usize,
+) -> std::result::Result<Vec<GraphQlRequest>, Rejection> {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    if essence.eq_ignore_ascii_case("application/graphql") {
+        let query = String::from_utf8(body.to_vec())
+            .map_err(|_| Rejection::bad_request("The document is not UTF-8", "BAD_REQUEST"))?;
+        return Ok(vec![GraphQlRequest {
+            query: Some(query),
+            operation_name: None,
+            variables: Value::Null,
+            hash: None,
+        }]);
+    }
+    if !essence.eq_ignore_ascii_case("application/json") && !essence.ends_with("+json") {
+        return Err(Rejection {
+            status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            message: format!("GraphQL requests cannot be sent as {}", essence),
+            code: "BAD_REQUEST",
+            operation: None,
+        });
+    }
+    let document: Value = serde_json::from_slice(body).map_err(|err| {
+        Rejection::bad_request(
+            format!("The body is not valid JSON: {}", err),
+            "BAD_REQUEST",
+        )
+    })?;
+    let items = match document {
+        Value::Array(items) if items.is_empty() => {
+            return Err(Rejection::bad_request("The batch is empty", "BAD_REQUEST"))
+        }
+        Value::Array(items) if items.len() > max_batch_size => {
+            return Err(Rejection::bad_request(
+                format!(
+                    "The batch has {} operations, beyond the limit of {}",
+                    items.len(),
+                    max_batch_size
+                ),
+                "BATCH_TOO_LARGE",
+            ))
+        }
+        Value::Array(items) => items,
+        item => vec![item],
+    };
+    items
+        .iter()
+        .map(|item| {
+            if !item.is_object() {
+                return Err(Rejection::bad_request(
+                    "A GraphQL request must be a JSON object",
+                    "BAD_REQUEST",
+                ));
+            }
+            Ok(GraphQlRequest {
+                query: item
+                    .get("query")
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+                operation_name: operation_name(item.get("operationName").and_then(Value::as_str))?,
+                variables: item.get("variables").cloned().unwrap_or(Value::Null),
+                hash: item.get("extensions").and_then(persisted_hash),
+            })
+        })
+        .collect()
+}
+
+/// Checks the operation name `name` of a request, which must be a GraphQL name since it is counted and shown as one.
+fn operation_name(name: Option<&str>) -> std::result::Result<Option<String>, Rejection> {
+    match name {
+        Some(name) if !is_name(name) => Err(Rejection::bad_request(
+            "operationName is not a valid GraphQL name",
+            "BAD_REQUEST",
+        )),
+        name => Ok(name.map(str::to_string)),
+    }
+}
+
+/// Whether `text` is a GraphQL name, `[_A-Za-z][_0-9A-Za-z]*`.
+fn is_name(text: &str) -> bool {
+    let mut bytes = text.bytes();
+    matches!(bytes.next(), Some(b'a'..=b'z' | b'A'..=b'Z' | b'_'))
+        && bytes.all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+}
+
+/// Returns the persisted query hash of the `extensions` of a request, if any.
+fn persisted_hash(extensions: &Value) -> Option<String> {
+    extensions
+        .pointer("/persistedQuery/sha256Hash")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token<'a> {
+    Punctuator(u8),
+    Spread,
+    Name(&'a str),
+    Int(i64),
+    Float,
+    String,
+}
+
+/// Argument values, as far as list sizes need them.
+enum Argument<'a> {
+    Int(i64),
+    Variable(&'a str),
+    Other,
+}
+
+enum Selection<'a> {
+    Field {
+        arguments: Vec<(&'a str, Argument<'a>)>,
+        selections: Vec<Selection<'a>>,
+    },
+    FragmentSpread(&'a str),
+    InlineFragment(Vec<Selection<'a>>),
+}
+
+struct OperationDefinition<'a> {
+    operation_type: OperationType,
+    name: Option<&'a str>,
+    selections: Vec<Selection<'a>>,
+}
+
+struct Document<'a> {
+    operations: Vec<OperationDefinition<'a>>,
+    fragments: HashMap<&'a str, Vec<Selection<'a>>>,
+}
+
+/// Splits `source` into tokens, skipping whitespace, commas and comments.
+fn tokenize(source: &str) -> Result<Vec<Token<'_>>> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        match byte {
+            b' ' | b'\t' | b'\n' | b'\r' | b',' => i += 1,
+            b'#' => {
+                while i < bytes.len() && bytes[i] != b'\n' && bytes[i] != b'\r' {
+                    i += 1;
+                }
+            }
+            b'!' | b'$' | b'&' | b'(' | b')' | b':' | b'=' | b'@' | b'[' | b']' | b'{' | b'|'
+            | b'}' => {
+                tokens.push(Token::Punctuator(byte));
+                i += 1;
+            }
+            b'.' if bytes[i..].starts_with(b"...") => {
+                tokens.push(Token::Spread);
+                i += 3;
+            }
+            b'"' if bytes[i..].starts_with(b"\"\"\"") => {
+                i += 3;
+                loop {
+                    if i >= bytes.len() {
+                        bail!("Syntax error: unterminated block string");
+                    } else if bytes[i..].starts_with(b"\\\"\"\"") {
+                        i += 4;
+                    } else if bytes[i..].starts_with(b"\"\"\"") {
+                        i += 3;
+                        break;
+                    } else {
+                        i += 1;
+                    }
+                }
+                tokens.push(Token::String);
+            }
+            b'"' => {
+                i += 1;
+                loop {
+                    match bytes.get(i) {
+                        None | Some(b'\n') | Some(b'\r') => {
+                            bail!("Syntax error: unterminated string")
+                        }
+                        Some(b'\\') => i += 2,
+                        Some(b'"') => break,
+                        Some(_) => i += 1,
+                    }
+                }
+                i += 1;
+                tokens.push(Token::String);
+            }
+            b'-' | b'0'..=b'9' => {
+                let start = i;
+                i += 1;
+                let mut float = false;
+                while i < bytes.len() {
+                    match bytes[i] {
+                        b'0'..=b'9' => i += 1,
+                        b'.' | b'e' | b'E' => {
+                            float = true;
+                            i += 1;
+                        }
+                        b'+' | b'-' if matches!(bytes[i - 1], b'e' | b'E') => i += 1,
+                        _ => break,
+                    }
+                }
+                if float {
+                    tokens.push(Token::Float);
+                } else {
+                    let value = source[start..i].parse().map_err(|_| {
+                        anyhow::anyhow!("Syntax error: invalid number {}", &source[start..i])
+                    })?;
+                    tokens.push(Token::Int(value));
+                }
+            }
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
+                let start = i;
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                tokens.push(Token::Name(&source[start..i]));
+            }
+            _ if source[i..].starts_with('\u{feff}') => i += '\u{feff}'.len_utf8(),
+            _ => bail!(
+                "Syntax error: unexpected character {:?}",
+                source[i..].chars().next().unwrap_or_default()
+            ),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parser of executable GraphQL documents, keeping only what the limits need.
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    position: usize,
+    nesting: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Result<Self> {
+        Ok(Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+            nesting: 0,
+        })
+    }
+
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Result<Token<'a>> {
+        let token = self
+            .peek()
+            .context("Syntax error: unexpected end of document")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    /// Consumes the punctuator `punctuator` if it is next.
+    fn eat(&mut self, punctuator: u8) -> bool {
+        let next = self.peek() == Some(Token::Punctuator(punctuator));
+        if next {
+            self.position += 1;
+        }
+        next
+    }
+
+    fn expect(&mut self, punctuator: u8) -> Result<()> {
+        if !self.eat(punctuator) {
+            bail!(
+                "Syntax error: expected {:?}, found {}",
+                punctuator as char,
+                self.describe_next()
+            );
+        }
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<&'a str> {
+        match self.peek() {
+            Some(Token::Name(name)) => {
+                self.position += 1;
+                Ok(name)
+            }
+            _ => bail!(
+                "Syntax error: expected a name, found {}",
+                self.describe_next()
+            ),
+        }
+    }
+
+    fn describe_next(&self) -> String {
+        match self.peek() {
+            Some(Token::Punctuator(punctuator)) => format!("{:?}", punctuator as char),
+            Some(Token::Spread) => "\"...\"".to_string(),
+            Some(Token::Name(name)) => format!("{:?}", name),
+            Some(Token::Int(_)) | Some(Token::Float) => "a number".to_string(),
+            Some(Token::String) => "a string".to_string(),
+            None => "the end of the document".to_string(),
+        }
+    }
+
+    /// Runs `parse` one nesting level deeper.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.nesting >= MAX_NESTING {
+            bail!("The document is nested too deeply");
+        }
+        self.nesting += 1;
+        let parsed = parse(self);
+        self.nesting -= 1;
+        parsed
+    }
+
+    fn document(mut self) -> Result<Document<'a>> {
+        let mut document = Document {
+            operations: Vec::new(),
+            fragments: HashMap::new(),
+        };
+        while let Some(token) = self.peek() {
+            let operation_type = match token {
+                Token::Punctuator(b'{') => {
+                    let selections = self.selection_set()?;
+                    document.operations.push(OperationDefinition {
+                        operation_type: OperationType::Query,
+                        name: None,
+                        selections,
+                    });
+                    continue;
+                }
+                Token::Name("query") => OperationType::Query,
+                Token::Name("mutation") => OperationType::Mutation,
+                Token::Name("subscription") => OperationType::Subscription,
+                Token::Name("fragment") => {
+                    self.position += 1;
+                    let name = self.name()?;
+                    if self.name()? != "on" {
+                        bail!("Syntax error: expected \"on\" in fragment {}", name);
+                    }
+                    self.name()?;
+                    self.directives()?;
+                    let selections = self.selection_set()?;
+                    if document.fragments.insert(name, selections).is_some() {
+                        bail!("Fragment {} is defined twice", name);
+                    }
+                    continue;
+                }
+                _ => bail!(
+                    "Syntax error: expected an operation or a fragment, found {}",
+                    self.describe_next()
+                ),
+            };
+            self.position += 1;
+            let name = match self.peek() {
+                Some(Token::Name(name)) => {
+                    self.position += 1;
+                    Some(name)
+                }
+                _ => None,
+            };
+            if self.eat(b'(') {
+                while !self.eat(b')') {
+                    self.expect(b'$')?;
+                    self.name()?;
+                    self.expect(b':')?;
+                    self.type_reference()?;
+                    if self.eat(b'=') {
+                        self.value()?;
+                    }
+                    self.directives()?;
+                }
+            }
+            self.directives()?;
+            let selections = self.selection_set()?;
+            document.operations.push(OperationDefinition {
+                operation_type,
+                name,
+                selections,
+            });
+        }
+        if document.operations.is_empty() {
+            bail!("The document has no operation");
+        }
+        Ok(document)
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Selection<'a>>> {
+        self.expect(b'{')?;
+        self.nested(|parser| {
+            let mut selections = Vec::new();
+            while !parser.eat(b'}') {
+                selections.push(parser.selection()?);
+            }
+            if selections.is_empty() {
+                bail!("Syntax error: empty selection set");
+            }
+            Ok(selections)
+        })
+    }
+
+    fn selection(&mut self) -> Result<Selection<'a>> {
+        if self.peek() == Some(Token::Spread) {
+            self.position += 1;
+            return match self.peek() {
+                Some(Token::Name("on")) => {
+                    self.position += 1;
+                    self.name()?;
+                    self.directives()?;
+                    Ok(Selection::InlineFragment(self.selection_set()?))
+                }
+                Some(Token::Name(name)) => {
+                    self.position += 1;
+                    self.directives()?;
+                    Ok(Selection::FragmentSpread(name))
+                }
+                _ => {
+                    self.directives()?;
+                    Ok(Selection::InlineFragment(self.selection_set()?))
+                }
+            };
+        }
+        self.name()?;
+        if self.eat(b':') {
+            self.name()?;
+        }
+        let arguments = self.arguments()?;
+        self.directives()?;
+        let selections = if self.peek() == Some(Token::Punctuator(b'{')) {
+            self.selection_set()?
+        } else {
+            Vec::new()
+        };
+        Ok(Selection::Field {
+            arguments,
+            selections,
+        })
+    }
+
+    fn arguments(&mut self) -> Result<Vec<(&'a str, Argument<'a>)>> {
+        let mut arguments = Vec::new();
+        if self.eat(b'(') {
+            while !self.eat(b')') {
+                let name = self.name()?;
+                self.expect(b':')?;
+                arguments.push((name, self.value()?));
+            }
+        }
+        Ok(arguments)
+    }
+
+    fn directives(&mut self) -> Result<()> {
+        while self.eat(b'@') {
+            self.name()?;
+            self.arguments()?;
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Argument<'a>> {
+        match self.next()? {
+            Token::Punctuator(b'$') => Ok(Argument::Variable(self.name()?)),
+            Token::Int(value) => Ok(Argument::Int(value)),
+            Token::Float | Token::String | Token::Name(_) => Ok(Argument::Other),
+            Token::Punctuator(b'[') => self.nested(|parser| {
+                while !parser.eat(b']') {
+                    parser.value()?;
+                }
+                Ok(Argument::Other)
+            }),
+            Token::Punctuator(b'{') => self.nested(|parser| {
+                while !parser.eat(b'}') {
+                    parser.name()?;
+                    parser.expect(b':')?;
+                    parser.value()?;
+                }
+                Ok(Argument::Other)
+            }),
+            _ => {
+                self.position -= 1;
+                bail!(
+                    "Syntax error: expected a value, found {}",
+                    self.describe_next()
+                )
+            }
+        }
+    }
+
+    fn type_reference(&mut self) -> Result<()> {
+        if self.eat(b'[') {
+            self.nested(|parser| parser.type_reference())?;
+            self.expect(b']')?;
+        } else {
+            self.name()?;
+        }
+        self.eat(b'!');
+        Ok(())
+    }
+}
+
+/// Measures the depth and complexity of selection sets, expanding fragments.
+struct Measure<'d, 'a> {
+    document: &'d Document<'a>,
+    list_size_arguments: &'d [String],
+    variables: &'d Value,
+    /// Depth and complexity of the fragments already measured.
+    fragments: HashMap<&'a str, (usize, u64)>,
+    /// Fragments being expanded, against fragment cycles.
+    visiting: Vec<&'a str>,
+}
+
+impl<'a> Measure<'_, 'a> {
+    fn selections(&mut self, selections: &[Selection<'a>]) -> Result<(usize, u64)> {
+        let mut depth = 0;
+        let mut complexity: u64 = 0;
+        for selection in selections {
+            let (selection_depth, selection_complexity) = match selection {
+                Selection::Field {
+                    arguments,
+                    selections,
+                } => {
+                    let (below_depth, below_complexity) = self.selections(selections)?;
+                    let size = self.list_size(arguments);
+                    (
+                        below_depth + 1,
+                        size.saturating_mul(below_complexity).saturating_add(1),
+                    )
+                }
+                Selection::InlineFragment(selections) => self.selections(selections)?,
+                Selection::FragmentSpread(name) => self.fragment(name)?,
+            };
+            depth = depth.max(selection_depth);
+            complexity = complexity.saturating_add(selection_complexity);
+        }
+        Ok((depth, complexity))
+    }
+
+    fn fragment(&mut self, name: &'a str) -> Result<(usize, u64)> {
+        if let Some(measured) = self.fragments.get(name) {
+            return Ok(*measured);
+        }
+        if self.visiting.contains(&name) {
+            bail!("Fragment {} spreads itself", name);
+        }
+        if self.visiting.len() >= MAX_NESTING {
+            bail!("The document is nested too deeply");
+        }
+        let document = self.document;
+        let selections = document
+            .fragments
+            .get(name)
+            .context(format!("Unknown fragment {}", name))?;
+        self.visiting.push(name);
+        let measured = self.selections(selections)?;
+        self.visiting.pop();
+        self.fragments.insert(name, measured);
+        Ok(measured)
+    }
+
+    /// Returns the size of the list a field with `arguments` returns, 1 unless one of them gives it.
+    fn list_size(&self, arguments: &[(&str, Argument)]) -> u64 {
+        arguments
+            .iter()
+            .filter(|(name, _)| self.list_size_arguments.iter().any(|size| size == name))
+            .find_map(|(_, value)| match value {
+                Argument::Int(size) => Some((*size).max(0) as u64),
+                Argument::Variable(variable) => self.variables.get(variable)?.as_u64(),
+                Argument::Other => None,
+            })
+            .unwrap_or(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Request;
+    use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+    use super::*;
+
+    /// Returns the request parts of a `method` request to `uri`.
+    fn parts(method: Method, uri: &str) -> request::Parts {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .body(())
+            .unwrap();
+        request.into_parts().0
+    }
+
+    /// Checks a `POST` to the endpoint with the JSON body `body`, returning the rejection code if it is rejected.
+    async fn post(graphql: &GraphQl, body: Value) -> Option<&'static str> {
+        let verdict = graphql
+            .check(
+                &parts(Method::POST, "/graphql"),
+                Body::from(body.to_string()),
+            )
+            .await
+            .unwrap();
+        match verdict {
+            GraphQlVerdict::Forward { .. } => None,
+            GraphQlVerdict::Rejected { code, .. } => Some(code),
+            GraphQlVerdict::TooLarge => Some("TOO_LARGE"),
+        }
+    }
+
+    /// Returns the depth and complexity of the only operation of `query`.
+    fn measure(query: &str) -> Result<(usize, u64)> {
+        let graphql = GraphQl::new(GraphQlConfig::default()).unwrap();
+        let request = GraphQlRequest {
+            query: Some(query.to_string()),
+            operation_name: None,
+            variables: json!({ "count": 20 }),
+            hash: None,
+        };
+        let analysis = graphql.measure(query, &request)?;
+        Ok((analysis.depth, analysis.complexity))
+    }
+
+    #[test]
+    fn measures_depth_and_complexity() {
+        assert_eq!(measure("{ a }").unwrap(), (1, 1));
+        assert_eq!(measure("query Q { a { b { c } } d }").unwrap(), (3, 4));
+        // The fields below a list count as many times as its size
+        assert_eq!(
+            measure("{ users(first: 10) { name id } }").unwrap(),
+            (2, 21)
+        );
+        assert_eq!(
+            measure("query Q($count: Int) { users(last: $count) { name } }").unwrap(),
+            (2, 21)
+        );
+        assert_eq!(
+            measure("{ ...F } fragment F on Query { a { b } }").unwrap(),
+            (2, 2)
+        );
+        assert_eq!(measure("{ ... on Query { a } }").unwrap(), (1, 1));
+        // Comments, commas, strings and block strings are skipped
+        assert_eq!(
+            measure("# comment\n{ a(x: \"}\", y: \"\"\"{ b }\"\"\"), b }").unwrap(),
+            (1, 2)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_documents() {
+        for query in [
+            "",
+            "{",
+            "{ a(x: ) }",
+            "{ a } }",
+            "{ a(x: \"unterminated) }",
+            "{ ...Missing }",
+            "{ ...F } fragment F on Query { ...F }",
+            "{ a \u{1} }",
+            "{ a(x: 99999999999999999999) }",
+        ] {
+            assert!(measure(query).is_err(), "{:?}", query);
+        }
+        let nested = format!("{}{}", "{ a ".repeat(1000), "}".repeat(1000));
+        assert!(measure(&nested).is_err());
+    }
+
+    #[test]
+    fn checks_operation_names() {
+        assert!(is_name("_Query1"));
+        assert!(!is_name(""));
+        assert!(!is_name("1Query"));
+        assert!(!is_name("<img src=x onerror=alert(1)>"));
+        assert!(!is_name("Query\t"));
+    }
+
+    #[tokio::test]
+    async fn rejects_operation_names_that_are_not_names() {
+        let graphql = GraphQl::new(GraphQlConfig::default()).unwrap();
+        let body = json!({
+            "operationName": "<img src=x onerror=alert(1)>",
+            "extensions": { "persistedQuery": { "version": 1, "sha256Hash": "00" } },
+        });
+        assert_eq!(post(&graphql, body).await, Some("BAD_REQUEST"));
+        assert!(graphql.stats().operations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn enforces_limits() {
+        let config = GraphQlConfig {
+            max_depth: Some(2),
+            max_complexity: Some(10),
+            max_batch_size: 2,
+            ..GraphQlConfig::default()
+        };
+        let graphql = GraphQl::new(config).unwrap();
+        assert_eq!(
+            post(&graphql, json!({ "query": "{ a { b } }" })).await,
+            None
+        );
+        assert_eq!(
+            post(&graphql, json!({ "query": "query Deep { a { b { c } } }" })).await,
+            Some("QUERY_TOO_DEEP")
+        );
+        assert_eq!(
+            post(&graphql, json!({ "query": "{ a(first: 50) { b } }" })).await,
+            Some("QUERY_TOO_COMPLEX")
+        );
+        let batch = json!([{ "query": "{ a }" }, { "query": "{ b }" }, { "query": "{ c }" }]);
+        assert_eq!(post(&graphql, batch).await, Some("BATCH_TOO_LARGE"));
+        let several = json!({ "query": "query A { a } query B { b }" });
+        assert_eq!(post(&graphql, several).await, Some("GRAPHQL_PARSE_FAILED"));
+        let stats = graphql.stats();
+        assert_eq!(stats.rejected, 4);
+        let deep = stats
+            .operations
+            .iter()
+            .find(|operation| operation.name == "Deep");
+        assert_eq!(deep.map(|operation| operation.rejected), Some(1));
+    }
+
+    #[tokio::test]
+    async fn rejects_mutations_sent_with_get() {
+        let graphql = GraphQl::new(GraphQlConfig::default()).unwrap();
+        let uri = "/graphql?query=mutation%20M%20%7B%20a%20%7D";
+        let verdict = graphql
+            .check(&parts(Method::GET, uri), Body::empty())
+            .await
+            .unwrap();
+        assert!(matches!(
+            verdict,
+            GraphQlVerdict::Rejected {
+                status: StatusCode::METHOD_NOT_ALLOWED,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn remembers_persisted_queries() {
+        let config = GraphQlConfig {
+            cache_persisted_queries: true,
+            ..GraphQlConfig::default()
+        };
+        let graphql = GraphQl::new(config).unwrap();
+        let query = "query Q { a }";
+        let hash = hex(digest::digest(&digest::SHA256, query.as_bytes()).as_ref());
+        let extensions = json!({ "persistedQuery": { "version": 1, "sha256Hash": hash } });
+        let wrong = json!({ "persistedQuery": { "version": 1, "sha256Hash": "00" } });
+        assert_eq!(
+            post(&graphql, json!({ "query": query, "extensions": wrong })).await,
+            Some("PERSISTED_QUERY_HASH_MISMATCH")
+        );
+        assert_eq!(
+            post(
+                &graphql,
+                json!({ "query": query, "extensions": extensions })
+            )
+            .await,
+            None
+        );
+        assert_eq!(graphql.stats().persisted_queries, 1);
+        let uri = format!(
+            "/graphql?extensions={}",
+            utf8_percent_encode(&extensions.to_string(), NON_ALPHANUMERIC)
+        );
+        let verdict = graphql
+            .check(&parts(Method::GET, &uri), Body::empty())
+            .await
+            .unwrap();
+        assert!(matches!(
+            verdict,
+            GraphQlVerdict::Forward {
+                cache: Some(CacheOverride::Force),
+                ..
+            }
+        ));
+        let mut credentialed = parts(Method::GET, &uri);
+        credentialed
+            .headers
+            .insert(AUTHORIZATION, "Bearer token".parse().unwrap());
+        let verdict = graphql.check(&credentialed, Body::empty()).await.unwrap();
+        assert!(matches!(
+            verdict,
+            GraphQlVerdict::Forward {
+                cache: Some(CacheOverride::Bypass),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn measures_persisted_queries_with_their_variables() {
+        let graphql = GraphQl::new(GraphQlConfig::default()).unwrap();
+        let query = "query Q($n: Int) { items(first: $n) { a } }";
+        let hash = hex(digest::digest(&digest::SHA256, query.as_bytes()).as_ref());
+        let extensions = json!({ "persistedQuery": { "version": 1, "sha256Hash": hash } });
+        assert_eq!(
+            post(
+                &graphql,
+                json!({ "query": query, "variables": { "n": 1 }, "extensions": extensions })
+            )
+            .await,
+            None
+        );
+        assert_eq!(
+            post(
+                &graphql,
+                json!({ "variables": { "n": 5000 }, "extensions": extensions })
+            )
+            .await,
+            Some("QUERY_TOO_COMPLEX")
+        );
+    }
+}
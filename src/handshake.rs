@@ -0,0 +1,181 @@
+//! Metrics of the TLS handshakes of clients: their durations, why they failed, and how many resumed a session.
+//!
+//! The ClientHello of every connection is checked before rustls processes it, so garbage TLS traffic is dropped
+//! cheaply: connections whose ClientHello is too large, malformed or late are closed and counted as rejections.
+
+use std::{collections::HashMap, fmt, io, time::Duration};
+
+use rustls::{AlertDescription, Error as TlsError};
+use serde::Serialize;
+
+use crate::{timing::TimingHistogram, tls_hello::MAX_CLIENT_HELLO_LEN};
+
+/// Why a TLS handshake with a client failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HandshakeFailure {
+    /// The client shares no cipher suite, key exchange group, signature scheme or protocol version with the proxy.
+    NoSharedCipher,
+    /// The client rejected the certificate of the proxy, or presented an invalid one.
+    BadCertificate,
+    /// The client closed the connection before the end of the handshake.
+    ClientClosed,
+    /// The handshake took longer than allowed.
+    Timeout,
+    /// Any other failure, such as a malformed message.
+    Other,
+}
+
+impl HandshakeFailure {
+    /// Classifies the error returned by a failed handshake.
+    pub fn of(error: &io::Error) -> Self {
+        if let Some(error) = error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<TlsError>())
+        {
+            return match error {
+                TlsError::PeerIncompatible(_) => HandshakeFailure::NoSharedCipher,
+                TlsError::InvalidCertificate(_) => HandshakeFailure::BadCertificate,
+                TlsError::AlertReceived(alert) => match alert {
+                    AlertDescription::HandshakeFailure
+                    | AlertDescription::InsufficientSecurity
+                    | AlertDescription::ProtocolVersion => HandshakeFailure::NoSharedCipher,
+                    AlertDescription::BadCertificate
+                    | AlertDescription::UnsupportedCertificate
+                    | AlertDescription::CertificateRevoked
+                    | AlertDescription::CertificateExpired
+                    | AlertDescription::CertificateUnknown
+                    | AlertDescription::UnknownCA => HandshakeFailure::BadCertificate,
+                    AlertDescription::CloseNotify | AlertDescription::UserCanceled => {
+                        HandshakeFailure::ClientClosed
+                    }
+                    _ => HandshakeFailure::Other,
+                },
+                _ => HandshakeFailure::Other,
+            };
+        }
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => HandshakeFailure::ClientClosed,
+            io::ErrorKind::TimedOut => HandshakeFailure::Timeout,
+            _ => HandshakeFailure::Other,
+        }
+    }
+
+    /// Returns the name of the failure in the metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HandshakeFailure::NoSharedCipher => "no_shared_cipher",
+            HandshakeFailure::BadCertificate => "bad_certificate",
+            HandshakeFailure::ClientClosed => "client_closed",
+            HandshakeFailure::Timeout => "timeout",
+            HandshakeFailure::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for HandshakeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Checks of the handshakes of HTTPS clients, made on their ClientHello before the handshake.
+#[derive(Clone, Debug)]
+pub struct HandshakeLimits {
+    /// Largest ClientHello accepted, in bytes, at most 16 KiB. Defaults to 16 KiB.
+    pub max_client_hello_len: usize,
+    /// Time allowed for the ClientHello to arrive. Defaults to 10 seconds.
+    pub client_hello_timeout: Duration,
+    /// Time allowed for the rest of the handshake once the ClientHello arrived. Defaults to 10 seconds.
+    pub handshake_timeout: Duration,
+}
+
+impl Default for HandshakeLimits {
+    fn default() -> Self {
+        Self {
+            max_client_hello_len: MAX_CLIENT_HELLO_LEN,
+            client_hello_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Why the ClientHello of a connection was rejected before the handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ClientHelloRejection {
+    /// The ClientHello is larger than allowed.
+    Oversized,
+    /// The bytes are not a valid ClientHello, such as plain HTTP or random data.
+    Malformed,
+    /// The ClientHello did not arrive in time.
+    Timeout,
+    /// The client closed the connection before sending a complete ClientHello.
+    Closed,
+}
+
+impl ClientHelloRejection {
+    /// Returns the name of the rejection in the metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientHelloRejection::Oversized => "oversized",
+            ClientHelloRejection::Malformed => "malformed",
+            ClientHelloRejection::Timeout => "timeout",
+            ClientHelloRejection::Closed => "closed",
+        }
+    }
+}
+
+impl fmt::Display for ClientHelloRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Counts and durations of the TLS handshakes of clients.
+///
+/// Resumptions are only detected for TLS 1.3, rustls not telling whether a TLS 1.2 handshake resumed a session.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct HandshakeStats {
+    /// Durations of the completed handshakes.
+    pub durations: TimingHistogram,
+    /// Number of completed handshakes that resumed a session.
+    pub resumptions: u64,
+    /// A hashmap of failure counts, with the keys representing the reasons of the failures.
+    pub failures: HashMap<String, u64>,
+    /// A hashmap of counts of connections closed before the handshake, with the keys representing why their
+    /// ClientHello was rejected.
+    pub rejections: HashMap<String, u64>,
+}
+
+impl HandshakeStats {
+    /// Records a completed handshake, which took `duration` and resumed a session if `resumed`.
+    pub fn record(&mut self, duration: Duration, resumed: bool) {
+        self.durations.record(duration);
+        if resumed {
+            self.resumptions += 1;
+        }
+    }
+
+    /// Records a failed handshake.
+    pub fn record_failure(&mut self, failure: HandshakeFailure) {
+        *self.failures.entry(failure.name().to_string()).or_insert(0) += 1;
+    }
+
+    /// Records a connection closed before the handshake for its ClientHello.
+    pub fn record_rejection(&mut self, rejection: ClientHelloRejection) {
+        *self
+            .rejections
+            .entry(rejection.name().to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Returns the share of the completed handshakes that resumed a session, between 0 and 1.
+    pub fn resumption_rate(&self) -> f64 {
+        if self.durations.count == 0 {
+            return 0.0;
+        }
+        self.resumptions as f64 / self.durations.count as f64
+    }
+}
@@ -0,0 +1,59 @@
+//! Casing of the header names of requests forwarded to legacy upstreams that are sensitive to it.
+//!
+//! Hyper lowercases header names by default. Upstreams can instead be sent the names as the client wrote them, or
+//! in title case. Headers keep the order in which the client sent them either way, except that repeated headers are
+//! grouped under their first occurrence.
+
+use hyper::client;
+
+use crate::tunnel;
+
+/// Casing of the header names sent to an upstream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HeaderCase {
+    /// Names are lowercased, such as `content-type`.
+    #[default]
+    Lowercase,
+    /// Names are sent as the client wrote them; headers added by the proxy are sent in title case.
+    Preserve,
+    /// Names are sent in title case, such as `Content-Type`.
+    TitleCase,
+}
+
+impl HeaderCase {
+    /// Applies the casing to a pooled client.
+    pub(crate) fn configure(self, builder: &mut client::Builder) {
+        builder
+            .http1_preserve_header_case(self == HeaderCase::Preserve)
+            .http1_title_case_headers(self != HeaderCase::Lowercase);
+    }
+
+    /// Applies the casing to a single client connection.
+    pub(crate) fn configure_conn(self, builder: &mut client::conn::Builder) {
+        builder
+            .http1_preserve_header_case(self == HeaderCase::Preserve)
+            .http1_title_case_headers(self != HeaderCase::Lowercase);
+    }
+}
+
+/// Casing of the header names sent to the upstreams of a host.
+#[derive(Clone, Debug, Default)]
+pub struct HeaderCaseRule {
+    /// Host name, or wildcard such as `*.example.com`, of the upstreams.
+    pub host: String,
+    /// Casing of the header names sent to them.
+    pub case: HeaderCase,
+}
+
+/// Returns the casing of the header names sent to `host`, from the first rule matching it.
+pub(crate) fn case_for(rules: &[HeaderCaseRule], host: &str) -> HeaderCase {
+    rules
+        .iter()
+        .find(|rule| tunnel::host_matches(&rule.host, host))
+        .map_or(HeaderCase::Lowercase, |rule| rule.case)
+}
+
+/// Whether the original casing of incoming header names must be recorded for some upstream.
+pub(crate) fn preserves(rules: &[HeaderCaseRule]) -> bool {
+    rules.iter().any(|rule| rule.case == HeaderCase::Preserve)
+}
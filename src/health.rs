@@ -0,0 +1,67 @@
+//! Passive upstream health tracking based on consecutive forwarding failures.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use log::{info, warn};
+
+/// Change of the health state of an upstream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HealthTransition {
+    /// The upstream reached the failure threshold and is now considered unhealthy.
+    BecameUnhealthy,
+    /// A request succeeded against an upstream previously considered unhealthy.
+    Recovered,
+}
+
+/// Counts consecutive failures per upstream and flags upstreams crossing a threshold.
+pub struct UpstreamHealth {
+    threshold: u32,
+    failures: Mutex<HashMap<String, u32>>,
+}
+
+impl UpstreamHealth {
+    /// Creates a tracker marking upstreams unhealthy after `threshold` consecutive failures.
+    pub fn new(threshold: u32) -> Self {
+        UpstreamHealth {
+            threshold: threshold.max(1),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records the outcome of a request to `upstream`, returning the transition it caused, if any.
+    pub fn record(&self, upstream: &str, success: bool) -> Option<HealthTransition> {
+        let mut failures = self.failures.lock().unwrap();
+        if success {
+            let previous = failures.remove(upstream).unwrap_or(0);
+            if previous >= self.threshold {
+                info!("Upstream {} recovered", upstream);
+                return Some(HealthTransition::Recovered);
+            }
+            return None;
+        }
+        let count = failures.entry(upstream.to_string()).or_insert(0);
+        *count += 1;
+        if *count == self.threshold {
+            warn!(
+                "Upstream {} marked unhealthy after {} consecutive failures",
+                upstream, count
+            );
+            return Some(HealthTransition::BecameUnhealthy);
+        }
+        None
+    }
+
+    /// Whether `upstream` is currently considered healthy.
+    pub fn is_healthy(&self, upstream: &str) -> bool {
+        self.failures
+            .lock()
+            .unwrap()
+            .get(upstream)
+            .is_none_or(|count| *count < self.threshold)
+    }
+
+    /// Returns the consecutive failure count of every upstream that has failed recently.
+    pub fn failures(&self) -> HashMap<String, u32> {
+        self.failures.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,267 @@
+//! Honeypot paths that only scanners request, such as `/wp-admin` on a site not running WordPress, and tagging of the
+//! clients requesting them as intruders.
+//!
+//! A client requesting a honeypot path is tagged for a while, and its further requests are rate limited or rejected
+//! altogether depending on the configured action. Responses to tagged clients can also be delayed, slowing down their
+//! scans. The tagged clients are listed in the admin API, which can release them.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hyper::StatusCode;
+use log::warn;
+use serde::Serialize;
+
+use crate::timeseries::unix_now;
+
+/// Number of distinct honeypot paths remembered per intruder.
+const MAX_PATHS: usize = 10;
+
+/// What happens to the further requests of intruders.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntruderAction {
+    /// Intruders are only tagged.
+    Tag,
+    /// Requests of intruders beyond `requests` per `window` are rejected with `429 Too Many Requests`.
+    RateLimit { requests: u32, window: Duration },
+    /// Requests of intruders are rejected with `403 Forbidden`.
+    Ban,
+}
+
+/// Honeypot settings.
+#[derive(Clone, Debug)]
+pub struct HoneypotConfig {
+    /// Honeypot paths, each also covering the paths below it. Defaults to paths of common admin panels and leaked
+    /// files, such as `/wp-admin`, `/phpmyadmin` and `/.env`, which should be adjusted if the site serves any of them.
+    pub paths: Vec<String>,
+    /// Status answering the requests of honeypot paths, as if they did not exist. Defaults to `404 Not Found`.
+    pub status: StatusCode,
+    /// What happens to the further requests of intruders. Defaults to 10 requests per minute.
+    pub action: IntruderAction,
+    /// Delay added to every response to intruders (optional). Disabled by default.
+    pub tarpit_delay: Option<Duration>,
+    /// How long a client stays tagged after its last honeypot request. Defaults to 24 hours.
+    pub tag_lifetime: Duration,
+    /// Number of intruders tracked, beyond which the least recently seen are forgotten. Defaults to 10000.
+    pub max_intruders: usize,
+}
+
+impl Default for HoneypotConfig {
+    fn default() -> Self {
+        Self {
+            paths: [
+                "/wp-admin",
+                "/wp-login.php",
+                "/xmlrpc.php",
+                "/phpmyadmin",
+                "/.env",
+                "/.git",
+            ]
+            .map(String::from)
+            .to_vec(),
+            status: StatusCode::NOT_FOUND,
+            action: IntruderAction::RateLimit {
+                requests: 10,
+                window: Duration::from_secs(60),
+            },
+            tarpit_delay: None,
+            tag_lifetime: Duration::from_secs(86_400),
+            max_intruders: 10_000,
+        }
+    }
+}
+
+/// A client tagged as an intruder.
+#[derive(Clone, Debug, Serialize)]
+pub struct Intruder {
+    /// Address of the client.
+    pub client: String,
+    /// Unix timestamp of the first honeypot request of the client.
+    pub first_seen: u64,
+    /// Unix timestamp of the latest honeypot request of the client.
+    pub last_seen: u64,
+    /// Number of honeypot requests of the client.
+    pub trap_hits: u64,
+    /// Honeypot paths the client requested, up to 10.
+    pub paths: Vec<String>,
+    /// Number of requests of the client rejected since it was tagged.
+    pub rejected: u64,
+    /// Whether the requests of the client are rejected.
+    pub banned: bool,
+}
+
+/// Standing of the client of a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum IntruderVerdict {
+    /// The client is not tagged.
+    Clean,
+    /// The client is tagged, and the request may proceed.
+    Tagged,
+    /// The client is tagged and exceeded its rate, so the request must be retried after the duration.
+    RateLimited(Duration),
+    /// The client is banned.
+    Banned,
+}
+
+struct Tracked {
+    intruder: Intruder,
+    tagged_at: Instant,
+    window_start: Instant,
+    window_requests: u32,
+}
+
+/// Detects honeypot requests and tracks the intruders.
+pub struct Honeypots {
+    config: HoneypotConfig,
+    intruders: Mutex<HashMap<IpAddr, Tracked>>,
+}
+
+impl Honeypots {
+    /// Creates honeypots with the given settings.
+    pub fn new(config: HoneypotConfig) -> Self {
+        Honeypots {
+            config,
+            intruders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `path` is a honeypot path.
+    pub(crate) fn is_trap(&self, path: &str) -> bool {
+        self.config.paths.iter().any(|trap| {
+            let trap = trap.trim_end_matches('/');
+            path.strip_prefix(trap)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    }
+
+    /// Returns the standing of `client`, counting the request against its rate.
+    pub(crate) fn check(&self, client: IpAddr) -> IntruderVerdict {
+        let now = Instant::now();
+        let mut intruders = self.intruders.lock().unwrap();
+        let tracked = match intruders.get_mut(&client) {
+            Some(tracked) => tracked,
+            None => return IntruderVerdict::Clean,
+        };
+        if now - tracked.tagged_at >= self.config.tag_lifetime {
+            intruders.remove(&client);
+            return IntruderVerdict::Clean;
+        }
+        let verdict = if tracked.intruder.banned {
+            IntruderVerdict::Banned
+        } else if let IntruderAction::RateLimit { requests, window } = self.config.action {
+            if now - tracked.window_start >= window {
+                tracked.window_start = now;
+                tracked.window_requests = 0;
+            }
+            tracked.window_requests += 1;
+            if tracked.window_requests > requests {
+                IntruderVerdict::RateLimited(window - (now - tracked.window_start))
+            } else {
+                IntruderVerdict::Tagged
+            }
+        } else {
+            IntruderVerdict::Tagged
+        };
+        if matches!(
+            verdict,
+            IntruderVerdict::RateLimited(_) | IntruderVerdict::Banned
+        ) {
+            tracked.intruder.rejected += 1;
+        }
+        verdict
+    }
+
+    /// Whether `client` is banned, without counting a request against its rate.
+    pub(crate) fn is_banned(&self, client: IpAddr) -> bool {
+        self.intruders
+            .lock()
+            .unwrap()
+            .get(&client)
+            .is_some_and(|tracked| {
+                tracked.intruder.banned && tracked.tagged_at.elapsed() < self.config.tag_lifetime
+            })
+    }
+
+    /// Tags `client` for requesting the honeypot `path`, returning whether this banned it.
+    pub(crate) fn tag(&self, client: IpAddr, path: &str) -> bool {
+        let now = Instant::now();
+        let mut intruders = self.intruders.lock().unwrap();
+        if !intruders.contains_key(&client) && intruders.len() >= self.config.max_intruders {
+            let lifetime = self.config.tag_lifetime;
+            intruders.retain(|_, tracked| now - tracked.tagged_at < lifetime);
+            let oldest = intruders
+                .iter()
+                .min_by_key(|(_, tracked)| tracked.tagged_at)
+                .map(|(client, _)| *client);
+            if let Some(oldest) = oldest.filter(|_| intruders.len() >= self.config.max_intruders) {
+                intruders.remove(&oldest);
+            }
+        }
+        let tracked = intruders.entry(client).or_insert_with(|| {
+            warn!(
+                "Client {} tagged as an intruder for requesting {}",
+                client, path
+            );
+            Tracked {
+                intruder: Intruder {
+                    client: client.to_string(),
+                    first_seen: unix_now(),
+                    last_seen: 0,
+                    trap_hits: 0,
+                    paths: Vec::new(),
+                    rejected: 0,
+                    banned: false,
+                },
+                tagged_at: now,
+                window_start: now,
+                window_requests: 0,
+            }
+        });
+        tracked.tagged_at = now;
+        tracked.intruder.last_seen = unix_now();
+        tracked.intruder.trap_hits += 1;
+        if tracked.intruder.paths.len() < MAX_PATHS
+            && !tracked.intruder.paths.iter().any(|p| p == path)
+        {
+            tracked.intruder.paths.push(path.to_string());
+        }
+        let ban = self.config.action == IntruderAction::Ban && !tracked.intruder.banned;
+        tracked.intruder.banned |= ban;
+        ban
+    }
+
+    /// Releases `client`, returning whether it was tagged.
+    pub fn release(&self, client: IpAddr) -> bool {
+        self.intruders.lock().unwrap().remove(&client).is_some()
+    }
+
+    /// Returns the tagged clients, the most recently seen first.
+    pub fn intruders(&self) -> Vec<Intruder> {
+        let now = Instant::now();
+        let mut intruders: Vec<Intruder> = self
+            .intruders
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|tracked| now - tracked.tagged_at < self.config.tag_lifetime)
+            .map(|tracked| tracked.intruder.clone())
+            .collect();
+        intruders.sort_by_key(|intruder| Reverse(intruder.last_seen));
+        intruders
+    }
+
+    /// Returns the status answering honeypot requests.
+    pub(crate) fn status(&self) -> StatusCode {
+        self.config.status
+    }
+
+    /// Returns the delay added to the responses to intruders, if any.
+    pub(crate) fn tarpit_delay(&self) -> Option<Duration> {
+        self.config.tarpit_delay
+    }
+}
@@ -0,0 +1,11 @@
+//! Escaping of the text put in the HTML pages served, the dashboard and the FTP directory listings, which can hold
+//! names chosen by clients or upstreams.
+
+/// Escapes text for inclusion in HTML.
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
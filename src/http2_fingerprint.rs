@@ -0,0 +1,398 @@
+//! HTTP/2 client fingerprints, in the Akamai format: the SETTINGS, connection WINDOW_UPDATE and PRIORITY frames a
+//! client opens its connection with, and the order of the pseudo-headers of its first request.
+//!
+//! Browsers open their connections in well-known ways, while scripted clients, such as credential stuffing tools,
+//! often do not and claim a browser User-Agent anyway. Clients are flagged as anomalous when they send no connection
+//! WINDOW_UPDATE, order their pseudo-headers unlike any common client, or order them unlike the browser their
+//! User-Agent claims to be. Policies match fingerprints and anomalous clients, and access logs record them.
+//!
+//! The opening bytes of every connection are recorded as they are read by the HTTP server, so fingerprinting never
+//! delays a request; recording stops as soon as the connection turns out not to be HTTP/2.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use anyhow::{Context as _, Result};
+use md5::{Digest, Md5};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::canary::hex;
+
+/// Client connection preface of HTTP/2.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// Length of HTTP/2 frame headers.
+const FRAME_HEADER_LEN: usize = 9;
+/// Opening bytes recorded; connections whose first request headers come later are not fingerprinted.
+const MAX_RECORDED_LEN: usize = 16 * 1024;
+
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_PRIORITY: u8 = 0x2;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+/// Pseudo-header orders of common clients: Chromium, Firefox, Safari, and curl and most HTTP libraries, with
+/// `m,a` for CONNECT requests.
+const COMMON_PSEUDO_HEADER_ORDERS: [&str; 6] =
+    ["m,a,s,p", "m,p,a,s", "m,s,p,a", "m,s,a,p", "m,p,s,a", "m,a"];
+
+/// Why an HTTP/2 client is flagged as anomalous.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Http2Anomaly {
+    /// The client sent no WINDOW_UPDATE for the connection before its first request, as every browser does.
+    MissingWindowUpdate,
+    /// The client ordered its pseudo-headers unlike any common client.
+    UnusualPseudoHeaderOrder,
+    /// The User-Agent of the request claims a browser ordering its pseudo-headers differently.
+    UserAgentMismatch,
+}
+
+impl Http2Anomaly {
+    /// Name of the anomaly in logs and metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Http2Anomaly::MissingWindowUpdate => "missing_window_update",
+            Http2Anomaly::UnusualPseudoHeaderOrder => "unusual_pseudo_header_order",
+            Http2Anomaly::UserAgentMismatch => "user_agent_mismatch",
+        }
+    }
+}
+
+/// The HTTP/2 fingerprint of a client connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Http2Fingerprint {
+    /// Akamai fingerprint, such as `1:65536;2:0;4:6291456;6:262144|15663105|0|m,a,s,p`: the settings, the
+    /// connection window increment, the PRIORITY frames and the pseudo-header order.
+    pub fingerprint: String,
+    /// MD5 hash of the fingerprint.
+    pub hash: String,
+    /// Order of the pseudo-headers of the first request, such as `m,a,s,p`.
+    pub pseudo_header_order: String,
+    /// Why the client is flagged as anomalous, empty for common clients.
+    pub anomalies: Vec<Http2Anomaly>,
+}
+
+impl Http2Fingerprint {
+    /// Whether `fingerprint`, an Akamai fingerprint or its hash, is this one.
+    pub fn matches(&self, fingerprint: &str) -> bool {
+        let fingerprint = fingerprint.trim();
+        fingerprint == self.fingerprint || fingerprint.eq_ignore_ascii_case(&self.hash)
+    }
+
+    /// Returns the fingerprint for a request with `user_agent`, flagged as anomalous if the User-Agent claims a
+    /// browser ordering its pseudo-headers differently.
+    pub fn for_request(&self, user_agent: Option<&str>) -> Http2Fingerprint {
+        let mut fingerprint = self.clone();
+        let expected: &[&str] = match user_agent {
+            Some(agent) if agent.contains("Firefox/") => &["m,p,a,s"],
+            Some(agent) if agent.contains("Chrome/") || agent.contains("Chromium/") => &["m,a,s,p"],
+            Some(agent) if agent.contains("Safari/") => &["m,s,p,a", "m,s,a,p"],
+            _ => &[],
+        };
+        if !expected.is_empty() && !expected.contains(&self.pseudo_header_order.as_str()) {
+            fingerprint.anomalies.push(Http2Anomaly::UserAgentMismatch);
+        }
+        fingerprint
+    }
+}
+
+/// Opening frames of an HTTP/2 connection.
+#[derive(Default)]
+struct Opening {
+    settings: Option<Vec<(u16, u32)>>,
+    window_update: Option<u32>,
+    priorities: Vec<String>,
+}
+
+/// Parses the opening bytes of a connection. Returns `Ok(None)` when more bytes are needed, and an error when the
+/// connection is not HTTP/2 or the pseudo-headers of its first request cannot be read.
+fn parse(data: &[u8]) -> Result<Option<Http2Fingerprint>> {
+    if data.len() < PREFACE.len() && PREFACE.starts_with(data) {
+        return Ok(None);
+    }
+    if !data.starts_with(PREFACE) {
+        anyhow::bail!("Not an HTTP/2 connection");
+    }
+    let mut opening = Opening::default();
+    let mut rest = &data[PREFACE.len()..];
+    while rest.len() >= FRAME_HEADER_LEN {
+        let len = usize::from(rest[0]) << 16 | usize::from(rest[1]) << 8 | usize::from(rest[2]);
+        let (kind, flags) = (rest[3], rest[4]);
+        let stream = u32::from_be_bytes([rest[5], rest[6], rest[7], rest[8]]) & 0x7fff_ffff;
+        let payload = match rest.get(FRAME_HEADER_LEN..FRAME_HEADER_LEN + len) {
+            Some(payload) => payload,
+            None => return Ok(None),
+        };
+        rest = &rest[FRAME_HEADER_LEN + len..];
+        match kind {
+            FRAME_SETTINGS if flags & FLAG_ACK == 0 && opening.settings.is_none() => {
+                let settings = payload
+                    .chunks_exact(6)
+                    .map(|setting| {
+                        let id = u16::from_be_bytes([setting[0], setting[1]]);
+                        let value =
+                            u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                        (id, value)
+                    })
+                    .collect();
+                opening.settings = Some(settings);
+            }
+            FRAME_WINDOW_UPDATE if stream == 0 && payload.len() == 4 => {
+                let increment =
+                    u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                opening.window_update.get_or_insert(increment & 0x7fff_ffff);
+            }
+            FRAME_PRIORITY if payload.len() == 5 => {
+                let dependency =
+                    u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                opening.priorities.push(format!(
+                    "{}:{}:{}:{}",
+                    stream,
+                    dependency >> 31,
+                    dependency & 0x7fff_ffff,
+                    u16::from(payload[4]) + 1
+                ));
+            }
+            FRAME_HEADERS => {
+                let order = pseudo_header_order(header_block(payload, flags)?)?;
+                return Ok(Some(fingerprint(opening, order)));
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the header block fragment of the payload of a HEADERS frame, without its padding and priority.
+fn header_block(payload: &[u8], flags: u8) -> Result<&[u8]> {
+    let mut block = payload;
+    if flags & FLAG_PADDED != 0 {
+        let padding = usize::from(*block.first().context("Missing padding length")?);
+        let end = block
+            .len()
+            .checked_sub(padding)
+            .context("Invalid padding")?;
+        block = block.get(1..end).context("Invalid padding")?;
+    }
+    if flags & FLAG_PRIORITY != 0 {
+        block = block.get(5..).context("Truncated priority")?;
+    }
+    Ok(block)
+}
+
+/// Returns the order of the pseudo-headers at the start of a header block, such as `m,a,s,p`.
+///
+/// The dynamic table is empty in the first request of a connection, so the pseudo-headers are static table entries,
+/// or literals named by them.
+fn pseudo_header_order(mut block: &[u8]) -> Result<String> {
+    let mut order = Vec::new();
+    while let Some(&first) = block.first() {
+        let (index, literal) = if first & 0x80 != 0 {
+            (integer(&mut block, 7)?, false)
+        } else if first & 0xc0 == 0x40 {
+            (integer(&mut block, 6)?, true)
+        } else if first & 0xe0 == 0x20 {
+            // Dynamic table size update
+            integer(&mut block, 5)?;
+            continue;
+        } else {
+            (integer(&mut block, 4)?, true)
+        };
+        // Static table entries 1 to 7 are :authority, :method, :path and :scheme
+        let name = match index {
+            1 => 'a',
+            2 | 3 => 'm',
+            4 | 5 => 'p',
+            6 | 7 => 's',
+            _ => break,
+        };
+        if literal {
+            skip_string(&mut block)?;
+        }
+        order.push(name.to_string());
+    }
+    if order.is_empty() {
+        anyhow::bail!("No pseudo-headers in the first request");
+    }
+    Ok(order.join(","))
+}
+
+/// Reads an HPACK integer with a prefix of `bits` bits.
+fn integer(block: &mut &[u8], bits: u32) -> Result<u64> {
+    let (&first, mut rest) = block.split_first().context("Truncated integer")?;
+    let max = (1u64 << bits) - 1;
+    let mut value = u64::from(first) & max;
+    if value == max {
+        let mut shift = 0;
+        loop {
+            let (&byte, tail) = rest.split_first().context("Truncated integer")?;
+            rest = tail;
+            value += u64::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if shift > 28 {
+                anyhow::bail!("Integer too large");
+            }
+        }
+    }
+    *block = rest;
+    Ok(value)
+}
+
+/// Skips an HPACK string literal.
+fn skip_string(block: &mut &[u8]) -> Result<()> {
+    let len = usize::try_from(integer(block, 7)?)?;
+    *block = block.get(len..).context("Truncated string")?;
+    Ok(())
+}
+
+/// Builds the fingerprint of a connection from its opening frames and the pseudo-header order of its first request.
+fn fingerprint(opening: Opening, pseudo_header_order: String) -> Http2Fingerprint {
+    let settings: Vec<String> = opening
+        .settings
+        .unwrap_or_default()
+        .iter()
+        .map(|(id, value)| format!("{}:{}", id, value))
+        .collect();
+    let window_update = opening
+        .window_update
+        .map_or_else(|| "00".to_string(), |increment| increment.to_string());
+    let priorities = if opening.priorities.is_empty() {
+        "0".to_string()
+    } else {
+        opening.priorities.join(",")
+    };
+    let fingerprint = format!(
+        "{}|{}|{}|{}",
+        settings.join(";"),
+        window_update,
+        priorities,
+        pseudo_header_order
+    );
+    let mut anomalies = Vec::new();
+    if opening.window_update.is_none() {
+        anomalies.push(Http2Anomaly::MissingWindowUpdate);
+    }
+    if !COMMON_PSEUDO_HEADER_ORDERS.contains(&pseudo_header_order.as_str()) {
+        anomalies.push(Http2Anomaly::UnusualPseudoHeaderOrder);
+    }
+    let hash = hex(&Md5::digest(fingerprint.as_bytes()));
+    Http2Fingerprint {
+        fingerprint,
+        hash,
+        pseudo_header_order,
+        anomalies,
+    }
+}
+
+/// The opening bytes of a connection, until its fingerprint is known or it turns out not to be HTTP/2.
+#[derive(Default)]
+struct Recording {
+    bytes: Vec<u8>,
+    finished: bool,
+    fingerprint: Option<Http2Fingerprint>,
+    /// Whether the anomalies of the connection were recorded in the metrics.
+    reported: bool,
+}
+
+/// The fingerprint of a client connection, taken from its opening bytes.
+#[derive(Clone, Default)]
+pub(crate) struct Http2Fingerprinter {
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl Http2Fingerprinter {
+    /// Returns the fingerprint of the connection, known once its first request headers were read.
+    pub(crate) fn fingerprint(&self) -> Option<Http2Fingerprint> {
+        self.recording.lock().unwrap().fingerprint.clone()
+    }
+
+    /// Returns true the first time it is called, so that the anomalies of the connection are recorded once rather
+    /// than for each of its requests.
+    pub(crate) fn report(&self) -> bool {
+        !std::mem::replace(&mut self.recording.lock().unwrap().reported, true)
+    }
+
+    /// Records the bytes read from `stream` for the fingerprint.
+    pub(crate) fn record<S>(&self, stream: S) -> Recorded<S> {
+        Recorded {
+            inner: stream,
+            recording: self.recording.clone(),
+        }
+    }
+}
+
+/// A client stream whose opening bytes are recorded for its HTTP/2 fingerprint.
+pub(crate) struct Recorded<S> {
+    inner: S,
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorded<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let mut recording = self.recording.lock().unwrap();
+            if !recording.finished {
+                recording.bytes.extend_from_slice(&buf.filled()[before..]);
+                // Connections that are not HTTP/2, or too long to open, are not fingerprinted
+                let finished = match parse(&recording.bytes) {
+                    Ok(Some(fingerprint)) => {
+                        recording.fingerprint = Some(fingerprint);
+                        true
+                    }
+                    Ok(None) => recording.bytes.len() >= MAX_RECORDED_LEN,
+                    Err(_) => true,
+                };
+                if finished {
+                    recording.finished = true;
+                    recording.bytes = Vec::new();
+                }
+            }
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorded<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,265 @@
+//! Experimental HTTP/3 listener terminating QUIC and feeding requests through the regular proxy pipeline.
+//!
+//! The listener requires the `http3` feature; without it, configuring one is an error.
+
+use std::time::Duration;
+
+/// HTTP/3 listener settings.
+#[derive(Clone, Debug)]
+pub struct Http3Config {
+    /// UDP port of the HTTP/3 listener, bound on the proxy's `ip_address`.
+    pub port: u16,
+    /// Whether responses of the TCP listener carry an `Alt-Svc` header advertising the HTTP/3 listener.
+    pub advertise: bool,
+    /// How long clients may remember the advertisement (`ma` of `Alt-Svc`).
+    pub max_age: Duration,
+    /// Largest request body accepted, in bytes; larger requests get `413 Payload Too Large`. Defaults to 16 MiB.
+    pub max_body_size: usize,
+}
+
+impl Default for Http3Config {
+    fn default() -> Self {
+        Self {
+            port: 4433,
+            advertise: true,
+            max_age: Duration::from_secs(86400),
+            max_body_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl Http3Config {
+    /// Returns the `Alt-Svc` header value advertising the listener.
+    pub fn alt_svc(&self) -> String {
+        format!("h3=\":{}\"; ma={}", self.port, self.max_age.as_secs())
+    }
+}
+
+/// Accepts QUIC connections and proxies their HTTP/3 requests.
+#[cfg(not(feature = "http3"))]
+pub(crate) async fn serve(
+    _config: Http3Config,
+    _state: std::sync::Arc<crate::ProxyState>,
+) -> anyhow::Result<()> {
+    anyhow::bail!("Cannot start HTTP/3 listener: fortifynet_proxy was built without the `http3` feature")
+}
+
+#[cfg(feature = "http3")]
+pub(crate) use listener::serve;
+
+#[cfg(feature = "http3")]
+mod listener {
+    use std::{
+        net::SocketAddr,
+        sync::{atomic::Ordering, Arc},
+    };
+
+    use anyhow::{Context, Result};
+    use h3::server::RequestResolver;
+    use hyper::{
+        body::{Buf, Bytes, HttpBody},
+        header::{HeaderValue, CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING, UPGRADE},
+        Body, Method, Request,
+    };
+    use log::{debug, error, info, warn};
+    use quinn::rustls::{
+        self,
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    };
+
+    use super::Http3Config;
+    use crate::{
+        handle_http_request, listeners, read_certificate_and_key, record_connection_error, ClientInfo,
+        ListenerEvent, ProxyState,
+    };
+
+    /// Headers specific to a connection, which HTTP/3 forbids.
+    const CONNECTION_HEADERS: &[&str] = &["keep-alive", "proxy-connection"];
+
+    type Resolver = RequestResolver<h3_quinn::Connection, Bytes>;
+
+    /// Accepts QUIC connections and proxies their HTTP/3 requests.
+    pub(crate) async fn serve(config: Http3Config, state: Arc<ProxyState>) -> Result<()> {
+        if state.config.authentication {
+            anyhow::bail!("The HTTP/3 listener does not support authentication");
+        }
+        let (certs, key) = read_certificate_and_key(&state.config)?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_single_cert(
+                certs.into_iter().map(CertificateDer::from).collect(),
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key)),
+            )
+            .map_err(|err| anyhow::anyhow!("Invalid certificate or private key: {}", err))?;
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)?;
+        let server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+
+        let bind_address = format!("{}:{}", state.config.ip_address, config.port);
+        let bind_address: SocketAddr = bind_address
+            .parse()
+            .context(format!("Invalid HTTP/3 address: {}", bind_address))?;
+        let endpoint = quinn::Endpoint::server(server_config, bind_address)
+            .context(format!("Failed to bind to address: {}", bind_address))?;
+        info!("HTTP/3 listening on: {}", bind_address);
+        state.http3_listening.store(true, Ordering::Relaxed);
+
+        while let Some(incoming) = endpoint.accept().await {
+            state
+                .metrics
+                .lock()
+                .unwrap()
+                .record_listener_event(listeners::HTTP3, ListenerEvent::Accepted);
+            let state = state.clone();
+            tokio::spawn(async move {
+                let addr = incoming.remote_address();
+                let metrics = state.metrics.clone();
+                if let Err(err) = handle_connection(incoming, state, config.max_body_size).await {
+                    record_connection_error(&metrics, listeners::HTTP3, &err);
+                    error!("Error handling HTTP/3 connection from {}: {}", addr, err);
+                }
+            });
+        }
+        state.http3_listening.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Serves the requests of a QUIC connection, accepting bodies of up to `max_body_size` bytes.
+    async fn handle_connection(
+        incoming: quinn::Incoming,
+        state: Arc<ProxyState>,
+        max_body_size: usize,
+    ) -> Result<()> {
+        let addr = incoming.remote_address();
+        let client = ClientInfo {
+            addr,
+            country: state.geoip.as_ref().and_then(|geoip| geoip.country(addr.ip())),
+            tls_fingerprint: None,
+            http2_fingerprint: None,
+        };
+        if let Some(geoip) = &state.config.geoip {
+            if !geoip.is_allowed(client.country.as_deref()) {
+                warn!(
+                    "Refusing HTTP/3 connection from {} (country: {:?})",
+                    addr, client.country
+                );
+                incoming.refuse();
+                return Ok(());
+            }
+        }
+
+        let connection = match incoming.await {
+            Ok(connection) => connection,
+            Err(err) => {
+                state
+                    .metrics
+                    .lock()
+                    .unwrap()
+                    .record_listener_event(listeners::HTTP3, ListenerEvent::HandshakeFailure);
+                return Err(err.into());
+            }
+        };
+        debug!("Handling HTTP/3 connection from: {}", addr);
+        let mut connection = h3::server::Connection::new(h3_quinn::Connection::new(connection)).await?;
+        loop {
+            match connection.accept().await {
+                Ok(Some(resolver)) => {
+                    let state = state.clone();
+                    let client = client.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) =
+                            handle_request(resolver, state, client, max_body_size).await
+                        {
+                            error!("Error serving HTTP/3 request from {}: {}", addr, err);
+                        }
+                    });
+                }
+                Ok(None) => return Ok(()),
+                Err(err) if err.is_h3_no_error() => return Ok(()),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Converts an HTTP/3 request to a hyper request, proxies it and streams the response back.
+    async fn handle_request(
+        resolver: Resolver,
+        state: Arc<ProxyState>,
+        client: ClientInfo,
+        max_body_size: usize,
+    ) -> Result<()> {
+        let (request, mut stream) = resolver.resolve_request().await?;
+        let mut body = Vec::new();
+        let mut oversized = request
+            .headers()
+            .get(CONTENT_LENGTH.as_str())
+            .and_then(|value| value.to_str().ok()?.parse::<usize>().ok())
+            .is_some_and(|length| length > max_body_size);
+        while !oversized {
+            match stream.recv_data().await? {
+                Some(mut chunk) => {
+                    oversized = body.len() + chunk.remaining() > max_body_size;
+                    body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+                }
+                None => break,
+            }
+        }
+
+        let response = if oversized {
+            warn!(
+                "Rejected HTTP/3 request from {} for: {} (body larger than {} bytes)",
+                client.addr,
+                request.uri(),
+                max_body_size
+            );
+            hyper::Response::builder()
+                .status(hyper::StatusCode::PAYLOAD_TOO_LARGE)
+                .body(Body::empty())?
+        } else if request.method().as_str() == Method::CONNECT.as_str() {
+            warn!("Refused CONNECT over HTTP/3 from {}", client.addr);
+            hyper::Response::builder()
+                .status(hyper::StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::empty())?
+        } else {
+            let path = request
+                .uri()
+                .path_and_query()
+                .map_or("/", |path| path.as_str());
+            let mut builder = Request::builder()
+                .method(request.method().as_str())
+                .uri(path);
+            for (name, value) in request.headers() {
+                builder = builder.header(name.as_str(), value.as_bytes());
+            }
+            let mut req = builder.body(Body::from(body))?;
+            if let Some(authority) = request.uri().authority() {
+                if !req.headers().contains_key(HOST) {
+                    req.headers_mut()
+                        .insert(HOST, HeaderValue::from_str(authority.as_str())?);
+                }
+            }
+            handle_http_request(req, state, client).await?
+        };
+
+        let (parts, mut body) = response.into_parts();
+        let mut builder = http1::Response::builder().status(parts.status.as_u16());
+        for (name, value) in &parts.headers {
+            if name == CONNECTION
+                || name == TRANSFER_ENCODING
+                || name == UPGRADE
+                || CONNECTION_HEADERS.contains(&name.as_str())
+            {
+                continue;
+            }
+            builder = builder.header(name.as_str(), value.as_bytes());
+        }
+        stream.send_response(builder.body(())?).await?;
+        while let Some(chunk) = body.data().await {
+            stream.send_data(chunk?).await?;
+        }
+        stream.finish().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+//! De-duplication of requests carrying an `Idempotency-Key` header, per client.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    Body, HeaderMap, Method, Response, StatusCode,
+};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::cache::{CacheBackend, MemoryCache};
+
+/// Idempotency settings.
+#[derive(Clone)]
+pub struct IdempotencyConfig {
+    /// Header carrying the idempotency key.
+    pub header_name: String,
+    /// How long the response of a request is replayed to duplicates.
+    pub window: Duration,
+    /// Methods the idempotency layer applies to.
+    pub methods: Vec<Method>,
+    /// Backend storing the responses. An in-memory backend is used when not set.
+    pub backend: Option<Arc<dyn CacheBackend>>,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            header_name: "Idempotency-Key".to_string(),
+            window: Duration::from_secs(86_400),
+            methods: vec![Method::POST, Method::PATCH],
+            backend: None,
+        }
+    }
+}
+
+impl fmt::Debug for IdempotencyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdempotencyConfig")
+            .field("header_name", &self.header_name)
+            .field("window", &self.window)
+            .field("methods", &self.methods)
+            .field("backend", &self.backend.as_ref().map(|_| "custom"))
+            .finish()
+    }
+}
+
+/// A response stored for replay.
+#[derive(Serialize, Deserialize)]
+struct StoredResponse {
+    status: u16,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
+}
+
+impl StoredResponse {
+    fn into_response(self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body));
+        *response.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        for (name, value) in self.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_bytes(&value),
+            ) {
+                response.headers_mut().append(name, value);
+            }
+        }
+        response
+    }
+}
+
+/// Outcome of starting a request with an idempotency key.
+pub enum IdempotencyStart {
+    /// No earlier request with the key exists; the request must be forwarded and completed through the guard.
+    Forward(IdempotencyGuard),
+    /// The response of an earlier request with the key.
+    Replay(Response<Body>),
+}
+
+/// Marks a request as in flight until it is completed or dropped.
+pub struct IdempotencyGuard {
+    idempotency: Arc<Idempotency>,
+    key: String,
+    _done: watch::Sender<()>,
+}
+
+impl IdempotencyGuard {
+    /// Stores the response for replay to later duplicates. Server errors are not stored so they can be retried.
+    pub fn complete(self, status: StatusCode, headers: &HeaderMap, body: &[u8]) {
+        if status.is_server_error() {
+            return;
+        }
+        let stored = StoredResponse {
+            status: status.as_u16(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            body: body.to_vec(),
+        };
+        match serde_json::to_vec(&stored) {
+            Ok(value) => {
+                self.idempotency
+                    .backend
+                    .put(&self.key, value, self.idempotency.config.window)
+            }
+            Err(err) => warn!("Failed to store response for idempotency key {}: {}", self.key, err),
+        }
+    }
+}
+
+impl Drop for IdempotencyGuard {
+    fn drop(&mut self) {
+        self.idempotency.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Replays stored responses to requests repeating an idempotency key.
+pub struct Idempotency {
+    config: IdempotencyConfig,
+    backend: Arc<dyn CacheBackend>,
+    memory: Option<Arc<MemoryCache>>,
+    in_flight: Mutex<HashMap<String, watch::Receiver<()>>>,
+}
+
+impl Idempotency {
+    /// Creates the idempotency layer for the given settings.
+    pub fn new(config: IdempotencyConfig) -> Self {
+        let (backend, memory): (Arc<dyn CacheBackend>, _) = match &config.backend {
+            Some(backend) => (backend.clone(), None),
+            None => {
+                let memory = Arc::new(MemoryCache::new());
+                (memory.clone(), Some(memory))
+            }
+        };
+        Idempotency {
+            config,
+            backend,
+            memory,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the storage key of a request of `client`, if it carries an idempotency key and uses a covered method.
+    /// The client is the authenticated user, or the client address for anonymous requests, so that clients never get
+    /// the responses to each other's requests.
+    pub fn key(
+        &self,
+        method: &Method,
+        uri: &str,
+        client: &str,
+        headers: &HeaderMap,
+    ) -> Option<String> {
+        if !self.config.methods.contains(method) {
+            return None;
+        }
+        let key = headers.get(self.config.header_name.as_str())?.to_str().ok()?;
+        // The client comes with its length, since user names can contain the separator
+        Some(format!(
+            "idempotency:{}:{}:{}:{} {}",
+            client.len(),
+            client,
+            key,
+            method,
+            uri
+        ))
+    }
+
+    /// Starts a request with the storage key `key`, waiting for a concurrent request with the same key to finish first.
+    pub async fn start(self: &Arc<Self>, key: &str) -> IdempotencyStart {
+        loop {
+            if let Some(stored) = self.backend.get(key) {
+                match serde_json::from_slice::<StoredResponse>(&stored) {
+                    Ok(stored) => {
+                        debug!("Replaying stored response for {}", key);
+                        return IdempotencyStart::Replay(stored.into_response());
+                    }
+                    Err(err) => warn!("Discarding unreadable stored response for {}: {}", key, err),
+                }
+            }
+            let mut pending = {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                match in_flight.get(key) {
+                    Some(pending) => pending.clone(),
+                    None => {
+                        let (done, pending) = watch::channel(());
+                        in_flight.insert(key.to_string(), pending);
+                        return IdempotencyStart::Forward(IdempotencyGuard {
+                            idempotency: self.clone(),
+                            key: key.to_string(),
+                            _done: done,
+                        });
+                    }
+                }
+            };
+            debug!("Waiting for in-flight request with {}", key);
+            // The sender is dropped once the first request completes
+            let _ = pending.changed().await;
+        }
+    }
+
+    /// Forgets expired responses of the built-in in-memory backend.
+    pub fn prune(&self) {
+        if let Some(memory) = &self.memory {
+            memory.prune();
+        }
+    }
+}
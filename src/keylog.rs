@@ -0,0 +1,53 @@
+//! Logging of TLS secrets in the `SSLKEYLOGFILE` format, so captures of the connections of the proxy can be decrypted
+//! with tools such as Wireshark while debugging.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use log::{error, warn};
+use rustls::KeyLog;
+
+use crate::canary::hex;
+
+/// Appends the secrets of the TLS connections of the proxy, with clients and upstreams alike, to a file.
+pub struct TlsKeyLog {
+    path: String,
+    file: Mutex<File>,
+}
+
+impl TlsKeyLog {
+    /// Opens `path` for appending, creating it readable by its owner only if it does not exist.
+    pub fn open(path: &str) -> Result<Self> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = options
+            .open(path)
+            .with_context(|| format!("Failed to open TLS key log file {}", path))?;
+        warn!(
+            "Writing TLS secrets to {}: anyone reading it can decrypt the traffic of the proxy",
+            path
+        );
+        Ok(TlsKeyLog {
+            path: path.to_string(),
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl KeyLog for TlsKeyLog {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!("{} {} {}\n", label, hex(client_random), hex(secret));
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            error!("Failed to write to TLS key log file {}: {}", self.path, err);
+        }
+    }
+}
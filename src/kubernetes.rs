@@ -0,0 +1,346 @@
+//! Upstream discovery from the Endpoints of a Kubernetes Service, watched through the API server, so the proxy can
+//! run as an in-cluster edge proxy.
+//!
+//! Watching requires the `kubernetes` feature; without it, configuring Kubernetes discovery is an error.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+
+use crate::discovery::{Discovery, UpstreamPool};
+
+/// Directory of the service account credentials mounted into pods.
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Discovery of the upstream pool from the Endpoints of a Service.
+#[derive(Clone, Debug)]
+pub struct KubernetesDiscoveryConfig {
+    /// Name of the Service.
+    pub service: String,
+    /// Namespace of the Service. Defaults to the namespace of the pod running the proxy.
+    pub namespace: Option<String>,
+    /// Name of the Service port to use. Defaults to its first TCP port.
+    pub port_name: Option<String>,
+    /// URL of the API server. Defaults to the in-cluster address given by `KUBERNETES_SERVICE_HOST` and
+    /// `KUBERNETES_SERVICE_PORT`.
+    pub api_server: Option<String>,
+    /// Path to the bearer token, read again on every connection to follow token rotation.
+    pub token_path: String,
+    /// Path to the PEM certificate of the authority signing the API server certificate.
+    pub ca_path: String,
+}
+
+impl Default for KubernetesDiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            service: String::new(),
+            namespace: None,
+            port_name: None,
+            api_server: None,
+            token_path: format!("{}/token", SERVICE_ACCOUNT),
+            ca_path: format!("{}/ca.crt", SERVICE_ACCOUNT),
+        }
+    }
+}
+
+/// Watcher of the Endpoints of a Service.
+pub(crate) struct EndpointsWatcher {
+    service: String,
+    #[cfg(feature = "kubernetes")]
+    inner: watch::Watcher,
+}
+
+impl EndpointsWatcher {
+    /// Prepares the watch of the configured Service, reconnecting after `retry_delay` when the watch fails. Fails if
+    /// the API server or its CA cannot be determined.
+    #[cfg(feature = "kubernetes")]
+    pub(crate) fn new(config: KubernetesDiscoveryConfig, retry_delay: Duration) -> Result<Self> {
+        Ok(EndpointsWatcher {
+            service: config.service.clone(),
+            inner: watch::Watcher::new(config, retry_delay)?,
+        })
+    }
+
+    /// Prepares the watch of the configured Service.
+    #[cfg(not(feature = "kubernetes"))]
+    pub(crate) fn new(config: KubernetesDiscoveryConfig, _retry_delay: Duration) -> Result<Self> {
+        anyhow::bail!(
+            "Cannot watch Kubernetes service {}: fortifynet_proxy was built without the `kubernetes` feature",
+            config.service
+        )
+    }
+}
+
+impl Discovery for EndpointsWatcher {
+    fn describe(&self) -> String {
+        format!("Kubernetes service {}", self.service)
+    }
+
+    #[cfg(feature = "kubernetes")]
+    fn run(self: Arc<Self>, pool: Arc<UpstreamPool>) -> BoxFuture<'static, ()> {
+        Box::pin(async move { self.inner.run(pool).await })
+    }
+
+    #[cfg(not(feature = "kubernetes"))]
+    fn run(self: Arc<Self>, _pool: Arc<UpstreamPool>) -> BoxFuture<'static, ()> {
+        Box::pin(async {})
+    }
+}
+
+#[cfg(feature = "kubernetes")]
+mod watch {
+    use std::{
+        net::{IpAddr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use anyhow::{Context, Result};
+    use futures::StreamExt;
+    use hyper::{
+        client::{Client, HttpConnector},
+        header::{ACCEPT, AUTHORIZATION},
+        Body, Request, StatusCode,
+    };
+    use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+    use log::{debug, info, warn};
+    use serde::Deserialize;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+    use super::{KubernetesDiscoveryConfig, SERVICE_ACCOUNT};
+    use crate::discovery::{Endpoint, UpstreamPool};
+
+    /// Longest time the API server keeps a watch open before it is restarted with a fresh list.
+    const WATCH_TIMEOUT_SECONDS: u64 = 300;
+
+    #[derive(Deserialize)]
+    struct Endpoints {
+        metadata: Metadata,
+        #[serde(default)]
+        subsets: Vec<Subset>,
+    }
+
+    #[derive(Deserialize)]
+    struct Metadata {
+        #[serde(rename = "resourceVersion", default)]
+        resource_version: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct Subset {
+        /// Addresses of the ready pods; the pods that are not ready are listed in `notReadyAddresses`.
+        #[serde(default)]
+        addresses: Vec<Address>,
+        #[serde(default)]
+        ports: Vec<Port>,
+    }
+
+    #[derive(Deserialize)]
+    struct Address {
+        ip: IpAddr,
+    }
+
+    #[derive(Deserialize)]
+    struct Port {
+        #[serde(default)]
+        name: Option<String>,
+        port: u16,
+        #[serde(default)]
+        protocol: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct WatchEvent {
+        #[serde(rename = "type")]
+        kind: String,
+        object: serde_json::Value,
+    }
+
+    pub(super) struct Watcher {
+        config: KubernetesDiscoveryConfig,
+        namespace: String,
+        api_server: String,
+        client: Client<HttpsConnector<HttpConnector>, Body>,
+        retry_delay: Duration,
+    }
+
+    impl Watcher {
+        pub(super) fn new(
+            config: KubernetesDiscoveryConfig,
+            retry_delay: Duration,
+        ) -> Result<Self> {
+            let api_server = match &config.api_server {
+                Some(api_server) => api_server.trim_end_matches('/').to_string(),
+                None => {
+                    let host = std::env::var("KUBERNETES_SERVICE_HOST").context(
+                        "Not running in Kubernetes: KUBERNETES_SERVICE_HOST is not set and no API server is configured",
+                    )?;
+                    let port =
+                        std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or("443".to_string());
+                    match host.parse::<IpAddr>() {
+                        Ok(IpAddr::V6(ip)) => format!("https://[{}]:{}", ip, port),
+                        _ => format!("https://{}:{}", host, port),
+                    }
+                }
+            };
+            let namespace = match &config.namespace {
+                Some(namespace) => namespace.clone(),
+                None => std::fs::read_to_string(format!("{}/namespace", SERVICE_ACCOUNT))
+                    .map(|namespace| namespace.trim().to_string())
+                    .unwrap_or("default".to_string()),
+            };
+
+            let ca = std::fs::File::open(&config.ca_path)
+                .context(format!("Failed to open Kubernetes CA {}", config.ca_path))?;
+            let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(ca))
+                .context(format!("Failed to read Kubernetes CA {}", config.ca_path))?;
+            let mut roots = RootCertStore::empty();
+            let (added, _) = roots.add_parsable_certificates(&certs);
+            if added == 0 {
+                anyhow::bail!("No certificate found in Kubernetes CA {}", config.ca_path);
+            }
+            let tls = ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let connector = HttpsConnectorBuilder::new()
+                .with_tls_config(tls)
+                .https_or_http()
+                .enable_http1()
+                .build();
+            Ok(Watcher {
+                config,
+                namespace,
+                api_server,
+                client: Client::builder().build(connector),
+                retry_delay,
+            })
+        }
+
+        pub(super) async fn run(&self, pool: Arc<UpstreamPool>) {
+            info!(
+                "Watching the endpoints of Kubernetes service {}/{}",
+                self.namespace, self.config.service
+            );
+            loop {
+                if let Err(err) = self.sync(&pool).await {
+                    warn!(
+                        "Watch of Kubernetes service {}/{} failed, retrying in {:?}: {}",
+                        self.namespace, self.config.service, self.retry_delay, err
+                    );
+                    tokio::time::sleep(self.retry_delay).await;
+                }
+            }
+        }
+
+        /// Lists the Endpoints, then applies their changes until the watch ends.
+        async fn sync(&self, pool: &UpstreamPool) -> Result<()> {
+            let path = format!(
+                "/api/v1/namespaces/{}/endpoints/{}",
+                self.namespace, self.config.service
+            );
+            let body = hyper::body::to_bytes(self.get(&path).await?.into_body()).await?;
+            let endpoints: Endpoints =
+                serde_json::from_slice(&body).context("Invalid Endpoints object")?;
+            self.apply(pool, &endpoints);
+            let resource_version = endpoints.metadata.resource_version.unwrap_or_default();
+
+            let path = format!(
+                "/api/v1/namespaces/{}/endpoints?watch=true&fieldSelector=metadata.name%3D{}&resourceVersion={}&timeoutSeconds={}",
+                self.namespace, self.config.service, resource_version, WATCH_TIMEOUT_SECONDS
+            );
+            let mut body = self.get(&path).await?.into_body();
+            let mut buffer = Vec::new();
+            while let Some(chunk) = body.next().await {
+                buffer.extend_from_slice(&chunk.context("Failed to read the watch")?);
+                // Events are newline-delimited JSON objects
+                while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=end).collect();
+                    if line.iter().all(u8::is_ascii_whitespace) {
+                        continue;
+                    }
+                    let event: WatchEvent =
+                        serde_json::from_slice(&line).context("Invalid watch event")?;
+                    match event.kind.as_str() {
+                        "ADDED" | "MODIFIED" => {
+                            let endpoints: Endpoints = serde_json::from_value(event.object)
+                                .context("Invalid Endpoints object")?;
+                            self.apply(pool, &endpoints);
+                        }
+                        "DELETED" => warn!(
+                            "Kubernetes service {}/{} was deleted, keeping the current upstreams",
+                            self.namespace, self.config.service
+                        ),
+                        // An expired resource version is reported as an error event; listing again recovers
+                        "ERROR" => anyhow::bail!("Watch error: {}", event.object),
+                        _ => {}
+                    }
+                }
+            }
+            debug!("Watch of Kubernetes service {} ended", self.config.service);
+            Ok(())
+        }
+
+        /// Updates `pool` with the ready addresses of `endpoints`, keeping the current upstreams if there are none.
+        fn apply(&self, pool: &UpstreamPool, endpoints: &Endpoints) {
+            let discovered: Vec<Endpoint> = endpoints
+                .subsets
+                .iter()
+                .filter_map(|subset| Some((subset, self.port(subset)?)))
+                .flat_map(|(subset, port)| {
+                    subset.addresses.iter().map(move |address| Endpoint {
+                        address: SocketAddr::new(address.ip, port),
+                        weight: 1,
+                    })
+                })
+                .collect();
+            if discovered.is_empty() {
+                warn!(
+                    "Kubernetes service {}/{} has no ready endpoints, keeping the current upstreams",
+                    self.namespace, self.config.service
+                );
+                return;
+            }
+            pool.update(discovered);
+        }
+
+        /// Returns the port of `subset` to use.
+        fn port(&self, subset: &Subset) -> Option<u16> {
+            let tcp = |port: &&Port| port.protocol.as_deref().unwrap_or("TCP") == "TCP";
+            let port = match &self.config.port_name {
+                Some(name) => subset
+                    .ports
+                    .iter()
+                    .find(|port| port.name.as_deref() == Some(name.as_str())),
+                None => subset.ports.iter().find(tcp),
+            };
+            port.map(|port| port.port)
+        }
+
+        /// Sends an authenticated GET request to the API server, failing on unsuccessful responses.
+        async fn get(&self, path: &str) -> Result<hyper::Response<Body>> {
+            let token = tokio::fs::read_to_string(&self.config.token_path)
+                .await
+                .context(format!(
+                    "Failed to read Kubernetes token {}",
+                    self.config.token_path
+                ))?;
+            let request = Request::get(format!("{}{}", self.api_server, path))
+                .header(ACCEPT, "application/json")
+                .header(AUTHORIZATION, format!("Bearer {}", token.trim()))
+                .body(Body::empty())?;
+            let response = self.client.request(request).await?;
+            if response.status() != StatusCode::OK {
+                let status = response.status();
+                let body = hyper::body::to_bytes(response.into_body()).await?;
+                anyhow::bail!(
+                    "API server answered {}: {}",
+                    status,
+                    String::from_utf8_lossy(&body)
+                );
+            }
+            Ok(response)
+        }
+    }
+}
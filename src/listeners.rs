@@ -0,0 +1,129 @@
+//! Connection-level metrics of the listeners: the connections they accept and their accept rate, accept errors,
+//! failed TLS handshakes, and the connections the clients reset or closed before the end of a request.
+//!
+//! Request-level metrics only see the connections that got as far as a request; these count what happens on the
+//! sockets before and around the requests, such as port scans, clients giving up on slow handshakes, or load
+//! balancers resetting their health checks.
+
+use std::{fmt, io};
+
+/// Name of the listener of the proxy port.
+pub(crate) const PROXY: &str = "proxy";
+/// Name of the listener of the TLS passthrough port.
+pub(crate) const PASSTHROUGH: &str = "passthrough";
+/// Name of the HTTP/3 listener.
+#[cfg_attr(not(feature = "http3"), allow(dead_code))]
+pub(crate) const HTTP3: &str = "http3";
+
+/// Number of seconds over which the accept rate is averaged.
+const RATE_WINDOW_SECS: usize = 60;
+
+/// Something happening on a connection of a listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListenerEvent {
+    /// A connection was accepted.
+    Accepted,
+    /// Accepting a connection failed, such as when running out of file descriptors.
+    AcceptError,
+    /// The TLS handshake of a connection failed, or its ClientHello was refused.
+    HandshakeFailure,
+    /// The client reset the connection.
+    Reset,
+    /// The client closed the connection in the middle of a request or a handshake.
+    PrematureDisconnect,
+}
+
+impl ListenerEvent {
+    /// Classifies the error a connection ended with, for client resets and premature disconnects.
+    pub(crate) fn of_error(error: &anyhow::Error) -> Option<Self> {
+        error.chain().find_map(|cause| {
+            if let Some(error) = cause.downcast_ref::<hyper::Error>() {
+                if error.is_incomplete_message() {
+                    return Some(ListenerEvent::PrematureDisconnect);
+                }
+            }
+            cause
+                .downcast_ref::<io::Error>()
+                .and_then(|error| Self::of_io_error(error.kind()))
+        })
+    }
+
+    fn of_io_error(kind: io::ErrorKind) -> Option<Self> {
+        match kind {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => Some(ListenerEvent::Reset),
+            io::ErrorKind::UnexpectedEof => Some(ListenerEvent::PrematureDisconnect),
+            _ => None,
+        }
+    }
+}
+
+/// Connection-level counts of a listener.
+#[derive(Clone, Default)]
+pub struct ListenerStats {
+    /// Number of connections accepted.
+    pub accepted: u64,
+    /// Number of failures to accept a connection.
+    pub accept_errors: u64,
+    /// Number of connections whose TLS handshake failed or whose ClientHello was refused.
+    pub handshake_failures: u64,
+    /// Number of connections reset by the client.
+    pub resets: u64,
+    /// Number of connections closed by the client in the middle of a request or a handshake.
+    pub premature_disconnects: u64,
+    /// Connections accepted per second over the last minute, as `(timestamp, count)` slots.
+    recent_accepts: Vec<(u64, u64)>,
+}
+
+impl ListenerStats {
+    /// Records `event` at `now`, in seconds since the Unix epoch.
+    pub(crate) fn record(&mut self, event: ListenerEvent, now: u64) {
+        match event {
+            ListenerEvent::Accepted => {
+                self.accepted += 1;
+                if self.recent_accepts.is_empty() {
+                    self.recent_accepts = vec![(0, 0); RATE_WINDOW_SECS];
+                }
+                let slot = &mut self.recent_accepts[now as usize % RATE_WINDOW_SECS];
+                if slot.0 != now {
+                    *slot = (now, 0);
+                }
+                slot.1 += 1;
+            }
+            ListenerEvent::AcceptError => self.accept_errors += 1,
+            ListenerEvent::HandshakeFailure => self.handshake_failures += 1,
+            ListenerEvent::Reset => self.resets += 1,
+            ListenerEvent::PrematureDisconnect => self.premature_disconnects += 1,
+        }
+    }
+
+    /// Returns the average number of connections accepted per second over the minute ending at `now`.
+    pub fn accept_rate(&self, now: u64) -> f64 {
+        let window = RATE_WINDOW_SECS as u64;
+        let accepted: u64 = self
+            .recent_accepts
+            .iter()
+            .filter(|(timestamp, _)| *timestamp <= now && now - timestamp < window)
+            .map(|(_, count)| count)
+            .sum();
+        accepted as f64 / window as f64
+    }
+
+    /// Returns the approximate memory taken by the accept rate slots, in bytes.
+    pub(crate) fn approximate_size(&self) -> usize {
+        self.recent_accepts.capacity() * std::mem::size_of::<(u64, u64)>()
+    }
+}
+
+impl fmt::Debug for ListenerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ListenerStats")
+            .field("accepted", &self.accepted)
+            .field("accept_errors", &self.accept_errors)
+            .field("handshake_failures", &self.handshake_failures)
+            .field("resets", &self.resets)
+            .field("premature_disconnects", &self.premature_disconnects)
+            .finish_non_exhaustive()
+    }
+}
@@ -1,10 +1,92 @@
-use fortifynet_proxy::{start_proxy_server, ProxyConfig};
+use std::path::PathBuf;
+
+use fortifynet_proxy::{start_proxy_server, ProxyConfig, ResourceSizing};
 use log::info;
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+const USAGE: &str = "expected --config <path>, --profile <name>, --daemon, --pidfile <path>, \
+                     --log-file <path> or --service <name>";
+
+fn main() -> anyhow::Result<()> {
+    // Read the configuration file and profile from `--config <path>` and `--profile <name>`, and how to run from
+    // `--daemon` or `--service <name>`, with `--pidfile <path>` and `--log-file <path>`
+    let mut config_path = None;
+    let mut profile = None;
+    let mut daemon = false;
+    let mut service = None;
+    let mut pid_file = None;
+    let mut log_file = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = args.next(),
+            "--profile" => profile = args.next(),
+            "--daemon" => daemon = true,
+            "--service" => service = args.next(),
+            "--pidfile" => pid_file = args.next().map(PathBuf::from),
+            "--log-file" => log_file = args.next().map(PathBuf::from),
+            _ => anyhow::bail!("Unknown argument {}: {}", arg, USAGE),
+        }
+    }
+    if pid_file.is_some() && !daemon {
+        anyhow::bail!("--pidfile requires --daemon");
+    }
+    if log_file.is_some() && !daemon && service.is_none() {
+        anyhow::bail!("--log-file requires --daemon or --service");
+    }
+
+    // Load the configuration before detaching, so that its errors are reported
+    let config = match config_path {
+        Some(path) => ProxyConfig::from_file(path, profile.as_deref())?,
+        None => default_config(),
+    };
+
+    match service {
+        #[cfg(all(windows, feature = "windows-service"))]
+        Some(name) => return fortifynet_proxy::run_service(&name, config, log_file.as_deref()),
+        #[cfg(not(all(windows, feature = "windows-service")))]
+        Some(_) => anyhow::bail!("--service requires Windows and the windows-service feature"),
+        None => {}
+    }
+    #[cfg(unix)]
+    let _pid_file = if daemon {
+        fortifynet_proxy::daemonize(&fortifynet_proxy::DaemonOptions { pid_file, log_file })?
+    } else {
+        None
+    };
+    #[cfg(not(unix))]
+    if daemon {
+        anyhow::bail!("--daemon is only supported on Unix");
+    }
+
+    // Size the runtime to the CPU quota of the container, unless configured
+    let sizing = ResourceSizing::detect(&config.resources);
+    sizing.runtime(&config.resources)?.block_on(serve(config))
+}
+
+/// Runs the proxy until it is stopped, or until SIGTERM is received on Unix
+async fn serve(config: ProxyConfig) -> anyhow::Result<()> {
+    info!("Starting Proxy server with configuration: {:?}", config);
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = start_proxy_server(config) => result,
+            _ = terminate.recv() => {
+                info!("Received SIGTERM, stopping");
+                Ok(())
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    start_proxy_server(config).await
+}
+
+/// Returns the configuration used without a configuration file
+fn default_config() -> ProxyConfig {
     // Create a proxy configuration with default values
-    let config = ProxyConfig {
+    ProxyConfig {
         // The IP address the proxy server will bind to
         ip_address: "127.0.0.1".to_string(),
         // The port the proxy server will listen on
@@ -26,9 +108,7 @@ async fn main() -> anyhow::Result<()> {
         private_key_path: Some("key.pem".to_string()),
         // The target address to proxy requests to
         target_address: Some("http://www.google.com".to_string()), // Set the target address
-    };
-    info!("Starting Proxy server with configuration: {:?}", config);
-    // Start the proxy server with the provided configuration
-    start_proxy_server(config).await?;
-    Ok(())
+        // Every other setting keeps its default value
+        ..Default::default()
+    }
 }
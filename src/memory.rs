@@ -0,0 +1,137 @@
+//! Accounting of the memory used by the cache, the bodies buffered by requests in flight and the metrics, and
+//! enforcement of a budget over their total.
+//!
+//! The usage of the cache and metrics is measured periodically, while buffers are counted as they are taken. When
+//! over budget, cached responses are evicted down to what the budget leaves them and new ones are not admitted; once
+//! the buffers and metrics alone exceed the budget, requests are shed until usage falls back under it.
+
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+use serde::Serialize;
+
+/// Memory used by the proxy, as last measured.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct MemoryUsage {
+    /// Bytes of cached bodies held in memory.
+    pub cache_bytes: usize,
+    /// Bytes of bodies buffered by requests in flight.
+    pub buffer_bytes: usize,
+    /// Approximate bytes of the metrics.
+    pub metrics_bytes: usize,
+    /// Total memory allowed, in bytes. Unlimited when `None`.
+    pub budget_bytes: Option<usize>,
+    /// Number of cached responses evicted to stay within the budget.
+    pub evictions: u64,
+    /// Number of requests shed for lack of memory.
+    pub shed_requests: u64,
+}
+
+impl MemoryUsage {
+    /// Returns the total memory used, in bytes.
+    pub fn total(&self) -> usize {
+        self.cache_bytes + self.buffer_bytes + self.metrics_bytes
+    }
+}
+
+/// Tracks the memory used by the proxy against its budget.
+pub struct MemoryTracker {
+    budget: Option<usize>,
+    buffers: Arc<AtomicUsize>,
+    cache: AtomicUsize,
+    metrics: AtomicUsize,
+    evictions: AtomicU64,
+    shed: AtomicU64,
+}
+
+/// Bytes of a buffered body, counted until dropped.
+pub struct BufferGuard {
+    buffers: Arc<AtomicUsize>,
+    len: usize,
+}
+
+impl Drop for BufferGuard {
+    fn drop(&mut self) {
+        self.buffers.fetch_sub(self.len, Ordering::Relaxed);
+    }
+}
+
+impl MemoryTracker {
+    /// Creates a tracker enforcing `budget` bytes, if any.
+    pub fn new(budget: Option<usize>) -> Self {
+        MemoryTracker {
+            budget,
+            buffers: Arc::new(AtomicUsize::new(0)),
+            cache: AtomicUsize::new(0),
+            metrics: AtomicUsize::new(0),
+            evictions: AtomicU64::new(0),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts `len` bytes of a body buffered by a request until the returned guard is dropped.
+    pub fn buffer(&self, len: usize) -> BufferGuard {
+        self.buffers.fetch_add(len, Ordering::Relaxed);
+        BufferGuard {
+            buffers: self.buffers.clone(),
+            len,
+        }
+    }
+
+    /// Records the measured usage of the cache and the metrics.
+    pub(crate) fn measure(&self, cache_bytes: usize, metrics_bytes: usize) {
+        self.cache.store(cache_bytes, Ordering::Relaxed);
+        self.metrics.store(metrics_bytes, Ordering::Relaxed);
+    }
+
+    /// Counts `len` bytes added to the cache until the next measurement.
+    pub(crate) fn record_cache_insert(&self, len: usize) {
+        self.cache.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Records cached responses evicted to stay within the budget.
+    pub(crate) fn record_evictions(&self, count: usize) {
+        self.evictions.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Returns how many bytes the cache may hold within the budget, next to the buffers and metrics.
+    pub fn cache_allowance(&self) -> Option<usize> {
+        let others = self.buffers.load(Ordering::Relaxed) + self.metrics.load(Ordering::Relaxed);
+        self.budget.map(|budget| budget.saturating_sub(others))
+    }
+
+    /// Whether the memory used exceeds the budget.
+    pub fn over_budget(&self) -> bool {
+        let usage = self.usage();
+        usage
+            .budget_bytes
+            .is_some_and(|budget| usage.total() > budget)
+    }
+
+    /// Whether a request must be shed, as the buffers and metrics alone exceed the budget and evicting the cache
+    /// cannot make room. Shed requests are counted.
+    pub fn shed(&self) -> bool {
+        let usage = self.usage();
+        let shed = usage
+            .budget_bytes
+            .is_some_and(|budget| usage.buffer_bytes + usage.metrics_bytes > budget);
+        if shed {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+        }
+        shed
+    }
+
+    /// Returns the memory used, as last measured.
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            cache_bytes: self.cache.load(Ordering::Relaxed),
+            buffer_bytes: self.buffers.load(Ordering::Relaxed),
+            metrics_bytes: self.metrics.load(Ordering::Relaxed),
+            budget_bytes: self.budget,
+            evictions: self.evictions.load(Ordering::Relaxed),
+            shed_requests: self.shed.load(Ordering::Relaxed),
+        }
+    }
+}
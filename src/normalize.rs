@@ -0,0 +1,169 @@
+//! URL canonicalization applied to requests before routing and caching, so equivalent URLs share routes and cache
+//! entries.
+
+use anyhow::{Context, Result};
+use hyper::Uri;
+
+/// Which canonicalizations are applied to request URLs.
+#[derive(Clone, Debug)]
+pub struct NormalizationConfig {
+    /// Uppercases the hex digits of percent-encodings and decodes percent-encoded unreserved characters
+    /// (`A-Z a-z 0-9 - . _ ~`). Defaults to `true`.
+    pub percent_encoding: bool,
+    /// Removes the `.` and `..` segments of the path. Defaults to `true`.
+    pub dot_segments: bool,
+    /// Collapses runs of `/` in the path into one. Defaults to `true`.
+    pub duplicate_slashes: bool,
+    /// Sorts the query parameters by name, keeping the order of repeated parameters. Defaults to `false`.
+    pub sort_query: bool,
+    /// Removes the query parameters named in `tracking_params`. Defaults to `false`.
+    pub strip_tracking_params: bool,
+    /// Names of the tracking parameters; a trailing `*` matches any suffix. Defaults to `utm_*`, `fbclid` and
+    /// `gclid`.
+    pub tracking_params: Vec<String>,
+}
+
+impl Default for NormalizationConfig {
+    fn default() -> Self {
+        Self {
+            percent_encoding: true,
+            dot_segments: true,
+            duplicate_slashes: true,
+            sort_query: false,
+            strip_tracking_params: false,
+            tracking_params: vec![
+                "utm_*".to_string(),
+                "fbclid".to_string(),
+                "gclid".to_string(),
+            ],
+        }
+    }
+}
+
+impl NormalizationConfig {
+    /// Returns the canonical form of `uri`, keeping its scheme and authority.
+    pub fn normalize(&self, uri: &Uri) -> Result<Uri> {
+        let mut path = uri.path().to_string();
+        let mut query = uri.query().map(str::to_string);
+        if self.percent_encoding {
+            path = normalize_percent_encoding(&path);
+            query = query.map(|query| normalize_percent_encoding(&query));
+        }
+        // Asterisk-form requests have no path to canonicalize
+        if path.starts_with('/') {
+            if self.duplicate_slashes {
+                path = collapse_slashes(&path);
+            }
+            if self.dot_segments {
+                path = remove_dot_segments(&path);
+            }
+        }
+        if let Some(current) = query.take() {
+            let mut params: Vec<&str> = current
+                .split('&')
+                .filter(|param| !param.is_empty())
+                .collect();
+            if self.strip_tracking_params {
+                params.retain(|param| !self.is_tracking_param(param));
+            }
+            if self.sort_query {
+                // Stable, so repeated parameters keep their order
+                params.sort_by_key(|param| param.split('=').next().unwrap_or(param));
+            }
+            query = (!params.is_empty()).then(|| params.join("&"));
+        }
+
+        let path_and_query = match query {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        if Some(path_and_query.as_str()) == uri.path_and_query().map(|pq| pq.as_str()) {
+            return Ok(uri.clone());
+        }
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(
+            path_and_query
+                .parse()
+                .context(format!("Invalid canonical form of {}", uri))?,
+        );
+        Uri::from_parts(parts).context(format!("Invalid canonical form of {}", uri))
+    }
+
+    /// Whether a `name=value` query parameter is a tracking parameter.
+    fn is_tracking_param(&self, param: &str) -> bool {
+        let name = param.split('=').next().unwrap_or(param);
+        self.tracking_params
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            })
+    }
+}
+
+/// Uppercases the hex digits of percent-encodings and decodes those of unreserved characters.
+fn normalize_percent_encoding(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let decoded = bytes
+            .get(index + 1..index + 3)
+            .filter(|_| bytes[index] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(byte)
+                if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') =>
+            {
+                output.push(byte);
+                index += 3;
+            }
+            Some(_) => {
+                output.push(b'%');
+                output.extend(bytes[index + 1..index + 3].to_ascii_uppercase());
+                index += 3;
+            }
+            None => {
+                output.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    // Only ASCII bytes were replaced, so the output stays valid UTF-8
+    String::from_utf8(output).unwrap_or_else(|_| input.to_string())
+}
+
+/// Collapses runs of `/` into one.
+fn collapse_slashes(path: &str) -> String {
+    let mut output = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c == '/' && output.ends_with('/') {
+            continue;
+        }
+        output.push(c);
+    }
+    output
+}
+
+/// Removes the `.` and `..` segments of an absolute path, as in RFC 3986 section 5.2.4.
+pub(crate) fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path[1..].split('/') {
+        trailing_slash = false;
+        match segment {
+            "." => trailing_slash = true,
+            ".." => {
+                segments.pop();
+                trailing_slash = true;
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut output = format!("/{}", segments.join("/"));
+    if trailing_slash && !output.ends_with('/') {
+        output.push('/');
+    }
+    output
+}
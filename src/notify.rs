@@ -0,0 +1,311 @@
+//! Webhook notifications for operational events.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use hyper::{
+    client::{connect::Connect, Client, HttpConnector},
+    header::CONTENT_TYPE,
+    Body, Method, Request,
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{debug, error, warn};
+use serde::Serialize;
+
+use crate::{error_budget::ErrorBudgetStatus, slo::SloStatus};
+
+/// Payload format used when posting to a webhook.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WebhookFormat {
+    /// The serialized [`Event`] as a JSON object.
+    Json,
+    /// A Slack-compatible `{"text": "..."}` message.
+    Slack,
+}
+
+/// Kinds of events a webhook can subscribe to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    /// An upstream was marked unhealthy or recovered.
+    UpstreamHealth,
+    /// The TLS certificate is close to its expiry date.
+    CertificateExpiring,
+    /// A client was banned.
+    BanIssued,
+    /// The overall error rate spiked above the configured threshold.
+    ErrorRateSpike,
+    /// An SLO was breached or recovered.
+    Slo,
+    /// An error budget started or stopped burning fast.
+    ErrorBudget,
+}
+
+/// A webhook receiving event notifications.
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    /// URL the notifications are POSTed to (`http` or `https`).
+    pub url: String,
+    /// Payload format of the notifications.
+    pub format: WebhookFormat,
+    /// Event kinds delivered to this webhook. All events are delivered when empty.
+    pub events: Vec<EventKind>,
+    /// Maximum number of notifications sent to this webhook per minute; extra ones are dropped.
+    pub max_per_minute: u32,
+    /// Number of delivery retries after a failed attempt.
+    pub max_retries: u32,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            format: WebhookFormat::Json,
+            events: Vec::new(),
+            max_per_minute: 10,
+            max_retries: 3,
+        }
+    }
+}
+
+/// Settings of the notifier subsystem.
+#[derive(Clone, Debug)]
+pub struct NotificationConfig {
+    /// Webhooks receiving the notifications. Notifications are disabled when empty.
+    pub webhooks: Vec<WebhookConfig>,
+    /// Number of consecutive forwarding failures after which an upstream is reported unhealthy.
+    pub unhealthy_after_failures: u32,
+    /// Error rate over the last minute above which an error-rate spike is reported.
+    pub error_rate_threshold: f64,
+    /// Minimum number of requests in the last minute before the error rate is evaluated.
+    pub error_rate_min_requests: u64,
+    /// How long before expiry the TLS certificate is reported as expiring.
+    pub certificate_expiry_warning: Duration,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            webhooks: Vec::new(),
+            unhealthy_after_failures: 3,
+            error_rate_threshold: 0.1,
+            error_rate_min_requests: 20,
+            certificate_expiry_warning: Duration::from_secs(14 * 86_400),
+        }
+    }
+}
+
+/// An operational event reported to the webhooks.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// An upstream reached the consecutive failure threshold.
+    UpstreamUnhealthy {
+        upstream: String,
+        consecutive_failures: u32,
+    },
+    /// A previously unhealthy upstream served a request successfully.
+    UpstreamRecovered { upstream: String },
+    /// The TLS certificate expires soon.
+    CertificateExpiring { path: String, days_remaining: i64 },
+    /// A client was banned.
+    BanIssued { client: String, reason: String },
+    /// The error rate over the last minute exceeded the threshold.
+    ErrorRateSpike { error_rate: f64, requests: u64 },
+    /// An SLO was breached.
+    SloBreached { status: SloStatus },
+    /// A breached SLO recovered.
+    SloRecovered { status: SloStatus },
+    /// An error budget is burning beyond the fast burn threshold.
+    ErrorBudgetBurning { status: ErrorBudgetStatus },
+    /// An error budget that was burning fast no longer is.
+    ErrorBudgetRecovered { status: ErrorBudgetStatus },
+}
+
+impl Event {
+    /// Returns the kind of the event.
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::UpstreamUnhealthy { .. } | Event::UpstreamRecovered { .. } => {
+                EventKind::UpstreamHealth
+            }
+            Event::CertificateExpiring { .. } => EventKind::CertificateExpiring,
+            Event::BanIssued { .. } => EventKind::BanIssued,
+            Event::ErrorRateSpike { .. } => EventKind::ErrorRateSpike,
+            Event::SloBreached { .. } | Event::SloRecovered { .. } => EventKind::Slo,
+            Event::ErrorBudgetBurning { .. } | Event::ErrorBudgetRecovered { .. } => {
+                EventKind::ErrorBudget
+            }
+        }
+    }
+
+    /// Returns a human readable one-line description of the event.
+    pub fn summary(&self) -> String {
+        match self {
+            Event::UpstreamUnhealthy {
+                upstream,
+                consecutive_failures,
+            } => format!(
+                "Upstream {} is unhealthy after {} consecutive failures",
+                upstream, consecutive_failures
+            ),
+            Event::UpstreamRecovered { upstream } => format!("Upstream {} recovered", upstream),
+            Event::CertificateExpiring {
+                path,
+                days_remaining,
+            } => format!(
+                "Certificate {} expires in {} days",
+                path, days_remaining
+            ),
+            Event::BanIssued { client, reason } => format!("Client {} banned: {}", client, reason),
+            Event::ErrorRateSpike {
+                error_rate,
+                requests,
+            } => format!(
+                "Error rate spiked to {:.2}% over the last {} requests",
+                error_rate * 100.0,
+                requests
+            ),
+            Event::SloBreached { status } => format!(
+                "SLO breached for upstream {} (latency: {}ms, error rate: {:.2}%)",
+                status.upstream,
+                status.latency_ms,
+                status.error_rate * 100.0
+            ),
+            Event::SloRecovered { status } => {
+                format!("SLO recovered for upstream {}", status.upstream)
+            }
+            Event::ErrorBudgetBurning { status } => format!(
+                "Error budget of {} burning fast (burn rate: {:.1}, budget left: {:.1}%)",
+                status.objective,
+                status.fast_burn_rate,
+                status.budget_remaining * 100.0
+            ),
+            Event::ErrorBudgetRecovered { status } => {
+                format!(
+                    "Error budget of {} no longer burning fast",
+                    status.objective
+                )
+            }
+        }
+    }
+}
+
+/// Delivers events to the configured webhooks with retries and per-webhook rate limiting.
+pub struct Notifier {
+    webhooks: Vec<WebhookConfig>,
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    sent: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl Notifier {
+    /// Creates a notifier for the given webhooks.
+    pub fn new(webhooks: Vec<WebhookConfig>) -> Self {
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Notifier {
+            webhooks,
+            client: Client::builder().build(connector),
+            sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends `event` to every webhook subscribed to its kind, in the background.
+    pub fn notify(self: &Arc<Self>, event: Event) {
+        debug!("Notifying webhooks of event: {}", event.summary());
+        for webhook in &self.webhooks {
+            if webhook.events.is_empty() || webhook.events.contains(&event.kind()) {
+                self.send(webhook.clone(), event.clone());
+            }
+        }
+    }
+
+    /// Sends `event` to a single webhook in the background, regardless of its subscriptions.
+    pub fn send(self: &Arc<Self>, webhook: WebhookConfig, event: Event) {
+        if !self.allow(&webhook) {
+            warn!(
+                "Dropping notification to {}: rate limit of {} per minute reached",
+                webhook.url, webhook.max_per_minute
+            );
+            return;
+        }
+        let notifier = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = notifier.deliver(&webhook, &event).await {
+                error!("Failed to deliver notification to {}: {}", webhook.url, err);
+            }
+        });
+    }
+
+    /// Checks and updates the rate limit of the webhook.
+    fn allow(&self, webhook: &WebhookConfig) -> bool {
+        let now = Instant::now();
+        let mut sent = self.sent.lock().unwrap();
+        let history = sent.entry(webhook.url.clone()).or_default();
+        while let Some(at) = history.front() {
+            if now.duration_since(*at) < Duration::from_secs(60) {
+                break;
+            }
+            history.pop_front();
+        }
+        if history.len() >= webhook.max_per_minute as usize {
+            return false;
+        }
+        history.push_back(now);
+        true
+    }
+
+    /// Delivers the event, retrying with exponential backoff.
+    async fn deliver(&self, webhook: &WebhookConfig, event: &Event) -> Result<()> {
+        let payload = match webhook.format {
+            WebhookFormat::Json => serde_json::to_value(event)?,
+            WebhookFormat::Slack => serde_json::json!({ "text": event.summary() }),
+        };
+        let mut backoff = Duration::from_millis(500);
+        let mut attempt = 0;
+        loop {
+            match post_json(&self.client, &webhook.url, &payload).await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt < webhook.max_retries => {
+                    attempt += 1;
+                    warn!(
+                        "Notification to {} failed (attempt {}): {}",
+                        webhook.url, attempt, err
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// POSTs `payload` as JSON to `url`, failing on transport errors and non-success statuses.
+pub(crate) async fn post_json<C, T>(client: &Client<C, Body>, url: &str, payload: &T) -> Result<()>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+    T: Serialize,
+{
+    let body = serde_json::to_vec(payload).context("Failed to serialize webhook payload")?;
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .context("Failed to build webhook request")?;
+    let response = client
+        .request(req)
+        .await
+        .context(format!("Failed to send webhook to {}", url))?;
+    if !response.status().is_success() {
+        anyhow::bail!("Webhook {} responded with {}", url, response.status());
+    }
+    Ok(())
+}
@@ -0,0 +1,188 @@
+//! OCSP stapling: fetching and caching OCSP responses for the served certificate.
+
+use std::{sync::Mutex, time::Duration};
+
+use anyhow::{Context, Result};
+use hyper::{
+    body::to_bytes, client::HttpConnector, header::CONTENT_TYPE, Body, Client, Method, Request,
+};
+use log::{debug, info};
+use ring::digest;
+use x509_parser::{
+    extensions::{GeneralName, ParsedExtension},
+    oid_registry::OID_PKIX_ACCESS_DESCRIPTOR_OCSP,
+    prelude::{FromDer, X509Certificate},
+};
+
+/// OCSP stapling settings.
+#[derive(Clone, Debug)]
+pub struct OcspConfig {
+    /// How often the OCSP response is refreshed.
+    pub refresh_interval: Duration,
+    /// OCSP responder URL used instead of the one named in the certificate.
+    pub responder_url: Option<String>,
+}
+
+impl Default for OcspConfig {
+    fn default() -> Self {
+        Self {
+            refresh_interval: Duration::from_secs(3600),
+            responder_url: None,
+        }
+    }
+}
+
+/// Holds the latest OCSP response of the served certificate.
+pub struct OcspStapler {
+    config: OcspConfig,
+    client: Client<HttpConnector, Body>,
+    response: Mutex<Option<Vec<u8>>>,
+}
+
+impl OcspStapler {
+    /// Creates a stapler without a response; call [`OcspStapler::refresh`] to fetch one.
+    pub fn new(config: OcspConfig) -> Self {
+        OcspStapler {
+            config,
+            client: Client::new(),
+            response: Mutex::new(None),
+        }
+    }
+
+    /// Returns the interval between refreshes.
+    pub fn refresh_interval(&self) -> Duration {
+        self.config.refresh_interval
+    }
+
+    /// Returns the latest OCSP response, if one was fetched.
+    pub fn response(&self) -> Option<Vec<u8>> {
+        self.response.lock().unwrap().clone()
+    }
+
+    /// Fetches a fresh OCSP response for the first certificate of the PEM file, which must also contain its issuer.
+    pub async fn refresh(&self, certificate_path: &str) -> Result<()> {
+        let file = std::fs::File::open(certificate_path).context("Failed to open cert file")?;
+        let chain = rustls_pemfile::certs(&mut std::io::BufReader::new(file))
+            .context("Failed to read certificate")?;
+        if chain.len() < 2 {
+            anyhow::bail!(
+                "OCSP stapling requires the issuer certificate after the leaf in {}",
+                certificate_path
+            );
+        }
+        let (_, leaf) = X509Certificate::from_der(&chain[0])
+            .map_err(|err| anyhow::anyhow!("Failed to parse certificate: {}", err))?;
+        let (_, issuer) = X509Certificate::from_der(&chain[1])
+            .map_err(|err| anyhow::anyhow!("Failed to parse issuer certificate: {}", err))?;
+
+        let url = match &self.config.responder_url {
+            Some(url) => url.clone(),
+            None => responder_url(&leaf).context("Certificate names no OCSP responder")?,
+        };
+        let request = ocsp_request(&leaf, &issuer);
+        debug!("Fetching OCSP response from {}", url);
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(&url)
+            .header(CONTENT_TYPE, "application/ocsp-request")
+            .body(Body::from(request))
+            .context("Failed to build OCSP request")?;
+        let response = self
+            .client
+            .request(req)
+            .await
+            .context(format!("Failed to reach OCSP responder {}", url))?;
+        if !response.status().is_success() {
+            anyhow::bail!(
+                "OCSP responder {} responded with {}",
+                url,
+                response.status()
+            );
+        }
+        let body = to_bytes(response.into_body())
+            .await
+            .context("Failed to read OCSP response")?;
+        if !is_successful_response(&body) {
+            anyhow::bail!("OCSP responder {} returned an unsuccessful response", url);
+        }
+        info!("Fetched OCSP response ({} bytes) from {}", body.len(), url);
+        *self.response.lock().unwrap() = Some(body.to_vec());
+        Ok(())
+    }
+}
+
+/// Returns the OCSP responder URL from the Authority Information Access extension.
+fn responder_url(certificate: &X509Certificate) -> Option<String> {
+    certificate
+        .iter_extensions()
+        .find_map(|extension| match extension.parsed_extension() {
+            ParsedExtension::AuthorityInfoAccess(aia) => aia
+                .accessdescs
+                .iter()
+                .filter(|desc| desc.access_method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP)
+                .find_map(|desc| match desc.access_location {
+                    GeneralName::URI(uri) => Some(uri.to_string()),
+                    _ => None,
+                }),
+            _ => None,
+        })
+}
+
+/// Builds a DER-encoded `OCSPRequest` for `leaf` with a SHA-1 `CertID`.
+fn ocsp_request(leaf: &X509Certificate, issuer: &X509Certificate) -> Vec<u8> {
+    const SHA1_OID: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+    let sha1 = |data: &[u8]| {
+        digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, data)
+            .as_ref()
+            .to_vec()
+    };
+    let algorithm = der(0x30, &[der(0x06, SHA1_OID), der(0x05, &[])].concat());
+    let cert_id = der(
+        0x30,
+        &[
+            algorithm,
+            der(0x04, &sha1(leaf.tbs_certificate.issuer.as_raw())),
+            der(0x04, &sha1(&issuer.public_key().subject_public_key.data)),
+            der(0x02, leaf.raw_serial()),
+        ]
+        .concat(),
+    );
+    let request = der(0x30, &cert_id);
+    let request_list = der(0x30, &request);
+    let tbs_request = der(0x30, &request_list);
+    der(0x30, &tbs_request)
+}
+
+/// Encodes a DER tag-length-value.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// Whether a DER `OCSPResponse` has the `successful` status and carries a response.
+fn is_successful_response(response: &[u8]) -> bool {
+    if response.first() != Some(&0x30) {
+        return false;
+    }
+    let header_len = match response.get(1) {
+        Some(len) if *len < 0x80 => 2,
+        Some(len) => 2 + (*len & 0x7f) as usize,
+        None => return false,
+    };
+    response.get(header_len..header_len + 3) == Some(&[0x0a, 0x01, 0x00])
+        && response.len() > header_len + 3
+}
@@ -0,0 +1,1015 @@
+//! OpenAPI-driven routing and validation: the operations described by an OpenAPI 3 document are routed to the
+//! upstream serving the API, their requests are checked against the documented parameters and request bodies, and
+//! their outcomes are counted per `operationId`.
+//!
+//! Documents are read as JSON. The base path and upstream of an API come from the first URL of its `servers`, with
+//! server variables replaced by their defaults. In strict mode, requests under the base path that match no documented
+//! operation are rejected with `404 Not Found`, or `405 Method Not Allowed` when only their method is undocumented.
+
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use futures::StreamExt;
+use hyper::{
+    body::Bytes,
+    header::{HeaderValue, CONTENT_TYPE},
+    http::request,
+    Body, Method, StatusCode,
+};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+use url::{form_urlencoded, Url};
+
+use crate::schema::{escape_pointer, JsonSchema, SchemaError};
+
+/// Methods an OpenAPI path item can describe.
+const METHODS: [&str; 8] = [
+    "get", "put", "post", "delete", "options", "head", "patch", "trace",
+];
+/// Number of `$ref` hops followed before giving up, against reference cycles.
+const MAX_REFERENCE_HOPS: usize = 8;
+
+/// An API described by an OpenAPI 3 document.
+#[derive(Clone, Debug)]
+pub struct OpenApiConfig {
+    /// Path of the OpenAPI 3 document, in JSON.
+    pub document_path: String,
+    /// Upstream the documented operations are routed to, such as `http://api:8080` (optional). Defaults to the origin
+    /// of the first server URL of the document when it is absolute; otherwise the operations are routed as any request.
+    pub upstream: Option<String>,
+    /// Whether requests under the base path of the API matching no documented operation are rejected. Defaults to
+    /// `false`.
+    pub strict: bool,
+    /// Whether request bodies are checked against their operations: bodies of undocumented media types are rejected
+    /// with `415 Unsupported Media Type`, and JSON bodies validated against their schemas. Defaults to `true`.
+    pub validate_bodies: bool,
+    /// Largest request body validated; larger ones are rejected with `413 Payload Too Large`. Defaults to 1 MiB.
+    pub max_body_size: usize,
+    /// Number of violations reported per rejected request. Defaults to 10.
+    pub max_errors: usize,
+}
+
+impl Default for OpenApiConfig {
+    fn default() -> Self {
+        Self {
+            document_path: String::new(),
+            upstream: None,
+            strict: false,
+            validate_bodies: true,
+            max_body_size: 1024 * 1024,
+            max_errors: 10,
+        }
+    }
+}
+
+/// Requests to a documented operation.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct OperationStats {
+    /// Title of the API.
+    pub api: String,
+    /// `operationId` of the operation, or its method and path template when it has none.
+    pub operation_id: String,
+    /// Method of the operation.
+    pub method: String,
+    /// Path template of the operation, such as `/users/{id}`.
+    pub path: String,
+    /// Number of requests to the operation.
+    pub requests: u64,
+    /// Number of requests answered with a server error, or failing without a response.
+    pub errors: u64,
+    /// Number of requests rejected for not conforming to the operation.
+    pub rejected: u64,
+    /// Average duration of the requests, in milliseconds.
+    pub average_ms: f64,
+    #[serde(skip)]
+    total_ms: f64,
+}
+
+/// Outcome of checking a request against the APIs.
+#[derive(Debug)]
+pub enum OpenApiVerdict {
+    /// Forward the request with this body, to the upstream of its API if it has one.
+    Forward {
+        body: Body,
+        upstream: Option<String>,
+    },
+    /// Reject the request, undocumented in strict mode, with `404 Not Found` or `405 Method Not Allowed`. The latter
+    /// comes with the documented methods of the path.
+    Undocumented {
+        status: StatusCode,
+        allow: Option<HeaderValue>,
+    },
+    /// Reject the request with `422 Unprocessable Entity`, for these violations.
+    Invalid(Vec<SchemaError>),
+    /// Reject the request with `413 Payload Too Large`.
+    TooLarge,
+    /// Reject the request with `415 Unsupported Media Type`, for a body of a media type its operation does not
+    /// document.
+    UnsupportedMediaType,
+}
+
+/// Identifies a documented operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct OperationKey {
+    api: usize,
+    operation: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Location {
+    Path,
+    Query,
+    Header,
+}
+
+struct Parameter {
+    name: String,
+    location: Location,
+    required: bool,
+    /// JSON Pointer to the schema of the parameter in the document.
+    schema: Option<String>,
+}
+
+struct RequestBody {
+    required: bool,
+    /// Documented media types, lowercased, which can be ranges such as `image/*`.
+    media_types: Vec<String>,
+    /// JSON Pointer to the schema of the JSON content in the document.
+    schema: Option<String>,
+}
+
+/// Outcome of checking a request body.
+enum BodyCheck {
+    Forward(Body),
+    TooLarge,
+    UnsupportedMediaType,
+}
+
+struct Operation {
+    method: Method,
+    regex: Regex,
+    /// Names of the path parameters, in the order of the groups of `regex`.
+    path_names: Vec<String>,
+    parameters: Vec<Parameter>,
+    body: Option<RequestBody>,
+    stats: Mutex<OperationStats>,
+}
+
+struct Api {
+    config: OpenApiConfig,
+    upstream: Option<String>,
+    base_path: String,
+    operations: Vec<Operation>,
+    document: JsonSchema,
+}
+
+/// The configured APIs.
+pub struct OpenApi {
+    apis: Vec<Api>,
+}
+
+impl OpenApi {
+    /// Loads the documents of `configs`.
+    pub fn load(configs: &[OpenApiConfig]) -> Result<Self> {
+        let apis = configs
+            .iter()
+            .map(|config| {
+                let text = std::fs::read_to_string(&config.document_path).context(format!(
+                    "Failed to read OpenAPI document {}",
+                    config.document_path
+                ))?;
+                let document: Value = serde_json::from_str(&text).context(format!(
+                    "OpenAPI document {} is not JSON",
+                    config.document_path
+                ))?;
+                Api::new(config.clone(), document)
+                    .context(format!("Invalid OpenAPI document {}", config.document_path))
+            })
+            .collect::<Result<_>>()?;
+        Ok(OpenApi { apis })
+    }
+
+    /// Returns the API whose base path `path` is under, with the rest of the path.
+    fn api<'a>(&self, path: &'a str) -> Option<(usize, &'a str)> {
+        self.apis.iter().enumerate().find_map(|(index, api)| {
+            let rest = path.strip_prefix(api.base_path.as_str())?;
+            (rest.is_empty() || rest.starts_with('/')).then_some((index, rest))
+        })
+    }
+
+    /// Returns the documented operation of a request with `method` to `path`, if any.
+    pub(crate) fn operation(&self, method: &Method, path: &str) -> Option<OperationKey> {
+        let (api, rest) = self.api(path)?;
+        let operation = self.apis[api].find(method, rest)?.0;
+        Some(OperationKey { api, operation })
+    }
+
+    /// Counts a request to the operation `key` that took `duration`.
+    pub(crate) fn record(&self, key: OperationKey, duration: Duration, success: bool) {
+        let operation = &self.apis[key.api].operations[key.operation];
+        let mut stats = operation.stats.lock().unwrap();
+        stats.requests += 1;
+        if !success {
+            stats.errors += 1;
+        }
+        stats.total_ms += duration.as_secs_f64() * 1000.0;
+        stats.average_ms = stats.total_ms / stats.requests as f64;
+    }
+
+    /// Checks the request with `parts` against the operation it matches, reading its body if it must be validated.
+    pub async fn check(&self, parts: &request::Parts, body: Body) -> Result<OpenApiVerdict> {
+        let forward = |body| OpenApiVerdict::Forward {
+            body,
+            upstream: None,
+        };
+        let (index, rest) = match self.api(parts.uri.path()) {
+            Some(api) => api,
+            None => return Ok(forward(body)),
+        };
+        let api = &self.apis[index];
+        let (operation, values) = match api.find(&parts.method, rest) {
+            Some((index, values)) => (&api.operations[index], values),
+            None if api.config.strict => {
+                let allowed: Vec<&str> = api
+                    .operations
+                    .iter()
+                    .filter(|operation| operation.regex.is_match(rest))
+                    .map(|operation| operation.method.as_str())
+                    .collect();
+                let verdict = if allowed.is_empty() {
+                    OpenApiVerdict::Undocumented {
+                        status: StatusCode::NOT_FOUND,
+                        allow: None,
+                    }
+                } else {
+                    OpenApiVerdict::Undocumented {
+                        status: StatusCode::METHOD_NOT_ALLOWED,
+                        allow: HeaderValue::from_str(&allowed.join(", ")).ok(),
+                    }
+                };
+                return Ok(verdict);
+            }
+            None => return Ok(forward(body)),
+        };
+
+        let mut errors = api.check_parameters(operation, parts, values);
+        let body = match operation
+            .body
+            .as_ref()
+            .filter(|_| api.config.validate_bodies)
+        {
+            Some(spec) => match api.check_body(spec, parts, body, &mut errors).await? {
+                BodyCheck::Forward(body) => body,
+                BodyCheck::TooLarge => {
+                    operation.stats.lock().unwrap().rejected += 1;
+                    return Ok(OpenApiVerdict::TooLarge);
+                }
+                BodyCheck::UnsupportedMediaType => {
+                    operation.stats.lock().unwrap().rejected += 1;
+                    return Ok(OpenApiVerdict::UnsupportedMediaType);
+                }
+            },
+            None => body,
+        };
+        if !errors.is_empty() {
+            operation.stats.lock().unwrap().rejected += 1;
+            errors.truncate(api.config.max_errors);
+            return Ok(OpenApiVerdict::Invalid(errors));
+        }
+        Ok(OpenApiVerdict::Forward {
+            body,
+            upstream: api.upstream.clone(),
+        })
+    }
+
+    /// Returns the requests to every documented operation.
+    pub fn stats(&self) -> Vec<OperationStats> {
+        self.apis
+            .iter()
+            .flat_map(|api| &api.operations)
+            .map(|operation| operation.stats.lock().unwrap().clone())
+            .collect()
+    }
+}
+
+impl Api {
+    fn new(config: OpenApiConfig, document: Value) -> Result<Self> {
+        let version = document
+            .get("openapi")
+            .and_then(Value::as_str)
+            .unwrap_or("");
+        if !version.starts_with("3.") {
+            bail!(
+                "Only OpenAPI 3 documents are supported, not version {:?}",
+                version
+            );
+        }
+        let title = document
+            .pointer("/info/title")
+            .and_then(Value::as_str)
+            .unwrap_or("API")
+            .to_string();
+        let (server_upstream, base_path) = match document.pointer("/servers/0") {
+            Some(server) => server_location(server)?,
+            None => (None, String::new()),
+        };
+        let upstream = config.upstream.clone().or(server_upstream);
+
+        let mut operations = Vec::new();
+        let paths = document.get("paths").and_then(Value::as_object);
+        for (template, item) in paths.into_iter().flatten() {
+            let item_pointer = format!("/paths/{}", escape_pointer(template));
+            let (item_pointer, item) = resolve(&document, &item_pointer, item)?;
+            let (regex, path_names) = compile_template(template)?;
+            let shared = item.get("parameters").and_then(Value::as_array);
+            for method in METHODS {
+                let operation = match item.get(method) {
+                    Some(operation) => operation,
+                    None => continue,
+                };
+                let operation_pointer = format!("{}/{}", item_pointer, method);
+                let mut parameters = Vec::new();
+                // Parameters of the operation override those of the path item with the same name and location
+                let own = operation.get("parameters").and_then(Value::as_array);
+                let sources = [
+                    (own, format!("{}/parameters", operation_pointer)),
+                    (shared, format!("{}/parameters", item_pointer)),
+                ];
+                for (list, list_pointer) in sources {
+                    for (index, parameter) in list.into_iter().flatten().enumerate() {
+                        let pointer = format!("{}/{}", list_pointer, index);
+                        let parameter = match compile_parameter(&document, &pointer, parameter)? {
+                            Some(parameter) => parameter,
+                            None => continue,
+                        };
+                        let overridden = parameters.iter().any(|other: &Parameter| {
+                            other.name == parameter.name && other.location == parameter.location
+                        });
+                        if !overridden {
+                            parameters.push(parameter);
+                        }
+                    }
+                }
+                let body = match operation.get("requestBody") {
+                    Some(body) => {
+                        let pointer = format!("{}/requestBody", operation_pointer);
+                        Some(compile_body(&document, &pointer, body)?)
+                    }
+                    None => None,
+                };
+                let method = Method::from_bytes(method.to_uppercase().as_bytes())?;
+                let operation_id = operation
+                    .get("operationId")
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("{} {}", method, template));
+                operations.push(Operation {
+                    stats: Mutex::new(OperationStats {
+                        api: title.clone(),
+                        operation_id,
+                        method: method.to_string(),
+                        path: template.clone(),
+                        ..Default::default()
+                    }),
+                    method,
+                    regex: regex.clone(),
+                    path_names: path_names.clone(),
+                    parameters,
+                    body,
+                });
+            }
+        }
+        // Concrete paths match before templated ones, so `/users/me` wins over `/users/{id}`
+        operations.sort_by_key(|operation| operation.path_names.len());
+        let document = JsonSchema::new(document)?;
+        Ok(Api {
+            config,
+            upstream,
+            base_path,
+            operations,
+            document,
+        })
+    }
+
+    /// Returns the index of the operation matching `method` and `path`, relative to the base path, with the values of
+    /// its path parameters.
+    fn find(&self, method: &Method, path: &str) -> Option<(usize, Vec<String>)> {
+        self.operations
+            .iter()
+            .enumerate()
+            .filter(|(_, operation)| operation.method == method)
+            .find_map(|(index, operation)| {
+                let captures = operation.regex.captures(path)?;
+                let values = captures
+                    .iter()
+                    .skip(1)
+                    .map(|value| value.map_or("", |value| value.as_str()))
+                    .map(|value| {
+                        percent_encoding::percent_decode_str(value)
+                            .decode_utf8_lossy()
+                            .into_owned()
+                    })
+                    .collect();
+                Some((index, values))
+            })
+    }
+
+    /// Checks the path, query and header parameters of a request against `operation`.
+    fn check_parameters(
+        &self,
+        operation: &Operation,
+        parts: &request::Parts,
+        path_values: Vec<String>,
+    ) -> Vec<SchemaError> {
+        let path_values: HashMap<&str, String> = operation
+            .path_names
+            .iter()
+            .map(String::as_str)
+            .zip(path_values)
+            .collect();
+        let query: Vec<(String, String)> = parts
+            .uri
+            .query()
+            .map(|query| {
+                form_urlencoded::parse(query.as_bytes())
+                    .into_owned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut errors = Vec::new();
+        for parameter in &operation.parameters {
+            let (location, value) = match parameter.location {
+                Location::Path => ("path", path_values.get(parameter.name.as_str()).cloned()),
+                Location::Query => {
+                    let values: Vec<&str> = query
+                        .iter()
+                        .filter(|(name, _)| *name == parameter.name)
+                        .map(|(_, value)| value.as_str())
+                        .collect();
+                    ("query", (!values.is_empty()).then(|| values.join(",")))
+                }
+                Location::Header => (
+                    "header",
+                    parts
+                        .headers
+                        .get(&parameter.name)
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_string),
+                ),
+            };
+            let instance_path = format!("/{}/{}", location, parameter.name);
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    if parameter.required {
+                        errors.push(SchemaError {
+                            instance_path,
+                            keyword: "required",
+                            message: format!("Missing {} parameter {}", location, parameter.name),
+                        });
+                    }
+                    continue;
+                }
+            };
+            if let Some(schema) = &parameter.schema {
+                let value = self.typed(schema, &value);
+                errors.extend(self.document.validate_at(
+                    schema,
+                    &value,
+                    &instance_path,
+                    self.config.max_errors,
+                ));
+            }
+        }
+        errors
+    }
+
+    /// Converts the raw `value` of a parameter to the type of its `schema`, leaving it a string when it is not one.
+    fn typed(&self, schema: &str, value: &str) -> Value {
+        let kind = self
+            .document_value(schema)
+            .and_then(|schema| schema.get("type"))
+            .and_then(Value::as_str);
+        match kind {
+            Some("integer" | "number") => serde_json::from_str::<serde_json::Number>(value)
+                .map(Value::Number)
+                .unwrap_or_else(|_| Value::String(value.to_string())),
+            Some("boolean") => match value {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                _ => Value::String(value.to_string()),
+            },
+            Some("array") => {
+                let items = format!("{}/items", schema);
+                Value::Array(
+                    value
+                        .split(',')
+                        .map(|item| self.typed(&items, item))
+                        .collect(),
+                )
+            }
+            _ => Value::String(value.to_string()),
+        }
+    }
+
+    /// Returns the value at `pointer` of the document, following a `$ref` it holds.
+    fn document_value(&self, pointer: &str) -> Option<&Value> {
+        let value = self.document.root().pointer(pointer)?;
+        match value.get("$ref").and_then(Value::as_str) {
+            Some(reference) => self.document.root().pointer(reference.strip_prefix('#')?),
+            None => Some(value),
+        }
+    }
+
+    /// Checks the media type of the body of a request against `spec` and validates it against its schema if it is
+    /// JSON. Bodies without a media type are taken for JSON.
+    async fn check_body(
+        &self,
+        spec: &RequestBody,
+        parts: &request::Parts,
+        mut body: Body,
+        errors: &mut Vec<SchemaError>,
+    ) -> Result<BodyCheck> {
+        let content_type = match parts.headers.get(CONTENT_TYPE) {
+            Some(content_type) => match content_type.to_str() {
+                Ok(content_type) => Some(content_type),
+                Err(_) => return Ok(BodyCheck::UnsupportedMediaType),
+            },
+            None => None,
+        };
+        if let Some(content_type) = content_type {
+            if !spec.media_types.is_empty() && !documents(&spec.media_types, content_type) {
+                return Ok(BodyCheck::UnsupportedMediaType);
+            }
+            if !is_json(content_type) {
+                return Ok(BodyCheck::Forward(body));
+            }
+        }
+        let mut buffered = Vec::new();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.context("Failed to read request body")?;
+            if buffered.len() + chunk.len() > self.config.max_body_size {
+                return Ok(BodyCheck::TooLarge);
+            }
+            buffered.extend_from_slice(&chunk);
+        }
+        if buffered.is_empty() {
+            if spec.required {
+                errors.push(SchemaError {
+                    instance_path: "/body".to_string(),
+                    keyword: "required",
+                    message: "Missing request body".to_string(),
+                });
+            }
+            return Ok(BodyCheck::Forward(Body::empty()));
+        }
+        match serde_json::from_slice::<Value>(&buffered) {
+            Ok(document) => {
+                if let Some(schema) = &spec.schema {
+                    errors.extend(self.document.validate_at(
+                        schema,
+                        &document,
+                        "/body",
+                        self.config.max_errors,
+                    ));
+                }
+            }
+            Err(err) => errors.push(SchemaError {
+                instance_path: "/body".to_string(),
+                keyword: "json",
+                message: format!("The body is not valid JSON: {}", err),
+            }),
+        }
+        Ok(BodyCheck::Forward(Body::from(Bytes::from(buffered))))
+    }
+}
+
+/// Returns the upstream and base path of an API from its server object.
+fn server_location(server: &Value) -> Result<(Option<String>, String)> {
+    let mut url = server
+        .get("url")
+        .and_then(Value::as_str)
+        .unwrap_or("/")
+        .to_string();
+    let variables = server.get("variables").and_then(Value::as_object);
+    for (name, variable) in variables.into_iter().flatten() {
+        if let Some(default) = variable.get("default").and_then(Value::as_str) {
+            url = url.replace(&format!("{{{}}}", name), default);
+        }
+    }
+    if url.starts_with("http://") || url.starts_with("https://") {
+        let parsed = Url::parse(&url).context(format!("Invalid server URL {}", url))?;
+        let origin = parsed.origin().ascii_serialization();
+        let base_path = parsed.path().trim_end_matches('/').to_string();
+        return Ok((Some(origin), base_path));
+    }
+    Ok((None, url.trim_end_matches('/').to_string()))
+}
+
+/// Compiles a path template such as `/users/{id}` to a regex capturing the values of its parameters, and their names.
+fn compile_template(template: &str) -> Result<(Regex, Vec<String>)> {
+    let mut pattern = String::from("^");
+    let mut names = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => bail!("Unclosed parameter in path {}", template),
+        };
+        pattern.push_str(&regex::escape(&rest[..start]));
+        pattern.push_str("([^/]+)");
+        names.push(rest[start + 1..end].to_string());
+        rest = &rest[end + 1..];
+    }
+    pattern.push_str(&regex::escape(rest));
+    pattern.push('$');
+    let regex = Regex::new(&pattern).context(format!("Invalid path {}", template))?;
+    Ok((regex, names))
+}
+
+/// Compiles the parameter at `pointer`, or returns `None` for cookie parameters, which are not checked.
+fn compile_parameter(
+    document: &Value,
+    pointer: &str,
+    parameter: &Value,
+) -> Result<Option<Parameter>> {
+    let (pointer, parameter) = resolve(document, pointer, parameter)?;
+    let name = parameter
+        .get("name")
+        .and_then(Value::as_str)
+        .context(format!("Parameter {} has no name", pointer))?;
+    let location = match parameter.get("in").and_then(Value::as_str) {
+        Some("path") => Location::Path,
+        Some("query") => Location::Query,
+        Some("header") => Location::Header,
+        Some("cookie") => return Ok(None),
+        location => bail!("Parameter {} has an invalid location {:?}", name, location),
+    };
+    Ok(Some(Parameter {
+        name: name.to_string(),
+        location,
+        // Path parameters are always required
+        required: location == Location::Path
+            || parameter.get("required") == Some(&Value::Bool(true)),
+        schema: parameter
+            .get("schema")
+            .map(|_| format!("{}/schema", pointer)),
+    }))
+}
+
+/// Compiles the request body at `pointer`.
+fn compile_body(document: &Value, pointer: &str, body: &Value) -> Result<RequestBody> {
+    let (pointer, body) = resolve(document, pointer, body)?;
+    let content = body.get("content").and_then(Value::as_object);
+    let media_types = content
+        .into_iter()
+        .flatten()
+        .map(|(media_type, _)| essence(media_type).to_ascii_lowercase())
+        .collect();
+    let schema = content
+        .into_iter()
+        .flatten()
+        .find_map(|(media_type, content)| {
+            (is_json(media_type) && content.get("schema").is_some())
+                .then(|| format!("{}/content/{}/schema", pointer, escape_pointer(media_type)))
+        });
+    Ok(RequestBody {
+        required: body.get("required") == Some(&Value::Bool(true)),
+        media_types,
+        schema,
+    })
+}
+
+/// Follows the `$ref` of `value`, found at `pointer`, returning the pointer and value of its target.
+fn resolve<'a>(
+    document: &'a Value,
+    pointer: &str,
+    mut value: &'a Value,
+) -> Result<(String, &'a Value)> {
+    let mut pointer = pointer.to_string();
+    for _ in 0..MAX_REFERENCE_HOPS {
+        let reference = match value.get("$ref").and_then(Value::as_str) {
+            Some(reference) => reference,
+            None => return Ok((pointer, value)),
+        };
+        let target = reference.strip_prefix('#').context(format!(
+            "Only local references are supported, not {}",
+            reference
+        ))?;
+        value = document
+            .pointer(target)
+            .context(format!("Unresolvable reference {}", reference))?;
+        pointer = target.to_string();
+    }
+    bail!("Too many references from {}", pointer)
+}
+
+/// Whether `media_type` is JSON, such as `application/json` or `application/problem+json`.
+fn is_json(media_type: &str) -> bool {
+    let essence = essence(media_type);
+    essence.eq_ignore_ascii_case("application/json") || essence.ends_with("+json")
+}
+
+/// Returns the type and subtype of `media_type`, without its parameters.
+fn essence(media_type: &str) -> &str {
+    media_type.split(';').next().unwrap_or("").trim()
+}
+
+/// Whether `media_type` is one of the documented `media_types`, or in one of their ranges.
+fn documents(media_types: &[String], media_type: &str) -> bool {
+    let essence = essence(media_type).to_ascii_lowercase();
+    media_types.iter().any(|documented| {
+        documented == "*/*"
+            || *documented == essence
+            || documented
+                .strip_suffix('*')
+                .is_some_and(|range| range.ends_with('/') && essence.starts_with(range))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Request;
+    use serde_json::json;
+
+    use super::*;
+
+    fn document() -> Value {
+        json!({
+            "openapi": "3.0.3",
+            "info": { "title": "Users" },
+            "servers": [{ "url": "https://{host}/v1/", "variables": { "host": { "default": "api.example.com" } } }],
+            "paths": {
+                "/users/{id}": {
+                    "parameters": [{ "name": "id", "in": "path", "schema": { "type": "integer", "minimum": 1 } }],
+                    "get": { "operationId": "getUser" },
+                    "delete": {}
+                },
+                "/users/me": { "get": { "operationId": "getMe" } },
+                "/users": {
+                    "get": {
+                        "parameters": [
+                            { "$ref": "#/components/parameters/limit" },
+                            { "name": "ids", "in": "query", "schema": { "type": "array", "items": { "type": "integer" } } },
+                            { "name": "x-tenant", "in": "header", "required": true, "schema": { "type": "string" } },
+                            { "name": "session", "in": "cookie", "required": true }
+                        ]
+                    },
+                    "post": {
+                        "operationId": "createUser",
+                        "requestBody": {
+                            "required": true,
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/User" } } }
+                        }
+                    }
+                }
+            },
+            "components": {
+                "parameters": {
+                    "limit": { "name": "limit", "in": "query", "required": true, "schema": { "$ref": "#/components/schemas/Limit" } }
+                },
+                "schemas": {
+                    "Limit": { "type": "integer", "maximum": 100 },
+                    "User": { "type": "object", "required": ["name"], "properties": { "name": { "type": "string" } } }
+                }
+            }
+        })
+    }
+
+    fn open_api(strict: bool) -> OpenApi {
+        let config = OpenApiConfig {
+            strict,
+            max_body_size: 64,
+            ..Default::default()
+        };
+        OpenApi {
+            apis: vec![Api::new(config, document()).unwrap()],
+        }
+    }
+
+    /// Checks a `method` request to `uri` with `headers` and `body`, returning the verdict.
+    async fn check(
+        open_api: &OpenApi,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> OpenApiVerdict {
+        let mut request = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let parts = request.body(()).unwrap().into_parts().0;
+        open_api
+            .check(&parts, Body::from(body.to_string()))
+            .await
+            .unwrap()
+    }
+
+    /// Returns the instance paths of the violations of `verdict`, or `None` if it is not `Invalid`.
+    fn violations(verdict: OpenApiVerdict) -> Option<Vec<String>> {
+        match verdict {
+            OpenApiVerdict::Invalid(errors) => Some(
+                errors
+                    .into_iter()
+                    .map(|error| format!("{} {}", error.keyword, error.instance_path))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn routes_operations() {
+        let open_api = open_api(false);
+        let api = &open_api.apis[0];
+        assert_eq!(api.base_path, "/v1");
+        assert_eq!(api.upstream.as_deref(), Some("https://api.example.com"));
+        let operation_id = |method, path| {
+            let key = open_api.operation(&method, path)?;
+            let stats = open_api.apis[key.api].operations[key.operation]
+                .stats
+                .lock()
+                .unwrap()
+                .operation_id
+                .clone();
+            Some(stats)
+        };
+        // Concrete paths win over templated ones
+        assert_eq!(
+            operation_id(Method::GET, "/v1/users/me").as_deref(),
+            Some("getMe")
+        );
+        assert_eq!(
+            operation_id(Method::GET, "/v1/users/7").as_deref(),
+            Some("getUser")
+        );
+        assert_eq!(
+            operation_id(Method::DELETE, "/v1/users/7").as_deref(),
+            Some("DELETE /users/{id}")
+        );
+        assert_eq!(operation_id(Method::GET, "/v1/users/7/x"), None);
+        assert_eq!(operation_id(Method::GET, "/v1x/users"), None);
+        assert_eq!(operation_id(Method::GET, "/users/7"), None);
+        let (_, values) = api.find(&Method::GET, "/users/a%20b").unwrap();
+        assert_eq!(values, vec!["a b"]);
+    }
+
+    #[tokio::test]
+    async fn rejects_undocumented_requests_in_strict_mode() {
+        let lenient = open_api(false);
+        let verdict = check(&lenient, Method::PUT, "/v1/users/1", &[], "").await;
+        assert!(matches!(
+            verdict,
+            OpenApiVerdict::Forward { upstream: None, .. }
+        ));
+
+        let strict = open_api(true);
+        match check(&strict, Method::PUT, "/v1/users/1", &[], "").await {
+            OpenApiVerdict::Undocumented { status, allow } => {
+                assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+                assert_eq!(allow.unwrap(), "GET, DELETE");
+            }
+            verdict => panic!("Unexpected verdict {:?}", verdict),
+        }
+        match check(&strict, Method::GET, "/v1/groups", &[], "").await {
+            OpenApiVerdict::Undocumented { status, allow } => {
+                assert_eq!(status, StatusCode::NOT_FOUND);
+                assert!(allow.is_none());
+            }
+            verdict => panic!("Unexpected verdict {:?}", verdict),
+        }
+        // Paths outside the base path are not part of the API
+        let verdict = check(&strict, Method::GET, "/other", &[], "").await;
+        assert!(matches!(
+            verdict,
+            OpenApiVerdict::Forward { upstream: None, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn checks_parameters() {
+        let open_api = open_api(false);
+        let tenant = [("x-tenant", "acme")];
+        let verdict = check(
+            &open_api,
+            Method::GET,
+            "/v1/users?limit=10&ids=1,2",
+            &tenant,
+            "",
+        )
+        .await;
+        match verdict {
+            OpenApiVerdict::Forward { upstream, .. } => {
+                assert_eq!(upstream.as_deref(), Some("https://api.example.com"))
+            }
+            verdict => panic!("Unexpected verdict {:?}", verdict),
+        }
+        assert_eq!(
+            violations(check(&open_api, Method::GET, "/v1/users?ids=1,x", &[], "").await),
+            Some(vec![
+                "required /query/limit".to_string(),
+                "type /query/ids/1".to_string(),
+                "required /header/x-tenant".to_string(),
+            ])
+        );
+        assert_eq!(
+            violations(check(&open_api, Method::GET, "/v1/users?limit=101", &tenant, "").await),
+            Some(vec!["maximum /query/limit".to_string()])
+        );
+        assert_eq!(
+            violations(check(&open_api, Method::GET, "/v1/users/0", &[], "").await),
+            Some(vec!["minimum /path/id".to_string()])
+        );
+        assert_eq!(
+            violations(check(&open_api, Method::GET, "/v1/users/abc", &[], "").await),
+            Some(vec!["type /path/id".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn validates_bodies() {
+        let open_api = open_api(false);
+        let json = [("content-type", "application/json")];
+        let verdict = check(
+            &open_api,
+            Method::POST,
+            "/v1/users",
+            &json,
+            r#"{"name":"ann"}"#,
+        )
+        .await;
+        match verdict {
+            OpenApiVerdict::Forward { body, .. } => {
+                let body = hyper::body::to_bytes(body).await.unwrap();
+                assert_eq!(body, r#"{"name":"ann"}"#);
+            }
+            verdict => panic!("Unexpected verdict {:?}", verdict),
+        }
+        assert_eq!(
+            violations(check(&open_api, Method::POST, "/v1/users", &json, "{}").await),
+            Some(vec!["required /body".to_string()])
+        );
+        assert_eq!(
+            violations(check(&open_api, Method::POST, "/v1/users", &json, "").await),
+            Some(vec!["required /body".to_string()])
+        );
+        assert_eq!(
+            violations(check(&open_api, Method::POST, "/v1/users", &json, "{").await),
+            Some(vec!["json /body".to_string()])
+        );
+        let large = format!(r#"{{"name":"{}"}}"#, "a".repeat(64));
+        let verdict = check(&open_api, Method::POST, "/v1/users", &json, &large).await;
+        assert!(matches!(verdict, OpenApiVerdict::TooLarge));
+        // Bodies of undocumented media types are rejected
+        let text = [("content-type", "text/plain")];
+        let verdict = check(&open_api, Method::POST, "/v1/users", &text, "{").await;
+        assert!(matches!(verdict, OpenApiVerdict::UnsupportedMediaType));
+
+        let stats = open_api.stats();
+        let create = stats
+            .iter()
+            .find(|stats| stats.operation_id == "createUser")
+            .unwrap();
+        assert_eq!(create.rejected, 5);
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        let config = OpenApiConfig::default();
+        let mut swagger = document();
+        swagger["openapi"] = json!("2.0");
+        assert!(Api::new(config.clone(), swagger).is_err());
+
+        let mut cycle = document();
+        cycle["components"]["parameters"]["limit"] =
+            json!({ "$ref": "#/components/parameters/limit" });
+        assert!(Api::new(config.clone(), cycle).is_err());
+
+        let mut remote = document();
+        remote["components"]["parameters"]["limit"] = json!({ "$ref": "other.json#/limit" });
+        assert!(Api::new(config.clone(), remote).is_err());
+
+        let mut unclosed = document();
+        unclosed["paths"]["/groups/{id"] = json!({ "get": {} });
+        assert!(Api::new(config, unclosed).is_err());
+    }
+
+    #[test]
+    fn recognizes_json_media_types() {
+        assert!(is_json("application/json; charset=utf-8"));
+        assert!(is_json("application/problem+json"));
+        assert!(!is_json("text/json-ish"));
+    }
+
+    #[test]
+    fn matches_documented_media_types() {
+        let documented = ["application/json".to_string(), "image/*".to_string()];
+        assert!(documents(&documented, "Application/JSON; charset=utf-8"));
+        assert!(documents(&documented, "image/png"));
+        assert!(!documents(&documented, "text/plain"));
+        assert!(!documents(&documented, "imagex/png"));
+        assert!(documents(&["*/*".to_string()], "text/plain"));
+    }
+}
@@ -0,0 +1,160 @@
+//! Certificate and SPKI pinning of upstream TLS connections, refusing origins whose certificate chain matches none
+//! of the pins of their host, so a compromised or coerced CA cannot intercept traffic to sensitive backends.
+
+use std::{sync::Arc, time::SystemTime};
+
+use anyhow::Result;
+use log::warn;
+use ring::digest;
+use tokio_rustls::rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, ClientConfig, Error, OwnedTrustAnchor, RootCertStore, ServerName,
+};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::{
+    canary::{hex, unhex},
+    tunnel,
+};
+
+/// A pin, the hex-encoded SHA-256 digest of a certificate or of its public key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Pin {
+    /// Digest of a whole DER-encoded certificate, which changes whenever the certificate is renewed.
+    Certificate(String),
+    /// Digest of the DER-encoded SubjectPublicKeyInfo of a certificate, which survives renewals keeping the key.
+    Spki(String),
+}
+
+/// Pins of the upstreams of a host.
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamPins {
+    /// Host name, or wildcard such as `*.example.com`, of the upstreams.
+    pub host: String,
+    /// Accepted pins; a connection is refused unless a certificate of the chain matches one of them.
+    pub pins: Vec<Pin>,
+    /// Whether a chain matching a pin is accepted without being validated against the web PKI roots, for origins
+    /// with self-signed or private CA certificates. Defaults to `false`.
+    pub trust_pinned_only: bool,
+}
+
+/// Returns the TLS settings of upstream connections, checking the chains of pinned hosts against their pins.
+pub(crate) fn client_config(pins: &[UpstreamPins]) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    let builder = ClientConfig::builder().with_safe_defaults();
+    if pins.is_empty() {
+        return Ok(builder.with_root_certificates(roots).with_no_client_auth());
+    }
+    for upstream in pins {
+        if upstream.pins.is_empty() {
+            anyhow::bail!("No pins configured for upstream host {}", upstream.host);
+        }
+        for pin in &upstream.pins {
+            let (Pin::Certificate(digest) | Pin::Spki(digest)) = pin;
+            if unhex(digest).map(|digest| digest.len()) != Some(32) {
+                anyhow::bail!(
+                    "Invalid pin {} for upstream host {}: expected a hex-encoded SHA-256 digest",
+                    digest,
+                    upstream.host
+                );
+            }
+        }
+    }
+    let verifier = PinningVerifier {
+        roots: WebPkiVerifier::new(roots, None),
+        pins: pins.to_vec(),
+    };
+    Ok(builder
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+/// Validates upstream certificates against the web PKI roots and the pins of their host.
+struct PinningVerifier {
+    roots: WebPkiVerifier,
+    pins: Vec<UpstreamPins>,
+}
+
+impl PinningVerifier {
+    /// Whether a certificate of the chain matches one of `pins`.
+    fn matches(pins: &[Pin], chain: &[&Certificate]) -> bool {
+        chain.iter().any(|certificate| {
+            let certificate_digest = hex(digest::digest(&digest::SHA256, &certificate.0).as_ref());
+            let spki_digest = X509Certificate::from_der(&certificate.0)
+                .ok()
+                .map(|(_, parsed)| {
+                    hex(digest::digest(&digest::SHA256, parsed.public_key().raw).as_ref())
+                });
+            pins.iter().any(|pin| match pin {
+                Pin::Certificate(digest) => digest.eq_ignore_ascii_case(&certificate_digest),
+                Pin::Spki(digest) => spki_digest
+                    .as_ref()
+                    .is_some_and(|spki_digest| digest.eq_ignore_ascii_case(spki_digest)),
+            })
+        })
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let host = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_string(),
+            ServerName::IpAddress(ip) => ip.to_string(),
+            _ => String::new(),
+        };
+        let pinned = self
+            .pins
+            .iter()
+            .find(|upstream| tunnel::host_matches(&upstream.host, &host));
+        let upstream = match pinned {
+            Some(upstream) => upstream,
+            None => {
+                return self.roots.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    scts,
+                    ocsp_response,
+                    now,
+                )
+            }
+        };
+        if !upstream.trust_pinned_only {
+            self.roots.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+        let chain: Vec<&Certificate> = std::iter::once(end_entity).chain(intermediates).collect();
+        if !Self::matches(&upstream.pins, &chain) {
+            warn!(
+                "Refused upstream {}: no certificate of its chain matches its pins",
+                host
+            );
+            return Err(Error::General(format!(
+                "certificate chain of {} matches none of its pins",
+                host
+            )));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
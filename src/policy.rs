@@ -0,0 +1,576 @@
+//! Declarative request policies: ordered rules matching requests by host, path, method, headers, User-Agent, client
+//! address and user, and applying actions to them — routing, path rewriting, header rules, blocking, rate limiting
+//! and cache overrides — in one place instead of the dedicated settings of each feature.
+
+use std::{net::IpAddr, time::Duration};
+
+use anyhow::{Context, Result};
+use hyper::{
+    header::{HeaderName, HeaderValue, HOST, USER_AGENT},
+    http::request,
+    Method, StatusCode, Uri,
+};
+use log::{debug, warn};
+use regex::Regex;
+
+use crate::{
+    rate_limit::{RateLimitConfig, RateLimitDecision, RateLimiter},
+    tenant::strip_port,
+    tunnel,
+    user_agent::{self, UserAgentMatch},
+    ClientInfo,
+};
+
+/// Condition on a request header.
+#[derive(Clone, Debug)]
+pub struct HeaderCondition {
+    /// Name of the header.
+    pub name: String,
+    /// Regular expression the header value must match; the header only has to be present when `None`.
+    pub pattern: Option<String>,
+}
+
+/// Conditions a request must all meet for a rule to apply. Empty conditions match every request.
+#[derive(Clone, Debug, Default)]
+pub struct PolicyMatch {
+    /// Host names, or wildcards such as `*.example.com`, one of which the `Host` header must match.
+    pub hosts: Vec<String>,
+    /// Prefix of the request path.
+    pub path_prefix: Option<String>,
+    /// Regular expression the request path must match.
+    pub path_regex: Option<String>,
+    /// Methods one of which the request must use.
+    pub methods: Vec<Method>,
+    /// Conditions on the request headers, all of which must hold.
+    pub headers: Vec<HeaderCondition>,
+    /// Regular expressions or categories, one of which the `User-Agent` header must match.
+    pub user_agents: Vec<UserAgentMatch>,
+    /// Addresses or CIDR networks, such as `10.0.0.0/8`, one of which must contain the client address.
+    pub client_networks: Vec<String>,
+    /// User names, one of which the client must have authenticated as through the Basic credentials of its tenant.
+    pub users: Vec<String>,
+    /// JA3 hashes or JA4 fingerprints, one of which the TLS ClientHello of the client must have. Only requests over
+    /// TLS terminated by the proxy have a fingerprint.
+    pub tls_fingerprints: Vec<String>,
+    /// Akamai HTTP/2 fingerprints or their hashes, one of which the client must have. Only HTTP/2 requests have a
+    /// fingerprint.
+    pub http2_fingerprints: Vec<String>,
+    /// Whether only the HTTP/2 requests of clients flagged as anomalous match. Defaults to false.
+    pub http2_anomalous: bool,
+}
+
+/// How a rule overrides the caching of the requests it matches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheOverride {
+    /// Neither serves the request from the cache nor caches its response.
+    Bypass,
+    /// Caches the response even when a User-Agent rule bypasses the cache, as long as `cache_enabled` is `true`.
+    Force,
+}
+
+/// What a rule does to the requests it matches.
+#[derive(Clone, Debug)]
+pub enum PolicyAction {
+    /// Forwards the request to the given target address.
+    Route(String),
+    /// Replaces the leading `prefix` of the request path with `replacement`, which must start with `/`.
+    RewritePath {
+        /// Prefix of the paths to rewrite.
+        prefix: String,
+        /// Replacement of the prefix.
+        replacement: String,
+    },
+    /// Sets a request header, replacing its existing values.
+    SetHeader {
+        /// Name of the header.
+        name: String,
+        /// Value of the header.
+        value: String,
+    },
+    /// Removes a request header.
+    RemoveHeader(String),
+    /// Answers the request with the given client or server error status instead of forwarding it.
+    Block(u16),
+    /// Limits the requests of each client matching the rule, apart from every other limit.
+    RateLimit(RateLimitConfig),
+    /// Overrides whether the request is cached.
+    Cache(CacheOverride),
+}
+
+/// A rule applying actions to the requests meeting its conditions.
+#[derive(Clone, Debug, Default)]
+pub struct PolicyRule {
+    /// Name of the rule, used in logs and rate limit keys.
+    pub name: String,
+    /// Conditions on the requests the rule applies to.
+    pub conditions: PolicyMatch,
+    /// Actions applied in order to matching requests.
+    pub actions: Vec<PolicyAction>,
+    /// Whether the following rules are still evaluated after this one matched. Defaults to `false`, so the first
+    /// matching rule applies alone.
+    pub fallthrough: bool,
+}
+
+/// Why a request was rejected by a policy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PolicyRejection {
+    /// A rule blocked the request with the given status.
+    Blocked {
+        /// Name of the rule.
+        rule: String,
+        /// Status answered to the client.
+        status: StatusCode,
+    },
+    /// The rate limit of a rule is exhausted; the client may retry after the given delay.
+    RateLimited {
+        /// Name of the rule.
+        rule: String,
+        /// Delay after which the client may retry.
+        retry_after: Duration,
+    },
+}
+
+/// Outcome of evaluating the policies for a request, whose path and headers were already modified by the rules.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PolicyDecision {
+    /// Names of the rules that matched, in order.
+    pub matched: Vec<String>,
+    /// Target address the request must be forwarded to, if overridden.
+    pub route: Option<String>,
+    /// Override of the caching of the request, if any.
+    pub cache: Option<CacheOverride>,
+    /// Why the request must be rejected, if it must.
+    pub rejection: Option<PolicyRejection>,
+}
+
+/// An address network of a condition.
+pub(crate) struct Network {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    /// Parses an address, or a CIDR network such as `10.0.0.0/8`.
+    pub(crate) fn parse(network: &str) -> Result<Self> {
+        let (address, prefix_len) = match network.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (network, None),
+        };
+        let address: IpAddr = address
+            .trim()
+            .parse()
+            .context(format!("Invalid network address: {}", network))?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .context(format!("Invalid network prefix length: {}", network))?,
+            None => max_len,
+        };
+        Ok(Network {
+            address,
+            prefix_len,
+        })
+    }
+
+    /// Returns the address of the network.
+    pub(crate) fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    /// Returns the length of the prefix of the network, in bits.
+    pub(crate) fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether `address` is in the network.
+    pub(crate) fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack listener show up as mapped IPv6 addresses
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+            IpAddr::V4(_) => address,
+        };
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+struct CompiledMatch {
+    hosts: Vec<String>,
+    path_prefix: Option<String>,
+    path_regex: Option<Regex>,
+    methods: Vec<Method>,
+    headers: Vec<(HeaderName, Option<Regex>)>,
+    user_agents: Vec<user_agent::CompiledMatch>,
+    client_networks: Vec<Network>,
+    users: Vec<String>,
+    tls_fingerprints: Vec<String>,
+    http2_fingerprints: Vec<String>,
+    http2_anomalous: bool,
+}
+
+impl CompiledMatch {
+    fn new(conditions: &PolicyMatch) -> Result<Self> {
+        Ok(CompiledMatch {
+            hosts: conditions.hosts.clone(),
+            path_prefix: conditions.path_prefix.clone(),
+            path_regex: conditions
+                .path_regex
+                .as_deref()
+                .map(|pattern| {
+                    Regex::new(pattern).context(format!("Invalid path regex: {}", pattern))
+                })
+                .transpose()?,
+            methods: conditions.methods.clone(),
+            headers: conditions
+                .headers
+                .iter()
+                .map(|condition| {
+                    let name = HeaderName::from_bytes(condition.name.as_bytes())
+                        .context(format!("Invalid header name: {}", condition.name))?;
+                    let pattern = condition
+                        .pattern
+                        .as_deref()
+                        .map(|pattern| {
+                            Regex::new(pattern)
+                                .context(format!("Invalid header regex: {}", pattern))
+                        })
+                        .transpose()?;
+                    Ok((name, pattern))
+                })
+                .collect::<Result<_>>()?,
+            user_agents: conditions
+                .user_agents
+                .iter()
+                .map(user_agent::CompiledMatch::new)
+                .collect::<Result<_>>()?,
+            client_networks: conditions
+                .client_networks
+                .iter()
+                .map(|network| Network::parse(network))
+                .collect::<Result<_>>()?,
+            users: conditions.users.clone(),
+            tls_fingerprints: conditions.tls_fingerprints.clone(),
+            http2_fingerprints: conditions.http2_fingerprints.clone(),
+            http2_anomalous: conditions.http2_anomalous,
+        })
+    }
+
+    fn matches(&self, parts: &request::Parts, client: &ClientInfo, user: Option<&str>) -> bool {
+        if !self.hosts.is_empty() {
+            let host = parts
+                .headers
+                .get(HOST)
+                .and_then(|host| host.to_str().ok())
+                .or_else(|| parts.uri.host())
+                .map(strip_port);
+            let matched = host.is_some_and(|host| {
+                self.hosts
+                    .iter()
+                    .any(|pattern| tunnel::host_matches(pattern, host))
+            });
+            if !matched {
+                return false;
+            }
+        }
+        let path = parts.uri.path();
+        if self
+            .path_prefix
+            .as_deref()
+            .is_some_and(|prefix| !path.starts_with(prefix))
+            || self
+                .path_regex
+                .as_ref()
+                .is_some_and(|regex| !regex.is_match(path))
+        {
+            return false;
+        }
+        if !self.methods.is_empty() && !self.methods.contains(&parts.method) {
+            return false;
+        }
+        let headers_match = self.headers.iter().all(|(name, pattern)| {
+            let mut values = parts.headers.get_all(name).iter();
+            match pattern {
+                Some(pattern) => {
+                    values.any(|value| value.to_str().is_ok_and(|value| pattern.is_match(value)))
+                }
+                None => values.next().is_some(),
+            }
+        });
+        if !headers_match {
+            return false;
+        }
+        if !self.user_agents.is_empty() {
+            let user_agent = parts
+                .headers
+                .get(USER_AGENT)
+                .and_then(|user_agent| user_agent.to_str().ok());
+            if !self
+                .user_agents
+                .iter()
+                .any(|matcher| matcher.matches(user_agent))
+            {
+                return false;
+            }
+        }
+        if !self.client_networks.is_empty()
+            && !self
+                .client_networks
+                .iter()
+                .any(|network| network.contains(client.addr.ip()))
+        {
+            return false;
+        }
+        if !self.tls_fingerprints.is_empty()
+            && !client.tls_fingerprint.as_ref().is_some_and(|fingerprint| {
+                self.tls_fingerprints
+                    .iter()
+                    .any(|expected| fingerprint.matches(expected))
+            })
+        {
+            return false;
+        }
+        let http2 = client.http2_fingerprint.as_ref();
+        if !self.http2_fingerprints.is_empty()
+            && !http2.is_some_and(|fingerprint| {
+                self.http2_fingerprints
+                    .iter()
+                    .any(|expected| fingerprint.matches(expected))
+            })
+        {
+            return false;
+        }
+        if self.http2_anomalous && http2.is_none_or(|fingerprint| fingerprint.anomalies.is_empty())
+        {
+            return false;
+        }
+        self.users.is_empty()
+            || user.is_some_and(|user| self.users.iter().any(|expected| expected == user))
+    }
+}
+
+enum CompiledAction {
+    Route(String),
+    RewritePath { prefix: String, replacement: String },
+    SetHeader(HeaderName, HeaderValue),
+    RemoveHeader(HeaderName),
+    Block(StatusCode),
+    RateLimit(Box<RateLimiter>),
+    Cache(CacheOverride),
+}
+
+impl CompiledAction {
+    fn new(action: &PolicyAction) -> Result<Self> {
+        Ok(match action {
+            PolicyAction::Route(target) => {
+                if target.is_empty() {
+                    anyhow::bail!("The target address of a route must not be empty");
+                }
+                CompiledAction::Route(target.clone())
+            }
+            PolicyAction::RewritePath {
+                prefix,
+                replacement,
+            } => {
+                if !prefix.starts_with('/') || !replacement.starts_with('/') {
+                    anyhow::bail!(
+                        "Invalid path rewrite from {} to {}: both must start with /",
+                        prefix,
+                        replacement
+                    );
+                }
+                CompiledAction::RewritePath {
+                    prefix: prefix.clone(),
+                    replacement: replacement.clone(),
+                }
+            }
+            PolicyAction::SetHeader { name, value } => CompiledAction::SetHeader(
+                HeaderName::from_bytes(name.as_bytes())
+                    .context(format!("Invalid header name: {}", name))?,
+                HeaderValue::from_str(value)
+                    .context(format!("Invalid value of header {}", name))?,
+            ),
+            PolicyAction::RemoveHeader(name) => CompiledAction::RemoveHeader(
+                HeaderName::from_bytes(name.as_bytes())
+                    .context(format!("Invalid header name: {}", name))?,
+            ),
+            PolicyAction::Block(status) => CompiledAction::Block(
+                StatusCode::from_u16(*status)
+                    .ok()
+                    .filter(|status| status.is_client_error() || status.is_server_error())
+                    .context(format!("Invalid block status: {}", status))?,
+            ),
+            PolicyAction::RateLimit(config) => {
+                CompiledAction::RateLimit(Box::new(RateLimiter::new(config.clone())?))
+            }
+            PolicyAction::Cache(cache) => CompiledAction::Cache(*cache),
+        })
+    }
+}
+
+struct CompiledRule {
+    name: String,
+    conditions: CompiledMatch,
+    actions: Vec<CompiledAction>,
+    fallthrough: bool,
+}
+
+/// Policy rules with their conditions and actions compiled.
+#[derive(Default)]
+pub struct Policies {
+    rules: Vec<CompiledRule>,
+}
+
+impl Policies {
+    /// Compiles the rules, failing on unnamed or duplicate rules, invalid regular expressions, networks, headers or
+    /// statuses.
+    pub fn new(rules: &[PolicyRule]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| {
+                if rule.name.is_empty() {
+                    anyhow::bail!("Policy rule {} has no name", index);
+                }
+                if rules[..index].iter().any(|other| other.name == rule.name) {
+                    anyhow::bail!("Duplicate policy rule name: {}", rule.name);
+                }
+                let context = || format!("Invalid policy rule {}", rule.name);
+                Ok(CompiledRule {
+                    name: rule.name.clone(),
+                    conditions: CompiledMatch::new(&rule.conditions).with_context(context)?,
+                    actions: rule
+                        .actions
+                        .iter()
+                        .map(CompiledAction::new)
+                        .collect::<Result<_>>()
+                        .with_context(context)?,
+                    fallthrough: rule.fallthrough,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Policies { rules })
+    }
+
+    /// Whether any rule is configured.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Evaluates the rules in order against a request from `client`, authenticated as `user` if any, applying their
+    /// rewrites and header rules to `parts`. Later rules see the request as modified by earlier ones.
+    ///
+    /// The first route and cache override win; evaluation stops at the first block or exhausted rate limit.
+    pub async fn evaluate(
+        &self,
+        parts: &mut request::Parts,
+        client: &ClientInfo,
+        user: Option<&str>,
+    ) -> PolicyDecision {
+        let mut decision = PolicyDecision::default();
+        for rule in &self.rules {
+            if !rule.conditions.matches(parts, client, user) {
+                continue;
+            }
+            debug!(
+                "Policy rule {} matched {} {}",
+                rule.name, parts.method, parts.uri
+            );
+            decision.matched.push(rule.name.clone());
+            for action in &rule.actions {
+                match action {
+                    CompiledAction::Route(target) => {
+                        decision.route.get_or_insert_with(|| target.clone());
+                    }
+                    CompiledAction::RewritePath {
+                        prefix,
+                        replacement,
+                    } => match rewrite_path(&parts.uri, prefix, replacement) {
+                        Some(Ok(uri)) => parts.uri = uri,
+                        Some(Err(err)) => warn!(
+                            "Policy rule {} produced an invalid URI from {}: {}",
+                            rule.name, parts.uri, err
+                        ),
+                        None => {}
+                    },
+                    CompiledAction::SetHeader(name, value) => {
+                        parts.headers.insert(name.clone(), value.clone());
+                    }
+                    CompiledAction::RemoveHeader(name) => {
+                        parts.headers.remove(name);
+                    }
+                    CompiledAction::Block(status) => {
+                        decision.rejection = Some(PolicyRejection::Blocked {
+                            rule: rule.name.clone(),
+                            status: *status,
+                        });
+                        return decision;
+                    }
+                    CompiledAction::RateLimit(limiter) => {
+                        // Rules keep their limits apart, also when they share a Redis
+                        let key = format!(
+                            "policy:{}:{}",
+                            rule.name,
+                            limiter.key(parts, client.addr.ip())
+                        );
+                        if let RateLimitDecision::Limited(retry_after) = limiter.check(&key).await {
+                            decision.rejection = Some(PolicyRejection::RateLimited {
+                                rule: rule.name.clone(),
+                                retry_after,
+                            });
+                            return decision;
+                        }
+                    }
+                    CompiledAction::Cache(cache) => {
+                        decision.cache.get_or_insert(*cache);
+                    }
+                }
+            }
+            if !rule.fallthrough {
+                break;
+            }
+        }
+        decision
+    }
+}
+
+/// Replaces the leading `prefix` of the path of `uri`, keeping its query. Returns `None` when the path does not
+/// start with `prefix`.
+fn rewrite_path(
+    uri: &Uri,
+    prefix: &str,
+    replacement: &str,
+) -> Option<Result<Uri, hyper::http::Error>> {
+    let rest = uri.path().strip_prefix(prefix)?;
+    let mut path_and_query = format!("{}{}", replacement, rest);
+    if let Some(query) = uri.query() {
+        path_and_query.push('?');
+        path_and_query.push_str(query);
+    }
+    let mut parts = uri.clone().into_parts();
+    Some(
+        path_and_query
+            .parse()
+            .map_err(hyper::http::Error::from)
+            .and_then(|path_and_query| {
+                parts.path_and_query = Some(path_and_query);
+                Uri::from_parts(parts).map_err(hyper::http::Error::from)
+            }),
+    )
+}
@@ -0,0 +1,186 @@
+//! Priority classes of requests, weighing how the connections of a saturated upstream are shared among the requests
+//! waiting for them.
+//!
+//! Requests are classified by the first rule matching their path, headers or user, and fall back to the default class.
+//! When an upstream is at its connection limit, its queue is served by weighted fair queuing: every class gets a share
+//! of the freed connections proportional to its weight, so latency-sensitive API calls keep flowing behind a backlog of
+//! bulk downloads, while no class is starved.
+
+use anyhow::{Context, Result};
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    http::request,
+};
+
+/// A priority class and its share of the connections.
+#[derive(Clone, Debug)]
+pub struct PriorityClass {
+    /// Name of the class.
+    pub name: String,
+    /// Weight of the class against the other ones; a class of weight 4 gets four connections for every connection of
+    /// a class of weight 1. Must be at least 1.
+    pub weight: u32,
+}
+
+/// A rule classifying the requests matching all of its conditions.
+#[derive(Clone, Debug, Default)]
+pub struct PriorityRule {
+    /// Class of the matching requests.
+    pub class: String,
+    /// Prefix the path of the request must start with, if any.
+    pub path_prefix: Option<String>,
+    /// Header the request must have, as `name` or `name: value`, if any.
+    pub header: Option<String>,
+    /// Users, one of which must have made the request. Any user, or none, when empty.
+    pub users: Vec<String>,
+}
+
+/// Priority classification settings.
+#[derive(Clone, Debug)]
+pub struct PriorityConfig {
+    /// Classes of requests. Defaults to `interactive` of weight 8 and `bulk` of weight 1.
+    pub classes: Vec<PriorityClass>,
+    /// Rules classifying the requests, the first matching one applying. Defaults to none.
+    pub rules: Vec<PriorityRule>,
+    /// Class of the requests no rule matches. Defaults to `interactive`.
+    pub default_class: String,
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        Self {
+            classes: vec![
+                PriorityClass {
+                    name: "interactive".to_string(),
+                    weight: 8,
+                },
+                PriorityClass {
+                    name: "bulk".to_string(),
+                    weight: 1,
+                },
+            ],
+            rules: Vec::new(),
+            default_class: "interactive".to_string(),
+        }
+    }
+}
+
+/// Priority of a request, carried in its extensions up to the upstream dispatch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Priority {
+    /// Index of the class among the configured ones.
+    pub class: usize,
+    /// Weight of the class.
+    pub weight: u32,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority {
+            class: 0,
+            weight: 1,
+        }
+    }
+}
+
+struct CompiledRule {
+    priority: Priority,
+    path_prefix: Option<String>,
+    header: Option<(HeaderName, Option<HeaderValue>)>,
+    users: Vec<String>,
+}
+
+/// Compiled priority classification.
+pub struct Priorities {
+    names: Vec<String>,
+    rules: Vec<CompiledRule>,
+    default: Priority,
+}
+
+impl Priorities {
+    /// Compiles the settings, failing on unknown or duplicate classes, zero weights or invalid headers.
+    pub fn new(config: &PriorityConfig) -> Result<Self> {
+        let mut names: Vec<String> = Vec::new();
+        for class in &config.classes {
+            if names.contains(&class.name) {
+                anyhow::bail!("Duplicate priority class {}", class.name);
+            }
+            if class.weight == 0 {
+                anyhow::bail!("Priority class {} has no weight", class.name);
+            }
+            names.push(class.name.clone());
+        }
+        let priority = |name: &str| {
+            names
+                .iter()
+                .position(|class| class == name)
+                .map(|class| Priority {
+                    class,
+                    weight: config.classes[class].weight,
+                })
+                .context(format!("Unknown priority class {}", name))
+        };
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                let header = rule
+                    .header
+                    .as_deref()
+                    .map(|header| {
+                        let (name, value) = match header.split_once(':') {
+                            Some((name, value)) => (name, Some(value.trim())),
+                            None => (header, None),
+                        };
+                        let name = HeaderName::from_bytes(name.trim().as_bytes())
+                            .context(format!("Invalid header name: {}", name))?;
+                        let value = value
+                            .map(HeaderValue::from_str)
+                            .transpose()
+                            .context(format!("Invalid header value: {}", header))?;
+                        Ok::<_, anyhow::Error>((name, value))
+                    })
+                    .transpose()?;
+                Ok(CompiledRule {
+                    priority: priority(&rule.class)?,
+                    path_prefix: rule.path_prefix.clone(),
+                    header,
+                    users: rule.users.clone(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Priorities {
+            default: priority(&config.default_class)?,
+            names,
+            rules,
+        })
+    }
+
+    /// Returns the priority of the request with `parts`, made by `user` if authenticated.
+    pub fn classify(&self, parts: &request::Parts, user: Option<&str>) -> Priority {
+        self.rules
+            .iter()
+            .find(|rule| {
+                rule.path_prefix
+                    .as_deref()
+                    .is_none_or(|prefix| parts.uri.path().starts_with(prefix))
+                    && rule.header.as_ref().is_none_or(|(name, value)| {
+                        match (parts.headers.get(name), value) {
+                            (Some(actual), Some(value)) => actual == value,
+                            (Some(_), None) => true,
+                            (None, _) => false,
+                        }
+                    })
+                    && (rule.users.is_empty()
+                        || user.is_some_and(|user| rule.users.iter().any(|name| name == user)))
+            })
+            .map_or(self.default, |rule| rule.priority)
+    }
+
+    /// Returns the name of the class of `priority`.
+    pub fn name(&self, priority: Priority) -> &str {
+        self.names
+            .get(priority.class)
+            .map_or("default", String::as_str)
+    }
+}
@@ -0,0 +1,204 @@
+//! RFC 7807 `application/problem+json` bodies of the responses rejecting requests, so clients can tell rejections
+//! apart without parsing text.
+
+use hyper::{
+    header::{HeaderValue, CONTENT_TYPE},
+    Body, Response, StatusCode,
+};
+use serde::Serialize;
+
+use crate::{schema::SchemaError, trace};
+
+/// Content type of problem details.
+const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Problem details settings.
+#[derive(Clone, Debug)]
+pub struct ProblemDetailsConfig {
+    /// Prefix of the `type` URI of the problems, followed by the kind of rejection such as `rate-limited`. Defaults
+    /// to `urn:fortifynet:problem:`.
+    pub type_base: String,
+    /// Whether problems explain the rejection in `detail`, which may name the rules involved. Defaults to `true`.
+    pub include_detail: bool,
+}
+
+impl Default for ProblemDetailsConfig {
+    fn default() -> Self {
+        Self {
+            type_base: "urn:fortifynet:problem:".to_string(),
+            include_detail: true,
+        }
+    }
+}
+
+/// Kind of a rejection, identifying its problem type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProblemType {
+    /// Credentials are missing or invalid.
+    Unauthorized,
+    /// An access rule refused the request.
+    Forbidden,
+    /// The egress policy refused the destination.
+    EgressDenied,
+    /// A rate limit was exceeded.
+    RateLimited,
+    /// The request body is too large.
+    PayloadTooLarge,
+    /// The method is not allowed.
+    MethodNotAllowed,
+    /// The request is malformed.
+    BadRequest,
+    /// The request URI is too long.
+    UriTooLong,
+    /// The upstream is too busy to take the request.
+    Overloaded,
+    /// The upstream redirected in a loop, or through too many hops.
+    RedirectLoop,
+    /// The request does not conform to its schema.
+    ValidationFailed,
+    /// The request is for an undocumented endpoint.
+    NotFound,
+    /// The request body has an undocumented media type.
+    UnsupportedMediaType,
+}
+
+impl ProblemType {
+    /// Returns the suffix of the type URI.
+    pub fn slug(&self) -> &'static str {
+        match self {
+            ProblemType::Unauthorized => "unauthorized",
+            ProblemType::Forbidden => "forbidden",
+            ProblemType::EgressDenied => "egress-denied",
+            ProblemType::RateLimited => "rate-limited",
+            ProblemType::PayloadTooLarge => "payload-too-large",
+            ProblemType::MethodNotAllowed => "method-not-allowed",
+            ProblemType::BadRequest => "bad-request",
+            ProblemType::UriTooLong => "uri-too-long",
+            ProblemType::Overloaded => "overloaded",
+            ProblemType::RedirectLoop => "redirect-loop",
+            ProblemType::ValidationFailed => "validation-failed",
+            ProblemType::NotFound => "not-found",
+            ProblemType::UnsupportedMediaType => "unsupported-media-type",
+        }
+    }
+
+    /// Returns the summary of the problem type, which is the same for every occurrence.
+    pub fn title(&self) -> &'static str {
+        match self {
+            ProblemType::Unauthorized => "Authentication required",
+            ProblemType::Forbidden => "Access denied",
+            ProblemType::EgressDenied => "Destination not allowed",
+            ProblemType::RateLimited => "Too many requests",
+            ProblemType::PayloadTooLarge => "Request body too large",
+            ProblemType::MethodNotAllowed => "Method not allowed",
+            ProblemType::BadRequest => "Bad request",
+            ProblemType::UriTooLong => "Request URI too long",
+            ProblemType::Overloaded => "Upstream overloaded",
+            ProblemType::RedirectLoop => "Too many redirects",
+            ProblemType::ValidationFailed => "Request failed validation",
+            ProblemType::NotFound => "Not found",
+            ProblemType::UnsupportedMediaType => "Unsupported media type",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    errors: &'a [SchemaError],
+}
+
+impl ProblemDetailsConfig {
+    /// Returns the problem details of a rejection, with the ID of the current request when it is traced.
+    fn body(&self, status: StatusCode, problem_type: ProblemType, detail: &str) -> String {
+        self.body_with_errors(status, problem_type, detail, &[])
+    }
+
+    /// Returns the problem details of a rejection listing the violations of the request.
+    fn body_with_errors(
+        &self,
+        status: StatusCode,
+        problem_type: ProblemType,
+        detail: &str,
+        errors: &[SchemaError],
+    ) -> String {
+        let problem = Problem {
+            problem_type: format!("{}{}", self.type_base, problem_type.slug()),
+            title: problem_type.title(),
+            status: status.as_u16(),
+            detail: Some(detail).filter(|_| self.include_detail),
+            request_id: trace::current_id(),
+            errors,
+        };
+        serde_json::to_string(&problem).unwrap_or_default()
+    }
+}
+
+/// Turns `response` into a rejection with `status`, with a problem details body when `config` is set.
+pub(crate) fn reject(
+    response: &mut Response<Body>,
+    config: Option<&ProblemDetailsConfig>,
+    status: StatusCode,
+    problem_type: ProblemType,
+    detail: &str,
+) {
+    *response.status_mut() = status;
+    if let Some(config) = config {
+        *response.body_mut() = Body::from(config.body(status, problem_type, detail));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+    }
+}
+
+/// Turns `response` into a `422 Unprocessable Entity` rejection listing the violations of the request.
+///
+/// Clients need the violations to fix their requests, so they are described even when problem details are not
+/// configured, with the default settings.
+pub(crate) fn reject_invalid(
+    response: &mut Response<Body>,
+    config: Option<&ProblemDetailsConfig>,
+    detail: &str,
+    errors: &[SchemaError],
+) {
+    let status = StatusCode::UNPROCESSABLE_ENTITY;
+    let config = config.cloned().unwrap_or_default();
+    let body = config.body_with_errors(status, ProblemType::ValidationFailed, detail, errors);
+    *response.status_mut() = status;
+    *response.body_mut() = Body::from(body);
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+}
+
+/// Returns a raw HTTP/1.1 response rejecting a connection before any request was parsed, with a problem details
+/// body when `config` is set.
+pub(crate) fn raw_rejection(
+    config: Option<&ProblemDetailsConfig>,
+    status: StatusCode,
+    problem_type: ProblemType,
+    detail: &str,
+) -> Vec<u8> {
+    match config {
+        Some(config) => {
+            let body = config.body(status, problem_type, detail);
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+                status,
+                PROBLEM_JSON,
+                body.len(),
+                body
+            )
+            .into_bytes()
+        }
+        None => format!("HTTP/1.1 {}\r\n\r\n", status).into_bytes(),
+    }
+}
@@ -0,0 +1,165 @@
+//! On-demand profiles of the running proxy for the admin API of the dashboard: CPU profiles in the pprof format,
+//! with the `profiling` feature, and heap statistics sampled over the same kind of window.
+//!
+//! CPU profiles sample the stacks of every thread of the process, so they cover the proxy runtime whichever runtime
+//! serves the dashboard. The profiler is process-wide, hence a single CPU profile runs at a time.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::memory::{MemoryTracker, MemoryUsage};
+
+/// Length of a profile when not given, in seconds.
+pub(crate) const DEFAULT_SECONDS: u64 = 10;
+/// Longest profile allowed, in seconds.
+pub(crate) const MAX_SECONDS: u64 = 300;
+/// Stack samples taken per second by CPU profiles when not given, off 100 so sampling does not run in lockstep with
+/// timers.
+pub(crate) const DEFAULT_FREQUENCY: u32 = 99;
+/// Most stack samples taken per second by CPU profiles.
+pub(crate) const MAX_FREQUENCY: u32 = 1000;
+
+/// Whether a CPU profile is being taken.
+static CPU_PROFILING: AtomicBool = AtomicBool::new(false);
+
+/// Why a CPU profile was not taken.
+#[derive(Debug)]
+pub(crate) enum CpuProfileError {
+    /// The proxy was built without the `profiling` feature.
+    #[cfg_attr(feature = "profiling", allow(dead_code))]
+    Unsupported,
+    /// Another CPU profile is being taken.
+    InProgress,
+    /// The profiler failed to start or to build the report.
+    #[cfg_attr(not(feature = "profiling"), allow(dead_code))]
+    Failed(anyhow::Error),
+}
+
+impl fmt::Display for CpuProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuProfileError::Unsupported => {
+                write!(f, "CPU profiling requires the `profiling` feature")
+            }
+            CpuProfileError::InProgress => write!(f, "A CPU profile is already being taken"),
+            CpuProfileError::Failed(err) => write!(f, "CPU profiling failed: {:#}", err),
+        }
+    }
+}
+
+/// Clears the CPU profiling flag when dropped.
+struct ProfilingFlag;
+
+impl Drop for ProfilingFlag {
+    fn drop(&mut self) {
+        CPU_PROFILING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Samples the stacks of the process `frequency` times per second for `seconds`, blocking meanwhile, and returns
+/// the profile as an uncompressed pprof protobuf, as read by `go tool pprof`.
+pub(crate) fn cpu_profile(seconds: u64, frequency: u32) -> Result<Vec<u8>, CpuProfileError> {
+    if CPU_PROFILING.swap(true, Ordering::SeqCst) {
+        return Err(CpuProfileError::InProgress);
+    }
+    let _flag = ProfilingFlag;
+    sample_cpu(Duration::from_secs(seconds), frequency)
+}
+
+#[cfg(feature = "profiling")]
+fn sample_cpu(duration: Duration, frequency: u32) -> Result<Vec<u8>, CpuProfileError> {
+    use pprof::protos::Message;
+
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(frequency as i32)
+        // Frames of the signal handler and the C runtime carry no information
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|err| CpuProfileError::Failed(err.into()))?;
+    std::thread::sleep(duration);
+    let profile = guard
+        .report()
+        .build()
+        .and_then(|report| report.pprof())
+        .map_err(|err| CpuProfileError::Failed(err.into()))?;
+    Ok(profile.encode_to_vec())
+}
+
+#[cfg(not(feature = "profiling"))]
+fn sample_cpu(_duration: Duration, _frequency: u32) -> Result<Vec<u8>, CpuProfileError> {
+    Err(CpuProfileError::Unsupported)
+}
+
+/// Memory of the process at a point of a heap profile.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct HeapSample {
+    /// Time since the start of the profile, in milliseconds.
+    pub offset_ms: u64,
+    /// Resident memory of the process, in bytes. `None` where it cannot be read, such as outside of Linux.
+    pub resident_bytes: Option<u64>,
+    /// Memory accounted by the proxy: the cache, the buffered bodies and the metrics.
+    pub tracked: MemoryUsage,
+}
+
+/// Memory of the process sampled every second over a window.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct HeapProfile {
+    /// Length of the window, in seconds.
+    pub seconds: u64,
+    /// The samples, the first taken at the start of the window and the last at its end.
+    pub samples: Vec<HeapSample>,
+    /// Highest resident memory of the process since its start, in bytes. `None` where it cannot be read.
+    pub peak_resident_bytes: Option<u64>,
+    /// Growth of the resident memory over the window, in bytes, negative when memory was released.
+    pub resident_growth_bytes: Option<i64>,
+    /// Growth of the memory accounted by the proxy over the window, in bytes.
+    pub tracked_growth_bytes: i64,
+}
+
+/// Samples the memory of the process and the usage accounted by `memory` every second for `seconds`.
+pub(crate) async fn heap_profile(memory: &MemoryTracker, seconds: u64) -> HeapProfile {
+    let start = tokio::time::Instant::now();
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut samples = Vec::new();
+    // The first tick is immediate, so the samples span the whole window
+    for _ in 0..=seconds {
+        interval.tick().await;
+        samples.push(HeapSample {
+            offset_ms: start.elapsed().as_millis() as u64,
+            resident_bytes: status_bytes("VmRSS:"),
+            tracked: memory.usage(),
+        });
+    }
+    let first = &samples[0];
+    let last = &samples[samples.len() - 1];
+    let resident_growth_bytes = match (first.resident_bytes, last.resident_bytes) {
+        (Some(first), Some(last)) => Some(last as i64 - first as i64),
+        _ => None,
+    };
+    let tracked_growth_bytes = last.tracked.total() as i64 - first.tracked.total() as i64;
+    HeapProfile {
+        seconds,
+        peak_resident_bytes: status_bytes("VmHWM:"),
+        resident_growth_bytes,
+        tracked_growth_bytes,
+        samples,
+    }
+}
+
+/// Reads the memory size of `field`, such as `VmRSS:`, from `/proc/self/status`, in bytes.
+fn status_bytes(field: &str) -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with(field))?;
+    let kilobytes: u64 = line[field.len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
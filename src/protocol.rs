@@ -0,0 +1,97 @@
+//! Detection of the HTTP version spoken by TLS upstreams, negotiated through ALPN and remembered for a while so
+//! later connections to an upstream offer only its protocol.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hyper::Version;
+
+/// HTTP version spoken by an upstream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamProtocol {
+    /// HTTP/1.1.
+    Http1,
+    /// HTTP/2.
+    Http2,
+}
+
+impl UpstreamProtocol {
+    /// Returns the protocol of a response of version `version`.
+    pub fn of(version: Version) -> Self {
+        match version {
+            Version::HTTP_2 => UpstreamProtocol::Http2,
+            _ => UpstreamProtocol::Http1,
+        }
+    }
+}
+
+/// Protocol detection settings.
+#[derive(Clone, Debug)]
+pub struct ProtocolDetectionConfig {
+    /// How long the protocol negotiated with an upstream is remembered. Defaults to 10 minutes.
+    pub ttl: Duration,
+}
+
+impl Default for ProtocolDetectionConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Protocols negotiated with the upstreams, by upstream.
+pub struct ProtocolCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (UpstreamProtocol, Instant)>>,
+}
+
+impl ProtocolCache {
+    /// Creates an empty cache.
+    pub fn new(config: ProtocolDetectionConfig) -> Self {
+        ProtocolCache {
+            ttl: config.ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the protocol of `upstream`, unless it is unknown or was negotiated longer than the TTL ago.
+    pub fn get(&self, upstream: &str) -> Option<UpstreamProtocol> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(upstream)
+            .filter(|(_, negotiated)| negotiated.elapsed() < self.ttl)
+            .map(|(protocol, _)| *protocol)
+    }
+
+    /// Remembers that `upstream` speaks `protocol`, unless it is already known to.
+    pub fn record(&self, upstream: &str, protocol: UpstreamProtocol) {
+        let mut entries = self.entries.lock().unwrap();
+        let known = entries.get(upstream).is_some_and(|(known, negotiated)| {
+            *known == protocol && negotiated.elapsed() < self.ttl
+        });
+        if !known {
+            entries.insert(upstream.to_string(), (protocol, Instant::now()));
+        }
+    }
+
+    /// Forgets the protocol of `upstream`, so the next request negotiates it again.
+    pub fn forget(&self, upstream: &str) {
+        self.entries.lock().unwrap().remove(upstream);
+    }
+
+    /// Returns the upstreams with a known protocol, sorted by upstream.
+    pub fn protocols(&self) -> Vec<(String, UpstreamProtocol)> {
+        let entries = self.entries.lock().unwrap();
+        let mut protocols: Vec<_> = entries
+            .iter()
+            .filter(|(_, (_, negotiated))| negotiated.elapsed() < self.ttl)
+            .map(|(upstream, (protocol, _))| (upstream.clone(), *protocol))
+            .collect();
+        protocols.sort_by(|(a, _), (b, _)| a.cmp(b));
+        protocols
+    }
+}
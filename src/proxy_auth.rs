@@ -0,0 +1,579 @@
+//! Authentication with upstream HTTP proxies: a `Proxy-Authorization` header sent as is, such as Basic credentials,
+//! or the NTLM and Kerberos (`Negotiate`) schemes of corporate proxies.
+//!
+//! NTLM authenticates a connection rather than a request: its handshake takes several `CONNECT` requests, which are
+//! all sent over the same connection to the proxy, and the tunnel is opened on that connection.
+//!
+//! NTLM requires the `ntlm` feature, and Kerberos the `kerberos` feature on Unix, which links the system's GSSAPI
+//! library (MIT Kerberos or Heimdal) and uses the credentials of its cache, as obtained by `kinit`. Without them,
+//! configuring the scheme is an error.
+
+use std::fmt;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    secret::{self, SecretSource},
+    tenant::{self, decode_base64, encode_base64},
+};
+
+/// How the proxy authenticates with an upstream HTTP proxy.
+#[derive(Clone)]
+pub enum ProxyAuth {
+    /// A `Proxy-Authorization` header sent with every `CONNECT` request, such as `Basic dXNlcjpwYXNz`.
+    Header(String),
+    /// NTLMv2 with a Windows account. Requires the `ntlm` feature.
+    Ntlm(NtlmCredentials),
+    /// Kerberos through SPNEGO, with the credentials of the system's Kerberos cache. Requires the `kerberos` feature
+    /// on Unix.
+    Negotiate(NegotiateConfig),
+}
+
+impl fmt::Debug for ProxyAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyAuth::Header(header) => f
+                .debug_tuple("Header")
+                .field(&secret::redact(header))
+                .finish(),
+            ProxyAuth::Ntlm(credentials) => f.debug_tuple("Ntlm").field(credentials).finish(),
+            ProxyAuth::Negotiate(config) => f.debug_tuple("Negotiate").field(config).finish(),
+        }
+    }
+}
+
+/// Windows account authenticated with NTLM.
+#[derive(Clone)]
+pub struct NtlmCredentials {
+    /// User name, without its domain.
+    pub username: String,
+    /// Password of the user, read at every handshake.
+    pub password: SecretSource,
+    /// Domain of the user, empty for a local account of the proxy's host.
+    pub domain: String,
+    /// Name of the workstation sent to the proxy (optional).
+    pub workstation: String,
+}
+
+impl fmt::Debug for NtlmCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NtlmCredentials")
+            .field("username", &self.username)
+            .field("password", &self.password)
+            .field("domain", &self.domain)
+            .field("workstation", &self.workstation)
+            .finish()
+    }
+}
+
+/// Kerberos authentication with an upstream HTTP proxy.
+#[derive(Clone, Debug, Default)]
+pub struct NegotiateConfig {
+    /// Service principal of the proxy, as `service@host`. Defaults to `HTTP@` followed by the host of the proxy
+    /// address.
+    pub service: Option<String>,
+}
+
+impl ProxyAuth {
+    /// Fails if the scheme is not supported by this build.
+    pub(crate) fn check(&self) -> Result<()> {
+        match self {
+            ProxyAuth::Header(_) => Ok(()),
+            ProxyAuth::Ntlm(_) if cfg!(feature = "ntlm") => Ok(()),
+            ProxyAuth::Ntlm(_) => anyhow::bail!(
+                "Cannot authenticate with NTLM: fortifynet_proxy was built without the `ntlm` feature"
+            ),
+            ProxyAuth::Negotiate(_) if cfg!(all(feature = "kerberos", unix)) => Ok(()),
+            ProxyAuth::Negotiate(_) => anyhow::bail!(
+                "Cannot authenticate with Kerberos: fortifynet_proxy was built without the `kerberos` feature, \
+                 or for a platform other than Unix"
+            ),
+        }
+    }
+
+    /// Starts authenticating a connection to the proxy at `proxy`, as `host:port`.
+    pub(crate) fn start(&self, proxy: &str) -> Handshake {
+        let state = match self {
+            ProxyAuth::Header(header) => State::Header(header.clone()),
+            ProxyAuth::Ntlm(credentials) => State::Ntlm {
+                credentials: credentials.clone(),
+                negotiated: false,
+            },
+            ProxyAuth::Negotiate(config) => {
+                let host = tenant::strip_port(proxy);
+                State::Negotiate(
+                    config
+                        .service
+                        .clone()
+                        .unwrap_or_else(|| format!("HTTP@{}", host)),
+                )
+            }
+        };
+        Handshake { state }
+    }
+}
+
+/// Where the authentication of a connection to a proxy stands.
+enum State {
+    Header(String),
+    Ntlm {
+        credentials: NtlmCredentials,
+        /// Whether the negotiation message was sent, so the next challenge is answered with the credentials.
+        negotiated: bool,
+    },
+    Negotiate(String),
+}
+
+/// The authentication of a connection to a proxy, over one or more `CONNECT` requests.
+pub(crate) struct Handshake {
+    state: State,
+}
+
+impl Handshake {
+    /// Returns the `Proxy-Authorization` header of the first request.
+    pub(crate) async fn first(&mut self) -> Result<String> {
+        match &mut self.state {
+            State::Header(header) => Ok(header.clone()),
+            State::Ntlm { negotiated, .. } => {
+                *negotiated = true;
+                Ok(format!("NTLM {}", encode_base64(&ntlm_negotiate()?)))
+            }
+            State::Negotiate(service) => {
+                let service = service.clone();
+                // Obtaining a service ticket may block on the KDC
+                let token = tokio::task::spawn_blocking(move || negotiate(&service)).await??;
+                Ok(format!("Negotiate {}", encode_base64(&token)))
+            }
+        }
+    }
+
+    /// Returns the `Proxy-Authorization` header answering `challenges`, the `Proxy-Authenticate` headers of a 407
+    /// response, or `None` when the credentials were refused.
+    pub(crate) fn next(&mut self, challenges: &[String]) -> Result<Option<String>> {
+        match &mut self.state {
+            State::Ntlm {
+                credentials,
+                negotiated,
+            } if *negotiated => {
+                *negotiated = false;
+                let challenge = challenges.iter().find_map(|challenge| {
+                    let (scheme, token) = challenge.trim().split_once(' ')?;
+                    scheme.eq_ignore_ascii_case("NTLM").then(|| token.trim())
+                });
+                let challenge = match challenge {
+                    Some(challenge) => {
+                        decode_base64(challenge).context("Invalid base64 in NTLM challenge")?
+                    }
+                    None => anyhow::bail!("The proxy sent no NTLM challenge"),
+                };
+                let message = ntlm_authenticate(credentials, &challenge)?;
+                Ok(Some(format!("NTLM {}", encode_base64(&message))))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(feature = "ntlm")]
+fn ntlm_negotiate() -> Result<Vec<u8>> {
+    Ok(ntlm::negotiate())
+}
+
+#[cfg(not(feature = "ntlm"))]
+fn ntlm_negotiate() -> Result<Vec<u8>> {
+    anyhow::bail!("fortifynet_proxy was built without the `ntlm` feature")
+}
+
+#[cfg(feature = "ntlm")]
+fn ntlm_authenticate(credentials: &NtlmCredentials, challenge: &[u8]) -> Result<Vec<u8>> {
+    ntlm::authenticate(credentials, challenge)
+}
+
+#[cfg(not(feature = "ntlm"))]
+fn ntlm_authenticate(_credentials: &NtlmCredentials, _challenge: &[u8]) -> Result<Vec<u8>> {
+    anyhow::bail!("fortifynet_proxy was built without the `ntlm` feature")
+}
+
+#[cfg(all(feature = "kerberos", unix))]
+fn negotiate(service: &str) -> Result<Vec<u8>> {
+    gssapi::initial_token(service)
+}
+
+#[cfg(not(all(feature = "kerberos", unix)))]
+fn negotiate(_service: &str) -> Result<Vec<u8>> {
+    anyhow::bail!("fortifynet_proxy was built without the `kerberos` feature")
+}
+
+/// NTLMv2 messages, as specified by MS-NLMP.
+#[cfg(feature = "ntlm")]
+mod ntlm {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use anyhow::Result;
+    use hmac::{Hmac, Mac};
+    use md4::{Digest, Md4};
+    use md5::Md5;
+
+    use super::NtlmCredentials;
+
+    const SIGNATURE: &[u8] = b"NTLMSSP\0";
+
+    const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+    const NEGOTIATE_OEM: u32 = 0x0000_0002;
+    const REQUEST_TARGET: u32 = 0x0000_0004;
+    const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+    const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+    const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+    /// Flags requested by the negotiation message.
+    const FLAGS: u32 = NEGOTIATE_UNICODE
+        | NEGOTIATE_OEM
+        | REQUEST_TARGET
+        | NEGOTIATE_NTLM
+        | NEGOTIATE_ALWAYS_SIGN
+        | NEGOTIATE_EXTENDED_SESSIONSECURITY;
+
+    /// Identifiers of the attributes of the target information.
+    const AV_EOL: u16 = 0;
+    const AV_TIMESTAMP: u16 = 7;
+
+    /// Length of the header of the authentication message, without version or MIC.
+    const AUTHENTICATE_HEADER_LEN: usize = 64;
+    /// Seconds between 1601, the epoch of Windows timestamps, and 1970.
+    const WINDOWS_EPOCH_OFFSET: u64 = 11_644_473_600;
+
+    type HmacMd5 = Hmac<Md5>;
+
+    /// Returns the negotiation message, first of the handshake.
+    pub(super) fn negotiate() -> Vec<u8> {
+        let mut message = SIGNATURE.to_vec();
+        message.extend_from_slice(&1u32.to_le_bytes());
+        message.extend_from_slice(&FLAGS.to_le_bytes());
+        // Neither domain nor workstation
+        message.extend_from_slice(&[0; 16]);
+        message
+    }
+
+    /// Returns the authentication message answering the challenge message `challenge`.
+    pub(super) fn authenticate(credentials: &NtlmCredentials, challenge: &[u8]) -> Result<Vec<u8>> {
+        authenticate_with(credentials, challenge, rand::random(), now())
+    }
+
+    /// Returns the authentication message answering `challenge` with `client_challenge`, at `time` unless the
+    /// server gave its own.
+    fn authenticate_with(
+        credentials: &NtlmCredentials,
+        challenge: &[u8],
+        client_challenge: [u8; 8],
+        time: u64,
+    ) -> Result<Vec<u8>> {
+        if challenge.len() < 32 || !challenge.starts_with(SIGNATURE) || read_u32(challenge, 8) != 2
+        {
+            anyhow::bail!("Invalid NTLM challenge message");
+        }
+        let flags = read_u32(challenge, 20);
+        let server_challenge = &challenge[24..32];
+        let target_info = if challenge.len() >= 48 {
+            security_buffer(challenge, 40)?
+        } else {
+            &[][..]
+        };
+        let unicode = flags & NEGOTIATE_UNICODE != 0;
+        let encode = |text: &str| {
+            if unicode {
+                utf16(text)
+            } else {
+                text.as_bytes().to_vec()
+            }
+        };
+
+        // A timestamp of the server replaces the client's own, and the LMv2 response
+        let timestamp = find_timestamp(target_info);
+        let time = timestamp.unwrap_or(time);
+
+        let key = ntowf_v2(credentials)?;
+        let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+        blob.extend_from_slice(&time.to_le_bytes());
+        blob.extend_from_slice(&client_challenge);
+        blob.extend_from_slice(&[0; 4]);
+        blob.extend_from_slice(target_info);
+        blob.extend_from_slice(&[0; 4]);
+        let mut nt_response = hmac_md5(&key, &[server_challenge, &blob]).to_vec();
+        nt_response.extend_from_slice(&blob);
+        let lm_response = match timestamp {
+            Some(_) => vec![0; 24],
+            None => {
+                let mut response = hmac_md5(&key, &[server_challenge, &client_challenge]).to_vec();
+                response.extend_from_slice(&client_challenge);
+                response
+            }
+        };
+
+        let fields = [
+            lm_response,
+            nt_response,
+            encode(&credentials.domain),
+            encode(&credentials.username),
+            encode(&credentials.workstation),
+            // No session key, as the connection is neither signed nor sealed
+            Vec::new(),
+        ];
+        let mut message = SIGNATURE.to_vec();
+        message.extend_from_slice(&3u32.to_le_bytes());
+        let mut payload = Vec::new();
+        for field in &fields {
+            let len = u16::try_from(field.len())
+                .map_err(|_| anyhow::anyhow!("NTLM credentials are too long"))?;
+            let offset = (AUTHENTICATE_HEADER_LEN + payload.len()) as u32;
+            message.extend_from_slice(&len.to_le_bytes());
+            message.extend_from_slice(&len.to_le_bytes());
+            message.extend_from_slice(&offset.to_le_bytes());
+            payload.extend_from_slice(field);
+        }
+        let charset = if unicode {
+            NEGOTIATE_UNICODE
+        } else {
+            NEGOTIATE_OEM
+        };
+        let flags = (flags & FLAGS & !(NEGOTIATE_UNICODE | NEGOTIATE_OEM)) | charset;
+        message.extend_from_slice(&flags.to_le_bytes());
+        message.extend_from_slice(&payload);
+        Ok(message)
+    }
+
+    /// Returns the NTLMv2 key of the credentials.
+    fn ntowf_v2(credentials: &NtlmCredentials) -> Result<[u8; 16]> {
+        let hash = Md4::digest(utf16(&credentials.password.read()?));
+        let identity = format!(
+            "{}{}",
+            credentials.username.to_uppercase(),
+            credentials.domain
+        );
+        Ok(hmac_md5(&hash, &[&utf16(&identity)]))
+    }
+
+    fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+        // HMAC takes keys of any length
+        let mut mac = <HmacMd5 as Mac>::new_from_slice(key).unwrap();
+        for part in parts {
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Returns the timestamp of the target information, if any.
+    fn find_timestamp(mut target_info: &[u8]) -> Option<u64> {
+        while target_info.len() >= 4 {
+            let id = u16::from_le_bytes([target_info[0], target_info[1]]);
+            let len = usize::from(u16::from_le_bytes([target_info[2], target_info[3]]));
+            let value = target_info.get(4..4 + len)?;
+            match id {
+                AV_EOL => return None,
+                AV_TIMESTAMP if len == 8 => {
+                    return Some(u64::from_le_bytes(value.try_into().ok()?))
+                }
+                _ => target_info = &target_info[4 + len..],
+            }
+        }
+        None
+    }
+
+    /// Returns the current time as a Windows timestamp, in tenths of microseconds since 1601.
+    fn now() -> u64 {
+        let elapsed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        (elapsed.as_secs() + WINDOWS_EPOCH_OFFSET) * 10_000_000
+            + u64::from(elapsed.subsec_nanos() / 100)
+    }
+
+    /// Returns the content of the security buffer at `offset` of `message`.
+    fn security_buffer(message: &[u8], offset: usize) -> Result<&[u8]> {
+        let len = usize::from(u16::from_le_bytes([message[offset], message[offset + 1]]));
+        let start = read_u32(message, offset + 4) as usize;
+        message
+            .get(start..start + len)
+            .ok_or_else(|| anyhow::anyhow!("Invalid NTLM challenge message"))
+    }
+
+    fn read_u32(message: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([
+            message[offset],
+            message[offset + 1],
+            message[offset + 2],
+            message[offset + 3],
+        ])
+    }
+
+    fn utf16(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    /// The NTLMv2 example of MS-NLMP section 4.2.4.
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::secret::SecretSource;
+
+        const SERVER_CHALLENGE: [u8; 8] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+        const CLIENT_CHALLENGE: [u8; 8] = [0xaa; 8];
+        /// Target information: `Domain` as NetBIOS domain name and `Server` as NetBIOS computer name.
+        const TARGET_INFO: [u8; 36] = [
+            0x02, 0x00, 0x0c, 0x00, 0x44, 0x00, 0x6f, 0x00, 0x6d, 0x00, 0x61, 0x00, 0x69, 0x00,
+            0x6e, 0x00, 0x01, 0x00, 0x0c, 0x00, 0x53, 0x00, 0x65, 0x00, 0x72, 0x00, 0x76, 0x00,
+            0x65, 0x00, 0x72, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        fn credentials() -> NtlmCredentials {
+            NtlmCredentials {
+                username: "User".to_string(),
+                password: SecretSource::Value("Password".to_string()),
+                domain: "Domain".to_string(),
+                workstation: "COMPUTER".to_string(),
+            }
+        }
+
+        /// Returns the challenge message of section 4.2.4.3, with `target_info`.
+        fn challenge(target_info: &[u8]) -> Vec<u8> {
+            let target_name = utf16("Server");
+            let mut message = SIGNATURE.to_vec();
+            message.extend_from_slice(&2u32.to_le_bytes());
+            message.extend_from_slice(&[0x0c, 0x00, 0x0c, 0x00, 0x38, 0x00, 0x00, 0x00]);
+            message.extend_from_slice(&0xe28a_8233u32.to_le_bytes());
+            message.extend_from_slice(&SERVER_CHALLENGE);
+            message.extend_from_slice(&[0; 8]);
+            let len = (target_info.len() as u16).to_le_bytes();
+            message.extend_from_slice(&[len[0], len[1], len[0], len[1], 0x44, 0x00, 0x00, 0x00]);
+            message.extend_from_slice(&[0x06, 0x00, 0x70, 0x17, 0x00, 0x00, 0x00, 0x0f]);
+            message.extend_from_slice(&target_name);
+            message.extend_from_slice(target_info);
+            message
+        }
+
+        /// Returns the fields of an authentication message, in the order of their security buffers.
+        fn fields(message: &[u8]) -> Vec<&[u8]> {
+            (0..6)
+                .map(|i| security_buffer(message, 12 + 8 * i).unwrap())
+                .collect()
+        }
+
+        #[test]
+        fn ntowf_v2() {
+            assert_eq!(
+                super::ntowf_v2(&credentials()).unwrap(),
+                [
+                    0x0c, 0x86, 0x8a, 0x40, 0x3b, 0xfd, 0x7a, 0x93, 0xa3, 0x00, 0x1e, 0xf2, 0x2e,
+                    0xf0, 0x2e, 0x3f
+                ]
+            );
+        }
+
+        #[test]
+        fn authenticate_message() {
+            let message = authenticate_with(
+                &credentials(),
+                &challenge(&TARGET_INFO),
+                CLIENT_CHALLENGE,
+                0,
+            )
+            .unwrap();
+            assert!(message.starts_with(SIGNATURE));
+            assert_eq!(read_u32(&message, 8), 3);
+            let fields = fields(&message);
+
+            let lm_response = [
+                0x86, 0xc3, 0x50, 0x97, 0xac, 0x9c, 0xec, 0x10, 0x25, 0x54, 0x76, 0x4a, 0x57, 0xcc,
+                0xcc, 0x19, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa,
+            ];
+            assert_eq!(fields[0], lm_response);
+
+            let nt_proof = [
+                0x68, 0xcd, 0x0a, 0xb8, 0x51, 0xe5, 0x1c, 0x96, 0xaa, 0xbc, 0x92, 0x7b, 0xeb, 0xef,
+                0x6a, 0x1c,
+            ];
+            let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+            blob.extend_from_slice(&[0; 8]);
+            blob.extend_from_slice(&CLIENT_CHALLENGE);
+            blob.extend_from_slice(&[0; 4]);
+            blob.extend_from_slice(&TARGET_INFO);
+            blob.extend_from_slice(&[0; 4]);
+            assert_eq!(fields[1][..16], nt_proof);
+            assert_eq!(fields[1][16..], blob);
+
+            assert_eq!(fields[2], utf16("Domain"));
+            assert_eq!(fields[3], utf16("User"));
+            assert_eq!(fields[4], utf16("COMPUTER"));
+            assert!(fields[5].is_empty());
+
+            // The payload follows the header, in the order of the fields
+            let mut offset = AUTHENTICATE_HEADER_LEN;
+            for (i, field) in fields.iter().enumerate() {
+                assert_eq!(read_u32(&message, 16 + 8 * i) as usize, offset);
+                offset += field.len();
+            }
+            assert_eq!(message.len(), offset);
+
+            let flags = read_u32(&message, 60);
+            assert_eq!(flags & NEGOTIATE_UNICODE, NEGOTIATE_UNICODE);
+            assert_eq!(flags & NEGOTIATE_OEM, 0);
+        }
+
+        #[test]
+        fn server_timestamp_replaces_lm_response() {
+            let timestamp = 0x01d0_0000_0000_0000u64;
+            let mut target_info = TARGET_INFO[..32].to_vec();
+            target_info.extend_from_slice(&[0x07, 0x00, 0x08, 0x00]);
+            target_info.extend_from_slice(&timestamp.to_le_bytes());
+            target_info.extend_from_slice(&[0; 4]);
+            let message = authenticate_with(
+                &credentials(),
+                &challenge(&target_info),
+                CLIENT_CHALLENGE,
+                0,
+            )
+            .unwrap();
+            let fields = fields(&message);
+            assert_eq!(fields[0], [0; 24]);
+            assert_eq!(fields[1][24..32], timestamp.to_le_bytes());
+        }
+
+        #[test]
+        fn invalid_challenges() {
+            let mut message = challenge(&TARGET_INFO);
+            message[8] = 3;
+            assert!(authenticate(&credentials(), &message).is_err());
+            assert!(authenticate(&credentials(), &challenge(&TARGET_INFO)[..24]).is_err());
+            let mut message = challenge(&TARGET_INFO);
+            message[40] = 0xff;
+            assert!(authenticate(&credentials(), &message).is_err());
+        }
+    }
+}
+
+/// Kerberos tokens from the system's GSSAPI library (MIT Kerberos, Heimdal or Apple's GSS framework).
+#[cfg(all(feature = "kerberos", unix))]
+mod gssapi {
+    use anyhow::{Context, Result};
+    use libgssapi::{
+        context::{ClientCtx, CtxFlags},
+        name::Name,
+        oid::{GSS_MECH_SPNEGO, GSS_NT_HOSTBASED_SERVICE},
+    };
+
+    /// Returns the initial SPNEGO token authenticating with `service`, as `service@host`.
+    pub(super) fn initial_token(service: &str) -> Result<Vec<u8>> {
+        let name = Name::new(service.as_bytes(), Some(GSS_NT_HOSTBASED_SERVICE))
+            .with_context(|| format!("Invalid Kerberos service {}", service))?;
+        // The default credentials, from the cache obtained by `kinit`
+        let mut context = ClientCtx::new(None, name, CtxFlags::empty(), Some(GSS_MECH_SPNEGO));
+        let token = context
+            .step(None, None)
+            .with_context(|| format!("Failed to obtain a Kerberos token for {}", service))?;
+        match token {
+            Some(token) => Ok(token.to_vec()),
+            None => anyhow::bail!(
+                "Failed to obtain a Kerberos token for {}: no token",
+                service
+            ),
+        }
+    }
+}
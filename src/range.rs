@@ -0,0 +1,95 @@
+//! Byte-range requests served from cached full objects.
+
+use anyhow::Result;
+use hyper::{
+    header::{HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, TRANSFER_ENCODING},
+    Body, Response, StatusCode,
+};
+
+use crate::chunks::CachedBody;
+
+/// Bytes requested by a single-range `Range` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// From the first position to the last one, inclusive, or to the end when `None`.
+    FromTo(u64, Option<u64>),
+    /// The given number of bytes at the end.
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Parses a `Range` header, returning `None` for other units and multiple ranges, which are answered in full.
+    fn parse(header: &HeaderValue) -> Option<Self> {
+        let spec = header.to_str().ok()?.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+        let (first, last) = spec.split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+        if first.is_empty() {
+            return last.parse().ok().map(ByteRange::Suffix);
+        }
+        let first = first.parse().ok()?;
+        let last = match last {
+            "" => None,
+            last => Some(last.parse().ok()?),
+        };
+        if last.is_some_and(|last| last < first) {
+            return None;
+        }
+        Some(ByteRange::FromTo(first, last))
+    }
+
+    /// Resolves the range against a body of `len` bytes, returning `None` when it is unsatisfiable.
+    fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        match *self {
+            ByteRange::FromTo(first, _) if first >= len => None,
+            ByteRange::FromTo(first, last) => {
+                Some((first, last.map_or(len - 1, |last| last.min(len - 1))))
+            }
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(_) if len == 0 => None,
+            ByteRange::Suffix(suffix) => Some((len.saturating_sub(suffix), len - 1)),
+        }
+    }
+}
+
+/// Answers the `range` of the full object `body` with `206 Partial Content`, keeping the other headers of
+/// `response`, or with `416 Range Not Satisfiable`. Ranges that cannot be served as one slice get the full object.
+pub(crate) fn serve(
+    mut response: Response<Body>,
+    body: CachedBody,
+    range: &HeaderValue,
+) -> Result<Response<Body>> {
+    let range = match ByteRange::parse(range) {
+        Some(range) => range,
+        None => {
+            *response.body_mut() = body.into_body();
+            return Ok(response);
+        }
+    };
+    let len = body.len() as u64;
+    let headers = response.headers_mut();
+    headers.remove(TRANSFER_ENCODING);
+    match range.resolve(len) {
+        Some((first, last)) => {
+            headers.insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", first, last, len))?,
+            );
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(last - first + 1));
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            *response.body_mut() = body.range_body(first as usize, last as usize);
+        }
+        None => {
+            headers.insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", len))?,
+            );
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(0));
+            *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            *response.body_mut() = Body::empty();
+        }
+    }
+    Ok(response)
+}
@@ -0,0 +1,394 @@
+//! Per-client rate limiting with the generic cell rate algorithm (GCRA), enforced either by each proxy instance or
+//! across a fleet of instances through counters shared in Redis.
+//!
+//! In the Redis mode, each decision is a Lua script run atomically by Redis on its own clock, so instances with
+//! skewed clocks agree. While Redis is unreachable, instances fall back to enforcing the limit locally, and retry
+//! Redis after `retry_interval`.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use hyper::http::request;
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+
+use crate::secret::SecretSource;
+
+/// Number of tracked clients above which the local limiter forgets the clients back within their limit.
+const LOCAL_PRUNE_THRESHOLD: usize = 10_000;
+
+/// GCRA decision run by Redis. Takes the emission interval and the burst tolerance in microseconds, and returns
+/// whether the request is allowed with the microseconds to wait before the next one would be.
+const GCRA_SCRIPT: &str = r#"
+local emission = tonumber(ARGV[1])
+local tolerance = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+local tat = math.max(tonumber(redis.call('GET', KEYS[1])) or now, now)
+if tat - now > tolerance then
+    return {0, tat - now - tolerance}
+end
+tat = tat + emission
+redis.call('SET', KEYS[1], string.format('%d', tat), 'PX', math.ceil((tat - now) / 1000))
+return {1, 0}
+"#;
+
+/// What identifies a client for rate limiting.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum RateLimitKey {
+    /// The client IP address.
+    #[default]
+    ClientIp,
+    /// The value of a request header, such as an API key, falling back to the client IP address when it is absent.
+    Header(String),
+}
+
+/// Redis shared by the proxy instances to enforce a global limit.
+#[derive(Clone)]
+pub struct RedisRateLimitConfig {
+    /// `host:port` of Redis.
+    pub address: String,
+    /// User name sent with the password, for Redis ACLs.
+    pub username: Option<String>,
+    /// Password sent with `AUTH`, if any, read at every connection.
+    pub password: Option<SecretSource>,
+    /// Prefix of the keys holding the counters.
+    pub key_prefix: String,
+    /// How long a decision may take before the request is limited locally instead.
+    pub timeout: Duration,
+    /// How long the local limits are used after Redis failed, before trying it again.
+    pub retry_interval: Duration,
+}
+
+impl Default for RedisRateLimitConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:6379".to_string(),
+            username: None,
+            password: None,
+            key_prefix: "fortifynet:ratelimit:".to_string(),
+            timeout: Duration::from_millis(100),
+            retry_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+impl fmt::Debug for RedisRateLimitConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisRateLimitConfig")
+            .field("address", &self.address)
+            .field("username", &self.username)
+            .field("password", &self.password)
+            .field("key_prefix", &self.key_prefix)
+            .field("timeout", &self.timeout)
+            .field("retry_interval", &self.retry_interval)
+            .finish()
+    }
+}
+
+/// Per-client rate limiting settings.
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    /// Requests allowed per `period` and client; further requests get `429 Too Many Requests` with `Retry-After`.
+    pub requests: u32,
+    /// Period of the limit. Defaults to a minute.
+    pub period: Duration,
+    /// Requests a client may send at once after being idle. Defaults to `requests`.
+    pub burst: Option<u32>,
+    /// What identifies a client.
+    pub key: RateLimitKey,
+    /// Redis enforcing the limit across the proxy instances. Each instance enforces it on its own when `None`.
+    pub redis: Option<RedisRateLimitConfig>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests: 600,
+            period: Duration::from_secs(60),
+            burst: None,
+            key: RateLimitKey::default(),
+            redis: None,
+        }
+    }
+}
+
+/// Decision on a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitDecision {
+    /// The request is within the limit.
+    Allowed,
+    /// The limit is exhausted; the client may retry after the given delay.
+    Limited(Duration),
+}
+
+/// Rate limiter of the requests of each client.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    /// Interval between requests at the sustained rate.
+    emission: Duration,
+    /// How far ahead of the sustained rate a client may be, allowing the burst.
+    tolerance: Duration,
+    /// Theoretical arrival time of the next request of each client, for the local limits.
+    local: Mutex<HashMap<String, Instant>>,
+    redis: Option<Redis>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter, failing on a limit of zero requests or an empty period.
+    pub fn new(config: RateLimitConfig) -> Result<Self> {
+        if config.requests == 0 || config.period.is_zero() {
+            anyhow::bail!("The rate limit must allow requests over a non-empty period");
+        }
+        let emission = config.period / config.requests;
+        let burst = config.burst.unwrap_or(config.requests).max(1);
+        let tolerance = emission * (burst - 1);
+        let redis = config.redis.clone().map(Redis::new);
+        Ok(RateLimiter {
+            config,
+            emission,
+            tolerance,
+            local: Mutex::new(HashMap::new()),
+            redis,
+        })
+    }
+
+    /// Returns the key identifying the client sending a request.
+    pub fn key(&self, parts: &request::Parts, client_ip: IpAddr) -> String {
+        match &self.config.key {
+            RateLimitKey::Header(name) => parts
+                .headers
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(|value| format!("header:{}", value))
+                .unwrap_or_else(|| format!("ip:{}", client_ip)),
+            RateLimitKey::ClientIp => format!("ip:{}", client_ip),
+        }
+    }
+
+    /// Counts a request of the client identified by `key`, deciding whether it is within the limit.
+    pub async fn check(&self, key: &str) -> RateLimitDecision {
+        if let Some(redis) = &self.redis {
+            if let Some(decision) = redis.check(key, self.emission, self.tolerance).await {
+                return decision;
+            }
+        }
+        self.check_local(key)
+    }
+
+    /// Decides on a request with the limits of this instance.
+    fn check_local(&self, key: &str) -> RateLimitDecision {
+        let now = Instant::now();
+        let mut local = self.local.lock().unwrap();
+        if local.len() >= LOCAL_PRUNE_THRESHOLD {
+            local.retain(|_, tat| *tat > now);
+        }
+        let tat = local.get(key).map_or(now, |tat| (*tat).max(now));
+        let ahead = tat - now;
+        if ahead > self.tolerance {
+            return RateLimitDecision::Limited(ahead - self.tolerance);
+        }
+        local.insert(key.to_string(), tat + self.emission);
+        RateLimitDecision::Allowed
+    }
+}
+
+/// Connections to Redis, used while it answers.
+struct Redis {
+    config: RedisRateLimitConfig,
+    /// Idle connections, reused by the next decisions.
+    idle: Mutex<Vec<BufStream<TcpStream>>>,
+    /// Until when Redis is skipped after a failure.
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Redis {
+    fn new(config: RedisRateLimitConfig) -> Self {
+        Redis {
+            config,
+            idle: Mutex::new(Vec::new()),
+            down_until: Mutex::new(None),
+        }
+    }
+
+    /// Decides on a request in Redis, returning `None` when Redis is unavailable.
+    async fn check(
+        &self,
+        key: &str,
+        emission: Duration,
+        tolerance: Duration,
+    ) -> Option<RateLimitDecision> {
+        let down_until = *self.down_until.lock().unwrap();
+        if down_until.is_some_and(|until| Instant::now() < until) {
+            return None;
+        }
+        let decision = tokio::time::timeout(
+            self.config.timeout,
+            self.eval(key, emission.as_micros(), tolerance.as_micros()),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out after {:?}", self.config.timeout)));
+        match decision {
+            Ok(decision) => {
+                if down_until.is_some() {
+                    info!(
+                        "Redis at {} is back, enforcing the global rate limits",
+                        self.config.address
+                    );
+                    *self.down_until.lock().unwrap() = None;
+                }
+                Some(decision)
+            }
+            Err(err) => {
+                if down_until.is_none() {
+                    warn!(
+                        "Redis at {} failed, enforcing local rate limits: {}",
+                        self.config.address, err
+                    );
+                }
+                *self.down_until.lock().unwrap() =
+                    Some(Instant::now() + self.config.retry_interval);
+                None
+            }
+        }
+    }
+
+    /// Runs the GCRA script for `key` on an idle or new connection.
+    async fn eval(&self, key: &str, emission: u128, tolerance: u128) -> Result<RateLimitDecision> {
+        let key = format!("{}{}", self.config.key_prefix, key);
+        let (emission, tolerance) = (emission.to_string(), tolerance.to_string());
+        let args = ["EVAL", GCRA_SCRIPT, "1", &key, &emission, &tolerance];
+        let idle = self.idle.lock().unwrap().pop();
+        let (connection, reply) = match idle {
+            Some(mut connection) => match command(&mut connection, &args).await {
+                Ok(reply) => (connection, reply),
+                // Idle connections are closed by Redis restarts and timeouts, so the command is sent again once
+                Err(err) => {
+                    debug!("Idle Redis connection failed, reconnecting: {}", err);
+                    let mut connection = self.connect().await?;
+                    let reply = command(&mut connection, &args).await?;
+                    (connection, reply)
+                }
+            },
+            None => {
+                let mut connection = self.connect().await?;
+                let reply = command(&mut connection, &args).await?;
+                (connection, reply)
+            }
+        };
+        // The connection is only reused after a complete exchange, so no stale reply is left on it
+        self.idle.lock().unwrap().push(connection);
+        match reply {
+            Reply::Array(values) => match values.as_slice() {
+                [Reply::Integer(1), _] => Ok(RateLimitDecision::Allowed),
+                [Reply::Integer(0), Reply::Integer(wait)] => Ok(RateLimitDecision::Limited(
+                    Duration::from_micros((*wait).max(0) as u64),
+                )),
+                _ => anyhow::bail!("Unexpected reply to the rate limit script: {:?}", values),
+            },
+            reply => anyhow::bail!("Unexpected reply to the rate limit script: {:?}", reply),
+        }
+    }
+
+    /// Opens a connection, authenticating when a password is configured.
+    async fn connect(&self) -> Result<BufStream<TcpStream>> {
+        let stream = TcpStream::connect(&self.config.address)
+            .await
+            .context(format!(
+                "Failed to connect to Redis at {}",
+                self.config.address
+            ))?;
+        stream.set_nodelay(true)?;
+        let mut connection = BufStream::new(stream);
+        if let Some(password) = &self.config.password {
+            let password = &password.read()?;
+            let reply = match &self.config.username {
+                Some(username) => command(&mut connection, &["AUTH", username, password]).await?,
+                None => command(&mut connection, &["AUTH", password]).await?,
+            };
+            if let Reply::Error(err) = reply {
+                anyhow::bail!("Redis authentication failed: {}", err);
+            }
+        }
+        Ok(connection)
+    }
+}
+
+/// A RESP reply, keeping only what the rate limiting needs.
+#[derive(Debug)]
+enum Reply {
+    /// A status such as `OK`.
+    Simple,
+    Error(String),
+    Integer(i64),
+    /// A bulk string, whose content is skipped.
+    Bulk,
+    Array(Vec<Reply>),
+}
+
+/// Sends a command, returning its reply. Error replies are returned, not failures.
+async fn command(connection: &mut BufStream<TcpStream>, args: &[&str]) -> Result<Reply> {
+    let mut encoded = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        encoded.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        encoded.extend_from_slice(arg.as_bytes());
+        encoded.extend_from_slice(b"\r\n");
+    }
+    connection.write_all(&encoded).await?;
+    connection.flush().await?;
+    match read_reply(connection).await? {
+        Reply::Error(err) if !args[0].eq_ignore_ascii_case("AUTH") => {
+            anyhow::bail!("Redis error: {}", err)
+        }
+        reply => Ok(reply),
+    }
+}
+
+/// Reads a reply, recursing into arrays.
+fn read_reply(connection: &mut BufStream<TcpStream>) -> BoxFuture<'_, Result<Reply>> {
+    Box::pin(async move {
+        let mut line = String::new();
+        if connection.read_line(&mut line).await? == 0 {
+            anyhow::bail!("Redis closed the connection");
+        }
+        let line = line.trim_end_matches("\r\n");
+        let (kind, value) = line.split_at(line.len().min(1));
+        let integer = || -> Result<i64> {
+            value
+                .parse()
+                .context(format!("Invalid Redis reply: {}", line))
+        };
+        Ok(match kind {
+            "+" => Reply::Simple,
+            "-" => Reply::Error(value.to_string()),
+            ":" => Reply::Integer(integer()?),
+            "$" => match integer()? {
+                length if length < 0 => Reply::Bulk,
+                length => {
+                    // Skips the content and its CRLF
+                    let mut skipped = vec![0; length as usize + 2];
+                    connection.read_exact(&mut skipped).await?;
+                    Reply::Bulk
+                }
+            },
+            "*" => {
+                let mut values = Vec::new();
+                for _ in 0..integer()?.max(0) {
+                    values.push(read_reply(connection).await?);
+                }
+                Reply::Array(values)
+            }
+            _ => anyhow::bail!("Invalid Redis reply: {}", line),
+        })
+    })
+}
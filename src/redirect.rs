@@ -0,0 +1,242 @@
+//! Redirects answered by upstreams: following them on behalf of clients, and caching permanent redirects when they
+//! are handed to clients instead.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    header::{HeaderValue, CACHE_CONTROL, LOCATION},
+    Body, Response, StatusCode,
+};
+use url::Url;
+
+use crate::admission;
+
+/// Number of redirects cached; storing another one evicts the expired ones, or else the oldest.
+const MAX_CACHED_REDIRECTS: usize = 10_000;
+/// Longest time a redirect is cached for, whatever its `max-age`.
+const MAX_REDIRECT_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Redirect following settings.
+#[derive(Clone, Debug)]
+pub struct FollowRedirectsConfig {
+    /// Maximum number of redirects followed for a request; a longer chain is answered with `508 Loop Detected`.
+    /// Defaults to 5.
+    pub max_hops: usize,
+    /// Whether only redirects to the origin of the request are followed, handing the others to the client.
+    /// Defaults to `true`.
+    pub same_origin_only: bool,
+}
+
+impl Default for FollowRedirectsConfig {
+    fn default() -> Self {
+        Self {
+            max_hops: 5,
+            same_origin_only: true,
+        }
+    }
+}
+
+/// Whether `status` redirects to the `Location` of the response.
+pub(crate) fn is_redirect(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::SEE_OTHER
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT
+    )
+}
+
+/// Returns the target of a redirect, resolving a relative `Location` against `base`, the URL of the request.
+pub(crate) fn location(response: &Response<Body>, base: &Url) -> Option<Url> {
+    let location = response.headers().get(LOCATION)?.to_str().ok()?;
+    base.join(location).ok()
+}
+
+/// Whether `a` and `b` have the same scheme, host and port.
+pub(crate) fn same_origin(a: &Url, b: &Url) -> bool {
+    a.origin() == b.origin()
+}
+
+/// A cached `301` or `308` redirect.
+#[derive(Clone, Debug)]
+struct CachedRedirect {
+    status: StatusCode,
+    location: HeaderValue,
+    /// Order in which the redirect was stored, to evict the oldest first.
+    sequence: u64,
+    expires: Option<Instant>,
+}
+
+/// Permanent redirects handed to clients, by cache key, replayed without asking the upstream again.
+///
+/// Redirects are cacheable unless `Cache-Control` forbids it with `no-store` or `private`; they expire after their
+/// `s-maxage` or `max-age` when given, at most a year, and are kept until the cache is flushed or they are evicted
+/// otherwise.
+#[derive(Default)]
+pub struct RedirectCache {
+    redirects: Mutex<HashMap<String, CachedRedirect>>,
+    stored: AtomicU64,
+}
+
+impl RedirectCache {
+    /// Stores `response` under `key` if it is a cacheable permanent redirect.
+    pub(crate) fn store(&self, key: &str, response: &Response<Body>) {
+        let status = response.status();
+        if status != StatusCode::MOVED_PERMANENTLY && status != StatusCode::PERMANENT_REDIRECT {
+            return;
+        }
+        let location = match response.headers().get(LOCATION) {
+            Some(location) => location.clone(),
+            None => return,
+        };
+        let directives: Vec<String> = response
+            .headers()
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|directive| directive.trim().to_ascii_lowercase())
+            .collect();
+        if directives
+            .iter()
+            .any(|directive| directive == "no-store" || directive == "private")
+        {
+            return;
+        }
+        let max_age = |name: &str| {
+            directives.iter().find_map(|directive| {
+                let (directive, seconds) = directive.split_once('=')?;
+                (directive == name).then(|| seconds.trim_matches('"').parse::<u64>().ok())?
+            })
+        };
+        let now = Instant::now();
+        let expires = max_age("s-maxage")
+            .or_else(|| max_age("max-age"))
+            .and_then(|seconds| {
+                now.checked_add(Duration::from_secs(seconds).min(MAX_REDIRECT_AGE))
+            });
+        let redirect = CachedRedirect {
+            status,
+            location,
+            sequence: self.stored.fetch_add(1, Ordering::Relaxed),
+            expires,
+        };
+        let mut redirects = self.redirects.lock().unwrap();
+        if redirects.len() >= MAX_CACHED_REDIRECTS && !redirects.contains_key(key) {
+            redirects.retain(|_, redirect| redirect.expires.is_none_or(|expires| expires > now));
+            if redirects.len() >= MAX_CACHED_REDIRECTS {
+                let oldest = redirects
+                    .iter()
+                    .min_by_key(|(_, redirect)| redirect.sequence)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    redirects.remove(&oldest);
+                }
+            }
+        }
+        redirects.insert(key.to_string(), redirect);
+    }
+
+    /// Returns the cached redirect of `key` as a response, unless there is none or it expired.
+    pub(crate) fn lookup(&self, key: &str) -> Option<Response<Body>> {
+        let mut redirects = self.redirects.lock().unwrap();
+        let redirect = redirects.get(key)?;
+        if redirect
+            .expires
+            .is_some_and(|expires| expires <= Instant::now())
+        {
+            redirects.remove(key);
+            return None;
+        }
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = redirect.status;
+        response
+            .headers_mut()
+            .insert(LOCATION, redirect.location.clone());
+        Some(response)
+    }
+
+    /// Removes the redirects of `namespace`, or all of them, returning how many were removed.
+    pub(crate) fn flush(&self, namespace: Option<&str>) -> usize {
+        let mut redirects = self.redirects.lock().unwrap();
+        let before = redirects.len();
+        match namespace {
+            Some(namespace) => {
+                redirects.retain(|key, _| admission::key_namespace(key) != Some(namespace))
+            }
+            None => redirects.clear(),
+        }
+        before - redirects.len()
+    }
+
+    /// Removes the redirects whose key `matches`, returning their keys.
+    pub(crate) fn remove_matching(&self, matches: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut redirects = self.redirects.lock().unwrap();
+        let removed: Vec<String> = redirects
+            .keys()
+            .filter(|key| matches(key))
+            .cloned()
+            .collect();
+        for key in &removed {
+            redirects.remove(key);
+        }
+        removed
+    }
+
+    /// Returns the number of cached redirects.
+    pub fn len(&self) -> usize {
+        self.redirects.lock().unwrap().len()
+    }
+
+    /// Whether no redirect is cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn permanent_redirect(cache_control: &str) -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header(LOCATION, "/new")
+            .header(CACHE_CONTROL, cache_control)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn clamps_long_max_ages() {
+        let cache = RedirectCache::default();
+        cache.store("a", &permanent_redirect("max-age=18446744073709551615"));
+        assert!(cache.lookup("a").is_some());
+        cache.store("b", &permanent_redirect("max-age=0"));
+        assert!(cache.lookup("b").is_none());
+        cache.store("c", &permanent_redirect("private"));
+        assert!(cache.lookup("c").is_none());
+    }
+
+    #[test]
+    fn evicts_the_oldest_redirects() {
+        let cache = RedirectCache::default();
+        for i in 0..MAX_CACHED_REDIRECTS + 10 {
+            cache.store(&i.to_string(), &permanent_redirect("public"));
+        }
+        assert_eq!(cache.len(), MAX_CACHED_REDIRECTS);
+        assert!(cache.lookup("0").is_none());
+        assert!(cache
+            .lookup(&(MAX_CACHED_REDIRECTS + 9).to_string())
+            .is_some());
+    }
+}
@@ -0,0 +1,254 @@
+//! Sizing of the proxy to the CPU and memory limits of its container, read from its cgroup (v1 or v2).
+//!
+//! Without explicit settings, the runtime gets a worker thread per CPU of the quota, the cache is bounded to a share
+//! of the memory limit, and connections beyond what the memory limit can hold wait to be accepted, rather than the
+//! proxy being killed for running out of memory.
+
+use std::{fmt, fs, io, num::NonZeroUsize, path::Path};
+
+use serde::Serialize;
+use tokio::runtime::{Builder, Runtime};
+
+/// Mount point of the cgroup hierarchy.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Memory limits from this many bytes up stand for no limit, as cgroup v1 reports one as a huge number.
+const UNLIMITED_MEMORY: u64 = 1 << 60;
+
+/// Settings overriding the sizes derived from the container limits.
+#[derive(Clone, Debug)]
+pub struct ResourceConfig {
+    /// Number of worker threads of the runtime. Defaults to the CPU quota rounded up, or the number of CPUs.
+    pub worker_threads: Option<usize>,
+    /// Maximum number of threads of the blocking pool of the runtime, running file I/O, DNS lookups and other
+    /// blocking work. Defaults to 512.
+    pub max_blocking_threads: Option<usize>,
+    /// Number of tasks a worker thread runs between polls for I/O and timer events. Lower values get new connections
+    /// and timers handled sooner under load, at the cost of throughput. Defaults to 61.
+    pub event_interval: Option<u32>,
+    /// Number of worker threads of the runtime of its own serving the dashboard and the admin API, so heavy
+    /// dashboard queries never hold up the threads accepting and serving connections. With 0, they are served on
+    /// the runtime of the proxy. Defaults to 1.
+    pub dashboard_threads: usize,
+    /// Number of client connections handled at once, further ones waiting to be accepted. Defaults to half the
+    /// memory limit divided by `memory_per_connection`, or unlimited without a memory limit.
+    pub max_connections: Option<usize>,
+    /// Share of the memory limit given to the cache when `cache_admission.max_total_size` is not set. Defaults to
+    /// 0.25.
+    pub cache_memory_fraction: f64,
+    /// Estimated memory used by a client connection, in bytes. Defaults to 256 KiB.
+    pub memory_per_connection: usize,
+    /// Memory the cache, the bodies buffered by requests and the metrics may take together, in bytes, before cached
+    /// responses are evicted and requests shed. Defaults to three quarters of the memory limit, or unlimited without
+    /// a memory limit.
+    pub memory_budget: Option<usize>,
+}
+
+impl Default for ResourceConfig {
+    fn default() -> Self {
+        Self {
+            worker_threads: None,
+            max_blocking_threads: None,
+            event_interval: None,
+            dashboard_threads: 1,
+            max_connections: None,
+            cache_memory_fraction: 0.25,
+            memory_per_connection: 256 * 1024,
+            memory_budget: None,
+        }
+    }
+}
+
+/// CPU and memory limits of the cgroup of the process.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct ContainerLimits {
+    /// CPU quota, in CPUs. `None` without a quota.
+    pub cpus: Option<f64>,
+    /// Memory limit, in bytes. `None` without a limit.
+    pub memory_bytes: Option<u64>,
+}
+
+impl ContainerLimits {
+    /// Reads the limits of the cgroup of the process and of its ancestors, keeping the lowest. Limits that cannot be
+    /// read, such as outside of Linux, are left unset.
+    pub fn detect() -> Self {
+        let root = Path::new(CGROUP_ROOT);
+        if root.join("cgroup.controllers").exists() {
+            detect_v2(root)
+        } else {
+            detect_v1(root)
+        }
+    }
+}
+
+/// Sizes of the runtime, connection limit and cache of the proxy.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ResourceSizing {
+    /// Limits the sizes were derived from.
+    pub limits: ContainerLimits,
+    /// Number of worker threads of the runtime.
+    pub worker_threads: usize,
+    /// Number of client connections handled at once. Unlimited when `None`.
+    pub max_connections: Option<usize>,
+    /// Total size of the cached bodies when not configured explicitly. Unlimited when `None`.
+    pub cache_max_bytes: Option<usize>,
+    /// Memory the cache, the buffered bodies and the metrics may take together. Unlimited when `None`.
+    pub memory_budget: Option<usize>,
+}
+
+impl ResourceSizing {
+    /// Derives the sizes from `limits`, unless given in `config`.
+    pub fn new(config: &ResourceConfig, limits: ContainerLimits) -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let worker_threads = config
+            .worker_threads
+            .unwrap_or_else(|| match limits.cpus {
+                Some(quota) => (quota.ceil() as usize).min(cpus),
+                None => cpus,
+            })
+            .max(1);
+        let memory = limits
+            .memory_bytes
+            .map(|memory| usize::try_from(memory).unwrap_or(usize::MAX));
+        let max_connections = config.max_connections.or_else(|| {
+            memory.map(|memory| (memory / 2 / config.memory_per_connection.max(1)).max(1))
+        });
+        let cache_max_bytes =
+            memory.map(|memory| (memory as f64 * config.cache_memory_fraction) as usize);
+        let memory_budget = config
+            .memory_budget
+            .or_else(|| memory.map(|memory| memory / 4 * 3));
+        ResourceSizing {
+            limits,
+            worker_threads,
+            max_connections,
+            cache_max_bytes,
+            memory_budget,
+        }
+    }
+
+    /// Detects the container limits and derives the sizes from them.
+    pub fn detect(config: &ResourceConfig) -> Self {
+        Self::new(config, ContainerLimits::detect())
+    }
+
+    /// Builds a multi-threaded runtime with the worker threads of the sizing, tuned with the blocking pool size and
+    /// event interval of `config`.
+    pub fn runtime(&self, config: &ResourceConfig) -> io::Result<Runtime> {
+        let mut builder = Builder::new_multi_thread();
+        builder.worker_threads(self.worker_threads).enable_all();
+        if let Some(threads) = config.max_blocking_threads {
+            builder.max_blocking_threads(threads.max(1));
+        }
+        if let Some(interval) = config.event_interval {
+            builder.event_interval(interval.max(1));
+        }
+        builder.build()
+    }
+}
+
+impl fmt::Display for ResourceSizing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} CPUs and {} bytes of memory available, {} worker threads, {} connections, {} bytes of cache, {} \
+             bytes of memory budget",
+            describe(self.limits.cpus),
+            describe(self.limits.memory_bytes),
+            self.worker_threads,
+            describe(self.max_connections),
+            describe(self.cache_max_bytes),
+            describe(self.memory_budget)
+        )
+    }
+}
+
+/// Formats an optional limit, `None` being unlimited.
+fn describe<T: ToString>(limit: Option<T>) -> String {
+    limit.map_or_else(|| "unlimited".to_string(), |limit| limit.to_string())
+}
+
+/// Reads the limits of the cgroup v2 of the process.
+fn detect_v2(root: &Path) -> ContainerLimits {
+    let own = own_cgroup("");
+    ContainerLimits {
+        cpus: lowest(root, &own, |dir| read_cpu_max(&dir.join("cpu.max"))),
+        memory_bytes: lowest(root, &own, |dir| read_bytes(&dir.join("memory.max"))),
+    }
+}
+
+/// Reads the limits of the cgroup v1 of the process, in the hierarchies mounted under `root`.
+fn detect_v1(root: &Path) -> ContainerLimits {
+    let cpu = ["cpu,cpuacct", "cpu"]
+        .iter()
+        .map(|controller| root.join(controller))
+        .find(|mount| mount.exists());
+    let cpus = cpu.and_then(|mount| {
+        lowest(&mount, &own_cgroup("cpu"), |dir| {
+            let quota: i64 = read_number(&dir.join("cpu.cfs_quota_us"))?;
+            let period: i64 = read_number(&dir.join("cpu.cfs_period_us"))?;
+            (quota > 0 && period > 0).then(|| quota as f64 / period as f64)
+        })
+    });
+    ContainerLimits {
+        cpus,
+        memory_bytes: lowest(&root.join("memory"), &own_cgroup("memory"), |dir| {
+            read_bytes(&dir.join("memory.limit_in_bytes"))
+        }),
+    }
+}
+
+/// Returns the path of the cgroup of the process in the hierarchy of `controller`, the empty name being the cgroup
+/// v2 hierarchy.
+fn own_cgroup(controller: &str) -> String {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+    cgroups
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let controllers = fields.nth(1)?;
+            let path = fields.next()?;
+            let matches = match controller {
+                "" => controllers.is_empty(),
+                _ => controllers.split(',').any(|name| name == controller),
+            };
+            matches.then(|| path.trim_start_matches('/').to_string())
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the lowest limit `read` finds in the cgroup `own` under `mount` and in its ancestors. Cgroups missing from
+/// the mount, such as those of the host seen from a container, are skipped.
+fn lowest<T: PartialOrd>(mount: &Path, own: &str, read: impl Fn(&Path) -> Option<T>) -> Option<T> {
+    let mut lowest: Option<T> = None;
+    let mut dir = mount.join(own);
+    loop {
+        if let Some(limit) = read(&dir) {
+            if lowest.as_ref().is_none_or(|lowest| limit < *lowest) {
+                lowest = Some(limit);
+            }
+        }
+        if dir.as_path() == mount || !dir.pop() {
+            return lowest;
+        }
+    }
+}
+
+/// Reads a cgroup v2 `cpu.max` file, `max 100000` or `200000 100000` for two CPUs.
+fn read_cpu_max(path: &Path) -> Option<f64> {
+    let content = fs::read_to_string(path).ok()?;
+    let mut fields = content.split_whitespace();
+    let quota: f64 = fields.next()?.parse().ok()?;
+    let period: f64 = fields.next()?.parse().ok()?;
+    (quota > 0.0 && period > 0.0).then(|| quota / period)
+}
+
+/// Reads a memory limit, `max` or too large a value standing for no limit.
+fn read_bytes(path: &Path) -> Option<u64> {
+    let bytes: u64 = read_number(path)?;
+    (bytes < UNLIMITED_MEMORY).then_some(bytes)
+}
+
+/// Reads a file holding a single number.
+fn read_number<T: std::str::FromStr>(path: &Path) -> Option<T> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
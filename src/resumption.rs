@@ -0,0 +1,192 @@
+//! TLS session resumption, so repeat clients and connections to the same upstreams skip the full handshake.
+//!
+//! Clients are given session tickets encrypted with a key replaced at a configured interval, and clients without
+//! ticket support can resume by session ID from a cache shared by all connections. Sessions with upstreams are kept
+//! in a cache of their own.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use rustls::{
+    client::Resumption,
+    server::{ProducesTickets, ServerSessionMemoryCache},
+    ClientConfig, ServerConfig,
+};
+
+/// Session resumption settings.
+#[derive(Clone, Debug)]
+pub struct SessionResumptionConfig {
+    /// Interval at which the key encrypting session tickets is replaced. Tickets remain valid for two intervals, as
+    /// the previous key still decrypts them. Defaults to 6 hours.
+    pub ticket_rotation: Duration,
+    /// Number of client sessions kept for resumption by session ID. Defaults to 256.
+    pub session_cache_size: usize,
+    /// Number of upstream sessions kept for resuming connections to upstreams, 0 disabling upstream resumption.
+    /// Defaults to 256.
+    pub upstream_cache_size: usize,
+}
+
+impl Default for SessionResumptionConfig {
+    fn default() -> Self {
+        Self {
+            ticket_rotation: Duration::from_secs(6 * 60 * 60),
+            session_cache_size: 256,
+            upstream_cache_size: 256,
+        }
+    }
+}
+
+/// Keys of a [`RotatingTicketer`].
+struct TicketKeys {
+    current: LessSafeKey,
+    previous: Option<LessSafeKey>,
+    rotated_at: Instant,
+}
+
+/// Encrypts session tickets with ChaCha20-Poly1305, replacing the key every `rotation` and keeping the previous one
+/// to decrypt the tickets it issued.
+struct RotatingTicketer {
+    rotation: Duration,
+    random: SystemRandom,
+    keys: Mutex<TicketKeys>,
+}
+
+impl RotatingTicketer {
+    fn new(rotation: Duration) -> Result<Self> {
+        let random = SystemRandom::new();
+        let current = new_key(&random)?;
+        Ok(RotatingTicketer {
+            rotation,
+            random,
+            keys: Mutex::new(TicketKeys {
+                current,
+                previous: None,
+                rotated_at: Instant::now(),
+            }),
+        })
+    }
+
+    /// Replaces the current key if it is older than the rotation interval, dropping the previous one if it is older
+    /// than two intervals.
+    fn rotate(&self, keys: &mut TicketKeys) {
+        let age = keys.rotated_at.elapsed();
+        if age < self.rotation {
+            return;
+        }
+        let current = match new_key(&self.random) {
+            Ok(key) => key,
+            Err(err) => {
+                log::error!("Failed to rotate the session ticket key: {}", err);
+                return;
+            }
+        };
+        let previous = std::mem::replace(&mut keys.current, current);
+        keys.previous = (age < self.rotation * 2).then_some(previous);
+        keys.rotated_at = Instant::now();
+        log::debug!("Rotated the session ticket key");
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        u32::try_from(self.rotation.as_secs().saturating_mul(2)).unwrap_or(u32::MAX)
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut keys = self.keys.lock().unwrap();
+        self.rotate(&mut keys);
+        let mut nonce = [0; NONCE_LEN];
+        self.random.fill(&mut nonce).ok()?;
+        let mut ticket = Vec::with_capacity(NONCE_LEN + plain.len() + CHACHA20_POLY1305.tag_len());
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(plain);
+        let tag = keys
+            .current
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut ticket[NONCE_LEN..],
+            )
+            .ok()?;
+        ticket.extend_from_slice(tag.as_ref());
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let nonce = cipher.get(..NONCE_LEN)?;
+        let sealed = cipher.get(NONCE_LEN..)?;
+        let mut keys = self.keys.lock().unwrap();
+        self.rotate(&mut keys);
+        let plain = [Some(&keys.current), keys.previous.as_ref()]
+            .into_iter()
+            .flatten()
+            .find_map(|key| {
+                let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+                let mut plain = sealed.to_vec();
+                let len = key
+                    .open_in_place(nonce, Aad::empty(), &mut plain)
+                    .ok()?
+                    .len();
+                plain.truncate(len);
+                Some(plain)
+            });
+        plain
+    }
+}
+
+/// Generates a random ticket key.
+fn new_key(random: &SystemRandom) -> Result<LessSafeKey> {
+    let mut key = [0; 32];
+    random
+        .fill(&mut key)
+        .map_err(|_| anyhow::anyhow!("Failed to generate a session ticket key"))?;
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+        .map_err(|_| anyhow::anyhow!("Invalid session ticket key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Session tickets and caches shared by the TLS connections of the proxy.
+pub struct SessionResumption {
+    ticketer: Arc<RotatingTicketer>,
+    sessions: Arc<ServerSessionMemoryCache>,
+    upstream_cache_size: usize,
+}
+
+impl SessionResumption {
+    /// Creates the ticket key and the session caches.
+    pub fn new(config: SessionResumptionConfig) -> Result<Self> {
+        if config.ticket_rotation < Duration::from_secs(1) {
+            anyhow::bail!("The session ticket rotation interval must be at least one second");
+        }
+        Ok(SessionResumption {
+            ticketer: Arc::new(RotatingTicketer::new(config.ticket_rotation)?),
+            sessions: ServerSessionMemoryCache::new(config.session_cache_size),
+            upstream_cache_size: config.upstream_cache_size,
+        })
+    }
+
+    /// Issues tickets to the clients of `server`, and caches their sessions.
+    pub(crate) fn configure(&self, server: &mut ServerConfig) {
+        server.ticketer = self.ticketer.clone();
+        server.session_storage = self.sessions.clone();
+    }
+
+    /// Caches the sessions of the upstream connections of `client`.
+    pub(crate) fn configure_upstream(&self, client: &mut ClientConfig) {
+        client.resumption = match self.upstream_cache_size {
+            0 => Resumption::disabled(),
+            size => Resumption::in_memory_sessions(size),
+        };
+    }
+}
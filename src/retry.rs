@@ -0,0 +1,79 @@
+//! Retries of requests whose upstream failed, limited to requests that are safe to repeat and to a time budget per
+//! request.
+
+use std::time::{Duration, SystemTime};
+
+use hyper::{header::RETRY_AFTER, Body, HeaderMap, Method, Response, StatusCode};
+
+/// Retry settings.
+#[derive(Clone, Debug)]
+pub struct RetryConfig {
+    /// Maximum number of retries of a request. Defaults to 2.
+    pub max_retries: u32,
+    /// Upstream statuses retried, besides unreachable upstreams. Defaults to 502, 503 and 504.
+    pub statuses: Vec<StatusCode>,
+    /// Delay before the first retry, doubled for every further one, unless the upstream asks for another delay with
+    /// `Retry-After`. Defaults to 100 milliseconds.
+    pub backoff: Duration,
+    /// Time a request may take including its retries; no retry is attempted once waiting for it would take the
+    /// request past the budget. Defaults to 10 seconds.
+    pub budget: Duration,
+    /// Header marking requests of non-idempotent methods as safe to retry. Defaults to `Idempotency-Key`.
+    pub idempotency_header: String,
+    /// Largest request body buffered to be sent again; requests with larger bodies are forwarded once, without
+    /// retries. Defaults to 1 MiB.
+    pub max_body_size: usize,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            statuses: vec![
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ],
+            backoff: Duration::from_millis(100),
+            budget: Duration::from_secs(10),
+            idempotency_header: "Idempotency-Key".to_string(),
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Whether a request may be sent again: its method is idempotent, or it carries an idempotency key.
+    pub fn is_repeatable(&self, method: &Method, headers: &HeaderMap) -> bool {
+        matches!(
+            *method,
+            Method::GET
+                | Method::HEAD
+                | Method::OPTIONS
+                | Method::TRACE
+                | Method::PUT
+                | Method::DELETE
+        ) || headers.contains_key(self.idempotency_header.as_str())
+    }
+
+    /// Returns how long to wait before retrying after `response` to the attempt numbered `retry`, starting at 0:
+    /// the `Retry-After` of the response when it has one, or the exponential backoff otherwise.
+    pub fn delay(&self, retry: u32, response: &Response<Body>) -> Duration {
+        retry_after(response).unwrap_or_else(|| self.backoff.saturating_mul(1 << retry.min(16)))
+    }
+}
+
+/// Returns the delay requested by the `Retry-After` header of `response`, given in seconds or as an HTTP date.
+pub(crate) fn retry_after(response: &Response<Body>) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            Some(
+                date.duration_since(SystemTime::now())
+                    .unwrap_or(Duration::ZERO),
+            )
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Background revalidation of the most requested cache entries, so hot content is kept in step with its origin
+//! without a client waiting on the upstream.
+//!
+//! Every interval, the entries with the most hits since the previous round are requested again from the upstream that
+//! answered them, with the headers of the request that filled them and the validators of the cached response. An
+//! unchanged response costs the origin a `304 Not Modified` and leaves the entry as it is, while a changed one drops
+//! it, so the next request fetches it again through the whole pipeline. Revalidations do not go through the checks
+//! made on client requests, so entries filled by requests carrying credentials are never revalidated.
+
+use std::{cmp::Reverse, collections::HashMap, sync::Mutex, time::Duration};
+
+use hyper::{
+    header::{
+        AUTHORIZATION, CONNECTION, COOKIE, ETAG, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        IF_RANGE, IF_UNMODIFIED_SINCE, LAST_MODIFIED, PROXY_AUTHORIZATION, RANGE,
+    },
+    Body, HeaderMap, Method, Request, StatusCode, Uri,
+};
+use serde::Serialize;
+
+/// Cache revalidation settings.
+#[derive(Clone, Debug)]
+pub struct RevalidationConfig {
+    /// Interval between rounds of revalidation. Defaults to 60 seconds.
+    pub interval: Duration,
+    /// Number of entries revalidated per round, the most hit since the previous round first. Defaults to 100.
+    pub top_n: usize,
+    /// Number of entries revalidated at once. Defaults to 8.
+    pub concurrency: usize,
+}
+
+impl Default for RevalidationConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            top_n: 100,
+            concurrency: 8,
+        }
+    }
+}
+
+/// Counts of the revalidations made.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct RevalidationStats {
+    /// Number of rounds run.
+    pub rounds: u64,
+    /// Number of entries the origin answered as unchanged.
+    pub not_modified: u64,
+    /// Number of entries the origin changed, dropped from the cache to be fetched again.
+    pub refreshed: u64,
+    /// Number of revalidations that failed or got an error status, leaving the entry as it is.
+    pub failed: u64,
+}
+
+/// How to request a cached response again, and how often it was hit.
+struct Entry {
+    uri: Uri,
+    /// Upstream chosen for the request, instead of the configured target address.
+    upstream: Option<String>,
+    headers: HeaderMap,
+    hits: u64,
+}
+
+/// A conditional request revalidating the cache entry under `key`.
+pub(crate) struct DueRevalidation {
+    pub(crate) key: String,
+    pub(crate) request: Request<Body>,
+    /// Upstream chosen for the request that filled the entry, instead of the configured target address.
+    pub(crate) upstream: Option<String>,
+}
+
+/// Picks the cache entries to revalidate and counts the outcomes.
+pub struct Revalidator {
+    config: RevalidationConfig,
+    entries: Mutex<HashMap<String, Entry>>,
+    stats: Mutex<RevalidationStats>,
+}
+
+impl Revalidator {
+    /// Creates a revalidator with the given settings.
+    pub fn new(config: RevalidationConfig) -> Self {
+        Revalidator {
+            config,
+            entries: Mutex::new(HashMap::new()),
+            stats: Mutex::new(RevalidationStats::default()),
+        }
+    }
+
+    /// Returns the interval between rounds.
+    pub fn interval(&self) -> Duration {
+        self.config.interval
+    }
+
+    /// Returns the number of entries revalidated at once.
+    pub fn concurrency(&self) -> usize {
+        self.config.concurrency.max(1)
+    }
+
+    /// Remembers the request for `uri` with `request_headers`, sent to `upstream`, that got the response cached under
+    /// `key`, along with the validators among `response_headers`. Requests carrying credentials are not remembered,
+    /// as they would be sent again on behalf of no client.
+    pub(crate) fn remember(
+        &self,
+        key: &str,
+        uri: Uri,
+        upstream: Option<String>,
+        request_headers: &HeaderMap,
+        response_headers: &HeaderMap,
+    ) {
+        let credentials = [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION];
+        if credentials
+            .iter()
+            .any(|name| request_headers.contains_key(name))
+        {
+            self.entries.lock().unwrap().remove(key);
+            return;
+        }
+        let mut headers = request_headers.clone();
+        for name in [
+            IF_NONE_MATCH,
+            IF_MODIFIED_SINCE,
+            IF_MATCH,
+            IF_UNMODIFIED_SINCE,
+            IF_RANGE,
+            RANGE,
+            CONNECTION,
+        ] {
+            headers.remove(name);
+        }
+        if let Some(etag) = response_headers.get(ETAG) {
+            headers.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = response_headers.get(LAST_MODIFIED) {
+            headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let hits = entries.get(key).map_or(0, |entry| entry.hits);
+        let entry = Entry {
+            uri,
+            upstream,
+            headers,
+            hits,
+        };
+        entries.insert(key.to_string(), entry);
+    }
+
+    /// Records a cache hit on `key`.
+    pub(crate) fn record_hit(&self, key: &str) {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+            entry.hits += 1;
+        }
+    }
+
+    /// Returns the requests revalidating the most hit entries of this round, forgetting the entries no longer
+    /// `cached`. Hits are halved every round, so entries that cool down give way to newly popular ones.
+    pub(crate) fn due(&self, cached: impl Fn(&str) -> bool) -> Vec<DueRevalidation> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|key, _| cached(key));
+        let mut hottest: Vec<(&String, &Entry)> =
+            entries.iter().filter(|(_, entry)| entry.hits > 0).collect();
+        hottest.sort_by_key(|(_, entry)| Reverse(entry.hits));
+        let requests = hottest
+            .into_iter()
+            .take(self.config.top_n)
+            .filter_map(|(key, entry)| {
+                let mut request = Request::builder()
+                    .method(Method::GET)
+                    .uri(entry.uri.clone())
+                    .body(Body::empty())
+                    .ok()?;
+                *request.headers_mut() = entry.headers.clone();
+                Some(DueRevalidation {
+                    key: key.clone(),
+                    request,
+                    upstream: entry.upstream.clone(),
+                })
+            })
+            .collect();
+        entries.values_mut().for_each(|entry| entry.hits /= 2);
+        self.stats.lock().unwrap().rounds += 1;
+        requests
+    }
+
+    /// Records the status answered to a revalidation, or `None` if it failed.
+    pub(crate) fn record_result(&self, status: Option<StatusCode>) {
+        let mut stats = self.stats.lock().unwrap();
+        match status {
+            Some(StatusCode::NOT_MODIFIED) => stats.not_modified += 1,
+            Some(status) if status.is_success() => stats.refreshed += 1,
+            _ => stats.failed += 1,
+        }
+    }
+
+    /// Returns the counts of the revalidations made.
+    pub fn stats(&self) -> RevalidationStats {
+        *self.stats.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn revalidates_uncredentialed_requests_with_their_validators() {
+        let revalidator = Revalidator::new(RevalidationConfig::default());
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(RANGE, HeaderValue::from_static("bytes=0-1"));
+        let uri: Uri = "/public".parse().unwrap();
+        revalidator.remember("public", uri, None, &request_headers, &response_headers);
+        request_headers.insert(COOKIE, HeaderValue::from_static("session=1"));
+        let uri: Uri = "/private".parse().unwrap();
+        revalidator.remember("private", uri, None, &request_headers, &response_headers);
+        revalidator.record_hit("public");
+        revalidator.record_hit("private");
+
+        let due = revalidator.due(|_| true);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].key, "public");
+        let headers = due[0].request.headers();
+        assert_eq!(headers[IF_NONE_MATCH], "\"v1\"");
+        assert!(!headers.contains_key(RANGE));
+    }
+}
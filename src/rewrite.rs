@@ -0,0 +1,249 @@
+//! Translation of upstream URLs to the public origin in responses of the reverse proxy.
+
+use anyhow::{bail, Context, Result};
+use hyper::{
+    header::{
+        HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_LOCATION, CONTENT_TYPE, LOCATION,
+        SET_COOKIE,
+    },
+    Body, Response,
+};
+use url::Url;
+
+use crate::body::{read_limited, LimitedBody};
+
+/// URL translation settings.
+#[derive(Clone, Debug)]
+pub struct UrlRewriteConfig {
+    /// Public origin clients use, such as `https://www.example.com`.
+    pub public_origin: String,
+    /// Upstream origins translated to the public origin, in addition to the upstream of each request.
+    pub upstream_origins: Vec<String>,
+    /// Whether absolute URLs in response bodies are translated too.
+    pub rewrite_bodies: bool,
+    /// Content types whose bodies are translated, which must be textual: `text/*`, JSON, XML or JavaScript.
+    pub content_types: Vec<String>,
+    /// Largest body translated, in bytes; larger bodies are streamed unchanged.
+    pub max_body_size: usize,
+}
+
+impl Default for UrlRewriteConfig {
+    fn default() -> Self {
+        Self {
+            public_origin: String::new(),
+            upstream_origins: Vec::new(),
+            rewrite_bodies: false,
+            content_types: vec!["text/html".to_string(), "application/json".to_string()],
+            max_body_size: 5 * 1024 * 1024,
+        }
+    }
+}
+
+/// An origin split into the forms it appears in.
+struct Origin {
+    /// `scheme://host[:port]`
+    origin: String,
+    /// `host[:port]`
+    authority: String,
+    /// `host`
+    host: String,
+}
+
+impl Origin {
+    fn parse(origin: &str) -> Result<Self> {
+        let url = Url::parse(origin).context(format!("Invalid origin: {}", origin))?;
+        let host = url
+            .host_str()
+            .context(format!("Missing host in origin: {}", origin))?;
+        let authority = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        Ok(Origin {
+            origin: format!("{}://{}", url.scheme(), authority),
+            authority,
+            host: host.to_string(),
+        })
+    }
+}
+
+/// Rewrites upstream URLs in response headers and bodies to the public origin.
+pub struct UrlRewriter {
+    config: UrlRewriteConfig,
+    public: Origin,
+    upstreams: Vec<Origin>,
+}
+
+impl UrlRewriter {
+    /// Parses the configured origins, failing on content types that are not textual.
+    pub fn new(config: UrlRewriteConfig) -> Result<Self> {
+        if let Some(content_type) = config
+            .content_types
+            .iter()
+            .find(|content_type| !is_textual(content_type))
+        {
+            bail!(
+                "Cannot translate URLs in {} bodies, which are not text",
+                content_type
+            );
+        }
+        let public = Origin::parse(&config.public_origin)?;
+        let upstreams = config
+            .upstream_origins
+            .iter()
+            .map(|origin| Origin::parse(origin))
+            .collect::<Result<_>>()?;
+        Ok(UrlRewriter {
+            config,
+            public,
+            upstreams,
+        })
+    }
+
+    /// Translates the URLs of `response`, which was returned by `upstream`.
+    pub async fn rewrite(
+        &self,
+        upstream: Option<&str>,
+        response: Response<Body>,
+    ) -> Result<Response<Body>> {
+        let request_upstream = upstream.and_then(|upstream| Origin::parse(upstream).ok());
+        let upstreams: Vec<&Origin> = request_upstream.iter().chain(&self.upstreams).collect();
+        let (mut parts, body) = response.into_parts();
+
+        for name in [LOCATION, CONTENT_LOCATION] {
+            if let Some(value) = parts
+                .headers
+                .get(&name)
+                .and_then(|value| value.to_str().ok())
+            {
+                if let Some(rewritten) = self.rewrite_location(value, &upstreams) {
+                    parts
+                        .headers
+                        .insert(name, HeaderValue::from_str(&rewritten)?);
+                }
+            }
+        }
+        let cookies: Vec<HeaderValue> = parts
+            .headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| match value.to_str() {
+                Ok(cookie) => {
+                    HeaderValue::from_str(&self.rewrite_cookie_domain(cookie, &upstreams))
+                        .unwrap_or_else(|_| value.clone())
+                }
+                Err(_) => value.clone(),
+            })
+            .collect();
+        if !cookies.is_empty() {
+            parts.headers.remove(SET_COOKIE);
+            for cookie in cookies {
+                parts.headers.append(SET_COOKIE, cookie);
+            }
+        }
+
+        if !self.config.rewrite_bodies || !self.rewrites_body(&parts.headers) {
+            return Ok(Response::from_parts(parts, body));
+        }
+        let announced = parts
+            .headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+        if announced.is_some_and(|size| size > self.config.max_body_size) {
+            return Ok(Response::from_parts(parts, body));
+        }
+        let body = match read_limited(body, self.config.max_body_size).await? {
+            LimitedBody::Complete(body) => body,
+            LimitedBody::Oversized(body) => return Ok(Response::from_parts(parts, body)),
+        };
+        // Bodies in other encodings than UTF-8 are passed unchanged
+        let mut text = match String::from_utf8(body.to_vec()) {
+            Ok(text) => text,
+            Err(_) => return Ok(Response::from_parts(parts, Body::from(body))),
+        };
+        for upstream in &upstreams {
+            text = text
+                .replace(&upstream.origin, &self.public.origin)
+                .replace(
+                    &upstream.origin.replace('/', "\\/"),
+                    &self.public.origin.replace('/', "\\/"),
+                )
+                .replace(
+                    &format!("//{}", upstream.authority),
+                    &format!("//{}", self.public.authority),
+                );
+        }
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(text.len()));
+        Ok(Response::from_parts(parts, Body::from(text)))
+    }
+
+    /// Returns the public URL for a `Location` pointing at an upstream.
+    fn rewrite_location(&self, location: &str, upstreams: &[&Origin]) -> Option<String> {
+        upstreams.iter().find_map(|upstream| {
+            let prefix = location.get(..upstream.origin.len())?;
+            let rest = &location[upstream.origin.len()..];
+            (prefix.eq_ignore_ascii_case(&upstream.origin)
+                && (rest.is_empty() || rest.starts_with(['/', '?', '#'])))
+            .then(|| format!("{}{}", self.public.origin, rest))
+        })
+    }
+
+    /// Replaces a `Domain` attribute naming an upstream host with the public host.
+    fn rewrite_cookie_domain(&self, cookie: &str, upstreams: &[&Origin]) -> String {
+        cookie
+            .split(';')
+            .map(|attribute| {
+                let (name, value) = match attribute.split_once('=') {
+                    Some((name, value)) if name.trim().eq_ignore_ascii_case("domain") => {
+                        (name, value)
+                    }
+                    _ => return attribute.to_string(),
+                };
+                let domain = value.trim().trim_start_matches('.');
+                if upstreams
+                    .iter()
+                    .any(|upstream| upstream.host.eq_ignore_ascii_case(domain))
+                {
+                    format!("{}={}", name, self.public.host)
+                } else {
+                    attribute.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+
+    /// Whether the body of a response with `headers` is translated.
+    fn rewrites_body(&self, headers: &hyper::HeaderMap) -> bool {
+        let encoded = headers
+            .get(CONTENT_ENCODING)
+            .is_some_and(|encoding| encoding.as_bytes() != b"identity");
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase());
+        !encoded
+            && content_type.is_some_and(|content_type| {
+                self.config
+                    .content_types
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(&content_type))
+            })
+    }
+}
+
+/// Whether `content_type` is a textual media type.
+fn is_textual(content_type: &str) -> bool {
+    let content_type = content_type.trim().to_ascii_lowercase();
+    let subtype = content_type
+        .split_once('/')
+        .map_or("", |(_, subtype)| subtype);
+    content_type.starts_with("text/")
+        || matches!(subtype, "json" | "xml" | "javascript" | "ecmascript")
+        || subtype.ends_with("+json")
+        || subtype.ends_with("+xml")
+}
@@ -0,0 +1,282 @@
+//! Serving of a configured robots.txt and enforcement of its rules against crawlers.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::user_agent::UserAgentCategory;
+
+/// Name under which the bots not named in robots.txt are counted, since theirs would be chosen by the client.
+const OTHER_CRAWLERS: &str = "other";
+
+/// What happens to crawlers requesting disallowed paths.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RobotsEnforcement {
+    /// Violations are only counted.
+    Report,
+    /// Violating requests are rejected with `403 Forbidden`.
+    Block,
+    /// Violating requests beyond the given number per minute and crawler are rejected with `429 Too Many Requests`.
+    Throttle(u32),
+}
+
+/// robots.txt settings.
+#[derive(Clone, Debug)]
+pub struct RobotsConfig {
+    /// Content served at `/robots.txt`.
+    pub content: String,
+    /// How crawlers violating the rules are treated.
+    pub enforcement: RobotsEnforcement,
+}
+
+/// Requests of a single crawler.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CrawlerStats {
+    /// Number of requests of the crawler.
+    pub requests: u64,
+    /// Number of requests for disallowed paths.
+    pub violations: u64,
+    /// Number of requests rejected.
+    pub rejected: u64,
+}
+
+/// Outcome of checking a request against the robots.txt rules.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RobotsVerdict {
+    /// The request may proceed.
+    Allow,
+    /// The request must be rejected with `403 Forbidden`.
+    Block,
+    /// The request must be rejected with `429 Too Many Requests`.
+    Throttle,
+}
+
+struct Group {
+    agents: Vec<String>,
+    rules: Vec<(bool, String)>,
+}
+
+/// Parsed robots.txt with per-crawler accounting.
+pub struct Robots {
+    config: RobotsConfig,
+    groups: Vec<Group>,
+    stats: Mutex<HashMap<String, CrawlerStats>>,
+    violations: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl Robots {
+    /// Parses the configured robots.txt.
+    pub fn new(config: RobotsConfig) -> Self {
+        let groups = parse(&config.content);
+        Robots {
+            config,
+            groups,
+            stats: Mutex::new(HashMap::new()),
+            violations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the robots.txt content.
+    pub fn content(&self) -> &str {
+        &self.config.content
+    }
+
+    /// Checks a request for `path` made by `user_agent`, counting it when it comes from a crawler.
+    pub fn check(&self, user_agent: Option<&str>, path: &str) -> RobotsVerdict {
+        let (crawler, group) = match self.crawler(user_agent) {
+            Some(found) => found,
+            None => return RobotsVerdict::Allow,
+        };
+        let allowed = group.is_none_or(|group| is_allowed(&group.rules, path));
+
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(crawler.clone()).or_default();
+        stats.requests += 1;
+        if allowed {
+            return RobotsVerdict::Allow;
+        }
+        stats.violations += 1;
+        let verdict = match self.config.enforcement {
+            RobotsEnforcement::Report => RobotsVerdict::Allow,
+            RobotsEnforcement::Block => RobotsVerdict::Block,
+            RobotsEnforcement::Throttle(max_per_minute) => {
+                let now = Instant::now();
+                let mut violations = self.violations.lock().unwrap();
+                let history = violations.entry(crawler).or_default();
+                while history
+                    .front()
+                    .is_some_and(|at| now.duration_since(*at) >= Duration::from_secs(60))
+                {
+                    history.pop_front();
+                }
+                history.push_back(now);
+                if history.len() > max_per_minute as usize {
+                    RobotsVerdict::Throttle
+                } else {
+                    RobotsVerdict::Allow
+                }
+            }
+        };
+        if verdict != RobotsVerdict::Allow {
+            stats.rejected += 1;
+        }
+        verdict
+    }
+
+    /// Returns the request counts of every crawler seen.
+    pub fn stats(&self) -> HashMap<String, CrawlerStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Identifies the crawler of `user_agent` and the group of rules applying to it.
+    ///
+    /// Crawlers named in a group are identified by that name; other bots fall under the `*` group and are counted
+    /// together as `other`.
+    fn crawler(&self, user_agent: Option<&str>) -> Option<(String, Option<&Group>)> {
+        let lowercase = user_agent?.to_ascii_lowercase();
+        for group in &self.groups {
+            if let Some(agent) = group
+                .agents
+                .iter()
+                .find(|agent| *agent != "*" && lowercase.contains(agent.as_str()))
+            {
+                return Some((agent.clone(), Some(group)));
+            }
+        }
+        if !UserAgentCategory::Bot.matches(user_agent) {
+            return None;
+        }
+        let group = self
+            .groups
+            .iter()
+            .find(|group| group.agents.iter().any(|agent| agent == "*"));
+        Some((OTHER_CRAWLERS.to_string(), group))
+    }
+}
+
+/// Parses robots.txt into groups of user agents sharing `Allow`/`Disallow` rules.
+fn parse(content: &str) -> Vec<Group> {
+    let mut groups: Vec<Group> = Vec::new();
+    let mut in_agents = false;
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field.trim().to_ascii_lowercase(), value.trim()),
+            None => continue,
+        };
+        match field.as_str() {
+            "user-agent" => {
+                if !in_agents {
+                    groups.push(Group {
+                        agents: Vec::new(),
+                        rules: Vec::new(),
+                    });
+                    in_agents = true;
+                }
+                if let Some(group) = groups.last_mut() {
+                    group.agents.push(value.to_ascii_lowercase());
+                }
+            }
+            "allow" | "disallow" => {
+                in_agents = false;
+                // An empty Disallow allows everything and adds no rule
+                if value.is_empty() {
+                    continue;
+                }
+                if let Some(group) = groups.last_mut() {
+                    group.rules.push((field == "allow", value.to_string()));
+                }
+            }
+            _ => in_agents = false,
+        }
+    }
+    groups
+}
+
+/// Whether `path` is allowed: the longest matching rule wins, with `Allow` winning ties.
+fn is_allowed(rules: &[(bool, String)], path: &str) -> bool {
+    rules
+        .iter()
+        .filter(|(_, pattern)| pattern_matches(pattern, path))
+        .max_by_key(|(allow, pattern)| (pattern.len(), *allow))
+        .is_none_or(|(allow, _)| *allow)
+}
+
+/// Matches a robots.txt path pattern supporting `*` wildcards and a trailing `$` anchor.
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    if !path.starts_with(first) {
+        return false;
+    }
+    let mut position = first.len();
+    for part in parts {
+        match path[position..].find(part) {
+            Some(index) => position += index + part.len(),
+            None => return false,
+        }
+    }
+    if !anchored {
+        return true;
+    }
+    match pattern.rsplit_once('*') {
+        Some((_, last)) => path.ends_with(last),
+        None => path.len() == pattern.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROBOTS_TXT: &str = "User-agent: Googlebot\nDisallow: /private\nAllow: /private/open\n\n\
+        User-agent: *\nDisallow: /admin\n";
+
+    #[test]
+    fn counts_unlisted_bots_together() {
+        let robots = Robots::new(RobotsConfig {
+            content: ROBOTS_TXT.to_string(),
+            enforcement: RobotsEnforcement::Block,
+        });
+        let googlebot = Some("Mozilla/5.0 (compatible; Googlebot/2.1)");
+        assert_eq!(robots.check(googlebot, "/private/x"), RobotsVerdict::Block);
+        assert_eq!(
+            robots.check(googlebot, "/private/open"),
+            RobotsVerdict::Allow
+        );
+        assert_eq!(robots.check(googlebot, "/admin"), RobotsVerdict::Allow);
+        for i in 0..100 {
+            let user_agent = format!("<img\tsrc=x\tonerror={}>bot/1.0", i);
+            assert_eq!(
+                robots.check(Some(&user_agent), "/admin"),
+                RobotsVerdict::Block
+            );
+        }
+        assert_eq!(
+            robots.check(Some("Mozilla/5.0"), "/admin"),
+            RobotsVerdict::Allow
+        );
+        let stats = robots.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats["googlebot"].requests, 3);
+        assert_eq!(stats[OTHER_CRAWLERS].rejected, 100);
+    }
+
+    #[test]
+    fn matches_patterns() {
+        assert!(pattern_matches("/a", "/a/b"));
+        assert!(pattern_matches("/*.pdf$", "/docs/file.pdf"));
+        assert!(!pattern_matches("/*.pdf$", "/docs/file.pdf?x"));
+        assert!(pattern_matches("/a$", "/a"));
+        assert!(!pattern_matches("/a$", "/ab"));
+        assert!(!pattern_matches("/b", "/a"));
+    }
+}
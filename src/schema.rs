@@ -0,0 +1,802 @@
+//! Validation of JSON request bodies against a JSON Schema per route, rejecting non-conforming payloads before they
+//! reach the upstream.
+//!
+//! The validator implements the commonly used subset of JSON Schema (draft 2020-12): `type`, `enum`, `const`, the
+//! numeric, string, array and object constraints, `pattern`, the `allOf`, `anyOf`, `oneOf` and `not` combinators, and
+//! `$ref` to the definitions of the same document. Unknown keywords, including `format`, are ignored as annotations.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Context, Result};
+use futures::StreamExt;
+use hyper::{body::Bytes, http::request, Body, Method};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Depth of nested subschemas and references beyond which validation stops, against reference cycles.
+const MAX_DEPTH: usize = 64;
+
+/// A JSON Schema for the bodies of the requests to the paths starting with a prefix.
+#[derive(Clone, Debug)]
+pub struct SchemaRoute {
+    /// Prefix of the paths of the route, matched on segment boundaries, such as `/api/users`.
+    pub path: String,
+    /// Methods whose bodies are validated. Defaults to `POST` and `PUT`.
+    pub methods: Vec<Method>,
+    /// JSON Schema of the bodies. Defaults to `true`, which accepts any JSON document.
+    pub schema: Value,
+}
+
+impl Default for SchemaRoute {
+    fn default() -> Self {
+        Self {
+            path: "/".to_string(),
+            methods: vec![Method::POST, Method::PUT],
+            schema: Value::Bool(true),
+        }
+    }
+}
+
+/// Request body validation settings.
+#[derive(Clone, Debug)]
+pub struct SchemaValidationConfig {
+    /// Routes with a schema, the first matching a request applying to it. Defaults to none.
+    pub routes: Vec<SchemaRoute>,
+    /// Largest body validated; larger ones are rejected with `413 Payload Too Large`. Defaults to 1 MiB.
+    pub max_body_size: usize,
+    /// Number of errors reported per rejected body. Defaults to 10.
+    pub max_errors: usize,
+}
+
+impl Default for SchemaValidationConfig {
+    fn default() -> Self {
+        Self {
+            routes: Vec::new(),
+            max_body_size: 1024 * 1024,
+            max_errors: 10,
+        }
+    }
+}
+
+/// A way a JSON document does not conform to its schema.
+#[derive(Clone, Debug, Serialize)]
+pub struct SchemaError {
+    /// JSON Pointer to the offending value, empty for the whole document.
+    pub instance_path: String,
+    /// Schema keyword the value violates, or `json` when the body is not JSON.
+    pub keyword: &'static str,
+    /// Description of the violation.
+    pub message: String,
+}
+
+/// A compiled JSON Schema.
+#[derive(Debug)]
+pub struct JsonSchema {
+    root: Value,
+    patterns: HashMap<String, Regex>,
+}
+
+impl JsonSchema {
+    /// Compiles `schema`, failing if it is not a schema or has an invalid `pattern`.
+    pub fn new(schema: Value) -> Result<Self> {
+        if !matches!(schema, Value::Object(_) | Value::Bool(_)) {
+            bail!(
+                "A JSON Schema must be an object or a boolean, not {}",
+                schema
+            );
+        }
+        let mut patterns = HashMap::new();
+        collect_patterns(&schema, &mut patterns)?;
+        Ok(JsonSchema {
+            root: schema,
+            patterns,
+        })
+    }
+
+    /// Validates `instance`, returning up to `max_errors` violations.
+    pub fn validate(&self, instance: &Value, max_errors: usize) -> Vec<SchemaError> {
+        let mut errors = Vec::new();
+        self.check(&self.root, instance, "", 0, &mut errors);
+        errors.truncate(max_errors);
+        errors
+    }
+
+    /// Returns the schema document.
+    pub(crate) fn root(&self) -> &Value {
+        &self.root
+    }
+
+    /// Validates `instance` against the subschema at the JSON Pointer `pointer` of the document, such as a schema of
+    /// an OpenAPI document, reporting violations under `instance_path`.
+    pub(crate) fn validate_at(
+        &self,
+        pointer: &str,
+        instance: &Value,
+        instance_path: &str,
+        max_errors: usize,
+    ) -> Vec<SchemaError> {
+        let mut errors = Vec::new();
+        if let Some(schema) = self.root.pointer(pointer) {
+            self.check(schema, instance, instance_path, 0, &mut errors);
+        }
+        errors.truncate(max_errors);
+        errors
+    }
+
+    /// Whether `instance` conforms to `schema`.
+    fn conforms(&self, schema: &Value, instance: &Value, depth: usize) -> bool {
+        let mut errors = Vec::new();
+        self.check(schema, instance, "", depth, &mut errors);
+        errors.is_empty()
+    }
+
+    /// Validates `instance`, found at `path`, against `schema`, adding the violations to `errors`.
+    fn check(
+        &self,
+        schema: &Value,
+        instance: &Value,
+        path: &str,
+        depth: usize,
+        errors: &mut Vec<SchemaError>,
+    ) {
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => {
+                let message = "No value is allowed here".to_string();
+                errors.push(violation(path, "false", message));
+                return;
+            }
+            Value::Object(schema) => schema,
+            _ => return,
+        };
+        if depth > MAX_DEPTH {
+            let message = "The schema is nested too deeply".to_string();
+            errors.push(violation(path, "$ref", message));
+            return;
+        }
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            match self.resolve(reference) {
+                Some(target) => self.check(target, instance, path, depth + 1, errors),
+                None => errors.push(violation(
+                    path,
+                    "$ref",
+                    format!("Unresolvable reference {}", reference),
+                )),
+            }
+        }
+
+        if let Some(expected) = schema.get("type") {
+            let types: Vec<&str> = match expected {
+                Value::String(name) => vec![name.as_str()],
+                Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if !types.is_empty() && !types.iter().any(|name| has_type(instance, name)) {
+                errors.push(violation(
+                    path,
+                    "type",
+                    format!(
+                        "Expected {}, found {}",
+                        types.join(" or "),
+                        type_name(instance)
+                    ),
+                ));
+                // The other keywords would only restate the type mismatch
+                return;
+            }
+        }
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.iter().any(|value| equal(value, instance)) {
+                errors.push(violation(
+                    path,
+                    "enum",
+                    format!("{} is not one of the allowed values", instance),
+                ));
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if !equal(expected, instance) {
+                errors.push(violation(path, "const", format!("Expected {}", expected)));
+            }
+        }
+
+        match instance {
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or(f64::NAN);
+                let bound = |keyword| schema.get(keyword).and_then(Value::as_f64);
+                if let Some(minimum) = bound("minimum").filter(|minimum| number < *minimum) {
+                    errors.push(violation(
+                        path,
+                        "minimum",
+                        format!("{} is less than {}", number, minimum),
+                    ));
+                }
+                if let Some(maximum) = bound("maximum").filter(|maximum| number > *maximum) {
+                    errors.push(violation(
+                        path,
+                        "maximum",
+                        format!("{} is greater than {}", number, maximum),
+                    ));
+                }
+                if let Some(minimum) =
+                    bound("exclusiveMinimum").filter(|minimum| number <= *minimum)
+                {
+                    errors.push(violation(
+                        path,
+                        "exclusiveMinimum",
+                        format!("{} is not greater than {}", number, minimum),
+                    ));
+                }
+                if let Some(maximum) =
+                    bound("exclusiveMaximum").filter(|maximum| number >= *maximum)
+                {
+                    errors.push(violation(
+                        path,
+                        "exclusiveMaximum",
+                        format!("{} is not less than {}", number, maximum),
+                    ));
+                }
+                if let Some(divisor) = bound("multipleOf").filter(|divisor| *divisor > 0.0) {
+                    let quotient = number / divisor;
+                    if (quotient - quotient.round()).abs() > 1e-9 {
+                        errors.push(violation(
+                            path,
+                            "multipleOf",
+                            format!("{} is not a multiple of {}", number, divisor),
+                        ));
+                    }
+                }
+            }
+            Value::String(string) => {
+                let length = string.chars().count() as u64;
+                if let Some(minimum) = schema.get("minLength").and_then(Value::as_u64) {
+                    if length < minimum {
+                        errors.push(violation(
+                            path,
+                            "minLength",
+                            format!("Shorter than {} characters", minimum),
+                        ));
+                    }
+                }
+                if let Some(maximum) = schema.get("maxLength").and_then(Value::as_u64) {
+                    if length > maximum {
+                        errors.push(violation(
+                            path,
+                            "maxLength",
+                            format!("Longer than {} characters", maximum),
+                        ));
+                    }
+                }
+                if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                    let matches = self
+                        .patterns
+                        .get(pattern)
+                        .is_some_and(|regex| regex.is_match(string));
+                    if !matches {
+                        errors.push(violation(
+                            path,
+                            "pattern",
+                            format!("Does not match {}", pattern),
+                        ));
+                    }
+                }
+            }
+            Value::Array(items) => {
+                let count = items.len() as u64;
+                if let Some(minimum) = schema.get("minItems").and_then(Value::as_u64) {
+                    if count < minimum {
+                        errors.push(violation(
+                            path,
+                            "minItems",
+                            format!("Fewer than {} items", minimum),
+                        ));
+                    }
+                }
+                if let Some(maximum) = schema.get("maxItems").and_then(Value::as_u64) {
+                    if count > maximum {
+                        errors.push(violation(
+                            path,
+                            "maxItems",
+                            format!("More than {} items", maximum),
+                        ));
+                    }
+                }
+                if schema.get("uniqueItems") == Some(&Value::Bool(true)) {
+                    let duplicate = items
+                        .iter()
+                        .enumerate()
+                        .any(|(index, item)| items[..index].iter().any(|other| equal(item, other)));
+                    if duplicate {
+                        errors.push(violation(
+                            path,
+                            "uniqueItems",
+                            "Items are not unique".to_string(),
+                        ));
+                    }
+                }
+                if let Some(item_schema) = schema.get("items") {
+                    for (index, item) in items.iter().enumerate() {
+                        let item_path = format!("{}/{}", path, index);
+                        self.check(item_schema, item, &item_path, depth + 1, errors);
+                    }
+                }
+            }
+            Value::Object(members) => self.check_object(schema, members, path, depth, errors),
+            _ => {}
+        }
+
+        if let Some(schemas) = schema.get("allOf").and_then(Value::as_array) {
+            for subschema in schemas {
+                self.check(subschema, instance, path, depth + 1, errors);
+            }
+        }
+        if let Some(schemas) = schema.get("anyOf").and_then(Value::as_array) {
+            if !schemas
+                .iter()
+                .any(|subschema| self.conforms(subschema, instance, depth + 1))
+            {
+                errors.push(violation(
+                    path,
+                    "anyOf",
+                    "Does not match any of the allowed schemas".to_string(),
+                ));
+            }
+        }
+        if let Some(schemas) = schema.get("oneOf").and_then(Value::as_array) {
+            let matching = schemas
+                .iter()
+                .filter(|subschema| self.conforms(subschema, instance, depth + 1))
+                .count();
+            if matching != 1 {
+                errors.push(violation(
+                    path,
+                    "oneOf",
+                    format!("Matches {} of the schemas instead of exactly one", matching),
+                ));
+            }
+        }
+        if let Some(subschema) = schema.get("not") {
+            if self.conforms(subschema, instance, depth + 1) {
+                errors.push(violation(
+                    path,
+                    "not",
+                    "Matches a disallowed schema".to_string(),
+                ));
+            }
+        }
+    }
+
+    /// Validates the members of an object against the object keywords of `schema`.
+    fn check_object(
+        &self,
+        schema: &Map<String, Value>,
+        members: &Map<String, Value>,
+        path: &str,
+        depth: usize,
+        errors: &mut Vec<SchemaError>,
+    ) {
+        let count = members.len() as u64;
+        if let Some(minimum) = schema.get("minProperties").and_then(Value::as_u64) {
+            if count < minimum {
+                errors.push(SchemaError {
+                    instance_path: path.to_string(),
+                    keyword: "minProperties",
+                    message: format!("Fewer than {} properties", minimum),
+                });
+            }
+        }
+        if let Some(maximum) = schema.get("maxProperties").and_then(Value::as_u64) {
+            if count > maximum {
+                errors.push(SchemaError {
+                    instance_path: path.to_string(),
+                    keyword: "maxProperties",
+                    message: format!("More than {} properties", maximum),
+                });
+            }
+        }
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !members.contains_key(name) {
+                    errors.push(SchemaError {
+                        instance_path: path.to_string(),
+                        keyword: "required",
+                        message: format!("Missing property {}", name),
+                    });
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        let additional = schema.get("additionalProperties");
+        for (name, value) in members {
+            let member_path = format!("{}/{}", path, escape_pointer(name));
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(property_schema) => {
+                    self.check(property_schema, value, &member_path, depth + 1, errors)
+                }
+                None => match additional {
+                    Some(Value::Bool(false)) => errors.push(violation(
+                        path,
+                        "additionalProperties",
+                        format!("Unexpected property {}", name),
+                    )),
+                    Some(additional) => {
+                        self.check(additional, value, &member_path, depth + 1, errors)
+                    }
+                    None => {}
+                },
+            }
+        }
+    }
+
+    /// Resolves a reference to a location of the same document, such as `#/$defs/address`.
+    fn resolve(&self, reference: &str) -> Option<&Value> {
+        let pointer = reference.strip_prefix('#')?;
+        let pointer = percent_encoding::percent_decode_str(pointer)
+            .decode_utf8()
+            .ok()?;
+        self.root.pointer(&pointer)
+    }
+}
+
+/// Compiles the `pattern` of `schema` and of its subschemas.
+fn collect_patterns(schema: &Value, patterns: &mut HashMap<String, Regex>) -> Result<()> {
+    match schema {
+        Value::Object(members) => {
+            if let Some(pattern) = members.get("pattern").and_then(Value::as_str) {
+                if !patterns.contains_key(pattern) {
+                    let regex = Regex::new(pattern)
+                        .context(format!("Invalid schema pattern: {}", pattern))?;
+                    patterns.insert(pattern.to_string(), regex);
+                }
+            }
+            for (keyword, value) in members {
+                // Enumerated, constant, default and example values are data, whose `pattern` members are no patterns
+                if !matches!(
+                    keyword.as_str(),
+                    "enum" | "const" | "default" | "example" | "examples"
+                ) {
+                    collect_patterns(value, patterns)?;
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                collect_patterns(item, patterns)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Whether `instance` is of the JSON Schema type `name`.
+fn has_type(instance: &Value, name: &str) -> bool {
+    match name {
+        "null" => instance.is_null(),
+        "boolean" => instance.is_boolean(),
+        "object" => instance.is_object(),
+        "array" => instance.is_array(),
+        "string" => instance.is_string(),
+        "number" => instance.is_number(),
+        "integer" => instance
+            .as_f64()
+            .is_some_and(|number| number.fract() == 0.0),
+        _ => false,
+    }
+}
+
+/// Returns the JSON Schema type of `instance`.
+fn type_name(instance: &Value) -> &'static str {
+    match instance {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_i64() || number.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Whether two values are equal as JSON Schema compares them, numbers by value.
+fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| equal(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(name, value)| b.get(name).is_some_and(|other| equal(value, other)))
+        }
+        _ => a == b,
+    }
+}
+
+/// Returns the violation of `keyword` by the value at `path`.
+fn violation(path: &str, keyword: &'static str, message: String) -> SchemaError {
+    SchemaError {
+        instance_path: path.to_string(),
+        keyword,
+        message,
+    }
+}
+
+/// Escapes `name` as a JSON Pointer reference token.
+pub(crate) fn escape_pointer(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
+
+/// Outcome of the validation of a request body.
+#[derive(Debug)]
+pub enum SchemaVerdict {
+    /// Forward the request with this body.
+    Forward(Body),
+    /// Reject the request with `422 Unprocessable Entity`, for these violations.
+    Invalid(Vec<SchemaError>),
+    /// Reject the request with `413 Payload Too Large`.
+    TooLarge,
+}
+
+struct CompiledRoute {
+    path: String,
+    methods: HashSet<Method>,
+    schema: JsonSchema,
+}
+
+/// Validates request bodies against the schemas of their routes.
+pub struct SchemaValidator {
+    config: SchemaValidationConfig,
+    routes: Vec<CompiledRoute>,
+}
+
+impl SchemaValidator {
+    /// Compiles the schemas of the routes of `config`.
+    pub fn new(config: SchemaValidationConfig) -> Result<Self> {
+        let routes = config
+            .routes
+            .iter()
+            .map(|route| {
+                let schema = JsonSchema::new(route.schema.clone())
+                    .context(format!("Invalid JSON Schema of route {}", route.path))?;
+                Ok(CompiledRoute {
+                    path: route.path.trim_end_matches('/').to_string(),
+                    methods: route.methods.iter().cloned().collect(),
+                    schema,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(SchemaValidator { config, routes })
+    }
+
+    /// Validates the body of the request with `parts` against the schema of its route, if any.
+    pub async fn validate(&self, parts: &request::Parts, mut body: Body) -> Result<SchemaVerdict> {
+        let path = parts.uri.path();
+        let route = self.routes.iter().find(|route| {
+            route.methods.contains(&parts.method)
+                && path
+                    .strip_prefix(route.path.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        });
+        let route = match route {
+            Some(route) => route,
+            None => return Ok(SchemaVerdict::Forward(body)),
+        };
+
+        let mut buffered = Vec::new();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.context("Failed to read request body")?;
+            if buffered.len() + chunk.len() > self.config.max_body_size {
+                return Ok(SchemaVerdict::TooLarge);
+            }
+            buffered.extend_from_slice(&chunk);
+        }
+        let document: Value = match serde_json::from_slice(&buffered) {
+            Ok(document) => document,
+            Err(err) => {
+                return Ok(SchemaVerdict::Invalid(vec![SchemaError {
+                    instance_path: String::new(),
+                    keyword: "json",
+                    message: format!("The body is not valid JSON: {}", err),
+                }]))
+            }
+        };
+        let errors = route.schema.validate(&document, self.config.max_errors);
+        if !errors.is_empty() {
+            return Ok(SchemaVerdict::Invalid(errors));
+        }
+        Ok(SchemaVerdict::Forward(Body::from(Bytes::from(buffered))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Request;
+    use serde_json::json;
+
+    use super::*;
+
+    /// Returns the keyword and instance path of each violation of `instance`.
+    fn violations(schema: Value, instance: Value) -> Vec<(&'static str, String)> {
+        JsonSchema::new(schema)
+            .unwrap()
+            .validate(&instance, 100)
+            .into_iter()
+            .map(|error| (error.keyword, error.instance_path))
+            .collect()
+    }
+
+    /// Validates a `method` request to `uri` with `body`, returning the violated keywords or `None` if forwarded.
+    async fn validate(
+        validator: &SchemaValidator,
+        method: Method,
+        uri: &str,
+        body: &str,
+    ) -> Option<Vec<&'static str>> {
+        let parts = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        match validator
+            .validate(&parts, Body::from(body.to_string()))
+            .await
+            .unwrap()
+        {
+            SchemaVerdict::Forward(forwarded) => {
+                let forwarded = hyper::body::to_bytes(forwarded).await.unwrap();
+                assert_eq!(forwarded, body.as_bytes());
+                None
+            }
+            SchemaVerdict::Invalid(errors) => {
+                Some(errors.into_iter().map(|error| error.keyword).collect())
+            }
+            SchemaVerdict::TooLarge => Some(vec!["too_large"]),
+        }
+    }
+
+    #[test]
+    fn checks_keywords() {
+        let schema = json!({
+            "type": "object",
+            "required": ["name", "age"],
+            "properties": {
+                "name": { "type": "string", "minLength": 2, "pattern": "^[a-z]+$" },
+                "age": { "type": "integer", "minimum": 0, "exclusiveMaximum": 150 },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] }, "uniqueItems": true },
+                "a/b": { "const": 1 }
+            },
+            "additionalProperties": false
+        });
+        assert!(violations(schema.clone(), json!({ "name": "ann", "age": 3 })).is_empty());
+        assert_eq!(
+            violations(
+                schema,
+                json!({ "name": "A", "age": 150.5, "tags": ["a", "c", "a"], "a/b": 1.0, "x": 1 })
+            ),
+            vec![
+                ("type", "/age".to_string()),
+                ("minLength", "/name".to_string()),
+                ("pattern", "/name".to_string()),
+                ("uniqueItems", "/tags".to_string()),
+                ("enum", "/tags/1".to_string()),
+                ("additionalProperties", String::new()),
+            ]
+        );
+        assert_eq!(
+            violations(json!({ "required": ["a"] }), json!({})),
+            vec![("required", String::new())]
+        );
+        // Keywords of other types do not apply
+        assert!(violations(json!({ "minLength": 5 }), json!(1)).is_empty());
+        assert_eq!(
+            violations(json!({ "multipleOf": 0.1 }), json!(0.35)),
+            vec![("multipleOf", String::new())]
+        );
+        assert!(violations(json!({ "multipleOf": 0.1 }), json!(0.3)).is_empty());
+    }
+
+    #[test]
+    fn escapes_instance_paths() {
+        assert_eq!(
+            violations(
+                json!({ "additionalProperties": { "type": "string" } }),
+                json!({ "a/b~c": 1 })
+            ),
+            vec![("type", "/a~1b~0c".to_string())]
+        );
+    }
+
+    #[test]
+    fn combines_schemas() {
+        let schema = json!({
+            "oneOf": [{ "type": "integer" }, { "type": "number", "minimum": 10 }],
+            "not": { "const": 12 }
+        });
+        assert!(violations(schema.clone(), json!(3)).is_empty());
+        assert!(violations(schema.clone(), json!(10.5)).is_empty());
+        assert_eq!(
+            violations(schema.clone(), json!(11)),
+            vec![("oneOf", String::new())]
+        );
+        assert_eq!(
+            violations(schema, json!(12)),
+            vec![("oneOf", String::new()), ("not", String::new())]
+        );
+        assert_eq!(
+            violations(json!({ "anyOf": [false, { "type": "null" }] }), json!(1)),
+            vec![("anyOf", String::new())]
+        );
+    }
+
+    #[test]
+    fn resolves_references() {
+        let schema = json!({
+            "$defs": { "positive": { "type": "integer", "minimum": 1 } },
+            "properties": { "count": { "$ref": "#/$defs/positive" }, "other": { "$ref": "#/$defs/missing" } }
+        });
+        assert_eq!(
+            violations(schema, json!({ "count": 0, "other": 1 })),
+            vec![
+                ("minimum", "/count".to_string()),
+                ("$ref", "/other".to_string())
+            ]
+        );
+        // A reference cycle stops at the maximum depth instead of overflowing the stack
+        let cycle = json!({ "$defs": { "a": { "$ref": "#/$defs/b" }, "b": { "$ref": "#/$defs/a" } }, "$ref": "#/$defs/a" });
+        assert_eq!(violations(cycle, json!(1)), vec![("$ref", String::new())]);
+    }
+
+    #[test]
+    fn rejects_invalid_schemas() {
+        assert!(JsonSchema::new(json!(1)).is_err());
+        assert!(JsonSchema::new(json!({ "properties": { "a": { "pattern": "(" } } })).is_err());
+        // Patterns in data are not compiled
+        assert!(JsonSchema::new(json!({ "const": { "pattern": "(" } })).is_ok());
+    }
+
+    #[tokio::test]
+    async fn validates_request_bodies() {
+        let validator = SchemaValidator::new(SchemaValidationConfig {
+            routes: vec![SchemaRoute {
+                path: "/api/users/".to_string(),
+                schema: json!({ "type": "object", "required": ["name"] }),
+                ..Default::default()
+            }],
+            max_body_size: 32,
+            max_errors: 1,
+        })
+        .unwrap();
+        let body = r#"{"name":"ann"}"#;
+        assert_eq!(
+            validate(&validator, Method::POST, "/api/users/1", body).await,
+            None
+        );
+        assert_eq!(
+            validate(&validator, Method::POST, "/api/users", "[]").await,
+            Some(vec!["type"])
+        );
+        assert_eq!(
+            validate(&validator, Method::PUT, "/api/users", "{").await,
+            Some(vec!["json"])
+        );
+        assert_eq!(
+            validate(&validator, Method::POST, "/api/users", &" ".repeat(33)).await,
+            Some(vec!["too_large"])
+        );
+        // Other methods and paths sharing only a prefix are not validated
+        assert_eq!(
+            validate(&validator, Method::GET, "/api/users", "{").await,
+            None
+        );
+        assert_eq!(
+            validate(&validator, Method::POST, "/api/usersx", "{").await,
+            None
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! Secrets of the configuration: reading them from files or environment variables rather than writing them in the
+//! configuration itself, and keeping them out of `Debug` output and logs.
+
+use std::fmt;
+
+use anyhow::{Context, Result};
+
+/// Shown in place of secrets in `Debug` output.
+const REDACTED: &str = "<redacted>";
+
+/// Where a secret is read from.
+#[derive(Clone, PartialEq, Eq)]
+pub enum SecretSource {
+    /// The secret itself.
+    Value(String),
+    /// A file holding the secret, such as a mounted Kubernetes or Docker secret. A trailing line break is ignored.
+    File(String),
+    /// An environment variable holding the secret.
+    Env(String),
+}
+
+impl SecretSource {
+    /// Reads the secret.
+    pub fn read(&self) -> Result<String> {
+        match self {
+            SecretSource::Value(secret) => Ok(secret.clone()),
+            SecretSource::File(path) => {
+                let secret = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read secret file {}", path))?;
+                Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+            }
+            SecretSource::Env(name) => std::env::var(name)
+                .with_context(|| format!("Failed to read secret environment variable {}", name)),
+        }
+    }
+}
+
+impl fmt::Debug for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretSource::Value(_) => f.debug_tuple("Value").field(&REDACTED).finish(),
+            SecretSource::File(path) => f.debug_tuple("File").field(path).finish(),
+            SecretSource::Env(name) => f.debug_tuple("Env").field(name).finish(),
+        }
+    }
+}
+
+/// Returns what `Debug` output shows of `secret`: whether it is set, but not its value.
+pub(crate) fn redact(secret: &str) -> &'static str {
+    if secret.is_empty() {
+        ""
+    } else {
+        REDACTED
+    }
+}
+
+/// Returns what `Debug` output shows of an optional secret.
+pub(crate) fn redact_option(secret: &Option<String>) -> Option<&'static str> {
+    secret.as_ref().map(|_| REDACTED)
+}
@@ -0,0 +1,206 @@
+//! Cookie-based session tracking.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hyper::{header::COOKIE, HeaderMap};
+use log::info;
+use rand::Rng;
+use serde::Serialize;
+
+use crate::{canary::hex, timeseries::unix_now};
+
+/// Session tracking settings.
+#[derive(Clone, Debug)]
+pub struct SessionConfig {
+    /// Name of the cookie carrying the session ID.
+    pub cookie_name: String,
+    /// Sessions without requests for this long are forgotten.
+    pub idle_timeout: Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            cookie_name: "fortifynet_session".to_string(),
+            idle_timeout: Duration::from_secs(1800),
+        }
+    }
+}
+
+/// Statistics of a single session.
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    /// Session ID stored in the cookie.
+    pub id: String,
+    /// Address of the client that started the session.
+    pub client: SocketAddr,
+    /// Unix timestamp of the first request of the session.
+    pub created: u64,
+    /// Unix timestamp of the latest request of the session.
+    pub last_seen: u64,
+    /// Total number of requests of the session.
+    pub requests: u64,
+    /// Number of requests during the last minute.
+    pub requests_last_minute: usize,
+}
+
+struct Session {
+    info: SessionInfo,
+    last_seen: Instant,
+    recent: VecDeque<Instant>,
+}
+
+/// Result of looking up the session of a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionLookup {
+    /// The request belongs to an existing session.
+    Existing(String),
+    /// A new session was started; its ID must be sent back in a cookie.
+    Started(String),
+    /// The session of the request was banned.
+    Banned(String),
+}
+
+/// Correlates requests into sessions through a cookie.
+pub struct SessionTracker {
+    config: SessionConfig,
+    sessions: Mutex<HashMap<String, Session>>,
+    banned: Mutex<HashSet<String>>,
+}
+
+impl SessionTracker {
+    /// Creates a session tracker.
+    pub fn new(config: SessionConfig) -> Self {
+        SessionTracker {
+            config,
+            sessions: Mutex::new(HashMap::new()),
+            banned: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Returns the name of the session cookie.
+    pub fn cookie_name(&self) -> &str {
+        &self.config.cookie_name
+    }
+
+    /// Finds or starts the session of a request and records the request in it.
+    pub fn track(&self, headers: &HeaderMap, client: SocketAddr) -> SessionLookup {
+        let cookie = read_cookie(headers, &self.config.cookie_name);
+        if let Some(id) = &cookie {
+            if self.banned.lock().unwrap().contains(id) {
+                return SessionLookup::Banned(id.clone());
+            }
+        }
+
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        let (id, started) = match cookie {
+            Some(id) if sessions.contains_key(&id) => (id, false),
+            _ => (new_session_id(), true),
+        };
+        let session = sessions.entry(id.clone()).or_insert_with(|| Session {
+            info: SessionInfo {
+                id: id.clone(),
+                client,
+                created: unix_now(),
+                last_seen: 0,
+                requests: 0,
+                requests_last_minute: 0,
+            },
+            last_seen: now,
+            recent: VecDeque::new(),
+        });
+        session.info.requests += 1;
+        session.info.last_seen = unix_now();
+        session.last_seen = now;
+        session.recent.push_back(now);
+        prune_recent(&mut session.recent, now);
+
+        if started {
+            SessionLookup::Started(id)
+        } else {
+            SessionLookup::Existing(id)
+        }
+    }
+
+    /// Returns the `Set-Cookie` value starting the session `id`.
+    pub fn set_cookie_value(&self, id: &str) -> String {
+        format!("{}={}; Path=/; HttpOnly", self.config.cookie_name, id)
+    }
+
+    /// Returns the statistics of every active session.
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions
+            .values_mut()
+            .map(|session| {
+                prune_recent(&mut session.recent, now);
+                let mut info = session.info.clone();
+                info.requests_last_minute = session.recent.len();
+                info
+            })
+            .collect()
+    }
+
+    /// Returns the number of active sessions.
+    pub fn count(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Ends a session; the client starts a new one on its next request. Returns whether it existed.
+    pub fn terminate(&self, id: &str) -> bool {
+        let existed = self.sessions.lock().unwrap().remove(id).is_some();
+        if existed {
+            info!("Session {} terminated", id);
+        }
+        existed
+    }
+
+    /// Ends a session and rejects every further request carrying its cookie.
+    pub fn ban(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+        self.banned.lock().unwrap().insert(id.to_string());
+        info!("Session {} banned", id);
+    }
+
+    /// Forgets sessions idle for longer than the idle timeout.
+    pub fn prune(&self) {
+        let idle_timeout = self.config.idle_timeout;
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, session| session.last_seen.elapsed() < idle_timeout);
+    }
+}
+
+/// Returns the value of the cookie `name` from the `Cookie` headers.
+pub(crate) fn read_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+fn new_session_id() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    hex(&bytes)
+}
+
+fn prune_recent(recent: &mut VecDeque<Instant>, now: Instant) {
+    while let Some(at) = recent.front() {
+        if now.duration_since(*at) < Duration::from_secs(60) {
+            break;
+        }
+        recent.pop_front();
+    }
+}
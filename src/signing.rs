@@ -0,0 +1,510 @@
+//! Signing of requests forwarded to upstreams (AWS Signature Version 4 or generic HMAC).
+
+use std::fmt;
+
+use anyhow::{Context, Result};
+use hyper::{
+    body::to_bytes,
+    header::{HeaderName, HeaderValue},
+    Body, Request,
+};
+use ring::{digest, hmac};
+
+use crate::{canary::hex, secret::SecretSource, timeseries::unix_now};
+
+/// How requests to an upstream are signed.
+#[derive(Clone, Debug)]
+pub enum SigningMethod {
+    /// AWS Signature Version 4, as used by S3, API Gateway and other AWS endpoints.
+    AwsSigV4 {
+        /// AWS region, such as `us-east-1`.
+        region: String,
+        /// AWS service name, such as `s3` or `execute-api`.
+        service: String,
+    },
+    /// An HMAC-SHA256 signature sent in a header as `keyId=..,timestamp=..,signature=..`.
+    ///
+    /// The signature covers `METHOD\npath?query\ntimestamp\nhex(sha256(body))`.
+    Hmac {
+        /// Header carrying the signature.
+        header: String,
+    },
+}
+
+/// Signing settings for a single upstream.
+#[derive(Clone)]
+pub struct SigningConfig {
+    /// Upstream the requests of which are signed, as `host` or `host:port`.
+    pub upstream: String,
+    /// Signing method.
+    pub method: SigningMethod,
+    /// Access key ID (AWS) or key ID (HMAC). Read from `AWS_ACCESS_KEY_ID` or `FORTIFYNET_SIGNING_KEY_ID` when `None`.
+    pub key_id: Option<String>,
+    /// Secret key. Read from `AWS_SECRET_ACCESS_KEY` or `FORTIFYNET_SIGNING_SECRET` when `None`.
+    pub secret: Option<SecretSource>,
+    /// AWS session token of temporary credentials. Read from `AWS_SESSION_TOKEN` when `None`.
+    pub session_token: Option<SecretSource>,
+}
+
+impl fmt::Debug for SigningConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningConfig")
+            .field("upstream", &self.upstream)
+            .field("method", &self.method)
+            .field("key_id", &self.key_id)
+            .field("secret", &self.secret)
+            .field("session_token", &self.session_token)
+            .finish()
+    }
+}
+
+struct Credentials {
+    key_id: String,
+    secret: String,
+    session_token: Option<String>,
+}
+
+/// Signs requests to the upstreams with signing configured.
+pub struct RequestSigner {
+    upstreams: Vec<(SigningConfig, Credentials)>,
+}
+
+impl RequestSigner {
+    /// Creates a signer, resolving credentials missing from the settings from the environment.
+    pub fn new(configs: &[SigningConfig]) -> Result<Self> {
+        let upstreams = configs
+            .iter()
+            .map(|config| {
+                let aws = matches!(config.method, SigningMethod::AwsSigV4 { .. });
+                let (key_id_env, secret_env) = if aws {
+                    ("AWS_ACCESS_KEY_ID", "AWS_SECRET_ACCESS_KEY")
+                } else {
+                    ("FORTIFYNET_SIGNING_KEY_ID", "FORTIFYNET_SIGNING_SECRET")
+                };
+                let key_id = match &config.key_id {
+                    Some(key_id) => key_id.clone(),
+                    None => std::env::var(key_id_env).context(format!(
+                        "No signing key ID configured for {} and {} is not set",
+                        config.upstream, key_id_env
+                    ))?,
+                };
+                let secret = match &config.secret {
+                    Some(secret) => secret.read()?,
+                    None => std::env::var(secret_env).context(format!(
+                        "No signing secret configured for {} and {} is not set",
+                        config.upstream, secret_env
+                    ))?,
+                };
+                let session_token = match &config.session_token {
+                    Some(session_token) => Some(session_token.read()?),
+                    None => aws
+                        .then(|| std::env::var("AWS_SESSION_TOKEN").ok())
+                        .flatten(),
+                };
+                Ok((
+                    config.clone(),
+                    Credentials {
+                        key_id,
+                        secret,
+                        session_token,
+                    },
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(RequestSigner { upstreams })
+    }
+
+    /// Signs `req` if signing is configured for `upstream`. The body is buffered to compute its hash.
+    pub async fn sign(&self, upstream: &str, req: &mut Request<Body>) -> Result<()> {
+        let (config, credentials) =
+            match self.upstreams.iter().find(|(c, _)| c.upstream == upstream) {
+                Some(entry) => entry,
+                None => return Ok(()),
+            };
+        let body = to_bytes(req.body_mut())
+            .await
+            .context("Failed to read request body for signing")?;
+        let payload_hash = hex(digest::digest(&digest::SHA256, &body).as_ref());
+        *req.body_mut() = Body::from(body);
+        match &config.method {
+            SigningMethod::AwsSigV4 { region, service } => {
+                sign_sigv4(req, credentials, region, service, &payload_hash)
+            }
+            SigningMethod::Hmac { header } => sign_hmac(req, credentials, header, &payload_hash),
+        }
+    }
+}
+
+fn sign_sigv4(
+    req: &mut Request<Body>,
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    payload_hash: &str,
+) -> Result<()> {
+    let amz_date = amz_timestamp(unix_now());
+    let headers = req.headers_mut();
+    headers.insert("x-amz-date", HeaderValue::from_str(&amz_date)?);
+    headers.insert("x-amz-content-sha256", HeaderValue::from_str(payload_hash)?);
+    if let Some(token) = &credentials.session_token {
+        headers.insert("x-amz-security-token", HeaderValue::from_str(token)?);
+    }
+
+    let mut signed: Vec<(String, String)> = [
+        "host",
+        "x-amz-content-sha256",
+        "x-amz-date",
+        "x-amz-security-token",
+    ]
+    .iter()
+    .filter_map(|name| {
+        let value = req.headers().get(*name)?.to_str().ok()?;
+        Some((name.to_string(), value.trim().to_string()))
+    })
+    .collect();
+    if !signed.iter().any(|(name, _)| name == "host") {
+        if let Some(host) = req.uri().authority() {
+            signed.push(("host".to_string(), host.to_string()));
+        }
+    }
+    let parts = SignedParts {
+        method: req.method().as_str(),
+        path: req.uri().path(),
+        query: req.uri().query().unwrap_or_default(),
+        headers: signed,
+        payload_hash,
+    };
+    let authorization = sigv4_authorization(parts, credentials, region, service, &amz_date);
+    req.headers_mut()
+        .insert("authorization", HeaderValue::from_str(&authorization)?);
+    Ok(())
+}
+
+/// The parts of a request covered by a SigV4 signature.
+struct SignedParts<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: &'a str,
+    /// Signed headers, as lowercase names and trimmed values.
+    headers: Vec<(String, String)>,
+    payload_hash: &'a str,
+}
+
+/// Returns the `Authorization` header signing `parts` at `amz_date`, as `YYYYMMDDTHHMMSSZ`.
+fn sigv4_authorization(
+    mut parts: SignedParts<'_>,
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    amz_date: &str,
+) -> String {
+    let date = &amz_date[..8];
+    parts.headers.sort();
+    let canonical_headers: String = parts
+        .headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value))
+        .collect();
+    let signed_headers = parts
+        .headers
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        parts.method,
+        canonical_path(parts.path, service),
+        canonical_query(parts.query),
+        canonical_headers,
+        signed_headers,
+        parts.payload_hash
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+    );
+
+    let mut key = format!("AWS4{}", credentials.secret).into_bytes();
+    for part in [date, region, service, "aws4_request"] {
+        key = hmac_sha256(&key, part.as_bytes());
+    }
+    let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.key_id, scope, signed_headers, signature
+    )
+}
+
+fn sign_hmac(
+    req: &mut Request<Body>,
+    credentials: &Credentials,
+    header: &str,
+    payload_hash: &str,
+) -> Result<()> {
+    let timestamp = unix_now();
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or("/".to_string(), |p| p.to_string());
+    let message = format!(
+        "{}\n{}\n{}\n{}",
+        req.method(),
+        path,
+        timestamp,
+        payload_hash
+    );
+    let signature = hex(&hmac_sha256(
+        credentials.secret.as_bytes(),
+        message.as_bytes(),
+    ));
+    let value = format!(
+        "keyId={},timestamp={},signature={}",
+        credentials.key_id, timestamp, signature
+    );
+    let name = HeaderName::from_bytes(header.as_bytes())
+        .context(format!("Invalid signature header name: {}", header))?;
+    req.headers_mut()
+        .insert(name, HeaderValue::from_str(&value)?);
+    Ok(())
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data)
+        .as_ref()
+        .to_vec()
+}
+
+/// Percent-encodes `value` as required by SigV4, leaving `/` alone when `keep_slash` is set.
+fn aws_encode(value: &str, keep_slash: bool) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b'/' if keep_slash => "/".to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Returns the canonical URI path of `path`, as sent. S3 takes the path decoded and encoded once, as is; other
+/// services take it without its `.` and `..` segments and empty segments, encoded a second time.
+fn canonical_path(path: &str, service: &str) -> String {
+    if path.is_empty() {
+        return "/".to_string();
+    }
+    if service == "s3" {
+        return aws_encode(&percent_decode(path), true);
+    }
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    let last = path.rsplit('/').next().unwrap_or_default();
+    if !segments.is_empty() && matches!(last, "" | "." | "..") {
+        normalized.push('/');
+    }
+    aws_encode(&normalized, true)
+}
+
+/// Returns the canonical query string: parameters encoded and sorted by name, then value.
+fn canonical_query(query: &str) -> String {
+    let mut params: Vec<(String, String)> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                aws_encode(&percent_decode(name), false),
+                aws_encode(&percent_decode(value), false),
+            )
+        })
+        .collect();
+    params.sort();
+    params
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Some(byte) = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Formats a Unix timestamp as `YYYYMMDDTHHMMSSZ`.
+fn amz_timestamp(timestamp: u64) -> String {
+    let (year, month, day) = civil_from_days((timestamp / 86_400) as i64);
+    let seconds = timestamp % 86_400;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// Converts days since the Unix epoch into a `(year, month, day)` date of the proleptic Gregorian calendar.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Date of the requests of the AWS SigV4 test suite.
+    const AMZ_DATE: &str = "20150830T123600Z";
+    /// Hash of an empty payload.
+    const EMPTY_PAYLOAD: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn credentials() -> Credentials {
+        Credentials {
+            key_id: "AKIDEXAMPLE".to_string(),
+            secret: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        }
+    }
+
+    /// Returns the signature of a `GET` of the test suite, for its `service` service.
+    fn suite_signature(path: &str, query: &str) -> String {
+        let parts = SignedParts {
+            method: "GET",
+            path,
+            query,
+            headers: vec![
+                ("x-amz-date".to_string(), AMZ_DATE.to_string()),
+                ("host".to_string(), "example.amazonaws.com".to_string()),
+            ],
+            payload_hash: EMPTY_PAYLOAD,
+        };
+        let authorization =
+            sigv4_authorization(parts, &credentials(), "us-east-1", "service", AMZ_DATE);
+        assert!(authorization.starts_with(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, Signature="
+        ));
+        authorization.rsplit('=').next().unwrap().to_string()
+    }
+
+    #[test]
+    fn aws_test_suite() {
+        let vanilla = "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31";
+        assert_eq!(suite_signature("/", ""), vanilla);
+        assert_eq!(
+            suite_signature("/", "Param2=value2&Param1=value1"),
+            "b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+        );
+        assert_eq!(
+            suite_signature("/ሴ", ""),
+            "8318018e0b0f223aa2bbf98705b62bb787dc9c0e678f255a891fd03141be5d85"
+        );
+        assert_eq!(
+            suite_signature("/example space/", ""),
+            "652487583200325589f1fba4c7e578f72c47cb61beeca81406b39ddec1366741"
+        );
+    }
+
+    #[test]
+    fn aws_test_suite_normalize_path() {
+        let vanilla = "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31";
+        for path in ["/example/..", "/example1/example2/../..", "//", "/./"] {
+            assert_eq!(suite_signature(path, ""), vanilla, "{}", path);
+        }
+        assert_eq!(
+            suite_signature("//example//", ""),
+            "9a624bd73a37c9a373b5312afbebe7a714a789de108f0bdfe846570885f57e84"
+        );
+        assert_eq!(
+            suite_signature("/./example", ""),
+            "ef75d96142cf21edca26f06005da7988e4f8dc83a165a80865db7089db637ec5"
+        );
+    }
+
+    #[test]
+    fn canonical_paths() {
+        for service in ["s3", "execute-api"] {
+            assert_eq!(canonical_path("", service), "/");
+            assert_eq!(canonical_path("/", service), "/");
+            assert_eq!(
+                canonical_path("/example space/", service),
+                "/example%20space/"
+            );
+            assert_eq!(canonical_path("/ሴ", service), "/%E1%88%B4");
+        }
+        // Paths as sent are encoded once already
+        assert_eq!(
+            canonical_path("/example%20space/", "s3"),
+            "/example%20space/"
+        );
+        assert_eq!(
+            canonical_path("/example%20space/", "execute-api"),
+            "/example%2520space/"
+        );
+        assert_eq!(canonical_path("/%E1%88%B4", "s3"), "/%E1%88%B4");
+        assert_eq!(
+            canonical_path("/%E1%88%B4", "execute-api"),
+            "/%25E1%2588%25B4"
+        );
+        // S3 keys are taken as they are
+        assert_eq!(canonical_path("//example//", "s3"), "//example//");
+        assert_eq!(canonical_path("/example/..", "s3"), "/example/..");
+        assert_eq!(canonical_path("/example/.", "execute-api"), "/example/");
+    }
+
+    #[test]
+    fn canonical_queries() {
+        assert_eq!(canonical_query(""), "");
+        assert_eq!(canonical_query("b=2&a=2&a=1&c"), "a=1&a=2&b=2&c=");
+        assert_eq!(canonical_query("q=a%20b&k=a+b"), "k=a%2Bb&q=a%20b");
+    }
+
+    #[test]
+    fn amz_timestamps() {
+        assert_eq!(amz_timestamp(1_440_938_160), AMZ_DATE);
+        assert_eq!(amz_timestamp(0), "19700101T000000Z");
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+    }
+}
@@ -0,0 +1,195 @@
+//! Per-upstream latency and error-rate SLO tracking.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::timeseries::unix_now;
+
+/// Maximum number of samples kept per upstream, regardless of the window length.
+const MAX_SAMPLES_PER_UPSTREAM: usize = 10_000;
+
+/// A service level objective for a single upstream.
+#[derive(Clone, Debug)]
+pub struct SloConfig {
+    /// Upstream the objective applies to, as `host` or `host:port`.
+    pub upstream: String,
+    /// Latency percentile to evaluate, between `0.0` and `1.0` (e.g. `0.99` for p99).
+    pub latency_percentile: f64,
+    /// Maximum allowed latency at `latency_percentile`.
+    pub latency_threshold: Duration,
+    /// Maximum allowed ratio of failed requests, between `0.0` and `1.0`.
+    pub max_error_rate: f64,
+    /// Sliding window the objective is evaluated over.
+    pub window: Duration,
+    /// Webhook invoked with the SLO status whenever the breach state changes.
+    pub webhook_url: Option<String>,
+}
+
+impl Default for SloConfig {
+    fn default() -> Self {
+        Self {
+            upstream: String::new(),
+            latency_percentile: 0.99,
+            latency_threshold: Duration::from_millis(500),
+            max_error_rate: 0.01,
+            window: Duration::from_secs(300),
+            webhook_url: None,
+        }
+    }
+}
+
+/// Current evaluation of an SLO.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SloStatus {
+    /// Upstream the status belongs to.
+    pub upstream: String,
+    /// Number of samples in the current window.
+    pub samples: usize,
+    /// Observed latency at the configured percentile, in milliseconds.
+    pub latency_ms: u64,
+    /// Observed ratio of failed requests.
+    pub error_rate: f64,
+    /// Whether the latency objective is currently breached.
+    pub latency_breached: bool,
+    /// Whether the error-rate objective is currently breached.
+    pub error_rate_breached: bool,
+    /// Unix timestamp of the last change of the breach state.
+    pub since: u64,
+}
+
+impl SloStatus {
+    /// Whether any objective of the SLO is breached.
+    pub fn breached(&self) -> bool {
+        self.latency_breached || self.error_rate_breached
+    }
+}
+
+/// Callback invoked when the breach state of an SLO changes.
+pub type SloHook = Arc<dyn Fn(&SloStatus) + Send + Sync>;
+
+#[derive(Default)]
+struct UpstreamWindow {
+    samples: VecDeque<(Instant, Duration, bool)>,
+}
+
+/// Collects upstream samples and evaluates the configured SLOs over them.
+#[derive(Default)]
+pub struct SloTracker {
+    slos: Vec<SloConfig>,
+    windows: Mutex<HashMap<String, UpstreamWindow>>,
+    statuses: Mutex<HashMap<String, SloStatus>>,
+    hooks: Mutex<Vec<SloHook>>,
+}
+
+impl SloTracker {
+    /// Creates a tracker for the given SLOs.
+    pub fn new(slos: Vec<SloConfig>) -> Self {
+        SloTracker {
+            slos,
+            ..Default::default()
+        }
+    }
+
+    /// Registers a callback invoked whenever an SLO is breached or recovers.
+    pub fn add_hook(&self, hook: SloHook) {
+        self.hooks.lock().unwrap().push(hook);
+    }
+
+    /// Records the outcome of a request forwarded to `upstream`.
+    ///
+    /// Samples for upstreams without an SLO are ignored.
+    pub fn record(&self, upstream: &str, duration: Duration, success: bool) {
+        if !self.slos.iter().any(|slo| slo.upstream == upstream) {
+            return;
+        }
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(upstream.to_string()).or_default();
+        window.samples.push_back((Instant::now(), duration, success));
+        if window.samples.len() > MAX_SAMPLES_PER_UPSTREAM {
+            window.samples.pop_front();
+        }
+    }
+
+    /// Returns the latest status of every SLO.
+    pub fn statuses(&self) -> Vec<SloStatus> {
+        let mut statuses: Vec<SloStatus> =
+            self.statuses.lock().unwrap().values().cloned().collect();
+        statuses.sort_by(|a, b| a.upstream.cmp(&b.upstream));
+        statuses
+    }
+
+    /// Evaluates every SLO over its window and returns the SLOs whose breach state changed.
+    pub fn evaluate(&self) -> Vec<(SloConfig, SloStatus)> {
+        let now = Instant::now();
+        let mut changed = Vec::new();
+        let mut windows = self.windows.lock().unwrap();
+        let mut statuses = self.statuses.lock().unwrap();
+        for slo in &self.slos {
+            let window = windows.entry(slo.upstream.clone()).or_default();
+            while let Some((at, _, _)) = window.samples.front() {
+                if now.duration_since(*at) <= slo.window {
+                    break;
+                }
+                window.samples.pop_front();
+            }
+
+            let mut latencies: Vec<Duration> = window.samples.iter().map(|s| s.1).collect();
+            latencies.sort();
+            let failures = window.samples.iter().filter(|s| !s.2).count();
+            let latency = percentile(&latencies, slo.latency_percentile);
+            let error_rate = if latencies.is_empty() {
+                0.0
+            } else {
+                failures as f64 / latencies.len() as f64
+            };
+
+            let previous = statuses.get(&slo.upstream).cloned().unwrap_or_default();
+            let mut status = SloStatus {
+                upstream: slo.upstream.clone(),
+                samples: latencies.len(),
+                latency_ms: latency.as_millis() as u64,
+                error_rate,
+                latency_breached: latency > slo.latency_threshold,
+                error_rate_breached: error_rate > slo.max_error_rate,
+                since: previous.since,
+            };
+            if status.breached() != previous.breached() || previous.since == 0 {
+                status.since = unix_now();
+            }
+            if status.breached() != previous.breached() {
+                if status.breached() {
+                    warn!("SLO breached for upstream {}: {:?}", slo.upstream, status);
+                } else {
+                    info!("SLO recovered for upstream {}", slo.upstream);
+                }
+                changed.push((slo.clone(), status.clone()));
+            }
+            statuses.insert(slo.upstream.clone(), status);
+        }
+        drop(statuses);
+        drop(windows);
+
+        let hooks = self.hooks.lock().unwrap().clone();
+        for (_, status) in &changed {
+            for hook in &hooks {
+                hook(status);
+            }
+        }
+        changed
+    }
+}
+
+/// Returns the value at `quantile` of the sorted `values`.
+fn percentile(values: &[Duration], quantile: f64) -> Duration {
+    if values.is_empty() {
+        return Duration::from_secs(0);
+    }
+    let rank = (quantile.clamp(0.0, 1.0) * (values.len() - 1) as f64).round() as usize;
+    values[rank]
+}
@@ -0,0 +1,163 @@
+//! Detection of the requests exceeding a latency threshold, for triage of slow routes and upstreams.
+//!
+//! A request is slow when its response took longer than the threshold to be sent, from the start of the request to
+//! the end of its body. Every slow request is logged with the durations of its phases and counted under its route, the
+//! method and first segments of its path, and the latest ones are kept for the dashboard.
+
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use hyper::Uri;
+use serde::Serialize;
+
+/// Route under which the slow requests beyond the tracked routes are counted.
+pub(crate) const OTHER_ROUTES: &str = "other";
+
+/// Slow request detection settings.
+#[derive(Clone, Debug)]
+pub struct SlowRequestConfig {
+    /// Duration beyond which a request is slow. Defaults to 1 second.
+    pub threshold: Duration,
+    /// Number of recent slow requests kept for the dashboard. Defaults to 20.
+    pub recent: usize,
+    /// Number of path segments identifying the route of a request, such as 1 for `GET /api`. Defaults to 1.
+    pub route_depth: usize,
+    /// Number of routes counted apart, beyond which slow requests are counted together under `other`. Defaults to
+    /// 1000.
+    pub max_routes: usize,
+}
+
+impl Default for SlowRequestConfig {
+    fn default() -> Self {
+        Self {
+            threshold: Duration::from_secs(1),
+            recent: 20,
+            route_depth: 1,
+            max_routes: 1000,
+        }
+    }
+}
+
+/// Duration of a phase of a slow request.
+#[derive(Clone, Debug, Serialize)]
+pub struct PhaseDuration {
+    /// Name of the phase, such as `ttfb`.
+    pub phase: &'static str,
+    /// Duration of the phase, in milliseconds.
+    pub duration_ms: f64,
+}
+
+/// A request that exceeded the threshold.
+#[derive(Clone, Debug, Serialize)]
+pub struct SlowRequest {
+    /// ID of the request.
+    pub request_id: String,
+    /// Route of the request, such as `GET /api`.
+    pub route: String,
+    /// URL of the request, as received.
+    pub url: String,
+    /// Status of the response.
+    pub status: String,
+    /// When the request completed, in RFC 3339 format.
+    pub completed_at: String,
+    /// Duration of the request, in milliseconds.
+    pub duration_ms: f64,
+    /// Durations of the phases of the request, in the order they happened.
+    pub phases: Vec<PhaseDuration>,
+}
+
+impl SlowRequest {
+    /// Returns the phases as `name=duration` pairs, for logging.
+    pub(crate) fn breakdown(&self) -> String {
+        self.phases
+            .iter()
+            .map(|phase| format!("{}={:.1}ms", phase.phase, phase.duration_ms))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Detects the slow requests and keeps the latest ones.
+pub struct SlowRequests {
+    config: SlowRequestConfig,
+    recent: Mutex<VecDeque<SlowRequest>>,
+}
+
+impl SlowRequests {
+    /// Creates a detector with the given settings.
+    pub fn new(config: SlowRequestConfig) -> Self {
+        SlowRequests {
+            config,
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Whether a request that took `duration` is slow.
+    pub(crate) fn is_slow(&self, duration: Duration) -> bool {
+        duration > self.config.threshold
+    }
+
+    /// Returns the route of a request with `method` to `url`: the method and the first path segments.
+    pub(crate) fn route(&self, method: &str, url: &str) -> String {
+        let path = url
+            .parse::<Uri>()
+            .map(|uri| uri.path().to_string())
+            .unwrap_or_default();
+        let segments: Vec<&str> = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .take(self.config.route_depth)
+            .collect();
+        format!("{} /{}", method, segments.join("/"))
+    }
+
+    /// Returns the number of routes counted apart.
+    pub(crate) fn max_routes(&self) -> usize {
+        self.config.max_routes
+    }
+
+    /// Keeps `request` among the recent slow requests, forgetting the oldest one beyond the configured number.
+    pub(crate) fn record(&self, request: SlowRequest) {
+        if self.config.recent == 0 {
+            return;
+        }
+        let mut recent = self.recent.lock().unwrap();
+        while recent.len() >= self.config.recent {
+            recent.pop_front();
+        }
+        recent.push_back(request);
+    }
+
+    /// Returns the recent slow requests, the slowest first.
+    pub fn recent(&self) -> Vec<SlowRequest> {
+        let mut recent: Vec<SlowRequest> = self.recent.lock().unwrap().iter().cloned().collect();
+        recent.sort_by(|a, b| b.duration_ms.total_cmp(&a.duration_ms));
+        recent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Metrics;
+
+    #[test]
+    fn counts_routes_beyond_the_limit_as_other() {
+        let slow_requests = SlowRequests::new(SlowRequestConfig {
+            max_routes: 2,
+            ..SlowRequestConfig::default()
+        });
+        assert_eq!(
+            slow_requests.route("GET", "http://example.com/api/users?id=1"),
+            "GET /api"
+        );
+        assert_eq!(slow_requests.route("POST", "/"), "POST /");
+        let mut metrics = Metrics::default();
+        for path in ["/a", "/b", "/c", "/a", "/d"] {
+            let route = slow_requests.route("GET", path);
+            metrics.record_slow_request(&route, slow_requests.max_routes());
+        }
+        assert_eq!(metrics.slow_requests.len(), 3);
+        assert_eq!(metrics.slow_requests["GET /a"], 2);
+        assert_eq!(metrics.slow_requests[OTHER_ROUTES], 2);
+    }
+}
@@ -0,0 +1,315 @@
+//! SSH tunnels to upstreams reachable only through a jump host, each a `direct-tcpip` channel of an SSH connection
+//! to the jump host, as `ssh -W` opens. The jump host's key is checked against the known hosts files of OpenSSH, and
+//! the user is authenticated with a private key.
+//!
+//! Tunnels require the `ssh` feature; without it, configuring one is an error.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+
+use crate::{connector::Connector, tunnel::UpstreamStream};
+
+/// SSH jump host through which upstreams are reached.
+#[derive(Clone, Debug)]
+pub struct SshConfig {
+    /// Host name or IP address of the jump host.
+    pub host: String,
+    /// SSH port of the jump host. Defaults to 22.
+    pub port: u16,
+    /// User logged in as on the jump host.
+    pub user: String,
+    /// Path to the private key authenticating the user, which must not be protected by a passphrase.
+    pub key_path: PathBuf,
+    /// Path to the known hosts file the key of the jump host is checked against. Defaults to the files of OpenSSH,
+    /// `~/.ssh/known_hosts` and `/etc/ssh/ssh_known_hosts`. Jump hosts with an unknown or changed key are refused.
+    pub known_hosts_path: Option<PathBuf>,
+    /// Whether the tunnels share a single SSH connection to the jump host, kept while it stays up, instead of
+    /// logging in for each. Defaults to `true`.
+    pub multiplex: bool,
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: 22,
+            user: String::new(),
+            key_path: PathBuf::new(),
+            known_hosts_path: None,
+            multiplex: true,
+        }
+    }
+}
+
+/// A transport through SSH tunnels from a jump host.
+pub(crate) struct SshConnector {
+    config: SshConfig,
+    #[cfg(feature = "ssh")]
+    jump_host: std::sync::Arc<tunnel::JumpHost>,
+}
+
+impl SshConnector {
+    /// Prepares the tunnels through the configured jump host, connected to on the first tunnel. Fails if the key
+    /// cannot be read.
+    #[cfg(feature = "ssh")]
+    pub(crate) fn new(config: SshConfig) -> Result<Self> {
+        if config.host.is_empty() || config.user.is_empty() {
+            anyhow::bail!("SSH jump host and user are required");
+        }
+        Ok(SshConnector {
+            jump_host: std::sync::Arc::new(tunnel::JumpHost::new(&config)?),
+            config,
+        })
+    }
+
+    /// Prepares the tunnels through the configured jump host.
+    #[cfg(not(feature = "ssh"))]
+    pub(crate) fn new(config: SshConfig) -> Result<Self> {
+        anyhow::bail!(
+            "Cannot tunnel through SSH jump host {}: fortifynet_proxy was built without the `ssh` feature",
+            config.host
+        )
+    }
+}
+
+impl Connector for SshConnector {
+    fn describe(&self) -> String {
+        format!(
+            "SSH jump host {}@{}:{}",
+            self.config.user, self.config.host, self.config.port
+        )
+    }
+
+    #[cfg(feature = "ssh")]
+    fn connect(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> BoxFuture<'static, Result<Box<dyn UpstreamStream>>> {
+        let jump_host = self.jump_host.clone();
+        let host = host.to_string();
+        Box::pin(async move { jump_host.open(&host, port).await })
+    }
+
+    #[cfg(not(feature = "ssh"))]
+    fn connect(
+        &self,
+        _host: &str,
+        _port: u16,
+    ) -> BoxFuture<'static, Result<Box<dyn UpstreamStream>>> {
+        Box::pin(async { anyhow::bail!("fortifynet_proxy was built without the `ssh` feature") })
+    }
+}
+
+#[cfg(feature = "ssh")]
+mod tunnel {
+    use std::{
+        io,
+        path::PathBuf,
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use anyhow::{Context as _, Result};
+    use log::info;
+    use russh::{
+        client::{self, Handle, Msg},
+        keys::{self, PrivateKey, PrivateKeyWithHashAlg, PublicKeyOrCertificate},
+        ChannelStream,
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncWrite, ReadBuf},
+        sync::Mutex,
+    };
+
+    use super::SshConfig;
+    use crate::tunnel::UpstreamStream;
+
+    /// Known hosts file of the system, checked after the user's.
+    const SYSTEM_KNOWN_HOSTS: &str = "/etc/ssh/ssh_known_hosts";
+    /// Interval of the keepalives on connections to the jump host, which are closed after three unanswered ones.
+    const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+    /// Checks the key of the jump host against the known hosts files.
+    struct HostKeyCheck {
+        host: String,
+        port: u16,
+        known_hosts_path: Option<PathBuf>,
+    }
+
+    impl HostKeyCheck {
+        /// Returns whether `key` is the known key of the jump host, failing if a file records another one.
+        fn known(&self, key: &keys::PublicKey) -> Result<bool> {
+            let known = match &self.known_hosts_path {
+                Some(path) => keys::check_known_hosts_path(&self.host, self.port, key, path),
+                None => keys::check_known_hosts(&self.host, self.port, key).and_then(|known| {
+                    Ok(known
+                        || keys::check_known_hosts_path(
+                            &self.host,
+                            self.port,
+                            key,
+                            SYSTEM_KNOWN_HOSTS,
+                        )?)
+                }),
+            };
+            known.context(format!("Refusing the key of SSH jump host {}", self.host))
+        }
+    }
+
+    impl client::Handler for HostKeyCheck {
+        type Error = anyhow::Error;
+
+        async fn check_server_key(&mut self, key: &PublicKeyOrCertificate) -> Result<bool> {
+            let known = match key {
+                PublicKeyOrCertificate::PublicKey { key, .. } => self.known(key)?,
+                // Host certificates would need the `@cert-authority` lines, which are not read
+                PublicKeyOrCertificate::Certificate(_) => false,
+            };
+            if !known {
+                anyhow::bail!("SSH jump host {} has an unknown key", self.host);
+            }
+            Ok(true)
+        }
+    }
+
+    /// The jump host, and its connection shared by the tunnels when multiplexing.
+    pub(super) struct JumpHost {
+        host: String,
+        port: u16,
+        user: String,
+        key: Arc<PrivateKey>,
+        known_hosts_path: Option<PathBuf>,
+        multiplex: bool,
+        config: Arc<client::Config>,
+        shared: Mutex<Option<Arc<Handle<HostKeyCheck>>>>,
+    }
+
+    impl JumpHost {
+        /// Reads the private key of the user.
+        pub(super) fn new(config: &SshConfig) -> Result<Self> {
+            let key = keys::load_secret_key(&config.key_path, None).context(format!(
+                "Failed to read SSH private key {}",
+                config.key_path.display()
+            ))?;
+            let client = client::Config {
+                keepalive_interval: Some(KEEPALIVE_INTERVAL),
+                keepalive_max: 3,
+                ..client::Config::default()
+            };
+            Ok(JumpHost {
+                host: config.host.clone(),
+                port: config.port,
+                user: config.user.clone(),
+                key: Arc::new(key),
+                known_hosts_path: config.known_hosts_path.clone(),
+                multiplex: config.multiplex,
+                config: Arc::new(client),
+                shared: Mutex::new(None),
+            })
+        }
+
+        /// Connects to the jump host and logs in.
+        async fn connect(&self) -> Result<Arc<Handle<HostKeyCheck>>> {
+            let check = HostKeyCheck {
+                host: self.host.clone(),
+                port: self.port,
+                known_hosts_path: self.known_hosts_path.clone(),
+            };
+            let mut session =
+                client::connect(self.config.clone(), (self.host.as_str(), self.port), check)
+                    .await
+                    .context(format!(
+                        "Failed to connect to SSH jump host {}:{}",
+                        self.host, self.port
+                    ))?;
+            // RSA keys sign with the strongest hash the jump host supports
+            let hash = session.best_supported_rsa_hash().await?.flatten();
+            let authenticated = session
+                .authenticate_publickey(
+                    &self.user,
+                    PrivateKeyWithHashAlg::new(self.key.clone(), hash),
+                )
+                .await?;
+            if !authenticated.success() {
+                anyhow::bail!(
+                    "SSH jump host {} refused the key of {}",
+                    self.host,
+                    self.user
+                );
+            }
+            info!(
+                "Connected to SSH jump host {}@{}:{}",
+                self.user, self.host, self.port
+            );
+            Ok(Arc::new(session))
+        }
+
+        /// Returns the connection of a new tunnel: the shared one while it is up when multiplexing.
+        async fn session(&self) -> Result<Arc<Handle<HostKeyCheck>>> {
+            if !self.multiplex {
+                return self.connect().await;
+            }
+            let mut shared = self.shared.lock().await;
+            if let Some(session) = shared.as_ref().filter(|session| !session.is_closed()) {
+                return Ok(session.clone());
+            }
+            let session = self.connect().await?;
+            *shared = Some(session.clone());
+            Ok(session)
+        }
+
+        /// Opens a tunnel to port `port` of `host` from the jump host.
+        pub(super) async fn open(&self, host: &str, port: u16) -> Result<Box<dyn UpstreamStream>> {
+            let session = self.session().await?;
+            let channel = session
+                .channel_open_direct_tcpip(host, u32::from(port), "127.0.0.1", 0)
+                .await
+                .context(format!(
+                    "SSH jump host {} failed to connect to {}:{}",
+                    self.host, host, port
+                ))?;
+            Ok(Box::new(SshTunnel {
+                stream: channel.into_stream(),
+                _session: session,
+            }))
+        }
+    }
+
+    /// A tunnel, keeping its connection to the jump host open.
+    struct SshTunnel {
+        stream: ChannelStream<Msg>,
+        _session: Arc<Handle<HostKeyCheck>>,
+    }
+
+    impl AsyncRead for SshTunnel {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for SshTunnel {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.stream).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_shutdown(cx)
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! Streaming of responses to clients as the upstream produces them, for routes such as server-sent events whose
+//! bodies must be flushed immediately instead of buffered.
+//!
+//! The proxy does not support `103 Early Hints`: the HTTP/1.1 client of hyper 0.14 discards the interim responses of
+//! upstreams and its server cannot send any, so clients only get the `Link` headers of the final response.
+
+use hyper::{header::CONTENT_TYPE, HeaderMap};
+
+/// Streaming settings.
+#[derive(Clone, Debug)]
+pub struct StreamingConfig {
+    /// Path prefixes of the routes whose responses are streamed. Defaults to none.
+    pub path_prefixes: Vec<String>,
+    /// Content types of the responses that are streamed whatever their route, compared without their parameters.
+    /// Defaults to `text/event-stream`.
+    pub content_types: Vec<String>,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        Self {
+            path_prefixes: Vec::new(),
+            content_types: vec!["text/event-stream".to_string()],
+        }
+    }
+}
+
+impl StreamingConfig {
+    /// Whether the response to a request for `path`, with the headers `headers`, is streamed. Streamed responses are
+    /// flushed to the client chunk by chunk, and are neither cached, adapted, scanned for malware nor stored for
+    /// idempotent replay, which would all need the whole body.
+    pub fn streams(&self, path: &str, headers: &HeaderMap) -> bool {
+        if self
+            .path_prefixes
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+        {
+            return true;
+        }
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(str::trim);
+        match content_type {
+            Some(content_type) => self
+                .content_types
+                .iter()
+                .any(|streamed| streamed.eq_ignore_ascii_case(content_type)),
+            None => false,
+        }
+    }
+}
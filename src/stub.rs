@@ -0,0 +1,344 @@
+//! Record-and-stub mode: upstream responses are recorded to a directory and served back as stubs when the upstream
+//! is unreachable, or always for offline development.
+//!
+//! The directory holds `manifest.json`, mapping requests to the recorded responses, and one `.body` file per
+//! response. The manifest can be edited by hand: paths may contain `*` wildcards.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use hyper::{
+    body::to_bytes,
+    header::{HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING},
+    http::request,
+    Body, Response, StatusCode,
+};
+use log::{debug, error, info};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use crate::canary::hex;
+
+/// File name of the manifest in the stub directory.
+const MANIFEST: &str = "manifest.json";
+
+/// When recorded responses are served.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StubMode {
+    /// Responses are recorded but never served.
+    Record,
+    /// Responses are recorded, and served when the upstream cannot be reached.
+    Fallback,
+    /// Recorded responses are always served; the upstream is never contacted.
+    Replay,
+}
+
+/// Record-and-stub settings.
+#[derive(Clone, Debug)]
+pub struct StubConfig {
+    /// When recorded responses are served.
+    pub mode: StubMode,
+    /// Directory holding the manifest and the recorded bodies.
+    pub directory: PathBuf,
+    /// Whether the query string is ignored when matching requests.
+    pub ignore_query: bool,
+    /// Query parameters ignored when matching requests, such as cache busters.
+    pub ignored_query_params: Vec<String>,
+    /// Request headers that must match, such as `Accept`.
+    pub match_headers: Vec<String>,
+    /// Whether request bodies must match.
+    pub match_body: bool,
+    /// Largest response body recorded, in bytes.
+    pub max_body_size: usize,
+}
+
+impl Default for StubConfig {
+    fn default() -> Self {
+        Self {
+            mode: StubMode::Fallback,
+            directory: PathBuf::from("stubs"),
+            ignore_query: false,
+            ignored_query_params: Vec::new(),
+            match_headers: Vec::new(),
+            match_body: false,
+            max_body_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// A recorded request with its response.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct StubEntry {
+    method: String,
+    /// Request path, in which `*` matches any characters.
+    path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    query: Option<String>,
+    /// Values of the matched request headers, by lowercase name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_sha256: Option<String>,
+    status: u16,
+    #[serde(default)]
+    response_headers: Vec<(String, String)>,
+    /// Body file, relative to the stub directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_file: Option<String>,
+}
+
+/// The fields of a manifest entry identifying its request.
+type RequestKey<'a> = (
+    &'a str,
+    &'a str,
+    Option<&'a str>,
+    &'a BTreeMap<String, String>,
+    Option<&'a str>,
+);
+
+impl StubEntry {
+    fn request_key(&self) -> RequestKey<'_> {
+        (
+            &self.method,
+            &self.path,
+            self.query.as_deref(),
+            &self.headers,
+            self.body_sha256.as_deref(),
+        )
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Manifest {
+    entries: Vec<StubEntry>,
+}
+
+/// Records and serves stubbed responses.
+pub struct Stubs {
+    config: StubConfig,
+    manifest: Mutex<Manifest>,
+    /// Serializes the writes of the manifest.
+    write_lock: tokio::sync::Mutex<()>,
+}
+
+impl Stubs {
+    /// Opens the stub directory, loading its manifest if present.
+    pub fn open(config: StubConfig) -> Result<Self> {
+        std::fs::create_dir_all(&config.directory).context(format!(
+            "Failed to create stub directory {}",
+            config.directory.display()
+        ))?;
+        let path = config.directory.join(MANIFEST);
+        let manifest = match std::fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .context(format!("Invalid stub manifest {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Manifest::default(),
+            Err(err) => {
+                return Err(err).context(format!("Failed to read stub manifest {}", path.display()))
+            }
+        };
+        Ok(Stubs {
+            config,
+            manifest: Mutex::new(manifest),
+            write_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Returns when recorded responses are served.
+    pub fn mode(&self) -> StubMode {
+        self.config.mode
+    }
+
+    /// Whether request bodies take part in matching, so they must be buffered.
+    pub fn matches_bodies(&self) -> bool {
+        self.config.match_body
+    }
+
+    /// Returns the recorded response matching a request, the latest recording winning.
+    pub async fn lookup(
+        &self,
+        head: &request::Parts,
+        body: Option<&[u8]>,
+    ) -> Result<Option<Response<Body>>> {
+        let request = self.entry_for(head, body);
+        let entry = {
+            let manifest = self.manifest.lock().unwrap();
+            manifest
+                .entries
+                .iter()
+                .rev()
+                .find(|entry| self.matches(entry, &request))
+                .cloned()
+        };
+        let entry = match entry {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let body = match &entry.body_file {
+            Some(file) => tokio::fs::read(self.config.directory.join(file))
+                .await
+                .context(format!("Failed to read stub body {}", file))?,
+            None => Vec::new(),
+        };
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::from_u16(entry.status)?;
+        for (name, value) in &entry.response_headers {
+            response.headers_mut().append(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+        response
+            .headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        *response.body_mut() = Body::from(body);
+        debug!("Serving stub for {} {}", head.method, head.uri);
+        Ok(Some(response))
+    }
+
+    /// Records `response` to a request, returning it to be forwarded. Failing to record only logs an error.
+    ///
+    /// Server errors are not recorded, so an outage does not replace good recordings.
+    pub async fn record(
+        &self,
+        head: &request::Parts,
+        body: Option<&[u8]>,
+        response: Response<Body>,
+    ) -> Result<Response<Body>> {
+        if response.status().is_server_error() {
+            return Ok(response);
+        }
+        let (parts, response_body) = response.into_parts();
+        let response_body = to_bytes(response_body).await?;
+        if response_body.len() > self.config.max_body_size {
+            debug!("Not recording {} {}: body too large", head.method, head.uri);
+            return Ok(Response::from_parts(parts, Body::from(response_body)));
+        }
+
+        let mut entry = self.entry_for(head, body);
+        entry.status = parts.status.as_u16();
+        entry.response_headers = parts
+            .headers
+            .iter()
+            .filter(|(name, _)| ![CONNECTION, TRANSFER_ENCODING, CONTENT_LENGTH].contains(name))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        if let Err(err) = self.save(entry, &response_body).await {
+            error!("Failed to record {} {}: {}", head.method, head.uri, err);
+        }
+        Ok(Response::from_parts(parts, Body::from(response_body)))
+    }
+
+    /// Stores a recorded response, replacing an earlier recording of the same request.
+    async fn save(&self, mut entry: StubEntry, body: &[u8]) -> Result<()> {
+        let _guard = self.write_lock.lock().await;
+        let key = serde_json::to_vec(&entry.request_key())?;
+        let file = format!(
+            "{}.body",
+            &hex(digest::digest(&digest::SHA256, &key).as_ref())[..32]
+        );
+        write_atomically(&self.config.directory.join(&file), body).await?;
+        entry.body_file = Some(file);
+
+        let manifest = {
+            let mut manifest = self.manifest.lock().unwrap();
+            manifest
+                .entries
+                .retain(|existing| existing.request_key() != entry.request_key());
+            info!("Recorded stub for {} {}", entry.method, entry.path);
+            manifest.entries.push(entry);
+            serde_json::to_vec_pretty(&*manifest)?
+        };
+        write_atomically(&self.config.directory.join(MANIFEST), &manifest).await
+    }
+
+    /// Describes a request as a manifest entry without response.
+    fn entry_for(&self, head: &request::Parts, body: Option<&[u8]>) -> StubEntry {
+        let headers = self
+            .config
+            .match_headers
+            .iter()
+            .filter_map(|name| {
+                let value = head.headers.get(name.as_str())?.to_str().ok()?;
+                Some((name.to_ascii_lowercase(), value.to_string()))
+            })
+            .collect();
+        StubEntry {
+            method: head.method.to_string(),
+            path: head.uri.path().to_string(),
+            query: head.uri.query().map(str::to_string),
+            headers,
+            body_sha256: body
+                .filter(|_| self.config.match_body)
+                .map(|body| hex(digest::digest(&digest::SHA256, body).as_ref())),
+            status: 0,
+            response_headers: Vec::new(),
+            body_file: None,
+        }
+    }
+
+    /// Whether a recorded entry matches a request described by `request`.
+    fn matches(&self, entry: &StubEntry, request: &StubEntry) -> bool {
+        entry.method.eq_ignore_ascii_case(&request.method)
+            && wildcard_match(&entry.path, &request.path)
+            && (self.config.ignore_query
+                || self.query_params(entry.query.as_deref())
+                    == self.query_params(request.query.as_deref()))
+            && entry.headers == request.headers
+            && (!self.config.match_body || entry.body_sha256 == request.body_sha256)
+    }
+
+    /// Returns the sorted query parameters that take part in matching.
+    fn query_params(&self, query: Option<&str>) -> Vec<(String, String)> {
+        let mut params: Vec<(String, String)> =
+            url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+                .filter(|(name, _)| {
+                    !self
+                        .config
+                        .ignored_query_params
+                        .iter()
+                        .any(|ignored| ignored == name)
+                })
+                .map(|(name, value)| (name.into_owned(), value.into_owned()))
+                .collect();
+        params.sort();
+        params
+    }
+}
+
+/// Whether `value` matches `pattern`, in which `*` matches any characters.
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match value.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Writes `content` to a temporary file renamed over `path`, so readers never see a partial file.
+async fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    let temporary = path.with_extension("tmp");
+    tokio::fs::write(&temporary, content)
+        .await
+        .context(format!("Failed to write {}", temporary.display()))?;
+    tokio::fs::rename(&temporary, path)
+        .await
+        .context(format!("Failed to replace {}", path.display()))
+}
@@ -0,0 +1,384 @@
+//! Live inspection of the traffic of the proxy: summaries of the transactions matching a filter, and optionally the
+//! beginning of their bodies, streamed to admin API clients as server-sent events.
+//!
+//! Subscribers must present the configured bearer token, their number is capped, and each receives at most a fixed
+//! number of events per second, the others being dropped and counted.
+
+use std::{
+    convert::Infallible,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Result;
+use futures::Stream;
+use hyper::{header::HOST, Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use subtle::ConstantTimeEq;
+use tokio::sync::broadcast::{self, error::RecvError};
+use warp::sse::Event;
+
+use crate::{access_log, debug_log::peek_body, secret::SecretSource};
+
+/// Number of events buffered for subscribers slower than the traffic before they miss some.
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Traffic inspection settings.
+#[derive(Clone)]
+pub struct TapConfig {
+    /// Bearer token subscribers must send in their `Authorization` header. Required.
+    pub token: Option<SecretSource>,
+    /// Maximum number of events sent to a subscriber per second; further events are dropped. Defaults to 50.
+    pub max_events_per_second: u32,
+    /// Maximum number of simultaneous subscribers. Defaults to 4.
+    pub max_subscribers: usize,
+    /// Number of bytes of the request and response bodies sent to subscribers asking for bodies. Bodies are held
+    /// back until that many bytes arrived or they ended. Defaults to 1 KiB.
+    pub max_body_size: usize,
+}
+
+impl Default for TapConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            max_events_per_second: 50,
+            max_subscribers: 4,
+            max_body_size: 1024,
+        }
+    }
+}
+
+impl fmt::Debug for TapConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TapConfig")
+            .field("token", &self.token)
+            .field("max_events_per_second", &self.max_events_per_second)
+            .field("max_subscribers", &self.max_subscribers)
+            .field("max_body_size", &self.max_body_size)
+            .finish()
+    }
+}
+
+/// A condition on the transactions sent to a subscriber, written `key:value` in the `filter` query parameter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TapCondition {
+    /// `host:example.com`: requests to the host, on any port unless one is given.
+    Host(String),
+    /// `path:/api`: requests whose path starts with the prefix.
+    Path(String),
+    /// `method:POST`: requests of the method.
+    Method(Method),
+    /// `status:404`: responses of the status.
+    Status(u16),
+    /// `client:192.0.2.1`: requests from the client address.
+    Client(IpAddr),
+}
+
+/// Conditions a transaction must all meet to be sent to a subscriber.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TapFilter {
+    /// The conditions, none matching all transactions.
+    pub conditions: Vec<TapCondition>,
+}
+
+impl TapFilter {
+    /// Parses conditions separated by commas or spaces, such as `host:example.com,status:500`.
+    pub fn parse(filter: &str) -> Result<Self> {
+        let mut conditions = Vec::new();
+        for term in filter.split([',', ' ']).filter(|term| !term.is_empty()) {
+            let (key, value) = term
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("Invalid filter {}: expected key:value", term))?;
+            let condition = match key {
+                "host" => TapCondition::Host(value.to_ascii_lowercase()),
+                "path" => TapCondition::Path(value.to_string()),
+                "method" => TapCondition::Method(
+                    Method::from_bytes(value.to_ascii_uppercase().as_bytes())
+                        .map_err(|_| anyhow::anyhow!("Invalid method {}", value))?,
+                ),
+                "status" => TapCondition::Status(
+                    value
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("Invalid status {}", value))?,
+                ),
+                "client" => TapCondition::Client(
+                    value
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("Invalid client address {}", value))?,
+                ),
+                _ => anyhow::bail!("Unknown filter key {}", key),
+            };
+            conditions.push(condition);
+        }
+        Ok(TapFilter { conditions })
+    }
+
+    /// Whether `event` meets all the conditions.
+    pub fn matches(&self, event: &TapEvent) -> bool {
+        self.conditions.iter().all(|condition| match condition {
+            TapCondition::Host(host) => event.host.as_deref().is_some_and(|found| {
+                let found = found.to_ascii_lowercase();
+                found == *host || found.rsplit_once(':').is_some_and(|(name, _)| name == host)
+            }),
+            TapCondition::Path(prefix) => event.path.starts_with(prefix.as_str()),
+            TapCondition::Method(method) => event.method == method.as_str(),
+            TapCondition::Status(status) => event.status == *status,
+            TapCondition::Client(ip) => event.client.ip() == *ip,
+        })
+    }
+}
+
+/// Summary of a transaction sent to subscribers.
+#[derive(Clone, Debug, Serialize)]
+pub struct TapEvent {
+    /// Time the request was received, in RFC 3339 format.
+    pub timestamp: String,
+    /// Address of the client.
+    pub client: SocketAddr,
+    /// Method of the request.
+    pub method: String,
+    /// Request target, as sent by the client.
+    pub url: String,
+    /// Path of the request.
+    #[serde(skip)]
+    pub path: String,
+    /// `Host` header of the request, if any.
+    pub host: Option<String>,
+    /// Status of the response, `500` for requests failing without a response.
+    pub status: u16,
+    /// Time taken to produce the response headers, in milliseconds.
+    pub duration_ms: u64,
+    /// Beginning of the request body, if a subscriber asked for bodies.
+    pub request_body: Option<String>,
+    /// Beginning of the response body, if a subscriber asked for bodies.
+    pub response_body: Option<String>,
+}
+
+/// Releases the slot of a subscriber when its stream is dropped.
+struct SubscriberGuard {
+    subscribers: Arc<AtomicUsize>,
+    body_subscribers: Option<Arc<AtomicUsize>>,
+}
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        self.subscribers.fetch_sub(1, Ordering::Relaxed);
+        if let Some(body_subscribers) = &self.body_subscribers {
+            body_subscribers.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// State of the stream of a subscriber.
+struct Subscription {
+    receiver: broadcast::Receiver<Arc<TapEvent>>,
+    filter: TapFilter,
+    bodies: bool,
+    max_events_per_second: u32,
+    window_start: Instant,
+    sent: u32,
+    dropped: u64,
+    _guard: SubscriberGuard,
+}
+
+/// Why a subscription was refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TapRejection {
+    /// The bearer token was missing or wrong.
+    Unauthorized,
+    /// The maximum number of subscribers was reached.
+    TooManySubscribers,
+}
+
+/// Publishes the transactions of the proxy to the subscribers of the admin API.
+pub struct Tap {
+    config: TapConfig,
+    token: String,
+    sender: broadcast::Sender<Arc<TapEvent>>,
+    subscribers: Arc<AtomicUsize>,
+    body_subscribers: Arc<AtomicUsize>,
+}
+
+impl Tap {
+    /// Creates a tap for the given settings, failing if its token is missing, cannot be read or is empty.
+    pub fn new(config: TapConfig) -> Result<Self> {
+        let token = match &config.token {
+            Some(token) => token.read()?,
+            None => String::new(),
+        };
+        if token.is_empty() {
+            anyhow::bail!("Traffic inspection requires a token");
+        }
+        if config.max_events_per_second == 0 {
+            anyhow::bail!("Traffic inspection allows no events");
+        }
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Ok(Tap {
+            config,
+            token,
+            sender,
+            subscribers: Arc::new(AtomicUsize::new(0)),
+            body_subscribers: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Whether anyone is subscribed, so transactions need to be captured.
+    pub fn active(&self) -> bool {
+        self.subscribers.load(Ordering::Relaxed) > 0
+    }
+
+    /// Subscribes to the transactions matching `filter`, with the beginning of their bodies if `bodies`, returning
+    /// a stream of server-sent events. `authorization` is the `Authorization` header of the subscription request.
+    pub fn subscribe(
+        &self,
+        authorization: Option<&str>,
+        filter: TapFilter,
+        bodies: bool,
+    ) -> Result<impl Stream<Item = Result<Event, Infallible>>, TapRejection> {
+        let token = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if !bool::from(token.as_bytes().ct_eq(self.token.as_bytes())) {
+            return Err(TapRejection::Unauthorized);
+        }
+        let subscribed =
+            self.subscribers
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                    (count < self.config.max_subscribers).then_some(count + 1)
+                });
+        if subscribed.is_err() {
+            return Err(TapRejection::TooManySubscribers);
+        }
+        let body_subscribers = bodies.then(|| {
+            self.body_subscribers.fetch_add(1, Ordering::Relaxed);
+            self.body_subscribers.clone()
+        });
+        let subscription = Subscription {
+            receiver: self.sender.subscribe(),
+            filter,
+            bodies,
+            max_events_per_second: self.config.max_events_per_second,
+            window_start: Instant::now(),
+            sent: 0,
+            dropped: 0,
+            _guard: SubscriberGuard {
+                subscribers: self.subscribers.clone(),
+                body_subscribers,
+            },
+        };
+        Ok(futures::stream::unfold(subscription, next_event))
+    }
+
+    /// Records the request of a transaction, capturing the beginning of its body if a subscriber asked for bodies.
+    pub(crate) async fn tap_request(
+        &self,
+        req: Request<Body>,
+        client: SocketAddr,
+    ) -> Result<(TapEvent, Request<Body>)> {
+        let (parts, body) = req.into_parts();
+        let (request_body, body) = self.capture(body).await?;
+        let event = TapEvent {
+            timestamp: access_log::rfc3339(SystemTime::now()),
+            client,
+            method: parts.method.to_string(),
+            url: parts.uri.to_string(),
+            path: parts.uri.path().to_string(),
+            host: parts
+                .headers
+                .get(HOST)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            status: 0,
+            duration_ms: 0,
+            request_body,
+            response_body: None,
+        };
+        Ok((event, Request::from_parts(parts, body)))
+    }
+
+    /// Completes `event` with the response of its transaction, which took `duration`, and publishes it.
+    pub(crate) async fn tap_response(
+        &self,
+        mut event: TapEvent,
+        result: Result<Response<Body>>,
+        duration: Duration,
+    ) -> Result<Response<Body>> {
+        event.duration_ms = duration.as_millis() as u64;
+        let result = match result {
+            Ok(response) => {
+                event.status = response.status().as_u16();
+                let (parts, body) = response.into_parts();
+                let (response_body, body) = self.capture(body).await?;
+                event.response_body = response_body;
+                Ok(Response::from_parts(parts, body))
+            }
+            Err(err) => {
+                event.status = StatusCode::INTERNAL_SERVER_ERROR.as_u16();
+                Err(err)
+            }
+        };
+        // Sending only fails when nobody is subscribed anymore
+        let _ = self.sender.send(Arc::new(event));
+        result
+    }
+
+    /// Reads the beginning of `body` when a subscriber asked for bodies, returning it and the body to forward.
+    async fn capture(&self, body: Body) -> Result<(Option<String>, Body)> {
+        if self.body_subscribers.load(Ordering::Relaxed) == 0 {
+            return Ok((None, body));
+        }
+        let (head, _, body) = peek_body(body, self.config.max_body_size).await?;
+        Ok((Some(String::from_utf8_lossy(&head).into_owned()), body))
+    }
+}
+
+/// Waits for the next event of `subscription` to send, skipping those not matching its filter and dropping those
+/// beyond its rate.
+async fn next_event(
+    mut subscription: Subscription,
+) -> Option<(Result<Event, Infallible>, Subscription)> {
+    loop {
+        let event = match subscription.receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                subscription.dropped += missed;
+                continue;
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        if !subscription.filter.matches(&event) {
+            continue;
+        }
+        if subscription.window_start.elapsed() >= Duration::from_secs(1) {
+            subscription.window_start = Instant::now();
+            subscription.sent = 0;
+        }
+        if subscription.sent >= subscription.max_events_per_second {
+            subscription.dropped += 1;
+            continue;
+        }
+        subscription.sent += 1;
+        let mut data = match serde_json::to_value(&*event) {
+            Ok(serde_json::Value::Object(data)) => data,
+            _ => continue,
+        };
+        if !subscription.bodies {
+            data.remove("request_body");
+            data.remove("response_body");
+        }
+        // Events dropped since the previous one sent, so subscribers know their view is incomplete
+        data.insert(
+            "dropped".to_string(),
+            std::mem::take(&mut subscription.dropped).into(),
+        );
+        let event = Event::default()
+            .event("transaction")
+            .data(serde_json::Value::Object(data).to_string());
+        return Some((Ok(event), subscription));
+    }
+}
@@ -0,0 +1,207 @@
+//! Tarpit holding the connections of banned clients open instead of refusing them, answering so slowly that their
+//! scans stall.
+//!
+//! A held connection gets the status line of a response, then a short header line every interval until the client
+//! gives up or the connection reaches its maximum duration. Nothing is read from the client, and every connection only
+//! costs a sleeping task, so holding many of them is cheap for the proxy and expensive for the client. Clients banned
+//! during a connection get a response whose body trickles in the same way.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::stream;
+use hyper::{body::Bytes, Body};
+use log::debug;
+use rand::Rng;
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Status line starting the responses of held connections.
+const STATUS_LINE: &[u8] = b"HTTP/1.1 200 OK\r\n";
+/// Shortest delay between two lines, below which held connections would cost the proxy more than the clients.
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Tarpit settings.
+#[derive(Clone, Debug)]
+pub struct TarpitConfig {
+    /// Delay between two lines sent to a held connection, at least 100 milliseconds. Defaults to 10 seconds.
+    pub interval: Duration,
+    /// Longest a connection is held before it is closed. Defaults to 10 minutes.
+    pub max_duration: Duration,
+    /// Number of connections held at once, beyond which the connections of banned clients are refused as usual.
+    /// Defaults to 256.
+    pub max_connections: usize,
+    /// Whether the connections refused because of the country of the client are held too. Defaults to `false`.
+    pub refused_countries: bool,
+}
+
+impl Default for TarpitConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            max_duration: Duration::from_secs(600),
+            max_connections: 256,
+            refused_countries: false,
+        }
+    }
+}
+
+/// Counters of the tarpit.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TarpitStats {
+    /// Number of connections held at the moment.
+    pub active: usize,
+    /// Number of connections held since the start.
+    pub trapped: u64,
+    /// Number of connections refused as usual because the tarpit was full.
+    pub overflowed: u64,
+    /// Number of bytes sent to held connections.
+    pub bytes_sent: u64,
+    /// Total time connections were held, in seconds.
+    pub seconds_held: f64,
+    /// Number of connections held for every reason, such as `banned_intruder`.
+    pub reasons: HashMap<&'static str, u64>,
+}
+
+/// Holds the connections of banned clients.
+pub struct Tarpit {
+    config: TarpitConfig,
+    active: AtomicUsize,
+    trapped: AtomicU64,
+    overflowed: AtomicU64,
+    bytes_sent: AtomicU64,
+    millis_held: AtomicU64,
+    reasons: Mutex<HashMap<&'static str, u64>>,
+}
+
+/// A connection held in the tarpit, released when dropped.
+struct Held {
+    tarpit: Arc<Tarpit>,
+    since: Instant,
+}
+
+impl Held {
+    /// Returns the next line to send, once the interval elapsed, or `None` once the connection was held long enough.
+    async fn next_line(&self) -> Option<Bytes> {
+        let config = &self.tarpit.config;
+        if self.since.elapsed().saturating_add(config.interval) > config.max_duration {
+            return None;
+        }
+        tokio::time::sleep(config.interval).await;
+        let line = format!(
+            "X-{:04x}: {:08x}\r\n",
+            rand::thread_rng().gen::<u16>(),
+            rand::thread_rng().gen::<u32>()
+        );
+        self.tarpit
+            .bytes_sent
+            .fetch_add(line.len() as u64, Ordering::Relaxed);
+        Some(Bytes::from(line))
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        self.tarpit.active.fetch_sub(1, Ordering::Relaxed);
+        self.tarpit
+            .millis_held
+            .fetch_add(self.since.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Tarpit {
+    /// Creates a tarpit with the given settings, raising the interval to its minimum if need be.
+    pub fn new(mut config: TarpitConfig) -> Self {
+        config.interval = config.interval.max(MIN_INTERVAL);
+        Tarpit {
+            config,
+            active: AtomicUsize::new(0),
+            trapped: AtomicU64::new(0),
+            overflowed: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            millis_held: AtomicU64::new(0),
+            reasons: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the connections refused because of the country of the client are held.
+    pub(crate) fn holds_refused_countries(&self) -> bool {
+        self.config.refused_countries
+    }
+
+    /// Takes a place in the tarpit for a connection held for `reason`, unless it is full.
+    fn enter(self: &Arc<Self>, reason: &'static str) -> Option<Held> {
+        let admitted = self
+            .active
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| {
+                (active < self.config.max_connections).then_some(active + 1)
+            })
+            .is_ok();
+        if !admitted {
+            self.overflowed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.trapped.fetch_add(1, Ordering::Relaxed);
+        *self.reasons.lock().unwrap().entry(reason).or_insert(0) += 1;
+        Some(Held {
+            tarpit: self.clone(),
+            since: Instant::now(),
+        })
+    }
+
+    /// Holds the connection `stream` for `reason`, returning `false` without writing anything if the tarpit is full.
+    pub(crate) async fn hold<S: AsyncWrite + Unpin>(
+        self: &Arc<Self>,
+        mut stream: S,
+        reason: &'static str,
+    ) -> bool {
+        let held = match self.enter(reason) {
+            Some(held) => held,
+            None => return false,
+        };
+        if stream.write_all(STATUS_LINE).await.is_err() {
+            return true;
+        }
+        self.bytes_sent
+            .fetch_add(STATUS_LINE.len() as u64, Ordering::Relaxed);
+        while let Some(line) = held.next_line().await {
+            if let Err(err) = stream.write_all(&line).await {
+                debug!(
+                    "Tarpitted client gave up after {:?}: {}",
+                    held.since.elapsed(),
+                    err
+                );
+                break;
+            }
+        }
+        true
+    }
+
+    /// Returns a body trickling in for a client banned during its connection, or `None` if the tarpit is full.
+    pub(crate) fn body(self: &Arc<Self>, reason: &'static str) -> Option<Body> {
+        let held = self.enter(reason)?;
+        let lines = stream::unfold(held, |held| async move {
+            let line = held.next_line().await?;
+            Some((Ok::<_, std::io::Error>(line), held))
+        });
+        Some(Body::wrap_stream(lines))
+    }
+
+    /// Returns the counters of the tarpit.
+    pub fn stats(&self) -> TarpitStats {
+        TarpitStats {
+            active: self.active.load(Ordering::Relaxed),
+            trapped: self.trapped.load(Ordering::Relaxed),
+            overflowed: self.overflowed.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            seconds_held: self.millis_held.load(Ordering::Relaxed) as f64 / 1000.0,
+            reasons: self.reasons.lock().unwrap().clone(),
+        }
+    }
+}
@@ -0,0 +1,377 @@
+//! Virtual hosting: tenants selected by the `Host` header, each with its own upstream, certificate, credentials,
+//! cache namespace, rate limit and metrics.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use hyper::{
+    header::{AUTHORIZATION, HOST},
+    http::request,
+};
+use serde::Serialize;
+use subtle::ConstantTimeEq;
+use tokio_rustls::rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey,
+};
+
+use crate::{secret::SecretSource, tunnel};
+
+/// Credentials required from the clients of a tenant through HTTP Basic authentication.
+#[derive(Clone)]
+pub struct BasicAuth {
+    /// Expected user name.
+    pub username: String,
+    /// Expected password.
+    pub password: SecretSource,
+}
+
+impl fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("username", &self.username)
+            .field("password", &self.password)
+            .finish()
+    }
+}
+
+/// A site served by the proxy.
+#[derive(Clone, Debug, Default)]
+pub struct TenantConfig {
+    /// Name of the tenant, used in logs and metrics.
+    pub name: String,
+    /// Host names served for the tenant; wildcards such as `*.example.com` are allowed.
+    pub hosts: Vec<String>,
+    /// Target address of the tenant's upstream, used instead of the global `target_address`.
+    pub target_address: Option<String>,
+    /// Certificate presented to clients requesting one of the tenant's hosts over HTTPS.
+    pub certificate_path: Option<String>,
+    /// Private key of the tenant's certificate.
+    pub private_key_path: Option<String>,
+    /// Passphrase of the tenant's private key, required when it is encrypted.
+    pub private_key_passphrase: Option<SecretSource>,
+    /// Credentials required from the tenant's clients, if any.
+    pub basic_auth: Option<BasicAuth>,
+    /// Namespace of the tenant's cache entries. Defaults to the tenant name.
+    ///
+    /// Tenants only read and evict entries of their own namespace.
+    pub cache_namespace: Option<String>,
+    /// Total size of the cached bodies of the namespace above which its entries are evicted, in bytes. Unlimited
+    /// when `None`.
+    pub cache_quota: Option<usize>,
+    /// Maximum number of requests per minute served for the tenant; further requests get `429 Too Many Requests`.
+    pub max_requests_per_minute: Option<u32>,
+}
+
+impl TenantConfig {
+    /// Returns the namespace of the tenant's cache entries.
+    pub fn cache_namespace(&self) -> &str {
+        self.cache_namespace.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// Traffic of a tenant.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TenantStats {
+    /// Number of requests for the tenant.
+    pub requests: u64,
+    /// Number of those requests that ended with a non-success status.
+    pub errors: u64,
+    /// Number of requests rejected by authentication or the rate limit.
+    pub rejected: u64,
+}
+
+/// Why a tenant's request was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TenantRejection {
+    /// The request lacks valid credentials.
+    Unauthorized,
+    /// The tenant's rate limit is exhausted.
+    RateLimited,
+}
+
+/// The configured tenants with their rate limits and statistics.
+#[derive(Default)]
+pub struct Tenants {
+    tenants: Vec<TenantConfig>,
+    /// Expected `username:password` of the tenants requiring Basic authentication, by tenant name.
+    credentials: HashMap<String, String>,
+    windows: Mutex<HashMap<String, VecDeque<Instant>>>,
+    stats: Mutex<HashMap<String, TenantStats>>,
+}
+
+impl Tenants {
+    /// Creates the tenants, failing on duplicate names, invalid cache namespaces or unreadable passwords.
+    pub fn new(tenants: Vec<TenantConfig>) -> Result<Self> {
+        let mut credentials = HashMap::new();
+        for (index, tenant) in tenants.iter().enumerate() {
+            if tenants[..index]
+                .iter()
+                .any(|other| other.name == tenant.name)
+            {
+                anyhow::bail!("Duplicate tenant name: {}", tenant.name);
+            }
+            let namespace = tenant.cache_namespace();
+            if namespace.is_empty() || namespace.contains(char::is_whitespace) {
+                anyhow::bail!(
+                    "Invalid cache namespace of tenant {}: {:?}",
+                    tenant.name,
+                    namespace
+                );
+            }
+            if let Some(auth) = &tenant.basic_auth {
+                let password = auth.password.read().with_context(|| {
+                    format!("Failed to read the password of tenant {}", tenant.name)
+                })?;
+                credentials.insert(
+                    tenant.name.clone(),
+                    format!("{}:{}", auth.username, password),
+                );
+            }
+        }
+        Ok(Tenants {
+            tenants,
+            credentials,
+            windows: Mutex::new(HashMap::new()),
+            stats: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether any tenant is configured.
+    pub fn is_empty(&self) -> bool {
+        self.tenants.is_empty()
+    }
+
+    /// Returns the tenant serving the host of a request, from its `Host` header or absolute URI.
+    pub fn resolve(&self, parts: &request::Parts) -> Option<&TenantConfig> {
+        let host = parts
+            .headers
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| parts.uri.host())?;
+        self.for_host(host)
+    }
+
+    /// Returns the tenant serving `host`, which may carry a port.
+    pub fn for_host(&self, host: &str) -> Option<&TenantConfig> {
+        let host = strip_port(host);
+        let routes: HashMap<String, String> = self
+            .tenants
+            .iter()
+            .flat_map(|tenant| {
+                tenant
+                    .hosts
+                    .iter()
+                    .map(|host| (host.clone(), tenant.name.clone()))
+            })
+            .collect();
+        let name = tunnel::route_for(&routes, host)?;
+        self.tenants.iter().find(|tenant| &tenant.name == name)
+    }
+
+    /// Checks the credentials and the rate limit of a request for `tenant`, counting the request. The tenant's
+    /// credentials are removed from an admitted request so that they do not reach the upstream.
+    pub fn admit(
+        &self,
+        tenant: &TenantConfig,
+        parts: &mut request::Parts,
+    ) -> Result<(), TenantRejection> {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(tenant.name.clone()).or_default();
+        stats.requests += 1;
+
+        if tenant.basic_auth.is_some() {
+            let expected = self
+                .credentials
+                .get(&tenant.name)
+                .map(String::as_str)
+                .unwrap_or_default();
+            let authorized = parts
+                .headers
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Basic "))
+                .and_then(|encoded| decode_base64(encoded.trim()))
+                .is_some_and(|decoded| bool::from(decoded.as_slice().ct_eq(expected.as_bytes())));
+            if !authorized {
+                stats.rejected += 1;
+                return Err(TenantRejection::Unauthorized);
+            }
+            parts.headers.remove(AUTHORIZATION);
+        }
+
+        if let Some(max_per_minute) = tenant.max_requests_per_minute {
+            let now = Instant::now();
+            let mut windows = self.windows.lock().unwrap();
+            let window = windows.entry(tenant.name.clone()).or_default();
+            while window
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= Duration::from_secs(60))
+            {
+                window.pop_front();
+            }
+            if window.len() >= max_per_minute as usize {
+                stats.rejected += 1;
+                return Err(TenantRejection::RateLimited);
+            }
+            window.push_back(now);
+        }
+        Ok(())
+    }
+
+    /// Records the outcome of a forwarded request for `tenant`.
+    pub fn record(&self, tenant: &TenantConfig, error: bool) {
+        if error {
+            self.stats
+                .lock()
+                .unwrap()
+                .entry(tenant.name.clone())
+                .or_default()
+                .errors += 1;
+        }
+    }
+
+    /// Returns the statistics of every tenant that served requests.
+    pub fn stats(&self) -> HashMap<String, TenantStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Loads the tenants' certificates into a resolver choosing them by SNI, falling back to `default`.
+    pub(crate) fn cert_resolver(&self, default: Arc<CertifiedKey>) -> Result<TenantCertResolver> {
+        let mut routes = HashMap::new();
+        let mut keys = HashMap::new();
+        for tenant in &self.tenants {
+            let (cert_path, key_path) = match (&tenant.certificate_path, &tenant.private_key_path) {
+                (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+                (None, None) => continue,
+                _ => anyhow::bail!(
+                    "Tenant {} needs both a certificate and a private key",
+                    tenant.name
+                ),
+            };
+            let (certs, key) = crate::load_certificate_and_key(
+                cert_path,
+                key_path,
+                tenant.private_key_passphrase.as_ref(),
+            )
+            .context(format!(
+                "Failed to load the certificate of tenant {}",
+                tenant.name
+            ))?;
+            keys.insert(tenant.name.clone(), certified_key(certs, key)?);
+            for host in &tenant.hosts {
+                routes.insert(host.clone(), tenant.name.clone());
+            }
+        }
+        Ok(TenantCertResolver {
+            routes,
+            keys,
+            default: RwLock::new(default),
+        })
+    }
+}
+
+/// Builds a signing key pair from DER certificates and a PKCS#8 private key.
+pub(crate) fn certified_key(certs: Vec<Vec<u8>>, key: Vec<u8>) -> Result<Arc<CertifiedKey>> {
+    let key = sign::any_supported_type(&PrivateKey(key))
+        .map_err(|err| anyhow::anyhow!("Invalid private key: {}", err))?;
+    Ok(Arc::new(CertifiedKey::new(
+        certs.into_iter().map(Certificate).collect(),
+        key,
+    )))
+}
+
+/// Picks the certificate of the tenant matching the SNI of the ClientHello, falling back to the default one.
+pub(crate) struct TenantCertResolver {
+    /// Host names of the tenants with a certificate, mapped to the tenant names.
+    routes: HashMap<String, String>,
+    /// Certificates by tenant name.
+    keys: HashMap<String, Arc<CertifiedKey>>,
+    /// Certificate of the proxy, with the latest OCSP response stapled.
+    default: RwLock<Arc<CertifiedKey>>,
+}
+
+impl TenantCertResolver {
+    /// Staples `ocsp_response` to the default certificate from the next handshake on.
+    pub(crate) fn staple(&self, ocsp_response: Option<Vec<u8>>) {
+        let mut default = self.default.write().unwrap();
+        let mut stapled = CertifiedKey::clone(&default);
+        stapled.ocsp = ocsp_response;
+        *default = Arc::new(stapled);
+    }
+}
+
+impl ResolvesServerCert for TenantCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let tenant = client_hello
+            .server_name()
+            .and_then(|name| tunnel::route_for(&self.routes, name))
+            .and_then(|tenant| self.keys.get(tenant));
+        match tenant {
+            Some(key) => Some(key.clone()),
+            None => Some(self.default.read().unwrap().clone()),
+        }
+    }
+}
+
+/// Removes the port from a `Host` header value.
+pub(crate) fn strip_port(host: &str) -> &str {
+    if let Some((address, _)) = host.strip_prefix('[').and_then(|host| host.split_once(']')) {
+        return address;
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|byte| byte.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+/// Decodes standard base64, as used by Basic authentication.
+pub(crate) fn decode_base64(encoded: &str) -> Option<Vec<u8>> {
+    let mut bits = 0u32;
+    let mut count = 0;
+    let mut decoded = Vec::new();
+    for byte in encoded.bytes().take_while(|byte| *byte != b'=') {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// Encodes bytes in standard base64, with padding.
+pub(crate) fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (index, byte)| {
+            bits | u32::from(*byte) << (16 - 8 * index)
+        });
+        for index in 0..4 {
+            if index <= chunk.len() {
+                encoded.push(char::from(
+                    ALPHABET[(bits >> (18 - 6 * index)) as usize & 63],
+                ));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,96 @@
+//! In-process test harness running the proxy on an ephemeral port.
+//!
+//! Available with the `test-util` feature.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result};
+use log::error;
+use tokio::{net::TcpListener, task::JoinSet};
+
+use crate::{serve_connections, start_background_tasks, Metrics, ProxyConfig, ProxyState};
+
+/// A proxy listening on `127.0.0.1` on an ephemeral port, for integration tests.
+///
+/// Connections are accepted and served as by [`start_proxy_server`](crate::start_proxy_server), with the same
+/// background tasks and other listeners. Only the metrics dashboard is not started. Dropping the proxy stops it.
+///
+/// ```rust,no_run
+/// use fortifynet_proxy::{ProxyConfig, TestProxy};
+///
+/// # async fn example() -> anyhow::Result<()> {
+/// let proxy = TestProxy::start(ProxyConfig {
+///     target_address: Some("http://127.0.0.1:3000".to_string()),
+///     ..Default::default()
+/// })
+/// .await?;
+/// let client = hyper::Client::new();
+/// let response = client.get(proxy.url("/").parse()?).await?;
+/// assert!(response.status().is_success());
+/// assert_eq!(proxy.metrics().total_requests, 1);
+/// proxy.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TestProxy {
+    addr: SocketAddr,
+    state: Arc<ProxyState>,
+    tasks: JoinSet<()>,
+}
+
+impl TestProxy {
+    /// Starts a proxy with `config`, ignoring its address and port.
+    pub async fn start(config: ProxyConfig) -> Result<Self> {
+        let state = Arc::new(ProxyState::new(config)?);
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .context("Failed to bind the test proxy")?;
+        let addr = listener.local_addr()?;
+
+        let mut tasks = JoinSet::new();
+        start_background_tasks(&state, &mut tasks);
+        let serve_state = state.clone();
+        tasks.spawn(async move {
+            if let Err(err) = serve_connections(listener, serve_state).await {
+                error!("Test proxy stopped: {}", err);
+            }
+        });
+        Ok(TestProxy { addr, state, tasks })
+    }
+
+    /// Returns the address the proxy listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the URL of `path` on the proxy, such as `http://127.0.0.1:40123/path`.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Returns the state of the proxy.
+    pub fn state(&self) -> &Arc<ProxyState> {
+        &self.state
+    }
+
+    /// Returns a snapshot of the metrics.
+    pub fn metrics(&self) -> Metrics {
+        self.state.metrics.lock().unwrap().clone()
+    }
+
+    /// Returns a snapshot of the cached response bodies by cache key, leaving out chunked bodies that cannot be read.
+    pub fn cache(&self) -> HashMap<String, Vec<u8>> {
+        self.state
+            .cache
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(key, body)| Some((key.clone(), body.to_vec().ok()?)))
+            .collect()
+    }
+
+    /// Stops accepting connections and closes the open ones, along with the background tasks.
+    pub async fn shutdown(mut self) {
+        self.tasks.shutdown().await;
+    }
+}
@@ -0,0 +1,205 @@
+//! Fixed-size ring buffers of time buckets used for the dashboard's historical graphs.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+/// Number of per-second buckets kept (the last 5 minutes).
+pub const SECOND_BUCKETS: usize = 300;
+/// Number of per-minute buckets kept (the last 24 hours).
+pub const MINUTE_BUCKETS: usize = 1440;
+
+/// Aggregated traffic for a single time bucket.
+#[derive(Default, Clone, Copy, Debug, Serialize)]
+pub struct Bucket {
+    /// Start of the bucket, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Number of requests completed during the bucket.
+    pub requests: u64,
+    /// Number of requests that ended with a non-success status.
+    pub errors: u64,
+    /// Sum of the response times of the requests, in milliseconds.
+    pub total_latency_ms: u64,
+    /// Slowest response time seen during the bucket, in milliseconds.
+    pub max_latency_ms: u64,
+}
+
+impl Bucket {
+    /// Gets the average response time of the bucket in milliseconds.
+    pub fn average_latency_ms(&self) -> u64 {
+        if self.requests == 0 {
+            return 0;
+        }
+        self.total_latency_ms / self.requests
+    }
+}
+
+/// A ring buffer of buckets with a fixed resolution.
+///
+/// Slots are addressed by `timestamp / resolution`, so a slot that has not been
+/// written to for a full rotation is detected as stale and reset on the next write.
+#[derive(Clone)]
+pub struct TimeSeries {
+    resolution: u64,
+    buckets: Vec<Bucket>,
+}
+
+impl TimeSeries {
+    /// Creates a time series with `len` buckets of `resolution` each.
+    pub fn new(resolution: Duration, len: usize) -> Self {
+        TimeSeries {
+            resolution: resolution.as_secs().max(1),
+            buckets: vec![Bucket::default(); len],
+        }
+    }
+
+    /// Records a completed request at `now`.
+    pub fn record_request(&mut self, now: u64, duration: Duration) {
+        let latency_ms = duration.as_millis() as u64;
+        let bucket = self.bucket_mut(now);
+        bucket.requests += 1;
+        bucket.total_latency_ms += latency_ms;
+        bucket.max_latency_ms = bucket.max_latency_ms.max(latency_ms);
+    }
+
+    /// Returns the approximate memory taken by the buckets, in bytes.
+    pub(crate) fn approximate_size(&self) -> usize {
+        self.buckets.capacity() * std::mem::size_of::<Bucket>()
+    }
+
+    /// Records an error at `now`.
+    pub fn record_error(&mut self, now: u64) {
+        self.bucket_mut(now).errors += 1;
+    }
+
+    /// Returns the buckets covering the last `window` ending at `now`, oldest first.
+    ///
+    /// Buckets without traffic are returned zeroed so the result always has one
+    /// entry per resolution step.
+    pub fn window(&self, now: u64, window: Duration) -> Vec<Bucket> {
+        let steps = (window.as_secs() / self.resolution).clamp(1, self.buckets.len() as u64);
+        let current = now / self.resolution;
+        (current + 1 - steps..=current)
+            .map(|index| {
+                let slot = self.buckets[(index % self.buckets.len() as u64) as usize];
+                let timestamp = index * self.resolution;
+                if slot.timestamp == timestamp {
+                    slot
+                } else {
+                    Bucket {
+                        timestamp,
+                        ..Bucket::default()
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn bucket_mut(&mut self, now: u64) -> &mut Bucket {
+        let index = now / self.resolution;
+        let timestamp = index * self.resolution;
+        let len = self.buckets.len() as u64;
+        let bucket = &mut self.buckets[(index % len) as usize];
+        if bucket.timestamp != timestamp {
+            *bucket = Bucket {
+                timestamp,
+                ..Bucket::default()
+            };
+        }
+        bucket
+    }
+}
+
+impl fmt::Debug for TimeSeries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TimeSeries")
+            .field("resolution_secs", &self.resolution)
+            .field("buckets", &self.buckets.len())
+            .finish()
+    }
+}
+
+/// Per-second and per-minute rollups of the proxy traffic.
+#[derive(Clone, Debug)]
+pub struct MetricsHistory {
+    /// Per-second buckets for the last 5 minutes.
+    pub per_second: TimeSeries,
+    /// Per-minute buckets for the last 24 hours.
+    pub per_minute: TimeSeries,
+}
+
+impl Default for MetricsHistory {
+    fn default() -> Self {
+        MetricsHistory {
+            per_second: TimeSeries::new(Duration::from_secs(1), SECOND_BUCKETS),
+            per_minute: TimeSeries::new(Duration::from_secs(60), MINUTE_BUCKETS),
+        }
+    }
+}
+
+impl MetricsHistory {
+    /// Records a completed request in both rollups.
+    pub fn record_request(&mut self, duration: Duration) {
+        let now = unix_now();
+        self.per_second.record_request(now, duration);
+        self.per_minute.record_request(now, duration);
+    }
+
+    /// Returns the approximate memory taken by both rollups, in bytes.
+    pub(crate) fn approximate_size(&self) -> usize {
+        self.per_second.approximate_size() + self.per_minute.approximate_size()
+    }
+
+    /// Records an error in both rollups.
+    pub fn record_error(&mut self) {
+        let now = unix_now();
+        self.per_second.record_error(now);
+        self.per_minute.record_error(now);
+    }
+
+    /// Returns the buckets for the requested window, choosing the per-second
+    /// series for windows up to 5 minutes and the per-minute series otherwise.
+    pub fn window(&self, window: Duration) -> Vec<Bucket> {
+        let now = unix_now();
+        if window.as_secs() <= SECOND_BUCKETS as u64 {
+            self.per_second.window(now, window)
+        } else {
+            self.per_minute.window(now, window)
+        }
+    }
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Renders the buckets as a small inline SVG line chart of the value picked by `value`.
+pub(crate) fn render_sparkline(buckets: &[Bucket], value: impl Fn(&Bucket) -> u64) -> String {
+    const WIDTH: usize = 600;
+    const HEIGHT: u64 = 80;
+    let max = buckets.iter().map(&value).max().unwrap_or(0).max(1);
+    let step = WIDTH as f64 / buckets.len().max(2).saturating_sub(1) as f64;
+    let points: Vec<String> = buckets
+        .iter()
+        .enumerate()
+        .map(|(i, bucket)| {
+            let y = HEIGHT - value(bucket) * HEIGHT / max;
+            format!("{:.1},{}", i as f64 * step, y)
+        })
+        .collect();
+    format!(
+        "<svg width='{}' height='{}' style='border: 1px solid #ccc;'>\
+            <polyline fill='none' stroke='blue' stroke-width='1' points='{}'/>\
+        </svg>",
+        WIDTH,
+        HEIGHT,
+        points.join(" ")
+    )
+}
@@ -0,0 +1,193 @@
+//! Per-request timing breakdown into phases, kept in histograms and optionally sent in `Server-Timing` headers.
+//!
+//! DNS resolution and connection establishment happen inside the HTTP client, so its connector is wrapped to time
+//! them. They are only measured for requests opening a new upstream connection.
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    client::{
+        connect::dns::{GaiAddrs, GaiResolver, Name},
+        HttpConnector,
+    },
+    service::Service,
+    Uri,
+};
+use serde::Serialize;
+
+use crate::trace;
+
+/// Upper bounds of the histogram buckets, in milliseconds. The last bucket is unbounded.
+const BUCKET_BOUNDS_MS: [f64; 13] = [
+    0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 5000.0,
+];
+
+/// A phase of the handling of a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Time between the acceptance of a connection and the start of its first request, excluding `Tls` and `Auth`.
+    Queue,
+    /// Handshake of the client's TLS connection, for the first request of the connection.
+    Tls,
+    /// Authentication of the connection and admission by the tenant.
+    Auth,
+    /// Lookup of the response in the cache.
+    Cache,
+    /// Resolution of the upstream host name.
+    Dns,
+    /// Establishment of the upstream connection, directly or through SOCKS5, excluding `Dns`.
+    Connect,
+    /// Time from sending the request upstream to receiving the response headers, excluding `Dns` and `Connect`.
+    Ttfb,
+    /// Sending of the response body to the client. Only recorded in the histograms, as it ends after the headers.
+    Transfer,
+    /// Time from the start of the request to its response headers, or to the end of its body in the histograms.
+    Total,
+}
+
+impl Phase {
+    /// Returns the name of the phase in `Server-Timing` headers and histograms.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Queue => "queue",
+            Phase::Tls => "tls",
+            Phase::Auth => "auth",
+            Phase::Cache => "cache",
+            Phase::Dns => "dns",
+            Phase::Connect => "connect",
+            Phase::Ttfb => "ttfb",
+            Phase::Transfer => "transfer",
+            Phase::Total => "total",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Distribution of the durations of a phase.
+#[derive(Clone, Debug, Serialize)]
+pub struct TimingHistogram {
+    /// Number of durations recorded.
+    pub count: u64,
+    /// Sum of the durations, in milliseconds.
+    pub sum_ms: f64,
+    /// Longest duration, in milliseconds.
+    pub max_ms: f64,
+    /// Upper bounds of the buckets, in milliseconds, the last bucket being unbounded.
+    pub bounds_ms: &'static [f64],
+    /// Number of durations per bucket, with one more bucket than bounds.
+    pub buckets: Vec<u64>,
+}
+
+impl Default for TimingHistogram {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            sum_ms: 0.0,
+            max_ms: 0.0,
+            bounds_ms: &BUCKET_BOUNDS_MS,
+            buckets: vec![0; BUCKET_BOUNDS_MS.len() + 1],
+        }
+    }
+}
+
+impl TimingHistogram {
+    /// Records a duration.
+    pub fn record(&mut self, duration: Duration) {
+        let ms = duration.as_secs_f64() * 1000.0;
+        let bucket = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_ms += ms;
+        self.max_ms = self.max_ms.max(ms);
+    }
+}
+
+/// Formats phases as the value of a `Server-Timing` header, such as `cache;dur=0.012, total;dur=3.400`.
+pub(crate) fn server_timing(phases: &[(Phase, Duration)]) -> String {
+    phases
+        .iter()
+        .map(|(phase, duration)| format!("{};dur={:.3}", phase, duration.as_secs_f64() * 1000.0))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+type BoxFuture<T, E> = Pin<Box<dyn Future<Output = Result<T, E>> + Send>>;
+
+/// DNS resolver recording the `Dns` phase of the current request.
+#[derive(Clone, Debug)]
+pub struct TimedResolver(GaiResolver);
+
+impl Service<Name> for TimedResolver {
+    type Response = GaiAddrs;
+    type Error = std::io::Error;
+    type Future = BoxFuture<GaiAddrs, std::io::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolving = self.0.call(name);
+        Box::pin(async move {
+            let start = Instant::now();
+            let addrs = resolving.await;
+            trace::phase(Phase::Dns, start.elapsed());
+            addrs
+        })
+    }
+}
+
+/// Connector of the HTTP client recording the `Dns` and `Connect` phases of the current request.
+#[derive(Clone, Debug)]
+pub struct TimedConnector(HttpConnector<TimedResolver>);
+
+impl TimedConnector {
+    /// Creates a connector with the defaults of `HttpConnector::new`, except that `https` URIs are accepted so
+    /// the connector can be wrapped by a TLS connector.
+    pub fn new() -> Self {
+        let mut connector = HttpConnector::new_with_resolver(TimedResolver(GaiResolver::new()));
+        connector.enforce_http(false);
+        TimedConnector(connector)
+    }
+}
+
+impl Default for TimedConnector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Service<Uri> for TimedConnector {
+    type Response = <HttpConnector<TimedResolver> as Service<Uri>>::Response;
+    type Error = <HttpConnector<TimedResolver> as Service<Uri>>::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.0.call(uri);
+        Box::pin(async move {
+            let start = Instant::now();
+            let stream = connecting.await;
+            // Includes the resolution, which is subtracted when the phases of the request are collected
+            trace::phase(Phase::Connect, start.elapsed());
+            stream
+        })
+    }
+}
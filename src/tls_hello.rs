@@ -0,0 +1,377 @@
+//! Read-only parsing of TLS ClientHello messages, used to route TLS streams without terminating them.
+
+use anyhow::Result;
+
+/// Extension type of `server_name`.
+const EXT_SERVER_NAME: u16 = 0x0000;
+/// Extension type of `supported_groups`.
+const EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+/// Extension type of `ec_point_formats`.
+const EXT_EC_POINT_FORMATS: u16 = 0x000b;
+/// Extension type of `signature_algorithms`.
+const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+/// Extension type of `application_layer_protocol_negotiation`.
+const EXT_ALPN: u16 = 0x0010;
+/// Extension type of `supported_versions`.
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+/// Extension type of `encrypted_client_hello`.
+const EXT_ENCRYPTED_CLIENT_HELLO: u16 = 0xfe0d;
+
+/// Largest ClientHello accepted, spread over at most a few TLS records.
+pub(crate) const MAX_CLIENT_HELLO_LEN: usize = 16 * 1024;
+
+/// Fields of a TLS ClientHello.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    /// Legacy protocol version of the handshake message.
+    pub version: u16,
+    /// Offered cipher suites, in order.
+    pub cipher_suites: Vec<u16>,
+    /// Extension types, in order.
+    pub extensions: Vec<u16>,
+    /// Host name of the `server_name` extension (the outer, public name when ECH is used).
+    pub server_name: Option<String>,
+    /// Protocols offered through ALPN.
+    pub alpn: Vec<String>,
+    /// Offered named groups.
+    pub supported_groups: Vec<u16>,
+    /// Offered EC point formats.
+    pub ec_point_formats: Vec<u8>,
+    /// Offered signature algorithms.
+    pub signature_algorithms: Vec<u16>,
+    /// Versions of the `supported_versions` extension.
+    pub supported_versions: Vec<u16>,
+    /// Total length of the handshake message in bytes.
+    pub length: usize,
+}
+
+impl ClientHello {
+    /// Whether the client offered Encrypted ClientHello.
+    pub fn has_ech(&self) -> bool {
+        self.extensions.contains(&EXT_ENCRYPTED_CLIENT_HELLO)
+    }
+
+    /// Whether the client sent GREASE values (RFC 8701) in its cipher suites or extensions.
+    pub fn has_grease(&self) -> bool {
+        self.cipher_suites
+            .iter()
+            .chain(&self.extensions)
+            .any(|value| is_grease(*value))
+    }
+}
+
+/// Whether `value` is a GREASE value (`0x?a?a`).
+pub fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// Parses the ClientHello at the start of a TLS stream.
+///
+/// Returns `Ok(None)` when more bytes are needed and an error when the bytes are not a ClientHello.
+pub fn parse_client_hello(data: &[u8]) -> Result<Option<ClientHello>> {
+    // Reassemble the handshake message from the TLS records
+    let mut handshake = Vec::new();
+    let mut offset = 0;
+    loop {
+        let header = match data.get(offset..offset + 5) {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if header[0] != 0x16 {
+            anyhow::bail!("Not a TLS handshake record (content type {})", header[0]);
+        }
+        let record_len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        // Zero-length handshake fragments are forbidden, and would otherwise be waited on until the buffer fills up
+        if record_len == 0 {
+            anyhow::bail!("Empty TLS handshake record");
+        }
+        let payload = match data.get(offset + 5..offset + 5 + record_len) {
+            Some(payload) => payload,
+            None => return Ok(None),
+        };
+        handshake.extend_from_slice(payload);
+        offset += 5 + record_len;
+        if handshake.len() >= 4 {
+            if handshake[0] != 0x01 {
+                anyhow::bail!("Not a ClientHello (handshake type {})", handshake[0]);
+            }
+            let message_len =
+                u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if message_len > MAX_CLIENT_HELLO_LEN {
+                anyhow::bail!("ClientHello of {} bytes is too large", message_len);
+            }
+            if handshake.len() >= 4 + message_len {
+                let mut hello = parse_body(&handshake[4..4 + message_len])?;
+                hello.length = message_len;
+                return Ok(Some(hello));
+            }
+        }
+        if offset > MAX_CLIENT_HELLO_LEN + 5 * 4 {
+            anyhow::bail!("ClientHello spans too many records");
+        }
+    }
+}
+
+/// Returns the length of the ClientHello at the start of a TLS stream, as declared by its first record, before the
+/// rest of the message arrived. Returns `None` when the first record is not a ClientHello or is too short.
+pub(crate) fn client_hello_len(data: &[u8]) -> Option<usize> {
+    match data.get(..9)? {
+        [0x16, _, _, _, _, 0x01, len @ ..] => {
+            Some(u32::from_be_bytes([0, len[0], len[1], len[2]]) as usize)
+        }
+        _ => None,
+    }
+}
+
+/// A cursor over big-endian TLS structures.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            anyhow::bail!("Truncated ClientHello");
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Returns a reader over a vector prefixed with a `len_bytes`-byte length.
+    fn vector(&mut self, len_bytes: usize) -> Result<Reader<'a>> {
+        let len = match len_bytes {
+            1 => self.u8()? as usize,
+            _ => self.u16()? as usize,
+        };
+        Ok(Reader {
+            data: self.take(len)?,
+        })
+    }
+
+    fn u16_list(mut self) -> Result<Vec<u16>> {
+        let mut values = Vec::with_capacity(self.data.len() / 2);
+        while !self.data.is_empty() {
+            values.push(self.u16()?);
+        }
+        Ok(values)
+    }
+}
+
+fn parse_body(body: &[u8]) -> Result<ClientHello> {
+    let mut reader = Reader { data: body };
+    let mut hello = ClientHello {
+        version: reader.u16()?,
+        ..Default::default()
+    };
+    reader.take(32)?; // random
+    reader.vector(1)?; // legacy session ID
+    hello.cipher_suites = reader.vector(2)?.u16_list()?;
+    reader.vector(1)?; // compression methods
+    if reader.data.is_empty() {
+        return Ok(hello);
+    }
+    let mut extensions = reader.vector(2)?;
+    while !extensions.data.is_empty() {
+        let kind = extensions.u16()?;
+        let mut data = extensions.vector(2)?;
+        hello.extensions.push(kind);
+        match kind {
+            EXT_SERVER_NAME => {
+                let mut names = data.vector(2)?;
+                while !names.data.is_empty() {
+                    let name_type = names.u8()?;
+                    let name = names.vector(2)?.data;
+                    if name_type == 0 {
+                        hello.server_name =
+                            Some(String::from_utf8_lossy(name).to_ascii_lowercase());
+                    }
+                }
+            }
+            EXT_SUPPORTED_GROUPS => hello.supported_groups = data.vector(2)?.u16_list()?,
+            EXT_EC_POINT_FORMATS => hello.ec_point_formats = data.vector(1)?.data.to_vec(),
+            EXT_SIGNATURE_ALGORITHMS => hello.signature_algorithms = data.vector(2)?.u16_list()?,
+            EXT_ALPN => {
+                let mut protocols = data.vector(2)?;
+                while !protocols.data.is_empty() {
+                    let protocol = protocols.vector(1)?.data;
+                    hello
+                        .alpn
+                        .push(String::from_utf8_lossy(protocol).into_owned());
+                }
+            }
+            EXT_SUPPORTED_VERSIONS => hello.supported_versions = data.vector(1)?.u16_list()?,
+            _ => {}
+        }
+    }
+    Ok(hello)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{handshake::ClientHelloRejection, tunnel::read_client_hello};
+
+    /// Returns a ClientHello handshake message with `extensions`.
+    fn handshake(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0x11; 32]);
+        body.extend_from_slice(&[0x00, 0x00, 0x04, 0x13, 0x01, 0x13, 0x02, 0x01, 0x00]);
+        let mut extension_data = Vec::new();
+        for (kind, data) in extensions {
+            extension_data.extend_from_slice(&kind.to_be_bytes());
+            extension_data.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            extension_data.extend_from_slice(data);
+        }
+        body.extend_from_slice(&(extension_data.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extension_data);
+        let mut message = vec![0x01];
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend_from_slice(&body);
+        message
+    }
+
+    /// Returns `handshake` split into TLS records of at most `size` bytes.
+    fn records(handshake: &[u8], size: usize) -> Vec<u8> {
+        let mut stream = Vec::new();
+        for fragment in handshake.chunks(size) {
+            stream.extend_from_slice(&[0x16, 0x03, 0x01]);
+            stream.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            stream.extend_from_slice(fragment);
+        }
+        stream
+    }
+
+    fn sample() -> Vec<u8> {
+        handshake(&[
+            (
+                EXT_SERVER_NAME,
+                vec![
+                    0x00, 0x0e, 0x00, 0x00, 0x0b, b'E', b'x', b'a', b'm', b'p', b'l', b'e', b'.',
+                    b'C', b'O', b'M',
+                ],
+            ),
+            (
+                EXT_SUPPORTED_GROUPS,
+                vec![0x00, 0x04, 0x00, 0x1d, 0x00, 0x17],
+            ),
+            (EXT_EC_POINT_FORMATS, vec![0x01, 0x00]),
+            (EXT_SIGNATURE_ALGORITHMS, vec![0x00, 0x02, 0x04, 0x03]),
+            (EXT_ALPN, vec![0x00, 0x03, 0x02, b'h', b'2']),
+            (EXT_SUPPORTED_VERSIONS, vec![0x04, 0x03, 0x04, 0x03, 0x03]),
+            (0x0a0a, Vec::new()),
+        ])
+    }
+
+    #[test]
+    fn parses_fields() {
+        let message = sample();
+        let hello = parse_client_hello(&records(&message, MAX_CLIENT_HELLO_LEN))
+            .unwrap()
+            .unwrap();
+        assert_eq!(hello.version, 0x0303);
+        assert_eq!(hello.cipher_suites, vec![0x1301, 0x1302]);
+        assert_eq!(
+            hello.extensions,
+            vec![0x0000, 0x000a, 0x000b, 0x000d, 0x0010, 0x002b, 0x0a0a]
+        );
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert_eq!(hello.alpn, vec!["h2"]);
+        assert_eq!(hello.supported_groups, vec![0x001d, 0x0017]);
+        assert_eq!(hello.ec_point_formats, vec![0]);
+        assert_eq!(hello.signature_algorithms, vec![0x0403]);
+        assert_eq!(hello.supported_versions, vec![0x0304, 0x0303]);
+        assert_eq!(hello.length, message.len() - 4);
+        assert!(hello.has_grease());
+        assert!(!hello.has_ech());
+    }
+
+    #[test]
+    fn waits_for_truncated_input() {
+        let stream = records(&sample(), MAX_CLIENT_HELLO_LEN);
+        for len in 0..stream.len() {
+            assert!(parse_client_hello(&stream[..len]).unwrap().is_none());
+        }
+        assert!(parse_client_hello(&stream).unwrap().is_some());
+    }
+
+    #[test]
+    fn reassembles_records_split_mid_message() {
+        let message = sample();
+        let whole = parse_client_hello(&records(&message, MAX_CLIENT_HELLO_LEN))
+            .unwrap()
+            .unwrap();
+        // Records of 1 byte also split the handshake header
+        for size in [1, 3, 7, 64] {
+            let stream = records(&message, size);
+            for len in 0..stream.len() {
+                assert!(parse_client_hello(&stream[..len]).unwrap().is_none());
+            }
+            assert_eq!(parse_client_hello(&stream).unwrap().unwrap(), whole);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_length() {
+        let mut stream = vec![0x16, 0x03, 0x01, 0x00, 0x04, 0x01];
+        stream.extend_from_slice(&(MAX_CLIENT_HELLO_LEN as u32 + 1).to_be_bytes()[1..]);
+        assert_eq!(client_hello_len(&stream), Some(MAX_CLIENT_HELLO_LEN + 1));
+        assert!(parse_client_hello(&stream).is_err());
+
+        // A vector longer than the message is truncated
+        let mut message = sample();
+        message[4 + 2 + 32] = 0xff;
+        assert!(parse_client_hello(&records(&message, MAX_CLIENT_HELLO_LEN)).is_err());
+    }
+
+    #[test]
+    fn rejects_zero_length_records() {
+        let mut stream = vec![0x16, 0x03, 0x01, 0x00, 0x00];
+        stream.extend_from_slice(&records(&sample(), MAX_CLIENT_HELLO_LEN));
+        assert!(parse_client_hello(&stream).is_err());
+        assert!(parse_client_hello(&[0x16, 0x03, 0x01, 0x00, 0x00]).is_err());
+    }
+
+    #[test]
+    fn rejects_other_messages() {
+        assert!(parse_client_hello(b"GET / HTTP/1.1\r\n\r\n").is_err());
+        let mut message = sample();
+        message[0] = 0x02;
+        assert!(parse_client_hello(&records(&message, MAX_CLIENT_HELLO_LEN)).is_err());
+        assert_eq!(
+            client_hello_len(&records(&message, MAX_CLIENT_HELLO_LEN)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_client_hellos_from_streams() {
+        let timeout = Duration::from_secs(1);
+        let stream = records(&sample(), 7);
+        let (buffer, hello) = read_client_hello(&mut stream.as_slice(), timeout, 1024).await;
+        assert_eq!(buffer, stream);
+        assert_eq!(hello.unwrap().server_name.as_deref(), Some("example.com"));
+
+        // The declared length is rejected before the rest of the message is read
+        let (_, hello) = read_client_hello(&mut stream.as_slice(), timeout, 64).await;
+        assert_eq!(hello, Err(ClientHelloRejection::Oversized));
+
+        let (_, hello) = read_client_hello(&mut &stream[..20], timeout, 1024).await;
+        assert_eq!(hello, Err(ClientHelloRejection::Closed));
+
+        let mut request = &b"GET / HTTP/1.1\r\n\r\n"[..];
+        let (_, hello) = read_client_hello(&mut request, timeout, 1024).await;
+        assert_eq!(hello, Err(ClientHelloRejection::Malformed));
+    }
+}
@@ -0,0 +1,132 @@
+//! Egress through Tor, by the SOCKS port of a local Tor client, for privacy-focused forward proxying.
+//!
+//! Streams are isolated on separate circuits by the SOCKS credentials they are opened with, which Tor compares by
+//! default (`IsolateSOCKSAuth`): traffic to different destinations, or of different clients, cannot be linked by the
+//! exit relays. Host names are resolved by Tor, never locally, and onion services are only reached through Tor: the
+//! proxy refuses `.onion` hosts over any other transport, whose name resolution would leak them.
+
+use std::{net::SocketAddr, str::FromStr};
+
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use tokio_socks::tcp::Socks5Stream;
+
+use crate::{connections, connector::Connector, tunnel::UpstreamStream};
+
+/// SOCKS user name of isolated streams, whose password is the isolation key.
+const ISOLATION_USER: &str = "fortifynet";
+/// Longest SOCKS password.
+const MAX_PASSWORD_LEN: usize = 255;
+
+/// How streams through Tor are spread over circuits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TorIsolation {
+    /// Streams share circuits, as Tor rotates them.
+    None,
+    /// Streams to different hosts use different circuits.
+    #[default]
+    PerDestination,
+    /// Streams of different client IP addresses use different circuits. Streams opened outside of a client
+    /// connection, such as those of SNI-routed tunnels, are isolated per destination.
+    PerClient,
+}
+
+/// Local Tor client through which upstreams are reached.
+#[derive(Clone, Debug)]
+pub struct TorConfig {
+    /// Address of the SOCKS port of the Tor client, as `ip:port`. Defaults to `127.0.0.1:9050`.
+    pub socks_address: String,
+    /// How streams are spread over circuits. Defaults to [`TorIsolation::PerDestination`].
+    pub isolation: TorIsolation,
+}
+
+impl Default for TorConfig {
+    fn default() -> Self {
+        Self {
+            socks_address: "127.0.0.1:9050".to_string(),
+            isolation: TorIsolation::default(),
+        }
+    }
+}
+
+/// A transport through Tor.
+pub(crate) struct TorConnector {
+    proxy: SocketAddr,
+    isolation: TorIsolation,
+}
+
+impl TorConnector {
+    /// Prepares the streams through the configured Tor client. Fails if its address is invalid.
+    pub(crate) fn new(config: &TorConfig) -> Result<Self> {
+        let proxy = SocketAddr::from_str(&config.socks_address).map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to parse Tor SOCKS address {}: {}",
+                config.socks_address,
+                e
+            )
+        })?;
+        Ok(TorConnector {
+            proxy,
+            isolation: config.isolation,
+        })
+    }
+
+    /// Returns the key isolating a stream to `host`, taken from the current client connection when isolating per
+    /// client.
+    fn isolation_key(&self, host: &str) -> Option<String> {
+        let client = || connections::current().map(|connection| connection.peer().ip().to_string());
+        let mut key = match self.isolation {
+            TorIsolation::None => return None,
+            TorIsolation::PerDestination => host.to_ascii_lowercase(),
+            TorIsolation::PerClient => client().unwrap_or_else(|| host.to_ascii_lowercase()),
+        };
+        // Host names are at most 253 bytes, so only overlong names are cut
+        while key.len() > MAX_PASSWORD_LEN {
+            key.pop();
+        }
+        Some(key)
+    }
+}
+
+impl Connector for TorConnector {
+    fn describe(&self) -> String {
+        format!("Tor {}", self.proxy)
+    }
+
+    fn connect(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> BoxFuture<'static, Result<Box<dyn UpstreamStream>>> {
+        let proxy = self.proxy;
+        let host = host.to_string();
+        // Read now, while the request of the client connection is being served
+        let key = self.isolation_key(&host);
+        Box::pin(async move {
+            let target = (host.as_str(), port);
+            let stream = match &key {
+                Some(key) => {
+                    Socks5Stream::connect_with_password(proxy, target, ISOLATION_USER, key).await
+                }
+                None => Socks5Stream::connect(proxy, target).await,
+            };
+            let stream = stream.context(format!(
+                "Failed to connect to {}:{} through Tor",
+                host, port
+            ))?;
+            Ok(Box::new(stream) as Box<dyn UpstreamStream>)
+        })
+    }
+}
+
+/// Returns whether `host` is an onion service, reachable only through Tor.
+pub(crate) fn is_onion(host: &str) -> bool {
+    host.trim_end_matches('.')
+        .to_ascii_lowercase()
+        .ends_with(".onion")
+}
+
+/// Returns the error refusing to reach onion service `host` outside of Tor.
+pub(crate) fn outside_tor(host: &str) -> anyhow::Error {
+    anyhow::anyhow!("Refused to reach onion service {} outside of Tor", host)
+}
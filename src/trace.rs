@@ -0,0 +1,469 @@
+//! Connection and request IDs correlating log lines, and timelines of the recent requests.
+//!
+//! Each accepted connection gets an ID, and each request on it another. While a connection or request is handled,
+//! every log line carries the IDs as `conn=` and `req=` fields. The events of a request, from the acceptance of its
+//! connection to the end of its response, are kept for the latest requests and served by `/admin/trace`. The
+//! durations of its phases are collected along, for the timing histograms and `Server-Timing` headers.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures::StreamExt;
+use hyper::{
+    body::HttpBody,
+    header::{HeaderValue, CONTENT_LENGTH},
+    Body, Method, Response,
+};
+use serde::Serialize;
+
+use log::warn;
+
+use crate::{
+    access_log::rfc3339,
+    canary::hex,
+    slow::{PhaseDuration, SlowRequest, SlowRequests},
+    timing::{self, Phase},
+    Metrics,
+};
+
+/// Header carrying the request ID in responses.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Header carrying the phases of a request in responses, when enabled.
+const SERVER_TIMING_HEADER: &str = "server-timing";
+
+/// ID of the next accepted connection.
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+tokio::task_local! {
+    /// The connection, and request if any, being handled by the current task.
+    static CONTEXT: TraceContext;
+}
+
+#[derive(Clone)]
+struct TraceContext {
+    connection: Arc<ConnectionTrace>,
+    request: Option<Arc<RequestTrace>>,
+}
+
+/// Request tracing settings.
+#[derive(Clone, Debug)]
+pub struct TraceConfig {
+    /// Number of recent requests whose timeline is kept; `0` keeps none.
+    pub capacity: usize,
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        Self { capacity: 1000 }
+    }
+}
+
+/// An event in the timeline of a request.
+#[derive(Clone, Debug, Serialize)]
+pub struct TraceEvent {
+    /// Name of the event, such as `cache_lookup` or `first_byte`.
+    pub event: &'static str,
+    /// Microseconds elapsed since the connection was accepted.
+    pub offset_us: u64,
+    /// Details of the event, such as the outcome of a cache lookup.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// The recorded timeline of a request.
+#[derive(Clone, Debug, Serialize)]
+pub struct RequestTimeline {
+    /// ID of the request.
+    pub request_id: String,
+    /// ID of the connection the request was received on.
+    pub connection_id: u64,
+    /// When the connection was accepted, in RFC 3339 format.
+    pub accepted_at: String,
+    /// Method of the request.
+    pub method: String,
+    /// URL of the request, as received.
+    pub url: String,
+    /// Events in the order they happened.
+    pub events: Vec<TraceEvent>,
+}
+
+struct ConnectionTrace {
+    id: u64,
+    accepted: Instant,
+    accepted_at: SystemTime,
+    /// Events preceding the requests, such as the authentication of the connection.
+    events: Mutex<Vec<TraceEvent>>,
+    /// Phases preceding the first request, such as the TLS handshake.
+    phases: Mutex<Vec<(Phase, Duration)>>,
+    /// Number of requests received on the connection.
+    requests: AtomicU64,
+}
+
+impl ConnectionTrace {
+    fn event(&self, event: &'static str, detail: Option<String>) -> TraceEvent {
+        TraceEvent {
+            event,
+            offset_us: self.accepted.elapsed().as_micros() as u64,
+            detail,
+        }
+    }
+}
+
+/// The trace of a request being handled.
+pub(crate) struct RequestTrace {
+    id: String,
+    connection: Arc<ConnectionTrace>,
+    timeline: Mutex<RequestTimeline>,
+    started: Instant,
+    phases: Mutex<Vec<(Phase, Duration)>>,
+}
+
+impl RequestTrace {
+    /// Returns the ID of the request.
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Records an event of the request.
+    pub(crate) fn record(&self, event: &'static str, detail: Option<String>) {
+        let event = self.connection.event(event, detail);
+        self.timeline.lock().unwrap().events.push(event);
+    }
+
+    /// Returns the durations of the phases of the request so far, in the order of `Phase`.
+    ///
+    /// The connector measures `Connect` including `Dns`, and `Ttfb` is measured including both, so they are
+    /// subtracted here.
+    fn phases(&self) -> Vec<(Phase, Duration)> {
+        let recorded = self.phases.lock().unwrap();
+        let total = |phase| {
+            recorded
+                .iter()
+                .filter(|(recorded, _)| *recorded == phase)
+                .map(|(_, duration)| *duration)
+                .sum::<Duration>()
+        };
+        let dns = total(Phase::Dns);
+        let connect = total(Phase::Connect);
+        let mut phases = Vec::new();
+        for phase in [
+            Phase::Queue,
+            Phase::Tls,
+            Phase::Auth,
+            Phase::Cache,
+            Phase::Dns,
+            Phase::Connect,
+            Phase::Ttfb,
+        ] {
+            if !recorded.iter().any(|(recorded, _)| *recorded == phase) {
+                continue;
+            }
+            let duration = match phase {
+                Phase::Connect => connect.saturating_sub(dns),
+                Phase::Ttfb => total(phase).saturating_sub(connect.max(dns)),
+                _ => total(phase),
+            };
+            phases.push((phase, duration));
+        }
+        phases
+    }
+}
+
+/// Assigns request IDs and keeps the timelines of the recent requests.
+pub struct Tracer {
+    capacity: usize,
+    timelines: Mutex<Timelines>,
+}
+
+#[derive(Default)]
+struct Timelines {
+    by_id: HashMap<String, Arc<RequestTrace>>,
+    order: VecDeque<String>,
+}
+
+impl Tracer {
+    /// Creates a tracer keeping the timelines of the last `config.capacity` requests.
+    pub fn new(config: TraceConfig) -> Self {
+        Tracer {
+            capacity: config.capacity,
+            timelines: Mutex::new(Timelines::default()),
+        }
+    }
+
+    /// Returns the timeline of request `id`, if it is among the recent requests.
+    pub fn timeline(&self, id: &str) -> Option<RequestTimeline> {
+        let timelines = self.timelines.lock().unwrap();
+        let trace = timelines.by_id.get(id)?;
+        let timeline = trace.timeline.lock().unwrap().clone();
+        Some(timeline)
+    }
+
+    /// Starts the trace of a request received on the current connection, keeping its timeline.
+    ///
+    /// Returns `None` outside of a connection, such as for HTTP/3 requests.
+    pub(crate) fn begin(&self, method: &Method, url: String) -> Option<Arc<RequestTrace>> {
+        let connection = CONTEXT
+            .try_with(|context| context.connection.clone())
+            .ok()?;
+        let id = hex(&rand::random::<[u8; 8]>());
+        let events = connection.events.lock().unwrap().clone();
+        // The phases of the connection are attributed to its first request
+        let mut phases = Vec::new();
+        if connection.requests.fetch_add(1, Ordering::Relaxed) == 0 {
+            let connection_phases = std::mem::take(&mut *connection.phases.lock().unwrap());
+            let setup: Duration = connection_phases
+                .iter()
+                .map(|(_, duration)| *duration)
+                .sum();
+            phases.push((
+                Phase::Queue,
+                connection.accepted.elapsed().saturating_sub(setup),
+            ));
+            phases.extend(connection_phases);
+        }
+        let trace = Arc::new(RequestTrace {
+            id: id.clone(),
+            timeline: Mutex::new(RequestTimeline {
+                request_id: id.clone(),
+                connection_id: connection.id,
+                accepted_at: rfc3339(connection.accepted_at),
+                method: method.to_string(),
+                url,
+                events,
+            }),
+            connection,
+            started: Instant::now(),
+            phases: Mutex::new(phases),
+        });
+        if self.capacity > 0 {
+            let mut timelines = self.timelines.lock().unwrap();
+            while timelines.order.len() >= self.capacity {
+                if let Some(oldest) = timelines.order.pop_front() {
+                    timelines.by_id.remove(&oldest);
+                }
+            }
+            timelines.order.push_back(id.clone());
+            timelines.by_id.insert(id, trace.clone());
+        }
+        Some(trace)
+    }
+}
+
+/// Runs `future`, handling a newly accepted connection, with a new connection ID.
+pub(crate) async fn in_connection<F: Future>(future: F) -> F::Output {
+    let connection = Arc::new(ConnectionTrace {
+        id: NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed),
+        accepted: Instant::now(),
+        accepted_at: SystemTime::now(),
+        events: Mutex::new(Vec::new()),
+        phases: Mutex::new(Vec::new()),
+        requests: AtomicU64::new(0),
+    });
+    let accept = connection.event("accept", None);
+    connection.events.lock().unwrap().push(accept);
+    let context = TraceContext {
+        connection,
+        request: None,
+    };
+    CONTEXT.scope(context, future).await
+}
+
+/// Runs `future`, handling the request traced by `trace`, with its request ID.
+pub(crate) async fn in_request<F: Future>(trace: Arc<RequestTrace>, future: F) -> F::Output {
+    let context = TraceContext {
+        connection: trace.connection.clone(),
+        request: Some(trace),
+    };
+    CONTEXT.scope(context, future).await
+}
+
+/// Returns the ID of the current connection, if any.
+pub(crate) fn connection_id() -> Option<u64> {
+    CONTEXT.try_with(|context| context.connection.id).ok()
+}
+
+/// Returns the ID of the current request, if it is traced.
+pub(crate) fn current_id() -> Option<String> {
+    CONTEXT
+        .try_with(|context| context.request.as_ref().map(|request| request.id.clone()))
+        .ok()
+        .flatten()
+}
+
+/// Records an event of the current request, or of the current connection before its first request.
+pub(crate) fn event(event: &'static str, detail: Option<String>) {
+    let _ = CONTEXT.try_with(|context| match &context.request {
+        Some(request) => request.record(event, detail),
+        None => {
+            let event = context.connection.event(event, detail);
+            context.connection.events.lock().unwrap().push(event);
+        }
+    });
+}
+
+/// Records the duration of a phase of the current request, or of the current connection before its first request.
+pub(crate) fn phase(phase: Phase, duration: Duration) {
+    let _ = CONTEXT.try_with(|context| match &context.request {
+        Some(request) => request.phases.lock().unwrap().push((phase, duration)),
+        None => context
+            .connection
+            .phases
+            .lock()
+            .unwrap()
+            .push((phase, duration)),
+    });
+}
+
+/// Tags `response` with the ID of its request, and with its phases in a `Server-Timing` header when `server_timing`
+/// is set.
+///
+/// Once the body has been sent, the `complete` event is recorded and the phases are added to the histograms of
+/// `metrics`. A request exceeding the threshold of `slow_requests` is then logged and counted as slow.
+pub(crate) fn finish(
+    trace: Arc<RequestTrace>,
+    response: Response<Body>,
+    metrics: Arc<Mutex<Metrics>>,
+    server_timing: bool,
+    slow_requests: Option<Arc<SlowRequests>>,
+) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+    if let Ok(id) = HeaderValue::from_str(trace.id()) {
+        parts.headers.insert(REQUEST_ID_HEADER, id);
+    }
+    let mut phases = trace.phases();
+    let headers_sent = Instant::now();
+    if server_timing {
+        phases.push((Phase::Total, headers_sent - trace.started));
+        if let Ok(value) = HeaderValue::from_str(&timing::server_timing(&phases)) {
+            parts.headers.insert(SERVER_TIMING_HEADER, value);
+        }
+        phases.pop();
+    }
+    let status = Some(parts.status.as_u16().to_string());
+    let completion = Completion {
+        trace,
+        status,
+        phases,
+        headers_sent,
+        metrics,
+        slow_requests,
+    };
+    if body.is_end_stream() {
+        drop(completion);
+        return Response::from_parts(parts, body);
+    }
+    // A streamed body loses its size, which must then be sent as a header to avoid chunked encoding
+    if let Some(length) = body.size_hint().exact() {
+        if !parts.headers.contains_key(CONTENT_LENGTH) {
+            parts
+                .headers
+                .insert(CONTENT_LENGTH, HeaderValue::from(length));
+        }
+    }
+    let body = Body::wrap_stream(body.map(move |chunk| {
+        let _ = &completion;
+        chunk
+    }));
+    Response::from_parts(parts, body)
+}
+
+/// Records the `complete` event and the phases of a request when its response body is dropped, after it was sent
+/// or when the client went away.
+struct Completion {
+    trace: Arc<RequestTrace>,
+    status: Option<String>,
+    phases: Vec<(Phase, Duration)>,
+    headers_sent: Instant,
+    metrics: Arc<Mutex<Metrics>>,
+    slow_requests: Option<Arc<SlowRequests>>,
+}
+
+impl Completion {
+    /// Returns the slow request this is the completion of, if it exceeded the threshold.
+    fn slow_request(&self, transfer: Duration, total: Duration) -> Option<SlowRequest> {
+        let slow_requests = self.slow_requests.as_ref()?;
+        if !slow_requests.is_slow(total) {
+            return None;
+        }
+        let timeline = self.trace.timeline.lock().unwrap();
+        let phases = self
+            .phases
+            .iter()
+            .copied()
+            .chain([(Phase::Transfer, transfer)])
+            .map(|(phase, duration)| PhaseDuration {
+                phase: phase.name(),
+                duration_ms: duration.as_secs_f64() * 1000.0,
+            })
+            .collect();
+        Some(SlowRequest {
+            request_id: self.trace.id.clone(),
+            route: slow_requests.route(&timeline.method, &timeline.url),
+            url: timeline.url.clone(),
+            status: self.status.clone().unwrap_or_default(),
+            completed_at: rfc3339(SystemTime::now()),
+            duration_ms: total.as_secs_f64() * 1000.0,
+            phases,
+        })
+    }
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        let transfer = self.headers_sent.elapsed();
+        let total = self.trace.started.elapsed();
+        let slow_request = self.slow_request(transfer, total);
+        self.trace.record("complete", self.status.take());
+        let mut metrics = self.metrics.lock().unwrap();
+        for (phase, duration) in &self.phases {
+            metrics.record_timing(*phase, *duration);
+        }
+        metrics.record_timing(Phase::Transfer, transfer);
+        metrics.record_timing(Phase::Total, total);
+        if let (Some(slow_requests), Some(request)) = (&self.slow_requests, slow_request) {
+            // The body may be dropped outside of the request, whose ID is then missing from the log line
+            warn!(
+                "Slow request req={} {} took {:.1}ms, status {}: {}",
+                request.request_id,
+                request.url,
+                request.duration_ms,
+                request.status,
+                request.breakdown()
+            );
+            metrics.record_slow_request(&request.route, slow_requests.max_routes());
+            slow_requests.record(request);
+        }
+    }
+}
+
+/// Formats log lines like `env_logger` does, with the IDs of the current connection and request.
+pub(crate) fn format_log(
+    buf: &mut env_logger::fmt::Formatter,
+    record: &log::Record,
+) -> std::io::Result<()> {
+    let ids = CONTEXT
+        .try_with(|context| match &context.request {
+            Some(request) => format!(" conn={} req={}", context.connection.id, request.id),
+            None => format!(" conn={}", context.connection.id),
+        })
+        .unwrap_or_default();
+    let style = buf.default_level_style(record.level());
+    let level = style.value(format!("{:<5}", record.level()));
+    writeln!(
+        buf,
+        "[{} {} {}{}] {}",
+        buf.timestamp(),
+        level,
+        record.target(),
+        ids,
+        record.args()
+    )
+}
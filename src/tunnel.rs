@@ -0,0 +1,307 @@
+//! CONNECT tunnels and TLS passthrough connections relaying raw bytes, optionally routed by the SNI of the TLS ClientHello.
+//!
+//! Tunneled bytes are never modified, so TLS extensions such as Encrypted ClientHello and GREASE reach the upstream intact.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use log::debug;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+use tokio_socks::tcp::Socks5Stream;
+
+use crate::{
+    handshake::ClientHelloRejection,
+    tls_hello::{client_hello_len, parse_client_hello, ClientHello, MAX_CLIENT_HELLO_LEN},
+};
+
+/// CONNECT tunnel settings.
+#[derive(Clone, Debug)]
+pub struct TunnelConfig {
+    /// Upstream `host:port` used instead of the CONNECT target for TLS streams whose SNI matches the key.
+    ///
+    /// Keys are host names or wildcards such as `*.example.com`.
+    pub sni_routes: HashMap<String, String>,
+    /// How long to wait for the ClientHello when SNI routes are configured or TLS is required.
+    pub sniff_timeout: Duration,
+    /// Ports CONNECT requests may target. Every port is allowed when empty. Defaults to 443 only.
+    pub allowed_ports: Vec<RangeInclusive<u16>>,
+    /// Whether tunnels whose first bytes are not a TLS ClientHello are closed, so protocols such as SSH or SMTP
+    /// cannot be tunneled even to an allowed port. Defaults to `false`.
+    pub require_tls: bool,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        Self {
+            sni_routes: HashMap::new(),
+            sniff_timeout: Duration::from_secs(5),
+            allowed_ports: vec![443..=443],
+            require_tls: false,
+        }
+    }
+}
+
+impl TunnelConfig {
+    /// Whether CONNECT requests may target `port`.
+    pub fn allows_port(&self, port: u16) -> bool {
+        self.allowed_ports.is_empty()
+            || self.allowed_ports.iter().any(|ports| ports.contains(&port))
+    }
+
+    /// Returns the upstream configured for `server_name`, preferring exact matches over wildcards.
+    pub fn route_for(&self, server_name: &str) -> Option<&String> {
+        route_for(&self.sni_routes, server_name)
+    }
+}
+
+/// Returns the route for `server_name` from `routes`, preferring exact matches over wildcards.
+pub(crate) fn route_for<'a>(
+    routes: &'a HashMap<String, String>,
+    server_name: &str,
+) -> Option<&'a String> {
+    let server_name = server_name.to_ascii_lowercase();
+    routes
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(&server_name))
+        .or_else(|| {
+            routes
+                .iter()
+                .find(|(name, _)| wildcard_matches(name, &server_name))
+        })
+        .map(|(_, upstream)| upstream)
+}
+
+/// Whether `server_name` (lowercase) matches a wildcard pattern such as `*.example.com`.
+fn wildcard_matches(pattern: &str, server_name: &str) -> bool {
+    pattern.strip_prefix("*.").is_some_and(|suffix| {
+        server_name
+            .strip_suffix(&suffix.to_ascii_lowercase())
+            .is_some_and(|prefix| prefix.ends_with('.'))
+    })
+}
+
+/// Whether `server_name` matches a host name or a wildcard such as `*.example.com`, ignoring case.
+pub(crate) fn host_matches(pattern: &str, server_name: &str) -> bool {
+    pattern.eq_ignore_ascii_case(server_name)
+        || wildcard_matches(pattern, &server_name.to_ascii_lowercase())
+}
+
+/// A bidirectional byte stream to an upstream.
+pub trait UpstreamStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> UpstreamStream for T {}
+
+/// Opens a TCP connection to `addr` (`host:port`), through the SOCKS5 proxy when given.
+pub(crate) async fn connect(addr: &str, socks5: Option<&str>) -> Result<Box<dyn UpstreamStream>> {
+    match socks5 {
+        Some(socks5) => {
+            let proxy_addr = SocketAddr::from_str(socks5)
+                .map_err(|e| anyhow::anyhow!("Failed to parse SOCKS5 address: {}", e))?;
+            let (host, port) = addr
+                .rsplit_once(':')
+                .context(format!("Missing port in tunnel target {}", addr))?;
+            let port: u16 = port
+                .parse()
+                .context(format!("Invalid port in tunnel target {}", addr))?;
+            let stream = Socks5Stream::connect(proxy_addr, (host, port))
+                .await
+                .context(format!("Failed to connect to {} through SOCKS5", addr))?;
+            Ok(Box::new(stream))
+        }
+        None => {
+            let stream = TcpStream::connect(addr)
+                .await
+                .context(format!("Failed to connect to {}", addr))?;
+            Ok(Box::new(stream))
+        }
+    }
+}
+
+/// Reads from `stream` until a complete ClientHello arrived, the bytes turn out not to be one, or `timeout` elapses.
+///
+/// Returns every byte read, to be replayed to the upstream, and the parsed ClientHello if any.
+pub(crate) async fn sniff_client_hello<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    timeout: Duration,
+) -> (Vec<u8>, Option<ClientHello>) {
+    let (buffer, hello) = read_client_hello(stream, timeout, MAX_CLIENT_HELLO_LEN).await;
+    (buffer, hello.ok())
+}
+
+/// Reads the ClientHello at the start of `stream`, rejecting it as soon as it turns out to be larger than `max_len`
+/// bytes or malformed, or when `timeout` elapses first.
+///
+/// Returns every byte read, to be replayed to the TLS handshake or the upstream, and the parsed ClientHello.
+pub(crate) async fn read_client_hello<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    timeout: Duration,
+    max_len: usize,
+) -> (Vec<u8>, Result<ClientHello, ClientHelloRejection>) {
+    let mut buffer = Vec::new();
+    let now = tokio::time::Instant::now();
+    // Timeouts too long to represent never elapse in practice
+    let deadline = now
+        .checked_add(timeout)
+        .unwrap_or_else(|| now + Duration::from_secs(86400 * 365 * 30));
+    let mut chunk = [0; 4096];
+    while buffer.len() < max_len.saturating_add(64) {
+        let read = tokio::time::timeout_at(deadline, stream.read(&mut chunk)).await;
+        match read {
+            Ok(Ok(0)) | Ok(Err(_)) => return (buffer, Err(ClientHelloRejection::Closed)),
+            Err(_) => return (buffer, Err(ClientHelloRejection::Timeout)),
+            Ok(Ok(n)) => buffer.extend_from_slice(&chunk[..n]),
+        }
+        // The declared length is checked before the rest of the message is waited for
+        if client_hello_len(&buffer).is_some_and(|len| len > max_len) {
+            return (buffer, Err(ClientHelloRejection::Oversized));
+        }
+        match parse_client_hello(&buffer) {
+            Ok(Some(hello)) => return (buffer, Ok(hello)),
+            Ok(None) => continue,
+            Err(err) => {
+                debug!("Stream is not TLS: {}", err);
+                return (buffer, Err(ClientHelloRejection::Malformed));
+            }
+        }
+    }
+    (buffer, Err(ClientHelloRejection::Oversized))
+}
+
+/// Sends `initial` to the upstream, then relays bytes in both directions until either side closes.
+pub(crate) async fn relay<C>(
+    mut client: C,
+    mut upstream: Box<dyn UpstreamStream>,
+    initial: &[u8],
+) -> Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    upstream.write_all(initial).await?;
+    let (sent, received) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok((sent + initial.len() as u64, received))
+}
+
+/// A stream whose sniffed bytes are read again before the rest, so they reach the TLS handshake.
+pub(crate) struct Rewound<S> {
+    initial: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> Rewound<S> {
+    /// Reads `initial`, the bytes already read from `inner`, before reading `inner` further.
+    pub(crate) fn new(initial: Vec<u8>, inner: S) -> Self {
+        Rewound {
+            initial,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewound<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let remaining = &self.initial[self.position..];
+        if remaining.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let len = remaining.len().min(buf.remaining());
+        buf.put_slice(&remaining[..len]);
+        self.position += len;
+        if self.position == self.initial.len() {
+            self.initial = Vec::new();
+            self.position = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewound<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// TLS passthrough listener settings.
+#[derive(Clone, Debug)]
+pub struct PassthroughConfig {
+    /// Port of the passthrough listener, bound on the proxy's `ip_address`.
+    pub port: u16,
+    /// Upstream `host:port` per SNI host name; keys may be wildcards such as `*.example.com`.
+    pub routes: HashMap<String, String>,
+    /// Upstream for connections whose SNI matches no route or is missing. Such connections are closed when `None`.
+    pub default_upstream: Option<String>,
+    /// How long to wait for the ClientHello.
+    pub sniff_timeout: Duration,
+}
+
+impl Default for PassthroughConfig {
+    fn default() -> Self {
+        Self {
+            port: 8443,
+            routes: HashMap::new(),
+            default_upstream: None,
+            sniff_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Routes a TLS connection by the SNI of its ClientHello and relays it to the upstream without decrypting it.
+///
+/// The connection is closed when `admit` refuses its ClientHello.
+pub(crate) async fn handle_passthrough_connection(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    config: &PassthroughConfig,
+    socks5: Option<&str>,
+    admit: impl FnOnce(&ClientHello) -> bool,
+) -> Result<()> {
+    let (initial, hello) = sniff_client_hello(&mut stream, config.sniff_timeout).await;
+    if hello.as_ref().is_some_and(|hello| !admit(hello)) {
+        return Ok(());
+    }
+    let server_name = hello.as_ref().and_then(|hello| hello.server_name.clone());
+    let upstream = server_name
+        .as_deref()
+        .and_then(|name| route_for(&config.routes, name))
+        .or(config.default_upstream.as_ref())
+        .context(format!(
+            "No passthrough route for {} (SNI: {:?})",
+            addr, server_name
+        ))?;
+    debug!(
+        "Passing through {} (SNI: {:?}) to {}",
+        addr, server_name, upstream
+    );
+    let upstream_stream = connect(upstream, socks5).await?;
+    relay(stream, upstream_stream, &initial).await?;
+    Ok(())
+}
@@ -0,0 +1,79 @@
+//! Host header and TLS server name sent to upstreams, which may differ from the address connected to when fronting
+//! CDNs or IP-addressed origins.
+
+use anyhow::Result;
+use tokio_rustls::rustls::ServerName;
+
+/// Host header sent to an upstream.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum HostHeader {
+    /// The upstream itself, as `host` or `host:port` when its port is explicit.
+    #[default]
+    Upstream,
+    /// The Host header sent by the client, unchanged.
+    Client,
+    /// A fixed value.
+    Fixed(String),
+}
+
+/// Host header and server name of a single upstream.
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamHostConfig {
+    /// Upstream the settings apply to, as `host` or `host:port`.
+    pub upstream: String,
+    /// Host header sent to the upstream. Defaults to the upstream itself.
+    pub host_header: HostHeader,
+    /// Server name sent in the TLS ClientHello and checked against the certificate of an `https` upstream, instead
+    /// of its host (optional).
+    pub sni: Option<String>,
+}
+
+/// Validates the settings.
+pub(crate) fn validate(configs: &[UpstreamHostConfig]) -> Result<()> {
+    for (index, config) in configs.iter().enumerate() {
+        if configs[..index]
+            .iter()
+            .any(|other| other.upstream.eq_ignore_ascii_case(&config.upstream))
+        {
+            anyhow::bail!("Duplicate upstream host settings for {}", config.upstream);
+        }
+        if let HostHeader::Fixed(host) = &config.host_header {
+            if host.parse::<hyper::http::uri::Authority>().is_err() {
+                anyhow::bail!(
+                    "Invalid Host header {} for upstream {}",
+                    host,
+                    config.upstream
+                );
+            }
+        }
+        if let Some(sni) = &config.sni {
+            if ServerName::try_from(sni.as_str()).is_err() {
+                anyhow::bail!(
+                    "Invalid server name {} for upstream {}",
+                    sni,
+                    config.upstream
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the settings of `upstream`, as returned by `upstream_key`.
+pub(crate) fn find<'a>(
+    configs: &'a [UpstreamHostConfig],
+    upstream: &str,
+) -> Option<&'a UpstreamHostConfig> {
+    configs
+        .iter()
+        .find(|config| config.upstream.eq_ignore_ascii_case(upstream))
+}
+
+/// Returns the Host header sent to `upstream`, or `None` to keep the one sent by the client.
+pub(crate) fn host_header(configs: &[UpstreamHostConfig], upstream: &str) -> Option<String> {
+    match find(configs, upstream).map(|config| &config.host_header) {
+        None | Some(HostHeader::Upstream) => Some(upstream.to_string()),
+        Some(HostHeader::Client) => None,
+        Some(HostHeader::Fixed(host)) => Some(host.clone()),
+    }
+}
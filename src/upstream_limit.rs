@@ -0,0 +1,365 @@
+//! Per-upstream limits on concurrent connections and queued requests, so a slow upstream cannot tie up the
+//! resources of the whole proxy. Requests beyond the connection limit wait in a bounded queue and are shed when it
+//! is full or when they waited too long.
+//!
+//! The queue is served by weighted fair queuing over the priority classes of the requests: every waiting request is
+//! tagged with the virtual time at which its class would have had its share of the connections, and freed
+//! connections go to the lowest tag. Within a class, requests are served in order.
+//!
+//! With adaptive concurrency, the number of requests in flight is bounded by a limit following the latency of the
+//! upstream instead of by the fixed connection limit, which then only caps it.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use futures::StreamExt;
+use hyper::{
+    body::HttpBody,
+    header::{HeaderValue, CONTENT_LENGTH},
+    Body, Response,
+};
+use serde::Serialize;
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    adaptive::{AdaptiveConcurrencyConfig, AdaptiveLimit},
+    priority::Priority,
+};
+
+/// Virtual time a class of weight 1 takes to use a connection; heavier classes take proportionally less.
+const WEIGHT_SCALE: u64 = 1 << 20;
+
+/// Limits of a single upstream.
+#[derive(Clone, Debug)]
+pub struct UpstreamLimitConfig {
+    /// Upstream the limits apply to, as `host` or `host:port`.
+    pub upstream: String,
+    /// Maximum number of requests in flight to the upstream, and so of HTTP/1.1 connections to it. Defaults to 100.
+    pub max_connections: usize,
+    /// Maximum number of requests waiting for a connection; requests beyond it are shed. Defaults to 100.
+    pub max_pending: usize,
+    /// How long a request waits for a connection before being shed. Defaults to 5 seconds.
+    pub queue_timeout: Duration,
+    /// Adaptive limit on the requests in flight, capped by `max_connections` (optional). The limit is
+    /// `max_connections` when `None`, the default.
+    pub adaptive: Option<AdaptiveConcurrencyConfig>,
+}
+
+impl Default for UpstreamLimitConfig {
+    fn default() -> Self {
+        Self {
+            upstream: String::new(),
+            max_connections: 100,
+            max_pending: 100,
+            queue_timeout: Duration::from_secs(5),
+            adaptive: None,
+        }
+    }
+}
+
+/// Why a request to a limited upstream was shed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitRejection {
+    /// The queue of the upstream was full.
+    QueueFull,
+    /// No connection became available within the queue timeout.
+    Timeout,
+}
+
+impl fmt::Display for LimitRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitRejection::QueueFull => {
+                write!(f, "too many requests are waiting for the upstream")
+            }
+            LimitRejection::Timeout => {
+                write!(f, "no connection to the upstream became available in time")
+            }
+        }
+    }
+}
+
+/// Load of a limited upstream.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct UpstreamLimitStats {
+    /// Upstream, as configured.
+    pub upstream: String,
+    /// Current limit on the requests in flight, which adapts to the latency of the upstream if enabled.
+    pub limit: usize,
+    /// Number of requests in flight.
+    pub active: usize,
+    /// Number of requests waiting for a connection.
+    pub pending: usize,
+    /// Number of requests shed since startup.
+    pub shed: u64,
+}
+
+struct UpstreamLimit {
+    config: UpstreamLimitConfig,
+    connections: Arc<Semaphore>,
+    queue: Mutex<Queue>,
+    pending: AtomicUsize,
+    shed: AtomicU64,
+    /// Current limit on the requests in flight, the number of permits of `connections` but for `debt`.
+    limit: AtomicUsize,
+    /// Permits in use to forget once released, as the limit was lowered below the requests in flight.
+    debt: Mutex<usize>,
+    adaptive: Option<Mutex<AdaptiveLimit>>,
+}
+
+/// Requests waiting for a connection, by virtual finish time and arrival.
+#[derive(Default)]
+struct Queue {
+    waiting: BTreeMap<(u64, u64), oneshot::Sender<OwnedSemaphorePermit>>,
+    /// Virtual finish time of the last request queued, by class.
+    finish: HashMap<usize, u64>,
+    /// Virtual finish time of the last request dispatched.
+    virtual_time: u64,
+    arrivals: u64,
+}
+
+impl Queue {
+    /// Queues a request of `priority`, returning its position.
+    fn push(
+        &mut self,
+        priority: Priority,
+        sender: oneshot::Sender<OwnedSemaphorePermit>,
+    ) -> (u64, u64) {
+        // A class that was idle starts from the current virtual time, rather than catching up on its unused share
+        let start = self
+            .finish
+            .get(&priority.class)
+            .map_or(self.virtual_time, |finish| (*finish).max(self.virtual_time));
+        let finish = start + WEIGHT_SCALE / u64::from(priority.weight.max(1));
+        self.finish.insert(priority.class, finish);
+        self.arrivals += 1;
+        let position = (finish, self.arrivals);
+        self.waiting.insert(position, sender);
+        position
+    }
+}
+
+impl UpstreamLimit {
+    /// Returns the number of requests in flight.
+    fn active(&self) -> usize {
+        let permits = self.limit.load(Ordering::Relaxed) + *self.debt.lock().unwrap();
+        permits.saturating_sub(self.connections.available_permits())
+    }
+
+    /// Updates the adaptive limit with a request answered in `rtt`, or failed.
+    fn record(&self, rtt: Duration, failed: bool) {
+        let mut adaptive = match &self.adaptive {
+            Some(adaptive) => adaptive.lock().unwrap(),
+            None => return,
+        };
+        let limit = adaptive.record(rtt, failed, self.active());
+        let previous = self.limit.swap(limit, Ordering::Relaxed);
+        if limit > previous {
+            let mut grow = limit - previous;
+            {
+                let mut debt = self.debt.lock().unwrap();
+                let repaid = grow.min(*debt);
+                *debt -= repaid;
+                grow -= repaid;
+            }
+            if grow > 0 {
+                self.connections.add_permits(grow);
+                drop(adaptive);
+                self.dispatch();
+            }
+        } else if limit < previous {
+            // Permits in use are forgotten as they are released
+            let shrink = previous - limit;
+            let forgotten = self.connections.forget_permits(shrink);
+            *self.debt.lock().unwrap() += shrink - forgotten;
+        }
+    }
+
+    /// Hands the free connections to the requests at the head of the queue.
+    fn dispatch(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        while !queue.waiting.is_empty() {
+            let permit = match self.connections.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => return,
+            };
+            if let Some(((finish, _), sender)) = queue.waiting.pop_first() {
+                queue.virtual_time = finish;
+                // A request that stopped waiting gives the connection back to the next one
+                let _ = sender.send(permit);
+            }
+        }
+    }
+}
+
+/// A connection to a limited upstream, handed to the next waiting request when dropped.
+pub struct ConnectionPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    limit: Arc<UpstreamLimit>,
+    acquired: Instant,
+}
+
+impl ConnectionPermit {
+    /// Records that the upstream answered, or failed, adapting its limit if enabled.
+    pub(crate) fn record(&self, failed: bool) {
+        self.limit.record(self.acquired.elapsed(), failed);
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            let mut debt = self.limit.debt.lock().unwrap();
+            if *debt > 0 {
+                *debt -= 1;
+                permit.forget();
+            }
+        }
+        self.limit.dispatch();
+    }
+}
+
+/// Connection limits of the upstreams.
+pub struct UpstreamLimits {
+    limits: HashMap<String, Arc<UpstreamLimit>>,
+}
+
+impl UpstreamLimits {
+    /// Validates the limits.
+    pub fn new(configs: Vec<UpstreamLimitConfig>) -> Result<Self> {
+        let mut limits = HashMap::new();
+        for config in configs {
+            if config.max_connections == 0 {
+                anyhow::bail!("Upstream {} allows no connections", config.upstream);
+            }
+            let upstream = config.upstream.to_ascii_lowercase();
+            if limits.contains_key(&upstream) {
+                anyhow::bail!(
+                    "Duplicate connection limits for upstream {}",
+                    config.upstream
+                );
+            }
+            let adaptive = config
+                .adaptive
+                .clone()
+                .map(|adaptive| AdaptiveLimit::new(adaptive, config.max_connections));
+            let initial_limit = adaptive
+                .as_ref()
+                .map_or(config.max_connections, AdaptiveLimit::limit);
+            let limit = UpstreamLimit {
+                connections: Arc::new(Semaphore::new(initial_limit)),
+                limit: AtomicUsize::new(initial_limit),
+                debt: Mutex::new(0),
+                adaptive: adaptive.map(Mutex::new),
+                config,
+                queue: Mutex::new(Queue::default()),
+                pending: AtomicUsize::new(0),
+                shed: AtomicU64::new(0),
+            };
+            limits.insert(upstream, Arc::new(limit));
+        }
+        Ok(UpstreamLimits { limits })
+    }
+
+    /// Waits for a connection to `upstream`, as returned by `upstream_key`, served by `priority` when queued. The
+    /// connection is released when the permit is dropped. Returns `None` for upstreams without limits.
+    pub async fn acquire(
+        &self,
+        upstream: &str,
+        priority: Priority,
+    ) -> Result<Option<ConnectionPermit>, LimitRejection> {
+        let limit = match self.limits.get(upstream) {
+            Some(limit) => limit,
+            None => return Ok(None),
+        };
+        let permit = |permit| ConnectionPermit {
+            permit: Some(permit),
+            limit: limit.clone(),
+            acquired: Instant::now(),
+        };
+        // Requests only skip the queue when it is empty, so that freed connections go to the queue first
+        if limit.queue.lock().unwrap().waiting.is_empty() {
+            if let Ok(free) = limit.connections.clone().try_acquire_owned() {
+                return Ok(Some(permit(free)));
+            }
+        }
+        let queued = limit
+            .pending
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pending| {
+                (pending < limit.config.max_pending).then_some(pending + 1)
+            });
+        if queued.is_err() {
+            limit.shed.fetch_add(1, Ordering::Relaxed);
+            return Err(LimitRejection::QueueFull);
+        }
+        let (sender, mut receiver) = oneshot::channel();
+        let position = limit.queue.lock().unwrap().push(priority, sender);
+        // A connection may have been freed since it was tried
+        limit.dispatch();
+        let dispatched = tokio::time::timeout(limit.config.queue_timeout, &mut receiver).await;
+        limit.pending.fetch_sub(1, Ordering::Relaxed);
+        match dispatched {
+            Ok(Ok(free)) => Ok(Some(permit(free))),
+            // Otherwise the wait timed out, as queued requests are always sent a connection
+            _ => {
+                limit.queue.lock().unwrap().waiting.remove(&position);
+                // The connection may have been handed over just as the wait timed out
+                receiver.close();
+                if let Ok(free) = receiver.try_recv() {
+                    drop(permit(free));
+                }
+                limit.shed.fetch_add(1, Ordering::Relaxed);
+                Err(LimitRejection::Timeout)
+            }
+        }
+    }
+
+    /// Returns the load of the limited upstreams, sorted by upstream.
+    pub fn stats(&self) -> Vec<UpstreamLimitStats> {
+        let mut stats: Vec<_> = self
+            .limits
+            .values()
+            .map(|limit| UpstreamLimitStats {
+                upstream: limit.config.upstream.clone(),
+                limit: limit.limit.load(Ordering::Relaxed),
+                active: limit.active(),
+                pending: limit.pending.load(Ordering::Relaxed),
+                shed: limit.shed.load(Ordering::Relaxed),
+            })
+            .collect();
+        stats.sort_by(|a, b| a.upstream.cmp(&b.upstream));
+        stats
+    }
+}
+
+/// Keeps `permit` until the body of `response` is sent or dropped, as the connection stays busy until then.
+pub(crate) fn hold_until_sent(
+    response: Response<Body>,
+    permit: ConnectionPermit,
+) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+    if body.is_end_stream() {
+        return Response::from_parts(parts, body);
+    }
+    // A streamed body loses its size, which must then be sent as a header to avoid chunked encoding
+    if let Some(length) = body.size_hint().exact() {
+        if !parts.headers.contains_key(CONTENT_LENGTH) {
+            parts
+                .headers
+                .insert(CONTENT_LENGTH, HeaderValue::from(length));
+        }
+    }
+    let body = Body::wrap_stream(body.map(move |chunk| {
+        let _ = &permit;
+        chunk
+    }));
+    Response::from_parts(parts, body)
+}
@@ -0,0 +1,115 @@
+//! Selection of the upstream by trusted clients through a request header, such as `X-Fortify-Upstream: canary`, to
+//! debug or test against a specific backend.
+//!
+//! Clients are trusted when authenticated to the proxy or to their tenant, or when connecting from one of the trusted
+//! networks. The header is removed from every request before it is forwarded, and every use is logged along with the
+//! client, whether it was honored or not.
+
+use std::{collections::HashMap, net::IpAddr};
+
+use anyhow::{Context, Result};
+use hyper::{header::HeaderName, HeaderMap};
+
+use crate::policy::Network;
+
+/// Upstream override settings.
+#[derive(Clone, Debug)]
+pub struct UpstreamOverrideConfig {
+    /// Header naming the upstream. Defaults to `X-Fortify-Upstream`.
+    pub header: String,
+    /// Target addresses that may be selected, by name, such as `canary` to `http://10.0.0.7:8080`.
+    pub upstreams: HashMap<String, String>,
+    /// Addresses or CIDR networks, such as `10.0.0.0/8`, whose clients may select an upstream. Defaults to none.
+    pub trusted_networks: Vec<String>,
+    /// Whether clients authenticated to the proxy or to their tenant may select an upstream. Defaults to true.
+    pub allow_authenticated: bool,
+}
+
+impl Default for UpstreamOverrideConfig {
+    fn default() -> Self {
+        Self {
+            header: "X-Fortify-Upstream".to_string(),
+            upstreams: HashMap::new(),
+            trusted_networks: Vec::new(),
+            allow_authenticated: true,
+        }
+    }
+}
+
+/// Outcome of the upstream override header of a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpstreamSelection {
+    /// The request names no upstream.
+    Default,
+    /// The request is forwarded to the target of the upstream it names.
+    Forced {
+        /// Name of the upstream.
+        name: String,
+        /// Target address of the upstream.
+        target: String,
+    },
+    /// The client may not select an upstream, so the header is ignored.
+    Untrusted {
+        /// Name of the upstream.
+        name: String,
+    },
+    /// The request names an upstream that is not configured.
+    Unknown {
+        /// Name of the upstream.
+        name: String,
+    },
+}
+
+/// Applies the upstream override header of requests.
+pub struct UpstreamOverride {
+    header: HeaderName,
+    upstreams: HashMap<String, String>,
+    trusted_networks: Vec<Network>,
+    allow_authenticated: bool,
+}
+
+impl UpstreamOverride {
+    /// Compiles the settings, failing on an invalid header name or network.
+    pub fn new(config: &UpstreamOverrideConfig) -> Result<Self> {
+        Ok(UpstreamOverride {
+            header: HeaderName::from_bytes(config.header.as_bytes())
+                .context(format!("Invalid header name: {}", config.header))?,
+            upstreams: config.upstreams.clone(),
+            trusted_networks: config
+                .trusted_networks
+                .iter()
+                .map(|network| Network::parse(network))
+                .collect::<Result<_>>()?,
+            allow_authenticated: config.allow_authenticated,
+        })
+    }
+
+    /// Removes the header from `headers`, returning the upstream it selects for a client at `client_ip`,
+    /// `authenticated` or not.
+    pub(crate) fn select(
+        &self,
+        headers: &mut HeaderMap,
+        client_ip: IpAddr,
+        authenticated: bool,
+    ) -> UpstreamSelection {
+        let name = match headers.remove(&self.header) {
+            Some(value) => String::from_utf8_lossy(value.as_bytes()).trim().to_string(),
+            None => return UpstreamSelection::Default,
+        };
+        let trusted = (self.allow_authenticated && authenticated)
+            || self
+                .trusted_networks
+                .iter()
+                .any(|network| network.contains(client_ip));
+        if !trusted {
+            return UpstreamSelection::Untrusted { name };
+        }
+        match self.upstreams.get(&name) {
+            Some(target) => UpstreamSelection::Forced {
+                target: target.clone(),
+                name,
+            },
+            None => UpstreamSelection::Unknown { name },
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Validation of request URIs before anything routes on them: overly long URIs are rejected, and so are paths
+//! climbing out of their prefix with `..` segments, however they are encoded.
+//!
+//! Routing by path prefix sees `/static/%2e%2e/admin` as a request under `/static`, while an origin decoding the path
+//! serves `/admin`. The percent-encodings of `.`, `/` and `\` are therefore decoded, several times over to catch
+//! double encodings such as `%252e`, before looking for `..` segments. Paths with such segments are either rejected or
+//! forwarded resolved, so routing and the origin agree on the path.
+
+use anyhow::{Context, Result};
+use hyper::Uri;
+
+use crate::normalize::remove_dot_segments;
+
+/// Number of times the separators of a path are decoded, against multiple encodings.
+const MAX_DECODING_ROUNDS: usize = 3;
+
+/// Request URI validation settings.
+#[derive(Clone, Debug)]
+pub struct UriGuardConfig {
+    /// Longest request URI accepted, in bytes; longer ones are rejected with `414 URI Too Long`. Defaults to 8192.
+    pub max_uri_length: usize,
+    /// Whether paths with `..` segments, once decoded, are rejected with `400 Bad Request`. When `false`, they are
+    /// forwarded with the segments resolved instead. Defaults to `true`.
+    pub reject_traversal: bool,
+}
+
+impl Default for UriGuardConfig {
+    fn default() -> Self {
+        Self {
+            max_uri_length: 8192,
+            reject_traversal: true,
+        }
+    }
+}
+
+/// A reason a request URI is rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UriViolation {
+    /// The URI is longer than the configured maximum.
+    TooLong,
+    /// The path has `..` segments once decoded.
+    Traversal,
+}
+
+impl UriViolation {
+    /// Returns the name of the violation, as counted in the metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            UriViolation::TooLong => "uri_too_long",
+            UriViolation::Traversal => "path_traversal",
+        }
+    }
+}
+
+/// Outcome of the validation of a request URI.
+#[derive(Debug)]
+pub(crate) enum UriVerdict {
+    /// The URI is forwarded as is.
+    Accept,
+    /// The URI is forwarded with its path resolved.
+    Rewrite(Uri),
+    /// The request is rejected.
+    Reject(UriViolation),
+}
+
+impl UriGuardConfig {
+    /// Validates `uri`.
+    pub(crate) fn check(&self, uri: &Uri) -> Result<UriVerdict> {
+        if uri.to_string().len() > self.max_uri_length {
+            return Ok(UriVerdict::Reject(UriViolation::TooLong));
+        }
+        // Asterisk-form and authority-form requests have no path to traverse
+        let path = uri.path();
+        if !path.starts_with('/') {
+            return Ok(UriVerdict::Accept);
+        }
+        let decoded = decode_separators(path);
+        if !decoded.split('/').any(|segment| segment == "..") {
+            return Ok(UriVerdict::Accept);
+        }
+        if self.reject_traversal {
+            return Ok(UriVerdict::Reject(UriViolation::Traversal));
+        }
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{}?{}", remove_dot_segments(&decoded), query),
+            None => remove_dot_segments(&decoded),
+        };
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(
+            path_and_query
+                .parse()
+                .context(format!("Invalid resolved form of {}", uri))?,
+        );
+        let uri = Uri::from_parts(parts).context(format!("Invalid resolved form of {}", uri))?;
+        Ok(UriVerdict::Rewrite(uri))
+    }
+}
+
+/// Decodes the percent-encodings of `.`, `/` and `\` in `path`, including multiple encodings, turning `\` into `/`.
+fn decode_separators(path: &str) -> String {
+    let mut path = path.to_string();
+    for _ in 0..MAX_DECODING_ROUNDS {
+        let decoded = decode_separators_once(&path);
+        if decoded == path {
+            break;
+        }
+        path = decoded;
+    }
+    path.replace('\\', "/")
+}
+
+/// Decodes one level of percent-encoding of `.`, `/` and `\`, and of the `%` encoding one of them.
+fn decode_separators_once(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let hex = |index: usize| {
+        bytes
+            .get(index..index + 2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+    };
+    let decode = |index: usize| hex(index + 1).filter(|_| bytes[index] == b'%');
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match decode(index) {
+            Some(byte @ (b'.' | b'/' | b'\\')) => {
+                output.push(byte);
+                index += 3;
+            }
+            // The `%` of `%252e`, leaving `%2e` to the next round
+            Some(b'%') if matches!(hex(index + 3), Some(b'.' | b'/' | b'\\')) => {
+                output.push(b'%');
+                index += 3;
+            }
+            _ => {
+                output.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    // Only ASCII bytes were replaced, so the output stays valid UTF-8
+    String::from_utf8(output).unwrap_or_else(|_| path.to_string())
+}
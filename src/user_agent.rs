@@ -0,0 +1,159 @@
+//! Rules matching the `User-Agent` header of requests.
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+/// Substrings (lowercase) identifying crawlers, scrapers and HTTP libraries.
+const BOT_MARKERS: &[&str] = &[
+    "bot",
+    "crawler",
+    "spider",
+    "slurp",
+    "scrapy",
+    "curl/",
+    "wget/",
+    "python-requests",
+    "python-urllib",
+    "go-http-client",
+    "java/",
+    "libwww-perl",
+    "headlesschrome",
+];
+
+/// Substrings (lowercase) identifying mobile devices.
+const MOBILE_MARKERS: &[&str] = &[
+    "mobile",
+    "android",
+    "iphone",
+    "ipad",
+    "ipod",
+    "windows phone",
+    "blackberry",
+    "opera mini",
+];
+
+/// Built-in categories of user agents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UserAgentCategory {
+    /// Crawlers, scrapers and command-line HTTP clients.
+    Bot,
+    /// Phones and tablets.
+    Mobile,
+    /// Requests without a `User-Agent` header.
+    Missing,
+}
+
+impl UserAgentCategory {
+    /// Whether `user_agent` belongs to the category.
+    pub fn matches(&self, user_agent: Option<&str>) -> bool {
+        let user_agent = match user_agent {
+            Some(user_agent) if !user_agent.trim().is_empty() => user_agent.to_lowercase(),
+            _ => return *self == UserAgentCategory::Missing,
+        };
+        match self {
+            UserAgentCategory::Bot => BOT_MARKERS.iter().any(|m| user_agent.contains(m)),
+            UserAgentCategory::Mobile => MOBILE_MARKERS.iter().any(|m| user_agent.contains(m)),
+            UserAgentCategory::Missing => false,
+        }
+    }
+}
+
+/// How a rule matches the `User-Agent` header.
+#[derive(Clone, Debug)]
+pub enum UserAgentMatch {
+    /// Matches user agents against a regular expression.
+    Regex(String),
+    /// Matches user agents of a built-in category.
+    Category(UserAgentCategory),
+}
+
+/// What happens to requests matching a rule.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UserAgentAction {
+    /// Rejects the request with `403 Forbidden`.
+    Block,
+    /// Neither serves the request from the cache nor caches its response.
+    BypassCache,
+    /// Forwards the request to the given target address instead of `target_address`.
+    Route(String),
+}
+
+/// A rule applying an action to requests whose `User-Agent` matches.
+#[derive(Clone, Debug)]
+pub struct UserAgentRule {
+    /// Condition on the `User-Agent` header.
+    pub matcher: UserAgentMatch,
+    /// Action applied to matching requests.
+    pub action: UserAgentAction,
+}
+
+/// Outcome of evaluating the user agent rules for a request.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserAgentDecision {
+    /// Whether the request must be rejected.
+    pub block: bool,
+    /// Whether the cache must be bypassed.
+    pub bypass_cache: bool,
+    /// Target address the request must be forwarded to, if overridden.
+    pub route: Option<String>,
+}
+
+/// A [`UserAgentMatch`] with its regular expression compiled, shared by the User-Agent rules and the policies.
+pub(crate) enum CompiledMatch {
+    Regex(Regex),
+    Category(UserAgentCategory),
+}
+
+impl CompiledMatch {
+    /// Compiles `matcher`, failing on an invalid regular expression.
+    pub(crate) fn new(matcher: &UserAgentMatch) -> Result<Self> {
+        Ok(match matcher {
+            UserAgentMatch::Regex(pattern) => CompiledMatch::Regex(
+                Regex::new(pattern).context(format!("Invalid User-Agent regex: {}", pattern))?,
+            ),
+            UserAgentMatch::Category(category) => CompiledMatch::Category(*category),
+        })
+    }
+
+    /// Whether `user_agent`, the value of the `User-Agent` header if any, matches.
+    pub(crate) fn matches(&self, user_agent: Option<&str>) -> bool {
+        match self {
+            CompiledMatch::Regex(regex) => user_agent.is_some_and(|ua| regex.is_match(ua)),
+            CompiledMatch::Category(category) => category.matches(user_agent),
+        }
+    }
+}
+
+/// User agent rules with their regular expressions compiled.
+pub struct UserAgentRules {
+    rules: Vec<(CompiledMatch, UserAgentAction)>,
+}
+
+impl UserAgentRules {
+    /// Compiles the rules, failing on invalid regular expressions.
+    pub fn new(rules: &[UserAgentRule]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| Ok((CompiledMatch::new(&rule.matcher)?, rule.action.clone())))
+            .collect::<Result<_>>()?;
+        Ok(UserAgentRules { rules })
+    }
+
+    /// Evaluates every rule against `user_agent`. The first matching route wins.
+    pub fn evaluate(&self, user_agent: Option<&str>) -> UserAgentDecision {
+        let mut decision = UserAgentDecision::default();
+        for (matcher, action) in &self.rules {
+            if !matcher.matches(user_agent) {
+                continue;
+            }
+            match action {
+                UserAgentAction::Block => decision.block = true,
+                UserAgentAction::BypassCache => decision.bypass_cache = true,
+                UserAgentAction::Route(target) => {
+                    decision.route.get_or_insert_with(|| target.clone());
+                }
+            }
+        }
+        decision
+    }
+}
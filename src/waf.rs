@@ -0,0 +1,600 @@
+//! Web application firewall: rules detecting SQL injection and cross-site scripting in the query strings, headers and
+//! bodies of requests, after a subset of the OWASP Core Rule Set.
+//!
+//! As in the Core Rule Set, a matching rule does not block a request by itself but adds its score to the anomaly
+//! score of the request, which is blocked once the score reaches the threshold. Query parameters and form bodies are
+//! URL-decoded before matching, and patterns match case-insensitively. In log mode, requests reaching the threshold are
+//! only logged, to try rules out before enforcing them. The firewall and its mode can be switched at runtime.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{Context, Result};
+use futures::{stream, StreamExt};
+use hyper::{
+    body::Bytes,
+    header::{CONTENT_TYPE, COOKIE},
+    http::request,
+    Body,
+};
+use log::warn;
+use regex::bytes::Regex;
+use serde::Serialize;
+
+/// What happens to requests reaching the anomaly threshold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WafMode {
+    /// Rejects the request with `403 Forbidden`.
+    Block,
+    /// Only logs the request.
+    Log,
+}
+
+/// Where a rule looks for its pattern.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WafTarget {
+    /// The names and values of the query parameters, URL-decoded.
+    Query,
+    /// The values of the headers, and the names and values of the cookies.
+    Headers,
+    /// The body, URL-decoded when it is a form.
+    Body,
+}
+
+/// A rule adding its score to the requests matching its pattern.
+#[derive(Clone, Debug)]
+pub struct WafRule {
+    /// ID of the rule, such as the ID of the Core Rule Set rule it follows.
+    pub id: String,
+    /// Description of what the rule detects, used in logs.
+    pub description: String,
+    /// Regular expression matched case-insensitively.
+    pub pattern: String,
+    /// Parts of the request matched against the pattern.
+    pub targets: Vec<WafTarget>,
+    /// Score added to the anomaly score of a matching request: 5 for critical, 4 for error, 3 for warning and 2 for
+    /// notice, as in the Core Rule Set.
+    pub score: u32,
+}
+
+impl WafRule {
+    /// Returns the built-in SQL injection and cross-site scripting rules.
+    pub fn core_rules() -> Vec<WafRule> {
+        const ALL: [WafTarget; 3] = [WafTarget::Query, WafTarget::Headers, WafTarget::Body];
+        let rule = |id: &str, description: &str, pattern: &str, score| WafRule {
+            id: id.to_string(),
+            description: description.to_string(),
+            pattern: pattern.to_string(),
+            targets: ALL.to_vec(),
+            score,
+        };
+        vec![
+            rule("941100", "XSS: script tag", r"<script[\s/>]", 5),
+            rule(
+                "941110",
+                "XSS: event handler attribute",
+                r"<[^>]*\bon(?:error|load|click|mouseover|focus|blur|submit|toggle)\s*=",
+                5,
+            ),
+            rule(
+                "941120",
+                "XSS: javascript or vbscript URI",
+                r"\b(?:java|vb)script\s*:",
+                5,
+            ),
+            rule(
+                "941160",
+                "XSS: HTML injection of active content",
+                r"<(?:iframe|object|embed|svg|math|base|form)\b",
+                5,
+            ),
+            rule(
+                "942100",
+                "SQLi: UNION SELECT",
+                r"\bunion\b(?:\s+|/\*.*?\*/)+(?:all\s+|distinct\s+)?select\b",
+                5,
+            ),
+            rule(
+                "942130",
+                "SQLi: tautology",
+                r#"['"]\s*(?:or|and)\s+['"]?\w+['"]?\s*(?:=|<>|!=|like)\s*['"]?\w+|\bor\s+1\s*=\s*1\b"#,
+                5,
+            ),
+            rule(
+                "942140",
+                "SQLi: database catalog access",
+                r"\b(?:information_schema|pg_catalog|sysobjects|syscolumns|mysql\.user|sqlite_master)\b",
+                5,
+            ),
+            rule(
+                "942160",
+                "SQLi: blind injection by time delay",
+                r"\b(?:sleep|benchmark|pg_sleep)\s*\(|\bwaitfor\s+delay\b",
+                5,
+            ),
+            rule(
+                "942190",
+                "SQLi: stacked query",
+                r";\s*(?:drop|delete|insert|update|alter|exec|shutdown)\s",
+                5,
+            ),
+            rule(
+                "942440",
+                "SQLi: comment sequence after a quote",
+                r#"['"]\s*(?:--|#|/\*)"#,
+                3,
+            ),
+        ]
+    }
+}
+
+/// Web application firewall settings.
+#[derive(Clone, Debug)]
+pub struct WafConfig {
+    /// Whether requests are inspected; can be changed at runtime. Defaults to `true`.
+    pub enabled: bool,
+    /// What happens to requests reaching the threshold; can be changed at runtime. Defaults to `WafMode::Block`.
+    pub mode: WafMode,
+    /// Rules evaluated against the requests. Defaults to `WafRule::core_rules()`.
+    pub rules: Vec<WafRule>,
+    /// Anomaly score at which a request is blocked. Defaults to 5, so one critical rule blocks.
+    pub anomaly_threshold: u32,
+    /// Number of body bytes buffered and inspected. Defaults to 64 KiB.
+    pub max_body_size: usize,
+    /// Whether requests with a body larger than `max_body_size` are rejected with `413 Payload Too Large`; otherwise
+    /// only the first `max_body_size` bytes are inspected and the rest is forwarded uninspected. Defaults to `true`.
+    pub block_oversized: bool,
+}
+
+impl Default for WafConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            mode: WafMode::Block,
+            rules: WafRule::core_rules(),
+            anomaly_threshold: 5,
+            max_body_size: 64 * 1024,
+            block_oversized: true,
+        }
+    }
+}
+
+/// Outcome of inspecting a request.
+pub enum WafVerdict {
+    /// Forward the request with this body.
+    Forward(Body),
+    /// Reject the request, which reached the threshold with the listed rules.
+    Block(Vec<String>),
+    /// Reject the request, whose body exceeds the inspection cap.
+    TooLarge,
+}
+
+/// Matches of a rule.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct WafRuleStats {
+    /// ID of the rule.
+    pub id: String,
+    /// Description of the rule.
+    pub description: String,
+    /// Number of requests the rule matched.
+    pub matches: u64,
+    /// Number of blocked requests the rule matched.
+    pub blocked: u64,
+}
+
+/// State and counters of the firewall.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct WafStats {
+    /// Whether requests are inspected.
+    pub enabled: bool,
+    /// Whether requests reaching the threshold are blocked, rather than only logged.
+    pub blocking: bool,
+    /// Number of requests inspected.
+    pub inspected: u64,
+    /// Number of requests that reached the threshold, blocked or not.
+    pub flagged: u64,
+    /// Number of requests blocked.
+    pub blocked: u64,
+    /// Number of requests rejected for a body beyond the inspection cap.
+    pub oversized: u64,
+    /// Matches of every rule that matched at least once.
+    pub rules: Vec<WafRuleStats>,
+}
+
+struct CompiledRule {
+    id: String,
+    description: String,
+    regex: Regex,
+    targets: Vec<WafTarget>,
+    score: u32,
+}
+
+/// Web application firewall with its rules compiled.
+pub struct Waf {
+    config: WafConfig,
+    rules: Vec<CompiledRule>,
+    enabled: AtomicBool,
+    blocking: AtomicBool,
+    inspected: AtomicU64,
+    flagged: AtomicU64,
+    blocked: AtomicU64,
+    oversized: AtomicU64,
+    /// Matches and blocked matches of every rule, by rule ID.
+    hits: Mutex<HashMap<String, (u64, u64)>>,
+}
+
+impl Waf {
+    /// Compiles the rules, failing on invalid regular expressions.
+    pub fn new(config: WafConfig) -> Result<Self> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                let regex = Regex::new(&format!("(?i){}", rule.pattern)).context(format!(
+                    "Invalid WAF regex in rule {}: {}",
+                    rule.id, rule.pattern
+                ))?;
+                Ok(CompiledRule {
+                    id: rule.id.clone(),
+                    description: rule.description.clone(),
+                    regex,
+                    targets: rule.targets.clone(),
+                    score: rule.score,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Waf {
+            enabled: AtomicBool::new(config.enabled),
+            blocking: AtomicBool::new(config.mode == WafMode::Block),
+            config,
+            rules,
+            inspected: AtomicU64::new(0),
+            flagged: AtomicU64::new(0),
+            blocked: AtomicU64::new(0),
+            oversized: AtomicU64::new(0),
+            hits: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether requests are inspected.
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Turns the inspection of requests on or off.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns what happens to requests reaching the threshold.
+    pub fn mode(&self) -> WafMode {
+        if self.blocking.load(Ordering::Relaxed) {
+            WafMode::Block
+        } else {
+            WafMode::Log
+        }
+    }
+
+    /// Changes what happens to requests reaching the threshold.
+    pub fn set_mode(&self, mode: WafMode) {
+        self.blocking
+            .store(mode == WafMode::Block, Ordering::Relaxed);
+    }
+
+    /// Evaluates the rules against the query string, headers and body of a request.
+    pub async fn inspect(&self, parts: &request::Parts, mut body: Body) -> Result<WafVerdict> {
+        if !self.enabled() {
+            return Ok(WafVerdict::Forward(body));
+        }
+        self.inspected.fetch_add(1, Ordering::Relaxed);
+        let mut matched: Vec<&CompiledRule> = Vec::new();
+
+        if let Some(query) = parts.uri.query() {
+            self.scan(
+                WafTarget::Query,
+                &decode_form(query.as_bytes()),
+                &mut matched,
+            );
+        }
+        for (name, value) in &parts.headers {
+            if name == COOKIE {
+                let cookies: Vec<u8> = value
+                    .as_bytes()
+                    .iter()
+                    .map(|byte| if *byte == b';' { b'&' } else { *byte })
+                    .collect();
+                self.scan(WafTarget::Headers, &decode_form(&cookies), &mut matched);
+            } else {
+                self.scan(WafTarget::Headers, value.as_bytes(), &mut matched);
+            }
+        }
+
+        // Buffer the body up to the cap
+        let mut buffered = Vec::new();
+        let mut overflow = None;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.context("Failed to read request body")?;
+            if buffered.len() + chunk.len() > self.config.max_body_size {
+                overflow = Some(chunk);
+                break;
+            }
+            buffered.extend_from_slice(&chunk);
+        }
+        if overflow.is_some() && self.config.block_oversized {
+            self.oversized.fetch_add(1, Ordering::Relaxed);
+            return Ok(WafVerdict::TooLarge);
+        }
+        let form = parts
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| {
+                content_type.starts_with("application/x-www-form-urlencoded")
+            });
+        if form {
+            self.scan(WafTarget::Body, &decode_form(&buffered), &mut matched);
+        } else {
+            self.scan(WafTarget::Body, &buffered, &mut matched);
+        }
+
+        let score: u32 = matched.iter().map(|rule| rule.score).sum();
+        let flagged = score >= self.config.anomaly_threshold;
+        let blocking = flagged && self.mode() == WafMode::Block;
+        {
+            let mut hits = self.hits.lock().unwrap();
+            for rule in &matched {
+                let (matches, blocked) = hits.entry(rule.id.clone()).or_default();
+                *matches += 1;
+                if blocking {
+                    *blocked += 1;
+                }
+            }
+        }
+        let ids: Vec<String> = matched.iter().map(|rule| rule.id.clone()).collect();
+        if flagged {
+            self.flagged.fetch_add(1, Ordering::Relaxed);
+            let descriptions: Vec<&str> = matched
+                .iter()
+                .map(|rule| rule.description.as_str())
+                .collect();
+            warn!(
+                "WAF anomaly score {} for a request to {} (rules {}: {}){}",
+                score,
+                parts.uri,
+                ids.join(", "),
+                descriptions.join("; "),
+                if blocking { "" } else { ", logged only" }
+            );
+        }
+        if blocking {
+            self.blocked.fetch_add(1, Ordering::Relaxed);
+            return Ok(WafVerdict::Block(ids));
+        }
+
+        let head = Bytes::from(buffered);
+        let body = match overflow {
+            // Stream the uninspected remainder after the inspected head
+            Some(overflow) => Body::wrap_stream(stream::iter([Ok(head), Ok(overflow)]).chain(body)),
+            None => Body::from(head),
+        };
+        Ok(WafVerdict::Forward(body))
+    }
+
+    /// Adds the rules targeting `target` that match `data` to `matched`, once each.
+    fn scan<'a>(&'a self, target: WafTarget, data: &[u8], matched: &mut Vec<&'a CompiledRule>) {
+        if data.is_empty() {
+            return;
+        }
+        for rule in &self.rules {
+            if rule.targets.contains(&target)
+                && !matched.iter().any(|other| other.id == rule.id)
+                && rule.regex.is_match(data)
+            {
+                matched.push(rule);
+            }
+        }
+    }
+
+    /// Returns the state and counters of the firewall.
+    pub fn stats(&self) -> WafStats {
+        let hits = self.hits.lock().unwrap();
+        let rules = self
+            .rules
+            .iter()
+            .filter_map(|rule| {
+                let (matches, blocked) = hits.get(&rule.id)?;
+                Some(WafRuleStats {
+                    id: rule.id.clone(),
+                    description: rule.description.clone(),
+                    matches: *matches,
+                    blocked: *blocked,
+                })
+            })
+            .collect();
+        WafStats {
+            enabled: self.enabled(),
+            blocking: self.mode() == WafMode::Block,
+            inspected: self.inspected.load(Ordering::Relaxed),
+            flagged: self.flagged.load(Ordering::Relaxed),
+            blocked: self.blocked.load(Ordering::Relaxed),
+            oversized: self.oversized.load(Ordering::Relaxed),
+            rules,
+        }
+    }
+}
+
+/// Returns the names and values of URL-encoded form data, decoded, as `name=value` lines.
+fn decode_form(data: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(data.len());
+    for (name, value) in url::form_urlencoded::parse(data) {
+        decoded.extend_from_slice(name.trim().as_bytes());
+        decoded.push(b'=');
+        decoded.extend_from_slice(value.as_bytes());
+        decoded.push(b'\n');
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Request;
+
+    use super::*;
+
+    /// Inspects a request to `uri` with `headers` and the body `chunks`, returning the matched rules if it is blocked
+    /// and the forwarded body otherwise.
+    async fn inspect(
+        waf: &Waf,
+        uri: &str,
+        headers: &[(&str, &str)],
+        chunks: &[&str],
+    ) -> Result<Bytes, Vec<String>> {
+        let mut request = Request::builder().method("POST").uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let parts = request.body(()).unwrap().into_parts().0;
+        let chunks: Vec<Result<Bytes, std::io::Error>> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from(chunk.to_string())))
+            .collect();
+        match waf
+            .inspect(&parts, Body::wrap_stream(stream::iter(chunks)))
+            .await
+            .unwrap()
+        {
+            WafVerdict::Forward(body) => Ok(hyper::body::to_bytes(body).await.unwrap()),
+            WafVerdict::Block(ids) => Err(ids),
+            WafVerdict::TooLarge => Err(vec!["too large".to_string()]),
+        }
+    }
+
+    const FORM: (&str, &str) = ("content-type", "application/x-www-form-urlencoded");
+
+    #[tokio::test]
+    async fn blocks_core_attacks() {
+        let waf = Waf::new(WafConfig::default()).unwrap();
+        let blocked = |ids: &[&str]| Err(ids.iter().map(|id| id.to_string()).collect::<Vec<_>>());
+        assert_eq!(
+            inspect(&waf, "/?q=1%27%20OR%20%271%27%3D%271", &[], &[]).await,
+            blocked(&["942130"])
+        );
+        assert_eq!(
+            inspect(&waf, "/?id=1+UNION/**/SELECT+password", &[], &[]).await,
+            blocked(&["942100"])
+        );
+        assert_eq!(
+            inspect(&waf, "/", &[("cookie", "a=1; b=%3CScRiPt%3E")], &[]).await,
+            blocked(&["941100"])
+        );
+        assert_eq!(
+            inspect(
+                &waf,
+                "/",
+                &[FORM],
+                &["c=%3Cimg%20src%3Dx%20onerror%3Dalert(1)%3E"]
+            )
+            .await,
+            blocked(&["941110"])
+        );
+        assert_eq!(
+            inspect(&waf, "/", &[], &["{\"q\": \"x; DROP TABLE users\"}"]).await,
+            blocked(&["942190"])
+        );
+        // Rules match once per request, whichever parts they match
+        assert_eq!(
+            inspect(&waf, "/?a=%3Csvg%3E", &[("referer", "<svg>")], &["<svg>"]).await,
+            blocked(&["941160"])
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_benign_requests() {
+        let waf = Waf::new(WafConfig::default()).unwrap();
+        let body = "{\"name\": \"O'Brien\", \"note\": \"select a union rep\"}";
+        assert_eq!(
+            inspect(&waf, "/search?q=o%27reilly+books", &[], &[body]).await,
+            Ok(Bytes::from(body))
+        );
+        // A warning alone stays below the threshold, but is counted
+        assert_eq!(
+            inspect(&waf, "/?q=x%27--", &[], &[]).await,
+            Ok(Bytes::new())
+        );
+        let stats = waf.stats();
+        assert_eq!(stats.inspected, 2);
+        assert_eq!(stats.flagged, 0);
+        assert_eq!(stats.rules.len(), 1);
+        assert_eq!(stats.rules[0].id, "942440");
+        assert_eq!(stats.rules[0].matches, 1);
+        assert_eq!(stats.rules[0].blocked, 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_bodies_beyond_the_cap() {
+        let waf = Waf::new(WafConfig {
+            max_body_size: 8,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            inspect(&waf, "/", &[], &["12345", "678", "<script>", "9"]).await,
+            Err(vec!["too large".to_string()])
+        );
+        assert_eq!(
+            inspect(&waf, "/", &[], &["12345678"]).await,
+            Ok(Bytes::from("12345678"))
+        );
+        assert_eq!(waf.stats().oversized, 1);
+    }
+
+    #[tokio::test]
+    async fn inspects_the_head_of_oversized_bodies_when_allowed() {
+        let waf = Waf::new(WafConfig {
+            max_body_size: 8,
+            block_oversized: false,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            inspect(&waf, "/", &[], &["12345", "678", "<script>", "9"]).await,
+            Ok(Bytes::from("12345678<script>9"))
+        );
+        assert!(inspect(&waf, "/", &[], &["<script>", "123456789"])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn switches_modes_at_runtime() {
+        let waf = Waf::new(WafConfig::default()).unwrap();
+        let attack = "/?q=%3Cscript%3E";
+        waf.set_mode(WafMode::Log);
+        assert!(inspect(&waf, attack, &[], &[]).await.is_ok());
+        waf.set_enabled(false);
+        assert!(inspect(&waf, attack, &[], &[]).await.is_ok());
+        waf.set_enabled(true);
+        waf.set_mode(WafMode::Block);
+        assert!(inspect(&waf, attack, &[], &[]).await.is_err());
+        let stats = waf.stats();
+        assert_eq!((stats.inspected, stats.flagged, stats.blocked), (2, 2, 1));
+        assert_eq!(stats.rules[0].matches, 2);
+        assert_eq!(stats.rules[0].blocked, 1);
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let mut rule = WafRule::core_rules().remove(0);
+        rule.pattern = "(".to_string();
+        assert!(Waf::new(WafConfig {
+            rules: vec![rule],
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,143 @@
+//! Running the proxy as a Windows service, started and stopped by the Service Control Manager.
+//!
+//! The binary is registered with the service name it is given, for instance:
+//!
+//! ```text
+//! sc.exe create FortifyNet binPath= "C:\fortifynet\fortifynet_proxy.exe --service FortifyNet --config C:\fortifynet\proxy.toml --log-file C:\fortifynet\proxy.log" start= auto
+//! ```
+//!
+//! Stopping the service stops accepting connections and closes the open ones along with the background tasks, as
+//! dropping the server does, before reporting the service stopped.
+
+use std::{ffi::OsString, fs::OpenOptions, path::Path, sync::Mutex, time::Duration};
+
+use ::windows_service::{
+    define_windows_service,
+    service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+};
+use anyhow::{Context, Result};
+use log::{error, info};
+use tokio::sync::oneshot;
+
+use crate::{start_proxy_server, trace, ProxyConfig, ResourceSizing};
+
+/// Time the Service Control Manager is told starting or stopping may take.
+const PENDING_WAIT_HINT: Duration = Duration::from_secs(5);
+/// Longest time the background tasks are given to finish once the server stopped.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Name and configuration of the service, taken by [`service_main`].
+static SERVICE: Mutex<Option<(String, ProxyConfig)>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Runs the proxy as the service `name`, returning once the service is stopped. Logs are appended to `log_file`, as
+/// services have no console.
+///
+/// Fails when the process was not started by the Service Control Manager.
+pub fn run_service(name: &str, config: ProxyConfig, log_file: Option<&Path>) -> Result<()> {
+    if let Some(path) = log_file {
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+        // Installed before the server starts, which then keeps it
+        let _ = env_logger::Builder::from_default_env()
+            .format(trace::format_log)
+            .target(env_logger::Target::Pipe(Box::new(log)))
+            .try_init();
+    }
+    *SERVICE.lock().unwrap() = Some((name.to_string(), config));
+    service_dispatcher::start(name, ffi_service_main)
+        .context("Failed to connect to the Service Control Manager")
+}
+
+/// Entry point of the service, run by the Service Control Manager on a thread of its own.
+fn service_main(_arguments: Vec<OsString>) {
+    let (name, config) = match SERVICE.lock().unwrap().take() {
+        Some(service) => service,
+        None => return,
+    };
+    let (stop, stopped) = oneshot::channel();
+    let stop = Mutex::new(Some(stop));
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            if let Some(stop) = stop.lock().unwrap().take() {
+                let _ = stop.send(());
+            }
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let handle = match service_control_handler::register(&name, handler) {
+        Ok(handle) => handle,
+        Err(err) => {
+            error!("Failed to register the service control handler: {}", err);
+            return;
+        }
+    };
+    set_status(
+        &handle,
+        ServiceState::StartPending,
+        ServiceExitCode::NO_ERROR,
+    );
+
+    let result = ResourceSizing::detect(&config.resources)
+        .runtime(&config.resources)
+        .context("Failed to start the runtime")
+        .and_then(|runtime| {
+            set_status(&handle, ServiceState::Running, ServiceExitCode::NO_ERROR);
+            let result = runtime.block_on(async {
+                tokio::select! {
+                    result = start_proxy_server(config) => result,
+                    _ = stopped => {
+                        info!("Stopping the proxy service");
+                        set_status(&handle, ServiceState::StopPending, ServiceExitCode::NO_ERROR);
+                        Ok(())
+                    }
+                }
+            });
+            runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+            result
+        });
+    match result {
+        Ok(()) => set_status(&handle, ServiceState::Stopped, ServiceExitCode::NO_ERROR),
+        Err(err) => {
+            error!("Proxy service failed: {:#}", err);
+            set_status(
+                &handle,
+                ServiceState::Stopped,
+                ServiceExitCode::ServiceSpecific(1),
+            );
+        }
+    }
+}
+
+/// Reports the state of the service.
+fn set_status(handle: &ServiceStatusHandle, state: ServiceState, exit_code: ServiceExitCode) {
+    let status = ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code,
+        checkpoint: 0,
+        wait_hint: match state {
+            ServiceState::StartPending | ServiceState::StopPending => PENDING_WAIT_HINT,
+            _ => Duration::ZERO,
+        },
+        process_id: None,
+    };
+    if let Err(err) = handle.set_service_status(status) {
+        error!("Failed to report the service status: {}", err);
+    }
+}
@@ -0,0 +1,786 @@
+//! Upstream connections through a WireGuard tunnel run in userspace: the proxy is a WireGuard peer with addresses
+//! of its own inside the tunnel and its own TCP/IP stack, so no interface, route or VPN client is set up on the host.
+//!
+//! The tunnel is brought up by the first connection through it, then re-keyed as WireGuard requires while in use.
+//! The WireGuard protocol itself is BoringTun's; this module runs the TCP/IP stack over it.
+//! Host names are resolved by the system resolver, outside of the tunnel.
+//!
+//! Tunnels require the `wireguard` feature; without it, configuring one is an error.
+
+use std::{fmt, net::IpAddr, time::Duration};
+
+#[cfg(feature = "wireguard")]
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+
+use crate::{connector::Connector, secret, tunnel::UpstreamStream};
+
+/// WireGuard peer through which upstreams are reached.
+#[derive(Clone)]
+pub struct WireGuardConfig {
+    /// Private key of the proxy, in base64 as printed by `wg genkey`.
+    pub private_key: String,
+    /// Public key of the peer, in base64.
+    pub peer_public_key: String,
+    /// Key shared with the peer, in base64 as printed by `wg genpsk` (optional).
+    pub preshared_key: Option<String>,
+    /// Endpoint of the peer, as `host:port`.
+    pub endpoint: String,
+    /// Addresses of the proxy inside the tunnel, such as `10.0.0.2`; at most one per IP version. Upstreams are
+    /// reached over the IP versions of these addresses.
+    pub addresses: Vec<IpAddr>,
+    /// Interval of the keepalives sent to the peer while the tunnel is up, keeping NAT mappings open (optional).
+    pub persistent_keepalive: Option<Duration>,
+    /// MTU of the tunnel. Defaults to 1420, as `wg-quick` does.
+    pub mtu: usize,
+}
+
+impl Default for WireGuardConfig {
+    fn default() -> Self {
+        Self {
+            private_key: String::new(),
+            peer_public_key: String::new(),
+            preshared_key: None,
+            endpoint: String::new(),
+            addresses: Vec::new(),
+            persistent_keepalive: None,
+            mtu: 1420,
+        }
+    }
+}
+
+impl fmt::Debug for WireGuardConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WireGuardConfig")
+            .field("private_key", &secret::redact(&self.private_key))
+            .field("peer_public_key", &self.peer_public_key)
+            .field("preshared_key", &secret::redact_option(&self.preshared_key))
+            .field("endpoint", &self.endpoint)
+            .field("addresses", &self.addresses)
+            .field("persistent_keepalive", &self.persistent_keepalive)
+            .field("mtu", &self.mtu)
+            .finish()
+    }
+}
+
+/// A transport through a WireGuard tunnel.
+pub(crate) struct WireGuardConnector {
+    endpoint: String,
+    #[cfg(feature = "wireguard")]
+    tunnel: Arc<tunnel::Tunnel>,
+}
+
+impl WireGuardConnector {
+    /// Prepares the tunnel to the configured peer, brought up on the first connection. Fails if a key or the
+    /// addresses are invalid.
+    #[cfg(feature = "wireguard")]
+    pub(crate) fn new(config: WireGuardConfig) -> Result<Self> {
+        Ok(WireGuardConnector {
+            endpoint: config.endpoint.clone(),
+            tunnel: Arc::new(tunnel::Tunnel::new(config)?),
+        })
+    }
+
+    /// Prepares the tunnel to the configured peer.
+    #[cfg(not(feature = "wireguard"))]
+    pub(crate) fn new(config: WireGuardConfig) -> Result<Self> {
+        anyhow::bail!(
+            "Cannot tunnel through WireGuard peer {}: fortifynet_proxy was built without the `wireguard` feature",
+            config.endpoint
+        )
+    }
+}
+
+impl Connector for WireGuardConnector {
+    fn describe(&self) -> String {
+        format!("WireGuard peer {}", self.endpoint)
+    }
+
+    #[cfg(feature = "wireguard")]
+    fn connect(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> BoxFuture<'static, Result<Box<dyn UpstreamStream>>> {
+        let tunnel = self.tunnel.clone();
+        let host = host.to_string();
+        Box::pin(async move { tunnel.connect(&host, port).await })
+    }
+
+    #[cfg(not(feature = "wireguard"))]
+    fn connect(
+        &self,
+        _host: &str,
+        _port: u16,
+    ) -> BoxFuture<'static, Result<Box<dyn UpstreamStream>>> {
+        Box::pin(async {
+            anyhow::bail!("fortifynet_proxy was built without the `wireguard` feature")
+        })
+    }
+}
+
+#[cfg(feature = "wireguard")]
+mod tunnel {
+    use std::{
+        collections::VecDeque,
+        future::poll_fn,
+        io,
+        net::{IpAddr, SocketAddr},
+        pin::Pin,
+        sync::{Arc, Mutex, Weak},
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use anyhow::{Context as _, Result};
+    use boringtun::{
+        noise::{errors::WireGuardError, Tunn, TunnResult},
+        x25519::{PublicKey, StaticSecret},
+    };
+    use log::{debug, info, warn};
+    use smoltcp::{
+        iface::{Config, Interface, SocketHandle, SocketSet},
+        phy::{self, DeviceCapabilities, Medium},
+        socket::{tcp, AnySocket},
+        wire::{HardwareAddress, IpAddress, IpCidr},
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncWrite, ReadBuf},
+        net::{lookup_host, UdpSocket},
+        sync::{Notify, OnceCell},
+    };
+
+    use super::WireGuardConfig;
+    use crate::{tenant::decode_base64, tunnel::UpstreamStream};
+
+    /// Size of the largest UDP message from the peer.
+    const MAX_MESSAGE_LEN: usize = 65535;
+    /// Bytes added to an IP packet by its transport data message.
+    const DATA_OVERHEAD: usize = 32;
+    /// Size of the buffers of every TCP connection, in each direction.
+    const TCP_BUFFER_SIZE: usize = 64 * 1024;
+    /// Time without acknowledgement after which a TCP connection is aborted, including while connecting.
+    const TCP_TIMEOUT: u64 = 30;
+    /// Longest wait of the tunnel task, so it notices that the tunnel is no longer used. The timers of the
+    /// WireGuard protocol are also updated at least this often.
+    const MAX_WAIT: Duration = Duration::from_secs(1);
+
+    /// Decodes a base64 key.
+    fn decode_key(encoded: &str, name: &str) -> Result<[u8; 32]> {
+        decode_base64(encoded.trim())
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .with_context(|| format!("Invalid WireGuard {}: expected 32 bytes in base64", name))
+    }
+
+    /// The WireGuard protocol with the peer, as implemented by BoringTun: handshakes and their retries, cookies,
+    /// sessions, replay protection and keepalives.
+    struct Peer {
+        tunn: Tunn,
+        /// Buffer the messages to the peer and the packets from it are written to.
+        buffer: Vec<u8>,
+        /// Whether the handshakes expired, which is reported once.
+        expired: bool,
+    }
+
+    impl Peer {
+        fn new(config: &WireGuardConfig) -> Result<Self> {
+            let private_key = StaticSecret::from(decode_key(&config.private_key, "private key")?);
+            let peer_key = PublicKey::from(decode_key(&config.peer_public_key, "peer public key")?);
+            let preshared_key = match &config.preshared_key {
+                Some(key) => Some(decode_key(key, "preshared key")?),
+                None => None,
+            };
+            let persistent_keepalive = config
+                .persistent_keepalive
+                .map(|interval| interval.as_secs().clamp(1, u64::from(u16::MAX)) as u16);
+            let tunn = Tunn::new(
+                private_key,
+                peer_key,
+                preshared_key,
+                persistent_keepalive,
+                rand::random::<u32>() >> 8,
+                None,
+            );
+            Ok(Peer {
+                tunn,
+                buffer: vec![0; config.mtu.max(MAX_MESSAGE_LEN) + DATA_OVERHEAD],
+                expired: false,
+            })
+        }
+
+        /// Handles a message from the peer, returning the IP packet it carried, if any, and the messages to send.
+        fn receive(&mut self, message: &[u8]) -> (Option<Vec<u8>>, Vec<Vec<u8>>) {
+            let mut messages = Vec::new();
+            let mut datagram = message;
+            loop {
+                match self.tunn.decapsulate(None, datagram, &mut self.buffer) {
+                    // Once a session is established, the packets waiting for it follow, one per call
+                    TunnResult::WriteToNetwork(message) => {
+                        messages.push(message.to_vec());
+                        datagram = &[];
+                    }
+                    TunnResult::WriteToTunnelV4(packet, _)
+                    | TunnResult::WriteToTunnelV6(packet, _) => {
+                        return (Some(packet.to_vec()), messages);
+                    }
+                    // Keepalives and cookie replies carry no packet
+                    TunnResult::Done => return (None, messages),
+                    TunnResult::Err(err) => {
+                        debug!("Ignored a message from the WireGuard peer: {:?}", err);
+                        return (None, messages);
+                    }
+                }
+            }
+        }
+
+        /// Encrypts `packet`, returning the message to send, or keeps it until a session is established and returns
+        /// the initiation of its handshake if none is in progress.
+        fn send(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+            self.expired = false;
+            match self.tunn.encapsulate(packet, &mut self.buffer) {
+                TunnResult::WriteToNetwork(message) => Some(message.to_vec()),
+                TunnResult::Err(err) => {
+                    debug!("Failed to send a packet through WireGuard: {:?}", err);
+                    None
+                }
+                _ => None,
+            }
+        }
+
+        /// Returns the message due, if any: a handshake retry, a new handshake or a keepalive.
+        fn tick(&mut self) -> Option<Vec<u8>> {
+            match self.tunn.update_timers(&mut self.buffer) {
+                TunnResult::WriteToNetwork(message) => Some(message.to_vec()),
+                TunnResult::Err(WireGuardError::ConnectionExpired) => {
+                    if !self.expired {
+                        warn!("WireGuard peer did not answer the handshake, dropping the packets waiting for it");
+                        self.expired = true;
+                    }
+                    None
+                }
+                TunnResult::Err(err) => {
+                    debug!("Failed to update the WireGuard timers: {:?}", err);
+                    None
+                }
+                _ => None,
+            }
+        }
+    }
+
+    /// Device of the TCP/IP stack exchanging packets with the tunnel.
+    struct VirtualDevice {
+        received: VecDeque<Vec<u8>>,
+        sent: Vec<Vec<u8>>,
+        mtu: usize,
+    }
+
+    struct RxToken(Vec<u8>);
+
+    impl phy::RxToken for RxToken {
+        fn consume<R, F: FnOnce(&mut [u8]) -> R>(mut self, f: F) -> R {
+            f(&mut self.0)
+        }
+    }
+
+    struct TxToken<'a>(&'a mut Vec<Vec<u8>>);
+
+    impl phy::TxToken for TxToken<'_> {
+        fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+            let mut packet = vec![0; len];
+            let result = f(&mut packet);
+            self.0.push(packet);
+            result
+        }
+    }
+
+    impl phy::Device for VirtualDevice {
+        type RxToken<'a> = RxToken;
+        type TxToken<'a> = TxToken<'a>;
+
+        fn receive(
+            &mut self,
+            _timestamp: smoltcp::time::Instant,
+        ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+            let packet = self.received.pop_front()?;
+            Some((RxToken(packet), TxToken(&mut self.sent)))
+        }
+
+        fn transmit(&mut self, _timestamp: smoltcp::time::Instant) -> Option<Self::TxToken<'_>> {
+            Some(TxToken(&mut self.sent))
+        }
+
+        fn capabilities(&self) -> DeviceCapabilities {
+            let mut capabilities = DeviceCapabilities::default();
+            capabilities.medium = Medium::Ip;
+            capabilities.max_transmission_unit = self.mtu;
+            capabilities
+        }
+    }
+
+    /// The TCP/IP stack of the proxy inside the tunnel.
+    struct Network {
+        interface: Interface,
+        device: VirtualDevice,
+        sockets: SocketSet<'static>,
+        next_port: u16,
+        /// Connections whose streams were dropped, removed once closed.
+        closing: Vec<SocketHandle>,
+    }
+
+    impl Network {
+        fn new(addresses: &[IpAddr], mtu: usize) -> Result<Self> {
+            let mut device = VirtualDevice {
+                received: VecDeque::new(),
+                sent: Vec::new(),
+                mtu,
+            };
+            let mut config = Config::new(HardwareAddress::Ip);
+            config.random_seed = rand::random();
+            let mut interface = Interface::new(config, &mut device, smoltcp::time::Instant::now());
+            let mut cidrs = Vec::new();
+            for address in addresses {
+                let address = IpAddress::from(*address);
+                let prefix = if address.version() == smoltcp::wire::IpVersion::Ipv4 {
+                    32
+                } else {
+                    128
+                };
+                cidrs.push(IpCidr::new(address, prefix));
+                // Every destination is reached through the peer, so the proxy's own address serves as gateway
+                let added = match address {
+                    IpAddress::Ipv4(address) => {
+                        interface.routes_mut().add_default_ipv4_route(address)
+                    }
+                    IpAddress::Ipv6(address) => {
+                        interface.routes_mut().add_default_ipv6_route(address)
+                    }
+                };
+                added.map_err(|_| anyhow::anyhow!("Too many WireGuard addresses"))?;
+            }
+            interface.update_ip_addrs(|addrs| {
+                for cidr in cidrs {
+                    let _ = addrs.push(cidr);
+                }
+            });
+            Ok(Network {
+                interface,
+                device,
+                sockets: SocketSet::new(Vec::new()),
+                next_port: 49152,
+                closing: Vec::new(),
+            })
+        }
+
+        /// Opens a TCP connection to `remote` from an unused port.
+        fn open(&mut self, remote: SocketAddr) -> Result<SocketHandle> {
+            let mut port = self.next_port;
+            let in_use = |sockets: &SocketSet<'static>, port: u16| {
+                sockets.iter().any(|(_, socket)| {
+                    tcp::Socket::downcast(socket)
+                        .and_then(|socket| socket.local_endpoint())
+                        .is_some_and(|endpoint| endpoint.port == port)
+                })
+            };
+            while in_use(&self.sockets, port) {
+                port = port.checked_add(1).unwrap_or(49152);
+                if port == self.next_port {
+                    anyhow::bail!("No free port for a connection through WireGuard");
+                }
+            }
+            self.next_port = port.checked_add(1).unwrap_or(49152);
+            let mut socket = tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+                tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            );
+            socket.set_nagle_enabled(false);
+            socket.set_timeout(Some(smoltcp::time::Duration::from_secs(TCP_TIMEOUT)));
+            socket
+                .connect(self.interface.context(), remote, port)
+                .map_err(|err| {
+                    anyhow::anyhow!("Failed to connect to {} through WireGuard: {}", remote, err)
+                })?;
+            Ok(self.sockets.add(socket))
+        }
+
+        /// Runs the TCP/IP stack, returning the packets to send and how long it can wait.
+        fn poll(&mut self) -> (Vec<Vec<u8>>, Option<Duration>) {
+            let now = smoltcp::time::Instant::now();
+            self.interface
+                .poll(now, &mut self.device, &mut self.sockets);
+            let sockets = &mut self.sockets;
+            self.closing.retain(|handle| {
+                let state = sockets.get::<tcp::Socket>(*handle).state();
+                let closed = matches!(state, tcp::State::Closed | tcp::State::TimeWait);
+                if closed {
+                    sockets.remove(*handle);
+                }
+                !closed
+            });
+            let delay = self
+                .interface
+                .poll_delay(now, &self.sockets)
+                .map(|delay| Duration::from_micros(delay.total_micros()));
+            (std::mem::take(&mut self.device.sent), delay)
+        }
+    }
+
+    /// State shared by the tunnel task and the connections through the tunnel.
+    struct Shared {
+        network: Mutex<Network>,
+        peer: Mutex<Peer>,
+        socket: UdpSocket,
+        /// Wakes the tunnel task when a connection has something to send.
+        wake: Notify,
+        addresses: Vec<IpAddr>,
+    }
+
+    /// A WireGuard tunnel, brought up on its first use.
+    pub(super) struct Tunnel {
+        config: WireGuardConfig,
+        shared: OnceCell<Arc<Shared>>,
+    }
+
+    impl Tunnel {
+        /// Checks the keys and addresses of the tunnel.
+        pub(super) fn new(config: WireGuardConfig) -> Result<Self> {
+            Peer::new(&config)?;
+            if config.addresses.is_empty() {
+                anyhow::bail!("WireGuard tunnel to {} needs an address", config.endpoint);
+            }
+            if config
+                .addresses
+                .iter()
+                .filter(|address| address.is_ipv4())
+                .count()
+                > 1
+                || config
+                    .addresses
+                    .iter()
+                    .filter(|address| address.is_ipv6())
+                    .count()
+                    > 1
+            {
+                anyhow::bail!(
+                    "WireGuard tunnel to {} has several addresses of an IP version",
+                    config.endpoint
+                );
+            }
+            if config.mtu < 576 {
+                anyhow::bail!("WireGuard MTU {} is below the minimum of 576", config.mtu);
+            }
+            Ok(Tunnel {
+                config,
+                shared: OnceCell::new(),
+            })
+        }
+
+        /// Binds the UDP socket exchanging messages with the peer and starts the tunnel task.
+        async fn start(&self) -> Result<Arc<Shared>> {
+            let endpoint = lookup_host(&self.config.endpoint)
+                .await
+                .context(format!(
+                    "Failed to resolve WireGuard endpoint {}",
+                    self.config.endpoint
+                ))?
+                .next()
+                .context(format!(
+                    "WireGuard endpoint {} has no address",
+                    self.config.endpoint
+                ))?;
+            let bind = if endpoint.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let socket = UdpSocket::bind(bind).await?;
+            socket.connect(endpoint).await?;
+            let shared = Arc::new(Shared {
+                network: Mutex::new(Network::new(&self.config.addresses, self.config.mtu)?),
+                peer: Mutex::new(Peer::new(&self.config)?),
+                socket,
+                wake: Notify::new(),
+                addresses: self.config.addresses.clone(),
+            });
+            info!("Starting WireGuard tunnel to {}", endpoint);
+            tokio::spawn(drive(Arc::downgrade(&shared)));
+            Ok(shared)
+        }
+
+        /// Opens a TCP connection to port `port` of `host` through the tunnel.
+        pub(super) async fn connect(
+            &self,
+            host: &str,
+            port: u16,
+        ) -> Result<Box<dyn UpstreamStream>> {
+            let shared = self.shared.get_or_try_init(|| self.start()).await?.clone();
+            let remote = lookup_host((host, port))
+                .await
+                .context(format!("Failed to resolve {}", host))?
+                .find(|address| {
+                    shared
+                        .addresses
+                        .iter()
+                        .any(|own| own.is_ipv4() == address.is_ipv4())
+                })
+                .context(format!(
+                    "{} has no address of the IP version of the WireGuard tunnel",
+                    host
+                ))?;
+            let handle = shared.network.lock().unwrap().open(remote)?;
+            let stream = TunnelStream { shared, handle };
+            stream.shared.wake.notify_one();
+            stream
+                .established()
+                .await
+                .context(format!("Failed to connect to {} through WireGuard", remote))?;
+            Ok(Box::new(stream))
+        }
+    }
+
+    /// Exchanges the packets of the TCP/IP stack with the peer until the tunnel is no longer used.
+    async fn drive(shared: Weak<Shared>) {
+        let mut buffer = vec![0; 65536];
+        loop {
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+            let (packets, delay) = shared.network.lock().unwrap().poll();
+            let messages = {
+                let mut peer = shared.peer.lock().unwrap();
+                let mut messages: Vec<Vec<u8>> = peer.tick().into_iter().collect();
+                for packet in packets {
+                    messages.extend(peer.send(&packet));
+                }
+                messages
+            };
+            for message in messages {
+                if let Err(err) = shared.socket.send(&message).await {
+                    debug!("Failed to send to the WireGuard peer: {}", err);
+                }
+            }
+            let delay = delay.map_or(MAX_WAIT, |delay| delay.min(MAX_WAIT));
+            tokio::select! {
+                received = shared.socket.recv(&mut buffer) => {
+                    let len = match received {
+                        Ok(len) => len,
+                        Err(err) => {
+                            debug!("Failed to receive from the WireGuard peer: {}", err);
+                            continue;
+                        }
+                    };
+                    let (packet, messages) = shared.peer.lock().unwrap().receive(&buffer[..len]);
+                    if let Some(packet) = packet {
+                        shared.network.lock().unwrap().device.received.push_back(packet);
+                    }
+                    for message in messages {
+                        if let Err(err) = shared.socket.send(&message).await {
+                            debug!("Failed to send to the WireGuard peer: {}", err);
+                        }
+                    }
+                }
+                _ = shared.wake.notified() => {}
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+    }
+
+    /// A TCP connection through the tunnel.
+    struct TunnelStream {
+        shared: Arc<Shared>,
+        handle: SocketHandle,
+    }
+
+    impl TunnelStream {
+        /// Waits until the connection is established, failing if it is refused or times out.
+        async fn established(&self) -> Result<()> {
+            poll_fn(|cx| {
+                let mut network = self.shared.network.lock().unwrap();
+                let socket = network.sockets.get_mut::<tcp::Socket>(self.handle);
+                match socket.state() {
+                    tcp::State::Established => Poll::Ready(Ok(())),
+                    tcp::State::SynSent | tcp::State::SynReceived => {
+                        socket.register_send_waker(cx.waker());
+                        Poll::Pending
+                    }
+                    _ => Poll::Ready(Err(anyhow::anyhow!("Connection refused or timed out"))),
+                }
+            })
+            .await
+        }
+    }
+
+    impl AsyncRead for TunnelStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let mut network = self.shared.network.lock().unwrap();
+            let socket = network.sockets.get_mut::<tcp::Socket>(self.handle);
+            if socket.can_recv() {
+                let read = socket
+                    .recv_slice(buf.initialize_unfilled())
+                    .map_err(|err| {
+                        io::Error::new(io::ErrorKind::ConnectionReset, err.to_string())
+                    })?;
+                buf.advance(read);
+                drop(network);
+                // The window opened, which the peer may be waiting for
+                self.shared.wake.notify_one();
+                return Poll::Ready(Ok(()));
+            }
+            if !socket.may_recv() {
+                // The peer closed the connection, or it was aborted
+                return Poll::Ready(Ok(()));
+            }
+            socket.register_recv_waker(cx.waker());
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for TunnelStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let mut network = self.shared.network.lock().unwrap();
+            let socket = network.sockets.get_mut::<tcp::Socket>(self.handle);
+            if !socket.may_send() {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            if !socket.can_send() {
+                socket.register_send_waker(cx.waker());
+                return Poll::Pending;
+            }
+            let written = socket
+                .send_slice(buf)
+                .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err.to_string()))?;
+            drop(network);
+            self.shared.wake.notify_one();
+            Poll::Ready(Ok(written))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.shared
+                .network
+                .lock()
+                .unwrap()
+                .sockets
+                .get_mut::<tcp::Socket>(self.handle)
+                .close();
+            self.shared.wake.notify_one();
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Drop for TunnelStream {
+        fn drop(&mut self) {
+            let mut network = self.shared.network.lock().unwrap();
+            network.sockets.get_mut::<tcp::Socket>(self.handle).close();
+            network.closing.push(self.handle);
+            drop(network);
+            self.shared.wake.notify_one();
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::tenant::encode_base64;
+
+        /// Returns an IPv4 packet from the proxy to 10.0.0.1 carrying `payload`.
+        fn ipv4_packet(payload: &[u8]) -> Vec<u8> {
+            let mut packet = vec![
+                0x45, 0, 0, 0, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 2, 10, 0, 0, 1,
+            ];
+            packet.extend_from_slice(payload);
+            let len = (packet.len() as u16).to_be_bytes();
+            packet[2..4].copy_from_slice(&len);
+            packet
+        }
+
+        /// Returns the proxy's side of a tunnel and the peer it tunnels to.
+        fn peers() -> (Peer, Tunn) {
+            let own = StaticSecret::from([1; 32]);
+            let remote = StaticSecret::from([2; 32]);
+            let config = WireGuardConfig {
+                private_key: encode_base64(&own.to_bytes()),
+                peer_public_key: encode_base64(PublicKey::from(&remote).as_bytes()),
+                preshared_key: Some(encode_base64(&[3; 32])),
+                ..WireGuardConfig::default()
+            };
+            let remote = Tunn::new(remote, PublicKey::from(&own), Some([3; 32]), None, 7, None);
+            (Peer::new(&config).unwrap(), remote)
+        }
+
+        #[test]
+        fn packets_wait_for_the_handshake() {
+            let (mut peer, mut remote) = peers();
+            let mut buffer = vec![0; 2048];
+            let packet = ipv4_packet(b"hello");
+
+            let initiation = peer.send(&packet).unwrap();
+            assert_eq!(initiation[0], 1);
+            // A second packet waits for the same handshake
+            assert!(peer.send(&packet).is_none());
+
+            let response = match remote.decapsulate(None, &initiation, &mut buffer) {
+                TunnResult::WriteToNetwork(response) => response.to_vec(),
+                other => panic!("unexpected {:?}", other),
+            };
+            let (received, messages) = peer.receive(&response);
+            assert!(received.is_none());
+            // Both packets follow, and a keepalive confirming the session
+            let mut delivered = 0;
+            for message in &messages {
+                match remote.decapsulate(None, message, &mut buffer) {
+                    TunnResult::WriteToTunnelV4(received, _) => {
+                        assert_eq!(received, &packet[..]);
+                        delivered += 1;
+                    }
+                    TunnResult::Done => {}
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+            assert_eq!(delivered, 2);
+        }
+
+        #[test]
+        fn transport_rejects_replays_and_forgeries() {
+            let (mut peer, mut remote) = peers();
+            let mut buffer = vec![0; 2048];
+            let initiation = peer.send(&ipv4_packet(b"")).unwrap();
+            let response = match remote.decapsulate(None, &initiation, &mut buffer) {
+                TunnResult::WriteToNetwork(response) => response.to_vec(),
+                other => panic!("unexpected {:?}", other),
+            };
+            for message in peer.receive(&response).1 {
+                remote.decapsulate(None, &message, &mut buffer);
+            }
+
+            let packet = ipv4_packet(b"reply");
+            let message = match remote.encapsulate(&packet, &mut buffer) {
+                TunnResult::WriteToNetwork(message) => message.to_vec(),
+                other => panic!("unexpected {:?}", other),
+            };
+            assert_eq!(peer.receive(&message).0, Some(packet));
+            assert_eq!(peer.receive(&message).0, None);
+
+            let mut forged = match remote.encapsulate(&ipv4_packet(b"forged"), &mut buffer) {
+                TunnResult::WriteToNetwork(message) => message.to_vec(),
+                other => panic!("unexpected {:?}", other),
+            };
+            let last = forged.len() - 1;
+            forged[last] ^= 1;
+            assert_eq!(peer.receive(&forged).0, None);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Integration tests driving requests through an in-process proxy.
+//!
+//! Run with `cargo test --features test-util`, adding `encrypted-keys` to include the HTTPS test.
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use fortifynet_proxy::{ConnectorConfig, EgressRoute, ProxyConfig, TestProxy};
+use hyper::{
+    body::to_bytes,
+    service::{make_service_fn, service_fn},
+    Body, Client, Response, Server, StatusCode,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Starts an origin answering every request with its path, returning its address and the number of requests it got.
+fn start_origin() -> (SocketAddr, Arc<AtomicUsize>) {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    let make_service = make_service_fn(move |_| {
+        let counter = counter.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let body = format!("origin {}", req.uri().path());
+                async move { Ok::<_, Infallible>(Response::new(Body::from(body))) }
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    (addr, requests)
+}
+
+#[tokio::test]
+async fn forwards_and_caches_requests() {
+    let (origin, requests) = start_origin();
+    let proxy = TestProxy::start(ProxyConfig {
+        cache_enabled: true,
+        target_address: Some(format!("http://{}", origin)),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let client = Client::new();
+    for _ in 0..2 {
+        let response = client
+            .get(proxy.url("/hello").parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"origin /hello");
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    let metrics = proxy.metrics();
+    // Requests answered from the cache are not forwarded, and only counted as cache hits
+    assert_eq!(metrics.total_requests, 1);
+    assert_eq!(metrics.cache_hits, 1);
+    assert_eq!(proxy.cache().len(), 1);
+    proxy.shutdown().await;
+}
+
+/// Sends `request` to `addr` over a new connection, returning the response.
+async fn send_raw(addr: SocketAddr, request: &str) -> String {
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn routes_only_absolute_form_requests_to_their_destination() {
+    let (target, target_requests) = start_origin();
+    let (destination, destination_requests) = start_origin();
+    let proxy = TestProxy::start(ProxyConfig {
+        target_address: Some(format!("http://{}", target)),
+        egress_routes: vec![EgressRoute {
+            hosts: vec!["*".to_string()],
+            connector: ConnectorConfig::Direct,
+        }],
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let response = send_raw(
+        proxy.addr(),
+        "GET /path HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.ends_with("origin /path"), "{}", response);
+    let request = format!(
+        "GET http://{0}/absolute HTTP/1.1\r\nHost: {0}\r\nConnection: close\r\n\r\n",
+        destination
+    );
+    let response = send_raw(proxy.addr(), &request).await;
+    assert!(response.ends_with("origin /absolute"), "{}", response);
+    assert_eq!(target_requests.load(Ordering::SeqCst), 1);
+    assert_eq!(destination_requests.load(Ordering::SeqCst), 1);
+    proxy.shutdown().await;
+}
+
+/// HTTPS with a passphrase-protected private key, which requires the `encrypted-keys` feature.
+#[cfg(feature = "encrypted-keys")]
+mod encrypted_key {
+    use std::{path::PathBuf, time::SystemTime};
+
+    use fortifynet_proxy::SecretSource;
+    use openssl::{
+        asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa, symm::Cipher, x509::X509,
+    };
+    use tokio_rustls::{
+        rustls::{
+            client::{ServerCertVerified, ServerCertVerifier},
+            Certificate, ClientConfig, Error, ServerName,
+        },
+        TlsConnector,
+    };
+
+    use super::*;
+
+    /// Accepts the self-signed certificate of the proxy.
+    struct AcceptAny;
+
+    impl ServerCertVerifier for AcceptAny {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _server_name: &ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: SystemTime,
+        ) -> Result<ServerCertVerified, Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    /// Writes a self-signed certificate and its private key, encrypted with `passphrase`, returning their paths.
+    fn write_certificate(passphrase: &str) -> (PathBuf, PathBuf) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = openssl::x509::X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut certificate = X509::builder().unwrap();
+        certificate.set_version(2).unwrap();
+        certificate.set_subject_name(&name).unwrap();
+        certificate.set_issuer_name(&name).unwrap();
+        certificate.set_pubkey(&key).unwrap();
+        certificate
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        certificate
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        certificate.sign(&key, MessageDigest::sha256()).unwrap();
+        let certificate = certificate.build();
+
+        let directory =
+            std::env::temp_dir().join(format!("fortifynet-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let certificate_path = directory.join("cert.pem");
+        let key_path = directory.join("key.pem");
+        std::fs::write(&certificate_path, certificate.to_pem().unwrap()).unwrap();
+        let encrypted = key
+            .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), passphrase.as_bytes())
+            .unwrap();
+        std::fs::write(&key_path, encrypted).unwrap();
+        (certificate_path, key_path)
+    }
+
+    #[tokio::test]
+    async fn serves_https_with_an_encrypted_key() {
+        let (origin, _) = start_origin();
+        let (certificate_path, key_path) = write_certificate("passphrase");
+        let proxy = TestProxy::start(ProxyConfig {
+            https_enabled: true,
+            certificate_path: Some(certificate_path.display().to_string()),
+            private_key_path: Some(key_path.display().to_string()),
+            private_key_passphrase: Some(SecretSource::Value("passphrase".to_string())),
+            target_address: Some(format!("http://{}", origin)),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        // The key is decrypted once, so that later changes to the files do not affect the handshakes
+        std::fs::remove_file(&key_path).unwrap();
+
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(AcceptAny))
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        for _ in 0..2 {
+            let stream = tokio::net::TcpStream::connect(proxy.addr()).await.unwrap();
+            let server_name = ServerName::try_from("localhost").unwrap();
+            let mut stream = connector.connect(server_name, stream).await.unwrap();
+            stream
+                .write_all(b"GET /secure HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            assert!(response.ends_with("origin /secure"), "{}", response);
+        }
+        assert_eq!(proxy.metrics().tls_handshakes.durations.count, 2);
+        proxy.shutdown().await;
+        std::fs::remove_file(&certificate_path).ok();
+    }
+}